@@ -0,0 +1,846 @@
+//! `LD_PRELOAD` shim that runs the TCP and UDP sockets of an unmodified program over utcp.
+//!
+//! ```text
+//! LD_PRELOAD=target/debug/libutcp_preload.so \
+//!     UTCP_TAP=tap0 UTCP_ADDR=192.0.2.2/24 UTCP_GATEWAY=192.0.2.1 program
+//! ```
+//!
+//! The stack starts on the first `socket(AF_INET | AF_INET6, SOCK_STREAM | SOCK_DGRAM, ..)`
+//! call and is configured from the environment:
+//!
+//! - `UTCP_TAP`: TAP interface to attach to. Without it the stack only has a loopback device,
+//!   which also has `::1`.
+//! - `UTCP_ADDR`: address and prefix length of the interface, `127.0.0.1/8` by default.
+//! - `UTCP_GATEWAY`: default gateway, optional.
+//! - `UTCP_HWADDR`: MAC address of the TAP device, random by default.
+//!
+//! Every utcp socket is represented in the program by a placeholder eventfd, so descriptor
+//! numbers never collide with the ones the kernel hands out. Calls on other descriptors go to
+//! libc untouched.
+//!
+//! Limitations:
+//!
+//! - Only `poll` waits on utcp sockets. `select`, `ppoll` and `epoll` do not see them.
+//! - `dup`, `sendmsg`, `recvmsg`, `readv`, `writev` and `fork` are not supported on them,
+//!   which rules out the DNS resolver of glibc. Use numeric addresses or `/etc/hosts`.
+//! - `send` and `recv` flags other than `MSG_NOSIGNAL` fail with `EOPNOTSUPP`, and writing to
+//!   a closed connection fails with `EPIPE` without raising `SIGPIPE`.
+//! - Socket options that utcp does not implement are accepted and ignored by `setsockopt`.
+//! - The stack reserves `SIGHUP`, `SIGUSR1`, `SIGUSR2`, `SIGALRM` and the real-time signals
+//!   of its devices, which it delivers to its own threads only.
+
+// The interposed functions have the safety contracts of their libc counterparts.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_int, c_ulong, c_void},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use libc::{
+    nfds_t, pollfd, size_t, sockaddr, sockaddr_in, sockaddr_in6, socklen_t, ssize_t, timeval,
+};
+use utcp::{
+    driver::{ether_tap::EtherTapNetDevice, loopback::LoopbackNetDevice},
+    error::{UtcpErr, UtcpResult},
+    event,
+    ip::{self, IpAddress, IpEndpoint, IpInterface},
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    net,
+    poll::{PollEvents, PollFd, utcp_poll},
+    socket::{self, SocketKind},
+};
+
+/// Looks up the next definition of a libc function, that is the one this library hides.
+/// Expands to an unsafe operation.
+macro_rules! real {
+    ($name:ident: fn($($arg:ty),*) -> $ret:ty) => {{
+        static ADDR: OnceLock<usize> = OnceLock::new();
+        let addr = real_sym(&ADDR, concat!(stringify!($name), "\0"));
+        std::mem::transmute::<usize, unsafe extern "C" fn($($arg),*) -> $ret>(addr)
+    }};
+}
+
+fn real_sym(cache: &OnceLock<usize>, name: &'static str) -> usize {
+    *cache.get_or_init(|| {
+        let sym = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr().cast()) };
+        assert!(!sym.is_null(), "{} not found", name.trim_end_matches('\0'));
+        sym as usize
+    })
+}
+
+/// Placeholder descriptor to utcp descriptor. The lock is never held while calling into
+/// utcp, whose own threads go through the interposed `read`, `write` and `close` too.
+static FDS: Mutex<BTreeMap<c_int, c_int>> = Mutex::new(BTreeMap::new());
+
+static STACK: OnceLock<Result<(), c_int>> = OnceLock::new();
+
+fn utcp_fd(fd: c_int) -> Option<c_int> {
+    FDS.lock().unwrap().get(&fd).copied()
+}
+
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+fn fail<T: From<i8>>(errno: c_int) -> T {
+    set_errno(errno);
+    T::from(-1)
+}
+
+fn ret_len(result: UtcpResult<usize>) -> ssize_t {
+    match result {
+        Ok(len) => len as ssize_t,
+        Err(e) => fail(e.errno()),
+    }
+}
+
+fn ret_unit(result: UtcpResult<()>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => fail(e.errno()),
+    }
+}
+
+fn parse_addr(s: &str) -> UtcpResult<(IpAddress, IpAddress)> {
+    let invalid = || UtcpErr::InvalidAddress(s.to_string());
+    let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+    let prefix: u32 = prefix
+        .parse()
+        .ok()
+        .filter(|&p| p <= 32)
+        .ok_or_else(invalid)?;
+    let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Ok((addr.parse()?, IpAddress::from(netmask)))
+}
+
+fn stack_setup() -> UtcpResult<()> {
+    utcp::log_init();
+    net::net_init()?;
+    let addr = std::env::var("UTCP_ADDR").unwrap_or_else(|_| "127.0.0.1/8".to_string());
+    let (unicast, netmask) = parse_addr(&addr)?;
+    let dev = match std::env::var("UTCP_TAP") {
+        Ok(tap) => {
+            let hwaddr = match std::env::var("UTCP_HWADDR") {
+                Ok(hwaddr) => Some(hwaddr.parse()?),
+                Err(_) => None,
+            };
+            EtherTapNetDevice::init(&tap, hwaddr)?
+        }
+        Err(_) => {
+            let dev = LoopbackNetDevice::init()?;
+            ipv6::ipv6_iface_register(dev, Ipv6Interface::new())?;
+            ipv6::ipv6_addr_add(dev, Ipv6Address::LOOPBACK, 128)?;
+            dev
+        }
+    };
+    ip::ip_iface_register(dev, IpInterface::new(unicast, netmask))?;
+    if let Ok(gateway) = std::env::var("UTCP_GATEWAY") {
+        ip::ip_route_set_default_gateway(gateway.parse()?)?;
+    }
+    net::net_run()
+}
+
+fn stack_init() -> Result<(), c_int> {
+    *STACK.get_or_init(|| {
+        // net_run blocks the stack's signals in the calling thread, which must not be one of
+        // the program's
+        std::thread::spawn(|| {
+            stack_setup().map_err(|e| {
+                log::error!("failed to start the stack: {}", e);
+                e.errno()
+            })
+        })
+        .join()
+        .unwrap_or(Err(libc::EIO))
+    })
+}
+
+/// Returns a placeholder descriptor for a new utcp socket.
+fn fd_register(ufd: c_int) -> c_int {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        let errno = std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EMFILE);
+        let _ = socket::close(ufd);
+        return fail(errno);
+    }
+    FDS.lock().unwrap().insert(fd, ufd);
+    fd
+}
+
+unsafe fn read_endpoint(addr: *const sockaddr, len: socklen_t) -> Result<IpEndpoint, c_int> {
+    if addr.is_null() || (len as usize) < size_of::<libc::sa_family_t>() {
+        return Err(libc::EINVAL);
+    }
+    match unsafe { addr.read_unaligned() }.sa_family as c_int {
+        libc::AF_INET if len as usize >= size_of::<sockaddr_in>() => {
+            let sin = unsafe { addr.cast::<sockaddr_in>().read_unaligned() };
+            let addr = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Ok(SocketAddrV4::new(addr, u16::from_be(sin.sin_port)).into())
+        }
+        libc::AF_INET6 if len as usize >= size_of::<sockaddr_in6>() => {
+            let sin6 = unsafe { addr.cast::<sockaddr_in6>().read_unaligned() };
+            let addr = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Ok(SocketAddrV6::new(addr, u16::from_be(sin6.sin6_port), 0, 0).into())
+        }
+        libc::AF_INET | libc::AF_INET6 => Err(libc::EINVAL),
+        _ => Err(libc::EAFNOSUPPORT),
+    }
+}
+
+/// Stores `ep` the way the kernel does: truncated to `*len`, with `*len` set to the full size.
+/// IPv6 endpoints are stored as `sockaddr_in6`, which is what `AF_INET6` sockets return.
+unsafe fn write_endpoint(ep: IpEndpoint, addr: *mut sockaddr, len: *mut socklen_t) {
+    if addr.is_null() || len.is_null() {
+        return;
+    }
+    match std::net::SocketAddr::from(ep) {
+        std::net::SocketAddr::V4(ep) => {
+            let mut sin: sockaddr_in = unsafe { std::mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = ep.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*ep.ip()).to_be();
+            unsafe { write_sockaddr(&sin, addr, len) };
+        }
+        std::net::SocketAddr::V6(ep) => {
+            let mut sin6: sockaddr_in6 = unsafe { std::mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = ep.port().to_be();
+            sin6.sin6_addr.s6_addr = ep.ip().octets();
+            unsafe { write_sockaddr(&sin6, addr, len) };
+        }
+    }
+}
+
+unsafe fn write_sockaddr<T>(value: &T, addr: *mut sockaddr, len: *mut socklen_t) {
+    unsafe {
+        let copy = (*len as usize).min(size_of::<T>());
+        std::ptr::copy_nonoverlapping((value as *const T).cast::<u8>(), addr.cast::<u8>(), copy);
+        *len = size_of::<T>() as socklen_t;
+    }
+}
+
+fn check_flags(flags: c_int) -> Result<(), c_int> {
+    if flags & !libc::MSG_NOSIGNAL != 0 {
+        return Err(libc::EOPNOTSUPP);
+    }
+    Ok(())
+}
+
+unsafe fn buf<'a>(ptr: *const c_void, len: size_t) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(ptr.cast(), len) }
+}
+
+unsafe fn buf_mut<'a>(ptr: *mut c_void, len: size_t) -> &'a mut [u8] {
+    if len == 0 {
+        return &mut [];
+    }
+    unsafe { std::slice::from_raw_parts_mut(ptr.cast(), len) }
+}
+
+fn duration_to_timeval(timeout: Option<Duration>) -> timeval {
+    let timeout = timeout.unwrap_or_default();
+    timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    }
+}
+
+fn timeval_to_duration(tv: timeval) -> Result<Option<Duration>, c_int> {
+    if tv.tv_sec < 0 || !(0..1_000_000).contains(&tv.tv_usec) {
+        return Err(libc::EDOM);
+    }
+    let timeout = Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    Ok((!timeout.is_zero()).then_some(timeout))
+}
+
+unsafe fn write_opt<T>(value: T, optval: *mut c_void, optlen: *mut socklen_t) -> c_int {
+    if optval.is_null() || optlen.is_null() || (unsafe { *optlen } as usize) < size_of::<T>() {
+        return fail(libc::EINVAL);
+    }
+    unsafe {
+        optval.cast::<T>().write_unaligned(value);
+        *optlen = size_of::<T>() as socklen_t;
+    }
+    0
+}
+
+unsafe fn read_opt<T>(optval: *const c_void, optlen: socklen_t) -> Result<T, c_int> {
+    if optval.is_null() || (optlen as usize) < size_of::<T>() {
+        return Err(libc::EINVAL);
+    }
+    Ok(unsafe { optval.cast::<T>().read_unaligned() })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int {
+    let base = ty & !(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC);
+    if (domain != libc::AF_INET && domain != libc::AF_INET6)
+        || (base != libc::SOCK_STREAM && base != libc::SOCK_DGRAM)
+    {
+        return unsafe { real!(socket: fn(c_int, c_int, c_int) -> c_int)(domain, ty, protocol) };
+    }
+    if let Err(errno) = stack_init() {
+        return fail(errno);
+    }
+    // placeholders are always close-on-exec, the stack does not survive an exec anyway
+    match socket::socket(domain, ty & !libc::SOCK_CLOEXEC, protocol) {
+        Ok(ufd) => fd_register(ufd),
+        Err(e) => fail(e.errno()),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bind(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(bind: fn(c_int, *const sockaddr, socklen_t) -> c_int)(fd, addr, len)
+        };
+    };
+    match unsafe { read_endpoint(addr, len) } {
+        Ok(ep) => ret_unit(socket::bind(ufd, ep)),
+        Err(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn connect(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(connect: fn(c_int, *const sockaddr, socklen_t) -> c_int)(fd, addr, len)
+        };
+    };
+    match unsafe { read_endpoint(addr, len) } {
+        Ok(ep) => ret_unit(socket::connect(ufd, ep)),
+        Err(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn listen(fd: c_int, backlog: c_int) -> c_int {
+    match utcp_fd(fd) {
+        Some(ufd) => ret_unit(socket::listen(ufd, backlog)),
+        None => unsafe { real!(listen: fn(c_int, c_int) -> c_int)(fd, backlog) },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn accept4(
+    fd: c_int,
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+    flags: c_int,
+) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(accept4: fn(c_int, *mut sockaddr, *mut socklen_t, c_int) -> c_int)(
+                fd, addr, len, flags,
+            )
+        };
+    };
+    let (new, peer) = match socket::accept(ufd) {
+        Ok(accepted) => accepted,
+        Err(e) => return fail(e.errno()),
+    };
+    if flags & libc::SOCK_NONBLOCK != 0
+        && let Err(e) = socket::set_nonblocking(new, true)
+    {
+        let _ = socket::close(new);
+        return fail(e.errno());
+    }
+    unsafe { write_endpoint(peer, addr, len) };
+    fd_register(new)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn accept(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    if utcp_fd(fd).is_none() {
+        return unsafe {
+            real!(accept: fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int)(fd, addr, len)
+        };
+    }
+    unsafe { accept4(fd, addr, len, 0) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sendto(
+    fd: c_int,
+    data: *const c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+    addrlen: socklen_t,
+) -> ssize_t {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(sendto: fn(c_int, *const c_void, size_t, c_int, *const sockaddr, socklen_t) -> ssize_t)(
+                fd, data, len, flags, addr, addrlen,
+            )
+        };
+    };
+    if let Err(errno) = check_flags(flags) {
+        return fail(errno);
+    }
+    let data = unsafe { buf(data, len) };
+    if addr.is_null() {
+        return ret_len(socket::send(ufd, data));
+    }
+    match unsafe { read_endpoint(addr, addrlen) } {
+        Ok(ep) => ret_len(socket::sendto(ufd, data, ep)),
+        Err(errno) => fail(errno),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn send(
+    fd: c_int,
+    data: *const c_void,
+    len: size_t,
+    flags: c_int,
+) -> ssize_t {
+    if utcp_fd(fd).is_none() {
+        return unsafe {
+            real!(send: fn(c_int, *const c_void, size_t, c_int) -> ssize_t)(fd, data, len, flags)
+        };
+    }
+    unsafe { sendto(fd, data, len, flags, std::ptr::null(), 0) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn recvfrom(
+    fd: c_int,
+    data: *mut c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
+) -> ssize_t {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(recvfrom: fn(c_int, *mut c_void, size_t, c_int, *mut sockaddr, *mut socklen_t) -> ssize_t)(
+                fd, data, len, flags, addr, addrlen,
+            )
+        };
+    };
+    if let Err(errno) = check_flags(flags) {
+        return fail(errno);
+    }
+    let data = unsafe { buf_mut(data, len) };
+    let result = match socket::socket_kind(ufd) {
+        Ok(SocketKind::Tcp(_)) => socket::recv(ufd, data).and_then(|n| {
+            // the peer of a stream does not change, report it like the kernel does
+            if !addr.is_null() {
+                unsafe { write_endpoint(socket::getpeername(ufd)?, addr, addrlen) };
+            }
+            Ok(n)
+        }),
+        Ok(_) => socket::recvfrom(ufd, data).map(|(n, peer)| {
+            unsafe { write_endpoint(peer, addr, addrlen) };
+            n
+        }),
+        Err(e) => Err(e),
+    };
+    ret_len(result)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn recv(fd: c_int, data: *mut c_void, len: size_t, flags: c_int) -> ssize_t {
+    match utcp_fd(fd) {
+        Some(ufd) => match check_flags(flags) {
+            Ok(()) => ret_len(socket::recv(ufd, unsafe { buf_mut(data, len) })),
+            Err(errno) => fail(errno),
+        },
+        None => unsafe {
+            real!(recv: fn(c_int, *mut c_void, size_t, c_int) -> ssize_t)(fd, data, len, flags)
+        },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn read(fd: c_int, data: *mut c_void, len: size_t) -> ssize_t {
+    match utcp_fd(fd) {
+        Some(ufd) => ret_len(socket::recv(ufd, unsafe { buf_mut(data, len) })),
+        None => unsafe { real!(read: fn(c_int, *mut c_void, size_t) -> ssize_t)(fd, data, len) },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write(fd: c_int, data: *const c_void, len: size_t) -> ssize_t {
+    match utcp_fd(fd) {
+        Some(ufd) => ret_len(socket::send(ufd, unsafe { buf(data, len) })),
+        None => unsafe { real!(write: fn(c_int, *const c_void, size_t) -> ssize_t)(fd, data, len) },
+    }
+}
+
+// Fortified builds call these instead of the plain functions.
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __read_chk(
+    fd: c_int,
+    data: *mut c_void,
+    len: size_t,
+    buflen: size_t,
+) -> ssize_t {
+    if len > buflen {
+        unsafe { libc::abort() };
+    }
+    unsafe { read(fd, data, len) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __recv_chk(
+    fd: c_int,
+    data: *mut c_void,
+    len: size_t,
+    buflen: size_t,
+    flags: c_int,
+) -> ssize_t {
+    if len > buflen {
+        unsafe { libc::abort() };
+    }
+    unsafe { recv(fd, data, len, flags) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __recvfrom_chk(
+    fd: c_int,
+    data: *mut c_void,
+    len: size_t,
+    buflen: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
+) -> ssize_t {
+    if len > buflen {
+        unsafe { libc::abort() };
+    }
+    unsafe { recvfrom(fd, data, len, flags, addr, addrlen) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+    let ufd = FDS.lock().unwrap().remove(&fd);
+    let ret = unsafe { real!(close: fn(c_int) -> c_int)(fd) };
+    match ufd {
+        Some(ufd) => ret_unit(socket::close(ufd)),
+        None => ret,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn shutdown(fd: c_int, how: c_int) -> c_int {
+    match utcp_fd(fd) {
+        Some(ufd) => ret_unit(socket::shutdown(ufd, how)),
+        None => unsafe { real!(shutdown: fn(c_int, c_int) -> c_int)(fd, how) },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn getsockname(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(getsockname: fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int)(fd, addr, len)
+        };
+    };
+    match socket::getsockname(ufd) {
+        Ok(ep) => {
+            unsafe { write_endpoint(ep, addr, len) };
+            0
+        }
+        Err(e) => fail(e.errno()),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn getpeername(fd: c_int, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(getpeername: fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int)(fd, addr, len)
+        };
+    };
+    match socket::getpeername(ufd) {
+        Ok(ep) => {
+            unsafe { write_endpoint(ep, addr, len) };
+            0
+        }
+        Err(e) => fail(e.errno()),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn setsockopt(
+    fd: c_int,
+    level: c_int,
+    name: c_int,
+    optval: *const c_void,
+    optlen: socklen_t,
+) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(setsockopt: fn(c_int, c_int, c_int, *const c_void, socklen_t) -> c_int)(
+                fd, level, name, optval, optlen,
+            )
+        };
+    };
+    let result = match (level, name) {
+        (libc::SOL_SOCKET, libc::SO_RCVTIMEO | libc::SO_SNDTIMEO) => {
+            match unsafe { read_opt::<timeval>(optval, optlen) }.and_then(timeval_to_duration) {
+                Ok(timeout) if name == libc::SO_RCVTIMEO => socket::set_recv_timeout(ufd, timeout),
+                Ok(timeout) => socket::set_send_timeout(ufd, timeout),
+                Err(errno) => return fail(errno),
+            }
+        }
+        (libc::IPPROTO_TCP, libc::TCP_NODELAY) => {
+            match unsafe { read_opt::<c_int>(optval, optlen) } {
+                Ok(nodelay) => socket::set_nodelay(ufd, nodelay != 0),
+                Err(errno) => return fail(errno),
+            }
+        }
+        (libc::SOL_SOCKET, libc::SO_REUSEADDR) => {
+            match unsafe { read_opt::<c_int>(optval, optlen) } {
+                Ok(reuse) => socket::set_reuse_addr(ufd, reuse != 0),
+                Err(errno) => return fail(errno),
+            }
+        }
+        (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY) => {
+            match unsafe { read_opt::<c_int>(optval, optlen) } {
+                Ok(v6only) => socket::set_v6only(ufd, v6only != 0),
+                Err(errno) => return fail(errno),
+            }
+        }
+        // `struct ip_mreqn` starts like `struct ip_mreq`, its interface index is not used
+        (libc::IPPROTO_IP, libc::IP_ADD_MEMBERSHIP | libc::IP_DROP_MEMBERSHIP) => {
+            match unsafe { read_opt::<libc::ip_mreq>(optval, optlen) } {
+                Ok(mreq) => {
+                    let group = IpAddress::from(u32::from_be(mreq.imr_multiaddr.s_addr));
+                    let iface = IpAddress::from(u32::from_be(mreq.imr_interface.s_addr));
+                    match name {
+                        libc::IP_ADD_MEMBERSHIP => socket::join_multicast(ufd, group, iface),
+                        _ => socket::leave_multicast(ufd, group, iface),
+                    }
+                }
+                Err(errno) => return fail(errno),
+            }
+        }
+        _ => {
+            log::debug!("ignored: fd={}, level={}, name={}", fd, level, name);
+            Ok(())
+        }
+    };
+    ret_unit(result)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn getsockopt(
+    fd: c_int,
+    level: c_int,
+    name: c_int,
+    optval: *mut c_void,
+    optlen: *mut socklen_t,
+) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe {
+            real!(getsockopt: fn(c_int, c_int, c_int, *mut c_void, *mut socklen_t) -> c_int)(
+                fd, level, name, optval, optlen,
+            )
+        };
+    };
+    let value = match (level, name) {
+        (libc::SOL_SOCKET, libc::SO_ERROR) => {
+            socket::take_error(ufd).map(|e| e.map(|e| e.errno()).unwrap_or(0))
+        }
+        (libc::SOL_SOCKET, libc::SO_TYPE) => socket::socket_kind(ufd).map(|kind| match kind {
+            SocketKind::Tcp(_) => libc::SOCK_STREAM,
+            SocketKind::Udp(_) => libc::SOCK_DGRAM,
+            SocketKind::Raw(_) => libc::SOCK_RAW,
+        }),
+        (libc::SOL_SOCKET, libc::SO_RCVTIMEO | libc::SO_SNDTIMEO) => {
+            let timeout = match name {
+                libc::SO_RCVTIMEO => socket::recv_timeout(ufd),
+                _ => socket::send_timeout(ufd),
+            };
+            return match timeout {
+                Ok(timeout) => unsafe { write_opt(duration_to_timeval(timeout), optval, optlen) },
+                Err(e) => fail(e.errno()),
+            };
+        }
+        (libc::IPPROTO_TCP, libc::TCP_NODELAY) => socket::nodelay(ufd).map(c_int::from),
+        (libc::SOL_SOCKET, libc::SO_REUSEADDR) => socket::reuse_addr(ufd).map(c_int::from),
+        (libc::SOL_SOCKET, libc::SO_DOMAIN) => socket::socket_domain(ufd),
+        (libc::IPPROTO_IPV6, libc::IPV6_V6ONLY) => socket::v6only(ufd).map(c_int::from),
+        _ => return fail(libc::ENOPROTOOPT),
+    };
+    match value {
+        Ok(value) => unsafe { write_opt(value, optval, optlen) },
+        Err(e) => fail(e.errno()),
+    }
+}
+
+// `fcntl` and `ioctl` are variadic in C. Reading the optional argument as a fixed one works
+// because the calling conventions of x86_64 and aarch64 Linux pass both the same way.
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fcntl(fd: c_int, cmd: c_int, arg: c_ulong) -> c_int {
+    if let Some(ufd) = utcp_fd(fd) {
+        match cmd {
+            libc::F_GETFL => {
+                return match socket::is_nonblocking(ufd) {
+                    Ok(true) => libc::O_RDWR | libc::O_NONBLOCK,
+                    Ok(false) => libc::O_RDWR,
+                    Err(e) => fail(e.errno()),
+                };
+            }
+            libc::F_SETFL => {
+                let nonblocking = arg as c_int & libc::O_NONBLOCK != 0;
+                return ret_unit(socket::set_nonblocking(ufd, nonblocking));
+            }
+            // descriptor flags belong to the placeholder
+            _ => {}
+        }
+    }
+    unsafe { real!(fcntl: fn(c_int, c_int, c_ulong) -> c_int)(fd, cmd, arg) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fcntl64(fd: c_int, cmd: c_int, arg: c_ulong) -> c_int {
+    unsafe { fcntl(fd, cmd, arg) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ioctl(fd: c_int, request: c_ulong, arg: *mut c_void) -> c_int {
+    let Some(ufd) = utcp_fd(fd) else {
+        return unsafe { real!(ioctl: fn(c_int, c_ulong, *mut c_void) -> c_int)(fd, request, arg) };
+    };
+    match request {
+        libc::FIONBIO => match unsafe { read_opt::<c_int>(arg, size_of::<c_int>() as socklen_t) } {
+            Ok(nonblocking) => ret_unit(socket::set_nonblocking(ufd, nonblocking != 0)),
+            Err(errno) => fail(errno),
+        },
+        _ => fail(libc::ENOTTY),
+    }
+}
+
+/// Waits on utcp sockets and kernel descriptors at once. The kernel descriptors are polled
+/// together with the event fd of utcp, which is cleared before the utcp sockets are checked
+/// so that an event during the check ends the wait.
+fn poll_mixed(
+    fds: &mut [pollfd],
+    ufds: &mut [(usize, PollFd)],
+    deadline: Option<Instant>,
+) -> UtcpResult<c_int> {
+    let event_fd = event::event_fd()?;
+    let mut host: Vec<(usize, pollfd)> = fds
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !ufds.iter().any(|(j, _)| i == j))
+        .map(|(i, fd)| (i, *fd))
+        .collect();
+    loop {
+        event::event_clear()?;
+        let mut pfds: Vec<PollFd> = ufds.iter().map(|(_, fd)| *fd).collect();
+        let mut ready = utcp_poll(&mut pfds, Some(Duration::ZERO))?;
+        for ((i, fd), pfd) in ufds.iter_mut().zip(pfds) {
+            fds[*i].revents = pfd.revents.bits() as i16;
+            *fd = pfd;
+        }
+
+        let timeout = match deadline {
+            _ if ready > 0 => 0,
+            None => -1,
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as c_int
+            }
+        };
+        let mut kfds: Vec<pollfd> = host.iter().map(|(_, fd)| *fd).collect();
+        kfds.push(pollfd {
+            fd: event_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let ret = unsafe {
+            real!(poll: fn(*mut pollfd, nfds_t, c_int) -> c_int)(
+                kfds.as_mut_ptr(),
+                kfds.len() as nfds_t,
+                timeout,
+            )
+        };
+        if ret < 0 {
+            return Err(UtcpErr::last_os_error("poll"));
+        }
+        for ((i, fd), kfd) in host.iter_mut().zip(&kfds) {
+            fds[*i].revents = kfd.revents;
+            fd.revents = kfd.revents;
+            if kfd.revents != 0 {
+                ready += 1;
+            }
+        }
+        if ready > 0 || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(ready as c_int);
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int {
+    let slice = if nfds == 0 {
+        &mut []
+    } else {
+        unsafe { std::slice::from_raw_parts_mut(fds, nfds as usize) }
+    };
+    let mut ufds: Vec<(usize, PollFd)> = {
+        let map = FDS.lock().unwrap();
+        slice
+            .iter()
+            .enumerate()
+            .filter_map(|(i, fd)| {
+                let ufd = *map.get(&fd.fd)?;
+                let events = PollEvents::from_bits_truncate(fd.events as u16);
+                Some((i, PollFd::new(ufd, events)))
+            })
+            .collect()
+    };
+    if ufds.is_empty() {
+        return unsafe { real!(poll: fn(*mut pollfd, nfds_t, c_int) -> c_int)(fds, nfds, timeout) };
+    }
+    let deadline = u64::try_from(timeout)
+        .ok()
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let result = if ufds.len() == slice.len() {
+        let mut pfds: Vec<PollFd> = ufds.iter().map(|(_, fd)| *fd).collect();
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        utcp_poll(&mut pfds, timeout).map(|ready| {
+            for ((i, _), pfd) in ufds.iter().zip(&pfds) {
+                slice[*i].revents = pfd.revents.bits() as i16;
+            }
+            ready as c_int
+        })
+    } else {
+        poll_mixed(slice, &mut ufds, deadline)
+    };
+    match result {
+        Ok(ready) => ready,
+        Err(e) => fail(e.errno()),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __poll_chk(
+    fds: *mut pollfd,
+    nfds: nfds_t,
+    timeout: c_int,
+    fdslen: size_t,
+) -> c_int {
+    if (fdslen / size_of::<pollfd>()) < nfds as usize {
+        unsafe { libc::abort() };
+    }
+    unsafe { poll(fds, nfds, timeout) }
+}
@@ -0,0 +1,96 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    process::Command,
+    thread,
+};
+
+/// Set in the environment of the client the test runs under the shim.
+const CHILD_ENV: &str = "UTCP_PRELOAD_TEST_CHILD";
+
+/// Builds `libutcp_preload.so`, which `cargo test` does not do for a `cdylib`, and returns
+/// its path next to the `deps` directory of this test.
+fn shim_path() -> PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "-p", "utcp-preload"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "failed to build the shim");
+    let exe = std::env::current_exe().unwrap();
+    let path = exe
+        .parent()
+        .and_then(|deps| deps.parent())
+        .unwrap()
+        .join("libutcp_preload.so");
+    assert!(path.exists(), "{} not built", path.display());
+    path
+}
+
+/// A descriptor of the shim is a placeholder eventfd rather than a kernel socket.
+fn is_placeholder(fd: i32) -> bool {
+    std::fs::read_link(format!("/proc/self/fd/{}", fd))
+        .is_ok_and(|target| target.to_string_lossy() == "anon_inode:[eventfd]")
+}
+
+/// The client: plain `std::net` code, which the shim runs over the loopback device of utcp.
+fn client() {
+    let listener = TcpListener::bind("127.0.0.1:7200").unwrap();
+    assert!(is_placeholder(listener.as_raw_fd()));
+    let server = thread::spawn(move || {
+        let (mut stream, peer) = listener.accept().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), peer);
+        let mut buf = [0u8; 64];
+        loop {
+            let len = stream.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            stream.write_all(&buf[..len]).unwrap();
+        }
+    });
+    let mut stream = TcpStream::connect("127.0.0.1:7200").unwrap();
+    assert!(is_placeholder(stream.as_raw_fd()));
+    stream.write_all(b"hello over utcp").unwrap();
+    let mut buf = [0u8; 15];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello over utcp");
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    server.join().unwrap();
+
+    let server = UdpSocket::bind("[::1]:7201").unwrap();
+    let client = UdpSocket::bind("[::1]:0").unwrap();
+    assert!(is_placeholder(client.as_raw_fd()));
+    client
+        .send_to(b"ping", server.local_addr().unwrap())
+        .unwrap();
+    let (len, from) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, client.local_addr().unwrap());
+}
+
+/// Runs this test again under the shim as the client. Without `UTCP_TAP`, the shim starts a
+/// stack with only a loopback device, so this needs no privilege.
+#[test]
+fn preload_loopback() {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        client();
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "preload_loopback", "--nocapture"])
+        .env("LD_PRELOAD", shim_path())
+        .env(CHILD_ENV, "1")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "client failed: {}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+}
@@ -0,0 +1,394 @@
+//! ARP (RFC 826) for IPv4 over Ethernet-class devices, that is devices with
+//! `NetDeviceFlags::NEED_ARP`.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::{IP_ADDR_ANY, IpAddress, IpInterface},
+    net::{
+        self, NET_PROTOCOL_TYPE_ARP, NET_PROTOCOL_TYPE_IP, NetDeviceHandler, NetInterfaceFamily,
+        NetInterfaceHandler, NetProtocol,
+    },
+    net_device_get,
+    wire::{
+        arp::{ARP_ETHER_IP_LEN, ARP_OP_REPLY, ARP_OP_REQUEST, ArpPacket},
+        ethernet::EthernetAddress,
+    },
+};
+
+/// Resolved entries are forgotten after this long without being refreshed.
+const ARP_CACHE_TIMEOUT: Duration = Duration::from_secs(30);
+const ARP_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// Requests sent for an address before the datagrams waiting for it are dropped.
+const ARP_REQUEST_MAX: u32 = 3;
+/// Datagrams kept per unresolved address. The oldest is dropped when it is full.
+const ARP_PENDING_LIMIT: usize = 8;
+const ARP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpState {
+    /// A request was sent and no reply arrived yet.
+    Incomplete,
+    Resolved,
+    /// Added by `arp_add_static`. Never expires or changes.
+    Static,
+}
+
+#[derive(Debug)]
+struct ArpEntry {
+    state: ArpState,
+    pa: IpAddress,
+    ha: EthernetAddress,
+    dev: NetDeviceHandler,
+    updated: Instant,
+    requests: u32,
+    /// IP datagrams waiting for the address to be resolved.
+    pending: VecDeque<Vec<u8>>,
+}
+
+static ARP_CACHE: Mutex<Vec<ArpEntry>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct ArpProbe {
+    dev: NetDeviceHandler,
+    pa: IpAddress,
+    conflict: bool,
+}
+
+/// Addresses checked by `arp_probe_start` that are waiting for `arp_probe_finish`.
+static ARP_PROBES: Mutex<Vec<ArpProbe>> = Mutex::new(Vec::new());
+
+fn arp_cache_select<'a>(
+    cache: &'a mut [ArpEntry],
+    dev: &NetDeviceHandler,
+    pa: IpAddress,
+) -> Option<&'a mut ArpEntry> {
+    cache
+        .iter_mut()
+        .find(|entry| entry.pa == pa && entry.dev.private == dev.private)
+}
+
+/// Returns the IPv4 address of the device, which is the sender address of its ARP messages.
+fn arp_iface_addr(dev: &NetDeviceHandler) -> Option<IpAddress> {
+    let iface = net::net_device_get_iface(dev, NetInterfaceFamily::Ip)?;
+    let iface: &IpInterface = (&*iface).try_into().ok()?;
+    Some(iface.unicast())
+}
+
+fn arp_output(
+    dev: &NetDeviceHandler,
+    op: u16,
+    spa: IpAddress,
+    tha: EthernetAddress,
+    tpa: IpAddress,
+    dst: EthernetAddress,
+) -> UtcpResult<()> {
+    let sha = net_device_get!(dev).hw_addr().ok_or(UtcpErr::NotSupported(
+        "ARP on a device without address".into(),
+    ))?;
+    let mut buf = [0u8; ARP_ETHER_IP_LEN];
+    let mut packet = ArpPacket::new_unchecked(&mut buf[..]);
+    packet.set_ether_ip(NET_PROTOCOL_TYPE_IP);
+    packet.set_operation(op);
+    packet.set_sender_hw_addr(sha);
+    packet.set_sender_proto_addr(spa);
+    packet.set_target_hw_addr(tha);
+    packet.set_target_proto_addr(tpa);
+    log::debug!("{:?}", packet);
+    net::net_device_output(dev, NET_PROTOCOL_TYPE_ARP, &buf, &mut dst.0.clone())
+}
+
+fn arp_request(dev: &NetDeviceHandler, tpa: IpAddress) -> UtcpResult<()> {
+    let spa = arp_iface_addr(dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    arp_output(
+        dev,
+        ARP_OP_REQUEST,
+        spa,
+        EthernetAddress::ANY,
+        tpa,
+        EthernetAddress::BROADCAST,
+    )
+}
+
+fn arp_send_pending(dev: &NetDeviceHandler, ha: EthernetAddress, pending: VecDeque<Vec<u8>>) {
+    for datagram in pending {
+        if let Err(e) =
+            net::net_device_output(dev, NET_PROTOCOL_TYPE_IP, &datagram, &mut ha.0.clone())
+        {
+            log::warn!("failed to send a pending datagram: {}", e);
+        }
+    }
+}
+
+fn arp_input(data: &[u8], dev: &NetDeviceHandler) {
+    let packet = match ArpPacket::new_checked(data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    log::debug!("{:?}", packet);
+    let (spa, sha) = (packet.sender_proto_addr(), packet.sender_hw_addr());
+    arp_probe_check(dev, spa, packet.target_proto_addr());
+    let Some(addr) = arp_iface_addr(dev) else {
+        return;
+    };
+
+    let mut cache = ARP_CACHE.lock().unwrap();
+    // update an existing entry whoever the target is (RFC 826 "merge_flag")
+    let mut pending = VecDeque::new();
+    let merged = match arp_cache_select(&mut cache, dev, spa) {
+        Some(entry) if entry.state == ArpState::Static => true,
+        Some(entry) => {
+            entry.state = ArpState::Resolved;
+            entry.ha = sha;
+            entry.updated = Instant::now();
+            pending = std::mem::take(&mut entry.pending);
+            true
+        }
+        None => false,
+    };
+    let for_us = packet.target_proto_addr() == addr;
+    // probes come from 0.0.0.0, which is not worth caching
+    if for_us && !merged && spa != IP_ADDR_ANY {
+        cache.push(ArpEntry {
+            state: ArpState::Resolved,
+            pa: spa,
+            ha: sha,
+            dev: *dev,
+            updated: Instant::now(),
+            requests: 0,
+            pending: VecDeque::new(),
+        });
+        log::debug!("cache insert: pa={}, ha={}", spa, sha);
+    }
+    drop(cache);
+
+    arp_send_pending(dev, sha, pending);
+    if for_us
+        && packet.operation() == ARP_OP_REQUEST
+        && let Err(e) = arp_output(dev, ARP_OP_REPLY, addr, sha, spa, sha)
+    {
+        log::error!("failed to reply: {}", e);
+    }
+}
+
+/// Sends an IP datagram to `nexthop` on the device of `iface`. If the hardware address is not
+/// known yet, the datagram waits for the reply to a request.
+pub(crate) fn arp_output_datagram(
+    iface: &NetInterfaceHandler,
+    nexthop: IpAddress,
+    datagram: &[u8],
+) -> UtcpResult<()> {
+    let dev = &iface.dev;
+    let mut cache = ARP_CACHE.lock().unwrap();
+    match arp_cache_select(&mut cache, dev, nexthop) {
+        Some(entry) if entry.state != ArpState::Incomplete => {
+            let ha = entry.ha;
+            drop(cache);
+            net::net_device_output(dev, NET_PROTOCOL_TYPE_IP, datagram, &mut ha.0.clone())
+        }
+        Some(entry) => {
+            if entry.pending.len() >= ARP_PENDING_LIMIT {
+                entry.pending.pop_front();
+                log::warn!("pending queue full, dropped a datagram: pa={}", nexthop);
+            }
+            entry.pending.push_back(datagram.to_vec());
+            Ok(())
+        }
+        None => {
+            cache.push(ArpEntry {
+                state: ArpState::Incomplete,
+                pa: nexthop,
+                ha: EthernetAddress::ANY,
+                dev: *dev,
+                updated: Instant::now(),
+                requests: 1,
+                pending: VecDeque::from([datagram.to_vec()]),
+            });
+            drop(cache);
+            arp_request(dev, nexthop)
+        }
+    }
+}
+
+/// Another host uses a probed address if it sends from it, or if it probes it too (RFC 5227).
+fn arp_probe_check(dev: &NetDeviceHandler, spa: IpAddress, tpa: IpAddress) {
+    let mut probes = ARP_PROBES.lock().unwrap();
+    for probe in probes.iter_mut() {
+        if probe.dev.private == dev.private
+            && (spa == probe.pa || (spa == IP_ADDR_ANY && tpa == probe.pa))
+        {
+            log::warn!("address conflict: pa={}", probe.pa);
+            probe.conflict = true;
+        }
+    }
+}
+
+/// Sends an ARP probe (a request from 0.0.0.0) for `pa` to find out whether another host on
+/// the link uses it. Call `arp_probe_finish` after waiting for replies.
+pub fn arp_probe_start(dev: NetDeviceHandler, pa: IpAddress) -> UtcpResult<()> {
+    {
+        let mut probes = ARP_PROBES.lock().unwrap();
+        probes.retain(|probe| !(probe.pa == pa && probe.dev.private == dev.private));
+        probes.push(ArpProbe {
+            dev,
+            pa,
+            conflict: false,
+        });
+    }
+    arp_output(
+        &dev,
+        ARP_OP_REQUEST,
+        IP_ADDR_ANY,
+        EthernetAddress::ANY,
+        pa,
+        EthernetAddress::BROADCAST,
+    )
+}
+
+/// Ends a probe started by `arp_probe_start`. Returns true if another host uses `pa`.
+pub fn arp_probe_finish(dev: NetDeviceHandler, pa: IpAddress) -> bool {
+    let mut probes = ARP_PROBES.lock().unwrap();
+    let Some(index) = probes
+        .iter()
+        .position(|probe| probe.pa == pa && probe.dev.private == dev.private)
+    else {
+        return false;
+    };
+    probes.remove(index).conflict
+}
+
+/// Adds an entry that never expires.
+pub fn arp_add_static(dev: NetDeviceHandler, pa: IpAddress, ha: EthernetAddress) {
+    let mut cache = ARP_CACHE.lock().unwrap();
+    cache.retain(|entry| !(entry.pa == pa && entry.dev.private == dev.private));
+    cache.push(ArpEntry {
+        state: ArpState::Static,
+        pa,
+        ha,
+        dev,
+        updated: Instant::now(),
+        requests: 0,
+        pending: VecDeque::new(),
+    });
+}
+
+/// Returns the hardware address and state of a cached address.
+pub fn arp_lookup(pa: IpAddress) -> Option<(EthernetAddress, ArpState)> {
+    let cache = ARP_CACHE.lock().unwrap();
+    cache
+        .iter()
+        .find(|entry| entry.pa == pa)
+        .map(|entry| (entry.ha, entry.state))
+}
+
+/// Forgets the resolved entries that are too old and gives up on the addresses that were
+/// requested too many times. Returns the addresses to request again.
+fn arp_cache_expire(cache: &mut Vec<ArpEntry>, now: Instant) -> Vec<(NetDeviceHandler, IpAddress)> {
+    let mut requests = Vec::new();
+    cache.retain_mut(|entry| match entry.state {
+        ArpState::Static => true,
+        ArpState::Resolved => {
+            let alive = now.duration_since(entry.updated) < ARP_CACHE_TIMEOUT;
+            if !alive {
+                log::debug!("cache expired: pa={}, ha={}", entry.pa, entry.ha);
+            }
+            alive
+        }
+        ArpState::Incomplete => {
+            if now.duration_since(entry.updated) < ARP_REQUEST_INTERVAL {
+                return true;
+            }
+            if entry.requests >= ARP_REQUEST_MAX {
+                log::warn!(
+                    "no reply, dropped {} datagrams: pa={}",
+                    entry.pending.len(),
+                    entry.pa
+                );
+                return false;
+            }
+            entry.requests += 1;
+            entry.updated = now;
+            requests.push((entry.dev, entry.pa));
+            true
+        }
+    });
+    requests
+}
+
+fn arp_timer() {
+    let requests = arp_cache_expire(&mut ARP_CACHE.lock().unwrap(), Instant::now());
+    for (dev, pa) in requests {
+        if let Err(e) = arp_request(&dev, pa) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+pub fn arp_init() -> UtcpResult<()> {
+    net::net_protocol_register(NetProtocol::new(NET_PROTOCOL_TYPE_ARP, arp_input));
+    net::net_timer_register(ARP_TIMER_INTERVAL, arp_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+#[test]
+fn test_arp_cache_expire() {
+    let start = Instant::now();
+    let dev = NetDeviceHandler { private: 0 };
+    let entry = |state, pa: &str, requests| ArpEntry {
+        state,
+        pa: IpAddress::parse_from(pa),
+        ha: EthernetAddress([0x02, 0, 0, 0, 0, 1]),
+        dev,
+        updated: start,
+        requests,
+        pending: VecDeque::from([vec![0u8; 20]]),
+    };
+    let mut cache = vec![
+        entry(ArpState::Static, "192.0.2.1", 0),
+        entry(ArpState::Resolved, "192.0.2.2", 0),
+        entry(ArpState::Incomplete, "192.0.2.3", 1),
+    ];
+    let pa = |cache: &[ArpEntry]| cache.iter().map(|entry| entry.pa).collect::<Vec<_>>();
+
+    // nothing is due before the request interval
+    assert!(arp_cache_expire(&mut cache, start).is_empty());
+    assert_eq!(cache.len(), 3);
+
+    // unanswered requests are repeated up to the limit, then the entry and its datagrams go
+    let mut now = start;
+    for _ in 1..ARP_REQUEST_MAX {
+        now += ARP_REQUEST_INTERVAL;
+        let requests = arp_cache_expire(&mut cache, now);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1, IpAddress::parse_from("192.0.2.3"));
+    }
+    now += ARP_REQUEST_INTERVAL;
+    assert!(arp_cache_expire(&mut cache, now).is_empty());
+    assert_eq!(
+        pa(&cache),
+        [
+            IpAddress::parse_from("192.0.2.1"),
+            IpAddress::parse_from("192.0.2.2")
+        ]
+    );
+
+    // resolved entries last until the timeout, static ones forever
+    arp_cache_expire(
+        &mut cache,
+        start + ARP_CACHE_TIMEOUT - Duration::from_millis(1),
+    );
+    assert_eq!(cache.len(), 2);
+    arp_cache_expire(&mut cache, start + ARP_CACHE_TIMEOUT);
+    assert_eq!(pa(&cache), [IpAddress::parse_from("192.0.2.1")]);
+    arp_cache_expire(&mut cache, start + ARP_CACHE_TIMEOUT * 100);
+    assert_eq!(cache[0].state, ArpState::Static);
+}
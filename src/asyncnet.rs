@@ -0,0 +1,256 @@
+//! Future-based sockets. They are non-blocking sockets of the socket layer whose operations
+//! return `Poll::Pending` instead of `WouldBlock` and are woken by their own PCB once it is
+//! ready, so they run on any executor.
+
+use std::{
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpEndpoint,
+    poll::PollEvents,
+    socket::{self, SHUT_WR, SOCK_DGRAM, SOCK_NONBLOCK, SOCK_STREAM, SocketKind},
+    stdnet::{Fd, to_socket_addr},
+    tcp::{self, TcpState},
+};
+
+/// Default backlog of `AsyncTcpListener::bind`.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Runs a non-blocking operation on `fd`, registering the task to be woken when the socket
+/// becomes ready for `interest` if it would block.
+fn poll_io<T>(
+    cx: &mut Context<'_>,
+    fd: i32,
+    interest: PollEvents,
+    mut op: impl FnMut() -> UtcpResult<T>,
+) -> Poll<io::Result<T>> {
+    match op() {
+        Err(UtcpErr::WouldBlock) => {}
+        result => return Poll::Ready(result.map_err(io::Error::from)),
+    }
+    if let Err(e) = socket::register_waker(fd, interest, cx.waker()) {
+        return Poll::Ready(Err(e.into()));
+    }
+    // try again, so that a change between the attempt and the registration is not missed
+    match op() {
+        Err(UtcpErr::WouldBlock) => Poll::Pending,
+        result => Poll::Ready(result.map_err(io::Error::from)),
+    }
+}
+
+/// TCP connection over utcp with `AsyncRead`/`AsyncWrite`-style methods.
+#[derive(Debug)]
+pub struct AsyncTcpStream {
+    fd: Fd,
+}
+
+impl AsyncTcpStream {
+    pub async fn connect(addr: SocketAddr) -> io::Result<AsyncTcpStream> {
+        let ep = IpEndpoint::from(addr);
+        let fd = Fd::open(&ep, SOCK_STREAM | SOCK_NONBLOCK)?;
+        match socket::connect(fd.0, ep) {
+            Ok(()) | Err(UtcpErr::InProgress) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let stream = AsyncTcpStream { fd };
+        poll_fn(|cx| stream.poll_connect(cx)).await?;
+        Ok(stream)
+    }
+
+    fn poll_connect(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_io(cx, self.fd.0, PollEvents::WRITABLE, || {
+            let SocketKind::Tcp(id) = socket::socket_kind(self.fd.0)? else {
+                unreachable!();
+            };
+            match tcp::tcp_state(id)? {
+                TcpState::SynSent | TcpState::SynReceived => Err(UtcpErr::WouldBlock),
+                TcpState::Closed => {
+                    Err(tcp::tcp_take_error(id)?.unwrap_or(UtcpErr::ConnectionRefused))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getpeername(self.fd.0)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        Ok(socket::set_nodelay(self.fd.0, nodelay)?)
+    }
+
+    /// Reads into `buf`. Ready with 0 when the peer closed the connection.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        poll_io(cx, self.fd.0, PollEvents::READABLE, || {
+            socket::recv(self.fd.0, buf)
+        })
+    }
+
+    /// Queues `buf` for sending. Ready as soon as part of it fits in the send buffer.
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        poll_io(cx, self.fd.0, PollEvents::WRITABLE, || {
+            socket::send(self.fd.0, buf)
+        })
+    }
+
+    pub fn poll_flush(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // segments are handed to the stack by `poll_write`
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends a FIN once the queued data is sent.
+    pub fn poll_shutdown(&self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(socket::shutdown(self.fd.0, SHUT_WR).map_err(io::Error::from))
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let len = self.write(buf).await?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_shutdown(cx)).await
+    }
+}
+
+/// TCP listener over utcp whose `accept` is a future.
+#[derive(Debug)]
+pub struct AsyncTcpListener {
+    fd: Fd,
+}
+
+impl AsyncTcpListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<AsyncTcpListener> {
+        let ep = IpEndpoint::from(addr);
+        let fd = Fd::open(&ep, SOCK_STREAM | SOCK_NONBLOCK)?;
+        socket::bind(fd.0, ep)?;
+        socket::listen(fd.0, LISTEN_BACKLOG)?;
+        Ok(AsyncTcpListener { fd })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(AsyncTcpStream, SocketAddr)>> {
+        poll_io(cx, self.fd.0, PollEvents::READABLE, || {
+            let (fd, peer) = socket::accept(self.fd.0)?;
+            let fd = Fd(fd);
+            socket::set_nonblocking(fd.0, true)?;
+            Ok((AsyncTcpStream { fd }, to_socket_addr(peer)))
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream, SocketAddr)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+/// UDP socket over utcp whose receive operations are futures.
+#[derive(Debug)]
+pub struct AsyncUdpSocket {
+    fd: Fd,
+}
+
+impl AsyncUdpSocket {
+    pub fn bind(addr: SocketAddr) -> io::Result<AsyncUdpSocket> {
+        let ep = IpEndpoint::from(addr);
+        let fd = Fd::open(&ep, SOCK_DGRAM | SOCK_NONBLOCK)?;
+        socket::bind(fd.0, ep)?;
+        Ok(AsyncUdpSocket { fd })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        Ok(socket::connect(self.fd.0, IpEndpoint::from(addr))?)
+    }
+
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        poll_io(cx, self.fd.0, PollEvents::READABLE, || {
+            let (len, from) = socket::recvfrom(self.fd.0, buf)?;
+            Ok((len, to_socket_addr(from)))
+        })
+    }
+
+    /// Datagrams are handed to the device without waiting, so this never returns `Pending`.
+    pub fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        let ep = IpEndpoint::from(addr);
+        poll_io(cx, self.fd.0, PollEvents::WRITABLE, || {
+            socket::sendto(self.fd.0, buf, ep)
+        })
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| {
+            poll_io(cx, self.fd.0, PollEvents::READABLE, || {
+                socket::recv(self.fd.0, buf)
+            })
+        })
+        .await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_send_to(cx, buf, addr)).await
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| {
+            poll_io(cx, self.fd.0, PollEvents::WRITABLE, || {
+                socket::send(self.fd.0, buf)
+            })
+        })
+        .await
+    }
+}
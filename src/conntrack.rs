@@ -0,0 +1,532 @@
+//! Connection tracking of IPv4 datagrams, shared by the packet filter and NAT.
+//!
+//! A connection is known by the endpoints of its first datagram as the filter hooks see them:
+//! after NAT translated the destination of a received datagram, and before it translates the
+//! source of a sent one. Datagrams the other way are its replies. NAT keeps the mapping it
+//! applies with the connection, and both see the same state and the same timeouts.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::UtcpResult,
+    ip::{IP_PROTOCOL_ICMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP, IpAddress},
+    net::{self, NetDeviceHandler},
+    wire::{
+        icmp::{ICMP_HEADER_LEN, ICMP_TYPE_ECHO, ICMP_TYPE_ECHOREPLY},
+        tcp::{TcpFlags, TcpPacket},
+    },
+};
+
+/// Connections kept at most. Datagrams that would start one more are not tracked.
+const CONNTRACK_LIMIT: usize = 4096;
+const CONNTRACK_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long connections are kept without traffic, by protocol and state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConntrackTimeouts {
+    pub tcp_established: Duration,
+    /// TCP connections being opened or closed.
+    pub tcp_transitory: Duration,
+    /// UDP flows that have only seen traffic in one direction.
+    pub udp: Duration,
+    pub udp_stream: Duration,
+    /// ICMP and every other protocol.
+    pub icmp: Duration,
+}
+
+/// The minimums of RFC 5382 for TCP and RFC 4787 for UDP.
+const CONNTRACK_TIMEOUTS_DEFAULT: ConntrackTimeouts = ConntrackTimeouts {
+    tcp_established: Duration::from_secs(7440),
+    tcp_transitory: Duration::from_secs(240),
+    udp: Duration::from_secs(30),
+    udp_stream: Duration::from_secs(180),
+    icmp: Duration::from_secs(60),
+};
+
+impl Default for ConntrackTimeouts {
+    fn default() -> Self {
+        CONNTRACK_TIMEOUTS_DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConntrackState {
+    /// Only seen in the direction that created it.
+    New,
+    Established,
+    /// A TCP connection that was reset or closed in both directions.
+    Closing,
+}
+
+pub(crate) type Endpoint = (IpAddress, u16);
+
+/// The mapping NAT applies to a connection: `inside`, its source or its destination, is seen
+/// as `outside` beyond `dev`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConntrackNat {
+    pub(crate) dev: NetDeviceHandler,
+    pub(crate) inside: Endpoint,
+    pub(crate) outside: Endpoint,
+}
+
+/// A connection as its protocol and the endpoints of its datagrams, which are the same in
+/// both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ConntrackKey(u8, Endpoint, Endpoint);
+
+impl ConntrackKey {
+    pub(crate) fn new(protocol: u8, a: Endpoint, b: Endpoint) -> Self {
+        let order = |(addr, port): Endpoint| (u32::from(addr), port);
+        match order(a) <= order(b) {
+            true => Self(protocol, a, b),
+            false => Self(protocol, b, a),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ConntrackEntry {
+    pub(crate) protocol: u8,
+    pub(crate) src: Endpoint,
+    pub(crate) dst: Endpoint,
+    /// Set with `ConntrackTable::translate`, which indexes it.
+    nat: Option<ConntrackNat>,
+    pub(crate) state: ConntrackState,
+    replied: bool,
+    fin_src: bool,
+    fin_dst: bool,
+    pub(crate) updated: Instant,
+}
+
+impl ConntrackEntry {
+    pub(crate) fn new(protocol: u8, src: Endpoint, dst: Endpoint) -> Self {
+        Self {
+            protocol,
+            src,
+            dst,
+            nat: None,
+            state: ConntrackState::New,
+            replied: false,
+            fin_src: false,
+            fin_dst: false,
+            updated: Instant::now(),
+        }
+    }
+
+    fn key(&self) -> ConntrackKey {
+        ConntrackKey::new(self.protocol, self.src, self.dst)
+    }
+
+    pub(crate) fn nat(&self) -> Option<ConntrackNat> {
+        self.nat
+    }
+
+    /// Returns the mapping of a translated connection, and the endpoint of the remote host.
+    pub(crate) fn nat_mapping(&self) -> Option<(ConntrackNat, Endpoint)> {
+        let nat = self.nat?;
+        let remote = match nat.inside == self.src {
+            true => self.dst,
+            false => self.src,
+        };
+        Some((nat, remote))
+    }
+
+    pub(crate) fn timeout(&self, timeouts: &ConntrackTimeouts) -> Duration {
+        match (self.protocol, self.state) {
+            (IP_PROTOCOL_TCP, ConntrackState::Established) => timeouts.tcp_established,
+            (IP_PROTOCOL_TCP, _) => timeouts.tcp_transitory,
+            (IP_PROTOCOL_UDP, ConntrackState::New) => timeouts.udp,
+            (IP_PROTOCOL_UDP, _) => timeouts.udp_stream,
+            _ => timeouts.icmp,
+        }
+    }
+
+    pub(crate) fn is_alive(&self, timeouts: &ConntrackTimeouts, now: Instant) -> bool {
+        now.duration_since(self.updated) < self.timeout(timeouts)
+    }
+
+    /// Whether a datagram from `src` goes the other way than the first one did.
+    pub(crate) fn is_reply(&self, src: Endpoint) -> bool {
+        src != self.src
+    }
+
+    /// Whether a reply has been seen.
+    pub(crate) fn replied(&self) -> bool {
+        self.replied
+    }
+
+    /// Updates the state with the payload of a datagram of the connection. Tracking the same
+    /// datagram again, as the filter and NAT both may, changes nothing more.
+    pub(crate) fn track(&mut self, payload: &[u8], reply: bool) {
+        self.updated = Instant::now();
+        if reply {
+            self.replied = true;
+        }
+        if self.state == ConntrackState::New && self.replied {
+            self.state = ConntrackState::Established;
+        }
+        if self.protocol != IP_PROTOCOL_TCP {
+            return;
+        }
+        let Ok(tcp) = TcpPacket::new_checked(payload) else {
+            return;
+        };
+        if tcp.flags().contains(TcpFlags::FIN) {
+            match reply {
+                false => self.fin_src = true,
+                true => self.fin_dst = true,
+            }
+        }
+        if tcp.flags().contains(TcpFlags::RST) || (self.fin_src && self.fin_dst) {
+            self.state = ConntrackState::Closing;
+        }
+    }
+}
+
+pub(crate) struct ConntrackTable {
+    entries: HashMap<ConntrackKey, ConntrackEntry>,
+    /// Translated connections by the endpoints their datagrams have beyond the NAT device:
+    /// the outside one and the remote one.
+    translated: HashMap<ConntrackKey, ConntrackKey>,
+    /// Translated connections by protocol and outside endpoint.
+    outside: HashMap<(u8, Endpoint), Vec<ConntrackKey>>,
+    /// Translated connections by protocol, inside endpoint and outside address.
+    inside: HashMap<(u8, Endpoint, IpAddress), Vec<ConntrackKey>>,
+    pub(crate) timeouts: ConntrackTimeouts,
+}
+
+impl ConntrackTable {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            translated: HashMap::new(),
+            outside: HashMap::new(),
+            inside: HashMap::new(),
+            timeouts: CONNTRACK_TIMEOUTS_DEFAULT,
+        }
+    }
+
+    /// Every connection, including the ones that expired since the last timer run.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &ConntrackEntry> {
+        self.entries.values()
+    }
+
+    /// Returns the live connection of a datagram from `src` to `dst`, in either direction.
+    pub(crate) fn find(
+        &mut self,
+        protocol: u8,
+        src: Endpoint,
+        dst: Endpoint,
+        now: Instant,
+    ) -> Option<&mut ConntrackEntry> {
+        let timeouts = self.timeouts;
+        self.entries
+            .get_mut(&ConntrackKey::new(protocol, src, dst))
+            .filter(|entry| entry.is_alive(&timeouts, now))
+    }
+
+    /// Returns the live translated connection of a datagram between `remote` and `outside`,
+    /// the endpoint the inside one has beyond the NAT device.
+    pub(crate) fn find_translated(
+        &mut self,
+        protocol: u8,
+        remote: Endpoint,
+        outside: Endpoint,
+        now: Instant,
+    ) -> Option<&mut ConntrackEntry> {
+        let key = self
+            .translated
+            .get(&ConntrackKey::new(protocol, remote, outside))?;
+        let timeouts = self.timeouts;
+        self.entries
+            .get_mut(key)
+            .filter(|entry| entry.is_alive(&timeouts, now))
+    }
+
+    /// Returns the port of `addr` that `inside` is seen as through `dev`, if a live
+    /// connection gave it one already.
+    pub(crate) fn outside_port(
+        &self,
+        protocol: u8,
+        dev: &NetDeviceHandler,
+        inside: Endpoint,
+        addr: IpAddress,
+        now: Instant,
+    ) -> Option<u16> {
+        self.live(self.inside.get(&(protocol, inside, addr)), now)
+            .filter_map(|entry| entry.nat)
+            .find(|nat| nat.dev == *dev)
+            .map(|nat| nat.outside.1)
+    }
+
+    /// Whether a live translated connection uses `outside`.
+    pub(crate) fn outside_in_use(&self, protocol: u8, outside: Endpoint, now: Instant) -> bool {
+        self.live(self.outside.get(&(protocol, outside)), now)
+            .next()
+            .is_some()
+    }
+
+    fn live<'a>(
+        &'a self,
+        keys: Option<&'a Vec<ConntrackKey>>,
+        now: Instant,
+    ) -> impl Iterator<Item = &'a ConntrackEntry> {
+        keys.into_iter()
+            .flatten()
+            .filter_map(|key| self.entries.get(key))
+            .filter(move |entry| entry.is_alive(&self.timeouts, now))
+    }
+
+    /// Adds a connection, unless the table is full of live ones. A connection with the same
+    /// endpoints that expired is replaced.
+    pub(crate) fn insert(
+        &mut self,
+        entry: ConntrackEntry,
+        now: Instant,
+    ) -> Option<&mut ConntrackEntry> {
+        let key = entry.key();
+        self.remove(&key);
+        if self.entries.len() >= CONNTRACK_LIMIT {
+            self.expire(now);
+            if self.entries.len() >= CONNTRACK_LIMIT {
+                log::warn!("table full, not tracked: protocol={}", entry.protocol);
+                return None;
+            }
+        }
+        if let Some(nat) = entry.nat_mapping() {
+            self.index(key, nat);
+        }
+        Some(self.entries.entry(key).insert_entry(entry).into_mut())
+    }
+
+    /// Translates the connection of a datagram from `src` to `dst` with `nat`.
+    pub(crate) fn translate(
+        &mut self,
+        protocol: u8,
+        src: Endpoint,
+        dst: Endpoint,
+        nat: ConntrackNat,
+    ) -> Option<&mut ConntrackEntry> {
+        let key = ConntrackKey::new(protocol, src, dst);
+        let entry = self.entries.get_mut(&key)?;
+        let old = entry.nat_mapping();
+        entry.nat = Some(nat);
+        let mapping = entry.nat_mapping().unwrap();
+        if let Some(old) = old {
+            self.unindex(key, old);
+        }
+        self.index(key, mapping);
+        self.entries.get_mut(&key)
+    }
+
+    fn index(&mut self, key: ConntrackKey, (nat, remote): (ConntrackNat, Endpoint)) {
+        let protocol = key.0;
+        self.translated
+            .insert(ConntrackKey::new(protocol, remote, nat.outside), key);
+        self.outside
+            .entry((protocol, nat.outside))
+            .or_default()
+            .push(key);
+        self.inside
+            .entry((protocol, nat.inside, nat.outside.0))
+            .or_default()
+            .push(key);
+    }
+
+    fn unindex(&mut self, key: ConntrackKey, (nat, remote): (ConntrackNat, Endpoint)) {
+        let protocol = key.0;
+        self.translated
+            .remove(&ConntrackKey::new(protocol, remote, nat.outside));
+        unindex(&mut self.outside, (protocol, nat.outside), key);
+        unindex(&mut self.inside, (protocol, nat.inside, nat.outside.0), key);
+    }
+
+    fn remove(&mut self, key: &ConntrackKey) -> Option<ConntrackEntry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(mapping) = entry.nat_mapping() {
+            self.unindex(*key, mapping);
+        }
+        Some(entry)
+    }
+
+    /// Removes the connections that timed out.
+    fn expire(&mut self, now: Instant) {
+        let timeouts = self.timeouts;
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_alive(&timeouts, now))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in expired {
+            let entry = self.remove(&key).unwrap();
+            log::debug!(
+                "expired: protocol={}, src={}:{}, dst={}:{}",
+                entry.protocol,
+                entry.src.0,
+                entry.src.1,
+                entry.dst.0,
+                entry.dst.1
+            );
+        }
+    }
+}
+
+/// Removes `key` from the connections indexed by `index`.
+fn unindex<K: Eq + Hash>(
+    index: &mut HashMap<K, Vec<ConntrackKey>>,
+    index_key: K,
+    key: ConntrackKey,
+) {
+    if let Some(keys) = index.get_mut(&index_key) {
+        keys.retain(|k| *k != key);
+        if keys.is_empty() {
+            index.remove(&index_key);
+        }
+    }
+}
+
+pub(crate) static CONNTRACK_TABLE: LazyLock<Mutex<ConntrackTable>> =
+    LazyLock::new(|| Mutex::new(ConntrackTable::new()));
+
+/// Returns the ports that, with the addresses, identify the connection of a datagram: those of
+/// TCP and UDP, the id of ICMP echo requests as the source and of echo replies as the
+/// destination, and none for the rest.
+pub(crate) fn conntrack_ports(protocol: u8, payload: &[u8]) -> (u16, u16) {
+    let port = |off: usize| u16::from_be_bytes([payload[off], payload[off + 1]]);
+    match protocol {
+        IP_PROTOCOL_TCP | IP_PROTOCOL_UDP if payload.len() >= 4 => (port(0), port(2)),
+        IP_PROTOCOL_ICMP if payload.len() >= ICMP_HEADER_LEN => match payload[0] {
+            ICMP_TYPE_ECHO => (port(4), 0),
+            ICMP_TYPE_ECHOREPLY => (0, port(4)),
+            _ => (0, 0),
+        },
+        _ => (0, 0),
+    }
+}
+
+pub fn conntrack_timeouts() -> ConntrackTimeouts {
+    CONNTRACK_TABLE.lock().unwrap().timeouts
+}
+
+/// Changes the timeouts, including the ones of the connections already tracked.
+pub fn conntrack_set_timeouts(timeouts: ConntrackTimeouts) {
+    CONNTRACK_TABLE.lock().unwrap().timeouts = timeouts;
+}
+
+fn conntrack_timer() {
+    CONNTRACK_TABLE.lock().unwrap().expire(Instant::now());
+}
+
+pub fn conntrack_init() -> UtcpResult<()> {
+    net::net_timer_register(CONNTRACK_TIMER_INTERVAL, conntrack_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+#[test]
+fn test_conntrack_track() {
+    use crate::wire::tcp::TCP_HEADER_MIN_LEN;
+
+    let segment = |flags: TcpFlags| {
+        let mut buf = [0u8; TCP_HEADER_MIN_LEN];
+        let mut seg = TcpPacket::new_unchecked(&mut buf[..]);
+        seg.set_header_len(TCP_HEADER_MIN_LEN);
+        seg.set_flags(flags);
+        buf
+    };
+    let (client, server) = (
+        (IpAddress::parse_from("192.0.2.1"), 40000),
+        (IpAddress::parse_from("198.51.100.2"), 80),
+    );
+    let now = Instant::now();
+    let mut table = ConntrackTable::new();
+    let mut entry = ConntrackEntry::new(IP_PROTOCOL_TCP, client, server);
+    entry.track(&segment(TcpFlags::SYN), false);
+    table.insert(entry, now).unwrap();
+
+    // replies belong to the same connection, which they establish
+    let entry = table.find(IP_PROTOCOL_TCP, server, client, now).unwrap();
+    assert!(entry.is_reply(server));
+    entry.track(&segment(TcpFlags::SYN | TcpFlags::ACK), true);
+    assert_eq!(entry.state, ConntrackState::Established);
+    assert_eq!(
+        entry.timeout(&CONNTRACK_TIMEOUTS_DEFAULT),
+        CONNTRACK_TIMEOUTS_DEFAULT.tcp_established
+    );
+    assert!(table.find(IP_PROTOCOL_UDP, client, server, now).is_none());
+
+    // closed once both sides sent FIN, however often each is seen
+    let entry = table.find(IP_PROTOCOL_TCP, client, server, now).unwrap();
+    entry.track(&segment(TcpFlags::FIN | TcpFlags::ACK), false);
+    entry.track(&segment(TcpFlags::FIN | TcpFlags::ACK), false);
+    assert_eq!(entry.state, ConntrackState::Established);
+    entry.track(&segment(TcpFlags::FIN | TcpFlags::ACK), true);
+    assert_eq!(entry.state, ConntrackState::Closing);
+    let later = Instant::now() + CONNTRACK_TIMEOUTS_DEFAULT.tcp_transitory;
+    assert!(table.find(IP_PROTOCOL_TCP, client, server, later).is_none());
+
+    // echo requests and replies are told apart by the side of the id
+    let mut echo = [0u8; ICMP_HEADER_LEN];
+    echo[0] = ICMP_TYPE_ECHO;
+    echo[4..6].copy_from_slice(&0x1234u16.to_be_bytes());
+    assert_eq!(conntrack_ports(IP_PROTOCOL_ICMP, &echo), (0x1234, 0));
+    echo[0] = ICMP_TYPE_ECHOREPLY;
+    assert_eq!(conntrack_ports(IP_PROTOCOL_ICMP, &echo), (0, 0x1234));
+}
+
+#[test]
+fn test_conntrack_translate() {
+    let dev = NetDeviceHandler { private: 0 };
+    let (inside, remote, outside) = (
+        (IpAddress::parse_from("192.168.0.2"), 40000),
+        (IpAddress::parse_from("198.51.100.2"), 53),
+        (IpAddress::parse_from("192.0.2.1"), 50000),
+    );
+    let now = Instant::now();
+    let mut table = ConntrackTable::new();
+    table
+        .insert(ConntrackEntry::new(IP_PROTOCOL_UDP, inside, remote), now)
+        .unwrap();
+    assert!(
+        table
+            .find_translated(IP_PROTOCOL_UDP, remote, outside, now)
+            .is_none()
+    );
+    let nat = ConntrackNat {
+        dev,
+        inside,
+        outside,
+    };
+    table
+        .translate(IP_PROTOCOL_UDP, inside, remote, nat)
+        .unwrap();
+
+    // replies are found by the outside endpoint, in either direction
+    let entry = table
+        .find_translated(IP_PROTOCOL_UDP, outside, remote, now)
+        .unwrap();
+    assert_eq!(entry.nat_mapping().unwrap().1, remote);
+    assert!(
+        table
+            .find_translated(IP_PROTOCOL_TCP, remote, outside, now)
+            .is_none()
+    );
+    assert!(table.outside_in_use(IP_PROTOCOL_UDP, outside, now));
+    assert_eq!(
+        table.outside_port(IP_PROTOCOL_UDP, &dev, inside, outside.0, now),
+        Some(outside.1)
+    );
+
+    // expired mappings free their outside endpoint
+    let later = Instant::now() + CONNTRACK_TIMEOUTS_DEFAULT.udp;
+    assert!(!table.outside_in_use(IP_PROTOCOL_UDP, outside, later));
+    table.expire(later);
+    assert_eq!(table.entries().count(), 0);
+    assert!(table.translated.is_empty() && table.outside.is_empty() && table.inside.is_empty());
+}
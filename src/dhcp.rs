@@ -0,0 +1,669 @@
+//! DHCPv4 client (RFC 2131) for Ethernet-class devices.
+//!
+//! `dhcp_client_start` leases an address for a device and registers it with
+//! `ip_iface_register`, together with a default route through the router the server hands
+//! out. The lease is renewed at T1, rebound at T2 and the interface is removed if it expires.
+//! Before the address is used it is probed with ARP, and declined if another host answers.
+//!
+//! Messages are built and received below the UDP layer, since the device has no interface
+//! while the address is acquired.
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    arp,
+    error::{UtcpErr, UtcpResult},
+    ip::{
+        self, IP_ADDR_ANY, IP_ADDR_BROADCAST, IP_PROTOCOL_UDP, IP_TTL_DEFAULT, IpAddress,
+        IpInterface,
+    },
+    net::{self, NetDeviceHandler},
+    net_device_get,
+    utils::{self, XorShift32},
+    wire::{
+        dhcpv4::*,
+        ethernet::EthernetAddress,
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    },
+};
+
+const DHCP_RETRANSMIT_MIN: Duration = Duration::from_secs(4);
+const DHCP_RETRANSMIT_MAX: Duration = Duration::from_secs(64);
+/// Lower bound of the retransmission interval while renewing or rebinding.
+const DHCP_RENEW_RETRANSMIT_MIN: Duration = Duration::from_secs(60);
+/// REQUEST messages sent for an offer before starting over with DISCOVER.
+const DHCP_REQUEST_MAX: u32 = 4;
+/// Time to wait for a reply to the ARP probe of a leased address.
+const DHCP_PROBE_WAIT: Duration = Duration::from_secs(1);
+/// Delay before restarting after declining an address (RFC 2131 section 3.1).
+const DHCP_DECLINE_WAIT: Duration = Duration::from_secs(10);
+/// Used when the server does not send a lease time.
+const DHCP_LEASE_TIME_DEFAULT: Duration = Duration::from_secs(3600);
+const DHCP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+const DHCP_PARAMETERS: [u8; 7] = [
+    DHCP_OPT_SUBNET_MASK,
+    DHCP_OPT_ROUTER,
+    DHCP_OPT_DNS_SERVER,
+    DHCP_OPT_DOMAIN_NAME,
+    DHCP_OPT_LEASE_TIME,
+    DHCP_OPT_RENEWAL_TIME,
+    DHCP_OPT_REBINDING_TIME,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpState {
+    Init,
+    /// DISCOVER sent, waiting for an offer.
+    Selecting,
+    /// REQUEST sent for an offer, waiting for the acknowledgement.
+    Requesting,
+    /// The address is acknowledged and probed with ARP before it is used.
+    Probing,
+    Bound,
+    /// Extending the lease with the server that granted it.
+    Renewing,
+    /// Extending the lease with any server.
+    Rebinding,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub addr: IpAddress,
+    pub netmask: IpAddress,
+    pub router: Option<IpAddress>,
+    pub dns_servers: Vec<IpAddress>,
+    pub domain_name: Option<String>,
+    pub server: IpAddress,
+    pub lease_time: Duration,
+    /// T1, after which the lease is renewed.
+    pub renewal_time: Duration,
+    /// T2, after which the lease is rebound.
+    pub rebinding_time: Duration,
+}
+
+impl DhcpLease {
+    fn parse(packet: &DhcpPacket<&[u8]>) -> Option<Self> {
+        let addr = packet.yiaddr();
+        let server = packet.option_addr(DHCP_OPT_SERVER_ID)?;
+        if addr == IP_ADDR_ANY {
+            return None;
+        }
+        let lease_time = packet
+            .option_u32(DHCP_OPT_LEASE_TIME)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DHCP_LEASE_TIME_DEFAULT);
+        let option_time = |code, default: Duration| {
+            packet
+                .option_u32(code)
+                .map(|secs| Duration::from_secs(secs as u64))
+                .filter(|&time| time <= lease_time)
+                .unwrap_or(default)
+        };
+        let rebinding_time = option_time(DHCP_OPT_REBINDING_TIME, lease_time * 7 / 8);
+        let renewal_time = option_time(DHCP_OPT_RENEWAL_TIME, lease_time / 2).min(rebinding_time);
+        Some(Self {
+            addr,
+            netmask: packet
+                .option_addr(DHCP_OPT_SUBNET_MASK)
+                .unwrap_or_else(|| dhcp_default_netmask(addr)),
+            router: packet.option_addr(DHCP_OPT_ROUTER),
+            dns_servers: packet.option_addrs(DHCP_OPT_DNS_SERVER).collect(),
+            domain_name: packet
+                .option(DHCP_OPT_DOMAIN_NAME)
+                .map(|name| String::from_utf8_lossy(name).into_owned()),
+            server,
+            lease_time,
+            renewal_time,
+            rebinding_time,
+        })
+    }
+}
+
+/// Netmask of the address class, for servers that do not send one.
+fn dhcp_default_netmask(addr: IpAddress) -> IpAddress {
+    match addr.octets()[0] {
+        0..128 => IpAddress::parse_from("255.0.0.0"),
+        128..192 => IpAddress::parse_from("255.255.0.0"),
+        _ => IpAddress::parse_from("255.255.255.0"),
+    }
+}
+
+#[derive(Debug)]
+struct DhcpClient {
+    dev: NetDeviceHandler,
+    hwaddr: EthernetAddress,
+    state: DhcpState,
+    xid: u32,
+    rng: XorShift32,
+    /// When the current exchange started, for the `secs` field.
+    started: Instant,
+    /// Next retransmission or state change.
+    deadline: Instant,
+    interval: Duration,
+    requests: u32,
+    /// Offered lease while selecting, requesting and probing, the current lease afterwards.
+    lease: Option<DhcpLease>,
+    /// Whether `lease` is installed on the device.
+    configured: bool,
+    /// When the REQUEST that got the current lease was sent. Lease times count from here.
+    requested_at: Instant,
+}
+
+static DHCP_CLIENTS: Mutex<Vec<DhcpClient>> = Mutex::new(Vec::new());
+/// Notified when a client binds or loses its lease.
+static DHCP_COND: Condvar = Condvar::new();
+
+impl DhcpClient {
+    fn name(&self) -> &str {
+        net_device_get!(&self.dev).name()
+    }
+
+    fn restart(&mut self, now: Instant, delay: Duration) {
+        self.unconfigure();
+        self.state = DhcpState::Init;
+        self.lease = None;
+        self.deadline = now + delay;
+        DHCP_COND.notify_all();
+    }
+
+    fn configure(&mut self) -> UtcpResult<()> {
+        let lease = self.lease.as_ref().unwrap();
+        ip::ip_iface_register(self.dev, IpInterface::new(lease.addr, lease.netmask))?;
+        self.configured = true;
+        if let Some(router) = lease.router {
+            ip::ip_route_set_default_gateway(router)?;
+        }
+        log::info!(
+            "dev={}, bound: addr={}, netmask={}, router={:?}, dns={:?}, lease={:?}",
+            self.name(),
+            lease.addr,
+            lease.netmask,
+            lease.router,
+            lease.dns_servers,
+            lease.lease_time
+        );
+        Ok(())
+    }
+
+    fn unconfigure(&mut self) {
+        if !self.configured {
+            return;
+        }
+        self.configured = false;
+        if let Err(e) = ip::ip_iface_unregister(self.dev) {
+            log::error!("dev={}, {}", self.name(), e);
+        }
+    }
+
+    /// Starts a new exchange: a new transaction ID and the shortest retransmission interval.
+    fn begin(&mut self, state: DhcpState, now: Instant) {
+        self.state = state;
+        self.xid = self.rng.next_u32();
+        self.started = now;
+        self.interval = DHCP_RETRANSMIT_MIN;
+        self.requests = 0;
+    }
+
+    /// Schedules a retransmission with exponential backoff, randomized by up to a second.
+    fn backoff(&mut self, now: Instant) {
+        let jitter = Duration::from_millis(self.rng.next_u32() as u64 % 2000);
+        self.deadline = now + self.interval + jitter - Duration::from_secs(1);
+        self.interval = (self.interval * 2).min(DHCP_RETRANSMIT_MAX);
+    }
+
+    /// While renewing or rebinding, retransmits after half of the time left until `until`.
+    fn backoff_until(&mut self, now: Instant, until: Instant) {
+        let half = until.saturating_duration_since(now) / 2;
+        self.deadline = (now + half.max(DHCP_RENEW_RETRANSMIT_MIN)).min(until);
+    }
+
+    fn lease_times(&self) -> (Instant, Instant, Instant) {
+        let lease = self.lease.as_ref().unwrap();
+        let at = self.requested_at;
+        (
+            at + lease.renewal_time,
+            at + lease.rebinding_time,
+            at + lease.lease_time,
+        )
+    }
+
+    fn send(&self, ty: u8, now: Instant) -> UtcpResult<()> {
+        let lease = self.lease.as_ref();
+        let bound =
+            matches!(self.state, DhcpState::Renewing | DhcpState::Rebinding) || ty == DHCP_RELEASE;
+        let mut buf = vec![0u8; DHCP_HEADER_LEN];
+        let mut packet = DhcpPacket::new_unchecked(&mut buf[..]);
+        packet.set_header(DHCP_OP_REQUEST, self.xid, self.hwaddr);
+        let secs = now.duration_since(self.started).as_secs();
+        packet.set_secs(secs.min(u16::MAX as u64) as u16);
+        let ciaddr = match (bound, lease) {
+            (true, Some(lease)) => lease.addr,
+            _ => IP_ADDR_ANY,
+        };
+        packet.set_ciaddr(ciaddr);
+        if ciaddr == IP_ADDR_ANY {
+            packet.set_flags(DHCP_FLAG_BROADCAST);
+        }
+
+        let mut client_id = vec![DHCP_HTYPE_ETHER];
+        client_id.extend_from_slice(&self.hwaddr.0);
+        let mut options = DhcpOptionWriter::new(&mut buf);
+        options
+            .option_u8(DHCP_OPT_MESSAGE_TYPE, ty)
+            .option(DHCP_OPT_CLIENT_ID, &client_id);
+        // the offer is identified by the requested address and the server
+        if let (false, Some(lease)) = (bound, lease) {
+            options
+                .option_addrs(DHCP_OPT_REQUESTED_IP, &[lease.addr])
+                .option_addrs(DHCP_OPT_SERVER_ID, &[lease.server]);
+        }
+        if ty == DHCP_RELEASE
+            && let Some(lease) = lease
+        {
+            options.option_addrs(DHCP_OPT_SERVER_ID, &[lease.server]);
+        }
+        if matches!(ty, DHCP_DISCOVER | DHCP_REQUEST) {
+            options.option(DHCP_OPT_PARAMETER_LIST, &DHCP_PARAMETERS);
+        }
+        options.finish();
+        log::debug!(
+            "dev={}, {:?}",
+            self.name(),
+            DhcpPacket::new_unchecked(&buf[..])
+        );
+
+        // renewals and releases go to the server, everything else is broadcast
+        let unicast =
+            (self.state == DhcpState::Renewing && ty == DHCP_REQUEST) || ty == DHCP_RELEASE;
+        let dst = match (unicast, lease) {
+            (true, Some(lease)) => lease.server,
+            _ => IP_ADDR_BROADCAST,
+        };
+        let datagram = dhcp_datagram(ciaddr, dst, &buf);
+        match dst {
+            IP_ADDR_BROADCAST => ip::ip_output_broadcast(&self.dev, &datagram),
+            _ => ip::ip_output_raw(&datagram).map(|_| ()),
+        }
+    }
+
+    fn send_logged(&self, ty: u8, now: Instant) {
+        if let Err(e) = self.send(ty, now) {
+            log::warn!("dev={}, failed to send type={}: {}", self.name(), ty, e);
+        }
+    }
+
+    fn timeout(&mut self, now: Instant) {
+        match self.state {
+            DhcpState::Init => {
+                self.begin(DhcpState::Selecting, now);
+                self.send_logged(DHCP_DISCOVER, now);
+                self.backoff(now);
+            }
+            DhcpState::Selecting => {
+                self.send_logged(DHCP_DISCOVER, now);
+                self.backoff(now);
+            }
+            DhcpState::Requesting => {
+                if self.requests >= DHCP_REQUEST_MAX {
+                    log::warn!("dev={}, no reply to REQUEST", self.name());
+                    self.restart(now, Duration::ZERO);
+                    return;
+                }
+                self.requests += 1;
+                self.requested_at = now;
+                self.send_logged(DHCP_REQUEST, now);
+                self.backoff(now);
+            }
+            DhcpState::Probing => {
+                let addr = self.lease.as_ref().unwrap().addr;
+                if arp::arp_probe_finish(self.dev, addr) {
+                    log::warn!("dev={}, declined {}: in use", self.name(), addr);
+                    self.send_logged(DHCP_DECLINE, now);
+                    self.restart(now, DHCP_DECLINE_WAIT);
+                    return;
+                }
+                if let Err(e) = self.configure() {
+                    log::error!("dev={}, {}", self.name(), e);
+                    self.restart(now, DHCP_RETRANSMIT_MIN);
+                    return;
+                }
+                self.state = DhcpState::Bound;
+                self.deadline = self.lease_times().0;
+                DHCP_COND.notify_all();
+            }
+            DhcpState::Bound => {
+                self.begin(DhcpState::Renewing, now);
+                self.renew(now);
+            }
+            DhcpState::Renewing | DhcpState::Rebinding => self.renew(now),
+        }
+    }
+
+    /// Sends or retransmits the REQUEST that extends the lease, moving on to rebinding at T2
+    /// and giving the address up when the lease expires.
+    fn renew(&mut self, now: Instant) {
+        let (_, t2, expiry) = self.lease_times();
+        if now >= expiry {
+            log::warn!("dev={}, lease expired", self.name());
+            self.restart(now, Duration::ZERO);
+            return;
+        }
+        if now >= t2 && self.state == DhcpState::Renewing {
+            self.state = DhcpState::Rebinding;
+        }
+        let until = match self.state {
+            DhcpState::Renewing => t2,
+            _ => expiry,
+        };
+        self.send_logged(DHCP_REQUEST, now);
+        self.backoff_until(now, until);
+    }
+
+    fn input(&mut self, packet: &DhcpPacket<&[u8]>, now: Instant) {
+        let ty = packet.message_type();
+        match (self.state, ty) {
+            (DhcpState::Selecting, Some(DHCP_OFFER)) => {
+                let Some(offer) = DhcpLease::parse(packet) else {
+                    return;
+                };
+                log::info!(
+                    "dev={}, offered {} by {}",
+                    self.name(),
+                    offer.addr,
+                    offer.server
+                );
+                self.lease = Some(offer);
+                self.state = DhcpState::Requesting;
+                self.interval = DHCP_RETRANSMIT_MIN;
+                self.deadline = now;
+                self.timeout(now);
+            }
+            (DhcpState::Requesting, Some(DHCP_ACK)) => {
+                let Some(lease) = DhcpLease::parse(packet) else {
+                    return;
+                };
+                if let Err(e) = arp::arp_probe_start(self.dev, lease.addr) {
+                    log::warn!("dev={}, failed to probe: {}", self.name(), e);
+                }
+                self.lease = Some(lease);
+                self.state = DhcpState::Probing;
+                self.deadline = now + DHCP_PROBE_WAIT;
+            }
+            (DhcpState::Renewing | DhcpState::Rebinding, Some(DHCP_ACK)) => {
+                let Some(lease) = DhcpLease::parse(packet) else {
+                    return;
+                };
+                let changed = self.lease.as_ref().is_none_or(|current| {
+                    (current.addr, current.netmask, current.router)
+                        != (lease.addr, lease.netmask, lease.router)
+                });
+                self.lease = Some(lease);
+                self.requested_at = self.started;
+                if changed {
+                    self.unconfigure();
+                    if let Err(e) = self.configure() {
+                        log::error!("dev={}, {}", self.name(), e);
+                        self.restart(now, DHCP_RETRANSMIT_MIN);
+                        return;
+                    }
+                }
+                log::info!("dev={}, lease extended", self.name());
+                self.state = DhcpState::Bound;
+                self.deadline = self.lease_times().0;
+                DHCP_COND.notify_all();
+            }
+            (
+                DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding,
+                Some(DHCP_NAK),
+            ) => {
+                log::warn!("dev={}, NAK from the server", self.name());
+                self.restart(now, Duration::ZERO);
+            }
+            _ => log::debug!(
+                "dev={}, ignored type={:?} in {:?}",
+                self.name(),
+                ty,
+                self.state
+            ),
+        }
+    }
+}
+
+/// Wraps a DHCP message in UDP and IPv4 headers. The IPv4 header is completed by
+/// `ip_output_raw` or `ip_output_broadcast`. Messages from a server (`DHCP_OP_REPLY`) go from
+/// the server port to the client port, and the other way around for the rest.
+pub(crate) fn dhcp_datagram(src: IpAddress, dst: IpAddress, message: &[u8]) -> Vec<u8> {
+    let (src_port, dst_port) = match message.first() {
+        Some(&DHCP_OP_REPLY) => (DHCP_SERVER_PORT, DHCP_CLIENT_PORT),
+        _ => (DHCP_CLIENT_PORT, DHCP_SERVER_PORT),
+    };
+    let udp_len = UDP_HEADER_LEN + message.len();
+    let mut buf = vec![0u8; IPV4_HEADER_MIN_LEN + udp_len];
+    let mut udp = UdpPacket::new_unchecked(&mut buf[IPV4_HEADER_MIN_LEN..]);
+    udp.set_src_port(src_port);
+    udp.set_dst_port(dst_port);
+    udp.set_len(udp_len as u16);
+    udp.payload_mut().copy_from_slice(message);
+    udp.fill_checksum(src, dst);
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_ttl(IP_TTL_DEFAULT);
+    ip_hdr.set_protocol(IP_PROTOCOL_UDP);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    buf
+}
+
+/// Returns whether a UDP datagram received on `dev` is for the client running on it.
+pub(crate) fn dhcp_client_accepts(dev: &NetDeviceHandler, data: &[u8]) -> bool {
+    UdpPacket::new_checked(data)
+        .is_ok_and(|udp| udp.dst_port() == DHCP_CLIENT_PORT && udp.src_port() == DHCP_SERVER_PORT)
+        && DHCP_CLIENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|client| client.dev.private == dev.private)
+}
+
+/// Handles a UDP datagram to the DHCP client port if a client runs on `dev`. Returns false
+/// if the datagram is not for a client.
+pub(crate) fn dhcp_client_input(
+    dev: &NetDeviceHandler,
+    src: IpAddress,
+    dst: IpAddress,
+    data: &[u8],
+) -> bool {
+    let Ok(udp) = UdpPacket::new_checked(data) else {
+        return false;
+    };
+    if udp.dst_port() != DHCP_CLIENT_PORT || udp.src_port() != DHCP_SERVER_PORT {
+        return false;
+    }
+    let mut clients = DHCP_CLIENTS.lock().unwrap();
+    let Some(client) = clients
+        .iter_mut()
+        .find(|client| client.dev.private == dev.private)
+    else {
+        return false;
+    };
+    if !udp.verify_checksum(src, dst) {
+        log::error!("checksum mismatch: sum=0x{:04x}", udp.sum());
+        return true;
+    }
+    let packet = match DhcpPacket::new_checked(udp.payload()) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return true;
+        }
+    };
+    log::debug!("dev={}, {:?}", client.name(), packet);
+    if packet.op() != DHCP_OP_REPLY
+        || packet.xid() != client.xid
+        || packet.chaddr() != client.hwaddr
+    {
+        return true;
+    }
+    client.input(&packet, Instant::now());
+    true
+}
+
+fn dhcp_timer() {
+    let now = Instant::now();
+    let mut clients = DHCP_CLIENTS.lock().unwrap();
+    for client in clients.iter_mut().filter(|client| client.deadline <= now) {
+        client.timeout(now);
+    }
+}
+
+/// Starts acquiring an address for `dev`, which must have a hardware address. The device must
+/// not have an interface yet. Use `dhcp_client_wait` to wait for the lease.
+pub fn dhcp_client_start(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let hwaddr = net_device_get!(&dev)
+        .hw_addr()
+        .ok_or(UtcpErr::NotSupported(
+            "DHCP on a device without address".into(),
+        ))?;
+    if net::net_device_get_iface(&dev, net::NetInterfaceFamily::Ip).is_some() {
+        return Err(UtcpErr::InterfaceExists {
+            dev: net_device_get!(&dev).name().to_string(),
+            family: format!("{:?}", net::NetInterfaceFamily::Ip),
+        });
+    }
+    // transaction IDs follow from the seed, so it must not be guessable by an off-path host
+    let mut seed = [0u8; 4];
+    utils::getrandom(&mut seed)?;
+    let mut clients = DHCP_CLIENTS.lock().unwrap();
+    if clients
+        .iter()
+        .any(|client| client.dev.private == dev.private)
+    {
+        return Err(UtcpErr::InvalidArgument(
+            "DHCP client already running".into(),
+        ));
+    }
+    let now = Instant::now();
+    clients.push(DhcpClient {
+        dev,
+        hwaddr,
+        state: DhcpState::Init,
+        xid: 0,
+        rng: XorShift32::new(u32::from_ne_bytes(seed) | 1),
+        started: now,
+        deadline: now,
+        interval: DHCP_RETRANSMIT_MIN,
+        requests: 0,
+        lease: None,
+        configured: false,
+        requested_at: now,
+    });
+    log::info!("dev={}, started", net_device_get!(&dev).name());
+    Ok(())
+}
+
+/// Stops the client of `dev`, releasing its lease and removing the interface.
+pub fn dhcp_client_stop(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let mut clients = DHCP_CLIENTS.lock().unwrap();
+    let index = clients
+        .iter()
+        .position(|client| client.dev.private == dev.private)
+        .ok_or(UtcpErr::InvalidArgument("DHCP client not running".into()))?;
+    let mut client = clients.remove(index);
+    if client.configured {
+        client.send_logged(DHCP_RELEASE, Instant::now());
+        client.unconfigure();
+    }
+    DHCP_COND.notify_all();
+    log::info!("dev={}, stopped", client.name());
+    Ok(())
+}
+
+pub fn dhcp_client_state(dev: NetDeviceHandler) -> Option<DhcpState> {
+    let clients = DHCP_CLIENTS.lock().unwrap();
+    clients
+        .iter()
+        .find(|client| client.dev.private == dev.private)
+        .map(|client| client.state)
+}
+
+/// Returns the lease of `dev` while its address is in use.
+pub fn dhcp_client_lease(dev: NetDeviceHandler) -> Option<DhcpLease> {
+    let clients = DHCP_CLIENTS.lock().unwrap();
+    clients
+        .iter()
+        .find(|client| client.dev.private == dev.private && client.configured)
+        .and_then(|client| client.lease.clone())
+}
+
+/// Name servers offered in the leases in use, in the order the devices started.
+pub(crate) fn dhcp_dns_servers() -> Vec<IpAddress> {
+    let clients = DHCP_CLIENTS.lock().unwrap();
+    let mut servers = Vec::new();
+    for lease in clients
+        .iter()
+        .filter(|client| client.configured)
+        .filter_map(|client| client.lease.as_ref())
+    {
+        for server in &lease.dns_servers {
+            if !servers.contains(server) {
+                servers.push(*server);
+            }
+        }
+    }
+    servers
+}
+
+/// Waits until `dev` has a lease, for at most `timeout`.
+pub fn dhcp_client_wait(dev: NetDeviceHandler, timeout: Duration) -> UtcpResult<DhcpLease> {
+    let deadline = Instant::now() + timeout;
+    let mut clients = DHCP_CLIENTS.lock().unwrap();
+    loop {
+        let Some(client) = clients
+            .iter()
+            .find(|client| client.dev.private == dev.private)
+        else {
+            return Err(UtcpErr::InvalidArgument("DHCP client not running".into()));
+        };
+        if client.configured {
+            return Ok(client.lease.clone().unwrap());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UtcpErr::TimedOut);
+        }
+        clients = DHCP_COND.wait_timeout(clients, deadline - now).unwrap().0;
+    }
+}
+
+pub fn dhcp_init() -> UtcpResult<()> {
+    net::net_timer_register(DHCP_TIMER_INTERVAL, dhcp_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+#[test]
+fn test_dhcp_lease_parse() {
+    let mut buf = vec![0u8; DHCP_HEADER_LEN];
+    let mut packet = DhcpPacket::new_unchecked(&mut buf[..]);
+    packet.set_header(DHCP_OP_REPLY, 1, EthernetAddress([0x02, 0, 0, 0, 0, 1]));
+    packet.set_yiaddr(IpAddress::parse_from("10.0.0.10"));
+    DhcpOptionWriter::new(&mut buf)
+        .option_u8(DHCP_OPT_MESSAGE_TYPE, DHCP_ACK)
+        .option_addrs(DHCP_OPT_SERVER_ID, &[IpAddress::parse_from("10.0.0.1")])
+        .option_u32(DHCP_OPT_LEASE_TIME, 600)
+        // later than the lease time, so the default is used
+        .option_u32(DHCP_OPT_REBINDING_TIME, 700)
+        .finish();
+    let lease = DhcpLease::parse(&DhcpPacket::new_checked(&buf[..]).unwrap()).unwrap();
+    assert_eq!(lease.addr, IpAddress::parse_from("10.0.0.10"));
+    assert_eq!(lease.netmask, IpAddress::parse_from("255.0.0.0"));
+    assert_eq!(lease.router, None);
+    assert_eq!(lease.lease_time, Duration::from_secs(600));
+    assert_eq!(lease.renewal_time, Duration::from_secs(300));
+    assert_eq!(lease.rebinding_time, Duration::from_secs(525));
+}
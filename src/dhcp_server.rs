@@ -0,0 +1,762 @@
+//! DHCPv4 server (RFC 2131) for lab networks built from utcp instances.
+//!
+//! `dhcp_server_start` serves the subnet of the IP interface of a device. Addresses are leased
+//! from a pool, or from a reservation for the hardware address of the client, and clients get
+//! the same address back as long as nobody else took it. Leases can be kept in a file, so that
+//! they survive a restart.
+//!
+//! Replies go to the client address when the client has one and are broadcast otherwise,
+//! which every client accepts. Relay agents are not supported.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, Once},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    dhcp,
+    error::{UtcpErr, UtcpResult},
+    ip::{self, IP_ADDR_ANY, IP_ADDR_BROADCAST, IpAddress, IpInterface},
+    net::{self, NetDeviceHandler, NetInterfaceFamily},
+    net_device_get,
+    wire::{dhcpv4::*, ethernet::EthernetAddress, udp::UdpPacket},
+};
+
+const DHCP_SERVER_LEASE_TIME_DEFAULT: Duration = Duration::from_secs(3600);
+/// How long an offered address is kept for the client before it can be offered to others.
+const DHCP_SERVER_OFFER_HOLD: Duration = Duration::from_secs(60);
+/// How long an address declined by a client is left out of the pool.
+const DHCP_SERVER_DECLINE_HOLD: Duration = Duration::from_secs(600);
+
+pub struct DhcpServerConfig {
+    /// First address of the pool. The pool has to be inside the subnet of the interface.
+    pub pool_start: IpAddress,
+    /// Last address of the pool.
+    pub pool_end: IpAddress,
+    pub lease_time: Duration,
+    pub router: Option<IpAddress>,
+    pub dns_servers: Vec<IpAddress>,
+    pub domain_name: Option<String>,
+    pub mtu: Option<u16>,
+    /// Addresses that are only leased to the given hardware address. They have to be inside
+    /// the subnet but not necessarily inside the pool.
+    pub reservations: Vec<(EthernetAddress, IpAddress)>,
+    /// File that the leases are written to and loaded from.
+    pub lease_file: Option<PathBuf>,
+}
+
+impl DhcpServerConfig {
+    pub fn new(pool_start: IpAddress, pool_end: IpAddress) -> Self {
+        Self {
+            pool_start,
+            pool_end,
+            lease_time: DHCP_SERVER_LEASE_TIME_DEFAULT,
+            router: None,
+            dns_servers: Vec::new(),
+            domain_name: None,
+            mtu: None,
+            reservations: Vec::new(),
+            lease_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpServerLease {
+    pub hwaddr: EthernetAddress,
+    pub addr: IpAddress,
+    pub expires: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DhcpBindingState {
+    Offered,
+    Leased,
+    /// Declined by a client because another host uses it. Not bound to a client.
+    Declined,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DhcpBinding {
+    state: DhcpBindingState,
+    hwaddr: EthernetAddress,
+    addr: IpAddress,
+    expires: SystemTime,
+}
+
+struct DhcpServer {
+    dev: NetDeviceHandler,
+    /// Address of the interface, which is the server identifier.
+    addr: IpAddress,
+    netmask: IpAddress,
+    config: DhcpServerConfig,
+    /// Expired leases are kept so that a returning client gets its address back.
+    bindings: Vec<DhcpBinding>,
+}
+
+static DHCP_SERVERS: Mutex<Vec<DhcpServer>> = Mutex::new(Vec::new());
+
+/// Lease files waiting for the writer thread, so that neither the intr thread nor the lock
+/// of the servers waits for the disk.
+struct DhcpServerSaves {
+    /// Newest contents of each file.
+    pending: Vec<(PathBuf, String)>,
+    writing: bool,
+}
+
+static DHCP_SERVER_SAVES: Mutex<DhcpServerSaves> = Mutex::new(DhcpServerSaves {
+    pending: Vec::new(),
+    writing: false,
+});
+static DHCP_SERVER_SAVES_COND: Condvar = Condvar::new();
+static DHCP_SERVER_WRITER: Once = Once::new();
+
+/// A message to send in reply to a client.
+struct DhcpServerReply {
+    message: Vec<u8>,
+    dst: IpAddress,
+}
+
+impl DhcpServer {
+    fn in_subnet(&self, addr: IpAddress) -> bool {
+        let mask = u32::from(self.netmask);
+        let host = u32::from(addr) & !mask;
+        u32::from(addr) & mask == u32::from(self.addr) & mask && host != 0 && host != !mask
+    }
+
+    fn reservation(&self, hwaddr: EthernetAddress) -> Option<IpAddress> {
+        self.config
+            .reservations
+            .iter()
+            .find(|(reserved, _)| *reserved == hwaddr)
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Whether `addr` can be leased to `hwaddr`.
+    fn available(&self, addr: IpAddress, hwaddr: EthernetAddress, now: SystemTime) -> bool {
+        if !self.in_subnet(addr) || addr == self.addr || Some(addr) == self.config.router {
+            return false;
+        }
+        if let Some(reserved) = self.reservation(hwaddr) {
+            return addr == reserved;
+        }
+        let pool = u32::from(self.config.pool_start)..=u32::from(self.config.pool_end);
+        pool.contains(&u32::from(addr))
+            && !self
+                .config
+                .reservations
+                .iter()
+                .any(|(_, reserved)| *reserved == addr)
+            && !self.bindings.iter().any(|binding| {
+                binding.addr == addr
+                    && (binding.hwaddr != hwaddr || binding.state == DhcpBindingState::Declined)
+                    && binding.expires > now
+            })
+    }
+
+    /// Chooses an address for `hwaddr`: its reservation, its previous address, the address it
+    /// asks for, or the first free one in the pool.
+    fn allocate(
+        &self,
+        hwaddr: EthernetAddress,
+        requested: Option<IpAddress>,
+        now: SystemTime,
+    ) -> Option<IpAddress> {
+        if let Some(reserved) = self.reservation(hwaddr) {
+            return Some(reserved);
+        }
+        let previous = self
+            .bindings
+            .iter()
+            .find(|binding| binding.hwaddr == hwaddr && binding.state != DhcpBindingState::Declined)
+            .map(|binding| binding.addr);
+        if let Some(addr) = previous
+            .into_iter()
+            .chain(requested)
+            .find(|&addr| self.available(addr, hwaddr, now))
+        {
+            return Some(addr);
+        }
+        (u32::from(self.config.pool_start)..=u32::from(self.config.pool_end))
+            .map(IpAddress::from)
+            .find(|&addr| {
+                self.available(addr, hwaddr, now)
+                    && !self.bindings.iter().any(|binding| binding.addr == addr)
+            })
+            // reuse the expired lease of another client only when nothing else is left
+            .or_else(|| {
+                (u32::from(self.config.pool_start)..=u32::from(self.config.pool_end))
+                    .map(IpAddress::from)
+                    .find(|&addr| self.available(addr, hwaddr, now))
+            })
+    }
+
+    fn bind(
+        &mut self,
+        state: DhcpBindingState,
+        hwaddr: EthernetAddress,
+        addr: IpAddress,
+        expires: SystemTime,
+    ) {
+        self.bindings
+            .retain(|binding| binding.hwaddr != hwaddr && binding.addr != addr);
+        self.bindings.push(DhcpBinding {
+            state,
+            hwaddr,
+            addr,
+            expires,
+        });
+    }
+
+    fn leases(&self, now: SystemTime) -> impl Iterator<Item = DhcpServerLease> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |binding| {
+                binding.state == DhcpBindingState::Leased && binding.expires > now
+            })
+            .map(|binding| DhcpServerLease {
+                hwaddr: binding.hwaddr,
+                addr: binding.addr,
+                expires: binding.expires,
+            })
+    }
+
+    fn reply(&self, request: &DhcpPacket<&[u8]>, ty: u8, yiaddr: IpAddress) -> Vec<u8> {
+        let mut buf = vec![0u8; DHCP_HEADER_LEN];
+        let mut packet = DhcpPacket::new_unchecked(&mut buf[..]);
+        packet.set_header(DHCP_OP_REPLY, request.xid(), request.chaddr());
+        packet.set_flags(request.flags());
+        packet.set_giaddr(request.giaddr());
+        if ty != DHCP_NAK {
+            packet.set_ciaddr(request.ciaddr());
+            packet.set_yiaddr(yiaddr);
+        }
+        let mut options = DhcpOptionWriter::new(&mut buf);
+        options
+            .option_u8(DHCP_OPT_MESSAGE_TYPE, ty)
+            .option_addrs(DHCP_OPT_SERVER_ID, &[self.addr]);
+        if ty == DHCP_NAK {
+            options.finish();
+            return buf;
+        }
+        // INFORM is answered without a lease, since the client has its address already
+        if yiaddr != IP_ADDR_ANY {
+            let lease_time = self.config.lease_time.as_secs().min(u32::MAX as u64) as u32;
+            options
+                .option_u32(DHCP_OPT_LEASE_TIME, lease_time)
+                .option_u32(DHCP_OPT_RENEWAL_TIME, lease_time / 2)
+                .option_u32(DHCP_OPT_REBINDING_TIME, (lease_time as u64 * 7 / 8) as u32);
+        }
+        options.option_addrs(DHCP_OPT_SUBNET_MASK, &[self.netmask]);
+        if let Some(router) = self.config.router {
+            options.option_addrs(DHCP_OPT_ROUTER, &[router]);
+        }
+        if !self.config.dns_servers.is_empty() {
+            options.option_addrs(DHCP_OPT_DNS_SERVER, &self.config.dns_servers);
+        }
+        if let Some(domain_name) = &self.config.domain_name {
+            options.option(DHCP_OPT_DOMAIN_NAME, domain_name.as_bytes());
+        }
+        if let Some(mtu) = self.config.mtu {
+            options.option(DHCP_OPT_INTERFACE_MTU, &mtu.to_be_bytes());
+        }
+        options.finish();
+        buf
+    }
+
+    /// Handles a message from a client. Returns the reply, if any, and whether the leases
+    /// changed.
+    fn input(
+        &mut self,
+        request: &DhcpPacket<&[u8]>,
+        now: SystemTime,
+    ) -> (Option<DhcpServerReply>, bool) {
+        self.bindings
+            .retain(|binding| binding.state == DhcpBindingState::Leased || binding.expires > now);
+        let hwaddr = request.chaddr();
+        let server_id = request.option_addr(DHCP_OPT_SERVER_ID);
+        let requested = request.option_addr(DHCP_OPT_REQUESTED_IP);
+        let ciaddr = request.ciaddr();
+        // replies to clients with an address are unicast, the rest are broadcast
+        let dst = match ciaddr {
+            IP_ADDR_ANY => IP_ADDR_BROADCAST,
+            ciaddr => ciaddr,
+        };
+        let reply = |server: &Self, ty, yiaddr| {
+            Some(DhcpServerReply {
+                message: server.reply(request, ty, yiaddr),
+                dst: if ty == DHCP_NAK {
+                    IP_ADDR_BROADCAST
+                } else {
+                    dst
+                },
+            })
+        };
+
+        match request.message_type() {
+            Some(DHCP_DISCOVER) => {
+                let Some(addr) = self.allocate(hwaddr, requested, now) else {
+                    log::warn!("no address left for hwaddr={}", hwaddr);
+                    return (None, false);
+                };
+                let leased = self.bindings.iter().any(|binding| {
+                    binding.hwaddr == hwaddr
+                        && binding.addr == addr
+                        && binding.state == DhcpBindingState::Leased
+                        && binding.expires > now
+                });
+                if !leased {
+                    self.bind(
+                        DhcpBindingState::Offered,
+                        hwaddr,
+                        addr,
+                        now + DHCP_SERVER_OFFER_HOLD,
+                    );
+                }
+                log::debug!("offer: hwaddr={}, addr={}", hwaddr, addr);
+                (reply(self, DHCP_OFFER, addr), false)
+            }
+            Some(DHCP_REQUEST) => {
+                // the client chose another server, so its offer is free again
+                if server_id.is_some_and(|id| id != self.addr) {
+                    self.bindings.retain(|binding| {
+                        !(binding.hwaddr == hwaddr && binding.state == DhcpBindingState::Offered)
+                    });
+                    return (None, false);
+                }
+                // SELECTING and INIT-REBOOT name the address, RENEWING and REBINDING use ciaddr
+                let Some(addr) = requested.or((ciaddr != IP_ADDR_ANY).then_some(ciaddr)) else {
+                    return (None, false);
+                };
+                if !self.available(addr, hwaddr, now) {
+                    log::info!("NAK: hwaddr={}, addr={}", hwaddr, addr);
+                    return (reply(self, DHCP_NAK, IP_ADDR_ANY), false);
+                }
+                self.bind(
+                    DhcpBindingState::Leased,
+                    hwaddr,
+                    addr,
+                    now + self.config.lease_time,
+                );
+                log::info!("leased: hwaddr={}, addr={}", hwaddr, addr);
+                (reply(self, DHCP_ACK, addr), true)
+            }
+            Some(DHCP_DECLINE) if server_id == Some(self.addr) => {
+                let Some(addr) = requested else {
+                    return (None, false);
+                };
+                // only the client the address was offered or leased to may decline it
+                if !self.bindings.iter().any(|binding| {
+                    binding.addr == addr
+                        && binding.hwaddr == hwaddr
+                        && binding.state != DhcpBindingState::Declined
+                }) {
+                    log::warn!("ignored a decline: hwaddr={}, addr={}", hwaddr, addr);
+                    return (None, false);
+                }
+                log::warn!("declined: hwaddr={}, addr={}", hwaddr, addr);
+                self.bindings.retain(|binding| binding.addr != addr);
+                self.bindings.push(DhcpBinding {
+                    state: DhcpBindingState::Declined,
+                    hwaddr: EthernetAddress::ANY,
+                    addr,
+                    expires: now + DHCP_SERVER_DECLINE_HOLD,
+                });
+                (None, true)
+            }
+            Some(DHCP_RELEASE) if server_id == Some(self.addr) => {
+                // keep the binding so that the client gets the same address next time
+                let mut changed = false;
+                for binding in self.bindings.iter_mut().filter(|binding| {
+                    binding.hwaddr == hwaddr
+                        && binding.addr == ciaddr
+                        && binding.state == DhcpBindingState::Leased
+                }) {
+                    log::info!("released: hwaddr={}, addr={}", hwaddr, ciaddr);
+                    binding.expires = now;
+                    changed = true;
+                }
+                (None, changed)
+            }
+            Some(DHCP_INFORM) if ciaddr != IP_ADDR_ANY => {
+                (reply(self, DHCP_ACK, IP_ADDR_ANY), false)
+            }
+            ty => {
+                log::debug!("ignored type={:?}", ty);
+                (None, false)
+            }
+        }
+    }
+
+    /// Hands the leases to the writer thread.
+    fn save(&self) {
+        let Some(path) = &self.config.lease_file else {
+            return;
+        };
+        let mut text = String::new();
+        for lease in self.leases(SystemTime::now()) {
+            let expires = lease
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            text += &format!("{} {} {}\n", lease.hwaddr, lease.addr, expires);
+        }
+        dhcp_server_save_later(path.clone(), text);
+    }
+
+    /// Reads leases written by `save`. Expired leases and addresses outside the subnet are
+    /// skipped.
+    fn load(&mut self, now: SystemTime) -> UtcpResult<()> {
+        let Some(path) = &self.config.lease_file else {
+            return Ok(());
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [hwaddr, addr, expires] = fields[..] else {
+                continue;
+            };
+            let (Ok(hwaddr), Ok(addr), Ok(expires)) = (
+                hwaddr.parse::<EthernetAddress>(),
+                addr.parse::<IpAddress>(),
+                expires.parse::<u64>(),
+            ) else {
+                log::warn!("skipped a lease: {}", line);
+                continue;
+            };
+            let expires = UNIX_EPOCH + Duration::from_secs(expires);
+            if expires > now && self.available(addr, hwaddr, now) {
+                self.bind(DhcpBindingState::Leased, hwaddr, addr, expires);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dhcp_server_save_later(path: PathBuf, text: String) {
+    DHCP_SERVER_WRITER.call_once(|| {
+        std::thread::spawn(dhcp_server_writer);
+    });
+    let mut saves = DHCP_SERVER_SAVES.lock().unwrap();
+    saves.pending.retain(|(pending, _)| *pending != path);
+    saves.pending.push((path, text));
+    DHCP_SERVER_SAVES_COND.notify_all();
+}
+
+/// Waits until the lease files handed to the writer thread are written.
+fn dhcp_server_flush() {
+    let saves = DHCP_SERVER_SAVES.lock().unwrap();
+    let _saves = DHCP_SERVER_SAVES_COND
+        .wait_while(saves, |saves| !saves.pending.is_empty() || saves.writing)
+        .unwrap();
+}
+
+fn dhcp_server_writer() {
+    let mut saves = DHCP_SERVER_SAVES.lock().unwrap();
+    loop {
+        saves = DHCP_SERVER_SAVES_COND
+            .wait_while(saves, |saves| saves.pending.is_empty())
+            .unwrap();
+        let pending = std::mem::take(&mut saves.pending);
+        saves.writing = true;
+        drop(saves);
+        for (path, text) in pending {
+            if let Err(e) = dhcp_server_write(&path, &text) {
+                log::error!("failed to save the leases: {}", e);
+            }
+        }
+        saves = DHCP_SERVER_SAVES.lock().unwrap();
+        saves.writing = false;
+        DHCP_SERVER_SAVES_COND.notify_all();
+    }
+}
+
+/// Replaces the file at once, so that a crash does not leave half of it.
+fn dhcp_server_write(path: &Path, text: &str) -> UtcpResult<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Returns whether a UDP datagram received on `dev` is for the server running on it.
+pub(crate) fn dhcp_server_accepts(dev: &NetDeviceHandler, data: &[u8]) -> bool {
+    UdpPacket::new_checked(data)
+        .is_ok_and(|udp| udp.dst_port() == DHCP_SERVER_PORT && udp.src_port() == DHCP_CLIENT_PORT)
+        && DHCP_SERVERS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|server| server.dev.private == dev.private)
+}
+
+/// Handles a UDP datagram to the DHCP server port if a server runs on `dev`. Returns false
+/// if the datagram is not for a server.
+pub(crate) fn dhcp_server_input(
+    dev: &NetDeviceHandler,
+    src: IpAddress,
+    dst: IpAddress,
+    data: &[u8],
+) -> bool {
+    let Ok(udp) = UdpPacket::new_checked(data) else {
+        return false;
+    };
+    if udp.dst_port() != DHCP_SERVER_PORT || udp.src_port() != DHCP_CLIENT_PORT {
+        return false;
+    }
+    let mut servers = DHCP_SERVERS.lock().unwrap();
+    let Some(server) = servers
+        .iter_mut()
+        .find(|server| server.dev.private == dev.private)
+    else {
+        return false;
+    };
+    if !udp.verify_checksum(src, dst) {
+        log::error!("checksum mismatch: sum=0x{:04x}", udp.sum());
+        return true;
+    }
+    let packet = match DhcpPacket::new_checked(udp.payload()) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return true;
+        }
+    };
+    log::debug!("dev={}, {:?}", net_device_get!(dev).name(), packet);
+    if packet.op() != DHCP_OP_REQUEST {
+        return true;
+    }
+    let (reply, changed) = server.input(&packet, SystemTime::now());
+    if changed {
+        server.save();
+    }
+    if let Some(reply) = reply {
+        let datagram = dhcp::dhcp_datagram(server.addr, reply.dst, &reply.message);
+        let result = match reply.dst {
+            IP_ADDR_BROADCAST => ip::ip_output_broadcast(dev, &datagram),
+            _ => ip::ip_output_raw(&datagram).map(|_| ()),
+        };
+        if let Err(e) = result {
+            log::warn!("failed to send a reply: {}", e);
+        }
+    }
+    true
+}
+
+/// Starts serving the subnet of the IP interface of `dev`.
+pub fn dhcp_server_start(dev: NetDeviceHandler, config: DhcpServerConfig) -> UtcpResult<()> {
+    let iface =
+        net::net_device_get_iface(&dev, NetInterfaceFamily::Ip).ok_or(UtcpErr::AddrNotAvailable)?;
+    let iface: &IpInterface = (&*iface).try_into()?;
+    let mut server = DhcpServer {
+        dev,
+        addr: iface.unicast(),
+        netmask: iface.netmask(),
+        config,
+        bindings: Vec::new(),
+    };
+    let config = &server.config;
+    if u32::from(config.pool_start) > u32::from(config.pool_end)
+        || !server.in_subnet(config.pool_start)
+        || !server.in_subnet(config.pool_end)
+    {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "pool {}-{} is not inside the subnet of {}",
+            config.pool_start, config.pool_end, server.addr
+        )));
+    }
+    if let Some((_, addr)) = config
+        .reservations
+        .iter()
+        .find(|(_, addr)| !server.in_subnet(*addr))
+    {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "reservation {} is not inside the subnet of {}",
+            addr, server.addr
+        )));
+    }
+    server.load(SystemTime::now())?;
+
+    let mut servers = DHCP_SERVERS.lock().unwrap();
+    if servers
+        .iter()
+        .any(|server| server.dev.private == dev.private)
+    {
+        return Err(UtcpErr::InvalidArgument(
+            "DHCP server already running".into(),
+        ));
+    }
+    log::info!(
+        "dev={}, serving {}-{}, {} leases loaded",
+        net_device_get!(&dev).name(),
+        server.config.pool_start,
+        server.config.pool_end,
+        server.bindings.len()
+    );
+    servers.push(server);
+    Ok(())
+}
+
+/// Stops the server on `dev`. The leases stay in the lease file.
+pub fn dhcp_server_stop(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let mut servers = DHCP_SERVERS.lock().unwrap();
+    let index = servers
+        .iter()
+        .position(|server| server.dev.private == dev.private)
+        .ok_or_else(|| UtcpErr::InvalidArgument("DHCP server not running".into()))?;
+    let server = servers.remove(index);
+    drop(servers);
+    server.save();
+    dhcp_server_flush();
+    Ok(())
+}
+
+/// Returns the leases in use on `dev`.
+pub fn dhcp_server_leases(dev: NetDeviceHandler) -> Vec<DhcpServerLease> {
+    let servers = DHCP_SERVERS.lock().unwrap();
+    servers
+        .iter()
+        .find(|server| server.dev.private == dev.private)
+        .map(|server| server.leases(SystemTime::now()).collect())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_dhcp_server() {
+    let mut server = DhcpServer {
+        dev: NetDeviceHandler { private: 0 },
+        addr: IpAddress::parse_from("10.0.0.1"),
+        netmask: IpAddress::parse_from("255.255.255.0"),
+        config: DhcpServerConfig {
+            router: Some(IpAddress::parse_from("10.0.0.1")),
+            mtu: Some(1400),
+            reservations: vec![(
+                EthernetAddress([0x02, 0, 0, 0, 0, 9]),
+                IpAddress::parse_from("10.0.0.9"),
+            )],
+            lease_file: Some(
+                std::env::temp_dir().join(format!("utcp-dhcp-leases-{}", std::process::id())),
+            ),
+            ..DhcpServerConfig::new(
+                IpAddress::parse_from("10.0.0.100"),
+                IpAddress::parse_from("10.0.0.101"),
+            )
+        },
+        bindings: Vec::new(),
+    };
+    let now = SystemTime::now();
+    let message = |ty: u8, hwaddr: u8, options: &[(u8, IpAddress)]| {
+        let mut buf = vec![0u8; DHCP_HEADER_LEN];
+        DhcpPacket::new_unchecked(&mut buf[..]).set_header(
+            DHCP_OP_REQUEST,
+            hwaddr as u32,
+            EthernetAddress([0x02, 0, 0, 0, 0, hwaddr]),
+        );
+        let mut writer = DhcpOptionWriter::new(&mut buf);
+        writer.option_u8(DHCP_OPT_MESSAGE_TYPE, ty);
+        for &(code, addr) in options {
+            writer.option_addrs(code, &[addr]);
+        }
+        writer.finish();
+        buf
+    };
+    let exchange = |server: &mut DhcpServer, buf: Vec<u8>| {
+        let (reply, _) = server.input(&DhcpPacket::new_checked(&buf[..]).unwrap(), now);
+        reply.map(|reply| {
+            let packet = DhcpPacket::new_checked(reply.message).unwrap();
+            (
+                packet.message_type().unwrap(),
+                packet.yiaddr(),
+                reply.dst,
+                packet,
+            )
+        })
+    };
+    let server_id = (DHCP_OPT_SERVER_ID, server.addr);
+    let addr = |s| IpAddress::parse_from(s);
+
+    // DISCOVER, OFFER, REQUEST, ACK
+    let (ty, offered, dst, offer) = exchange(&mut server, message(DHCP_DISCOVER, 1, &[])).unwrap();
+    assert_eq!(
+        (ty, offered, dst),
+        (DHCP_OFFER, addr("10.0.0.100"), IP_ADDR_BROADCAST)
+    );
+    assert_eq!(offer.option_addr(DHCP_OPT_ROUTER), Some(server.addr));
+    assert_eq!(
+        offer.option(DHCP_OPT_INTERFACE_MTU),
+        Some(&1400u16.to_be_bytes()[..])
+    );
+    let request = message(
+        DHCP_REQUEST,
+        1,
+        &[server_id, (DHCP_OPT_REQUESTED_IP, offered)],
+    );
+    let (ty, acked, ..) = exchange(&mut server, request).unwrap();
+    assert_eq!((ty, acked), (DHCP_ACK, offered));
+
+    // the next client gets the next address, the reserved one gets its reservation
+    let (_, second, ..) = exchange(&mut server, message(DHCP_DISCOVER, 2, &[])).unwrap();
+    assert_eq!(second, addr("10.0.0.101"));
+    let (_, reserved, ..) = exchange(&mut server, message(DHCP_DISCOVER, 9, &[])).unwrap();
+    assert_eq!(reserved, addr("10.0.0.9"));
+
+    // the pool is exhausted while the offer is held
+    assert!(exchange(&mut server, message(DHCP_DISCOVER, 3, &[])).is_none());
+    // until the client takes another server's offer
+    let other = (DHCP_OPT_SERVER_ID, addr("10.0.0.2"));
+    assert!(exchange(&mut server, message(DHCP_REQUEST, 2, &[other])).is_none());
+    let (_, third, ..) = exchange(&mut server, message(DHCP_DISCOVER, 3, &[])).unwrap();
+    assert_eq!(third, addr("10.0.0.101"));
+
+    // an address leased to someone else is refused
+    let request = message(DHCP_REQUEST, 3, &[(DHCP_OPT_REQUESTED_IP, offered)]);
+    let (ty, ..) = exchange(&mut server, request).unwrap();
+    assert_eq!(ty, DHCP_NAK);
+
+    // the lease survives a restart through the lease file
+    server.save();
+    dhcp_server_flush();
+    server.bindings.clear();
+    server.load(now).unwrap();
+    assert_eq!(server.leases(now).count(), 1);
+    assert_eq!(server.leases(now).next().unwrap().addr, offered);
+    std::fs::remove_file(server.config.lease_file.as_ref().unwrap()).unwrap();
+
+    // renewals are unicast, releases keep the address for the client
+    let mut renew = message(DHCP_REQUEST, 1, &[]);
+    DhcpPacket::new_unchecked(&mut renew[..]).set_ciaddr(offered);
+    let (ty, _, dst, _) = exchange(&mut server, renew).unwrap();
+    assert_eq!((ty, dst), (DHCP_ACK, offered));
+    let mut release = message(DHCP_RELEASE, 1, &[server_id]);
+    DhcpPacket::new_unchecked(&mut release[..]).set_ciaddr(offered);
+    assert!(exchange(&mut server, release).is_none());
+    assert_eq!(server.leases(now).count(), 0);
+    let (_, again, ..) = exchange(&mut server, message(DHCP_DISCOVER, 1, &[])).unwrap();
+    assert_eq!(again, offered);
+
+    // a declined address is left out of the pool
+    let decline = message(
+        DHCP_DECLINE,
+        1,
+        &[server_id, (DHCP_OPT_REQUESTED_IP, offered)],
+    );
+    // only from the client holding it
+    let other_decline = message(
+        DHCP_DECLINE,
+        3,
+        &[server_id, (DHCP_OPT_REQUESTED_IP, offered)],
+    );
+    assert!(exchange(&mut server, other_decline.clone()).is_none());
+    assert!(server.available(offered, EthernetAddress([0x02, 0, 0, 0, 0, 1]), now));
+    assert!(exchange(&mut server, decline).is_none());
+    assert!(!server.available(offered, EthernetAddress([0x02, 0, 0, 0, 0, 1]), now));
+    // and only once
+    assert!(exchange(&mut server, other_decline).is_none());
+    assert!(!server.available(offered, EthernetAddress([0x02, 0, 0, 0, 0, 3]), now));
+}
@@ -0,0 +1,452 @@
+//! Stub resolver for IPv4 addresses.
+//!
+//! `dns_resolve` answers from, in order: literal addresses, the static hosts table, the cache,
+//! and A queries to the name servers. Queries go over UDP with retransmission and are retried
+//! over TCP when the response is truncated. CNAME chains are followed, within one response or
+//! across several queries, and answers are cached for the smallest TTL along the chain.
+//! Negative answers are cached for the time given by the SOA record of the zone (RFC 2308).
+//!
+//! The name servers are the ones set with `dns_set_nameservers`, or, if none are, the ones
+//! offered by DHCP.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dhcp,
+    error::{UtcpErr, UtcpResult},
+    ip::{IP_ADDR_ANY, IpAddress, IpEndpoint},
+    tcp, udp, utils,
+    wire::dns::{
+        DNS_FLAG_RD, DNS_HEADER_LEN, DNS_PORT, DNS_RCODE_NOERROR, DNS_RCODE_NXDOMAIN, DNS_TYPE_A,
+        DnsPacket, DnsRecordData, DnsSections, DnsWriter,
+    },
+};
+
+const DNS_TIMEOUT_DEFAULT: Duration = Duration::from_secs(2);
+/// Rounds over all name servers before giving up. The timeout doubles every round.
+const DNS_ATTEMPTS_DEFAULT: u32 = 2;
+/// Longest CNAME chain followed for one name.
+const DNS_CNAME_MAX: usize = 8;
+/// Names kept in the cache. The entry closest to expiry is evicted first.
+const DNS_CACHE_LIMIT: usize = 256;
+/// Negative answers without an SOA record are cached this long.
+const DNS_NEGATIVE_TTL_DEFAULT: u32 = 60;
+/// TCP responses can be up to 64KiB long, which is more than a stub resolver needs.
+const DNS_TCP_MESSAGE_MAX: usize = 16384;
+
+struct DnsConfig {
+    nameservers: Vec<IpAddress>,
+    timeout: Duration,
+    attempts: u32,
+}
+
+static DNS_CONFIG: Mutex<DnsConfig> = Mutex::new(DnsConfig {
+    nameservers: Vec::new(),
+    timeout: DNS_TIMEOUT_DEFAULT,
+    attempts: DNS_ATTEMPTS_DEFAULT,
+});
+
+/// Static names, as in `/etc/hosts`. Names are stored in lower case.
+static DNS_HOSTS: Mutex<Vec<(String, IpAddress)>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct DnsCacheEntry {
+    name: String,
+    /// Empty for a cached negative answer.
+    addrs: Vec<IpAddress>,
+    expires: Instant,
+}
+
+static DNS_CACHE: Mutex<Vec<DnsCacheEntry>> = Mutex::new(Vec::new());
+
+/// Names compare case-insensitively and may be written with a trailing dot.
+fn dns_name_normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// Query IDs come from the kernel, so that they cannot be predicted from earlier ones.
+fn dns_generate_id() -> UtcpResult<u16> {
+    let mut id = [0u8; 2];
+    utils::getrandom(&mut id)?;
+    Ok(u16::from_ne_bytes(id))
+}
+
+/// Sets the name servers to query, replacing the ones offered by DHCP. An empty list goes
+/// back to using DHCP.
+pub fn dns_set_nameservers(nameservers: &[IpAddress]) {
+    DNS_CONFIG.lock().unwrap().nameservers = nameservers.to_vec();
+}
+
+/// Returns the name servers that queries are sent to.
+pub fn dns_nameservers() -> Vec<IpAddress> {
+    let nameservers = DNS_CONFIG.lock().unwrap().nameservers.clone();
+    if nameservers.is_empty() {
+        dhcp::dhcp_dns_servers()
+    } else {
+        nameservers
+    }
+}
+
+/// Sets how long to wait for the first response from a name server and how many rounds over
+/// all name servers to make.
+pub fn dns_set_timeout(timeout: Duration, attempts: u32) -> UtcpResult<()> {
+    if timeout.is_zero() || attempts == 0 {
+        return Err(UtcpErr::InvalidArgument(
+            "timeout and attempts must be positive".into(),
+        ));
+    }
+    let mut config = DNS_CONFIG.lock().unwrap();
+    config.timeout = timeout;
+    config.attempts = attempts;
+    Ok(())
+}
+
+/// Adds a static name. Static names are answered before the cache and the name servers.
+pub fn dns_hosts_add(name: &str, addr: IpAddress) {
+    let name = dns_name_normalize(name);
+    let mut hosts = DNS_HOSTS.lock().unwrap();
+    if !hosts.contains(&(name.clone(), addr)) {
+        hosts.push((name, addr));
+    }
+}
+
+pub fn dns_hosts_clear() {
+    DNS_HOSTS.lock().unwrap().clear();
+}
+
+/// Adds the names of a table in the format of `/etc/hosts`: an address followed by names on
+/// each line, with `#` starting a comment. Lines with an address other than IPv4 are skipped.
+/// Returns the number of names added.
+pub fn dns_hosts_parse(text: &str) -> usize {
+    let mut count = 0;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(addr)) = fields.next().map(str::parse::<IpAddress>) else {
+            continue;
+        };
+        for name in fields {
+            dns_hosts_add(name, addr);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Reads a file in the format of `/etc/hosts` with `dns_hosts_parse`.
+pub fn dns_hosts_load(path: impl AsRef<std::path::Path>) -> UtcpResult<usize> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(dns_hosts_parse(&text))
+}
+
+fn dns_hosts_lookup(name: &str) -> Vec<IpAddress> {
+    let hosts = DNS_HOSTS.lock().unwrap();
+    hosts
+        .iter()
+        .filter(|(host, _)| host == name)
+        .map(|(_, addr)| *addr)
+        .collect()
+}
+
+fn dns_cache_lookup(name: &str) -> Option<Vec<IpAddress>> {
+    let now = Instant::now();
+    let mut cache = DNS_CACHE.lock().unwrap();
+    cache.retain(|entry| entry.expires > now);
+    cache
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.addrs.clone())
+}
+
+fn dns_cache_insert(name: &str, addrs: Vec<IpAddress>, ttl: u32) {
+    if ttl == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let mut cache = DNS_CACHE.lock().unwrap();
+    cache.retain(|entry| entry.expires > now && entry.name != name);
+    if cache.len() >= DNS_CACHE_LIMIT
+        && let Some(index) = cache
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| entry.expires)
+            .map(|(index, _)| index)
+    {
+        cache.remove(index);
+    }
+    log::debug!(
+        "cache insert: name={}, addrs={:?}, ttl={}",
+        name,
+        addrs,
+        ttl
+    );
+    cache.push(DnsCacheEntry {
+        name: name.to_string(),
+        addrs,
+        expires: now + Duration::from_secs(ttl as u64),
+    });
+}
+
+/// Forgets every cached answer.
+pub fn dns_cache_flush() {
+    DNS_CACHE.lock().unwrap().clear();
+}
+
+/// A response to one of our queries, as opposed to a stray or spoofed datagram.
+fn dns_response_check(query: &[u8], response: &[u8]) -> bool {
+    let Ok(packet) = DnsPacket::new_checked(response) else {
+        return false;
+    };
+    // the question echoed back has to match ours byte for byte, except for the case
+    let question = &query[DNS_HEADER_LEN..];
+    let echoed = response.get(DNS_HEADER_LEN..DNS_HEADER_LEN + question.len());
+    packet.is_response()
+        && packet.id() == DnsPacket::new_unchecked(query).id()
+        && echoed.is_some_and(|echoed| echoed.eq_ignore_ascii_case(question))
+}
+
+/// Sends the query over TCP, with the two-byte length prefix of RFC 1035 4.2.2.
+fn dns_query_tcp(server: IpAddress, query: &[u8], timeout: Duration) -> UtcpResult<Vec<u8>> {
+    let id = tcp::tcp_open()?;
+    let result = (|| {
+        tcp::tcp_set_send_timeout(id, Some(timeout))?;
+        tcp::tcp_set_recv_timeout(id, Some(timeout))?;
+        tcp::tcp_connect(id, IpEndpoint::new(server, DNS_PORT))?;
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        let mut sent = 0;
+        while sent < message.len() {
+            sent += tcp::tcp_send(id, &message[sent..])?;
+        }
+        let mut response = Vec::new();
+        let mut buf = [0u8; 2048];
+        loop {
+            if response.len() >= 2 {
+                let len = u16::from_be_bytes([response[0], response[1]]) as usize;
+                if len > DNS_TCP_MESSAGE_MAX {
+                    return Err(UtcpErr::Malformed("DNS response is too long".into()));
+                }
+                if response.len() >= 2 + len {
+                    response.truncate(2 + len);
+                    return Ok(response.split_off(2));
+                }
+            }
+            match tcp::tcp_receive(id, &mut buf)? {
+                0 => return Err(UtcpErr::ConnectionAborted),
+                len => response.extend_from_slice(&buf[..len]),
+            }
+        }
+    })();
+    let _ = tcp::tcp_close(id);
+    result
+}
+
+/// Waits for the response to `query` from `server` on the UDP PCB until `deadline`.
+fn dns_query_udp_wait(
+    pcb: usize,
+    server: IpAddress,
+    query: &[u8],
+    deadline: Instant,
+) -> UtcpResult<Vec<u8>> {
+    let mut buf = [0u8; DNS_TCP_MESSAGE_MAX];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UtcpErr::TimedOut);
+        }
+        udp::udp_set_recv_timeout(pcb, Some(deadline - now))?;
+        let (len, from) = match udp::udp_recvfrom(pcb, &mut buf) {
+            Ok(received) => received,
+            Err(UtcpErr::WouldBlock) => return Err(UtcpErr::TimedOut),
+            Err(e) => return Err(e),
+        };
+        if from != IpEndpoint::new(server, DNS_PORT) || !dns_response_check(query, &buf[..len]) {
+            log::debug!("ignored a datagram: from={}", from);
+            continue;
+        }
+        return Ok(buf[..len].to_vec());
+    }
+}
+
+/// Sends a query to the name servers in turn until one answers. Returns the response, which
+/// may still carry an error code other than a server failure.
+fn dns_query(name: &str, qtype: u16) -> UtcpResult<Vec<u8>> {
+    let nameservers = dns_nameservers();
+    if nameservers.is_empty() {
+        return Err(UtcpErr::InvalidArgument("no name server configured".into()));
+    }
+    let (timeout, attempts) = {
+        let config = DNS_CONFIG.lock().unwrap();
+        (config.timeout, config.attempts)
+    };
+    let mut query = vec![0u8; DNS_HEADER_LEN];
+    DnsPacket::new_unchecked(&mut query[..]).set_header(dns_generate_id()?, DNS_FLAG_RD);
+    DnsWriter::new(&mut query).question(name, qtype)?;
+
+    let pcb = udp::udp_open()?;
+    let result = (|| {
+        udp::udp_bind_random(pcb, IP_ADDR_ANY.into())?;
+        let mut last_err = UtcpErr::TimedOut;
+        for attempt in 0..attempts {
+            let timeout = timeout * (1 << attempt.min(16));
+            for &server in &nameservers {
+                log::debug!("query: name={}, type={}, server={}", name, qtype, server);
+                if let Err(e) = udp::udp_sendto(pcb, &query, IpEndpoint::new(server, DNS_PORT)) {
+                    log::warn!("failed to send a query: server={}, {}", server, e);
+                    last_err = e;
+                    continue;
+                }
+                let mut response =
+                    match dns_query_udp_wait(pcb, server, &query, Instant::now() + timeout) {
+                        Ok(response) => response,
+                        Err(e) => {
+                            last_err = e;
+                            continue;
+                        }
+                    };
+                let packet = DnsPacket::new_unchecked(&response[..]);
+                if packet.truncated() {
+                    log::debug!("truncated, retrying over TCP: server={}", server);
+                    response = match dns_query_tcp(server, &query, timeout) {
+                        Ok(response) if dns_response_check(&query, &response) => response,
+                        Ok(_) => {
+                            last_err = UtcpErr::Malformed("DNS response mismatch".into());
+                            continue;
+                        }
+                        Err(e) => {
+                            last_err = e;
+                            continue;
+                        }
+                    };
+                }
+                let rcode = DnsPacket::new_unchecked(&response[..]).rcode();
+                if rcode != DNS_RCODE_NOERROR && rcode != DNS_RCODE_NXDOMAIN {
+                    log::debug!("server failure: server={}, rcode={}", server, rcode);
+                    last_err = UtcpErr::NameServerFailure(rcode);
+                    continue;
+                }
+                return Ok(response);
+            }
+        }
+        Err(last_err)
+    })();
+    let _ = udp::udp_close(pcb);
+    result
+}
+
+/// TTL of a negative answer: the smaller of the SOA record's TTL and its minimum field.
+fn dns_negative_ttl(sections: &DnsSections) -> u32 {
+    sections
+        .authorities
+        .iter()
+        .find_map(|record| match record.data {
+            DnsRecordData::Soa { minimum } => Some(record.ttl.min(minimum)),
+            _ => None,
+        })
+        .unwrap_or(DNS_NEGATIVE_TTL_DEFAULT)
+}
+
+/// TTL and normalized target of the CNAME record of `name` among the answers, if any.
+fn dns_cname(sections: &DnsSections, name: &str) -> Option<(u32, String)> {
+    sections
+        .answers
+        .iter()
+        .find_map(|record| match &record.data {
+            DnsRecordData::Cname(target) if dns_name_normalize(&record.name) == name => {
+                Some((record.ttl, dns_name_normalize(target)))
+            }
+            _ => None,
+        })
+}
+
+/// Resolves `name` to its IPv4 addresses. A literal address resolves to itself.
+pub fn dns_resolve(name: &str) -> UtcpResult<Vec<IpAddress>> {
+    if let Ok(addr) = name.parse::<IpAddress>() {
+        return Ok(vec![addr]);
+    }
+    let name = dns_name_normalize(name);
+    let addrs = dns_hosts_lookup(&name);
+    if !addrs.is_empty() {
+        return Ok(addrs);
+    }
+    if let Some(addrs) = dns_cache_lookup(&name) {
+        log::debug!("cache hit: name={}, addrs={:?}", name, addrs);
+        return match addrs.is_empty() {
+            true => Err(UtcpErr::NameNotFound(name)),
+            false => Ok(addrs),
+        };
+    }
+
+    let mut qname = name.clone();
+    let mut ttl = u32::MAX;
+    let mut hops = 0;
+    loop {
+        let response = dns_query(&qname, DNS_TYPE_A)?;
+        let packet = DnsPacket::new_checked(&response[..])?;
+        let sections = packet.sections()?;
+        if packet.rcode() == DNS_RCODE_NXDOMAIN {
+            dns_cache_insert(&name, Vec::new(), dns_negative_ttl(&sections));
+            return Err(UtcpErr::NameNotFound(name));
+        }
+
+        // follow the chain as far as this response goes
+        let queried = qname.clone();
+        while let Some((record_ttl, target)) = dns_cname(&sections, &qname) {
+            hops += 1;
+            if hops > DNS_CNAME_MAX {
+                log::warn!("CNAME chain is too long: name={}", name);
+                return Err(UtcpErr::NameNotFound(name));
+            }
+            log::debug!("CNAME: {} -> {}", qname, target);
+            ttl = ttl.min(record_ttl);
+            qname = target;
+        }
+        let addrs: Vec<IpAddress> = sections
+            .answers
+            .iter()
+            .filter(|record| dns_name_normalize(&record.name) == qname)
+            .filter_map(|record| match record.data {
+                DnsRecordData::A(addr) => {
+                    ttl = ttl.min(record.ttl);
+                    Some(addr)
+                }
+                _ => None,
+            })
+            .collect();
+        if !addrs.is_empty() {
+            dns_cache_insert(&name, addrs.clone(), ttl);
+            return Ok(addrs);
+        }
+        // the chain leads out of the response, so ask for the target itself
+        if qname != queried {
+            continue;
+        }
+        dns_cache_insert(&name, Vec::new(), dns_negative_ttl(&sections));
+        return Err(UtcpErr::NameNotFound(name));
+    }
+}
+
+#[test]
+fn test_dns_hosts_parse() {
+    let text = "\
+# static table
+127.0.0.1   localhost
+192.0.2.10  Gateway.example gw  # trailing comment
+::1         localhost ip6-localhost
+bogus line
+";
+    assert_eq!(dns_hosts_parse(text), 3);
+    assert_eq!(
+        dns_hosts_lookup("gateway.example"),
+        vec![IpAddress::parse_from("192.0.2.10")]
+    );
+    assert_eq!(
+        dns_hosts_lookup("localhost"),
+        vec![IpAddress::parse_from("127.0.0.1")]
+    );
+    assert_eq!(dns_resolve("GW.").unwrap().len(), 1);
+    assert!(dns_hosts_lookup("ip6-localhost").is_empty());
+}
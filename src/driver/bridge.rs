@@ -0,0 +1,394 @@
+//! Software learning bridge over Ethernet-class devices.
+//!
+//! The frames a port receives never reach the protocols of the port itself: `ether_input_helper`
+//! passes them to `bridge_input`, which learns the source address into the forwarding database
+//! and forwards, floods or delivers them to the bridge. The bridge is a device of its own and
+//! can carry interfaces like any other Ethernet device.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ether::{self, ETHER_MTU},
+    net::{
+        self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetInterface,
+        NetInterfaceHandler, net_device_register,
+    },
+    net_device_get, net_device_get_mut,
+    wire::ethernet::{ETHERNET_ADDR_LEN, ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+};
+
+/// Default time after which a learned address is forgotten, as in 802.1D.
+pub const BRIDGE_AGEING_TIME: Duration = Duration::from_secs(300);
+/// Addresses learned per bridge. Frames from new addresses are flooded but not learned beyond it.
+const BRIDGE_FDB_LIMIT: usize = 1024;
+const BRIDGE_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+struct BridgePort {
+    bridge: NetDeviceHandler,
+    port: NetDeviceHandler,
+}
+
+static BRIDGE_PORTS: Mutex<Vec<BridgePort>> = Mutex::new(Vec::new());
+
+/// Entry of the forwarding database: `addr` was last seen as the source of a frame received
+/// on `port`, `age` ago.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeFdbEntry {
+    pub addr: EthernetAddress,
+    pub port: NetDeviceHandler,
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+struct FdbEntry {
+    bridge: NetDeviceHandler,
+    addr: EthernetAddress,
+    port: NetDeviceHandler,
+    updated: Instant,
+}
+
+static BRIDGE_FDB: Mutex<Vec<FdbEntry>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct BridgeNetDevice {
+    name: String,
+    flags: NetDeviceFlags,
+    hwaddr: EthernetAddress,
+    ageing_time: Duration,
+    /// Set once registered, to forward what the bridge itself sends.
+    handler: Option<NetDeviceHandler>,
+    /// Port of the frame being delivered to the bridge, only set in the interrupt thread.
+    rx_port: Option<NetDeviceHandler>,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl BridgeNetDevice {
+    /// Registers a bridge without ports. A random address is used if `hwaddr` is `None`.
+    pub fn init(hwaddr: Option<EthernetAddress>) -> UtcpResult<NetDeviceHandler> {
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            flags: NetDeviceFlags::BROADCAST | NetDeviceFlags::NEED_ARP,
+            hwaddr: hwaddr.unwrap_or_else(ether::ether_addr_generate),
+            ageing_time: BRIDGE_AGEING_TIME,
+            handler: None,
+            rx_port: None,
+            ifaces: Vec::new(),
+        };
+        log::info!("dev={}, type=bridge, hwaddr={}", name, dev.hwaddr);
+        let handler = net_device_register(NetDevice::Bridge(dev))?;
+        let dev: &mut BridgeNetDevice = net_device_get_mut!(handler).try_into()?;
+        dev.handler = Some(handler);
+        Ok(handler)
+    }
+
+    pub(crate) fn rx_port(&self) -> Option<NetDeviceHandler> {
+        self.rx_port
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+}
+
+impl NetDeviceOps for BridgeNetDevice {
+    const MTU: u16 = ETHER_MTU;
+    const HEADER_LEN: usize = ETHERNET_HEADER_LEN;
+    const ADDR_LEN: usize = ETHERNET_ADDR_LEN;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        Some(self.hwaddr)
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.flags.insert(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    /// Sends out of the port the destination was learned on, or out of all of them.
+    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+        let Some(bridge) = self.handler else {
+            return Err(UtcpErr::DeviceNotOpened(self.name.clone()));
+        };
+        ether::ether_transmit_helper(self.hwaddr, ty, data, dst, |frame| {
+            bridge_forward(&bridge, None, frame)
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut BridgeNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::Bridge(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch { expected: "bridge" }),
+        }
+    }
+}
+
+/// Enslaves `port` to `bridge`. The port has to send frames as they are, which TAP and
+/// `AF_PACKET` devices and UDP tunnels with Ethernet framing do, and can only be in one
+/// bridge. Its own interfaces stop receiving anything.
+pub fn bridge_port_add(bridge: NetDeviceHandler, port: NetDeviceHandler) -> UtcpResult<()> {
+    let _: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    let frames = match net_device_get!(port) {
+        NetDevice::EtherTap(_) | NetDevice::EtherPacket(_) => true,
+        NetDevice::UdpTunnel(dev) => dev.hw_addr().is_some(),
+        _ => false,
+    };
+    if !frames {
+        return Err(UtcpErr::DeviceTypeMismatch {
+            expected: "ethernet",
+        });
+    }
+    let mut ports = BRIDGE_PORTS.lock().unwrap();
+    if ports.iter().any(|p| p.port == port) {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "{} is already a bridge port",
+            net_device_get!(port).name()
+        )));
+    }
+    log::info!(
+        "bridge={}, port={}",
+        net_device_get!(bridge).name(),
+        net_device_get!(port).name()
+    );
+    ports.push(BridgePort { bridge, port });
+    Ok(())
+}
+
+/// Releases `port` from `bridge`, forgetting the addresses learned on it.
+pub fn bridge_port_del(bridge: NetDeviceHandler, port: NetDeviceHandler) -> UtcpResult<()> {
+    let mut ports = BRIDGE_PORTS.lock().unwrap();
+    let Some(index) = ports
+        .iter()
+        .position(|p| p.bridge == bridge && p.port == port)
+    else {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "{} is not a port of {}",
+            net_device_get!(port).name(),
+            net_device_get!(bridge).name()
+        )));
+    };
+    ports.remove(index);
+    drop(ports);
+    BRIDGE_FDB
+        .lock()
+        .unwrap()
+        .retain(|entry| entry.port != port);
+    Ok(())
+}
+
+pub fn bridge_ports(bridge: NetDeviceHandler) -> Vec<NetDeviceHandler> {
+    let ports = BRIDGE_PORTS.lock().unwrap();
+    ports
+        .iter()
+        .filter(|p| p.bridge == bridge)
+        .map(|p| p.port)
+        .collect()
+}
+
+/// Returns the bridge `port` is enslaved to.
+pub(crate) fn bridge_master(port: &NetDeviceHandler) -> Option<NetDeviceHandler> {
+    let ports = BRIDGE_PORTS.lock().unwrap();
+    ports.iter().find(|p| p.port == *port).map(|p| p.bridge)
+}
+
+/// Changes how long learned addresses are kept without being seen again.
+pub fn bridge_set_ageing_time(bridge: NetDeviceHandler, ageing_time: Duration) -> UtcpResult<()> {
+    let dev: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    dev.ageing_time = ageing_time;
+    Ok(())
+}
+
+/// Returns the addresses `bridge` has learned and not yet forgotten.
+pub fn bridge_fdb(bridge: NetDeviceHandler) -> UtcpResult<Vec<BridgeFdbEntry>> {
+    let dev: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    let now = Instant::now();
+    let fdb = BRIDGE_FDB.lock().unwrap();
+    Ok(fdb
+        .iter()
+        .filter(|entry| entry.bridge == bridge)
+        .map(|entry| BridgeFdbEntry {
+            addr: entry.addr,
+            port: entry.port,
+            age: now.duration_since(entry.updated),
+        })
+        .filter(|entry| entry.age < dev.ageing_time)
+        .collect())
+}
+
+/// Forgets all the addresses `bridge` has learned.
+pub fn bridge_fdb_flush(bridge: NetDeviceHandler) -> UtcpResult<()> {
+    let _: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    BRIDGE_FDB
+        .lock()
+        .unwrap()
+        .retain(|entry| entry.bridge != bridge);
+    Ok(())
+}
+
+fn bridge_fdb_learn(bridge: &NetDeviceHandler, addr: EthernetAddress, port: &NetDeviceHandler) {
+    let mut fdb = BRIDGE_FDB.lock().unwrap();
+    if let Some(entry) = fdb
+        .iter_mut()
+        .find(|entry| entry.bridge == *bridge && entry.addr == addr)
+    {
+        if entry.port != *port {
+            log::debug!("fdb moved: addr={}, port={}", addr, port.private);
+            entry.port = *port;
+        }
+        entry.updated = Instant::now();
+        return;
+    }
+    if fdb.iter().filter(|entry| entry.bridge == *bridge).count() >= BRIDGE_FDB_LIMIT {
+        log::debug!("fdb full, not learned: addr={}", addr);
+        return;
+    }
+    log::debug!("fdb insert: addr={}, port={}", addr, port.private);
+    fdb.push(FdbEntry {
+        bridge: *bridge,
+        addr,
+        port: *port,
+        updated: Instant::now(),
+    });
+}
+
+fn bridge_fdb_lookup(
+    bridge: &NetDeviceHandler,
+    ageing_time: Duration,
+    addr: EthernetAddress,
+) -> Option<NetDeviceHandler> {
+    let fdb = BRIDGE_FDB.lock().unwrap();
+    fdb.iter()
+        .find(|entry| {
+            entry.bridge == *bridge && entry.addr == addr && entry.updated.elapsed() < ageing_time
+        })
+        .map(|entry| entry.port)
+}
+
+/// Sends `frame` out of the port its destination was learned on, or floods it out of the ports
+/// other than `ingress` if the destination is unknown or a group address.
+fn bridge_forward(
+    bridge: &NetDeviceHandler,
+    ingress: Option<&NetDeviceHandler>,
+    frame: &[u8],
+) -> UtcpResult<()> {
+    let dst = EthernetFrame::new_checked(frame)?.dst();
+    let dev: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    let egress = match dst.is_multicast() {
+        true => None,
+        false => bridge_fdb_lookup(bridge, dev.ageing_time, dst),
+    };
+    let ports = match egress {
+        Some(port) if Some(&port) == ingress => {
+            // the destination is on the segment the frame came from
+            return Ok(());
+        }
+        Some(port) => vec![port],
+        None => bridge_ports(*bridge)
+            .into_iter()
+            .filter(|port| Some(port) != ingress)
+            .collect(),
+    };
+    for port in ports {
+        let dev = net_device_get_mut!(port);
+        if !dev.is_up() {
+            continue;
+        }
+        if let Err(e) = dev.transmit_frame(frame) {
+            log::debug!("dev={}, {}", dev.name(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Handles a frame received by a bridge port.
+pub(crate) fn bridge_input(
+    bridge: &NetDeviceHandler,
+    port: &NetDeviceHandler,
+    frame: &[u8],
+) -> UtcpResult<()> {
+    let eth = EthernetFrame::new_checked(frame)?;
+    let dev: &mut BridgeNetDevice = net_device_get_mut!(bridge).try_into()?;
+    if !dev.is_up() {
+        return Ok(());
+    }
+    let (src, dst, hwaddr) = (eth.src(), eth.dst(), dev.hwaddr);
+    if src.is_multicast() || src == hwaddr {
+        log::debug!("dropped, invalid source: src={}", src);
+        return Ok(());
+    }
+    bridge_fdb_learn(bridge, src, port);
+    if dst != hwaddr {
+        bridge_forward(bridge, Some(port), frame)?;
+    }
+    if dst == hwaddr || dst.is_multicast() {
+        // throttling applies to the port the frame came from
+        dev.rx_port = Some(*port);
+        ether::ether_input_helper(bridge, hwaddr, frame)?;
+    }
+    Ok(())
+}
+
+fn bridge_timer() {
+    let now = Instant::now();
+    let mut fdb = BRIDGE_FDB.lock().unwrap();
+    fdb.retain(|entry| {
+        let NetDevice::Bridge(dev) = net_device_get!(entry.bridge) else {
+            return false;
+        };
+        let alive = now.duration_since(entry.updated) < dev.ageing_time;
+        if !alive {
+            log::debug!("fdb expired: addr={}", entry.addr);
+        }
+        alive
+    });
+}
+
+pub fn bridge_init() -> UtcpResult<()> {
+    net::net_timer_register(BRIDGE_TIMER_INTERVAL, bridge_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
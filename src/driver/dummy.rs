@@ -1,17 +1,23 @@
+use std::sync::Arc;
+
 use crate::{
     error::UtcpResult,
-    net::{self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, net_device_register},
+    net::{
+        self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetInterface,
+        NetInterfaceHandler, net_device_register,
+    },
     platform::{IRQFlags, linux::intr},
 };
 
 use super::INTR_IRQ_BASE;
 
-const DUMMY_IRQ: i32 = INTR_IRQ_BASE;
+pub(crate) const DUMMY_IRQ: i32 = INTR_IRQ_BASE;
 
 #[derive(Debug)]
 pub struct DummyNetDevice {
     name: String,
     flags: NetDeviceFlags,
+    ifaces: Vec<Arc<NetInterface>>,
 }
 
 impl DummyNetDevice {
@@ -20,12 +26,35 @@ impl DummyNetDevice {
         let dev = Self {
             name: name.clone(),
             flags: NetDeviceFlags::empty(),
+            ifaces: Vec::new(),
         };
         let handler = net_device_register(NetDevice::Dummy(dev))?;
         let flags = IRQFlags::SHARED;
-        intr::intr_request_irq(DUMMY_IRQ, dummy_isr, flags, name, handler.clone())?;
+        intr::intr_request_irq(DUMMY_IRQ, dummy_isr, flags, name, handler)?;
         Ok(handler)
     }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
 }
 
 impl NetDeviceOps for DummyNetDevice {
@@ -37,6 +66,10 @@ impl NetDeviceOps for DummyNetDevice {
         &self.name
     }
 
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
     fn is_up(&self) -> bool {
         self.flags.contains(NetDeviceFlags::UP)
     }
@@ -0,0 +1,759 @@
+//! Ethernet device attached to an existing Linux interface through an `AF_PACKET` socket.
+//!
+//! Unlike a TAP interface, the host interface keeps its own traffic: the device has an address
+//! of its own, and a BPF filter on the socket lets only the frames to that address, broadcast
+//! and multicast through. The interface is put into promiscuous mode so that it does not drop
+//! the frames to the device. Frames the kernel sends out of the interface are not received, so
+//! the host can not talk to the device through the same interface; a veth pair or a bridge
+//! does that.
+//!
+//! The socket sees frames as the kernel holds them, before any offload is done: stripped VLAN
+//! tags are put back and unfinished TCP and UDP checksums are filled in, but frames that GRO
+//! merged or GSO has yet to split are larger than the MTU and dropped, so those offloads have
+//! to be off on the interface (and, for veth, on its peer) to carry TCP.
+
+use std::{
+    ffi::{CString, c_int},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ether::{self, ETHER_FRAME_MAX_LEN, ETHER_MTU},
+    ip::{IP_PROTOCOL_TCP, IP_PROTOCOL_UDP, IpAddr},
+    net::{
+        self, NET_PROTOCOL_TYPE_IP, NET_PROTOCOL_TYPE_IPV6, NetDevice, NetDeviceFlags,
+        NetDeviceHandler, NetDeviceOps, NetInterface, NetInterfaceHandler, net_device_register,
+    },
+    net_device_get_mut,
+    platform::{IRQFlags, linux::intr},
+    wire::{
+        self,
+        ethernet::{ETHERNET_ADDR_LEN, ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+        ipv4::Ipv4Packet,
+        ipv6::{IPV6_HEADER_LEN, Ipv6Packet},
+        vlan::{ETHERNET_TYPE_VLAN, VLAN_HEADER_LEN},
+    },
+};
+
+use super::INTR_IRQ_BASE;
+
+pub(crate) const ETHER_PACKET_IRQ: i32 = INTR_IRQ_BASE + 3;
+
+/// Size of a slot of the rings, enough for the largest frame and the headers before it.
+const RING_FRAME_SIZE: usize = 2048;
+const RING_BLOCK_SIZE: usize = 4096;
+/// Offset of the frame in a slot of the tx ring.
+const RING_TX_DATA_OFFSET: usize = libc::TPACKET2_HDRLEN - std::mem::size_of::<libc::sockaddr_ll>();
+
+/// Ancillary data the BPF program loads instead of packet data (linux/filter.h).
+const SKF_AD_OFF: u32 = -0x1000i32 as u32;
+const SKF_AD_PKTTYPE: u32 = 4;
+
+/// `PACKET_RX_RING` and `PACKET_TX_RING` mapped one after the other.
+#[derive(Debug)]
+struct EtherPacketRing {
+    base: *mut u8,
+    frames: usize,
+    rx_next: usize,
+    tx_next: usize,
+}
+
+impl EtherPacketRing {
+    fn size(&self) -> usize {
+        self.frames * RING_FRAME_SIZE * 2
+    }
+
+    fn rx_slot(&self, index: usize) -> *mut u8 {
+        unsafe { self.base.add(index * RING_FRAME_SIZE) }
+    }
+
+    fn tx_slot(&self, index: usize) -> *mut u8 {
+        unsafe { self.base.add((self.frames + index) * RING_FRAME_SIZE) }
+    }
+
+    /// The kernel and this side hand the slots over with `tp_status`, the first field of the
+    /// slot header.
+    fn status(slot: *mut u8) -> &'static AtomicU32 {
+        unsafe { &*(slot as *const AtomicU32) }
+    }
+}
+
+/// Ethernet device attached to the Linux interface `if_name` with an `AF_PACKET` socket.
+/// Received frames are signalled to the intr thread with `ETHER_PACKET_IRQ` through `O_ASYNC`.
+#[derive(Debug)]
+pub struct EtherPacketNetDevice {
+    name: String,
+    if_name: String,
+    flags: NetDeviceFlags,
+    hwaddr: EthernetAddress,
+    /// Slots of each of the rx and tx rings, or `None` to use `recvmsg` and `send`.
+    ring_frames: Option<usize>,
+    fd: c_int,
+    ring: Option<EtherPacketRing>,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl EtherPacketNetDevice {
+    /// Registers a device attached to the existing interface `if_name`. A random address is
+    /// used if `hwaddr` is `None`; the address of the interface itself would make the device
+    /// and the host both process the frames to it. With `ring_frames`, frames go through
+    /// `PACKET_MMAP` rings of that many slots instead of a system call each.
+    pub fn init(
+        if_name: &str,
+        hwaddr: Option<EthernetAddress>,
+        ring_frames: Option<usize>,
+    ) -> UtcpResult<NetDeviceHandler> {
+        if if_name.is_empty() || if_name.len() >= libc::IFNAMSIZ {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "interface name: {:?}",
+                if_name
+            )));
+        }
+        if ring_frames.is_some_and(|frames| frames < 2) {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "ring frames: {:?}",
+                ring_frames
+            )));
+        }
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            if_name: if_name.to_string(),
+            flags: NetDeviceFlags::BROADCAST | NetDeviceFlags::NEED_ARP,
+            hwaddr: hwaddr.unwrap_or_else(ether::ether_addr_generate),
+            // whole blocks of two slots
+            ring_frames: ring_frames.map(|frames| frames.div_ceil(2) * 2),
+            fd: -1,
+            ring: None,
+            ifaces: Vec::new(),
+        };
+        log::info!(
+            "dev={}, if={}, hwaddr={}, ring={:?}",
+            name,
+            if_name,
+            dev.hwaddr,
+            dev.ring_frames
+        );
+        let handler = net_device_register(NetDevice::EtherPacket(dev))?;
+        let flags = IRQFlags::SHARED;
+        intr::intr_request_irq(ETHER_PACKET_IRQ, ether_packet_isr, flags, name, handler)?;
+        Ok(handler)
+    }
+
+    pub fn if_name(&self) -> &str {
+        &self.if_name
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+
+    fn open_socket(&mut self) -> UtcpResult<c_int> {
+        // no protocol until bound, so that nothing is received before the filter is attached
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(UtcpErr::last_os_error("socket"));
+        }
+        let result = self.setup_socket(fd);
+        if result.is_err() {
+            self.unmap_ring();
+            unsafe { libc::close(fd) };
+        }
+        result.map(|_| fd)
+    }
+
+    fn setup_socket(&mut self, fd: c_int) -> UtcpResult<()> {
+        let if_name = CString::new(self.if_name.as_str()).unwrap();
+        let ifindex = unsafe { libc::if_nametoindex(if_name.as_ptr()) } as c_int;
+        if ifindex == 0 {
+            return Err(UtcpErr::last_os_error("if_nametoindex"));
+        }
+        let mut filter = ether_packet_filter(self.hwaddr);
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog,
+            "setsockopt(SO_ATTACH_FILTER)",
+        )?;
+        match self.ring_frames {
+            Some(frames) => self.map_ring(fd, frames)?,
+            // tags the kernel strips come as ancillary data
+            None => setsockopt(
+                fd,
+                libc::SOL_PACKET,
+                libc::PACKET_AUXDATA,
+                &1,
+                "setsockopt(PACKET_AUXDATA)",
+            )?,
+        }
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = ifindex;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(UtcpErr::last_os_error("bind"));
+        }
+        // dropped with the socket
+        let mut mreq: libc::packet_mreq = unsafe { std::mem::zeroed() };
+        mreq.mr_ifindex = ifindex;
+        mreq.mr_type = libc::PACKET_MR_PROMISC as u16;
+        setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_ADD_MEMBERSHIP,
+            &mreq,
+            "setsockopt(PACKET_ADD_MEMBERSHIP)",
+        )?;
+        intr::intr_fd_set_async(fd, ETHER_PACKET_IRQ)
+    }
+
+    fn map_ring(&mut self, fd: c_int, frames: usize) -> UtcpResult<()> {
+        setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_VERSION,
+            &(libc::tpacket_versions::TPACKET_V2 as c_int),
+            "setsockopt(PACKET_VERSION)",
+        )?;
+        let req = libc::tpacket_req {
+            tp_block_size: RING_BLOCK_SIZE as u32,
+            tp_block_nr: (frames * RING_FRAME_SIZE / RING_BLOCK_SIZE) as u32,
+            tp_frame_size: RING_FRAME_SIZE as u32,
+            tp_frame_nr: frames as u32,
+        };
+        setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_RX_RING,
+            &req,
+            "setsockopt(PACKET_RX_RING)",
+        )?;
+        setsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_TX_RING,
+            &req,
+            "setsockopt(PACKET_TX_RING)",
+        )?;
+        let ring = EtherPacketRing {
+            base: std::ptr::null_mut(),
+            frames,
+            rx_next: 0,
+            tx_next: 0,
+        };
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                ring.size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(UtcpErr::last_os_error("mmap"));
+        }
+        self.ring = Some(EtherPacketRing {
+            base: base as *mut u8,
+            ..ring
+        });
+        Ok(())
+    }
+
+    fn unmap_ring(&mut self) {
+        if let Some(ring) = self.ring.take() {
+            unsafe { libc::munmap(ring.base as *mut libc::c_void, ring.size()) };
+        }
+    }
+
+    /// Reads a frame into `buf`, as it was on the wire, and returns its length, or `None` if
+    /// there is none left.
+    fn receive_frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+        match &mut self.ring {
+            Some(ring) => loop {
+                let slot = ring.rx_slot(ring.rx_next);
+                let status = EtherPacketRing::status(slot).load(Ordering::Acquire);
+                if status & libc::TP_STATUS_USER == 0 {
+                    return None;
+                }
+                let hdr = unsafe { &*(slot as *const libc::tpacket2_hdr) };
+                let len = hdr.tp_snaplen as usize;
+                let frame =
+                    unsafe { std::slice::from_raw_parts(slot.add(hdr.tp_mac as usize), len) };
+                // truncated frames are larger than any the device takes
+                let copied = (len == hdr.tp_len as usize).then(|| {
+                    let (tpid, tci) = (hdr.tp_vlan_tpid, hdr.tp_vlan_tci);
+                    ether_packet_copy(buf, frame, status, tpid, tci)
+                });
+                EtherPacketRing::status(slot).store(libc::TP_STATUS_KERNEL, Ordering::Release);
+                ring.rx_next = (ring.rx_next + 1) % ring.frames;
+                if let Some(Some(len)) = copied {
+                    return Some(len);
+                }
+            },
+            None => loop {
+                let mut frame = [0u8; ETHER_FRAME_MAX_LEN];
+                let mut iov = libc::iovec {
+                    iov_base: frame.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: frame.len(),
+                };
+                let mut control = [0u64; 8];
+                let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = std::mem::size_of_val(&control);
+                let len = unsafe { libc::recvmsg(self.fd, &mut msg, libc::MSG_TRUNC) };
+                if len < 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != std::io::ErrorKind::WouldBlock {
+                        log::error!("dev={}, recvmsg: {}", self.name, err);
+                    }
+                    return None;
+                }
+                if len as usize > frame.len() {
+                    continue;
+                }
+                let frame = &frame[..len as usize];
+                let copied = match ether_packet_auxdata(&msg) {
+                    Some(aux) => {
+                        let (tpid, tci) = (aux.tp_vlan_tpid, aux.tp_vlan_tci);
+                        ether_packet_copy(buf, frame, aux.tp_status, tpid, tci)
+                    }
+                    None => ether_packet_copy(buf, frame, 0, 0, 0),
+                };
+                if let Some(len) = copied {
+                    return Some(len);
+                }
+            },
+        }
+    }
+}
+
+impl NetDeviceOps for EtherPacketNetDevice {
+    const MTU: u16 = ETHER_MTU;
+    const HEADER_LEN: usize = ETHERNET_HEADER_LEN;
+    const ADDR_LEN: usize = ETHERNET_ADDR_LEN;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        Some(self.hwaddr)
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.fd = self.open_socket()?;
+        self.flags.insert(NetDeviceFlags::UP);
+        // frames may have arrived before the signal was set up
+        intr::intr_raise_irq(ETHER_PACKET_IRQ)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        self.unmap_ring();
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+            self.fd = -1;
+        }
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+        ether::ether_transmit_helper(self.hwaddr, ty, data, dst, |frame| {
+            self.transmit_frame(frame)
+        })
+    }
+
+    fn transmit_frame(&mut self, frame: &[u8]) -> UtcpResult<()> {
+        let Some(ring) = &mut self.ring else {
+            let ret = unsafe {
+                libc::send(
+                    self.fd,
+                    frame.as_ptr() as *const libc::c_void,
+                    frame.len(),
+                    0,
+                )
+            };
+            if ret < 0 {
+                return Err(UtcpErr::last_os_error("send"));
+            }
+            return Ok(());
+        };
+        if frame.len() > RING_FRAME_SIZE - RING_TX_DATA_OFFSET {
+            return Err(UtcpErr::MessageTooLong {
+                mtu: RING_FRAME_SIZE - RING_TX_DATA_OFFSET,
+            });
+        }
+        let slot = ring.tx_slot(ring.tx_next);
+        let status = EtherPacketRing::status(slot);
+        if status.load(Ordering::Acquire) & (libc::TP_STATUS_SEND_REQUEST | libc::TP_STATUS_SENDING)
+            != 0
+        {
+            // the kernel has not sent the whole ring yet
+            return Err(UtcpErr::QueueFull);
+        }
+        unsafe {
+            let hdr = &mut *(slot as *mut libc::tpacket2_hdr);
+            hdr.tp_len = frame.len() as u32;
+            std::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                slot.add(RING_TX_DATA_OFFSET),
+                frame.len(),
+            );
+        }
+        status.store(libc::TP_STATUS_SEND_REQUEST, Ordering::Release);
+        ring.tx_next = (ring.tx_next + 1) % ring.frames;
+        // the kernel sends the requested slots without waiting for them to be done
+        let ret = unsafe { libc::send(self.fd, std::ptr::null(), 0, libc::MSG_DONTWAIT) };
+        if ret < 0 {
+            return Err(UtcpErr::last_os_error("send"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut EtherPacketNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::EtherPacket(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch {
+                expected: "ether_packet",
+            }),
+        }
+    }
+}
+
+fn setsockopt<T>(
+    fd: c_int,
+    level: c_int,
+    name: c_int,
+    value: &T,
+    call: &'static str,
+) -> UtcpResult<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(UtcpErr::last_os_error(call));
+    }
+    Ok(())
+}
+
+/// Returns the classic BPF program that accepts the frames to `hwaddr`, broadcast and
+/// multicast, except the ones the host sends.
+fn ether_packet_filter(hwaddr: EthernetAddress) -> Vec<libc::sock_filter> {
+    const LD_W: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const LD_H: u16 = (libc::BPF_LD | libc::BPF_H | libc::BPF_ABS) as u16;
+    const LD_B: u16 = (libc::BPF_LD | libc::BPF_B | libc::BPF_ABS) as u16;
+    const JEQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const JSET: u16 = (libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K) as u16;
+    const RET: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    let insn = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    let addr = hwaddr.0;
+    vec![
+        insn(LD_W, 0, 0, SKF_AD_OFF + SKF_AD_PKTTYPE),
+        insn(JEQ, 7, 0, libc::PACKET_OUTGOING as u32),
+        // the group bit of the destination
+        insn(LD_B, 0, 0, 0),
+        insn(JSET, 4, 0, 1),
+        insn(LD_W, 0, 0, 0),
+        insn(
+            JEQ,
+            0,
+            3,
+            u32::from_be_bytes([addr[0], addr[1], addr[2], addr[3]]),
+        ),
+        insn(LD_H, 0, 0, 4),
+        insn(JEQ, 0, 1, u16::from_be_bytes([addr[4], addr[5]]) as u32),
+        insn(RET, 0, 0, u32::MAX),
+        insn(RET, 0, 0, 0),
+    ]
+}
+
+/// Returns the `PACKET_AUXDATA` of a received frame.
+fn ether_packet_auxdata(msg: &libc::msghdr) -> Option<libc::tpacket_auxdata> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let hdr = unsafe { &*cmsg };
+        if hdr.cmsg_level == libc::SOL_PACKET && hdr.cmsg_type == libc::PACKET_AUXDATA {
+            let data = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::tpacket_auxdata;
+            return Some(unsafe { std::ptr::read_unaligned(data) });
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+    None
+}
+
+/// Copies `frame` into `buf` as it was on the wire and returns its length, or `None` if it
+/// does not fit. The kernel tells with `status` that it stripped the `(tpid, tci)` tag, which
+/// is put back after the addresses, and that it left the checksum for the hardware to finish,
+/// as it does for the frames a veth peer sends.
+fn ether_packet_copy(
+    buf: &mut [u8],
+    frame: &[u8],
+    status: u32,
+    tpid: u16,
+    tci: u16,
+) -> Option<usize> {
+    const ADDRS_LEN: usize = ETHERNET_ADDR_LEN * 2;
+    let len = match status & libc::TP_STATUS_VLAN_VALID != 0 {
+        true => {
+            let tpid = match status & libc::TP_STATUS_VLAN_TPID_VALID != 0 {
+                true => tpid,
+                false => ETHERNET_TYPE_VLAN,
+            };
+            let len = frame.len() + VLAN_HEADER_LEN;
+            if frame.len() < ADDRS_LEN || len > buf.len() {
+                return None;
+            }
+            buf[..ADDRS_LEN].copy_from_slice(&frame[..ADDRS_LEN]);
+            buf[ADDRS_LEN..ADDRS_LEN + 2].copy_from_slice(&tpid.to_be_bytes());
+            buf[ADDRS_LEN + 2..ADDRS_LEN + 4].copy_from_slice(&tci.to_be_bytes());
+            buf[ADDRS_LEN + VLAN_HEADER_LEN..len].copy_from_slice(&frame[ADDRS_LEN..]);
+            len
+        }
+        false => {
+            buf.get_mut(..frame.len())?.copy_from_slice(frame);
+            frame.len()
+        }
+    };
+    if status & libc::TP_STATUS_CSUMNOTREADY != 0 {
+        ether_packet_fill_checksum(&mut buf[..len]);
+    }
+    Some(len)
+}
+
+/// Fills in the TCP or UDP checksum of an IP packet in `frame`.
+fn ether_packet_fill_checksum(frame: &mut [u8]) {
+    let Ok(eth) = EthernetFrame::new_checked(&*frame) else {
+        return;
+    };
+    let (mut ty, mut off) = (eth.ethertype(), ETHERNET_HEADER_LEN);
+    if ty == ETHERNET_TYPE_VLAN && frame.len() >= off + VLAN_HEADER_LEN {
+        ty = u16::from_be_bytes([frame[off + 2], frame[off + 3]]);
+        off += VLAN_HEADER_LEN;
+    }
+    let packet = &frame[off..];
+    let (src, dst, protocol, range) = match ty {
+        NET_PROTOCOL_TYPE_IP => {
+            let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+                return;
+            };
+            let range = ip.header_len()..ip.total() as usize;
+            (
+                IpAddr::V4(ip.src()),
+                IpAddr::V4(ip.dst()),
+                ip.protocol(),
+                range,
+            )
+        }
+        NET_PROTOCOL_TYPE_IPV6 => {
+            let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+                return;
+            };
+            let range = IPV6_HEADER_LEN..IPV6_HEADER_LEN + ip.payload_len() as usize;
+            (
+                IpAddr::V6(ip.src()),
+                IpAddr::V6(ip.dst()),
+                ip.next_header(),
+                range,
+            )
+        }
+        _ => return,
+    };
+    let sum_off = match protocol {
+        IP_PROTOCOL_TCP => 16,
+        IP_PROTOCOL_UDP => 6,
+        _ => return,
+    };
+    let Some(segment) = frame.get_mut(off + range.start..off + range.end) else {
+        return;
+    };
+    if segment.len() < sum_off + 2 {
+        return;
+    }
+    segment[sum_off..sum_off + 2].fill(0);
+    let sum = match wire::transport_checksum(src, dst, protocol, segment) {
+        // zero is no checksum in UDP
+        0 if protocol == IP_PROTOCOL_UDP => 0xffff,
+        sum => sum,
+    };
+    wire::write_checksum(segment, sum_off, sum);
+}
+
+fn ether_packet_isr(_: i32, handler: NetDeviceHandler) {
+    let dev = net_device_get_mut!(&handler);
+    let Ok(dev) = <&mut EtherPacketNetDevice>::try_from(dev) else {
+        return;
+    };
+    if dev.fd < 0 {
+        return;
+    }
+    let mut buf = [0u8; ETHER_FRAME_MAX_LEN];
+    while !net::net_device_throttled(&handler) {
+        let Some(len) = dev.receive_frame(&mut buf) else {
+            break;
+        };
+        if let Err(e) = ether::ether_input_helper(&handler, dev.hwaddr, &buf[..len]) {
+            log::debug!("dev={}, {}", dev.name, e);
+        }
+    }
+}
+
+#[test]
+fn test_ether_packet_copy() {
+    let frame: Vec<u8> = (0..20).collect();
+    let mut buf = [0u8; 32];
+    assert_eq!(ether_packet_copy(&mut buf, &frame, 0, 0, 0), Some(20));
+    assert_eq!(&buf[..20], &frame[..]);
+    let status = libc::TP_STATUS_VLAN_VALID;
+    assert_eq!(
+        ether_packet_copy(&mut buf, &frame, status, 0, 0x200a),
+        Some(24)
+    );
+    assert_eq!(&buf[..12], &frame[..12]);
+    assert_eq!(&buf[12..16], &[0x81, 0x00, 0x20, 0x0a]);
+    assert_eq!(&buf[16..24], &frame[12..]);
+    assert_eq!(
+        ether_packet_copy(&mut buf[..23], &frame, status, 0, 0x200a),
+        None
+    );
+    let status = status | libc::TP_STATUS_VLAN_TPID_VALID;
+    ether_packet_copy(&mut buf, &frame, status, 0x88a8, 0x200a).unwrap();
+    assert_eq!(&buf[12..16], &[0x88, 0xa8, 0x20, 0x0a]);
+}
+
+#[test]
+fn test_ether_packet_fill_checksum() {
+    use crate::{ip::IpAddress, wire::udp::UdpPacket};
+
+    let (src, dst) = (
+        IpAddress::parse_from("10.0.0.1"),
+        IpAddress::parse_from("10.0.0.2"),
+    );
+    let mut frame = vec![0u8; ETHERNET_HEADER_LEN + 20 + 8 + 4];
+    frame[12..14].copy_from_slice(&NET_PROTOCOL_TYPE_IP.to_be_bytes());
+    let mut ip = Ipv4Packet::new_unchecked(&mut frame[ETHERNET_HEADER_LEN..]);
+    ip.set_header_len(20);
+    ip.set_total(32);
+    ip.set_protocol(IP_PROTOCOL_UDP);
+    ip.set_src(src);
+    ip.set_dst(dst);
+    let mut udp = UdpPacket::new_unchecked(&mut frame[ETHERNET_HEADER_LEN + 20..]);
+    udp.set_len(12);
+    udp.payload_mut().copy_from_slice(b"ping");
+    // a partial sum, as the kernel leaves it
+    frame[ETHERNET_HEADER_LEN + 26] = 0x12;
+    let mut buf = [0u8; 64];
+    let len = ether_packet_copy(&mut buf, &frame, libc::TP_STATUS_CSUMNOTREADY, 0, 0).unwrap();
+    let udp = UdpPacket::new_checked(&buf[ETHERNET_HEADER_LEN + 20..len]).unwrap();
+    assert!(udp.verify_checksum(src, dst));
+}
+
+#[test]
+fn test_ether_packet_filter() {
+    // runs the instructions the program uses, on a frame the kernel classified as `pkttype`
+    fn run(program: &[libc::sock_filter], frame: &[u8], pkttype: u8) -> u32 {
+        let load = |k: u32, size: usize| match k.checked_sub(SKF_AD_OFF) {
+            Some(SKF_AD_PKTTYPE) => pkttype as u32,
+            Some(_) => unreachable!(),
+            None => frame[k as usize..k as usize + size]
+                .iter()
+                .fold(0, |acc, &b| (acc << 8) | b as u32),
+        };
+        let (mut pc, mut a) = (0, 0);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            let jump = |taken: bool| if taken { insn.jt } else { insn.jf } as usize;
+            match insn.code as u32 {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => a = load(insn.k, 4),
+                code if code == libc::BPF_LD | libc::BPF_H | libc::BPF_ABS => a = load(insn.k, 2),
+                code if code == libc::BPF_LD | libc::BPF_B | libc::BPF_ABS => a = load(insn.k, 1),
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += jump(a == insn.k)
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K => {
+                    pc += jump(a & insn.k != 0)
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return insn.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    let hwaddr = EthernetAddress([0x02, 0, 0, 0x99, 0x48, 0x01]);
+    let program = ether_packet_filter(hwaddr);
+    let frame = |dst: [u8; 6]| {
+        let mut frame = vec![0u8; ETHERNET_HEADER_LEN];
+        frame[..6].copy_from_slice(&dst);
+        frame
+    };
+    let accepts = |dst: [u8; 6], pkttype| run(&program, &frame(dst), pkttype) == u32::MAX;
+    let host = libc::PACKET_HOST;
+    assert!(accepts(hwaddr.0, host));
+    assert!(accepts([0xff; 6], libc::PACKET_BROADCAST));
+    assert!(accepts([0x01, 0, 0x5e, 0, 0, 1], libc::PACKET_MULTICAST));
+    // to other hosts, whichever half of the address differs
+    assert!(!accepts([0x02, 0, 0, 0x99, 0x48, 0x02], host));
+    assert!(!accepts([0x02, 0, 0, 0x99, 0x49, 0x01], host));
+    assert!(!accepts([0x12, 0, 0, 0x99, 0x48, 0x01], host));
+    // what the host sends, even to the device itself
+    assert!(!accepts(hwaddr.0, libc::PACKET_OUTGOING));
+    assert!(!accepts([0xff; 6], libc::PACKET_OUTGOING));
+}
@@ -0,0 +1,199 @@
+use std::{ffi::c_int, sync::Arc};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ether::{self, ETHER_FRAME_MAX_LEN, ETHER_MTU},
+    net::{
+        self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetInterface,
+        NetInterfaceHandler, net_device_register,
+    },
+    net_device_get_mut,
+    platform::{IRQFlags, linux::intr},
+    wire::ethernet::{ETHERNET_ADDR_LEN, ETHERNET_HEADER_LEN, EthernetAddress},
+};
+
+use super::INTR_IRQ_BASE;
+
+pub(crate) const ETHER_TAP_IRQ: i32 = INTR_IRQ_BASE + 2;
+
+const CLONE_DEVICE: &std::ffi::CStr = c"/dev/net/tun";
+
+/// Ethernet device backed by a Linux TAP interface. Received frames are signalled to the
+/// intr thread with `ETHER_TAP_IRQ` through `O_ASYNC`.
+#[derive(Debug)]
+pub struct EtherTapNetDevice {
+    name: String,
+    tap_name: String,
+    flags: NetDeviceFlags,
+    hwaddr: EthernetAddress,
+    fd: c_int,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl EtherTapNetDevice {
+    /// Registers a device attached to the TAP interface `tap_name`, which is created if it
+    /// does not exist. A random address is used if `hwaddr` is `None`.
+    pub fn init(tap_name: &str, hwaddr: Option<EthernetAddress>) -> UtcpResult<NetDeviceHandler> {
+        if tap_name.is_empty() || tap_name.len() >= libc::IFNAMSIZ {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "TAP name: {:?}",
+                tap_name
+            )));
+        }
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            tap_name: tap_name.to_string(),
+            flags: NetDeviceFlags::BROADCAST | NetDeviceFlags::NEED_ARP,
+            hwaddr: hwaddr.unwrap_or_else(ether::ether_addr_generate),
+            fd: -1,
+            ifaces: Vec::new(),
+        };
+        log::info!("dev={}, tap={}, hwaddr={}", name, tap_name, dev.hwaddr);
+        let handler = net_device_register(NetDevice::EtherTap(dev))?;
+        let flags = IRQFlags::SHARED;
+        intr::intr_request_irq(ETHER_TAP_IRQ, ether_tap_isr, flags, name, handler)?;
+        Ok(handler)
+    }
+
+    pub fn tap_name(&self) -> &str {
+        &self.tap_name
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+
+    fn open_tap(&self) -> UtcpResult<c_int> {
+        let fd = unsafe { libc::open(CLONE_DEVICE.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(UtcpErr::last_os_error("open"));
+        }
+        let result = self.setup_tap(fd);
+        if result.is_err() {
+            unsafe { libc::close(fd) };
+        }
+        result.map(|_| fd)
+    }
+
+    fn setup_tap(&self, fd: c_int) -> UtcpResult<()> {
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(self.tap_name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(fd, libc::TUNSETIFF, &mut ifr) } < 0 {
+            return Err(UtcpErr::last_os_error("ioctl(TUNSETIFF)"));
+        }
+        intr::intr_fd_set_async(fd, ETHER_TAP_IRQ)
+    }
+}
+
+impl NetDeviceOps for EtherTapNetDevice {
+    const MTU: u16 = ETHER_MTU;
+    const HEADER_LEN: usize = ETHERNET_HEADER_LEN;
+    const ADDR_LEN: usize = ETHERNET_ADDR_LEN;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        Some(self.hwaddr)
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.fd = self.open_tap()?;
+        self.flags.insert(NetDeviceFlags::UP);
+        // frames may have arrived before the signal was set up
+        intr::intr_raise_irq(ETHER_TAP_IRQ)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+            self.fd = -1;
+        }
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+        ether::ether_transmit_helper(self.hwaddr, ty, data, dst, |frame| {
+            self.transmit_frame(frame)
+        })
+    }
+
+    fn transmit_frame(&mut self, frame: &[u8]) -> UtcpResult<()> {
+        let ret =
+            unsafe { libc::write(self.fd, frame.as_ptr() as *const libc::c_void, frame.len()) };
+        if ret < 0 {
+            return Err(UtcpErr::last_os_error("write"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut EtherTapNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::EtherTap(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch {
+                expected: "ether_tap",
+            }),
+        }
+    }
+}
+
+fn ether_tap_isr(_: i32, handler: NetDeviceHandler) {
+    let dev = net_device_get_mut!(&handler);
+    let Ok(dev) = <&mut EtherTapNetDevice>::try_from(dev) else {
+        return;
+    };
+    if dev.fd < 0 {
+        return;
+    }
+    let mut buf = [0u8; ETHER_FRAME_MAX_LEN];
+    while !net::net_device_throttled(&handler) {
+        let len = unsafe { libc::read(dev.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                log::error!("dev={}, read: {}", dev.name, err);
+            }
+            break;
+        }
+        if let Err(e) = ether::ether_input_helper(&handler, dev.hwaddr, &buf[..len as usize]) {
+            log::debug!("dev={}, {}", dev.name, e);
+        }
+    }
+}
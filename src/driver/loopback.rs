@@ -1,39 +1,46 @@
+use std::sync::{Arc, Mutex};
+
 use crate::{
-    error::UtcpResult,
+    error::{UtcpErr, UtcpResult},
     net::{
-        self, DEVICES, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetDeviceType,
+        self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetDeviceType,
         NetInterface, NetInterfaceHandler, net_device_register,
     },
-    net_device_get, net_device_get_mut,
+    net_device_get_mut,
     platform::{IRQFlags, linux::intr},
-    utils::SmallQueue,
+    utils::{BoundedQueue, DropPolicy, PushResult, QueueStats},
 };
 
 use super::INTR_IRQ_BASE;
 
-const LOOPBACK_QUEUE_LIMIT: usize = 16;
-const LOOPBACK_IRQ: i32 = INTR_IRQ_BASE + 1;
+pub const LOOPBACK_QUEUE_LIMIT: usize = 16;
+pub(crate) const LOOPBACK_IRQ: i32 = INTR_IRQ_BASE + 1;
 
 #[derive(Debug)]
 pub struct LoopbackNetDevice {
     name: String,
     flags: NetDeviceFlags,
-    pub(self) queue: SmallQueue<(u16, Vec<u8>), LOOPBACK_QUEUE_LIMIT>,
-    ifaces: Vec<NetInterface>,
+    /// Shared between the transmitting thread and the interrupt thread.
+    queue: Mutex<BoundedQueue<(u16, Vec<u8>)>>,
+    ifaces: Vec<Arc<NetInterface>>,
 }
 
 impl LoopbackNetDevice {
     pub fn init() -> UtcpResult<NetDeviceHandler> {
+        Self::init_with_queue_limit(LOOPBACK_QUEUE_LIMIT, DropPolicy::DropHead)
+    }
+
+    pub fn init_with_queue_limit(limit: usize, policy: DropPolicy) -> UtcpResult<NetDeviceHandler> {
         let name = format!("dev{}", net::new_device_index());
         let dev = Self {
             name: name.clone(),
             flags: NetDeviceFlags::empty(),
-            queue: SmallQueue::new(),
+            queue: Mutex::new(BoundedQueue::new(limit, policy)),
             ifaces: Vec::new(),
         };
         let handler = net_device_register(NetDevice::Loopback(dev))?;
         let flags = IRQFlags::SHARED;
-        intr::intr_request_irq(LOOPBACK_IRQ, loopback_isr, flags, name, handler.clone())?;
+        intr::intr_request_irq(LOOPBACK_IRQ, loopback_isr, flags, name, handler)?;
         Ok(handler)
     }
 
@@ -42,16 +49,20 @@ impl LoopbackNetDevice {
         self_handler: NetDeviceHandler,
         iface: NetInterface,
     ) -> NetInterfaceHandler {
-        let handler = NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
             dev: self_handler,
-            iface_index: self.ifaces.len(),
             family: iface.family(),
-        };
-        self.ifaces.push(iface);
-        handler
+            iface,
+        }
     }
 
-    pub fn get_interfaces(&self) -> &[NetInterface] {
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
         &self.ifaces
     }
 }
@@ -65,6 +76,10 @@ impl NetDeviceOps for LoopbackNetDevice {
         &self.name
     }
 
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
     fn is_up(&self) -> bool {
         self.flags.contains(NetDeviceFlags::UP)
     }
@@ -79,15 +94,38 @@ impl NetDeviceOps for LoopbackNetDevice {
         Ok(())
     }
 
+    fn set_queue_limit(&mut self, limit: usize, policy: DropPolicy) -> UtcpResult<()> {
+        self.queue.lock().unwrap().set_limit(limit, policy);
+        Ok(())
+    }
+
+    fn queue_stats(&self) -> Option<QueueStats> {
+        Some(self.queue.lock().unwrap().stats())
+    }
+
     fn transmit(&mut self, ty: u16, data: &[u8], _: &mut [u8]) -> UtcpResult<()> {
-        let _ = self.queue.push((ty, data.to_vec()));
+        let mut queue = self.queue.lock().unwrap();
+        match queue.push((ty, data.to_vec())) {
+            PushResult::Queued => (),
+            PushResult::DroppedHead(_) => {
+                log::warn!(
+                    "queue overflow, dropped the oldest packet, dev={}",
+                    self.name
+                );
+            }
+            PushResult::Dropped(_) => {
+                log::warn!("queue overflow, dropped the packet, dev={}", self.name);
+                return Err(UtcpErr::QueueFull);
+            }
+        }
         log::debug!(
             "queue pushed (num:{}), dev={}, type={:?}, len={}",
-            self.queue.len(),
+            queue.len(),
             self.name,
             NetDeviceType::Loopback,
             data.len()
         );
+        drop(queue);
         intr::intr_raise_irq(LOOPBACK_IRQ)?;
         Ok(())
     }
@@ -97,12 +135,17 @@ fn loopback_isr(_: i32, handler: NetDeviceHandler) {
     let dev = net_device_get_mut!(&handler);
     let dev: &mut LoopbackNetDevice = dev.try_into().unwrap();
 
-    while let Some((ty, data)) = dev.queue.pop_front() {
+    // stop pulling packets while the protocol queues are full. the softirq raises this irq
+    // again once they have been drained.
+    while !net::net_device_throttled(&handler) {
+        let Some((ty, data)) = dev.queue.lock().unwrap().pop_front() else {
+            break;
+        };
         // TODO: remove unwrap?
         net::net_input_handler(&handler, ty, &data).unwrap();
         log::debug!(
             "queue popped (num:{}), dev={}, type={:?}, len={}",
-            dev.queue.len(),
+            dev.queue.lock().unwrap().len(),
             dev.name,
             NetDeviceType::Loopback,
             data.len()
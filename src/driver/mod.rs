@@ -1,8 +1,20 @@
+pub mod bridge;
 pub mod dummy;
+pub mod ether_packet;
+pub mod ether_tap;
 pub mod loopback;
+pub mod tun;
+pub mod udp_tunnel;
+pub mod vlan;
 
 const SIGRTMIN: i32 = 34;
 const INTR_IRQ_BASE: i32 = SIGRTMIN + 1;
 
 const INTR_IRQ_SIGUSR1: i32 = 10;
 pub const INTR_IRQ_SOFTIRQ: i32 = INTR_IRQ_SIGUSR1;
+
+const INTR_IRQ_SIGUSR2: i32 = 12;
+pub const INTR_IRQ_TX_SOFTIRQ: i32 = INTR_IRQ_SIGUSR2;
+
+const INTR_IRQ_SIGALRM: i32 = 14;
+pub const INTR_IRQ_TIMER: i32 = INTR_IRQ_SIGALRM;
@@ -0,0 +1,272 @@
+//! Point-to-point device carrying raw IP datagrams over a Linux TUN interface.
+//!
+//! Each packet read from or written to the TUN interface starts with the packet information
+//! header of the kernel, whose protocol field tells IPv4 from IPv6. There is no link-layer
+//! address, so nothing is resolved before sending, and the host routes traffic into the stack
+//! simply by routing it to the TUN interface.
+
+use std::{ffi::c_int, sync::Arc};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    net::{
+        self, NET_PROTOCOL_TYPE_IP, NET_PROTOCOL_TYPE_IPV6, NetDevice, NetDeviceFlags,
+        NetDeviceHandler, NetDeviceOps, NetInterface, NetInterfaceHandler, net_device_register,
+    },
+    net_device_get_mut,
+    platform::{IRQFlags, linux::intr},
+};
+
+use super::INTR_IRQ_BASE;
+
+pub(crate) const TUN_IRQ: i32 = INTR_IRQ_BASE + 4;
+
+/// Default MTU of TUN interfaces.
+pub const TUN_MTU: u16 = 1500;
+/// Length of the packet information header (`struct tun_pi`): flags and protocol.
+const TUN_PI_LEN: usize = 4;
+/// Set by the kernel in the flags of a packet that did not fit in the buffer.
+const TUN_PKT_STRIP: u16 = 0x0001;
+
+const CLONE_DEVICE: &std::ffi::CStr = c"/dev/net/tun";
+
+/// IP device backed by a Linux TUN interface. Received packets are signalled to the intr
+/// thread with `TUN_IRQ` through `O_ASYNC`.
+#[derive(Debug)]
+pub struct TunNetDevice {
+    name: String,
+    tun_name: String,
+    flags: NetDeviceFlags,
+    fd: c_int,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl TunNetDevice {
+    /// Registers a device attached to the TUN interface `tun_name`, which is created if it
+    /// does not exist.
+    pub fn init(tun_name: &str) -> UtcpResult<NetDeviceHandler> {
+        if tun_name.is_empty() || tun_name.len() >= libc::IFNAMSIZ {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "TUN name: {:?}",
+                tun_name
+            )));
+        }
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            tun_name: tun_name.to_string(),
+            flags: NetDeviceFlags::P2P,
+            fd: -1,
+            ifaces: Vec::new(),
+        };
+        log::info!("dev={}, tun={}", name, tun_name);
+        let handler = net_device_register(NetDevice::Tun(dev))?;
+        let flags = IRQFlags::SHARED;
+        intr::intr_request_irq(TUN_IRQ, tun_isr, flags, name, handler)?;
+        Ok(handler)
+    }
+
+    pub fn tun_name(&self) -> &str {
+        &self.tun_name
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+
+    fn open_tun(&self) -> UtcpResult<c_int> {
+        let fd = unsafe { libc::open(CLONE_DEVICE.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(UtcpErr::last_os_error("open"));
+        }
+        let result = self.setup_tun(fd);
+        if result.is_err() {
+            unsafe { libc::close(fd) };
+        }
+        result.map(|_| fd)
+    }
+
+    fn setup_tun(&self, fd: c_int) -> UtcpResult<()> {
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(self.tun_name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        // with the packet information header
+        ifr.ifr_ifru.ifru_flags = libc::IFF_TUN as libc::c_short;
+        if unsafe { libc::ioctl(fd, libc::TUNSETIFF, &mut ifr) } < 0 {
+            return Err(UtcpErr::last_os_error("ioctl(TUNSETIFF)"));
+        }
+        intr::intr_fd_set_async(fd, TUN_IRQ)
+    }
+}
+
+impl NetDeviceOps for TunNetDevice {
+    const MTU: u16 = TUN_MTU;
+    const HEADER_LEN: usize = TUN_PI_LEN;
+    const ADDR_LEN: usize = 0;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.fd = self.open_tun()?;
+        self.flags.insert(NetDeviceFlags::UP);
+        // packets may have arrived before the signal was set up
+        intr::intr_raise_irq(TUN_IRQ)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        if self.fd >= 0 {
+            unsafe { libc::close(self.fd) };
+            self.fd = -1;
+        }
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn transmit(&mut self, ty: u16, data: &[u8], _: &mut [u8]) -> UtcpResult<()> {
+        let mut pi = tun_pi(ty)
+            .map_err(|_| UtcpErr::NotSupported(format!("type {:#06x} on {}", ty, self.name)))?;
+        let iov = [
+            libc::iovec {
+                iov_base: pi.as_mut_ptr() as *mut libc::c_void,
+                iov_len: pi.len(),
+            },
+            libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            },
+        ];
+        let ret = unsafe { libc::writev(self.fd, iov.as_ptr(), iov.len() as c_int) };
+        if ret < 0 {
+            return Err(UtcpErr::last_os_error("writev"));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut TunNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::Tun(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch { expected: "tun" }),
+        }
+    }
+}
+
+/// Returns the packet information header of a datagram of protocol `ty` to write to the TUN
+/// interface.
+fn tun_pi(ty: u16) -> UtcpResult<[u8; TUN_PI_LEN]> {
+    if !matches!(ty, NET_PROTOCOL_TYPE_IP | NET_PROTOCOL_TYPE_IPV6) {
+        return Err(UtcpErr::NotSupported(format!("protocol {:#06x}", ty)));
+    }
+    let mut pi = [0u8; TUN_PI_LEN];
+    pi[2..4].copy_from_slice(&ty.to_be_bytes());
+    Ok(pi)
+}
+
+/// Returns the protocol of a packet read from the TUN interface, and the datagram in it.
+fn tun_packet(packet: &[u8]) -> UtcpResult<(u16, &[u8])> {
+    if packet.len() < TUN_PI_LEN {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "packet too short: len={}",
+            packet.len()
+        )));
+    }
+    let flags = u16::from_be_bytes([packet[0], packet[1]]);
+    if flags & TUN_PKT_STRIP != 0 {
+        return Err(UtcpErr::InvalidArgument("truncated packet".into()));
+    }
+    match u16::from_be_bytes([packet[2], packet[3]]) {
+        ty @ (NET_PROTOCOL_TYPE_IP | NET_PROTOCOL_TYPE_IPV6) => Ok((ty, &packet[TUN_PI_LEN..])),
+        ty => Err(UtcpErr::NotSupported(format!("protocol {:#06x}", ty))),
+    }
+}
+
+fn tun_isr(_: i32, handler: NetDeviceHandler) {
+    let dev = net_device_get_mut!(&handler);
+    let Ok(dev) = <&mut TunNetDevice>::try_from(dev) else {
+        return;
+    };
+    if dev.fd < 0 {
+        return;
+    }
+    let mut buf = [0u8; TUN_PI_LEN + TUN_MTU as usize];
+    while !net::net_device_throttled(&handler) {
+        let len = unsafe { libc::read(dev.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                log::error!("dev={}, read: {}", dev.name, err);
+            }
+            break;
+        }
+        let result = tun_packet(&buf[..len as usize])
+            .and_then(|(ty, data)| net::net_input_handler(&handler, ty, data));
+        if let Err(e) = result {
+            log::debug!("dev={}, {}", dev.name, e);
+        }
+    }
+}
+
+#[test]
+fn test_tun_packet() {
+    let packet = [0, 0, 0x86, 0xdd, 0x60, 0, 0, 0];
+    assert_eq!(
+        tun_packet(&packet).unwrap(),
+        (NET_PROTOCOL_TYPE_IPV6, &packet[TUN_PI_LEN..])
+    );
+    let packet = [0, 0, 0x08, 0x00, 0x45, 0, 0, 0];
+    assert_eq!(
+        tun_packet(&packet).unwrap(),
+        (NET_PROTOCOL_TYPE_IP, &packet[TUN_PI_LEN..])
+    );
+    assert!(tun_packet(&[0, 0, 0x08, 0x06, 0, 0]).is_err());
+    assert!(tun_packet(&[0, 1, 0x08, 0x00, 0x45]).is_err());
+    assert!(tun_packet(&[0, 0, 0x08]).is_err());
+}
+
+#[test]
+fn test_tun_pi() {
+    use crate::net::NET_PROTOCOL_TYPE_ARP;
+
+    assert_eq!(tun_pi(NET_PROTOCOL_TYPE_IP).unwrap(), [0, 0, 0x08, 0x00]);
+    assert_eq!(tun_pi(NET_PROTOCOL_TYPE_IPV6).unwrap(), [0, 0, 0x86, 0xdd]);
+    assert!(tun_pi(NET_PROTOCOL_TYPE_ARP).is_err());
+    // what the stack writes is read back as the same protocol
+    for ty in [NET_PROTOCOL_TYPE_IP, NET_PROTOCOL_TYPE_IPV6] {
+        let mut packet = tun_pi(ty).unwrap().to_vec();
+        packet.extend_from_slice(&[0x45, 0, 0, 0]);
+        assert_eq!(tun_packet(&packet).unwrap(), (ty, &packet[TUN_PI_LEN..]));
+    }
+}
@@ -0,0 +1,289 @@
+//! Virtual link to another utcp process, carried in datagrams of a host UDP socket.
+//!
+//! Each datagram holds one Ethernet frame or one raw IP datagram, as the two ends agree on,
+//! and is sent to the configured remote endpoint. Datagrams from anywhere else are dropped.
+//! Both ends only need a UDP port, such as one on 127.0.0.1, so a topology of several
+//! processes can be built on one machine without any privilege. Ethernet framing behaves like
+//! a cable between two hosts, and raw IP framing like a point-to-point line, where the version
+//! field tells IPv4 from IPv6.
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ether::{self, ETHER_FRAME_MAX_LEN, ETHER_MTU},
+    net::{
+        self, NET_PROTOCOL_TYPE_IP, NET_PROTOCOL_TYPE_IPV6, NetDevice, NetDeviceFlags,
+        NetDeviceHandler, NetDeviceOps, NetInterface, NetInterfaceHandler, net_device_register,
+    },
+    net_device_get_mut,
+    platform::{IRQFlags, linux::intr},
+    wire::ethernet::{ETHERNET_ADDR_LEN, ETHERNET_HEADER_LEN, EthernetAddress},
+};
+
+use super::INTR_IRQ_BASE;
+
+pub(crate) const UDP_TUNNEL_IRQ: i32 = INTR_IRQ_BASE + 5;
+
+/// What the datagrams of a tunnel carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpTunnelFraming {
+    /// Ethernet frames, from the given address or a random one if `None`.
+    Ethernet(Option<EthernetAddress>),
+    /// IPv4 and IPv6 datagrams without any header.
+    Ip,
+}
+
+/// Device tunnelling its traffic to `remote` over a UDP socket bound to `local`. Received
+/// datagrams are signalled to the intr thread with `UDP_TUNNEL_IRQ` through `O_ASYNC`.
+#[derive(Debug)]
+pub struct UdpTunnelNetDevice {
+    name: String,
+    local: SocketAddr,
+    remote: SocketAddr,
+    flags: NetDeviceFlags,
+    /// `None` with raw IP framing.
+    hwaddr: Option<EthernetAddress>,
+    socket: Option<UdpSocket>,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl UdpTunnelNetDevice {
+    /// Registers a device whose socket is bound to `local` when the device is opened, and
+    /// which exchanges datagrams with `remote` only. Both have to be of the same family.
+    pub fn init(
+        local: SocketAddr,
+        remote: SocketAddr,
+        framing: UdpTunnelFraming,
+    ) -> UtcpResult<NetDeviceHandler> {
+        if local.is_ipv4() != remote.is_ipv4() {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "endpoints of different families: {} and {}",
+                local, remote
+            )));
+        }
+        if remote.ip().is_unspecified() || remote.port() == 0 {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "remote endpoint: {}",
+                remote
+            )));
+        }
+        let (flags, hwaddr) = match framing {
+            UdpTunnelFraming::Ethernet(hwaddr) => (
+                NetDeviceFlags::BROADCAST | NetDeviceFlags::NEED_ARP,
+                Some(hwaddr.unwrap_or_else(ether::ether_addr_generate)),
+            ),
+            UdpTunnelFraming::Ip => (NetDeviceFlags::P2P, None),
+        };
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            local,
+            remote,
+            flags,
+            hwaddr,
+            socket: None,
+            ifaces: Vec::new(),
+        };
+        log::info!(
+            "dev={}, local={}, remote={}, framing={:?}",
+            name,
+            local,
+            remote,
+            framing
+        );
+        let handler = net_device_register(NetDevice::UdpTunnel(dev))?;
+        let flags = IRQFlags::SHARED;
+        intr::intr_request_irq(UDP_TUNNEL_IRQ, udp_tunnel_isr, flags, name, handler)?;
+        Ok(handler)
+    }
+
+    /// Returns the address the socket is bound to, with the port the host chose if `local`
+    /// had none, once the device is open.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+            .unwrap_or(self.local)
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+
+    fn open_socket(&self) -> UtcpResult<UdpSocket> {
+        let socket = UdpSocket::bind(self.local)?;
+        intr::intr_fd_set_async(socket.as_raw_fd(), UDP_TUNNEL_IRQ)?;
+        Ok(socket)
+    }
+
+    fn send(&self, datagram: &[u8]) -> UtcpResult<()> {
+        let Some(socket) = &self.socket else {
+            return Err(UtcpErr::DeviceNotOpened(self.name.clone()));
+        };
+        socket.send_to(datagram, self.remote)?;
+        Ok(())
+    }
+}
+
+impl NetDeviceOps for UdpTunnelNetDevice {
+    const MTU: u16 = ETHER_MTU;
+    const HEADER_LEN: usize = ETHERNET_HEADER_LEN;
+    const ADDR_LEN: usize = ETHERNET_ADDR_LEN;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        self.hwaddr
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.socket = Some(self.open_socket()?);
+        self.flags.insert(NetDeviceFlags::UP);
+        // datagrams may have arrived before the signal was set up
+        intr::intr_raise_irq(UDP_TUNNEL_IRQ)?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        self.socket = None;
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+        match self.hwaddr {
+            Some(hwaddr) => {
+                ether::ether_transmit_helper(hwaddr, ty, data, dst, |frame| self.send(frame))
+            }
+            None if matches!(ty, NET_PROTOCOL_TYPE_IP | NET_PROTOCOL_TYPE_IPV6) => self.send(data),
+            None => Err(UtcpErr::NotSupported(format!(
+                "type {:#06x} on {}",
+                ty, self.name
+            ))),
+        }
+    }
+
+    fn transmit_frame(&mut self, frame: &[u8]) -> UtcpResult<()> {
+        if self.hwaddr.is_none() {
+            return Err(UtcpErr::NotSupported(format!(
+                "raw frames on {}",
+                self.name
+            )));
+        }
+        self.send(frame)
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut UdpTunnelNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::UdpTunnel(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch {
+                expected: "udp tunnel",
+            }),
+        }
+    }
+}
+
+/// Returns the protocol of a datagram received with raw IP framing, from its version field.
+fn udp_tunnel_ip_type(datagram: &[u8]) -> UtcpResult<u16> {
+    match datagram.first().map(|b| b >> 4) {
+        Some(4) => Ok(NET_PROTOCOL_TYPE_IP),
+        Some(6) => Ok(NET_PROTOCOL_TYPE_IPV6),
+        Some(version) => Err(UtcpErr::NotSupported(format!("IP version {}", version))),
+        None => Err(UtcpErr::InvalidArgument("empty datagram".into())),
+    }
+}
+
+fn udp_tunnel_isr(_: i32, handler: NetDeviceHandler) {
+    let dev = net_device_get_mut!(&handler);
+    let Ok(dev) = <&mut UdpTunnelNetDevice>::try_from(dev) else {
+        return;
+    };
+    let Some(socket) = &dev.socket else {
+        return;
+    };
+    // one more byte than the largest frame tells the longer datagrams, cut by the socket
+    let mut buf = [0u8; ETHER_FRAME_MAX_LEN + 1];
+    while !net::net_device_throttled(&handler) {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    log::error!("dev={}, recvfrom: {}", dev.name, e);
+                }
+                break;
+            }
+        };
+        if src != dev.remote {
+            log::debug!("dev={}, datagram from {}, dropped", dev.name, src);
+            continue;
+        }
+        if len > ETHER_FRAME_MAX_LEN {
+            log::debug!("dev={}, datagram too long, dropped", dev.name);
+            continue;
+        }
+        let datagram = &buf[..len];
+        let result = match dev.hwaddr {
+            Some(hwaddr) => ether::ether_input_helper(&handler, hwaddr, datagram),
+            None => udp_tunnel_ip_type(datagram)
+                .and_then(|ty| net::net_input_handler(&handler, ty, datagram)),
+        };
+        if let Err(e) = result {
+            log::debug!("dev={}, {}", dev.name, e);
+        }
+    }
+}
+
+#[test]
+fn test_udp_tunnel_ip_type() {
+    assert_eq!(
+        udp_tunnel_ip_type(&[0x45, 0]).unwrap(),
+        NET_PROTOCOL_TYPE_IP
+    );
+    assert_eq!(
+        udp_tunnel_ip_type(&[0x60, 0]).unwrap(),
+        NET_PROTOCOL_TYPE_IPV6
+    );
+    assert!(udp_tunnel_ip_type(&[0x50, 0]).is_err());
+    assert!(udp_tunnel_ip_type(&[]).is_err());
+}
@@ -0,0 +1,256 @@
+//! 802.1Q VLAN sub-interfaces of Ethernet-class devices.
+//!
+//! A `VlanNetDevice` has no hardware of its own: it tags the frames it transmits and hands them
+//! to its parent, and `ether_input_helper` passes the tagged frames the parent receives to
+//! `vlan_input`, which demultiplexes them by VID before `net_input_handler`. Each sub-device
+//! has its own interfaces, MTU and statistics.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    net::{
+        self, NetDevice, NetDeviceFlags, NetDeviceHandler, NetDeviceOps, NetDeviceStats,
+        NetInterface, NetInterfaceHandler, net_device_register,
+    },
+    net_device_get, net_device_get_mut,
+    wire::{
+        ethernet::{ETHERNET_ADDR_LEN, ETHERNET_HEADER_LEN, EthernetAddress},
+        vlan::{ETHERNET_TYPE_VLAN, VLAN_HEADER_LEN, VLAN_PCP_MAX, VLAN_VID_MAX, VlanPacket},
+    },
+};
+
+/// VLAN devices, to demultiplex the frames of their parents.
+static VLAN_DEVICES: Mutex<Vec<NetDeviceHandler>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub struct VlanNetDevice {
+    name: String,
+    parent: NetDeviceHandler,
+    vid: u16,
+    pcp: u8,
+    flags: NetDeviceFlags,
+    /// The one of the parent.
+    hwaddr: EthernetAddress,
+    mtu: u16,
+    /// Shared between the transmitting thread and the interrupt thread.
+    stats: Mutex<NetDeviceStats>,
+    ifaces: Vec<Arc<NetInterface>>,
+}
+
+impl VlanNetDevice {
+    /// Registers a sub-device of the Ethernet-class device `parent` that sends frames tagged
+    /// with `vid` and `pcp`, and receives the frames tagged with `vid`. Like other devices, it
+    /// has to be created before `net_run`.
+    pub fn init(parent: NetDeviceHandler, vid: u16, pcp: u8) -> UtcpResult<NetDeviceHandler> {
+        if !(1..=VLAN_VID_MAX).contains(&vid) {
+            return Err(UtcpErr::InvalidArgument(format!("VID {}", vid)));
+        }
+        if pcp > VLAN_PCP_MAX {
+            return Err(UtcpErr::InvalidArgument(format!("PCP {}", pcp)));
+        }
+        let lower = net_device_get!(parent);
+        let hwaddr = match (lower, lower.hw_addr()) {
+            (NetDevice::Vlan(_), _) | (_, None) => {
+                return Err(UtcpErr::DeviceTypeMismatch {
+                    expected: "ethernet",
+                });
+            }
+            (_, Some(hwaddr)) => hwaddr,
+        };
+        let mut devices = VLAN_DEVICES.lock().unwrap();
+        if vlan_lookup(&devices, &parent, vid).is_some() {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "VID {} already exists on {}",
+                vid,
+                lower.name()
+            )));
+        }
+        let name = format!("dev{}", net::new_device_index());
+        let dev = Self {
+            name: name.clone(),
+            parent,
+            vid,
+            pcp,
+            flags: lower.flags() & (NetDeviceFlags::BROADCAST | NetDeviceFlags::NEED_ARP),
+            hwaddr,
+            mtu: net::net_device_mtu(&parent),
+            stats: Mutex::new(NetDeviceStats::default()),
+            ifaces: Vec::new(),
+        };
+        log::info!(
+            "dev={}, parent={}, vid={}, pcp={}",
+            name,
+            lower.name(),
+            vid,
+            pcp
+        );
+        let handler = net_device_register(NetDevice::Vlan(dev))?;
+        devices.push(handler);
+        Ok(handler)
+    }
+
+    pub fn parent(&self) -> NetDeviceHandler {
+        self.parent
+    }
+
+    pub fn vid(&self) -> u16 {
+        self.vid
+    }
+
+    pub fn pcp(&self) -> u8 {
+        self.pcp
+    }
+
+    pub fn set_pcp(&mut self, pcp: u8) -> UtcpResult<()> {
+        if pcp > VLAN_PCP_MAX {
+            return Err(UtcpErr::InvalidArgument(format!("PCP {}", pcp)));
+        }
+        self.pcp = pcp;
+        Ok(())
+    }
+
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// The tag does not count against the MTU, so it can be up to the one of the parent.
+    pub fn set_mtu(&mut self, mtu: u16) -> UtcpResult<()> {
+        if mtu == 0 || mtu > net::net_device_mtu(&self.parent) {
+            return Err(UtcpErr::InvalidArgument(format!("MTU {}", mtu)));
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    pub fn add_interface(
+        &mut self,
+        self_handler: NetDeviceHandler,
+        iface: NetInterface,
+    ) -> NetInterfaceHandler {
+        let iface = Arc::new(iface);
+        self.ifaces.push(iface.clone());
+        NetInterfaceHandler {
+            dev: self_handler,
+            family: iface.family(),
+            iface,
+        }
+    }
+
+    pub fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        self.ifaces.remove(index)
+    }
+
+    pub fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        &self.ifaces
+    }
+}
+
+impl NetDeviceOps for VlanNetDevice {
+    /// The default, `mtu` may be lower.
+    const MTU: u16 = crate::ether::ETHER_MTU;
+    const HEADER_LEN: usize = ETHERNET_HEADER_LEN + VLAN_HEADER_LEN;
+    const ADDR_LEN: usize = ETHERNET_ADDR_LEN;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn flags(&self) -> NetDeviceFlags {
+        self.flags
+    }
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        Some(self.hwaddr)
+    }
+
+    fn stats(&self) -> Option<NetDeviceStats> {
+        Some(*self.stats.lock().unwrap())
+    }
+
+    fn is_up(&self) -> bool {
+        self.flags.contains(NetDeviceFlags::UP)
+    }
+
+    fn open(&mut self) -> UtcpResult<()> {
+        self.flags.insert(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn close(&mut self) -> UtcpResult<()> {
+        self.flags.remove(NetDeviceFlags::UP);
+        Ok(())
+    }
+
+    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+        let mut buf = vec![0u8; VLAN_HEADER_LEN + data.len()];
+        let mut packet = VlanPacket::new_unchecked(&mut buf[..]);
+        packet.set_tci(self.pcp, self.vid);
+        packet.set_ethertype(ty);
+        packet.payload_mut().copy_from_slice(data);
+        log::debug!("dev={}, {:?}", self.name, packet);
+        // the parent puts the TPID in the type field of the Ethernet header
+        let parent = net_device_get_mut!(self.parent);
+        let result = match parent.is_up() {
+            true => parent.transmit(ETHERNET_TYPE_VLAN, &buf, dst),
+            false => Err(UtcpErr::DeviceNotOpened(parent.name().to_string())),
+        };
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                stats.tx_packets += 1;
+                stats.tx_bytes += data.len() as u64;
+            }
+            Err(_) => stats.tx_errors += 1,
+        }
+        result
+    }
+}
+
+impl<'a> TryFrom<&'a mut NetDevice> for &'a mut VlanNetDevice {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
+        match value {
+            NetDevice::Vlan(dev) => Ok(dev),
+            _ => Err(UtcpErr::DeviceTypeMismatch { expected: "vlan" }),
+        }
+    }
+}
+
+fn vlan_lookup(
+    devices: &[NetDeviceHandler],
+    parent: &NetDeviceHandler,
+    vid: u16,
+) -> Option<NetDeviceHandler> {
+    devices.iter().copied().find(|handler| {
+        matches!(net_device_get!(handler),
+            NetDevice::Vlan(dev) if dev.parent.private == parent.private && dev.vid == vid)
+    })
+}
+
+/// Handles a tagged frame received by `parent`. `data` starts at the TCI. Priority-tagged
+/// frames, with VID 0, belong to the parent itself.
+pub(crate) fn vlan_input(parent: &NetDeviceHandler, data: &[u8]) -> UtcpResult<()> {
+    let packet = VlanPacket::new_checked(data)?;
+    log::debug!("{:?}", packet);
+    if packet.vid() == 0 {
+        return net::net_input_handler(parent, packet.ethertype(), packet.payload());
+    }
+    let devices = VLAN_DEVICES.lock().unwrap();
+    let Some(handler) = vlan_lookup(&devices, parent, packet.vid()) else {
+        log::debug!("no VLAN device for vid={}", packet.vid());
+        return Ok(());
+    };
+    drop(devices);
+    let dev: &mut VlanNetDevice = net_device_get_mut!(handler).try_into()?;
+    let mut stats = dev.stats.lock().unwrap();
+    if !dev.flags.contains(NetDeviceFlags::UP) {
+        stats.rx_dropped += 1;
+        return Ok(());
+    }
+    stats.rx_packets += 1;
+    stats.rx_bytes += packet.payload().len() as u64;
+    drop(stats);
+    net::net_input_handler(&handler, packet.ethertype(), packet.payload())
+}
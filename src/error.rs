@@ -1,16 +1,192 @@
 pub type UtcpResult<T> = Result<T, UtcpErr>;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum UtcpErr {
-    Net(String),
+    // device errors
+    #[error("device not opened: dev={0}")]
+    DeviceNotOpened(String),
+    #[error("device type mismatch: expected {expected}")]
+    DeviceTypeMismatch { expected: &'static str },
+    #[error("message too long: mtu={mtu}")]
+    MessageTooLong { mtu: usize },
+    #[error("queue full")]
+    QueueFull,
+    #[error("operation not supported: {0}")]
+    NotSupported(String),
+
+    // address errors
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("address already in use")]
+    AddrInUse,
+    #[error("address not available")]
+    AddrNotAvailable,
+    #[error("address family not supported")]
+    AddrFamilyNotSupported,
+    #[error("interface already exists: dev={dev}, family={family}")]
+    InterfaceExists { dev: String, family: String },
+
+    // routing errors
+    #[error("no route to host")]
+    NoRoute,
+    #[error("network unreachable")]
+    NetUnreachable,
+    #[error("host unreachable")]
+    HostUnreachable,
+
+    // socket errors
+    #[error("operation would block")]
+    WouldBlock,
+    #[error("operation timed out")]
+    TimedOut,
+    #[error("operation in progress")]
+    InProgress,
+    #[error("broken pipe")]
+    BrokenPipe,
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("connection reset by peer")]
+    ConnectionReset,
+    #[error("connection aborted")]
+    ConnectionAborted,
+    #[error("not connected")]
+    NotConnected,
+    #[error("already connected")]
+    AlreadyConnected,
+    #[error("operation not permitted")]
+    NotPermitted,
+    #[error("bad descriptor: {0}")]
+    BadDescriptor(i32),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    // protocol errors
+    #[error("protocol not registered: type=0x{0:04x}")]
+    ProtocolNotRegistered(u16),
+    #[error("protocol not supported: {0}")]
+    ProtocolNotSupported(u8),
+    #[error("malformed packet: {0}")]
+    Malformed(String),
+    #[error("checksum mismatch: sum=0x{0:04x}")]
+    ChecksumMismatch(u16),
+
+    // resolver errors
+    #[error("name not found: {0}")]
+    NameNotFound(String),
+    #[error("name server failure: rcode={0}")]
+    NameServerFailure(u8),
+
+    // platform errors
+    #[error("interrupt error: {0}")]
     Intr(String),
+    #[error("{call} failed: errno={errno}")]
+    Sys { call: &'static str, errno: i32 },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
-impl std::fmt::Display for UtcpErr {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl UtcpErr {
+    /// Builds an error from the `errno` of the last failed libc call.
+    pub fn last_os_error(call: &'static str) -> Self {
+        UtcpErr::Sys {
+            call,
+            errno: std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        }
+    }
+
+    /// Returns the closest `errno` value for socket-API consumers.
+    pub fn errno(&self) -> i32 {
         match self {
-            UtcpErr::Net(s) => write!(f, "net error: {}", s),
-            UtcpErr::Intr(s) => write!(f, "interrupt error: {}", s),
+            UtcpErr::DeviceNotOpened(_) => libc::ENETDOWN,
+            UtcpErr::DeviceTypeMismatch { .. } => libc::ENODEV,
+            UtcpErr::MessageTooLong { .. } => libc::EMSGSIZE,
+            UtcpErr::QueueFull => libc::ENOBUFS,
+            UtcpErr::NotSupported(_) => libc::EOPNOTSUPP,
+            UtcpErr::InvalidAddress(_) => libc::EINVAL,
+            UtcpErr::AddrInUse => libc::EADDRINUSE,
+            UtcpErr::AddrNotAvailable => libc::EADDRNOTAVAIL,
+            UtcpErr::AddrFamilyNotSupported => libc::EAFNOSUPPORT,
+            UtcpErr::InterfaceExists { .. } => libc::EEXIST,
+            UtcpErr::NoRoute | UtcpErr::HostUnreachable => libc::EHOSTUNREACH,
+            UtcpErr::NetUnreachable => libc::ENETUNREACH,
+            UtcpErr::WouldBlock => libc::EWOULDBLOCK,
+            UtcpErr::TimedOut => libc::ETIMEDOUT,
+            UtcpErr::InProgress => libc::EINPROGRESS,
+            UtcpErr::BrokenPipe => libc::EPIPE,
+            UtcpErr::ConnectionRefused => libc::ECONNREFUSED,
+            UtcpErr::ConnectionReset => libc::ECONNRESET,
+            UtcpErr::ConnectionAborted => libc::ECONNABORTED,
+            UtcpErr::NotConnected => libc::ENOTCONN,
+            UtcpErr::AlreadyConnected => libc::EISCONN,
+            UtcpErr::NotPermitted => libc::EPERM,
+            UtcpErr::BadDescriptor(_) => libc::EBADF,
+            UtcpErr::InvalidArgument(_) => libc::EINVAL,
+            UtcpErr::ProtocolNotRegistered(_) => libc::EPROTONOSUPPORT,
+            UtcpErr::ProtocolNotSupported(_) => libc::EPROTONOSUPPORT,
+            UtcpErr::Malformed(_) | UtcpErr::ChecksumMismatch(_) => libc::EBADMSG,
+            UtcpErr::NameNotFound(_) => libc::ENOENT,
+            UtcpErr::NameServerFailure(_) => libc::EIO,
+            UtcpErr::Intr(_) => libc::EIO,
+            UtcpErr::Sys { errno, .. } => *errno,
+            UtcpErr::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
         }
     }
 }
+
+impl From<UtcpErr> for std::io::Error {
+    fn from(err: UtcpErr) -> Self {
+        use std::io::ErrorKind;
+
+        let err = match err {
+            UtcpErr::Io(e) => return e,
+            UtcpErr::Sys { errno, .. } => return std::io::Error::from_raw_os_error(errno),
+            err => err,
+        };
+        let kind = match &err {
+            UtcpErr::WouldBlock => ErrorKind::WouldBlock,
+            UtcpErr::TimedOut => ErrorKind::TimedOut,
+            UtcpErr::InProgress => ErrorKind::WouldBlock,
+            UtcpErr::BrokenPipe => ErrorKind::BrokenPipe,
+            UtcpErr::ConnectionRefused => ErrorKind::ConnectionRefused,
+            UtcpErr::ConnectionReset => ErrorKind::ConnectionReset,
+            UtcpErr::ConnectionAborted => ErrorKind::ConnectionAborted,
+            UtcpErr::NotConnected => ErrorKind::NotConnected,
+            UtcpErr::AddrInUse => ErrorKind::AddrInUse,
+            UtcpErr::AddrNotAvailable => ErrorKind::AddrNotAvailable,
+            UtcpErr::NoRoute | UtcpErr::HostUnreachable => ErrorKind::HostUnreachable,
+            UtcpErr::NetUnreachable => ErrorKind::NetworkUnreachable,
+            UtcpErr::DeviceNotOpened(_) => ErrorKind::NetworkDown,
+            UtcpErr::InvalidAddress(_) | UtcpErr::InvalidArgument(_) => ErrorKind::InvalidInput,
+            UtcpErr::Malformed(_) | UtcpErr::ChecksumMismatch(_) => ErrorKind::InvalidData,
+            UtcpErr::NameNotFound(_) => ErrorKind::NotFound,
+            UtcpErr::NotPermitted => ErrorKind::PermissionDenied,
+            UtcpErr::NotSupported(_)
+            | UtcpErr::AddrFamilyNotSupported
+            | UtcpErr::ProtocolNotRegistered(_)
+            | UtcpErr::ProtocolNotSupported(_) => ErrorKind::Unsupported,
+            UtcpErr::InterfaceExists { .. } | UtcpErr::AlreadyConnected => ErrorKind::AlreadyExists,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[test]
+fn test_errno_mapping() {
+    let err = UtcpErr::MessageTooLong { mtu: 1500 };
+    assert_eq!(err.errno(), libc::EMSGSIZE);
+    assert_eq!(err.to_string(), "message too long: mtu=1500");
+
+    let err: std::io::Error = UtcpErr::WouldBlock.into();
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+    let err: std::io::Error = UtcpErr::Sys {
+        call: "socket",
+        errno: libc::EACCES,
+    }
+    .into();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+    let err = UtcpErr::from(std::io::Error::from_raw_os_error(libc::ENOENT));
+    assert_eq!(err.errno(), libc::ENOENT);
+}
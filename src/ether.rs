@@ -0,0 +1,89 @@
+//! Ethernet framing shared by the drivers of Ethernet-class devices.
+
+use crate::{
+    driver::{bridge, vlan},
+    error::{UtcpErr, UtcpResult},
+    net::{self, NetDeviceHandler},
+    utils::XorShift32,
+    wire::{
+        ethernet::{
+            ETHERNET_HEADER_LEN, ETHERNET_PAYLOAD_MAX_LEN, ETHERNET_PAYLOAD_MIN_LEN,
+            EthernetAddress, EthernetFrame,
+        },
+        vlan::{ETHERNET_TYPE_VLAN, VLAN_HEADER_LEN},
+    },
+};
+
+pub const ETHER_MTU: u16 = ETHERNET_PAYLOAD_MAX_LEN as u16;
+/// Largest frame a driver has to read, with an 802.1Q tag and without the FCS.
+pub const ETHER_FRAME_MAX_LEN: usize =
+    ETHERNET_HEADER_LEN + VLAN_HEADER_LEN + ETHERNET_PAYLOAD_MAX_LEN;
+
+/// Builds a frame from `src` to the address in `dst` and passes it to `write`. Short payloads
+/// are padded to the minimum frame size.
+pub fn ether_transmit_helper(
+    src: EthernetAddress,
+    ty: u16,
+    data: &[u8],
+    dst: &[u8],
+    write: impl FnOnce(&[u8]) -> UtcpResult<()>,
+) -> UtcpResult<()> {
+    let dst = EthernetAddress::from_bytes(dst)
+        .ok_or_else(|| UtcpErr::InvalidAddress(format!("hardware address {:?}", dst)))?;
+    let len = ETHERNET_HEADER_LEN + data.len().max(ETHERNET_PAYLOAD_MIN_LEN);
+    let mut buf = vec![0u8; len];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    frame.set_dst(dst);
+    frame.set_src(src);
+    frame.set_ethertype(ty);
+    frame.payload_mut()[..data.len()].copy_from_slice(data);
+    log::debug!("{:?}", frame);
+    write(&buf)
+}
+
+/// Passes the payload of a received frame to `net_input_handler` if it is addressed to
+/// `hwaddr`, broadcast or multicast. Tagged frames go to `vlan_input` instead, and all the
+/// frames of a bridge port to `bridge_input`.
+pub fn ether_input_helper(
+    handler: &NetDeviceHandler,
+    hwaddr: EthernetAddress,
+    frame: &[u8],
+) -> UtcpResult<()> {
+    if let Some(bridge) = bridge::bridge_master(handler) {
+        return bridge::bridge_input(&bridge, handler, frame);
+    }
+    let frame = EthernetFrame::new_checked(frame)?;
+    let dst = frame.dst();
+    if dst != hwaddr && !dst.is_multicast() {
+        // for other hosts
+        return Ok(());
+    }
+    log::debug!("{:?}", frame);
+    match frame.ethertype() {
+        ETHERNET_TYPE_VLAN => vlan::vlan_input(handler, frame.payload()),
+        ty => net::net_input_handler(handler, ty, frame.payload()),
+    }
+}
+
+/// Returns a random locally administered unicast address.
+pub fn ether_addr_generate() -> EthernetAddress {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let mut rng = XorShift32::new((nanos ^ std::process::id().rotate_left(16)) | 1);
+    let mut addr = [0u8; 6];
+    for chunk in addr.chunks_mut(4) {
+        let bytes = rng.next_u32().to_be_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+    addr[0] = (addr[0] & !0x01) | 0x02;
+    EthernetAddress(addr)
+}
+
+#[test]
+fn test_ether_addr_generate() {
+    let addr = ether_addr_generate();
+    assert!(addr.is_unicast());
+    assert_eq!(addr.0[0] & 0x02, 0x02);
+}
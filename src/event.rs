@@ -0,0 +1,126 @@
+//! Readiness notification for executors and event loops.
+//!
+//! The transports call `event_notify` wherever they wake threads blocked on their condition
+//! variables, which is mostly from the softirq. The tasks waiting for the sockets that changed
+//! are woken, threads in `utcp_poll` re-check their sockets and the eventfd returned by
+//! `event_fd` becomes readable, so that an external loop can poll utcp sockets alongside its
+//! own descriptors.
+
+use std::{
+    sync::{Condvar, Mutex},
+    task::Waker,
+    time::Instant,
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    poll::PollEvents,
+};
+
+/// Incremented by every `event_notify`.
+static EVENT_SEQ: Mutex<u64> = Mutex::new(0);
+static EVENT_COND: Condvar = Condvar::new();
+/// Created on first use and kept open for the lifetime of the process.
+static EVENT_FD: Mutex<Option<i32>> = Mutex::new(None);
+
+/// Tasks waiting for one socket, kept in its PCB. They are woken once and must register
+/// again.
+#[derive(Debug, Default)]
+pub(crate) struct SocketWakers {
+    read: Vec<Waker>,
+    write: Vec<Waker>,
+}
+
+impl SocketWakers {
+    /// Registers `waker` for the readiness in `interest`, `READABLE` and/or `WRITABLE`.
+    pub(crate) fn register(&mut self, interest: PollEvents, waker: &Waker) {
+        for (event, wakers) in [
+            (PollEvents::READABLE, &mut self.read),
+            (PollEvents::WRITABLE, &mut self.write),
+        ] {
+            if interest.contains(event) && !wakers.iter().any(|w| w.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+    }
+
+    /// Removes and returns the tasks that `ready`, the current readiness of the socket,
+    /// concerns. Errors and hangups concern all of them.
+    pub(crate) fn take(&mut self, ready: PollEvents) -> Vec<Waker> {
+        let all = ready.intersects(PollEvents::ERROR | PollEvents::HANGUP | PollEvents::INVALID);
+        let mut woken = Vec::new();
+        if all || ready.contains(PollEvents::READABLE) {
+            woken.append(&mut self.read);
+        }
+        if all || ready.contains(PollEvents::WRITABLE) {
+            woken.append(&mut self.write);
+        }
+        woken
+    }
+}
+
+/// Wakes `woken`, taken from the sockets that changed, and everything waiting for any socket.
+/// Call it without holding the PCB lock, since a task may be polled right away.
+pub(crate) fn event_notify(woken: Vec<Waker>) {
+    *EVENT_SEQ.lock().unwrap() += 1;
+    EVENT_COND.notify_all();
+    for waker in woken {
+        waker.wake();
+    }
+    if let Some(fd) = *EVENT_FD.lock().unwrap() {
+        let value = 1u64;
+        // fails with EAGAIN only when the counter is about to overflow, which is still readable
+        unsafe { libc::write(fd, &value as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+pub(crate) fn event_seq() -> u64 {
+    *EVENT_SEQ.lock().unwrap()
+}
+
+/// Blocks until an event newer than `seq` is notified. Returns false if `deadline` passed
+/// first.
+pub(crate) fn event_wait(seq: u64, deadline: Option<Instant>) -> bool {
+    let mut current = EVENT_SEQ.lock().unwrap();
+    while *current == seq {
+        current = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                EVENT_COND.wait_timeout(current, deadline - now).unwrap().0
+            }
+            None => EVENT_COND.wait(current).unwrap(),
+        };
+    }
+    true
+}
+
+/// Returns an eventfd that becomes readable when a socket may have changed. Call
+/// `event_clear` before polling the sockets so that later changes are not missed.
+pub fn event_fd() -> UtcpResult<i32> {
+    let mut event_fd = EVENT_FD.lock().unwrap();
+    if let Some(fd) = *event_fd {
+        return Ok(fd);
+    }
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(UtcpErr::last_os_error("eventfd"));
+    }
+    *event_fd = Some(fd);
+    Ok(fd)
+}
+
+/// Resets the eventfd to the non-readable state.
+pub fn event_clear() -> UtcpResult<()> {
+    let Some(fd) = *EVENT_FD.lock().unwrap() else {
+        return Ok(());
+    };
+    let mut value = 0u64;
+    let ret = unsafe { libc::read(fd, &mut value as *mut u64 as *mut libc::c_void, 8) };
+    if ret < 0 && std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock {
+        return Err(UtcpErr::last_os_error("read"));
+    }
+    Ok(())
+}
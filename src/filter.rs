@@ -0,0 +1,557 @@
+//! Packet filtering of IPv4 datagrams at five hooks of the stack.
+//!
+//! `Prerouting` sees every datagram received, after NAT translated its destination, `Input`
+//! the ones for the stack, `Forward` the forwarded ones, `Output` the ones the stack builds and
+//! `Postrouting` every datagram sent, before NAT translates its source. At a hook, the handlers
+//! registered for it run first, in the order they were registered, then its rules, of which
+//! the first matching one decides. The policy of the hook decides for the datagrams no rule
+//! matches. Datagrams are tracked as connections while anything is registered, so that rules
+//! can match on the state of their connection, in the table that NAT keeps its mappings in.
+
+use std::{ops::RangeInclusive, sync::Mutex, time::Instant};
+
+use bitflags::bitflags;
+
+use crate::{
+    conntrack::{self, CONNTRACK_TABLE, ConntrackEntry},
+    error::{UtcpErr, UtcpResult},
+    ip::{
+        self, IP_ADDR_ANY, IP_ADDR_BROADCAST, IP_PROTOCOL_ICMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP,
+        IpAddress,
+    },
+    net::NetDeviceHandler,
+    wire::{
+        icmp::{
+            ICMP_CODE_PORT_UNREACH, ICMP_HEADER_LEN, ICMP_TYPE_DEST_UNREACH,
+            ICMP_TYPE_TIME_EXCEEDED, IcmpPacket,
+        },
+        ipv4::Ipv4Packet,
+        tcp::{TCP_HEADER_MIN_LEN, TcpFlags, TcpPacket},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterHook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+    Postrouting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterVerdict {
+    Accept,
+    Drop,
+    /// Drops a received datagram and answers it with RST for TCP, or ICMP port unreachable.
+    /// Datagrams built by the stack are only dropped.
+    Reject,
+}
+
+bitflags! {
+    /// States of the connection of a datagram.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FilterStates: u8 {
+        /// Starts a connection, or is sent the same way before any reply.
+        const NEW = 0x1;
+        const ESTABLISHED = 0x2;
+        /// ICMP errors about a tracked connection.
+        const RELATED = 0x4;
+        /// Belongs to no connection and can not start one, such as TCP segments without SYN.
+        const INVALID = 0x8;
+    }
+}
+
+/// A datagram at a hook, as handlers see it.
+#[derive(Debug)]
+pub struct FilterPacket<'a> {
+    pub hook: FilterHook,
+    /// The device the datagram was received on, for the datagrams that were.
+    pub in_dev: Option<NetDeviceHandler>,
+    /// The device the datagram is sent out of, from the forward and the output hooks on.
+    pub out_dev: Option<NetDeviceHandler>,
+    /// One of the states.
+    pub state: FilterStates,
+    /// Handlers may modify the datagram, keeping its header and checksums valid.
+    pub datagram: &'a mut Vec<u8>,
+}
+
+pub type FilterHandler = fn(packet: &mut FilterPacket) -> FilterVerdict;
+
+/// What a rule matches. `None` matches anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterMatch {
+    pub in_dev: Option<NetDeviceHandler>,
+    pub out_dev: Option<NetDeviceHandler>,
+    /// Network and netmask of the source address.
+    pub src: Option<(IpAddress, IpAddress)>,
+    pub dst: Option<(IpAddress, IpAddress)>,
+    pub protocol: Option<u8>,
+    /// Source ports of TCP or UDP, which `protocol` has to be.
+    pub src_ports: Option<RangeInclusive<u16>>,
+    pub dst_ports: Option<RangeInclusive<u16>>,
+    /// TCP flags that, under the mask (first), equal the value (second). SYN without ACK is
+    /// `(SYN | ACK, SYN)`.
+    pub tcp_flags: Option<(TcpFlags, TcpFlags)>,
+    pub states: Option<FilterStates>,
+}
+
+/// The fields of a datagram rules match on.
+struct FilterFields {
+    protocol: u8,
+    src: IpAddress,
+    dst: IpAddress,
+    ports: Option<(u16, u16)>,
+    tcp_flags: Option<TcpFlags>,
+}
+
+impl FilterFields {
+    fn parse(datagram: &[u8]) -> Self {
+        let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+        let payload = ip_hdr.payload();
+        let protocol = ip_hdr.protocol();
+        let ports = match protocol {
+            IP_PROTOCOL_TCP | IP_PROTOCOL_UDP if payload.len() >= 4 => Some((
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            )),
+            _ => None,
+        };
+        let tcp_flags = match protocol {
+            IP_PROTOCOL_TCP if payload.len() >= TCP_HEADER_MIN_LEN => {
+                Some(TcpPacket::new_unchecked(payload).flags())
+            }
+            _ => None,
+        };
+        Self {
+            protocol,
+            src: ip_hdr.src(),
+            dst: ip_hdr.dst(),
+            ports,
+            tcp_flags,
+        }
+    }
+}
+
+impl FilterMatch {
+    fn matches(&self, packet: &FilterPacket, fields: &FilterFields) -> bool {
+        let prefix = |prefix: &Option<(IpAddress, IpAddress)>, addr: IpAddress| {
+            prefix.is_none_or(|(network, netmask)| {
+                let (addr, network, netmask) =
+                    (u32::from(addr), u32::from(network), u32::from(netmask));
+                addr & netmask == network & netmask
+            })
+        };
+        let port = |ports: &Option<RangeInclusive<u16>>, port: Option<u16>| match ports {
+            Some(ports) => port.is_some_and(|port| ports.contains(&port)),
+            None => true,
+        };
+        (self.in_dev.is_none() || self.in_dev == packet.in_dev)
+            && (self.out_dev.is_none() || self.out_dev == packet.out_dev)
+            && prefix(&self.src, fields.src)
+            && prefix(&self.dst, fields.dst)
+            && self
+                .protocol
+                .is_none_or(|protocol| protocol == fields.protocol)
+            && port(&self.src_ports, fields.ports.map(|ports| ports.0))
+            && port(&self.dst_ports, fields.ports.map(|ports| ports.1))
+            && self.tcp_flags.is_none_or(|(mask, value)| {
+                fields.tcp_flags.is_some_and(|flags| flags & mask == value)
+            })
+            && self
+                .states
+                .is_none_or(|states| states.intersects(packet.state))
+    }
+
+    /// Rejects what can never match at `hook`.
+    fn validate(&self, hook: FilterHook) -> UtcpResult<()> {
+        if self.in_dev.is_some() && hook == FilterHook::Output {
+            return Err(UtcpErr::InvalidArgument(
+                "no input device at the output hook".into(),
+            ));
+        }
+        if self.out_dev.is_some() && matches!(hook, FilterHook::Prerouting | FilterHook::Input) {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "no output device at the {:?} hook",
+                hook
+            )));
+        }
+        if (self.src_ports.is_some() || self.dst_ports.is_some())
+            && !matches!(self.protocol, Some(IP_PROTOCOL_TCP | IP_PROTOCOL_UDP))
+        {
+            return Err(UtcpErr::InvalidArgument(
+                "ports need TCP or UDP as the protocol".into(),
+            ));
+        }
+        if self.tcp_flags.is_some() && self.protocol != Some(IP_PROTOCOL_TCP) {
+            return Err(UtcpErr::InvalidArgument(
+                "TCP flags need TCP as the protocol".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A rule and the datagrams it matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterRule {
+    pub id: usize,
+    pub hook: FilterHook,
+    pub matches: FilterMatch,
+    pub verdict: FilterVerdict,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+struct FilterTable {
+    handlers: Vec<(usize, FilterHook, FilterHandler)>,
+    rules: Vec<FilterRule>,
+    /// By hook, in the order of the variants.
+    policies: [FilterVerdict; 5],
+    next_id: usize,
+}
+
+impl FilterTable {
+    fn is_active(&self) -> bool {
+        !self.handlers.is_empty()
+            || !self.rules.is_empty()
+            || self
+                .policies
+                .iter()
+                .any(|policy| *policy != FilterVerdict::Accept)
+    }
+}
+
+static FILTER_TABLE: Mutex<FilterTable> = Mutex::new(FilterTable {
+    handlers: Vec::new(),
+    rules: Vec::new(),
+    policies: [FilterVerdict::Accept; 5],
+    next_id: 1,
+});
+
+/// Registers a handler that sees every datagram at `hook`. Returns the id that unregisters it.
+pub fn filter_handler_register(hook: FilterHook, handler: FilterHandler) -> usize {
+    let mut table = FILTER_TABLE.lock().unwrap();
+    let id = table.next_id;
+    table.next_id += 1;
+    table.handlers.push((id, hook, handler));
+    log::info!("registered handler: id={}, hook={:?}", id, hook);
+    id
+}
+
+pub fn filter_handler_unregister(id: usize) -> UtcpResult<()> {
+    let mut table = FILTER_TABLE.lock().unwrap();
+    let len = table.handlers.len();
+    table
+        .handlers
+        .retain(|(handler_id, _, _)| *handler_id != id);
+    match table.handlers.len() == len {
+        true => Err(UtcpErr::InvalidArgument(format!("no such handler: {}", id))),
+        false => Ok(()),
+    }
+}
+
+/// Appends a rule to the ones of `hook`. Returns its id.
+pub fn filter_rule_add(
+    hook: FilterHook,
+    matches: FilterMatch,
+    verdict: FilterVerdict,
+) -> UtcpResult<usize> {
+    matches.validate(hook)?;
+    let mut table = FILTER_TABLE.lock().unwrap();
+    let id = table.next_id;
+    table.next_id += 1;
+    log::info!(
+        "rule added: id={}, hook={:?}, verdict={:?}, {:?}",
+        id,
+        hook,
+        verdict,
+        matches
+    );
+    table.rules.push(FilterRule {
+        id,
+        hook,
+        matches,
+        verdict,
+        packets: 0,
+        bytes: 0,
+    });
+    Ok(id)
+}
+
+pub fn filter_rule_del(id: usize) -> UtcpResult<()> {
+    let mut table = FILTER_TABLE.lock().unwrap();
+    let len = table.rules.len();
+    table.rules.retain(|rule| rule.id != id);
+    match table.rules.len() == len {
+        true => Err(UtcpErr::InvalidArgument(format!("no such rule: {}", id))),
+        false => Ok(()),
+    }
+}
+
+/// Returns the rules of every hook with their counters, in the order they are evaluated.
+pub fn filter_rules() -> Vec<FilterRule> {
+    FILTER_TABLE.lock().unwrap().rules.clone()
+}
+
+pub fn filter_reset_counters() {
+    for rule in FILTER_TABLE.lock().unwrap().rules.iter_mut() {
+        rule.packets = 0;
+        rule.bytes = 0;
+    }
+}
+
+/// Sets what happens to the datagrams no rule of `hook` matches. It is `Accept` initially.
+pub fn filter_set_policy(hook: FilterHook, verdict: FilterVerdict) {
+    log::info!("policy: hook={:?}, verdict={:?}", hook, verdict);
+    FILTER_TABLE.lock().unwrap().policies[hook as usize] = verdict;
+}
+
+pub fn filter_policy(hook: FilterHook) -> FilterVerdict {
+    FILTER_TABLE.lock().unwrap().policies[hook as usize]
+}
+
+/// Runs the handlers and the rules of the hook of `packet`. Datagrams a handler left invalid
+/// are dropped.
+pub(crate) fn filter_hook(packet: &mut FilterPacket) -> FilterVerdict {
+    let handlers: Vec<FilterHandler> = {
+        let table = FILTER_TABLE.lock().unwrap();
+        if !table.is_active() {
+            return FilterVerdict::Accept;
+        }
+        table
+            .handlers
+            .iter()
+            .filter(|(_, hook, _)| *hook == packet.hook)
+            .map(|(_, _, handler)| *handler)
+            .collect()
+    };
+    // handlers run without the lock, so that they can change the rules
+    for handler in handlers {
+        let verdict = handler(packet);
+        if let Err(e) = Ipv4Packet::new_checked(&packet.datagram[..]) {
+            log::warn!("dropped, a handler broke the datagram: {}", e);
+            return FilterVerdict::Drop;
+        }
+        if verdict != FilterVerdict::Accept {
+            log::debug!("{:?} by a handler at {:?}", verdict, packet.hook);
+            return verdict;
+        }
+    }
+
+    let fields = FilterFields::parse(packet.datagram);
+    let mut table = FILTER_TABLE.lock().unwrap();
+    let rule = table
+        .rules
+        .iter_mut()
+        .filter(|rule| rule.hook == packet.hook)
+        .find(|rule| rule.matches.matches(packet, &fields));
+    match rule {
+        Some(rule) => {
+            rule.packets += 1;
+            rule.bytes += packet.datagram.len() as u64;
+            log::debug!(
+                "{:?} by rule {} at {:?}",
+                rule.verdict,
+                rule.id,
+                packet.hook
+            );
+            rule.verdict
+        }
+        None => table.policies[packet.hook as usize],
+    }
+}
+
+fn filter_is_icmp_error(ty: u8) -> bool {
+    // destination unreachable, source quench, redirect, time exceeded and parameter problem
+    matches!(
+        ty,
+        ICMP_TYPE_DEST_UNREACH | 4 | 5 | ICMP_TYPE_TIME_EXCEEDED | 12
+    )
+}
+
+/// Tracks the connection of a datagram the stack received or built, and returns its state.
+/// Datagrams keep the state they had here through the following hooks.
+pub(crate) fn filter_conntrack(datagram: &[u8]) -> FilterStates {
+    if !FILTER_TABLE.lock().unwrap().is_active() {
+        return FilterStates::NEW;
+    }
+    let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+    let (protocol, payload) = (ip_hdr.protocol(), ip_hdr.payload());
+    let now = Instant::now();
+    let mut table = CONNTRACK_TABLE.lock().unwrap();
+
+    // errors quote the datagram they are about
+    if protocol == IP_PROTOCOL_ICMP
+        && payload.len() >= ICMP_HEADER_LEN
+        && filter_is_icmp_error(payload[0])
+    {
+        let quoted = Ipv4Packet::new_unchecked(&payload[ICMP_HEADER_LEN..]);
+        let quoted_len = payload.len() - ICMP_HEADER_LEN;
+        if quoted_len < 20 || quoted.header_len() < 20 || quoted.header_len() + 4 > quoted_len {
+            return FilterStates::INVALID;
+        }
+        let quoted_payload = &payload[ICMP_HEADER_LEN + quoted.header_len()..];
+        let (src_port, dst_port) = conntrack::conntrack_ports(quoted.protocol(), quoted_payload);
+        let (src, dst) = ((quoted.src(), src_port), (quoted.dst(), dst_port));
+        return match table.find(quoted.protocol(), src, dst, now) {
+            Some(_) => FilterStates::RELATED,
+            None => FilterStates::INVALID,
+        };
+    }
+
+    let (src_port, dst_port) = conntrack::conntrack_ports(protocol, payload);
+    let (src, dst) = ((ip_hdr.src(), src_port), (ip_hdr.dst(), dst_port));
+    let flags = match protocol {
+        IP_PROTOCOL_TCP if payload.len() >= TCP_HEADER_MIN_LEN => {
+            TcpPacket::new_unchecked(payload).flags()
+        }
+        _ => TcpFlags::empty(),
+    };
+    let Some(entry) = table.find(protocol, src, dst, now) else {
+        if protocol == IP_PROTOCOL_TCP && !flags.contains(TcpFlags::SYN) {
+            return FilterStates::INVALID;
+        }
+        let mut entry = ConntrackEntry::new(protocol, src, dst);
+        entry.track(payload, false);
+        table.insert(entry, now);
+        return FilterStates::NEW;
+    };
+    let reply = entry.is_reply(src);
+    entry.track(payload, reply);
+    match entry.replied() {
+        true => FilterStates::ESTABLISHED,
+        false => FilterStates::NEW,
+    }
+}
+
+/// Answers a received datagram that was rejected: TCP with RST sent on behalf of the
+/// destination, and the rest with ICMP port unreachable. Broadcast, multicast and ICMP errors
+/// are not answered.
+pub(crate) fn filter_reject(datagram: &[u8]) {
+    let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+    let (src, dst, payload) = (ip_hdr.src(), ip_hdr.dst(), ip_hdr.payload());
+    let not_unicast =
+        |addr: IpAddress| addr == IP_ADDR_ANY || addr == IP_ADDR_BROADCAST || addr.is_multicast();
+    if not_unicast(src) || not_unicast(dst) {
+        return;
+    }
+    let result = match ip_hdr.protocol() {
+        IP_PROTOCOL_TCP => {
+            let Ok(seg) = TcpPacket::new_checked(payload) else {
+                return;
+            };
+            if seg.flags().contains(TcpFlags::RST) {
+                return;
+            }
+            let (seq, ack, flags) = match seg.flags().contains(TcpFlags::ACK) {
+                true => (seg.ack(), 0, TcpFlags::RST),
+                false => (
+                    0,
+                    seg.seq().wrapping_add(seg.segment_len() as u32),
+                    TcpFlags::RST | TcpFlags::ACK,
+                ),
+            };
+            let mut buf = vec![0u8; TCP_HEADER_MIN_LEN];
+            let mut rst = TcpPacket::new_unchecked(&mut buf[..]);
+            rst.set_src_port(seg.dst_port());
+            rst.set_dst_port(seg.src_port());
+            rst.set_seq(seq);
+            rst.set_ack(ack);
+            rst.set_header_len(TCP_HEADER_MIN_LEN);
+            rst.set_flags(flags);
+            rst.fill_checksum(dst, src);
+            ip::ip_output_from(IP_PROTOCOL_TCP, &buf, dst, src)
+        }
+        IP_PROTOCOL_ICMP if payload.is_empty() || filter_is_icmp_error(payload[0]) => return,
+        _ => {
+            // the header and the first 8 bytes of the payload are quoted
+            let quoted = &datagram[..(ip_hdr.header_len() + 8).min(ip_hdr.total() as usize)];
+            let mut buf = vec![0u8; ICMP_HEADER_LEN + quoted.len()];
+            let mut icmp = IcmpPacket::new_unchecked(&mut buf[..]);
+            icmp.set_msg_type(ICMP_TYPE_DEST_UNREACH);
+            icmp.set_code(ICMP_CODE_PORT_UNREACH);
+            icmp.payload_mut().copy_from_slice(quoted);
+            icmp.fill_checksum();
+            ip::ip_output(IP_PROTOCOL_ICMP, &buf, IP_ADDR_ANY, src)
+        }
+    };
+    if let Err(e) = result {
+        log::warn!("failed to reject: src={}, dst={}, {}", src, dst, e);
+    }
+}
+
+#[test]
+fn test_filter_match() {
+    use crate::wire::ipv4::IPV4_HEADER_MIN_LEN;
+
+    let (src, dst) = (
+        IpAddress::parse_from("192.0.2.1"),
+        IpAddress::parse_from("198.51.100.2"),
+    );
+    let mut datagram = vec![0u8; IPV4_HEADER_MIN_LEN + TCP_HEADER_MIN_LEN];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut datagram[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_total((IPV4_HEADER_MIN_LEN + TCP_HEADER_MIN_LEN) as u16);
+    ip_hdr.set_protocol(IP_PROTOCOL_TCP);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    let mut seg = TcpPacket::new_unchecked(ip_hdr.payload_mut());
+    seg.set_src_port(40000);
+    seg.set_dst_port(22);
+    seg.set_header_len(TCP_HEADER_MIN_LEN);
+    seg.set_flags(TcpFlags::SYN);
+
+    let fields = FilterFields::parse(&datagram);
+    let mut datagram = datagram.clone();
+    let packet = FilterPacket {
+        hook: FilterHook::Input,
+        in_dev: None,
+        out_dev: None,
+        state: FilterStates::NEW,
+        datagram: &mut datagram,
+    };
+    let netmask = IpAddress::parse_from("255.255.255.0");
+    let syn = FilterMatch {
+        src: Some((IpAddress::parse_from("192.0.2.0"), netmask)),
+        protocol: Some(IP_PROTOCOL_TCP),
+        dst_ports: Some(22..=22),
+        tcp_flags: Some((TcpFlags::SYN | TcpFlags::ACK, TcpFlags::SYN)),
+        states: Some(FilterStates::NEW | FilterStates::INVALID),
+        ..Default::default()
+    };
+    assert!(FilterMatch::default().matches(&packet, &fields));
+    assert!(syn.matches(&packet, &fields));
+    let other_network = FilterMatch {
+        dst: Some((IpAddress::parse_from("198.51.101.0"), netmask)),
+        ..syn.clone()
+    };
+    assert!(!other_network.matches(&packet, &fields));
+    let other_ports = FilterMatch {
+        src_ports: Some(1..=1023),
+        ..syn.clone()
+    };
+    assert!(!other_ports.matches(&packet, &fields));
+    let established = FilterMatch {
+        states: Some(FilterStates::ESTABLISHED),
+        ..syn.clone()
+    };
+    assert!(!established.matches(&packet, &fields));
+    let udp = FilterMatch {
+        protocol: Some(IP_PROTOCOL_UDP),
+        ..Default::default()
+    };
+    assert!(!udp.matches(&packet, &fields));
+
+    assert!(syn.validate(FilterHook::Input).is_ok());
+    let no_protocol = FilterMatch {
+        dst_ports: Some(22..=22),
+        ..Default::default()
+    };
+    assert!(no_protocol.validate(FilterHook::Input).is_err());
+    let flags_of_udp = FilterMatch {
+        tcp_flags: syn.tcp_flags,
+        ..udp
+    };
+    assert!(flags_of_udp.validate(FilterHook::Input).is_err());
+}
@@ -0,0 +1,369 @@
+//! ICMPv6 (RFC 4443): echo, error messages and the dispatch of Neighbor Discovery messages
+//! to `ndp`.
+//!
+//! Errors about a datagram are passed to the handler registered for its upper-layer protocol
+//! with `icmpv6_error_handler_register`, and errors about echo requests to `icmpv6_ping`.
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ipv6::{self, IPV6_MIN_MTU, IPV6_NEXT_HEADER_ICMPV6, Ipv6Address},
+    ndp,
+    net::NetInterfaceHandler,
+    wire::{
+        icmpv6::{
+            ICMPV6_CODE_NO_ROUTE, ICMPV6_CODE_PORT_UNREACH, ICMPV6_CODE_UNRECOGNIZED_OPTION,
+            ICMPV6_HEADER_LEN, ICMPV6_TYPE_DEST_UNREACH, ICMPV6_TYPE_ECHO_REPLY,
+            ICMPV6_TYPE_ECHO_REQUEST, ICMPV6_TYPE_PACKET_TOO_BIG, ICMPV6_TYPE_PARAM_PROBLEM,
+            ICMPV6_TYPE_REDIRECT, ICMPV6_TYPE_ROUTER_SOLICIT, ICMPV6_TYPE_TIME_EXCEEDED,
+            Icmpv6Packet,
+        },
+        ipv6::{IPV6_HEADER_LEN, Ipv6Packet, ipv6_ext_headers_walk},
+    },
+};
+
+/// Error messages sent in a burst before the rate limit applies (RFC 4443 section 2.4 (f)).
+const ICMPV6_ERROR_BURST: u32 = 10;
+/// One more error message may be sent after each interval.
+const ICMPV6_ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called with the type, code and parameter of an error message, and the part of the invoking
+/// datagram it carries, starting with the IPv6 header.
+pub type Icmpv6ErrorHandler = fn(ty: u8, code: u8, param: u32, invoking: &[u8]);
+
+struct Icmpv6ErrorProtocol {
+    ty: u8,
+    handler: Icmpv6ErrorHandler,
+}
+
+static mut ICMPV6_ERROR_PROTOCOLS: Vec<Icmpv6ErrorProtocol> = Vec::new();
+
+/// Token bucket limiting the error messages sent.
+struct Icmpv6RateLimit {
+    tokens: u32,
+    refilled: Option<Instant>,
+}
+
+static ICMPV6_RATE_LIMIT: Mutex<Icmpv6RateLimit> = Mutex::new(Icmpv6RateLimit {
+    tokens: ICMPV6_ERROR_BURST,
+    refilled: None,
+});
+
+#[derive(Debug, Clone, Copy)]
+enum Icmpv6EchoResult {
+    Reply,
+    Error { ty: u8, code: u8, param: u32 },
+}
+
+#[derive(Debug)]
+struct Icmpv6Echo {
+    seq: u16,
+    /// Requests being waited for by sequence number, with their result once it arrived.
+    results: Vec<(u16, Option<Icmpv6EchoResult>)>,
+}
+
+static ICMPV6_ECHO: Mutex<Icmpv6Echo> = Mutex::new(Icmpv6Echo {
+    seq: 0,
+    results: Vec::new(),
+});
+/// Notified when an echo reply or an error about an echo request arrives.
+static ICMPV6_ECHO_COND: Condvar = Condvar::new();
+
+/// Identifier of the echo requests sent by `icmpv6_ping`.
+fn icmpv6_echo_id() -> u16 {
+    std::process::id() as u16
+}
+
+/// Records the result of the request `seq` if `icmpv6_ping` is waiting for it.
+fn icmpv6_echo_complete(seq: u16, result: Icmpv6EchoResult) {
+    let mut echo = ICMPV6_ECHO.lock().unwrap();
+    if let Some((_, slot)) = echo.results.iter_mut().find(|(s, _)| *s == seq) {
+        slot.get_or_insert(result);
+        ICMPV6_ECHO_COND.notify_all();
+    }
+}
+
+/// Maps an error message to the error reported to the sender of the invoking datagram.
+pub(crate) fn icmpv6_error_to_utcp(ty: u8, code: u8, param: u32) -> UtcpErr {
+    match (ty, code) {
+        (ICMPV6_TYPE_DEST_UNREACH, ICMPV6_CODE_NO_ROUTE) => UtcpErr::NetUnreachable,
+        (ICMPV6_TYPE_DEST_UNREACH, ICMPV6_CODE_PORT_UNREACH) => UtcpErr::ConnectionRefused,
+        (ICMPV6_TYPE_PACKET_TOO_BIG, _) => UtcpErr::MessageTooLong {
+            mtu: param as usize,
+        },
+        (ICMPV6_TYPE_PARAM_PROBLEM, _) => UtcpErr::ProtocolNotSupported(code),
+        _ => UtcpErr::HostUnreachable,
+    }
+}
+
+fn icmpv6_output(mut msg: Vec<u8>, src: Ipv6Address, dst: Ipv6Address) -> UtcpResult<()> {
+    // the checksum needs the source address, which `ipv6_output` would otherwise choose
+    let src = match src.is_unspecified() {
+        true => ipv6::ipv6_route_source(dst)?,
+        false => src,
+    };
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.fill_checksum(src, dst);
+    log::debug!("{:?}", packet);
+    ipv6::ipv6_output(IPV6_NEXT_HEADER_ICMPV6, &msg, src, dst)?;
+    Ok(())
+}
+
+/// Takes a token from the bucket if there is one.
+fn icmpv6_rate_limit_take() -> bool {
+    let now = Instant::now();
+    let mut limit = ICMPV6_RATE_LIMIT.lock().unwrap();
+    let refilled = *limit.refilled.get_or_insert(now);
+    let earned = (now.duration_since(refilled).as_millis() / ICMPV6_ERROR_INTERVAL.as_millis())
+        .min(ICMPV6_ERROR_BURST as u128) as u32;
+    if earned > 0 {
+        limit.tokens = (limit.tokens + earned).min(ICMPV6_ERROR_BURST);
+        limit.refilled = Some(now);
+    }
+    if limit.tokens == 0 {
+        return false;
+    }
+    limit.tokens -= 1;
+    true
+}
+
+/// Sends an error message about `datagram` to its source, unless RFC 4443 section 2.4 (e)
+/// forbids it or the rate limit is reached.
+pub(crate) fn icmpv6_error(ty: u8, code: u8, param: u32, datagram: &[u8]) {
+    let Ok(invoking) = Ipv6Packet::new_checked(datagram) else {
+        return;
+    };
+    let (src, dst) = (invoking.src(), invoking.dst());
+    if src.is_unspecified() || src.is_multicast() {
+        return;
+    }
+    // only these errors are sent about multicast datagrams
+    if dst.is_multicast()
+        && ty != ICMPV6_TYPE_PACKET_TOO_BIG
+        && !(ty == ICMPV6_TYPE_PARAM_PROBLEM && code == ICMPV6_CODE_UNRECOGNIZED_OPTION)
+    {
+        return;
+    }
+    // never about another error message
+    if let Ok(upper) = invoking.upper_layer()
+        && upper.protocol == IPV6_NEXT_HEADER_ICMPV6
+        && invoking
+            .payload()
+            .get(upper.offset)
+            .is_none_or(|&ty| ty < ICMPV6_TYPE_ECHO_REQUEST)
+    {
+        return;
+    }
+    if !icmpv6_rate_limit_take() {
+        log::debug!("rate limited: type={}, code={}, dst={}", ty, code, src);
+        return;
+    }
+
+    // as much of the datagram as fits in the minimum MTU
+    let len = datagram
+        .len()
+        .min(IPV6_MIN_MTU - IPV6_HEADER_LEN - ICMPV6_HEADER_LEN);
+    let mut msg = vec![0u8; ICMPV6_HEADER_LEN + len];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ty);
+    packet.set_code(code);
+    packet.set_rest_of_header(param);
+    packet.payload_mut().copy_from_slice(&datagram[..len]);
+    let reply_src = match dst.is_multicast() {
+        true => Ipv6Address::UNSPECIFIED,
+        false => dst,
+    };
+    if let Err(e) = icmpv6_output(msg, reply_src, src) {
+        log::error!("failed to send an error: {}", e);
+    }
+}
+
+#[allow(static_mut_refs)]
+fn icmpv6_error_input(packet: &Icmpv6Packet<&[u8]>) {
+    let (ty, code, param) = (packet.msg_type(), packet.code(), packet.rest_of_header());
+    let invoking = packet.payload();
+    // the invoking datagram may be truncated, so its payload length is not checked
+    if invoking.len() < IPV6_HEADER_LEN {
+        log::error!("invoking datagram is too short: len={}", invoking.len());
+        return;
+    }
+    let header = Ipv6Packet::new_unchecked(invoking);
+    log::warn!(
+        "error: type={}, code={}, param={}, dst={}",
+        ty,
+        code,
+        param,
+        header.dst()
+    );
+    let payload = &invoking[IPV6_HEADER_LEN..];
+    let payload = &payload[..payload.len().min(header.payload_len() as usize)];
+    let Ok(upper) = ipv6_ext_headers_walk(header.next_header(), payload) else {
+        return;
+    };
+
+    if upper.protocol == IPV6_NEXT_HEADER_ICMPV6 {
+        if payload.len() < upper.offset + ICMPV6_HEADER_LEN {
+            return;
+        }
+        let echo = Icmpv6Packet::new_unchecked(&payload[upper.offset..]);
+        if echo.msg_type() == ICMPV6_TYPE_ECHO_REQUEST && echo.id() == icmpv6_echo_id() {
+            icmpv6_echo_complete(echo.seq(), Icmpv6EchoResult::Error { ty, code, param });
+        }
+        return;
+    }
+    for proto in unsafe { ICMPV6_ERROR_PROTOCOLS.iter() } {
+        if proto.ty == upper.protocol {
+            (proto.handler)(ty, code, param, invoking);
+            return;
+        }
+    }
+}
+
+fn icmpv6_echo_input(data: &[u8], src: Ipv6Address, dst: Ipv6Address) {
+    let packet = Icmpv6Packet::new_unchecked(data);
+    match packet.msg_type() {
+        ICMPV6_TYPE_ECHO_REQUEST => {
+            let mut reply = data.to_vec();
+            Icmpv6Packet::new_unchecked(&mut reply[..]).set_msg_type(ICMPV6_TYPE_ECHO_REPLY);
+            // replies to multicast requests come from a unicast address (section 4.2)
+            let reply_src = match dst.is_multicast() {
+                true => Ipv6Address::UNSPECIFIED,
+                false => dst,
+            };
+            if let Err(e) = icmpv6_output(reply, reply_src, src) {
+                log::error!("failed to reply: {}", e);
+            }
+        }
+        _ if packet.id() == icmpv6_echo_id() => {
+            icmpv6_echo_complete(packet.seq(), Icmpv6EchoResult::Reply);
+        }
+        _ => {}
+    }
+}
+
+/// Handles ICMPv6 messages. Called by `ipv6_input` rather than registered as a protocol,
+/// since Neighbor Discovery checks the hop limit of the datagram.
+pub(crate) fn icmpv6_input(
+    data: &[u8],
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    hop_limit: u8,
+    iface: &NetInterfaceHandler,
+) {
+    let packet = match Icmpv6Packet::new_checked(data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if !packet.verify_checksum(src, dst) {
+        log::error!("checksum mismatch: sum=0x{:04x}", packet.sum());
+        return;
+    }
+    log::debug!("{:?}", packet);
+    match packet.msg_type() {
+        ICMPV6_TYPE_DEST_UNREACH
+        | ICMPV6_TYPE_PACKET_TOO_BIG
+        | ICMPV6_TYPE_TIME_EXCEEDED
+        | ICMPV6_TYPE_PARAM_PROBLEM => icmpv6_error_input(&packet),
+        ICMPV6_TYPE_ECHO_REQUEST | ICMPV6_TYPE_ECHO_REPLY => icmpv6_echo_input(data, src, dst),
+        ICMPV6_TYPE_ROUTER_SOLICIT..=ICMPV6_TYPE_REDIRECT => {
+            ndp::ndp_input(&packet, src, dst, hop_limit, iface)
+        }
+        // unknown informational messages are discarded, unknown errors are not expected
+        ty => log::debug!("unsupported message: type={}", ty),
+    }
+}
+
+/// Registers a handler for the error messages about datagrams carrying the given next header
+/// value.
+#[allow(static_mut_refs)]
+pub fn icmpv6_error_handler_register(ty: u8, handler: Icmpv6ErrorHandler) -> UtcpResult<()> {
+    // Safety: handlers are registered before the stack runs
+    let protocols = unsafe { &mut ICMPV6_ERROR_PROTOCOLS };
+    if protocols.iter().any(|proto| proto.ty == ty) {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "ICMPv6 error handler already registered: {}",
+            ty
+        )));
+    }
+    protocols.push(Icmpv6ErrorProtocol { ty, handler });
+    Ok(())
+}
+
+/// Sends an echo request with `data` to `dst` and waits up to `timeout` for the reply.
+/// Returns the round-trip time, or the error reported by the network.
+pub fn icmpv6_ping(dst: Ipv6Address, data: &[u8], timeout: Duration) -> UtcpResult<Duration> {
+    let seq = {
+        let mut echo = ICMPV6_ECHO.lock().unwrap();
+        echo.seq = echo.seq.wrapping_add(1);
+        let seq = echo.seq;
+        echo.results.push((seq, None));
+        seq
+    };
+    let mut msg = vec![0u8; ICMPV6_HEADER_LEN + data.len()];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ICMPV6_TYPE_ECHO_REQUEST);
+    packet.set_code(0);
+    packet.set_id(icmpv6_echo_id());
+    packet.set_seq(seq);
+    packet.payload_mut().copy_from_slice(data);
+    let start = Instant::now();
+    if let Err(e) = icmpv6_output(msg, Ipv6Address::UNSPECIFIED, dst) {
+        ICMPV6_ECHO
+            .lock()
+            .unwrap()
+            .results
+            .retain(|(s, _)| *s != seq);
+        return Err(e);
+    }
+
+    let deadline = start + timeout;
+    let mut echo = ICMPV6_ECHO.lock().unwrap();
+    loop {
+        let pos = echo.results.iter().position(|(s, _)| *s == seq).unwrap();
+        let now = Instant::now();
+        let result = match echo.results[pos].1 {
+            Some(Icmpv6EchoResult::Reply) => Some(Ok(start.elapsed())),
+            Some(Icmpv6EchoResult::Error { ty, code, param }) => {
+                Some(Err(icmpv6_error_to_utcp(ty, code, param)))
+            }
+            // late replies are not waited for any more
+            None if now >= deadline => Some(Err(UtcpErr::TimedOut)),
+            None => None,
+        };
+        if let Some(result) = result {
+            echo.results.remove(pos);
+            return result;
+        }
+        echo = ICMPV6_ECHO_COND
+            .wait_timeout(echo, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+
+#[test]
+fn test_icmpv6_error_to_utcp() {
+    assert!(matches!(
+        icmpv6_error_to_utcp(ICMPV6_TYPE_DEST_UNREACH, ICMPV6_CODE_PORT_UNREACH, 0),
+        UtcpErr::ConnectionRefused
+    ));
+    assert!(matches!(
+        icmpv6_error_to_utcp(
+            ICMPV6_TYPE_DEST_UNREACH,
+            crate::wire::icmpv6::ICMPV6_CODE_ADDR_UNREACH,
+            0
+        ),
+        UtcpErr::HostUnreachable
+    ));
+    assert!(matches!(
+        icmpv6_error_to_utcp(ICMPV6_TYPE_PACKET_TOO_BIG, 0, 1280),
+        UtcpErr::MessageTooLong { mtu: 1280 }
+    ));
+}
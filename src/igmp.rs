@@ -0,0 +1,432 @@
+//! IGMP host side (RFC 2236, RFC 3376) and IPv4 multicast group membership.
+//!
+//! Groups are joined per device with `igmp_join` and counted, so that several sockets can
+//! share one membership. Joining and leaving are announced with unsolicited reports, and
+//! queries are answered after a random delay. The host speaks IGMPv3 and falls back to
+//! IGMPv2 or IGMPv1 while a router of that version is heard (RFC 3376 section 7.2). Only
+//! whole groups are joined, so IGMPv3 reports carry no sources.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::{self, IP_ADDR_ALL_HOSTS, IP_ADDR_ANY, IP_PROTOCOL_IGMP, IpAddress, IpInterface},
+    net::{self, NetDeviceHandler, NetInterfaceHandler},
+    utils::XorShift32,
+    wire::{
+        igmp::*,
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+    },
+};
+
+/// 224.0.0.2, where IGMPv2 leaves are sent.
+pub const IGMP_ADDR_ALL_ROUTERS: IpAddress = IpAddress::parse_from("224.0.0.2");
+/// 224.0.0.22, where IGMPv3 reports are sent.
+pub const IGMP_ADDR_V3_ROUTERS: IpAddress = IpAddress::parse_from("224.0.0.22");
+
+/// Number of unsolicited reports sent for a change of membership.
+const IGMP_ROBUSTNESS: u32 = 2;
+const IGMP_V2_UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const IGMP_V3_UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time an older version is used after a query of it (RFC 3376 section 8.12).
+const IGMP_OLDER_QUERIER_TIMEOUT: Duration = Duration::from_secs(260);
+const IGMP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+/// IP Router Alert option (RFC 2113), which every IGMP message carries.
+const IGMP_ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgmpVersion {
+    V1,
+    V2,
+    V3,
+}
+
+#[derive(Debug)]
+struct IgmpGroup {
+    dev: NetDeviceHandler,
+    group: IpAddress,
+    /// Number of `igmp_join` calls not yet left.
+    users: usize,
+    /// When the next report is due.
+    report_at: Option<Instant>,
+    /// Reports still to send for the current change of membership or query.
+    reports_left: u32,
+    /// Group record type of the pending IGMPv3 report.
+    record: u8,
+    /// Set if this host sent the last report, so that it sends an IGMPv2 leave.
+    last_reporter: bool,
+}
+
+#[derive(Debug)]
+struct IgmpIface {
+    dev: NetDeviceHandler,
+    v1_until: Option<Instant>,
+    v2_until: Option<Instant>,
+    /// When the reply to an IGMPv3 general query is due.
+    general_report_at: Option<Instant>,
+}
+
+impl IgmpIface {
+    fn version(&self, now: Instant) -> IgmpVersion {
+        if self.v1_until.is_some_and(|until| until > now) {
+            IgmpVersion::V1
+        } else if self.v2_until.is_some_and(|until| until > now) {
+            IgmpVersion::V2
+        } else {
+            IgmpVersion::V3
+        }
+    }
+}
+
+struct IgmpState {
+    groups: Vec<IgmpGroup>,
+    ifaces: Vec<IgmpIface>,
+    rng: Option<XorShift32>,
+}
+
+impl IgmpState {
+    fn iface(&mut self, dev: NetDeviceHandler) -> &mut IgmpIface {
+        let pos = match self
+            .ifaces
+            .iter()
+            .position(|iface| iface.dev.private == dev.private)
+        {
+            Some(pos) => pos,
+            None => {
+                self.ifaces.push(IgmpIface {
+                    dev,
+                    v1_until: None,
+                    v2_until: None,
+                    general_report_at: None,
+                });
+                self.ifaces.len() - 1
+            }
+        };
+        &mut self.ifaces[pos]
+    }
+
+    fn version(&mut self, dev: NetDeviceHandler, now: Instant) -> IgmpVersion {
+        self.iface(dev).version(now)
+    }
+
+    /// Returns a random delay up to `max`.
+    fn random_delay(&mut self, max: Duration) -> Duration {
+        let rng = self.rng.get_or_insert_with(|| {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            XorShift32::new(nanos | 1)
+        });
+        max.mul_f64(rng.next_f64())
+    }
+}
+
+static IGMP: Mutex<IgmpState> = Mutex::new(IgmpState {
+    groups: Vec::new(),
+    ifaces: Vec::new(),
+    rng: None,
+});
+
+/// Sends an IGMP message to `dst` on the link of `dev`, from the address of its interface.
+fn igmp_output(dev: &NetDeviceHandler, dst: IpAddress, message: &[u8]) -> UtcpResult<()> {
+    let iface = ip::ip_iface_of_dev(dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let ip_iface: &IpInterface = iface.get().try_into().unwrap();
+    let header_len = IPV4_HEADER_MIN_LEN + IGMP_ROUTER_ALERT.len();
+    let mut buf = vec![0u8; header_len + message.len()];
+    buf[IPV4_HEADER_MIN_LEN..header_len].copy_from_slice(&IGMP_ROUTER_ALERT);
+    buf[header_len..].copy_from_slice(message);
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(header_len);
+    ip_hdr.set_ttl(1);
+    ip_hdr.set_protocol(IP_PROTOCOL_IGMP);
+    ip_hdr.set_src(ip_iface.unicast());
+    ip_hdr.set_dst(dst);
+    log::debug!(
+        "{} => {}, {:?}",
+        ip_iface.unicast(),
+        dst,
+        IgmpPacket::new_unchecked(message)
+    );
+    ip::ip_output_iface(&iface, &buf)
+}
+
+/// Builds an IGMPv1 or IGMPv2 message about `group`.
+fn igmp_message(ty: u8, group: IpAddress) -> [u8; IGMP_HEADER_LEN] {
+    let mut buf = [0u8; IGMP_HEADER_LEN];
+    let mut packet = IgmpPacket::new_unchecked(&mut buf[..]);
+    packet.set_msg_type(ty);
+    packet.set_group(group);
+    packet.fill_checksum();
+    buf
+}
+
+/// Sends the pending report of `group` in the version spoken on its device.
+fn igmp_report_send(group: &IgmpGroup, version: IgmpVersion) -> UtcpResult<()> {
+    match version {
+        IgmpVersion::V1 => {
+            let message = igmp_message(IGMP_TYPE_V1_MEMBERSHIP_REPORT, group.group);
+            igmp_output(&group.dev, group.group, &message)
+        }
+        IgmpVersion::V2 => {
+            let message = igmp_message(IGMP_TYPE_V2_MEMBERSHIP_REPORT, group.group);
+            igmp_output(&group.dev, group.group, &message)
+        }
+        IgmpVersion::V3 => {
+            let message = igmp_v3_report(&[(group.record, group.group)]);
+            igmp_output(&group.dev, IGMP_ADDR_V3_ROUTERS, &message)
+        }
+    }
+}
+
+/// Announces that the last user of `group` left it.
+fn igmp_leave_send(group: &IgmpGroup, version: IgmpVersion) -> UtcpResult<()> {
+    match version {
+        // IGMPv1 has no leave message, the membership times out on the router
+        IgmpVersion::V1 => Ok(()),
+        IgmpVersion::V2 if !group.last_reporter => Ok(()),
+        IgmpVersion::V2 => {
+            let message = igmp_message(IGMP_TYPE_V2_LEAVE_GROUP, group.group);
+            igmp_output(&group.dev, IGMP_ADDR_ALL_ROUTERS, &message)
+        }
+        IgmpVersion::V3 => {
+            let message = igmp_v3_report(&[(IGMP_V3_CHANGE_TO_INCLUDE, group.group)]);
+            igmp_output(&group.dev, IGMP_ADDR_V3_ROUTERS, &message)
+        }
+    }
+}
+
+fn igmp_unsolicited_interval(version: IgmpVersion) -> Duration {
+    match version {
+        IgmpVersion::V3 => IGMP_V3_UNSOLICITED_REPORT_INTERVAL,
+        _ => IGMP_V2_UNSOLICITED_REPORT_INTERVAL,
+    }
+}
+
+fn igmp_query_input(
+    state: &mut IgmpState,
+    dev: NetDeviceHandler,
+    query: &IgmpPacket<&[u8]>,
+    now: Instant,
+) {
+    let iface = state.iface(dev);
+    if query.is_v1_query() {
+        iface.v1_until = Some(now + IGMP_OLDER_QUERIER_TIMEOUT);
+    } else if !query.is_v3_query() {
+        iface.v2_until = Some(now + IGMP_OLDER_QUERIER_TIMEOUT);
+    }
+    let version = iface.version(now);
+    let delay = state.random_delay(query.max_resp_time());
+    let due = now + delay;
+
+    // an IGMPv3 general query is answered with one report of every group
+    if version == IgmpVersion::V3 && query.group() == IP_ADDR_ANY {
+        let iface = state.iface(dev);
+        if iface.general_report_at.is_none_or(|at| at > due) {
+            iface.general_report_at = Some(due);
+        }
+        return;
+    }
+    for group in state.groups.iter_mut().filter(|group| {
+        group.dev.private == dev.private
+            && (query.group() == IP_ADDR_ANY || query.group() == group.group)
+    }) {
+        if group.report_at.is_none_or(|at| at > due) {
+            group.report_at = Some(due);
+        }
+        if group.reports_left == 0 {
+            group.reports_left = 1;
+            group.record = IGMP_V3_MODE_IS_EXCLUDE;
+        }
+    }
+}
+
+fn igmp_input(data: &[u8], src: IpAddress, dst: IpAddress, iface: &NetInterfaceHandler) {
+    let packet = match IgmpPacket::new_checked(data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if !packet.verify_checksum() {
+        log::error!("checksum mismatch: sum=0x{:04x}", packet.sum());
+        return;
+    }
+    log::debug!("{} => {}, {:?}", src, dst, packet);
+
+    let now = Instant::now();
+    let dev = iface.dev;
+    let mut state = IGMP.lock().unwrap();
+    match packet.msg_type() {
+        IGMP_TYPE_MEMBERSHIP_QUERY => igmp_query_input(&mut state, dev, &packet, now),
+        // another member answered for the link, so an older querier does not need ours
+        IGMP_TYPE_V1_MEMBERSHIP_REPORT | IGMP_TYPE_V2_MEMBERSHIP_REPORT => {
+            let ip_iface: &IpInterface = iface.get().try_into().unwrap();
+            if src == ip_iface.unicast() || state.version(dev, now) == IgmpVersion::V3 {
+                return;
+            }
+            if let Some(group) = state
+                .groups
+                .iter_mut()
+                .find(|group| group.dev.private == dev.private && group.group == packet.group())
+            {
+                group.report_at = None;
+                group.reports_left = 0;
+                group.last_reporter = false;
+            }
+        }
+        // reports of other IGMPv3 hosts and leaves are for routers
+        _ => {}
+    }
+}
+
+fn igmp_timer() {
+    let now = Instant::now();
+    let mut state = IGMP.lock().unwrap();
+    let state = &mut *state;
+    for i in 0..state.groups.len() {
+        if state.groups[i].report_at.is_none_or(|at| at > now) {
+            continue;
+        }
+        let version = state.version(state.groups[i].dev, now);
+        let interval = igmp_unsolicited_interval(version);
+        let delay = state.random_delay(interval);
+        let group = &mut state.groups[i];
+        if let Err(e) = igmp_report_send(group, version) {
+            log::error!("failed to report {}: {}", group.group, e);
+        }
+        group.last_reporter = true;
+        group.reports_left = group.reports_left.saturating_sub(1);
+        group.report_at = (group.reports_left > 0).then(|| now + delay);
+    }
+    for iface in state.ifaces.iter_mut() {
+        if iface.general_report_at.is_none_or(|at| at > now) {
+            continue;
+        }
+        iface.general_report_at = None;
+        if iface.version(now) != IgmpVersion::V3 {
+            continue;
+        }
+        let records = state
+            .groups
+            .iter()
+            .filter(|group| group.dev.private == iface.dev.private)
+            .map(|group| (IGMP_V3_MODE_IS_EXCLUDE, group.group))
+            .collect::<Vec<_>>();
+        if records.is_empty() {
+            continue;
+        }
+        let message = igmp_v3_report(&records);
+        if let Err(e) = igmp_output(&iface.dev, IGMP_ADDR_V3_ROUTERS, &message) {
+            log::error!("failed to report groups: {}", e);
+        }
+    }
+}
+
+/// Joins `group` on `dev`, which must have an IP interface. Every call is matched by a call
+/// of `igmp_leave`.
+pub fn igmp_join(dev: NetDeviceHandler, group: IpAddress) -> UtcpResult<()> {
+    if !group.is_multicast() {
+        return Err(UtcpErr::InvalidAddress(format!(
+            "not a multicast group: {}",
+            group
+        )));
+    }
+    ip::ip_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    // every host is a member of the all-hosts group without reporting it
+    if group == IP_ADDR_ALL_HOSTS {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let mut state = IGMP.lock().unwrap();
+    if let Some(entry) = state
+        .groups
+        .iter_mut()
+        .find(|entry| entry.dev.private == dev.private && entry.group == group)
+    {
+        entry.users += 1;
+        return Ok(());
+    }
+    let version = state.version(dev, now);
+    let delay = state.random_delay(igmp_unsolicited_interval(version));
+    let mut entry = IgmpGroup {
+        dev,
+        group,
+        users: 1,
+        report_at: None,
+        reports_left: IGMP_ROBUSTNESS,
+        record: IGMP_V3_CHANGE_TO_EXCLUDE,
+        last_reporter: true,
+    };
+    if let Err(e) = igmp_report_send(&entry, version) {
+        log::error!("failed to report {}: {}", group, e);
+    }
+    entry.reports_left -= 1;
+    entry.report_at = Some(now + delay);
+    state.groups.push(entry);
+    log::info!("joined: group={}", group);
+    Ok(())
+}
+
+/// Leaves `group` on `dev` once every `igmp_join` of it is matched.
+pub fn igmp_leave(dev: NetDeviceHandler, group: IpAddress) -> UtcpResult<()> {
+    if group == IP_ADDR_ALL_HOSTS {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let mut state = IGMP.lock().unwrap();
+    let pos = state
+        .groups
+        .iter()
+        .position(|entry| entry.dev.private == dev.private && entry.group == group)
+        .ok_or(UtcpErr::AddrNotAvailable)?;
+    state.groups[pos].users -= 1;
+    if state.groups[pos].users > 0 {
+        return Ok(());
+    }
+    let entry = state.groups.remove(pos);
+    let version = state.version(dev, now);
+    if let Err(e) = igmp_leave_send(&entry, version) {
+        log::error!("failed to leave {}: {}", group, e);
+    }
+    log::info!("left: group={}", group);
+    Ok(())
+}
+
+/// Returns true if datagrams to `group` are received on `dev`.
+pub fn igmp_group_joined(dev: NetDeviceHandler, group: IpAddress) -> bool {
+    group == IP_ADDR_ALL_HOSTS
+        || IGMP
+            .lock()
+            .unwrap()
+            .groups
+            .iter()
+            .any(|entry| entry.dev.private == dev.private && entry.group == group)
+}
+
+/// Returns the groups joined on `dev`.
+pub fn igmp_groups(dev: NetDeviceHandler) -> Vec<IpAddress> {
+    IGMP.lock()
+        .unwrap()
+        .groups
+        .iter()
+        .filter(|entry| entry.dev.private == dev.private)
+        .map(|entry| entry.group)
+        .collect()
+}
+
+/// Returns the IGMP version spoken on `dev`.
+pub fn igmp_version(dev: NetDeviceHandler) -> IgmpVersion {
+    IGMP.lock().unwrap().version(dev, Instant::now())
+}
+
+pub fn igmp_init() -> UtcpResult<()> {
+    ip::ip_protocol_register(IP_PROTOCOL_IGMP, igmp_input)?;
+    net::net_timer_register(IGMP_TIMER_INTERVAL, igmp_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
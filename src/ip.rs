@@ -1,132 +1,37 @@
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, AtomicU16, Ordering},
+};
+
 use crate::{
-    error::UtcpResult,
+    arp, dhcp, dhcp_server,
+    error::{UtcpErr, UtcpResult},
+    filter::{self, FilterHook, FilterPacket, FilterStates, FilterVerdict},
+    igmp,
+    ipv6::{self, Ipv6Address},
+    nat,
     net::{
-        self, NET_PROTOCOL_TYPE_IP, NetDeviceHandler, NetInterface, NetInterfaceHandler,
-        NetProtocol,
+        self, NET_PROTOCOL_TYPE_IP, NetDeviceFlags, NetDeviceHandler, NetInterface,
+        NetInterfaceHandler, NetProtocol,
+    },
+    net_device_get, raw,
+    wire::{
+        ethernet::EthernetAddress,
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
     },
-    net_device_get, net_device_get_mut, net_iface_get, utils,
 };
 
-#[repr(C)]
-pub struct IpHeader {
-    /// Version and header length
-    vhl: u8,
-    /// Type of service
-    tos: u8,
-    /// Total length (header + data)
-    total: u16,
-    /// Identification
-    id: u16,
-    /// Fragment offset (13 bits) + flags (3 bits)
-    offset: u16,
-    /// Time to live
-    ttl: u8,
-    /// Protocol
-    protocol: u8,
-    /// Header checksum
-    sum: u16,
-    /// Source address
-    src: u32,
-    /// Destination address
-    dst: u32,
-    /// Options
-    options: [u8; 0],
-}
-
-// static assert IP header size
-const _: [(); std::mem::size_of::<IpHeader>()] = [(); 20];
-
-impl IpHeader {
-    pub fn new(data: &[u8]) -> Option<&IpHeader> {
-        if data.len() < std::mem::size_of::<IpHeader>() {
-            return None;
-        }
-        let ip_hdr = unsafe { &*(data.as_ptr() as *const IpHeader) };
-        Some(ip_hdr)
-    }
-
-    pub fn version(&self) -> u8 {
-        self.vhl >> 4
-    }
-
-    pub fn header_len(&self) -> u8 {
-        self.vhl & 0x0f
-    }
-
-    pub fn tos(&self) -> u8 {
-        self.tos
-    }
-
-    pub fn total(&self) -> u16 {
-        u16::from_be(self.total)
-    }
-
-    pub fn id(&self) -> u16 {
-        u16::from_be(self.id)
-    }
+pub const IP_PROTOCOL_ICMP: u8 = 1;
+pub const IP_PROTOCOL_IGMP: u8 = 2;
+pub const IP_PROTOCOL_TCP: u8 = 6;
+pub const IP_PROTOCOL_UDP: u8 = 17;
 
-    pub fn offset(&self) -> u16 {
-        u16::from_be(self.offset) & 0x1fff
-    }
-
-    pub fn flags(&self) -> u8 {
-        ((u16::from_be(self.offset) & 0xe000) >> 13) as u8
-    }
-
-    pub fn dont_fragment(&self) -> bool {
-        self.flags() & 0x02 != 0
-    }
-
-    pub fn more_fragments(&self) -> bool {
-        self.flags() & 0x01 != 0
-    }
-
-    pub fn ttl(&self) -> u8 {
-        self.ttl
-    }
-
-    pub fn protocol(&self) -> u8 {
-        self.protocol
-    }
-
-    pub fn sum(&self) -> u16 {
-        u16::from_be(self.sum)
-    }
-
-    pub fn src(&self) -> IpAddress {
-        IpAddress(u32::from_be(self.src))
-    }
-
-    pub fn dst(&self) -> IpAddress {
-        IpAddress(u32::from_be(self.dst))
-    }
-}
-
-impl std::fmt::Debug for IpHeader {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let version = self.version();
-        let header_len = self.header_len();
-        let tos = self.tos();
-        let total = self.total();
-        let id = self.id();
-        let offset = self.offset();
-        let df = self.dont_fragment();
-        let mf = self.more_fragments();
-        let ttl = self.ttl();
-        let protocol = self.protocol();
-        let sum = self.sum();
-        let src = self.src();
-        let dst = self.dst();
-        write!(
-            f,
-            "version={}, header_len={}, tos={}, total={}, id={}, offset={} df={}, mf={}, ttl={}, protocol={}, sum={}, src={}, dst={}",
-            version, header_len, tos, total, id, offset, df, mf, ttl, protocol, sum, src, dst
-        )
-    }
-}
+pub const IP_TTL_DEFAULT: u8 = 255;
+/// Multicast datagrams stay on the link unless the application asks otherwise.
+pub const IP_MULTICAST_TTL_DEFAULT: u8 = 1;
 
 /// IPv4 address
-#[derive(Clone, Copy, Default, Eq, PartialEq)]
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash)]
 pub struct IpAddress(u32);
 
 impl IpAddress {
@@ -164,12 +69,29 @@ impl IpAddress {
     }
 }
 
+impl IpAddress {
+    pub const fn octets(&self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+
+    /// Class D, 224.0.0.0/4.
+    pub const fn is_multicast(&self) -> bool {
+        self.0 >> 28 == 0xe
+    }
+}
+
 impl From<u32> for IpAddress {
     fn from(addr: u32) -> Self {
         IpAddress(addr)
     }
 }
 
+impl From<IpAddress> for u32 {
+    fn from(addr: IpAddress) -> Self {
+        addr.0
+    }
+}
+
 impl From<[u8; 4]> for IpAddress {
     fn from(octets: [u8; 4]) -> Self {
         IpAddress(u32::from_be_bytes(octets))
@@ -190,30 +112,367 @@ impl std::fmt::Debug for IpAddress {
     }
 }
 
+impl std::str::FromStr for IpAddress {
+    type Err = UtcpErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts
+                .next()
+                .ok_or_else(|| UtcpErr::InvalidAddress(s.to_string()))?;
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(UtcpErr::InvalidAddress(s.to_string()));
+            }
+            *octet = part
+                .parse()
+                .map_err(|_| UtcpErr::InvalidAddress(s.to_string()))?;
+        }
+        if parts.next().is_some() {
+            return Err(UtcpErr::InvalidAddress(s.to_string()));
+        }
+        Ok(IpAddress::from(octets))
+    }
+}
+
+/// Address of either family. The address of an `IpEndpoint`, so that transport PCBs serve
+/// both IPv4 and IPv6.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IpAddr {
+    V4(IpAddress),
+    V6(Ipv6Address),
+}
+
+impl IpAddr {
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            IpAddr::V4(addr) => *addr == IP_ADDR_ANY,
+            IpAddr::V6(addr) => addr.is_unspecified(),
+        }
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        match self {
+            IpAddr::V4(addr) => addr.is_multicast(),
+            IpAddr::V6(addr) => addr.is_multicast(),
+        }
+    }
+
+    /// Returns the IPv4 address of an IPv4 or IPv4-mapped IPv6 address.
+    pub fn to_ipv4(&self) -> Option<IpAddress> {
+        match self {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(addr) => addr.to_ipv4_mapped(),
+        }
+    }
+
+    /// Turns IPv4-mapped IPv6 addresses into IPv4 ones, the family they are sent with.
+    pub fn to_canonical(&self) -> IpAddr {
+        match self.to_ipv4() {
+            Some(addr) => IpAddr::V4(addr),
+            None => *self,
+        }
+    }
+
+    /// Returns the address as IPv6, mapping IPv4 ones into ::ffff:0:0/96.
+    pub fn to_ipv6_mapped(&self) -> Ipv6Address {
+        match self {
+            IpAddr::V4(addr) => Ipv6Address::from_ipv4_mapped(*addr),
+            IpAddr::V6(addr) => *addr,
+        }
+    }
+}
+
+impl Default for IpAddr {
+    fn default() -> Self {
+        IpAddr::V4(IP_ADDR_ANY)
+    }
+}
+
+impl From<IpAddress> for IpAddr {
+    fn from(addr: IpAddress) -> Self {
+        IpAddr::V4(addr)
+    }
+}
+
+impl From<Ipv6Address> for IpAddr {
+    fn from(addr: Ipv6Address) -> Self {
+        IpAddr::V6(addr)
+    }
+}
+
+impl PartialEq<IpAddress> for IpAddr {
+    fn eq(&self, other: &IpAddress) -> bool {
+        *self == IpAddr::V4(*other)
+    }
+}
+
+impl PartialEq<Ipv6Address> for IpAddr {
+    fn eq(&self, other: &Ipv6Address) -> bool {
+        *self == IpAddr::V6(*other)
+    }
+}
+
+impl std::fmt::Display for IpAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IpAddr::V4(addr) => std::fmt::Display::fmt(addr, f),
+            IpAddr::V6(addr) => std::fmt::Display::fmt(addr, f),
+        }
+    }
+}
+
+impl std::fmt::Debug for IpAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::str::FromStr for IpAddr {
+    type Err = UtcpErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.contains(':') {
+            true => Ok(IpAddr::V6(s.parse()?)),
+            false => Ok(IpAddr::V4(s.parse()?)),
+        }
+    }
+}
+
+impl From<std::net::IpAddr> for IpAddr {
+    fn from(addr: std::net::IpAddr) -> Self {
+        match addr {
+            std::net::IpAddr::V4(addr) => IpAddr::V4(addr.into()),
+            std::net::IpAddr::V6(addr) => IpAddr::V6(addr.into()),
+        }
+    }
+}
+
+impl From<IpAddr> for std::net::IpAddr {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(addr) => std::net::IpAddr::V4(addr.into()),
+            IpAddr::V6(addr) => std::net::IpAddr::V6(addr.into()),
+        }
+    }
+}
+
+/// Returns true if a PCB bound to `bound` receives datagrams sent to `addr`, which is
+/// canonical. The unspecified IPv6 address also covers IPv4 unless `v6only` is set.
+pub(crate) fn ip_addr_covers(bound: IpAddr, v6only: bool, addr: IpAddr) -> bool {
+    match (bound.to_canonical(), addr) {
+        (IpAddr::V4(bound), IpAddr::V4(addr)) => bound == IP_ADDR_ANY || bound == addr,
+        (IpAddr::V6(bound), IpAddr::V6(addr)) => bound.is_unspecified() || bound == addr,
+        (IpAddr::V6(bound), IpAddr::V4(_)) => bound.is_unspecified() && !v6only,
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+    }
+}
+
+/// Returns true if PCBs bound to `a` and `b` would receive datagrams to a common address.
+pub(crate) fn ip_addr_overlaps(a: IpAddr, a_v6only: bool, b: IpAddr, b_v6only: bool) -> bool {
+    match (a.to_canonical(), b.to_canonical()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a == IP_ADDR_ANY || b == IP_ADDR_ANY || a == b,
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.is_unspecified() || b.is_unspecified() || a == b,
+        (IpAddr::V6(a), IpAddr::V4(_)) => a.is_unspecified() && !a_v6only,
+        (IpAddr::V4(_), IpAddr::V6(b)) => b.is_unspecified() && !b_v6only,
+    }
+}
+
+/// Pair of an address of either family and a port
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct IpEndpoint {
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+impl IpEndpoint {
+    pub fn new(addr: impl Into<IpAddr>, port: u16) -> Self {
+        Self {
+            addr: addr.into(),
+            port,
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.addr, IpAddr::V6(_))
+    }
+
+    /// See `IpAddr::to_canonical`.
+    pub fn to_canonical(&self) -> IpEndpoint {
+        IpEndpoint::new(self.addr.to_canonical(), self.port)
+    }
+
+    /// Returns the endpoint in the family of `like`: IPv4 ones are mapped into IPv6 for the
+    /// PCBs of IPv6 sockets, which see IPv4 peers that way.
+    pub(crate) fn in_family_of(&self, like: &IpEndpoint) -> IpEndpoint {
+        match like.is_ipv6() {
+            true => IpEndpoint::new(self.addr.to_ipv6_mapped(), self.port),
+            false => self.to_canonical(),
+        }
+    }
+}
+
+impl std::str::FromStr for IpEndpoint {
+    type Err = UtcpErr;
+
+    /// Parses `a.b.c.d:port` or `[v6 address]:port`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| UtcpErr::InvalidAddress(s.to_string()))?;
+        let port = port
+            .parse()
+            .map_err(|_| UtcpErr::InvalidAddress(s.to_string()))?;
+        let addr = match addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+            Some(addr) => IpAddr::V6(addr.parse()?),
+            None => IpAddr::V4(addr.parse()?),
+        };
+        Ok(IpEndpoint::new(addr, port))
+    }
+}
+
+impl std::fmt::Display for IpEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.addr {
+            IpAddr::V4(addr) => write!(f, "{}:{}", addr, self.port),
+            IpAddr::V6(addr) => write!(f, "[{}]:{}", addr, self.port),
+        }
+    }
+}
+
+impl std::fmt::Debug for IpEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<std::net::Ipv4Addr> for IpAddress {
+    fn from(addr: std::net::Ipv4Addr) -> Self {
+        IpAddress::from(addr.octets())
+    }
+}
+
+impl From<IpAddress> for std::net::Ipv4Addr {
+    fn from(addr: IpAddress) -> Self {
+        std::net::Ipv4Addr::from(addr.octets())
+    }
+}
+
+impl From<std::net::SocketAddrV4> for IpEndpoint {
+    fn from(addr: std::net::SocketAddrV4) -> Self {
+        IpEndpoint::new(IpAddress::from(*addr.ip()), addr.port())
+    }
+}
+
+impl From<std::net::SocketAddrV6> for IpEndpoint {
+    fn from(addr: std::net::SocketAddrV6) -> Self {
+        IpEndpoint::new(Ipv6Address::from(*addr.ip()), addr.port())
+    }
+}
+
+impl From<std::net::SocketAddr> for IpEndpoint {
+    fn from(addr: std::net::SocketAddr) -> Self {
+        IpEndpoint::new(IpAddr::from(addr.ip()), addr.port())
+    }
+}
+
+impl From<IpEndpoint> for std::net::SocketAddr {
+    fn from(ep: IpEndpoint) -> Self {
+        std::net::SocketAddr::new(ep.addr.into(), ep.port)
+    }
+}
+
+#[test]
+fn test_ip_address_parse() {
+    assert_eq!(
+        "192.168.0.1".parse::<IpAddress>().unwrap(),
+        IpAddress::parse_from("192.168.0.1")
+    );
+    assert!("192.168.0".parse::<IpAddress>().is_err());
+    assert!("192.168.0.256".parse::<IpAddress>().is_err());
+    assert!("192.168.0.1.1".parse::<IpAddress>().is_err());
+    assert!("192.168..1".parse::<IpAddress>().is_err());
+
+    let ep: IpEndpoint = "10.0.0.1:8080".parse().unwrap();
+    assert_eq!(ep, IpEndpoint::new(IpAddress::parse_from("10.0.0.1"), 8080));
+    assert_eq!(ep.to_string(), "10.0.0.1:8080");
+    assert!("10.0.0.1".parse::<IpEndpoint>().is_err());
+
+    let ep: IpEndpoint = "[2001:db8::1]:53".parse().unwrap();
+    assert!(ep.is_ipv6());
+    assert_eq!(ep.to_string(), "[2001:db8::1]:53");
+    assert!("2001:db8::1:53".parse::<IpEndpoint>().is_err());
+}
+
+#[test]
+fn test_ip_addr_dual_stack() {
+    let v4 = IpAddr::V4(IpAddress::parse_from("192.0.2.1"));
+    let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+    let any6 = IpAddr::V6(Ipv6Address::UNSPECIFIED);
+    assert_eq!(mapped.to_canonical(), v4);
+    assert_eq!(v4.to_ipv6_mapped(), mapped.to_ipv6_mapped());
+    assert_eq!(
+        IpEndpoint::new(v4, 7).in_family_of(&IpEndpoint::new(any6, 0)),
+        IpEndpoint::new(mapped, 7)
+    );
+
+    assert!(ip_addr_covers(any6, false, v4));
+    assert!(!ip_addr_covers(any6, true, v4));
+    assert!(ip_addr_covers(mapped, true, v4));
+    assert!(!ip_addr_covers(
+        IpAddr::V4(IP_ADDR_ANY),
+        false,
+        "2001:db8::1".parse().unwrap()
+    ));
+
+    assert!(ip_addr_overlaps(
+        IpAddr::V4(IP_ADDR_ANY),
+        false,
+        any6,
+        false
+    ));
+    assert!(!ip_addr_overlaps(
+        IpAddr::V4(IP_ADDR_ANY),
+        false,
+        any6,
+        true
+    ));
+    assert!(ip_addr_overlaps(mapped, false, v4, false));
+    assert!(!ip_addr_overlaps(
+        v4,
+        false,
+        "2001:db8::1".parse().unwrap(),
+        false
+    ));
+}
+
 /// 0.0.0.0
-const IP_ADDR_ANY: IpAddress = IpAddress(0);
+pub const IP_ADDR_ANY: IpAddress = IpAddress(0);
 /// 255.255.255.255
-const IP_ADDR_BROADCAST: IpAddress = IpAddress(0xffffffff);
+pub const IP_ADDR_BROADCAST: IpAddress = IpAddress(0xffffffff);
+/// 224.0.0.1, which every multicast-capable host is a member of.
+pub const IP_ADDR_ALL_HOSTS: IpAddress = IpAddress(0xe0000001);
 
+#[allow(static_mut_refs)]
 fn ip_input(data: &[u8], dev: &NetDeviceHandler) {
-    let Some(ip_hdr) = IpHeader::new(data) else {
-        log::error!("IP header is too short");
-        return;
+    let ip_hdr = match Ipv4Packet::new_checked(data) {
+        Ok(ip_hdr) => ip_hdr,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
     };
-    if data.len() < ip_hdr.total() as usize {
-        log::error!("IP datagram is too short");
-        return;
-    }
     if ip_hdr.version() != 4 {
         log::error!("IPv4 is only supported");
         return;
     }
     // Check checksum
-    let actual = utils::checksum16(data, 0);
-    if actual != 0 {
-        log::error!("checksum mismatch: expected=0, actual=0x{:04x}", actual);
+    if !ip_hdr.verify_checksum() {
+        log::error!("checksum mismatch: sum=0x{:04x}", ip_hdr.sum());
         return;
     }
+    log::debug!("{:?}", ip_hdr);
 
     // do not support fragmented packets for now
     if ip_hdr.more_fragments() || ip_hdr.offset() != 0 {
@@ -221,29 +480,532 @@ fn ip_input(data: &[u8], dev: &NetDeviceHandler) {
         return;
     }
 
-    // Get interfaces associated with the device
-    if let Some(iface) = ip_iface_select(ip_hdr.dst()) {
-        let dev = net_device_get!(dev);
-        log::debug!("dev={}, iface={:?}", dev.name(), iface.family);
-    } else {
-        // No interface to send the packet. Drop it.
+    // NAT may turn a datagram to the stack into one to forward
+    let mut datagram = match nat::nat_prerouting(dev, data) {
+        Some(buf) => buf,
+        None => data[..ip_hdr.total() as usize].to_vec(),
+    };
+    let mut packet = FilterPacket {
+        hook: FilterHook::Prerouting,
+        in_dev: Some(*dev),
+        out_dev: None,
+        state: filter::filter_conntrack(&datagram),
+        datagram: &mut datagram,
+    };
+    if !ip_filter_received(&mut packet) {
+        return;
+    }
+    let state = packet.state;
+    let ip_hdr = Ipv4Packet::new_unchecked(&datagram[..]);
+
+    // the DHCP client receives its replies before the device has an interface, and the
+    // server has to know the device its clients are on. The input hook still sees them.
+    if ip_hdr.protocol() == IP_PROTOCOL_UDP
+        && (dhcp::dhcp_client_accepts(dev, ip_hdr.payload())
+            || dhcp_server::dhcp_server_accepts(dev, ip_hdr.payload()))
+    {
+        let mut packet = FilterPacket {
+            hook: FilterHook::Input,
+            in_dev: Some(*dev),
+            out_dev: None,
+            state,
+            datagram: &mut datagram,
+        };
+        if !ip_filter_received(&mut packet) {
+            return;
+        }
+        let ip_hdr = Ipv4Packet::new_unchecked(&datagram[..]);
+        let (src, dst, payload) = (ip_hdr.src(), ip_hdr.dst(), ip_hdr.payload());
+        if !dhcp::dhcp_client_input(dev, src, dst, payload) {
+            dhcp_server::dhcp_server_input(dev, src, dst, payload);
+        }
+        return;
+    }
+
+    // Get interfaces associated with the device. Multicast is accepted on the devices that
+    // joined the group.
+    let iface = match ip_hdr.dst().is_multicast() {
+        true => ip_iface_of_dev(dev).filter(|_| igmp::igmp_group_joined(*dev, ip_hdr.dst())),
+        false => ip_iface_select(ip_hdr.dst()),
+    };
+    let Some(iface) = iface else {
+        if ip_forwarding() && !ip_hdr.dst().is_multicast() {
+            ip_forward(dev, datagram, state);
+        }
+        // Otherwise there is no interface to send the packet. Drop it.
+        return;
+    };
+    log::debug!(
+        "dev={}, iface={:?}",
+        net_device_get!(dev).name(),
+        iface.family
+    );
+
+    let mut packet = FilterPacket {
+        hook: FilterHook::Input,
+        in_dev: Some(*dev),
+        out_dev: None,
+        state,
+        datagram: &mut datagram,
+    };
+    if !ip_filter_received(&mut packet) {
+        return;
+    }
+    let ip_hdr = Ipv4Packet::new_unchecked(&datagram[..]);
+
+    // raw sockets get a copy of every datagram
+    raw::raw_input(&ip_hdr);
+
+    for proto in unsafe { IP_PROTOCOLS.iter() } {
+        if proto.ty == ip_hdr.protocol() {
+            (proto.handler)(ip_hdr.payload(), ip_hdr.src(), ip_hdr.dst(), &iface);
+            return;
+        }
+    }
+    // unsupported protocol
+}
+
+/// Runs the hook of a received datagram. Returns whether it passes, after answering it if it
+/// was rejected.
+fn ip_filter_received(packet: &mut FilterPacket) -> bool {
+    match filter::filter_hook(packet) {
+        FilterVerdict::Accept => true,
+        FilterVerdict::Drop => false,
+        FilterVerdict::Reject => {
+            filter::filter_reject(packet.datagram);
+            false
+        }
+    }
+}
+
+/// Runs the output and the postrouting hooks on a datagram built by the stack.
+fn ip_filter_output(out_dev: &NetDeviceHandler, datagram: &mut Vec<u8>) -> UtcpResult<()> {
+    let mut packet = FilterPacket {
+        hook: FilterHook::Output,
+        in_dev: None,
+        out_dev: Some(*out_dev),
+        state: filter::filter_conntrack(datagram),
+        datagram,
+    };
+    for hook in [FilterHook::Output, FilterHook::Postrouting] {
+        packet.hook = hook;
+        if filter::filter_hook(&mut packet) != FilterVerdict::Accept {
+            return Err(UtcpErr::NotPermitted);
+        }
+    }
+    Ok(())
+}
+
+pub type IpProtocolHandler =
+    fn(data: &[u8], src: IpAddress, dst: IpAddress, iface: &NetInterfaceHandler);
+
+struct IpProtocol {
+    ty: u8,
+    handler: IpProtocolHandler,
+}
+
+static mut IP_PROTOCOLS: Vec<IpProtocol> = Vec::new();
+
+/// Registers a handler for the payload of datagrams carrying the given protocol number.
+#[allow(static_mut_refs)]
+pub fn ip_protocol_register(ty: u8, handler: IpProtocolHandler) -> UtcpResult<()> {
+    // Safety: protocols are registered before the stack runs
+    let protocols = unsafe { &mut IP_PROTOCOLS };
+    if protocols.iter().any(|proto| proto.ty == ty) {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "IP protocol already registered: {}",
+            ty
+        )));
+    }
+    protocols.push(IpProtocol { ty, handler });
+    log::info!("registered protocol={}", ty);
+    Ok(())
+}
+
+static IP_FORWARDING: AtomicBool = AtomicBool::new(false);
+
+/// Enables forwarding the datagrams that are not for the stack, as a router does.
+pub fn ip_forwarding_set(enabled: bool) {
+    log::info!("forwarding={}", enabled);
+    IP_FORWARDING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn ip_forwarding() -> bool {
+    IP_FORWARDING.load(Ordering::Relaxed)
+}
+
+/// Sends a datagram received on `dev` toward its destination. Fragmentation is not supported,
+/// so datagrams larger than the MTU of the next link are dropped, as are the ones whose TTL
+/// runs out.
+fn ip_forward(dev: &NetDeviceHandler, mut datagram: Vec<u8>, state: FilterStates) {
+    let ip_hdr = Ipv4Packet::new_unchecked(&datagram[..]);
+    let (src, dst, ttl) = (ip_hdr.src(), ip_hdr.dst(), ip_hdr.ttl());
+    if ttl <= 1 {
+        log::debug!("TTL exceeded, dropped: src={}, dst={}", src, dst);
+        return;
+    }
+    let (iface, nexthop) = match ip_route_lookup(IP_ADDR_ANY, dst) {
+        Ok(route) => route,
+        Err(e) => {
+            log::debug!("not forwarded: dst={}, {}", dst, e);
+            return;
+        }
+    };
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut datagram[..]);
+    ip_hdr.set_ttl(ttl - 1);
+    ip_hdr.fill_checksum();
+    let mut packet = FilterPacket {
+        hook: FilterHook::Forward,
+        in_dev: Some(*dev),
+        out_dev: Some(iface.dev),
+        state,
+        datagram: &mut datagram,
+    };
+    for hook in [FilterHook::Forward, FilterHook::Postrouting] {
+        packet.hook = hook;
+        if !ip_filter_received(&mut packet) {
+            return;
+        }
+    }
+    if let Err(e) = nat::nat_postrouting(dev, &iface.dev, &mut datagram) {
+        log::warn!("not translated, dropped: src={}, dst={}, {}", src, dst, e);
+        return;
+    }
+    log::debug!(
+        "forwarded: src={}, dst={}, dev={}",
+        src,
+        dst,
+        net_device_get!(iface.dev).name()
+    );
+    if let Err(e) = ip_output_device(&iface, nexthop, &datagram) {
+        log::debug!("not forwarded: dst={}, {}", dst, e);
     }
 }
 
+pub(crate) fn ip_generate_id() -> u16 {
+    static ID: AtomicU16 = AtomicU16::new(128);
+    ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone)]
+struct IpRoute {
+    network: IpAddress,
+    netmask: IpAddress,
+    /// `IP_ADDR_ANY` for the network the interface is directly connected to.
+    nexthop: IpAddress,
+    iface: NetInterfaceHandler,
+}
+
+static IP_ROUTES: Mutex<Vec<IpRoute>> = Mutex::new(Vec::new());
+
+/// Adds a route to `network`/`netmask` through `gateway`, which must be on the network of
+/// one of the interfaces.
+pub fn ip_route_add(network: IpAddress, netmask: IpAddress, gateway: IpAddress) -> UtcpResult<()> {
+    let mut routes = IP_ROUTES.lock().unwrap();
+    let iface = routes
+        .iter()
+        .find(|route| {
+            route.nexthop == IP_ADDR_ANY && gateway.0 & route.netmask.0 == route.network.0
+        })
+        .map(|route| route.iface.clone())
+        .ok_or(UtcpErr::NetUnreachable)?;
+    let network = IpAddress(network.0 & netmask.0);
+    routes.retain(|route| !(route.network == network && route.netmask == netmask));
+    routes.push(IpRoute {
+        network,
+        netmask,
+        nexthop: gateway,
+        iface,
+    });
+    log::info!(
+        "route added: network={}, netmask={}, nexthop={}",
+        network,
+        netmask,
+        gateway
+    );
+    Ok(())
+}
+
+pub fn ip_route_set_default_gateway(gateway: IpAddress) -> UtcpResult<()> {
+    ip_route_add(IP_ADDR_ANY, IP_ADDR_ANY, gateway)
+}
+
+/// Selects the interface that sends a datagram from `src` to `dst`, and the address of the
+/// next hop. `src` may be `IP_ADDR_ANY`. Multicast goes out on the interface with the address
+/// `src`, or on the first interface.
+fn ip_route_lookup(src: IpAddress, dst: IpAddress) -> UtcpResult<(NetInterfaceHandler, IpAddress)> {
+    let src_matches = |iface: &NetInterfaceHandler| {
+        let ip_iface: &IpInterface = iface.get().try_into().unwrap();
+        src == IP_ADDR_ANY || src == ip_iface.unicast
+    };
+    // there is no route for these, so they leave through the first matching interface
+    if dst == IP_ADDR_BROADCAST || dst.is_multicast() {
+        return IP_INTERFACES
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|iface| src_matches(iface))
+            .map(|iface| (iface.clone(), dst))
+            .ok_or(UtcpErr::NoRoute);
+    }
+    let routes = IP_ROUTES.lock().unwrap();
+    // longest prefix match
+    let route = routes
+        .iter()
+        .filter(|route| dst.0 & route.netmask.0 == route.network.0 && src_matches(&route.iface))
+        .max_by_key(|route| route.netmask.0.count_ones())
+        .ok_or(UtcpErr::NoRoute)?;
+    let nexthop = match route.nexthop {
+        IP_ADDR_ANY => dst,
+        nexthop => nexthop,
+    };
+    Ok((route.iface.clone(), nexthop))
+}
+
+/// Returns the source address used for datagrams sent to `dst`.
+pub fn ip_route_source(dst: IpAddress) -> UtcpResult<IpAddress> {
+    let (iface, _) = ip_route_lookup(IP_ADDR_ANY, dst)?;
+    let ip_iface: &IpInterface = iface.get().try_into()?;
+    Ok(ip_iface.unicast)
+}
+
+/// Returns the MTU of the device that sends datagrams from `src` to `dst`.
+pub fn ip_route_mtu(src: IpAddress, dst: IpAddress) -> UtcpResult<u16> {
+    let (iface, _) = ip_route_lookup(src, dst)?;
+    Ok(net::net_device_mtu(&iface.dev))
+}
+
+fn ip_output_device(
+    iface: &NetInterfaceHandler,
+    nexthop: IpAddress,
+    datagram: &[u8],
+) -> UtcpResult<()> {
+    let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+    log::debug!("{:?}", ip_hdr);
+    if !net::net_device_flags(&iface.dev).contains(NetDeviceFlags::NEED_ARP) {
+        return net::net_device_output(&iface.dev, NET_PROTOCOL_TYPE_IP, datagram, &mut []);
+    }
+    let ip_iface: &IpInterface = iface.get().try_into()?;
+    if nexthop == IP_ADDR_BROADCAST || nexthop == ip_iface.broadcast {
+        let mut dst = EthernetAddress::BROADCAST.0;
+        return net::net_device_output(&iface.dev, NET_PROTOCOL_TYPE_IP, datagram, &mut dst);
+    }
+    if nexthop.is_multicast() {
+        let mut dst = EthernetAddress::from_ip_multicast(nexthop).0;
+        return net::net_device_output(&iface.dev, NET_PROTOCOL_TYPE_IP, datagram, &mut dst);
+    }
+    arp::arp_output_datagram(iface, nexthop, datagram)
+}
+
+/// Sends `data` as the payload of a datagram built by the stack. Returns the payload length.
+pub fn ip_output(protocol: u8, data: &[u8], src: IpAddress, dst: IpAddress) -> UtcpResult<usize> {
+    let (iface, nexthop) = ip_route_lookup(src, dst)?;
+    let ip_iface: &IpInterface = iface.get().try_into()?;
+    ip_output_route(&iface, nexthop, protocol, data, ip_iface.unicast, dst)
+}
+
+/// Sends `data` from `src`, which does not have to be an address of the stack, such as the
+/// RST sent on behalf of the destination of a rejected datagram.
+pub(crate) fn ip_output_from(
+    protocol: u8,
+    data: &[u8],
+    src: IpAddress,
+    dst: IpAddress,
+) -> UtcpResult<usize> {
+    let (iface, nexthop) = ip_route_lookup(IP_ADDR_ANY, dst)?;
+    ip_output_route(&iface, nexthop, protocol, data, src, dst)
+}
+
+fn ip_output_route(
+    iface: &NetInterfaceHandler,
+    nexthop: IpAddress,
+    protocol: u8,
+    data: &[u8],
+    src: IpAddress,
+    dst: IpAddress,
+) -> UtcpResult<usize> {
+    let total = IPV4_HEADER_MIN_LEN + data.len();
+    if total > u16::MAX as usize {
+        return Err(UtcpErr::MessageTooLong {
+            mtu: u16::MAX as usize,
+        });
+    }
+
+    let mut buf = vec![0u8; total];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_total(total as u16);
+    ip_hdr.set_id(ip_generate_id());
+    ip_hdr.set_ttl(match dst.is_multicast() {
+        true => IP_MULTICAST_TTL_DEFAULT,
+        false => IP_TTL_DEFAULT,
+    });
+    ip_hdr.set_protocol(protocol);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    ip_hdr.payload_mut().copy_from_slice(data);
+    ip_hdr.fill_checksum();
+
+    ip_filter_output(&iface.dev, &mut buf)?;
+    ip_output_device(iface, nexthop, &buf)?;
+    Ok(data.len())
+}
+
+/// Resolves the addresses a transport protocol sends with from `src` to `dst`. IPv4-mapped
+/// addresses become IPv4, and an unspecified `src` is selected by routing. An unspecified IPv6
+/// `src` also selects IPv4 sources, for dual-stack PCBs.
+pub(crate) fn ip_transport_addrs(src: IpAddr, dst: IpAddr) -> UtcpResult<(IpAddr, IpAddr)> {
+    let dst = dst.to_canonical();
+    let src = match (src.to_canonical(), dst) {
+        (IpAddr::V4(IP_ADDR_ANY), IpAddr::V4(dst)) => IpAddr::V4(ip_route_source(dst)?),
+        (IpAddr::V6(src), IpAddr::V4(dst)) if src.is_unspecified() => {
+            IpAddr::V4(ip_route_source(dst)?)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) if src.is_unspecified() => {
+            IpAddr::V6(ipv6::ipv6_route_source(dst)?)
+        }
+        (src, _) => src,
+    };
+    match (src, dst) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => Ok((src, dst)),
+        _ => Err(UtcpErr::AddrFamilyNotSupported),
+    }
+}
+
+/// Sends a transport segment with `ip_output` or `ipv6_output`, by the family of the
+/// addresses resolved by `ip_transport_addrs`.
+pub(crate) fn ip_transport_output(
+    protocol: u8,
+    data: &[u8],
+    src: IpAddr,
+    dst: IpAddr,
+) -> UtcpResult<usize> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => ip_output(protocol, data, src, dst),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => ipv6::ipv6_output(protocol, data, src, dst),
+        _ => Err(UtcpErr::AddrFamilyNotSupported),
+    }
+}
+
+/// Fills the total length, the checksum and, if it is zero, the identification of a datagram
+/// whose header was built by the caller.
+fn ip_complete_raw(datagram: &[u8]) -> UtcpResult<Vec<u8>> {
+    let mut buf = datagram.to_vec();
+    if buf.len() < IPV4_HEADER_MIN_LEN || buf.len() > u16::MAX as usize {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "invalid datagram length: {}",
+            buf.len()
+        )));
+    }
+    let total = buf.len() as u16;
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_total(total);
+    let mut ip_hdr = Ipv4Packet::new_checked(&mut buf[..])?;
+    if ip_hdr.version() != 4 {
+        return Err(UtcpErr::Malformed("IPv4 is only supported".into()));
+    }
+    if ip_hdr.id() == 0 {
+        ip_hdr.set_id(ip_generate_id());
+    }
+    ip_hdr.fill_checksum();
+    Ok(buf)
+}
+
+/// Sends a datagram whose header was built by the caller. The stack fills the total length,
+/// the checksum and, if it is zero, the identification. Returns the datagram length.
+pub fn ip_output_raw(datagram: &[u8]) -> UtcpResult<usize> {
+    let mut buf = ip_complete_raw(datagram)?;
+    let ip_hdr = Ipv4Packet::new_unchecked(&buf[..]);
+    let (iface, nexthop) = ip_route_lookup(ip_hdr.src(), ip_hdr.dst())?;
+    ip_filter_output(&iface.dev, &mut buf)?;
+    ip_output_device(&iface, nexthop, &buf)?;
+    Ok(buf.len())
+}
+
+/// Sends a datagram built by the caller to its destination on the link of `iface`, without
+/// routing it. The header is completed as by `ip_output_raw`.
+pub(crate) fn ip_output_iface(iface: &NetInterfaceHandler, datagram: &[u8]) -> UtcpResult<()> {
+    let mut buf = ip_complete_raw(datagram)?;
+    ip_filter_output(&iface.dev, &mut buf)?;
+    let dst = Ipv4Packet::new_unchecked(&buf[..]).dst();
+    ip_output_device(iface, dst, &buf)
+}
+
+/// Broadcasts a datagram built by the caller on `dev` without routing it, so that it can be
+/// sent before the device has an interface. The header is completed as by `ip_output_raw`.
+pub(crate) fn ip_output_broadcast(dev: &NetDeviceHandler, datagram: &[u8]) -> UtcpResult<()> {
+    let mut buf = ip_complete_raw(datagram)?;
+    ip_filter_output(dev, &mut buf)?;
+    log::debug!("{:?}", Ipv4Packet::new_unchecked(&buf[..]));
+    let mut dst = EthernetAddress::BROADCAST.0;
+    let dst: &mut [u8] = match net::net_device_flags(dev).contains(NetDeviceFlags::NEED_ARP) {
+        true => &mut dst,
+        false => &mut [],
+    };
+    net::net_device_output(dev, NET_PROTOCOL_TYPE_IP, &buf, dst)
+}
+
 pub fn ip_init() -> UtcpResult<()> {
     net::net_protocol_register(NetProtocol::new(NET_PROTOCOL_TYPE_IP, ip_input));
     log::info!("initialized");
     Ok(())
 }
 
-static mut IP_INTERFACES: Vec<NetInterfaceHandler> = Vec::new();
+/// Interfaces in the order they were registered.
+static IP_INTERFACES: Mutex<Vec<NetInterfaceHandler>> = Mutex::new(Vec::new());
+
+/// Returns the IP interface of `dev`.
+pub(crate) fn ip_iface_of_dev(dev: &NetDeviceHandler) -> Option<NetInterfaceHandler> {
+    IP_INTERFACES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|iface| iface.dev.private == dev.private)
+        .cloned()
+}
+
+/// Returns the device of the interface with the address `addr`, as in `struct ip_mreq`.
+/// `IP_ADDR_ANY` picks the interface that multicast datagrams are sent from.
+pub(crate) fn ip_multicast_dev(addr: IpAddress) -> UtcpResult<NetDeviceHandler> {
+    let (iface, _) = ip_route_lookup(addr, IP_ADDR_ALL_HOSTS)?;
+    Ok(iface.dev)
+}
 
-#[allow(static_mut_refs)]
 pub fn ip_iface_register(handler: NetDeviceHandler, iface: IpInterface) -> UtcpResult<()> {
-    let dev = net_device_get_mut!(handler);
-    log::info!("registered iface: dev={}, iface={:?}", dev.name(), iface);
-    let iface_handler = dev.add_interface(handler, NetInterface::Ip(iface));
-    unsafe { IP_INTERFACES.push(iface_handler) };
+    log::info!(
+        "registered iface: dev={}, iface={:?}",
+        net_device_get!(handler).name(),
+        iface
+    );
+    let route = IpRoute {
+        network: IpAddress(iface.unicast.0 & iface.netmask.0),
+        netmask: iface.netmask,
+        nexthop: IP_ADDR_ANY,
+        iface: net::net_device_add_iface(handler, NetInterface::Ip(iface))?,
+    };
+    IP_INTERFACES.lock().unwrap().push(route.iface.clone());
+    IP_ROUTES.lock().unwrap().push(route);
+    Ok(())
+}
+
+/// Removes the interface of `handler` and the routes through it.
+pub fn ip_iface_unregister(handler: NetDeviceHandler) -> UtcpResult<()> {
+    let iface = net::net_device_remove_iface(handler, net::NetInterfaceFamily::Ip)?;
+    log::info!(
+        "unregistered iface: dev={}, iface={:?}",
+        net_device_get!(handler).name(),
+        iface
+    );
+
+    let same_dev = |iface: &NetInterfaceHandler| iface.dev.private == handler.private;
+    IP_INTERFACES
+        .lock()
+        .unwrap()
+        .retain(|iface| !same_dev(iface));
+    IP_ROUTES
+        .lock()
+        .unwrap()
+        .retain(|route| !same_dev(&route.iface));
     Ok(())
 }
 
@@ -263,25 +1025,37 @@ impl IpInterface {
             broadcast,
         }
     }
+
+    pub fn unicast(&self) -> IpAddress {
+        self.unicast
+    }
+
+    pub fn netmask(&self) -> IpAddress {
+        self.netmask
+    }
+
+    pub fn broadcast(&self) -> IpAddress {
+        self.broadcast
+    }
 }
 
-#[allow(static_mut_refs)]
 fn ip_iface_select(addr: IpAddress) -> Option<NetInterfaceHandler> {
-    for iface in unsafe { IP_INTERFACES.iter() } {
-        let net_iface = net_iface_get!(iface);
+    let ifaces = IP_INTERFACES.lock().unwrap();
+    for iface in ifaces.iter() {
+        let net_iface = iface.get();
         let ip_iface: &IpInterface = net_iface.try_into().unwrap();
 
         // unicast?
         if addr == ip_iface.unicast {
-            return Some(*iface);
+            return Some(iface.clone());
         }
         // subnet broadcast?
         if addr == ip_iface.broadcast {
-            return Some(*iface);
+            return Some(iface.clone());
         }
         // broadcast?
         if addr == IP_ADDR_BROADCAST {
-            return Some(*iface);
+            return Some(iface.clone());
         }
     }
     None
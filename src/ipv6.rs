@@ -0,0 +1,977 @@
+//! IPv6 (RFC 8200) addressing, input and output.
+//!
+//! A device gets an `Ipv6Interface` with `ipv6_iface_register`, next to its IPv4 interface,
+//! and any number of addresses with `ipv6_addr_add`. Every address adds an on-link route for
+//! its prefix. Destinations of link scope have no routes and leave through the device of the
+//! source address, or the first device with an IPv6 interface.
+
+use std::sync::Mutex;
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    icmpv6,
+    ip::IpAddress,
+    ndp,
+    net::{
+        self, NET_PROTOCOL_TYPE_IPV6, NetDeviceFlags, NetDeviceHandler, NetInterface,
+        NetInterfaceFamily, NetInterfaceHandler, NetProtocol,
+    },
+    net_device_get,
+    wire::{
+        ethernet::EthernetAddress,
+        icmpv6::{
+            ICMPV6_CODE_ERRONEOUS_FIELD, ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+            ICMPV6_CODE_UNRECOGNIZED_OPTION, ICMPV6_TYPE_PARAM_PROBLEM,
+        },
+        ipv6::{IPV6_HEADER_LEN, IPV6_NEXT_HEADER_NONE, Ipv6ExtError, Ipv6Packet},
+    },
+};
+
+/// Next header value of ICMPv6 (RFC 4443).
+pub const IPV6_NEXT_HEADER_ICMPV6: u8 = 58;
+
+/// Every link carries datagrams of this size (RFC 8200 section 5).
+pub const IPV6_MIN_MTU: usize = 1280;
+
+pub const IPV6_HOP_LIMIT_DEFAULT: u8 = 64;
+/// Multicast datagrams stay on the link unless the application asks otherwise.
+pub const IPV6_MULTICAST_HOP_LIMIT_DEFAULT: u8 = 1;
+
+/// IPv6 address
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Ipv6Address([u8; 16]);
+
+/// Scope of an address (RFC 4007). Unicast addresses are of link or global scope, the scope
+/// of multicast addresses is part of the address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ipv6Scope {
+    InterfaceLocal = 1,
+    LinkLocal = 2,
+    AdminLocal = 4,
+    SiteLocal = 5,
+    OrganizationLocal = 8,
+    Global = 14,
+}
+
+impl Ipv6Address {
+    /// ::
+    pub const UNSPECIFIED: Ipv6Address = Ipv6Address([0; 16]);
+    /// ::1
+    pub const LOOPBACK: Ipv6Address = Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1);
+    /// ff02::1
+    pub const ALL_NODES: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+    /// ff02::2
+    pub const ALL_ROUTERS: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+    /// Builds an address from its eight 16-bit groups.
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(a: u16, b: u16, c: u16, d: u16, e: u16, f: u16, g: u16, h: u16) -> Self {
+        let groups = [a, b, c, d, e, f, g, h];
+        let mut bytes = [0u8; 16];
+        let mut i = 0;
+        while i < 8 {
+            let [hi, lo] = groups[i].to_be_bytes();
+            bytes[i * 2] = hi;
+            bytes[i * 2 + 1] = lo;
+            i += 1;
+        }
+        Ipv6Address(bytes)
+    }
+
+    /// Panics unless `data` is 16 bytes long.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Ipv6Address(data.try_into().unwrap())
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn segments(&self) -> [u16; 8] {
+        std::array::from_fn(|i| u16::from_be_bytes([self.0[i * 2], self.0[i * 2 + 1]]))
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        *self == Self::LOOPBACK
+    }
+
+    /// ff00::/8
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] == 0xff
+    }
+
+    /// fe80::/10
+    pub fn is_link_local(&self) -> bool {
+        self.0[0] == 0xfe && self.0[1] & 0xc0 == 0x80
+    }
+
+    /// fc00::/7
+    pub fn is_unique_local(&self) -> bool {
+        self.0[0] & 0xfe == 0xfc
+    }
+
+    /// Returns ::ffff:`addr`.
+    pub fn from_ipv4_mapped(addr: IpAddress) -> Self {
+        let mut bytes = [0u8; 16];
+        bytes[10..12].copy_from_slice(&[0xff, 0xff]);
+        bytes[12..].copy_from_slice(&addr.octets());
+        Ipv6Address(bytes)
+    }
+
+    /// ::ffff:0:0/96
+    pub fn to_ipv4_mapped(&self) -> Option<IpAddress> {
+        let (prefix, v4) = self.0.split_at(12);
+        (prefix == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff])
+            .then(|| IpAddress::from([v4[0], v4[1], v4[2], v4[3]]))
+    }
+
+    pub fn scope(&self) -> Ipv6Scope {
+        if self.is_multicast() {
+            return match self.0[1] & 0x0f {
+                1 => Ipv6Scope::InterfaceLocal,
+                2 => Ipv6Scope::LinkLocal,
+                4 => Ipv6Scope::AdminLocal,
+                5 => Ipv6Scope::SiteLocal,
+                8 => Ipv6Scope::OrganizationLocal,
+                _ => Ipv6Scope::Global,
+            };
+        }
+        // the loopback address is treated as link-local (RFC 6724 section 3.1)
+        if self.is_link_local() || self.is_loopback() {
+            Ipv6Scope::LinkLocal
+        } else {
+            Ipv6Scope::Global
+        }
+    }
+
+    /// Solicited-node multicast address, ff02::1:ff00:0/104 with the low 24 bits of the
+    /// address (RFC 4291 section 2.7.1).
+    pub fn solicited_node(&self) -> Ipv6Address {
+        let mut addr = Ipv6Address::new(0xff02, 0, 0, 0, 0, 1, 0xff00, 0);
+        addr.0[13..].copy_from_slice(&self.0[13..]);
+        addr
+    }
+
+    /// Keeps the first `prefix_len` bits.
+    pub fn mask(&self, prefix_len: u8) -> Ipv6Address {
+        let bits = u128::from_be_bytes(self.0);
+        let mask = match prefix_len {
+            0 => 0,
+            len => u128::MAX << (128 - len.min(128) as u32),
+        };
+        Ipv6Address((bits & mask).to_be_bytes())
+    }
+
+    /// Number of leading bits shared with `other`.
+    pub fn common_prefix_len(&self, other: &Ipv6Address) -> u8 {
+        (u128::from_be_bytes(self.0) ^ u128::from_be_bytes(other.0)).leading_zeros() as u8
+    }
+}
+
+impl From<[u8; 16]> for Ipv6Address {
+    fn from(bytes: [u8; 16]) -> Self {
+        Ipv6Address(bytes)
+    }
+}
+
+impl From<std::net::Ipv6Addr> for Ipv6Address {
+    fn from(addr: std::net::Ipv6Addr) -> Self {
+        Ipv6Address(addr.octets())
+    }
+}
+
+impl From<Ipv6Address> for std::net::Ipv6Addr {
+    fn from(addr: Ipv6Address) -> Self {
+        std::net::Ipv6Addr::from(addr.0)
+    }
+}
+
+impl std::fmt::Display for Ipv6Address {
+    /// Writes the canonical text form (RFC 5952): lower-case groups without leading zeros,
+    /// and the longest run of two or more zero groups replaced by `::`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(v4) = self.to_ipv4_mapped() {
+            return write!(f, "::ffff:{}", v4);
+        }
+        let groups = self.segments();
+        let (mut best_start, mut best_len) = (0, 0);
+        let mut i = 0;
+        while i < groups.len() {
+            let start = i;
+            while i < groups.len() && groups[i] == 0 {
+                i += 1;
+            }
+            if i - start > best_len {
+                (best_start, best_len) = (start, i - start);
+            }
+            i += 1;
+        }
+        let write_groups = |f: &mut std::fmt::Formatter, groups: &[u16]| {
+            for (i, group) in groups.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(":")?;
+                }
+                write!(f, "{:x}", group)?;
+            }
+            Ok(())
+        };
+        if best_len < 2 {
+            return write_groups(f, &groups);
+        }
+        write_groups(f, &groups[..best_start])?;
+        f.write_str("::")?;
+        write_groups(f, &groups[best_start + best_len..])
+    }
+}
+
+impl std::fmt::Debug for Ipv6Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::str::FromStr for Ipv6Address {
+    type Err = UtcpErr;
+
+    /// Parses the text forms of RFC 4291 section 2.2, including `::` and a trailing IPv4
+    /// address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UtcpErr::InvalidAddress(s.to_string());
+        // the last group may be an IPv4 address standing for two groups
+        let parse_groups = |part: &str, last: bool| -> UtcpResult<Vec<u16>> {
+            let mut groups = Vec::new();
+            if part.is_empty() {
+                return Ok(groups);
+            }
+            let pieces = part.split(':').collect::<Vec<_>>();
+            for (i, piece) in pieces.iter().enumerate() {
+                if last && i == pieces.len() - 1 && piece.contains('.') {
+                    let [a, b, c, d] = piece.parse::<IpAddress>().map_err(|_| invalid())?.octets();
+                    groups.extend([u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d])]);
+                } else if (1..=4).contains(&piece.len())
+                    && piece.bytes().all(|b| b.is_ascii_hexdigit())
+                {
+                    groups.push(u16::from_str_radix(piece, 16).map_err(|_| invalid())?);
+                } else {
+                    return Err(invalid());
+                }
+            }
+            Ok(groups)
+        };
+        let groups = match s.split_once("::") {
+            Some((head, tail)) => {
+                let head = parse_groups(head, false)?;
+                let tail = parse_groups(tail, true)?;
+                // `::` stands for at least one group
+                if head.len() + tail.len() > 7 {
+                    return Err(invalid());
+                }
+                let mut groups = head;
+                groups.resize(8 - tail.len(), 0);
+                groups.extend(tail);
+                groups
+            }
+            None => parse_groups(s, true)?,
+        };
+        if groups.len() != 8 {
+            return Err(invalid());
+        }
+        let mut addr = [0u8; 16];
+        for (i, group) in groups.iter().enumerate() {
+            addr[i * 2..i * 2 + 2].copy_from_slice(&group.to_be_bytes());
+        }
+        Ok(Ipv6Address(addr))
+    }
+}
+
+#[test]
+fn test_ipv6_address_parse() {
+    let cases = [
+        ("::", "::"),
+        ("::1", "::1"),
+        ("2001:DB8:0:0:1:0:0:1", "2001:db8::1:0:0:1"),
+        ("2001:db8:0000:1:1:1:1:1", "2001:db8:0:1:1:1:1:1"),
+        ("fe80::0200:5eff:fe00:5301", "fe80::200:5eff:fe00:5301"),
+        ("ff02::1:ff00:1", "ff02::1:ff00:1"),
+        ("::ffff:192.0.2.1", "::ffff:192.0.2.1"),
+        ("64:ff9b::192.0.2.33", "64:ff9b::c000:221"),
+        ("1:0:0:2:0:0:0:3", "1:0:0:2::3"),
+    ];
+    for (input, canonical) in cases {
+        let addr: Ipv6Address = input.parse().unwrap();
+        assert_eq!(addr.to_string(), canonical, "{}", input);
+        assert_eq!(canonical.parse::<Ipv6Address>().unwrap(), addr);
+    }
+    for input in [
+        "",
+        ":",
+        ":::",
+        "1::2::3",
+        "1:2:3:4:5:6:7",
+        "1:2:3:4:5:6:7:8:9",
+        "1:2:3:4::5:6:7:8",
+        "12345::",
+        "g::",
+        "::1.2.3.4:5",
+        "1.2.3.4::",
+    ] {
+        assert!(input.parse::<Ipv6Address>().is_err(), "{}", input);
+    }
+
+    let link_local: Ipv6Address = "fe80::1:2".parse().unwrap();
+    assert!(link_local.is_link_local());
+    assert_eq!(link_local.scope(), Ipv6Scope::LinkLocal);
+    assert_eq!(Ipv6Address::LOOPBACK.scope(), Ipv6Scope::LinkLocal);
+    assert_eq!(Ipv6Address::ALL_NODES.scope(), Ipv6Scope::LinkLocal);
+    let global: Ipv6Address = "2001:db8::1".parse().unwrap();
+    assert_eq!(global.scope(), Ipv6Scope::Global);
+    assert!("fd00::1".parse::<Ipv6Address>().unwrap().is_unique_local());
+    assert_eq!(
+        "ff05::2".parse::<Ipv6Address>().unwrap().scope(),
+        Ipv6Scope::SiteLocal
+    );
+    assert_eq!(
+        "2001:db8::12:3456"
+            .parse::<Ipv6Address>()
+            .unwrap()
+            .solicited_node(),
+        "ff02::1:ff12:3456".parse().unwrap()
+    );
+    assert_eq!(global.mask(32), "2001:db8::".parse().unwrap());
+    assert_eq!(global.mask(0), Ipv6Address::UNSPECIFIED);
+    assert_eq!(global.mask(128), global);
+    assert_eq!(
+        global.common_prefix_len(&"2001:db8:8000::".parse().unwrap()),
+        32
+    );
+}
+
+/// Address of an IPv6 interface and the length of its on-link prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6InterfaceAddress {
+    pub addr: Ipv6Address,
+    pub prefix_len: u8,
+}
+
+/// State of an address of an IPv6 interface (RFC 4862 section 2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6AddrState {
+    /// Duplicate Address Detection is in progress. Only Neighbor Discovery uses the address.
+    Tentative,
+    Preferred,
+    /// The preferred lifetime expired. The address stays valid but is only selected as a
+    /// source address when no preferred one fits.
+    Deprecated,
+}
+
+/// IPv6 interface of a device. Addresses are added and removed while the stack runs, so they
+/// are behind a lock.
+#[derive(Debug, Default)]
+pub struct Ipv6Interface {
+    addrs: Mutex<Vec<(Ipv6InterfaceAddress, Ipv6AddrState)>>,
+}
+
+impl Ipv6Interface {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn addresses(&self) -> Vec<Ipv6InterfaceAddress> {
+        self.addrs.lock().unwrap().iter().map(|(a, _)| *a).collect()
+    }
+
+    pub fn address_state(&self, addr: Ipv6Address) -> Option<Ipv6AddrState> {
+        let addrs = self.addrs.lock().unwrap();
+        addrs
+            .iter()
+            .find(|(a, _)| a.addr == addr)
+            .map(|(_, state)| *state)
+    }
+
+    /// Returns true if `addr` is assigned to the interface. Tentative addresses are not.
+    pub fn has_address(&self, addr: Ipv6Address) -> bool {
+        self.address_state(addr)
+            .is_some_and(|state| state != Ipv6AddrState::Tentative)
+    }
+
+    /// Returns true if datagrams to the multicast address `group` are received: the
+    /// all-nodes addresses and the solicited-node addresses of the interface, including
+    /// those of tentative addresses (RFC 4862 section 5.4.2).
+    pub fn is_member(&self, group: Ipv6Address) -> bool {
+        group == Ipv6Address::ALL_NODES
+            || group == Ipv6Address::new(0xff01, 0, 0, 0, 0, 0, 0, 1)
+            || self
+                .addrs
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(a, _)| a.addr.solicited_node() == group)
+    }
+
+    /// Selects the source address for `dst` (RFC 6724 section 5, rules 1, 2, 3 and 8): `dst`
+    /// itself, then an address of the same scope, then one that is not deprecated, then the
+    /// longest matching prefix. Ties go to the address added first. Tentative addresses are
+    /// never selected.
+    pub fn source_for(&self, dst: Ipv6Address) -> Option<Ipv6Address> {
+        let addrs = self.addrs.lock().unwrap();
+        addrs
+            .iter()
+            .filter(|(_, state)| *state != Ipv6AddrState::Tentative)
+            .rev()
+            .max_by_key(|(a, state)| {
+                (
+                    a.addr == dst,
+                    a.addr.scope() == dst.scope(),
+                    a.addr.scope() >= dst.scope(),
+                    *state != Ipv6AddrState::Deprecated,
+                    a.addr.common_prefix_len(&dst),
+                )
+            })
+            .map(|(a, _)| a.addr)
+    }
+}
+
+#[test]
+fn test_ipv6_source_select() {
+    let iface = Ipv6Interface::new();
+    let addrs = ["fe80::1", "2001:db8:1::1", "2001:db8:2::1"];
+    for addr in addrs {
+        let addr = Ipv6InterfaceAddress {
+            addr: addr.parse().unwrap(),
+            prefix_len: 64,
+        };
+        iface
+            .addrs
+            .lock()
+            .unwrap()
+            .push((addr, Ipv6AddrState::Preferred));
+    }
+    let source = |dst: &str| iface.source_for(dst.parse().unwrap()).unwrap().to_string();
+    assert_eq!(source("fe80::99"), "fe80::1");
+    assert_eq!(source("ff02::1"), "fe80::1");
+    assert_eq!(source("2001:db8:2::99"), "2001:db8:2::1");
+    assert_eq!(source("2001:db8:1::1"), "2001:db8:1::1");
+    assert_eq!(source("2001:4860::1"), "2001:db8:1::1");
+    assert!(iface.is_member("ff02::1:ff00:1".parse().unwrap()));
+    assert!(!iface.is_member("ff02::1:ff00:2".parse().unwrap()));
+
+    // a deprecated address loses against a preferred one, a tentative one is not used
+    iface.addrs.lock().unwrap()[2].1 = Ipv6AddrState::Deprecated;
+    assert_eq!(source("2001:db8:2::99"), "2001:db8:1::1");
+    iface.addrs.lock().unwrap()[1].1 = Ipv6AddrState::Tentative;
+    assert_eq!(source("2001:db8:2::99"), "2001:db8:2::1");
+    assert!(!iface.has_address("2001:db8:1::1".parse().unwrap()));
+}
+
+#[allow(static_mut_refs)]
+fn ipv6_input(data: &[u8], dev: &NetDeviceHandler) {
+    let packet = match Ipv6Packet::new_checked(data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if packet.version() != 6 {
+        log::error!("not an IPv6 datagram: version={}", packet.version());
+        return;
+    }
+    log::debug!("{:?}", packet);
+    let (src, dst) = (packet.src(), packet.dst());
+    if src.is_multicast() {
+        log::error!("multicast source address: {}", src);
+        return;
+    }
+    // the loopback address never appears on a link (RFC 4291 section 2.5.3)
+    if (src.is_loopback() || dst.is_loopback())
+        && net::net_device_flags(dev).contains(NetDeviceFlags::NEED_ARP)
+    {
+        log::error!("loopback address on dev={}", net_device_get!(dev).name());
+        return;
+    }
+
+    let Some(iface) = ipv6_iface_of_dev(dev) else {
+        return;
+    };
+    let ip6_iface: &Ipv6Interface = iface.get().try_into().unwrap();
+    let accepted = match dst.is_multicast() {
+        true => ip6_iface.is_member(dst),
+        false => ip6_iface.has_address(dst),
+    };
+    if !accepted {
+        // for other hosts
+        return;
+    }
+
+    let upper = match packet.upper_layer() {
+        Ok(upper) => upper,
+        Err(e) => {
+            log::error!("extension headers rejected: {:?}", e);
+            let (code, pointer) = match e {
+                Ipv6ExtError::Malformed => return,
+                Ipv6ExtError::ErroneousField { pointer } => (ICMPV6_CODE_ERRONEOUS_FIELD, pointer),
+                Ipv6ExtError::UnrecognizedNextHeader { pointer } => {
+                    (ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER, pointer)
+                }
+                Ipv6ExtError::UnrecognizedOption {
+                    pointer,
+                    report,
+                    report_multicast,
+                } => {
+                    if !report || (dst.is_multicast() && !report_multicast) {
+                        return;
+                    }
+                    (ICMPV6_CODE_UNRECOGNIZED_OPTION, pointer)
+                }
+            };
+            icmpv6::icmpv6_error(ICMPV6_TYPE_PARAM_PROBLEM, code, pointer as u32, data);
+            return;
+        }
+    };
+    // do not support fragmented packets for now
+    if upper.fragment.is_some_and(|fragment| !fragment.is_atomic()) {
+        log::error!("fragmented packets are not supported");
+        return;
+    }
+    if upper.protocol == IPV6_NEXT_HEADER_NONE {
+        return;
+    }
+    let payload = &packet.payload()[upper.offset..];
+    if upper.protocol == IPV6_NEXT_HEADER_ICMPV6 {
+        icmpv6::icmpv6_input(payload, src, dst, packet.hop_limit(), &iface);
+        return;
+    }
+    for proto in unsafe { IPV6_PROTOCOLS.iter() } {
+        if proto.ty == upper.protocol {
+            (proto.handler)(payload, src, dst, &iface);
+            return;
+        }
+    }
+    log::debug!("unsupported protocol: {}", upper.protocol);
+    icmpv6::icmpv6_error(
+        ICMPV6_TYPE_PARAM_PROBLEM,
+        ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+        upper.pointer as u32,
+        data,
+    );
+}
+
+pub type Ipv6ProtocolHandler =
+    fn(data: &[u8], src: Ipv6Address, dst: Ipv6Address, iface: &NetInterfaceHandler);
+
+struct Ipv6Protocol {
+    ty: u8,
+    handler: Ipv6ProtocolHandler,
+}
+
+static mut IPV6_PROTOCOLS: Vec<Ipv6Protocol> = Vec::new();
+
+/// Registers a handler for the upper-layer payload of datagrams carrying the given next
+/// header value. The handler gets the payload after the extension headers.
+#[allow(static_mut_refs)]
+pub fn ipv6_protocol_register(ty: u8, handler: Ipv6ProtocolHandler) -> UtcpResult<()> {
+    // Safety: protocols are registered before the stack runs
+    let protocols = unsafe { &mut IPV6_PROTOCOLS };
+    if ty == IPV6_NEXT_HEADER_ICMPV6 || protocols.iter().any(|proto| proto.ty == ty) {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "IPv6 protocol already registered: {}",
+            ty
+        )));
+    }
+    protocols.push(Ipv6Protocol { ty, handler });
+    log::info!("registered protocol={}", ty);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ipv6Route {
+    prefix: Ipv6Address,
+    prefix_len: u8,
+    /// `Ipv6Address::UNSPECIFIED` for prefixes on the link of the device.
+    nexthop: Ipv6Address,
+    dev: NetDeviceHandler,
+}
+
+static IPV6_ROUTES: Mutex<Vec<Ipv6Route>> = Mutex::new(Vec::new());
+
+/// Adds a route to `prefix`/`prefix_len` through `gateway` on `dev`. Gateways are usually
+/// link-local, so the device is not derived from them as IPv4 does.
+pub fn ipv6_route_add(
+    dev: NetDeviceHandler,
+    prefix: Ipv6Address,
+    prefix_len: u8,
+    gateway: Ipv6Address,
+) -> UtcpResult<()> {
+    if prefix_len > 128 {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "prefix length: {}",
+            prefix_len
+        )));
+    }
+    ipv6_iface_of_dev(&dev).ok_or(UtcpErr::NetUnreachable)?;
+    let prefix = prefix.mask(prefix_len);
+    let mut routes = IPV6_ROUTES.lock().unwrap();
+    routes.retain(|route| !(route.prefix == prefix && route.prefix_len == prefix_len));
+    routes.push(Ipv6Route {
+        prefix,
+        prefix_len,
+        nexthop: gateway,
+        dev,
+    });
+    log::info!(
+        "route added: prefix={}/{}, nexthop={}",
+        prefix,
+        prefix_len,
+        gateway
+    );
+    Ok(())
+}
+
+/// Removes the route to `prefix`/`prefix_len` through `dev`.
+pub fn ipv6_route_del(
+    dev: NetDeviceHandler,
+    prefix: Ipv6Address,
+    prefix_len: u8,
+) -> UtcpResult<()> {
+    let prefix = prefix.mask(prefix_len);
+    let mut routes = IPV6_ROUTES.lock().unwrap();
+    let pos = routes
+        .iter()
+        .position(|route| {
+            route.dev.private == dev.private
+                && route.prefix == prefix
+                && route.prefix_len == prefix_len
+        })
+        .ok_or(UtcpErr::NoRoute)?;
+    let route = routes.remove(pos);
+    log::info!(
+        "route removed: prefix={}/{}, nexthop={}",
+        prefix,
+        prefix_len,
+        route.nexthop
+    );
+    Ok(())
+}
+
+/// Returns the gateway of the default route through `dev`, if there is one.
+pub fn ipv6_route_default_gateway(dev: NetDeviceHandler) -> Option<Ipv6Address> {
+    let routes = IPV6_ROUTES.lock().unwrap();
+    routes
+        .iter()
+        .find(|route| route.dev.private == dev.private && route.prefix_len == 0)
+        .map(|route| route.nexthop)
+}
+
+pub fn ipv6_route_set_default_gateway(
+    dev: NetDeviceHandler,
+    gateway: Ipv6Address,
+) -> UtcpResult<()> {
+    ipv6_route_add(dev, Ipv6Address::UNSPECIFIED, 0, gateway)
+}
+
+/// Selects the interface that sends a datagram from `src` to `dst`, and the address of the
+/// next hop. `src` may be `Ipv6Address::UNSPECIFIED`.
+fn ipv6_route_lookup(
+    src: Ipv6Address,
+    dst: Ipv6Address,
+) -> UtcpResult<(NetInterfaceHandler, Ipv6Address)> {
+    let src_matches = |dev: &NetDeviceHandler| {
+        src.is_unspecified()
+            || ipv6_iface_of_dev(dev).is_some_and(|iface| {
+                let ip6_iface: &Ipv6Interface = iface.get().try_into().unwrap();
+                ip6_iface.has_address(src)
+            })
+    };
+    // there is no route for these, so they leave through the first matching interface
+    if dst.is_multicast() || dst.is_link_local() {
+        let devs = IPV6_INTERFACES.lock().unwrap().clone();
+        return devs
+            .iter()
+            .find(|dev| src_matches(dev))
+            .and_then(ipv6_iface_of_dev)
+            .map(|iface| (iface, dst))
+            .ok_or(UtcpErr::NoRoute);
+    }
+    let routes = IPV6_ROUTES.lock().unwrap().clone();
+    // longest prefix match
+    let route = routes
+        .iter()
+        .filter(|route| dst.mask(route.prefix_len) == route.prefix && src_matches(&route.dev))
+        .max_by_key(|route| route.prefix_len)
+        .ok_or(UtcpErr::NoRoute)?;
+    let iface = ipv6_iface_of_dev(&route.dev).ok_or(UtcpErr::NoRoute)?;
+    let nexthop = match route.nexthop.is_unspecified() {
+        true => dst,
+        false => route.nexthop,
+    };
+    Ok((iface, nexthop))
+}
+
+/// Returns the source address used for datagrams sent to `dst`.
+pub fn ipv6_route_source(dst: Ipv6Address) -> UtcpResult<Ipv6Address> {
+    let (iface, _) = ipv6_route_lookup(Ipv6Address::UNSPECIFIED, dst)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+    ip6_iface.source_for(dst).ok_or(UtcpErr::AddrNotAvailable)
+}
+
+/// Returns the MTU for datagrams from `src` to `dst`: the one of the device, or a smaller one
+/// advertised by routers.
+pub fn ipv6_route_mtu(src: Ipv6Address, dst: Ipv6Address) -> UtcpResult<u16> {
+    let (iface, _) = ipv6_route_lookup(src, dst)?;
+    let mtu = net::net_device_mtu(&iface.dev);
+    Ok(match ndp::ndp_mtu(iface.dev) {
+        Some(advertised) => mtu.min(advertised.min(u16::MAX as u32) as u16),
+        None => mtu,
+    })
+}
+
+fn ipv6_output_device(
+    iface: &NetInterfaceHandler,
+    nexthop: Ipv6Address,
+    datagram: &[u8],
+) -> UtcpResult<()> {
+    log::debug!("{:?}", Ipv6Packet::new_unchecked(datagram));
+    if !net::net_device_flags(&iface.dev).contains(NetDeviceFlags::NEED_ARP) {
+        return net::net_device_output(&iface.dev, NET_PROTOCOL_TYPE_IPV6, datagram, &mut []);
+    }
+    if nexthop.is_multicast() {
+        let mut dst = EthernetAddress::from_ipv6_multicast(nexthop).0;
+        return net::net_device_output(&iface.dev, NET_PROTOCOL_TYPE_IPV6, datagram, &mut dst);
+    }
+    ndp::ndp_output_datagram(iface, nexthop, datagram)
+}
+
+/// Builds a datagram from `src` to `dst` and sends it to `nexthop` on `iface`, without
+/// routing it or selecting the source address, which may be unspecified.
+pub(crate) fn ipv6_output_iface(
+    iface: &NetInterfaceHandler,
+    nexthop: Ipv6Address,
+    next_header: u8,
+    data: &[u8],
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    hop_limit: u8,
+) -> UtcpResult<()> {
+    if data.len() > u16::MAX as usize {
+        return Err(UtcpErr::MessageTooLong {
+            mtu: u16::MAX as usize,
+        });
+    }
+    let mut buf = vec![0u8; IPV6_HEADER_LEN + data.len()];
+    let mut packet = Ipv6Packet::new_unchecked(&mut buf[..]);
+    packet.set_version_tc_flow(0, 0);
+    packet.set_payload_len(data.len() as u16);
+    packet.set_next_header(next_header);
+    packet.set_hop_limit(hop_limit);
+    packet.set_src(src);
+    packet.set_dst(dst);
+    packet.payload_mut().copy_from_slice(data);
+    ipv6_output_device(iface, nexthop, &buf)
+}
+
+/// Sends `data` as the payload of a datagram built by the stack. The source address is
+/// selected if `src` is unspecified. Returns the payload length.
+pub fn ipv6_output(
+    next_header: u8,
+    data: &[u8],
+    src: Ipv6Address,
+    dst: Ipv6Address,
+) -> UtcpResult<usize> {
+    let (iface, nexthop) = ipv6_route_lookup(src, dst)?;
+    let src = match src.is_unspecified() {
+        true => {
+            let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+            ip6_iface.source_for(dst).ok_or(UtcpErr::AddrNotAvailable)?
+        }
+        false => src,
+    };
+    let hop_limit = match dst.is_multicast() {
+        true => IPV6_MULTICAST_HOP_LIMIT_DEFAULT,
+        false => ndp::ndp_hop_limit(&iface.dev),
+    };
+    ipv6_output_iface(&iface, nexthop, next_header, data, src, dst, hop_limit)?;
+    Ok(data.len())
+}
+
+pub fn ipv6_init() -> UtcpResult<()> {
+    net::net_protocol_register(NetProtocol::new(NET_PROTOCOL_TYPE_IPV6, ipv6_input));
+    log::info!("initialized");
+    Ok(())
+}
+
+/// Devices with an IPv6 interface, in the order they were registered.
+static IPV6_INTERFACES: Mutex<Vec<NetDeviceHandler>> = Mutex::new(Vec::new());
+
+/// Returns the IPv6 interface of `dev`.
+pub(crate) fn ipv6_iface_of_dev(dev: &NetDeviceHandler) -> Option<NetInterfaceHandler> {
+    IPV6_INTERFACES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|d| d.private == dev.private)
+        .then(|| net::net_iface_handler(dev, NetInterfaceFamily::Ipv6))
+        .flatten()
+}
+
+pub fn ipv6_iface_register(handler: NetDeviceHandler, iface: Ipv6Interface) -> UtcpResult<()> {
+    log::info!(
+        "registered iface: dev={}, iface={:?}",
+        net_device_get!(handler).name(),
+        iface
+    );
+    let addrs = iface.addresses();
+    net::net_device_add_iface(handler, NetInterface::Ipv6(iface))?;
+    IPV6_INTERFACES.lock().unwrap().push(handler);
+    for addr in addrs {
+        ipv6_route_on_link(handler, addr);
+    }
+    Ok(())
+}
+
+/// Removes the IPv6 interface of `handler` and the routes through it.
+pub fn ipv6_iface_unregister(handler: NetDeviceHandler) -> UtcpResult<()> {
+    let iface = net::net_device_remove_iface(handler, NetInterfaceFamily::Ipv6)?;
+    log::info!(
+        "unregistered iface: dev={}, iface={:?}",
+        net_device_get!(handler).name(),
+        iface
+    );
+
+    let same_dev = |dev: &NetDeviceHandler| dev.private == handler.private;
+    IPV6_INTERFACES.lock().unwrap().retain(|dev| !same_dev(dev));
+    IPV6_ROUTES
+        .lock()
+        .unwrap()
+        .retain(|route| !same_dev(&route.dev));
+    ndp::ndp_dev_flush(&handler);
+    Ok(())
+}
+
+/// Adds the on-link route of the prefix of `addr` unless there is one.
+fn ipv6_route_on_link(dev: NetDeviceHandler, addr: Ipv6InterfaceAddress) {
+    let prefix = addr.addr.mask(addr.prefix_len);
+    let mut routes = IPV6_ROUTES.lock().unwrap();
+    if routes.iter().any(|route| {
+        route.dev.private == dev.private
+            && route.prefix == prefix
+            && route.prefix_len == addr.prefix_len
+            && route.nexthop.is_unspecified()
+    }) {
+        return;
+    }
+    routes.push(Ipv6Route {
+        prefix,
+        prefix_len: addr.prefix_len,
+        nexthop: Ipv6Address::UNSPECIFIED,
+        dev,
+    });
+}
+
+/// Adds `addr` with an on-link prefix of `prefix_len` bits to the IPv6 interface of `dev`.
+/// The address is used right away, without Duplicate Address Detection.
+pub fn ipv6_addr_add(dev: NetDeviceHandler, addr: Ipv6Address, prefix_len: u8) -> UtcpResult<()> {
+    ipv6_addr_add_state(dev, addr, prefix_len, Ipv6AddrState::Preferred)
+}
+
+/// Adds an address in the given state, tentative ones being checked by the caller.
+pub(crate) fn ipv6_addr_add_state(
+    dev: NetDeviceHandler,
+    addr: Ipv6Address,
+    prefix_len: u8,
+    state: Ipv6AddrState,
+) -> UtcpResult<()> {
+    if addr.is_unspecified() || addr.is_multicast() || prefix_len > 128 {
+        return Err(UtcpErr::InvalidAddress(format!("{}/{}", addr, prefix_len)));
+    }
+    let iface = ipv6_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+    let entry = Ipv6InterfaceAddress { addr, prefix_len };
+    {
+        let mut addrs = ip6_iface.addrs.lock().unwrap();
+        if addrs.iter().any(|(a, _)| a.addr == addr) {
+            return Err(UtcpErr::AddrInUse);
+        }
+        addrs.push((entry, state));
+    }
+    ipv6_route_on_link(dev, entry);
+    log::info!(
+        "address added: dev={}, addr={}/{}, state={:?}",
+        net_device_get!(dev).name(),
+        addr,
+        prefix_len,
+        state
+    );
+    Ok(())
+}
+
+/// Changes the state of an address of `dev`.
+pub(crate) fn ipv6_addr_set_state(
+    dev: NetDeviceHandler,
+    addr: Ipv6Address,
+    state: Ipv6AddrState,
+) -> UtcpResult<()> {
+    let iface = ipv6_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+    let mut addrs = ip6_iface.addrs.lock().unwrap();
+    let (_, current) = addrs
+        .iter_mut()
+        .find(|(a, _)| a.addr == addr)
+        .ok_or(UtcpErr::AddrNotAvailable)?;
+    if *current != state {
+        log::info!("address {}: {:?} -> {:?}", addr, current, state);
+        *current = state;
+    }
+    Ok(())
+}
+
+/// Returns the state of an address of `dev`.
+pub fn ipv6_addr_state(dev: NetDeviceHandler, addr: Ipv6Address) -> Option<Ipv6AddrState> {
+    let iface = ipv6_iface_of_dev(&dev)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into().ok()?;
+    ip6_iface.address_state(addr)
+}
+
+/// Removes `addr` from the IPv6 interface of `dev`, and its on-link route if no other address
+/// is in the prefix.
+pub fn ipv6_addr_del(dev: NetDeviceHandler, addr: Ipv6Address) -> UtcpResult<()> {
+    let iface = ipv6_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+    let mut addrs = ip6_iface.addrs.lock().unwrap();
+    let pos = addrs
+        .iter()
+        .position(|(a, _)| a.addr == addr)
+        .ok_or(UtcpErr::AddrNotAvailable)?;
+    let (removed, _) = addrs.remove(pos);
+    let prefix = addr.mask(removed.prefix_len);
+    if !addrs
+        .iter()
+        .any(|(a, _)| a.prefix_len == removed.prefix_len && a.addr.mask(a.prefix_len) == prefix)
+    {
+        IPV6_ROUTES.lock().unwrap().retain(|route| {
+            !(route.dev.private == dev.private
+                && route.prefix == prefix
+                && route.prefix_len == removed.prefix_len
+                && route.nexthop.is_unspecified())
+        });
+    }
+    log::info!(
+        "address removed: dev={}, addr={}",
+        net_device_get!(dev).name(),
+        addr
+    );
+    Ok(())
+}
+
+/// Returns the addresses of the IPv6 interface of `dev`.
+pub fn ipv6_addrs(dev: NetDeviceHandler) -> Vec<Ipv6InterfaceAddress> {
+    ipv6_iface_of_dev(&dev)
+        .map(|iface| {
+            let ip6_iface: &Ipv6Interface = iface.get().try_into().unwrap();
+            ip6_iface.addresses()
+        })
+        .unwrap_or_default()
+}
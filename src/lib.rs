@@ -1,9 +1,35 @@
+pub mod arp;
+mod asyncnet;
+pub mod conntrack;
+pub mod dhcp;
+pub mod dhcp_server;
+pub mod dns;
 pub mod driver;
 pub mod error;
+pub mod ether;
+pub mod event;
+pub mod filter;
+pub mod icmpv6;
+pub mod igmp;
 pub mod ip;
+pub mod ipv6;
+pub mod nat;
+pub mod ndp;
 pub mod net;
 pub mod platform;
+pub mod poll;
+pub mod qdisc;
+pub mod raw;
+pub mod slaac;
+pub mod socket;
+mod stdnet;
+pub mod tcp;
+pub mod udp;
 pub mod utils;
+pub mod wire;
+
+pub use asyncnet::{AsyncTcpListener, AsyncTcpStream, AsyncUdpSocket};
+pub use stdnet::{Incoming, TcpListener, TcpStream, UdpSocket};
 
 use env_logger::{Builder, Env, fmt::style};
 use log::Level;
@@ -1,16 +1,11 @@
-mod driver;
-mod error;
-mod ip;
-mod net;
-mod platform;
-pub mod utils;
-
 use std::sync::{Arc, atomic::AtomicBool};
 
-use driver::loopback::LoopbackNetDevice;
-use error::UtcpResult;
-use ip::IpAddress;
-use net::NET_PROTOCOL_TYPE_IP;
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpResult,
+    ip::{self, IpAddress},
+    net::{self, NET_PROTOCOL_TYPE_IP},
+};
 
 const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
 const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
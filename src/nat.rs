@@ -0,0 +1,655 @@
+//! Network address and port translation (NAPT) of forwarded IPv4 datagrams.
+//!
+//! Datagrams forwarded from an inside device out of an outside device with a masquerade rule
+//! leave with the address of the outside interface and a port (or ICMP query id) allocated for
+//! the inside endpoint. Connection tracking keeps the mapping so that the replies get the
+//! inside endpoint back, and static port forwards map ports of the outside address to inside
+//! endpoints. ICMP errors about a tracked connection (destination unreachable, fragmentation
+//! needed among them, and time exceeded) are translated as RFC 5508 asks: the address of the
+//! outer header along with the endpoint of the datagram they quote. Other ICMP messages are
+//! forwarded untranslated.
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    conntrack::{
+        CONNTRACK_TABLE, ConntrackEntry, ConntrackNat, ConntrackState, ConntrackTable, Endpoint,
+    },
+    error::{UtcpErr, UtcpResult},
+    ip::{self, IP_PROTOCOL_ICMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP, IpAddr, IpAddress, IpEndpoint},
+    net::NetDeviceHandler,
+    net_device_get, utils,
+    wire::{
+        icmp::{
+            ICMP_HEADER_LEN, ICMP_TYPE_DEST_UNREACH, ICMP_TYPE_ECHO, ICMP_TYPE_ECHOREPLY,
+            ICMP_TYPE_TIME_EXCEEDED,
+        },
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        write_checksum,
+    },
+};
+
+/// Ports allocated on the outside address, below the ephemeral ports of the stack's own
+/// sockets.
+pub const NAT_PORT_MIN: u16 = 32768;
+pub const NAT_PORT_MAX: u16 = 49151;
+
+/// A tracked connection. `inside` is seen from the remote host as `outside`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatConnection {
+    pub protocol: u8,
+    pub inside: IpEndpoint,
+    pub outside: IpEndpoint,
+    /// The port is 0 for ICMP.
+    pub remote: IpEndpoint,
+    pub state: ConntrackState,
+    pub expires_in: Duration,
+}
+
+/// Where the search for a free port starts.
+static NAT_NEXT_PORT: AtomicU16 = AtomicU16::new(NAT_PORT_MIN);
+
+#[derive(Debug, Clone, Copy)]
+struct NatMasquerade {
+    inside: NetDeviceHandler,
+    outside: NetDeviceHandler,
+}
+
+static NAT_MASQUERADES: Mutex<Vec<NatMasquerade>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy)]
+struct NatPortForward {
+    dev: NetDeviceHandler,
+    protocol: u8,
+    port: u16,
+    to: Endpoint,
+}
+
+static NAT_PORT_FORWARDS: Mutex<Vec<NatPortForward>> = Mutex::new(Vec::new());
+
+/// Translates the datagrams forwarded from `inside` out of `outside` to the address of the
+/// interface of `outside`. Forwarding has to be enabled with `ip_forwarding_set`.
+pub fn nat_masquerade_add(inside: NetDeviceHandler, outside: NetDeviceHandler) -> UtcpResult<()> {
+    let mut rules = NAT_MASQUERADES.lock().unwrap();
+    if rules
+        .iter()
+        .any(|rule| rule.inside == inside && rule.outside == outside)
+    {
+        return Err(UtcpErr::InvalidArgument("masquerade rule exists".into()));
+    }
+    log::info!(
+        "masquerade: inside={}, outside={}",
+        net_device_get!(inside).name(),
+        net_device_get!(outside).name()
+    );
+    rules.push(NatMasquerade { inside, outside });
+    Ok(())
+}
+
+/// Removes a masquerade rule. Tracked connections are kept until they expire.
+pub fn nat_masquerade_del(inside: NetDeviceHandler, outside: NetDeviceHandler) -> UtcpResult<()> {
+    let mut rules = NAT_MASQUERADES.lock().unwrap();
+    let len = rules.len();
+    rules.retain(|rule| !(rule.inside == inside && rule.outside == outside));
+    match rules.len() == len {
+        true => Err(UtcpErr::InvalidArgument("no such masquerade rule".into())),
+        false => Ok(()),
+    }
+}
+
+/// Forwards the TCP or UDP `port` of the address of the interface of `dev` to `to`.
+pub fn nat_port_forward_add(
+    dev: NetDeviceHandler,
+    protocol: u8,
+    port: u16,
+    to: IpEndpoint,
+) -> UtcpResult<()> {
+    if protocol != IP_PROTOCOL_TCP && protocol != IP_PROTOCOL_UDP {
+        return Err(UtcpErr::ProtocolNotSupported(protocol));
+    }
+    let IpAddr::V4(addr) = to.addr.to_canonical() else {
+        return Err(UtcpErr::AddrFamilyNotSupported);
+    };
+    if port == 0 || to.port == 0 {
+        return Err(UtcpErr::InvalidArgument("port 0".into()));
+    }
+    let mut rules = NAT_PORT_FORWARDS.lock().unwrap();
+    if rules
+        .iter()
+        .any(|rule| rule.dev == dev && rule.protocol == protocol && rule.port == port)
+    {
+        return Err(UtcpErr::AddrInUse);
+    }
+    log::info!(
+        "port forward: dev={}, protocol={}, port={}, to={}",
+        net_device_get!(dev).name(),
+        protocol,
+        port,
+        to
+    );
+    rules.push(NatPortForward {
+        dev,
+        protocol,
+        port,
+        to: (addr, to.port),
+    });
+    Ok(())
+}
+
+pub fn nat_port_forward_del(dev: NetDeviceHandler, protocol: u8, port: u16) -> UtcpResult<()> {
+    let mut rules = NAT_PORT_FORWARDS.lock().unwrap();
+    let len = rules.len();
+    rules.retain(|rule| !(rule.dev == dev && rule.protocol == protocol && rule.port == port));
+    match rules.len() == len {
+        true => Err(UtcpErr::InvalidArgument("no such port forward".into())),
+        false => Ok(()),
+    }
+}
+
+/// Returns the translated connections that have not expired. They time out as `conntrack`
+/// says.
+pub fn nat_connections() -> Vec<NatConnection> {
+    let now = Instant::now();
+    let table = CONNTRACK_TABLE.lock().unwrap();
+    let endpoint = |(addr, port): Endpoint| IpEndpoint::new(addr, port);
+    table
+        .entries()
+        .filter(|entry| entry.is_alive(&table.timeouts, now))
+        .filter_map(|entry| {
+            let (nat, remote) = entry.nat_mapping()?;
+            Some(NatConnection {
+                protocol: entry.protocol,
+                inside: endpoint(nat.inside),
+                outside: endpoint(nat.outside),
+                remote: endpoint(remote),
+                state: entry.state,
+                expires_in: entry.timeout(&table.timeouts) - now.duration_since(entry.updated),
+            })
+        })
+        .collect()
+}
+
+/// Returns the ports of a datagram that identify its connection: the source and destination
+/// ports of TCP and UDP, or the id of ICMP echo requests from the inside (as the source) and
+/// of echo replies from the remote host (as the destination).
+fn nat_ports(protocol: u8, payload: &[u8], from_inside: bool) -> Option<(u16, u16)> {
+    if protocol == IP_PROTOCOL_TCP && payload.len() < 20 {
+        return None;
+    }
+    nat_quoted_ports(protocol, payload, from_inside)
+}
+
+/// Same as `nat_ports` for the start of a datagram quoted by an ICMP error, which holds the
+/// first 8 bytes of its payload at least.
+fn nat_quoted_ports(protocol: u8, payload: &[u8], from_inside: bool) -> Option<(u16, u16)> {
+    if payload.len() < 8 {
+        return None;
+    }
+    let port = |off: usize| u16::from_be_bytes([payload[off], payload[off + 1]]);
+    match protocol {
+        IP_PROTOCOL_TCP | IP_PROTOCOL_UDP => Some((port(0), port(2))),
+        IP_PROTOCOL_ICMP => match (payload[0], from_inside) {
+            (ICMP_TYPE_ECHO, true) => Some((port(4), 0)),
+            (ICMP_TYPE_ECHOREPLY, false) => Some((0, port(4))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn nat_iface_addr(dev: &NetDeviceHandler) -> Option<IpAddress> {
+    let iface = ip::ip_iface_of_dev(dev)?;
+    let ip_iface: &ip::IpInterface = iface.get().try_into().ok()?;
+    Some(ip_iface.unicast())
+}
+
+/// Replaces the source or the destination address of a datagram, updating the header checksum,
+/// and returns the address it had.
+fn nat_translate_addr(datagram: &mut [u8], source: bool, addr: IpAddress) -> [u8; 4] {
+    let addr_off = if source { 12 } else { 16 };
+    let old_addr: [u8; 4] = datagram[addr_off..addr_off + 4].try_into().unwrap();
+    let new_addr = addr.octets();
+    datagram[addr_off..addr_off + 4].copy_from_slice(&new_addr);
+    let sum = u16::from_le_bytes([datagram[10], datagram[11]]);
+    write_checksum(
+        datagram,
+        10,
+        utils::checksum16_adjust(sum, &old_addr, &new_addr),
+    );
+    old_addr
+}
+
+/// Replaces the source or the destination of a datagram, updating the checksums as it goes.
+/// The port of ICMP is the query id. The datagram may be the start of one quoted by an ICMP
+/// error, whose transport checksum is left alone if it is cut off.
+fn nat_translate(datagram: &mut [u8], source: bool, (addr, port): Endpoint) {
+    let ip_hdr = Ipv4Packet::new_unchecked(&*datagram);
+    let (protocol, header_len, total) = (
+        ip_hdr.protocol(),
+        ip_hdr.header_len(),
+        ip_hdr.total() as usize,
+    );
+    let old_addr = nat_translate_addr(datagram, source, addr);
+    let new_addr = addr.octets();
+
+    let end = total.min(datagram.len());
+    let payload = &mut datagram[header_len..end];
+    // offsets of the port and of the checksum, and whether the pseudo header is covered
+    let (port_off, sum_off, pseudo) = match (protocol, source) {
+        (IP_PROTOCOL_TCP, true) => (0, 16, true),
+        (IP_PROTOCOL_TCP, false) => (2, 16, true),
+        (IP_PROTOCOL_UDP, true) => (0, 6, true),
+        (IP_PROTOCOL_UDP, false) => (2, 6, true),
+        _ => (4, 2, false),
+    };
+    let old_port: [u8; 2] = payload[port_off..port_off + 2].try_into().unwrap();
+    let new_port = port.to_be_bytes();
+    payload[port_off..port_off + 2].copy_from_slice(&new_port);
+    if payload.len() < sum_off + 2 {
+        return;
+    }
+    let sum = u16::from_le_bytes([payload[sum_off], payload[sum_off + 1]]);
+    if protocol == IP_PROTOCOL_UDP && sum == 0 {
+        // sent without a checksum
+        return;
+    }
+    let mut sum = utils::checksum16_adjust(sum, &old_port, &new_port);
+    if pseudo {
+        sum = utils::checksum16_adjust(sum, &old_addr, &new_addr);
+    }
+    if protocol == IP_PROTOCOL_UDP && sum == 0 {
+        sum = 0xffff;
+    }
+    write_checksum(payload, sum_off, sum);
+}
+
+/// Whether an ICMP message is an error that quotes the datagram it is about.
+fn nat_is_icmp_error(protocol: u8, payload: &[u8]) -> bool {
+    protocol == IP_PROTOCOL_ICMP
+        && payload.len() >= ICMP_HEADER_LEN
+        && matches!(payload[0], ICMP_TYPE_DEST_UNREACH | ICMP_TYPE_TIME_EXCEEDED)
+}
+
+/// Returns the protocol, the source and the destination of the datagram quoted by an ICMP
+/// error, which was sent by the inside host or by the remote one.
+fn nat_quoted(icmp: &[u8], from_inside: bool) -> Option<(u8, Endpoint, Endpoint)> {
+    let quoted = &icmp[ICMP_HEADER_LEN..];
+    if quoted.len() < IPV4_HEADER_MIN_LEN {
+        return None;
+    }
+    let ip_hdr = Ipv4Packet::new_unchecked(quoted);
+    let header_len = ip_hdr.header_len();
+    if header_len < IPV4_HEADER_MIN_LEN || header_len > quoted.len() {
+        return None;
+    }
+    // the quoted datagram may be cut off, but it must claim the 8 bytes of its payload that
+    // are translated
+    if (ip_hdr.total() as usize) < header_len + 8 {
+        return None;
+    }
+    let protocol = ip_hdr.protocol();
+    let (src_port, dst_port) = nat_quoted_ports(protocol, &quoted[header_len..], from_inside)?;
+    Some((protocol, (ip_hdr.src(), src_port), (ip_hdr.dst(), dst_port)))
+}
+
+/// Translates an ICMP error about a datagram of a tracked connection. The outer source or
+/// destination gets the address of `endpoint`, and the other end of the quoted datagram gets
+/// `endpoint`. The ICMP checksum is adjusted rather than computed again, so that it still
+/// tells whether the message was damaged on the way.
+fn nat_translate_icmp_error(datagram: &mut [u8], source: bool, endpoint: Endpoint) {
+    let ip_hdr = Ipv4Packet::new_unchecked(&*datagram);
+    let (header_len, total) = (ip_hdr.header_len(), ip_hdr.total() as usize);
+    nat_translate_addr(datagram, source, endpoint.0);
+    let icmp = &mut datagram[header_len..total];
+    let old = icmp.to_vec();
+    nat_translate(&mut icmp[ICMP_HEADER_LEN..], !source, endpoint);
+    // the translated fields are 16-bit words of the message, so an odd last byte is untouched
+    let len = old.len() & !1;
+    let sum = utils::checksum16_adjust(
+        u16::from_le_bytes([icmp[2], icmp[3]]),
+        &old[4..len],
+        &icmp[4..len],
+    );
+    write_checksum(icmp, 2, sum);
+}
+
+/// Translates an ICMP error from a remote host about a datagram that the inside host sent
+/// through `dev`, back to the inside host.
+fn nat_prerouting_icmp_error(dev: &NetDeviceHandler, datagram: &[u8]) -> Option<Vec<u8>> {
+    let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+    let (protocol, src, dst) = nat_quoted(ip_hdr.payload(), true)?;
+    let now = Instant::now();
+    let mut table = CONNTRACK_TABLE.lock().unwrap();
+    // errors do not keep the connection alive
+    let inside = table
+        .find_translated(protocol, dst, src, now)?
+        .nat_mapping()
+        .filter(|(nat, remote)| {
+            nat.dev == *dev && nat.outside == src && *remote == dst && src.0 == ip_hdr.dst()
+        })?
+        .0
+        .inside;
+    drop(table);
+    let mut buf = datagram[..ip_hdr.total() as usize].to_vec();
+    nat_translate_icmp_error(&mut buf, false, inside);
+    Some(buf)
+}
+
+/// Translates an ICMP error from an inside host about a datagram that a remote host sent to it
+/// through `out_dev`, if any, to the outside endpoint.
+fn nat_postrouting_icmp_error(out_dev: &NetDeviceHandler, datagram: &mut [u8]) {
+    let ip_hdr = Ipv4Packet::new_unchecked(&*datagram);
+    let Some((protocol, src, dst)) = nat_quoted(ip_hdr.payload(), false) else {
+        return;
+    };
+    let now = Instant::now();
+    let mut table = CONNTRACK_TABLE.lock().unwrap();
+    let outside = table
+        .find(protocol, src, dst, now)
+        .and_then(|entry| entry.nat_mapping())
+        .filter(|(nat, remote)| {
+            nat.dev == *out_dev && *remote == src && nat.inside == dst && dst.0 == ip_hdr.src()
+        })
+        .map(|(nat, _)| nat.outside);
+    drop(table);
+    if let Some(outside) = outside {
+        nat_translate_icmp_error(datagram, true, outside);
+    }
+}
+
+/// Translates the destination of a datagram received on `dev` if it is for a tracked
+/// connection or a port forward. Runs before the stack decides whether the datagram is its
+/// own, and returns the translated datagram.
+pub(crate) fn nat_prerouting(dev: &NetDeviceHandler, datagram: &[u8]) -> Option<Vec<u8>> {
+    let ip_hdr = Ipv4Packet::new_unchecked(datagram);
+    let (protocol, src, dst) = (ip_hdr.protocol(), ip_hdr.src(), ip_hdr.dst());
+    if nat_is_icmp_error(protocol, ip_hdr.payload()) {
+        return nat_prerouting_icmp_error(dev, datagram);
+    }
+    let (src_port, dst_port) = nat_ports(protocol, ip_hdr.payload(), false)?;
+    let now = Instant::now();
+    let mut table = CONNTRACK_TABLE.lock().unwrap();
+    let remote = (src, src_port);
+    let outside = (dst, dst_port);
+    let entry = table
+        .find_translated(protocol, remote, outside, now)
+        .filter(|entry| {
+            entry.nat_mapping().is_some_and(|(nat, entry_remote)| {
+                nat.dev == *dev && nat.outside == outside && entry_remote == remote
+            })
+        });
+    let inside = match entry {
+        Some(entry) => {
+            let reply = entry.is_reply(remote);
+            entry.track(ip_hdr.payload(), reply);
+            entry.nat()?.inside
+        }
+        None => {
+            let rules = NAT_PORT_FORWARDS.lock().unwrap();
+            let rule = rules.iter().find(|rule| {
+                rule.dev == *dev && rule.protocol == protocol && rule.port == dst_port
+            })?;
+            if nat_iface_addr(dev) != Some(dst) {
+                return None;
+            }
+            let mut entry = ConntrackEntry::new(protocol, remote, rule.to);
+            entry.track(ip_hdr.payload(), false);
+            table.insert(entry, now)?;
+            let nat = ConntrackNat {
+                dev: *dev,
+                inside: rule.to,
+                outside,
+            };
+            table.translate(protocol, remote, rule.to, nat);
+            log::debug!(
+                "forwarded port: protocol={}, outside={}:{}, inside={}:{}",
+                protocol,
+                dst,
+                dst_port,
+                rule.to.0,
+                rule.to.1
+            );
+            rule.to
+        }
+    };
+    let mut buf = datagram[..ip_hdr.total() as usize].to_vec();
+    nat_translate(&mut buf, false, inside);
+    Some(buf)
+}
+
+/// Allocates a port of `addr` for `inside`, reusing the one it already has so that the mapping
+/// does not depend on the remote endpoint (RFC 4787 REQ-1).
+fn nat_port_alloc(
+    table: &ConntrackTable,
+    protocol: u8,
+    dev: &NetDeviceHandler,
+    addr: IpAddress,
+    inside: Endpoint,
+) -> Option<u16> {
+    let now = Instant::now();
+    if let Some(port) = table.outside_port(protocol, dev, inside, addr, now) {
+        return Some(port);
+    }
+    let forwards = NAT_PORT_FORWARDS.lock().unwrap();
+    let range = NAT_PORT_MAX - NAT_PORT_MIN + 1;
+    let next_port = NAT_NEXT_PORT.load(Ordering::Relaxed);
+    for i in 0..range {
+        let port = NAT_PORT_MIN + (next_port - NAT_PORT_MIN + i) % range;
+        let in_use = table.outside_in_use(protocol, (addr, port), now)
+            || forwards
+                .iter()
+                .any(|rule| rule.dev == *dev && rule.protocol == protocol && rule.port == port);
+        if !in_use {
+            let next_port = if port == NAT_PORT_MAX {
+                NAT_PORT_MIN
+            } else {
+                port + 1
+            };
+            NAT_NEXT_PORT.store(next_port, Ordering::Relaxed);
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// Translates the source of a datagram forwarded from `in_dev` out of `out_dev`: datagrams of
+/// translated connections get their outside endpoint, and new connections allowed by a
+/// masquerade rule are mapped to the address of `out_dev`.
+pub(crate) fn nat_postrouting(
+    in_dev: &NetDeviceHandler,
+    out_dev: &NetDeviceHandler,
+    datagram: &mut [u8],
+) -> UtcpResult<()> {
+    let ip_hdr = Ipv4Packet::new_unchecked(&*datagram);
+    let (protocol, src, dst) = (ip_hdr.protocol(), ip_hdr.src(), ip_hdr.dst());
+    if nat_is_icmp_error(protocol, ip_hdr.payload()) {
+        nat_postrouting_icmp_error(out_dev, datagram);
+        return Ok(());
+    }
+    let Some((src_port, dst_port)) = nat_ports(protocol, ip_hdr.payload(), true) else {
+        return Ok(());
+    };
+    let payload = ip_hdr.payload().to_vec();
+    let now = Instant::now();
+    let mut table = CONNTRACK_TABLE.lock().unwrap();
+    let inside = (src, src_port);
+    let remote = (dst, dst_port);
+    // the filter may have started tracking the connection already
+    if let Some(entry) = table.find(protocol, inside, remote, now) {
+        let reply = entry.is_reply(inside);
+        match entry.nat() {
+            Some(nat) if nat.dev == *out_dev && nat.inside == inside => {
+                entry.track(&payload, reply);
+                drop(table);
+                nat_translate(datagram, true, nat.outside);
+                return Ok(());
+            }
+            // translated elsewhere, or a reply to a connection from the remote host
+            Some(_) => return Ok(()),
+            None if reply => return Ok(()),
+            None => {}
+        }
+    }
+    let masquerade = NAT_MASQUERADES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|rule| rule.inside == *in_dev && rule.outside == *out_dev);
+    if !masquerade {
+        return Ok(());
+    }
+    let addr = nat_iface_addr(out_dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let port = nat_port_alloc(&table, protocol, out_dev, addr, inside).ok_or(UtcpErr::AddrInUse)?;
+    let nat = ConntrackNat {
+        dev: *out_dev,
+        inside,
+        outside: (addr, port),
+    };
+    if table.find(protocol, inside, remote, now).is_none() {
+        table
+            .insert(ConntrackEntry::new(protocol, inside, remote), now)
+            .ok_or(UtcpErr::QueueFull)?;
+    }
+    if let Some(entry) = table.translate(protocol, inside, remote, nat) {
+        entry.track(&payload, false);
+    }
+    log::debug!(
+        "masqueraded: protocol={}, inside={}:{}, outside={}:{}",
+        protocol,
+        src,
+        src_port,
+        addr,
+        port
+    );
+    drop(table);
+    nat_translate(datagram, true, (addr, port));
+    Ok(())
+}
+
+#[test]
+fn test_nat_translate() {
+    use crate::wire::udp::{UDP_HEADER_LEN, UdpPacket};
+
+    let (inside, outside, remote) = (
+        IpAddress::parse_from("192.168.0.2"),
+        IpAddress::parse_from("203.0.113.1"),
+        IpAddress::parse_from("198.51.100.7"),
+    );
+    let mut buf = vec![0u8; 20 + UDP_HEADER_LEN + 5];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(20);
+    ip_hdr.set_total(33);
+    ip_hdr.set_ttl(64);
+    ip_hdr.set_protocol(IP_PROTOCOL_UDP);
+    ip_hdr.set_src(inside);
+    ip_hdr.set_dst(remote);
+    ip_hdr.fill_checksum();
+    let mut udp = UdpPacket::new_unchecked(&mut buf[20..]);
+    udp.set_src_port(5000);
+    udp.set_dst_port(53);
+    udp.set_len(13);
+    udp.payload_mut().copy_from_slice(b"hello");
+    udp.fill_checksum(inside, remote);
+
+    nat_translate(&mut buf, true, (outside, 40000));
+    let ip_hdr = Ipv4Packet::new_checked(&buf[..]).unwrap();
+    assert!(ip_hdr.verify_checksum());
+    assert_eq!(ip_hdr.src(), outside);
+    let udp = UdpPacket::new_checked(ip_hdr.payload()).unwrap();
+    assert_eq!(udp.src_port(), 40000);
+    assert!(udp.verify_checksum(outside, remote));
+    assert_eq!(
+        nat_ports(IP_PROTOCOL_UDP, ip_hdr.payload(), true),
+        Some((40000, 53))
+    );
+}
+
+#[test]
+fn test_nat_translate_icmp_error() {
+    use crate::wire::{
+        icmp::{ICMP_CODE_PORT_UNREACH, IcmpPacket},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    };
+
+    let (inside, outside, remote) = (
+        IpAddress::parse_from("192.168.0.2"),
+        IpAddress::parse_from("203.0.113.1"),
+        IpAddress::parse_from("198.51.100.7"),
+    );
+    // a datagram from the inside host, as it left
+    let mut sent = [0u8; 20 + UDP_HEADER_LEN + 5];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut sent[..]);
+    ip_hdr.set_header_len(20);
+    ip_hdr.set_total(33);
+    ip_hdr.set_ttl(64);
+    ip_hdr.set_protocol(IP_PROTOCOL_UDP);
+    ip_hdr.set_src(outside);
+    ip_hdr.set_dst(remote);
+    ip_hdr.fill_checksum();
+    let mut udp = UdpPacket::new_unchecked(&mut sent[20..]);
+    udp.set_src_port(40000);
+    udp.set_dst_port(53);
+    udp.set_len(13);
+    udp.payload_mut().copy_from_slice(b"hello");
+    udp.fill_checksum(outside, remote);
+
+    // the remote host quotes all of it, an odd number of bytes, or only the first 8 of the
+    // payload
+    for quoted_len in [sent.len(), 20 + 8] {
+        let total = 20 + ICMP_HEADER_LEN + quoted_len;
+        let mut buf = vec![0u8; total];
+        let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ip_hdr.set_header_len(20);
+        ip_hdr.set_total(total as u16);
+        ip_hdr.set_ttl(64);
+        ip_hdr.set_protocol(IP_PROTOCOL_ICMP);
+        ip_hdr.set_src(remote);
+        ip_hdr.set_dst(outside);
+        ip_hdr.fill_checksum();
+        let mut icmp = IcmpPacket::new_unchecked(&mut buf[20..]);
+        icmp.set_msg_type(ICMP_TYPE_DEST_UNREACH);
+        icmp.set_code(ICMP_CODE_PORT_UNREACH);
+        icmp.payload_mut().copy_from_slice(&sent[..quoted_len]);
+        icmp.fill_checksum();
+
+        let ip_hdr = Ipv4Packet::new_checked(&buf[..]).unwrap();
+        assert!(nat_is_icmp_error(IP_PROTOCOL_ICMP, ip_hdr.payload()));
+        assert_eq!(
+            nat_quoted(ip_hdr.payload(), true),
+            Some((IP_PROTOCOL_UDP, (outside, 40000), (remote, 53)))
+        );
+        nat_translate_icmp_error(&mut buf, false, (inside, 5000));
+
+        let ip_hdr = Ipv4Packet::new_checked(&buf[..]).unwrap();
+        assert!(ip_hdr.verify_checksum());
+        assert_eq!((ip_hdr.src(), ip_hdr.dst()), (remote, inside));
+        let icmp = IcmpPacket::new_checked(ip_hdr.payload()).unwrap();
+        assert!(icmp.verify_checksum());
+        let quoted = Ipv4Packet::new_unchecked(icmp.payload());
+        assert!(quoted.verify_checksum());
+        assert_eq!((quoted.src(), quoted.dst()), (inside, remote));
+        assert_eq!(
+            nat_quoted(ip_hdr.payload(), true),
+            Some((IP_PROTOCOL_UDP, (inside, 5000), (remote, 53)))
+        );
+        if quoted_len == sent.len() {
+            let udp = UdpPacket::new_checked(quoted.payload()).unwrap();
+            assert!(udp.verify_checksum(inside, remote));
+        }
+
+        // a quoted header whose total does not reach the ports is not translated
+        for quoted_total in [0, 20, 20 + 7] {
+            let mut bad = buf.clone();
+            let mut quoted = Ipv4Packet::new_unchecked(&mut bad[20 + ICMP_HEADER_LEN..]);
+            quoted.set_total(quoted_total);
+            let ip_hdr = Ipv4Packet::new_checked(&bad[..]).unwrap();
+            assert_eq!(nat_quoted(ip_hdr.payload(), true), None);
+        }
+    }
+}
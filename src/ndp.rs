@@ -0,0 +1,896 @@
+//! Neighbor Discovery (RFC 4861) for IPv6 over Ethernet-class devices, that is devices with
+//! `NetDeviceFlags::NEED_ARP`.
+//!
+//! Neighbors are resolved with solicitations sent to their solicited-node multicast address,
+//! and kept in a cache whose entries go through the states of section 7.3.2. Router
+//! Advertisements update the parameters of the link and the list of default routers.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ipv6::{self, IPV6_HOP_LIMIT_DEFAULT, IPV6_NEXT_HEADER_ICMPV6, Ipv6Address, Ipv6Interface},
+    net::{self, NET_PROTOCOL_TYPE_IPV6, NetDeviceHandler, NetInterfaceHandler},
+    net_device_get, slaac,
+    utils::XorShift32,
+    wire::{
+        ethernet::EthernetAddress,
+        icmpv6::{
+            ICMPV6_TYPE_NEIGHBOR_ADVERT, ICMPV6_TYPE_NEIGHBOR_SOLICIT, ICMPV6_TYPE_REDIRECT,
+            ICMPV6_TYPE_ROUTER_ADVERT, ICMPV6_TYPE_ROUTER_SOLICIT, Icmpv6Packet,
+            NDP_NA_FLAG_OVERRIDE, NDP_NA_FLAG_ROUTER, NDP_NA_FLAG_SOLICITED, NDP_NEIGHBOR_LEN,
+            NDP_OPTION_LL_ADDR_LEN, NDP_OPTION_SOURCE_LL_ADDR, NDP_OPTION_TARGET_LL_ADDR,
+            NDP_RA_FLAG_MANAGED, NDP_RA_FLAG_OTHER, NDP_ROUTER_SOLICIT_LEN, NdpOption,
+            NdpPrefixInfo, ndp_option_ll_addr_write,
+        },
+    },
+};
+
+/// Every Neighbor Discovery message is sent with this hop limit, and received ones are
+/// dropped unless they have it, which proves that they come from the link.
+pub const NDP_HOP_LIMIT: u8 = 255;
+
+/// Protocol constants of RFC 4861 section 10.
+const NDP_REACHABLE_TIME: Duration = Duration::from_secs(30);
+const NDP_RETRANS_TIMER: Duration = Duration::from_secs(1);
+const NDP_DELAY_FIRST_PROBE_TIME: Duration = Duration::from_secs(5);
+const NDP_MAX_MULTICAST_SOLICIT: u32 = 3;
+const NDP_MAX_UNICAST_SOLICIT: u32 = 3;
+/// Datagrams kept per unresolved address. The oldest is dropped when it is full.
+const NDP_PENDING_LIMIT: usize = 8;
+const NDP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// States of a neighbor cache entry (RFC 4861 section 7.3.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NdpState {
+    /// A multicast solicitation was sent and no advertisement arrived yet.
+    Incomplete,
+    /// The neighbor was confirmed reachable within the reachable time.
+    Reachable,
+    /// The address is known but not confirmed. Sending to it starts a probe.
+    Stale,
+    /// A datagram was sent in the stale state. A probe is sent unless the neighbor is
+    /// confirmed within `NDP_DELAY_FIRST_PROBE_TIME`.
+    Delay,
+    /// Unicast solicitations are being sent to confirm the address.
+    Probe,
+    /// Added by `ndp_add_static`. Never expires or changes.
+    Static,
+}
+
+#[derive(Debug)]
+struct NdpEntry {
+    state: NdpState,
+    addr: Ipv6Address,
+    ha: EthernetAddress,
+    dev: NetDeviceHandler,
+    is_router: bool,
+    updated: Instant,
+    probes: u32,
+    /// IPv6 datagrams waiting for the address to be resolved.
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl NdpEntry {
+    fn new(dev: NetDeviceHandler, addr: Ipv6Address, now: Instant) -> Self {
+        Self {
+            state: NdpState::Incomplete,
+            addr,
+            ha: EthernetAddress::ANY,
+            dev,
+            is_router: false,
+            updated: now,
+            probes: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn set_state(&mut self, state: NdpState, now: Instant) {
+        if self.state != state {
+            log::debug!("{}: {:?} -> {:?}", self.addr, self.state, state);
+        }
+        self.state = state;
+        self.updated = now;
+        self.probes = 0;
+    }
+
+    /// Applies a link-layer address learned from a solicitation or a Router Advertisement
+    /// (RFC 4861 sections 6.3.4 and 7.2.3). Returns the datagrams that can be sent now.
+    fn learn(&mut self, ha: EthernetAddress, now: Instant) -> VecDeque<Vec<u8>> {
+        match self.state {
+            NdpState::Static => {}
+            NdpState::Incomplete => {
+                self.ha = ha;
+                self.set_state(NdpState::Stale, now);
+                return std::mem::take(&mut self.pending);
+            }
+            _ if self.ha != ha => {
+                self.ha = ha;
+                self.set_state(NdpState::Stale, now);
+            }
+            _ => {}
+        }
+        VecDeque::new()
+    }
+
+    /// Applies a Neighbor Advertisement (RFC 4861 section 7.2.5). Returns the datagrams that
+    /// can be sent now.
+    fn advert(
+        &mut self,
+        tha: Option<EthernetAddress>,
+        flags: u32,
+        now: Instant,
+    ) -> VecDeque<Vec<u8>> {
+        let solicited = flags & NDP_NA_FLAG_SOLICITED != 0;
+        match self.state {
+            NdpState::Static => {}
+            NdpState::Incomplete => {
+                let Some(ha) = tha else {
+                    return VecDeque::new();
+                };
+                self.ha = ha;
+                self.is_router = flags & NDP_NA_FLAG_ROUTER != 0;
+                let state = match solicited {
+                    true => NdpState::Reachable,
+                    false => NdpState::Stale,
+                };
+                self.set_state(state, now);
+                return std::mem::take(&mut self.pending);
+            }
+            _ => {
+                let changed = tha.is_some_and(|ha| ha != self.ha);
+                if changed && flags & NDP_NA_FLAG_OVERRIDE == 0 {
+                    // keep the address, but do not trust it any more
+                    if self.state == NdpState::Reachable {
+                        self.set_state(NdpState::Stale, now);
+                    }
+                    return VecDeque::new();
+                }
+                if let Some(ha) = tha {
+                    self.ha = ha;
+                }
+                if solicited {
+                    self.set_state(NdpState::Reachable, now);
+                } else if changed {
+                    self.set_state(NdpState::Stale, now);
+                }
+                self.is_router = flags & NDP_NA_FLAG_ROUTER != 0;
+            }
+        }
+        VecDeque::new()
+    }
+}
+
+#[test]
+fn test_ndp_entry_states() {
+    let dev = NetDeviceHandler { private: 0 };
+    let now = Instant::now();
+    let ha1: EthernetAddress = "02:00:00:00:00:01".parse().unwrap();
+    let ha2: EthernetAddress = "02:00:00:00:00:02".parse().unwrap();
+    let mut entry = NdpEntry::new(dev, "fe80::1".parse().unwrap(), now);
+    entry.pending.push_back(vec![1]);
+
+    // an advertisement without the address does not resolve it
+    assert!(entry.advert(None, NDP_NA_FLAG_SOLICITED, now).is_empty());
+    assert_eq!(entry.state, NdpState::Incomplete);
+    let pending = entry.advert(Some(ha1), NDP_NA_FLAG_SOLICITED | NDP_NA_FLAG_ROUTER, now);
+    assert_eq!(pending, [vec![1]]);
+    assert_eq!(
+        (entry.state, entry.ha, entry.is_router),
+        (NdpState::Reachable, ha1, true)
+    );
+
+    // another address without the override flag only makes the entry stale
+    entry.advert(Some(ha2), NDP_NA_FLAG_SOLICITED, now);
+    assert_eq!((entry.state, entry.ha), (NdpState::Stale, ha1));
+    // a solicited advertisement confirms it
+    entry.advert(None, NDP_NA_FLAG_SOLICITED, now);
+    assert_eq!((entry.state, entry.is_router), (NdpState::Reachable, false));
+    // an unsolicited override changes the address
+    entry.advert(Some(ha2), NDP_NA_FLAG_OVERRIDE, now);
+    assert_eq!((entry.state, entry.ha), (NdpState::Stale, ha2));
+
+    // a solicitation from the same address leaves the state alone, another address does not
+    entry.state = NdpState::Delay;
+    entry.learn(ha2, now);
+    assert_eq!(entry.state, NdpState::Delay);
+    entry.learn(ha1, now);
+    assert_eq!((entry.state, entry.ha), (NdpState::Stale, ha1));
+}
+
+/// A router on the link that sent a Router Advertisement with a non-zero lifetime.
+#[derive(Debug, Clone, Copy)]
+struct NdpRouter {
+    addr: Ipv6Address,
+    expires: Instant,
+}
+
+/// Parameters of the link of a device, which routers may change.
+#[derive(Debug)]
+struct NdpLink {
+    dev: NetDeviceHandler,
+    base_reachable_time: Duration,
+    /// Random value between 0.5 and 1.5 times `base_reachable_time`.
+    reachable_time: Duration,
+    retrans_timer: Duration,
+    cur_hop_limit: u8,
+    mtu: Option<u32>,
+    routers: Vec<NdpRouter>,
+}
+
+/// Duplicate Address Detection of a tentative address.
+#[derive(Debug)]
+struct NdpDad {
+    dev: NetDeviceHandler,
+    addr: Ipv6Address,
+    duplicate: bool,
+}
+
+#[derive(Debug)]
+struct NdpTables {
+    cache: Vec<NdpEntry>,
+    links: Vec<NdpLink>,
+    /// Addresses checked by `ndp_dad_start` that are waiting for `ndp_dad_finish`.
+    dads: Vec<NdpDad>,
+    rng: Option<XorShift32>,
+}
+
+impl NdpTables {
+    fn entry(&mut self, dev: &NetDeviceHandler, addr: Ipv6Address) -> Option<&mut NdpEntry> {
+        self.cache
+            .iter_mut()
+            .find(|entry| entry.addr == addr && entry.dev.private == dev.private)
+    }
+
+    fn random_reachable_time(&mut self, base: Duration) -> Duration {
+        let rng = self.rng.get_or_insert_with(|| {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            XorShift32::new(nanos | 1)
+        });
+        base.mul_f64(0.5 + rng.next_f64())
+    }
+
+    fn link(&mut self, dev: NetDeviceHandler) -> &mut NdpLink {
+        let pos = match self.links.iter().position(|l| l.dev.private == dev.private) {
+            Some(pos) => pos,
+            None => {
+                let reachable_time = self.random_reachable_time(NDP_REACHABLE_TIME);
+                self.links.push(NdpLink {
+                    dev,
+                    base_reachable_time: NDP_REACHABLE_TIME,
+                    reachable_time,
+                    retrans_timer: NDP_RETRANS_TIMER,
+                    cur_hop_limit: IPV6_HOP_LIMIT_DEFAULT,
+                    mtu: None,
+                    routers: Vec::new(),
+                });
+                self.links.len() - 1
+            }
+        };
+        &mut self.links[pos]
+    }
+}
+
+static NDP: Mutex<NdpTables> = Mutex::new(NdpTables {
+    cache: Vec::new(),
+    links: Vec::new(),
+    dads: Vec::new(),
+    rng: None,
+});
+
+/// Sends a Neighbor Discovery message built in `msg` from `src` to `dst`.
+fn ndp_output(
+    iface: &NetInterfaceHandler,
+    mut msg: Vec<u8>,
+    src: Ipv6Address,
+    dst: Ipv6Address,
+) -> UtcpResult<()> {
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.fill_checksum(src, dst);
+    log::debug!("{:?}", packet);
+    ipv6::ipv6_output_iface(
+        iface,
+        dst,
+        IPV6_NEXT_HEADER_ICMPV6,
+        &msg,
+        src,
+        dst,
+        NDP_HOP_LIMIT,
+    )
+}
+
+/// Builds a Neighbor Solicitation or Advertisement for `target`, with a link-layer address
+/// option of `ty` unless it is 0.
+fn ndp_neighbor_msg(
+    dev: &NetDeviceHandler,
+    msg_type: u8,
+    flags: u32,
+    target: Ipv6Address,
+    ty: u8,
+) -> UtcpResult<Vec<u8>> {
+    let ha = net_device_get!(dev).hw_addr().ok_or(UtcpErr::NotSupported(
+        "Neighbor Discovery on a device without address".into(),
+    ))?;
+    let len = match ty {
+        0 => NDP_NEIGHBOR_LEN,
+        _ => NDP_NEIGHBOR_LEN + NDP_OPTION_LL_ADDR_LEN,
+    };
+    let mut msg = vec![0u8; len];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(msg_type);
+    packet.set_code(0);
+    packet.set_rest_of_header(flags);
+    packet.set_target(target);
+    if ty != 0 {
+        ndp_option_ll_addr_write(&mut msg[NDP_NEIGHBOR_LEN..], ty, ha);
+    }
+    Ok(msg)
+}
+
+/// Sends a Neighbor Solicitation for `target`, to its solicited-node multicast address or,
+/// when probing a known neighbor, to the target itself. An unspecified `src` selects an
+/// address of the interface.
+pub(crate) fn ndp_solicit(
+    iface: &NetInterfaceHandler,
+    target: Ipv6Address,
+    src: Ipv6Address,
+    unicast: bool,
+) -> UtcpResult<()> {
+    let src = match src.is_unspecified() {
+        true => {
+            let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+            ip6_iface
+                .source_for(target)
+                .ok_or(UtcpErr::AddrNotAvailable)?
+        }
+        false => src,
+    };
+    let msg = ndp_neighbor_msg(
+        &iface.dev,
+        ICMPV6_TYPE_NEIGHBOR_SOLICIT,
+        0,
+        target,
+        NDP_OPTION_SOURCE_LL_ADDR,
+    )?;
+    let dst = match unicast {
+        true => target,
+        false => target.solicited_node(),
+    };
+    ndp_output(iface, msg, src, dst)
+}
+
+/// Sends a Router Solicitation to all routers, so that they advertise without waiting for
+/// their next periodic advertisement.
+pub fn ndp_router_solicit(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let iface = ipv6::ipv6_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    let ip6_iface: &Ipv6Interface = iface.get().try_into()?;
+    // the link-layer address is only included with a source address (RFC 4861 section 4.1)
+    let src = ip6_iface
+        .source_for(Ipv6Address::ALL_ROUTERS)
+        .filter(|addr| addr.is_link_local())
+        .unwrap_or(Ipv6Address::UNSPECIFIED);
+    let mut msg = vec![0u8; NDP_ROUTER_SOLICIT_LEN];
+    if !src.is_unspecified()
+        && let Some(ha) = net_device_get!(dev).hw_addr()
+    {
+        msg.resize(NDP_ROUTER_SOLICIT_LEN + NDP_OPTION_LL_ADDR_LEN, 0);
+        ndp_option_ll_addr_write(
+            &mut msg[NDP_ROUTER_SOLICIT_LEN..],
+            NDP_OPTION_SOURCE_LL_ADDR,
+            ha,
+        );
+    }
+    Icmpv6Packet::new_unchecked(&mut msg[..]).set_msg_type(ICMPV6_TYPE_ROUTER_SOLICIT);
+    ndp_output(&iface, msg, src, Ipv6Address::ALL_ROUTERS)
+}
+
+fn ndp_send_pending(dev: &NetDeviceHandler, ha: EthernetAddress, pending: VecDeque<Vec<u8>>) {
+    for datagram in pending {
+        if let Err(e) =
+            net::net_device_output(dev, NET_PROTOCOL_TYPE_IPV6, &datagram, &mut ha.0.clone())
+        {
+            log::warn!("failed to send a pending datagram: {}", e);
+        }
+    }
+}
+
+/// Sends an IPv6 datagram to `nexthop` on the device of `iface`. If the link-layer address is
+/// not known yet, the datagram waits for the advertisement answering a solicitation.
+pub(crate) fn ndp_output_datagram(
+    iface: &NetInterfaceHandler,
+    nexthop: Ipv6Address,
+    datagram: &[u8],
+) -> UtcpResult<()> {
+    let dev = &iface.dev;
+    let now = Instant::now();
+    let mut ndp = NDP.lock().unwrap();
+    match ndp.entry(dev, nexthop) {
+        Some(entry) if entry.state != NdpState::Incomplete => {
+            if entry.state == NdpState::Stale {
+                entry.set_state(NdpState::Delay, now);
+            }
+            let ha = entry.ha;
+            drop(ndp);
+            net::net_device_output(dev, NET_PROTOCOL_TYPE_IPV6, datagram, &mut ha.0.clone())
+        }
+        Some(entry) => {
+            if entry.pending.len() >= NDP_PENDING_LIMIT {
+                entry.pending.pop_front();
+                log::warn!("pending queue full, dropped a datagram: addr={}", nexthop);
+            }
+            entry.pending.push_back(datagram.to_vec());
+            Ok(())
+        }
+        None => {
+            let mut entry = NdpEntry::new(*dev, nexthop, now);
+            entry.probes = 1;
+            entry.pending.push_back(datagram.to_vec());
+            ndp.cache.push(entry);
+            drop(ndp);
+            ndp_solicit(iface, nexthop, Ipv6Address::UNSPECIFIED, false)
+        }
+    }
+}
+
+/// Records the link-layer address of `addr` from a message other than an advertisement,
+/// creating a stale entry if there is none.
+fn ndp_learn(dev: &NetDeviceHandler, addr: Ipv6Address, ha: EthernetAddress) {
+    let now = Instant::now();
+    let mut ndp = NDP.lock().unwrap();
+    let pending = match ndp.entry(dev, addr) {
+        Some(entry) => entry.learn(ha, now),
+        None => {
+            let mut entry = NdpEntry::new(*dev, addr, now);
+            entry.learn(ha, now);
+            ndp.cache.push(entry);
+            log::debug!("cache insert: addr={}, ha={}", addr, ha);
+            VecDeque::new()
+        }
+    };
+    drop(ndp);
+    ndp_send_pending(dev, ha, pending);
+}
+
+fn ndp_neighbor_solicit_input(
+    packet: &Icmpv6Packet<&[u8]>,
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    iface: &NetInterfaceHandler,
+    options: &[NdpOption],
+) {
+    let target = packet.target();
+    let sha = options.iter().find_map(|option| match option {
+        NdpOption::SourceLinkLayerAddr(ha) => Some(*ha),
+        _ => None,
+    });
+    if target.is_multicast() {
+        log::error!("multicast target: {}", target);
+        return;
+    }
+    // Duplicate Address Detection from another host (RFC 4861 section 7.1.1)
+    if src.is_unspecified() && (dst != target.solicited_node() || sha.is_some()) {
+        log::error!("invalid solicitation from the unspecified address");
+        return;
+    }
+    // a node checking the same address makes it a duplicate, others are not answered until
+    // the check is over (RFC 4862 section 5.4.3)
+    if ndp_dad_check(&iface.dev, target, src.is_unspecified()) {
+        return;
+    }
+    let ip6_iface: &Ipv6Interface = iface.get().try_into().unwrap();
+    if !ip6_iface.has_address(target) {
+        return;
+    }
+    if let Some(ha) = sha {
+        ndp_learn(&iface.dev, src, ha);
+    }
+
+    // a host answering Duplicate Address Detection tells all nodes (section 7.2.4)
+    let (flags, reply_dst) = match src.is_unspecified() {
+        true => (NDP_NA_FLAG_OVERRIDE, Ipv6Address::ALL_NODES),
+        false => (NDP_NA_FLAG_SOLICITED | NDP_NA_FLAG_OVERRIDE, src),
+    };
+    let msg = ndp_neighbor_msg(
+        &iface.dev,
+        ICMPV6_TYPE_NEIGHBOR_ADVERT,
+        flags,
+        target,
+        NDP_OPTION_TARGET_LL_ADDR,
+    );
+    if let Err(e) = msg.and_then(|msg| ndp_output(iface, msg, target, reply_dst)) {
+        log::error!("failed to advertise: {}", e);
+    }
+}
+
+fn ndp_neighbor_advert_input(
+    packet: &Icmpv6Packet<&[u8]>,
+    dst: Ipv6Address,
+    iface: &NetInterfaceHandler,
+    options: &[NdpOption],
+) {
+    let (target, flags) = (packet.target(), packet.rest_of_header());
+    if target.is_multicast() || (dst.is_multicast() && flags & NDP_NA_FLAG_SOLICITED != 0) {
+        log::error!(
+            "invalid advertisement: target={}, flags=0x{:08x}",
+            target,
+            flags
+        );
+        return;
+    }
+    if ndp_dad_check(&iface.dev, target, true) {
+        return;
+    }
+    let ip6_iface: &Ipv6Interface = iface.get().try_into().unwrap();
+    if ip6_iface.has_address(target) {
+        log::warn!("another node advertises our address: {}", target);
+        return;
+    }
+    let tha = options.iter().find_map(|option| match option {
+        NdpOption::TargetLinkLayerAddr(ha) => Some(*ha),
+        _ => None,
+    });
+    let dev = &iface.dev;
+    let mut ndp = NDP.lock().unwrap();
+    // advertisements never create entries (section 7.2.5)
+    let Some(entry) = ndp.entry(dev, target) else {
+        return;
+    };
+    let was_router = entry.is_router;
+    let pending = entry.advert(tha, flags, Instant::now());
+    let (ha, is_router) = (entry.ha, entry.is_router);
+    if was_router && !is_router {
+        // no longer a router (section 7.2.5)
+        ndp.link(*dev)
+            .routers
+            .retain(|router| router.addr != target);
+    }
+    drop(ndp);
+    ndp_send_pending(dev, ha, pending);
+}
+
+/// Information of a Router Advertisement (RFC 4861 section 4.2).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdpRouterAdvert {
+    /// Link-local address of the router.
+    pub router: Ipv6Address,
+    /// Hop limit for outgoing datagrams, 0 if unspecified.
+    pub cur_hop_limit: u8,
+    /// Addresses are available with DHCPv6.
+    pub managed: bool,
+    /// Other configuration is available with DHCPv6.
+    pub other: bool,
+    /// How long the router may be used as a default router, zero if it is not one.
+    pub router_lifetime: Duration,
+    pub reachable_time: Option<Duration>,
+    pub retrans_timer: Option<Duration>,
+    pub mtu: Option<u32>,
+    pub prefixes: Vec<NdpPrefixInfo>,
+}
+
+fn ndp_router_advert_input(
+    packet: &Icmpv6Packet<&[u8]>,
+    src: Ipv6Address,
+    iface: &NetInterfaceHandler,
+    options: &[NdpOption],
+) {
+    // routers are identified by their link-local address (section 6.1.2)
+    if !src.is_link_local() {
+        log::error!("advertisement from a non link-local address: {}", src);
+        return;
+    }
+    let advert = NdpRouterAdvert {
+        router: src,
+        cur_hop_limit: packet.cur_hop_limit(),
+        managed: packet.ra_flags() & NDP_RA_FLAG_MANAGED != 0,
+        other: packet.ra_flags() & NDP_RA_FLAG_OTHER != 0,
+        router_lifetime: Duration::from_secs(packet.router_lifetime() as u64),
+        reachable_time: Some(packet.reachable_time())
+            .filter(|&ms| ms != 0)
+            .map(|ms| Duration::from_millis(ms as u64)),
+        retrans_timer: Some(packet.retrans_timer())
+            .filter(|&ms| ms != 0)
+            .map(|ms| Duration::from_millis(ms as u64)),
+        mtu: options.iter().find_map(|option| match option {
+            NdpOption::Mtu(mtu) => Some(*mtu),
+            _ => None,
+        }),
+        prefixes: options
+            .iter()
+            .filter_map(|option| match option {
+                NdpOption::PrefixInfo(info) => Some(*info),
+                _ => None,
+            })
+            .collect(),
+    };
+    log::debug!("{:?}", advert);
+    ndp_router_advert_apply(&iface.dev, &advert);
+
+    if let Some(ha) = options.iter().find_map(|option| match option {
+        NdpOption::SourceLinkLayerAddr(ha) => Some(*ha),
+        _ => None,
+    }) {
+        ndp_learn(&iface.dev, src, ha);
+    }
+    let mut ndp = NDP.lock().unwrap();
+    if let Some(entry) = ndp.entry(&iface.dev, src) {
+        entry.is_router = true;
+    }
+    drop(ndp);
+    slaac::slaac_router_advert(iface.dev, &advert);
+}
+
+/// Updates the parameters of the link and the default router list (RFC 4861 section 6.3.4).
+fn ndp_router_advert_apply(dev: &NetDeviceHandler, advert: &NdpRouterAdvert) {
+    let now = Instant::now();
+    let mut ndp = NDP.lock().unwrap();
+    let reachable_time = advert
+        .reachable_time
+        .map(|base| (base, ndp.random_reachable_time(base)));
+    let link = ndp.link(*dev);
+    if advert.cur_hop_limit != 0 {
+        link.cur_hop_limit = advert.cur_hop_limit;
+    }
+    // the random value is only recomputed when the base changes
+    if let Some((base, random)) = reachable_time
+        && base != link.base_reachable_time
+    {
+        link.base_reachable_time = base;
+        link.reachable_time = random;
+    }
+    if let Some(retrans_timer) = advert.retrans_timer {
+        link.retrans_timer = retrans_timer;
+    }
+    if advert.mtu.is_some() {
+        link.mtu = advert.mtu;
+    }
+    link.routers.retain(|router| router.addr != advert.router);
+    if !advert.router_lifetime.is_zero() {
+        link.routers.push(NdpRouter {
+            addr: advert.router,
+            expires: now + advert.router_lifetime,
+        });
+    }
+}
+
+/// Handles Neighbor Discovery messages passed on by `icmpv6_input`, after their checksum was
+/// verified.
+pub(crate) fn ndp_input(
+    packet: &Icmpv6Packet<&[u8]>,
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    hop_limit: u8,
+    iface: &NetInterfaceHandler,
+) {
+    // validation common to all messages (RFC 4861 sections 6.1 and 7.1)
+    if hop_limit != NDP_HOP_LIMIT || packet.code() != 0 {
+        log::error!(
+            "invalid message: hop_limit={}, code={}",
+            hop_limit,
+            packet.code()
+        );
+        return;
+    }
+    let options = match packet.ndp_options() {
+        Ok(options) => options,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    match packet.msg_type() {
+        ICMPV6_TYPE_NEIGHBOR_SOLICIT => {
+            ndp_neighbor_solicit_input(packet, src, dst, iface, &options)
+        }
+        ICMPV6_TYPE_NEIGHBOR_ADVERT => ndp_neighbor_advert_input(packet, dst, iface, &options),
+        ICMPV6_TYPE_ROUTER_ADVERT => ndp_router_advert_input(packet, src, iface, &options),
+        // only routers answer solicitations, and redirects are not followed
+        ICMPV6_TYPE_ROUTER_SOLICIT | ICMPV6_TYPE_REDIRECT => {}
+        ty => log::debug!("unsupported message: type={}", ty),
+    }
+}
+
+/// Adds an entry that never expires.
+pub fn ndp_add_static(dev: NetDeviceHandler, addr: Ipv6Address, ha: EthernetAddress) {
+    let now = Instant::now();
+    let mut ndp = NDP.lock().unwrap();
+    ndp.cache
+        .retain(|entry| !(entry.addr == addr && entry.dev.private == dev.private));
+    let mut entry = NdpEntry::new(dev, addr, now);
+    entry.ha = ha;
+    entry.state = NdpState::Static;
+    ndp.cache.push(entry);
+}
+
+/// Returns the link-layer address and state of a cached address.
+pub fn ndp_lookup(addr: Ipv6Address) -> Option<(EthernetAddress, NdpState)> {
+    let ndp = NDP.lock().unwrap();
+    ndp.cache
+        .iter()
+        .find(|entry| entry.addr == addr)
+        .map(|entry| (entry.ha, entry.state))
+}
+
+/// Returns the routers of the link of `dev` that may be used as default routers.
+pub fn ndp_default_routers(dev: NetDeviceHandler) -> Vec<Ipv6Address> {
+    let now = Instant::now();
+    let ndp = NDP.lock().unwrap();
+    ndp.links
+        .iter()
+        .filter(|link| link.dev.private == dev.private)
+        .flat_map(|link| link.routers.iter())
+        .filter(|router| router.expires > now)
+        .map(|router| router.addr)
+        .collect()
+}
+
+/// Returns the hop limit for unicast datagrams sent on `dev`, as advertised by routers.
+pub(crate) fn ndp_hop_limit(dev: &NetDeviceHandler) -> u8 {
+    let ndp = NDP.lock().unwrap();
+    ndp.links
+        .iter()
+        .find(|link| link.dev.private == dev.private)
+        .map_or(IPV6_HOP_LIMIT_DEFAULT, |link| link.cur_hop_limit)
+}
+
+/// Returns the link MTU advertised by routers on `dev`, if any.
+pub fn ndp_mtu(dev: NetDeviceHandler) -> Option<u32> {
+    let ndp = NDP.lock().unwrap();
+    ndp.links
+        .iter()
+        .find(|link| link.dev.private == dev.private)
+        .and_then(|link| link.mtu)
+}
+
+/// Marks `addr` as a duplicate if it is being checked on `dev` and `conflict` is set.
+/// Returns true if it is being checked.
+fn ndp_dad_check(dev: &NetDeviceHandler, addr: Ipv6Address, conflict: bool) -> bool {
+    let mut ndp = NDP.lock().unwrap();
+    let Some(dad) = ndp
+        .dads
+        .iter_mut()
+        .find(|dad| dad.addr == addr && dad.dev.private == dev.private)
+    else {
+        return false;
+    };
+    if conflict && !dad.duplicate {
+        log::warn!("duplicate address: {}", addr);
+        dad.duplicate = true;
+    }
+    true
+}
+
+/// Sends a Neighbor Solicitation from the unspecified address for the tentative address
+/// `addr` (RFC 4862 section 5.4.2). Call `ndp_dad_finish` after waiting for the retransmission
+/// timer of the link, which `ndp_retrans_timer` returns.
+pub(crate) fn ndp_dad_start(dev: NetDeviceHandler, addr: Ipv6Address) -> UtcpResult<()> {
+    let iface = ipv6::ipv6_iface_of_dev(&dev).ok_or(UtcpErr::AddrNotAvailable)?;
+    {
+        let mut ndp = NDP.lock().unwrap();
+        ndp.dads
+            .retain(|dad| !(dad.addr == addr && dad.dev.private == dev.private));
+        ndp.dads.push(NdpDad {
+            dev,
+            addr,
+            duplicate: false,
+        });
+    }
+    let msg = ndp_neighbor_msg(&dev, ICMPV6_TYPE_NEIGHBOR_SOLICIT, 0, addr, 0)?;
+    ndp_output(&iface, msg, Ipv6Address::UNSPECIFIED, addr.solicited_node())
+}
+
+/// Ends a check started by `ndp_dad_start`. Returns true if another node uses `addr`.
+pub(crate) fn ndp_dad_finish(dev: NetDeviceHandler, addr: Ipv6Address) -> bool {
+    let mut ndp = NDP.lock().unwrap();
+    let Some(index) = ndp
+        .dads
+        .iter()
+        .position(|dad| dad.addr == addr && dad.dev.private == dev.private)
+    else {
+        return false;
+    };
+    ndp.dads.remove(index).duplicate
+}
+
+/// Returns the retransmission timer of the link of `dev`, which routers may advertise.
+pub(crate) fn ndp_retrans_timer(dev: &NetDeviceHandler) -> Duration {
+    let ndp = NDP.lock().unwrap();
+    ndp.links
+        .iter()
+        .find(|link| link.dev.private == dev.private)
+        .map_or(NDP_RETRANS_TIMER, |link| link.retrans_timer)
+}
+
+/// Forgets the neighbors and parameters of `dev`, whose IPv6 interface is removed.
+pub(crate) fn ndp_dev_flush(dev: &NetDeviceHandler) {
+    let mut ndp = NDP.lock().unwrap();
+    ndp.cache.retain(|entry| entry.dev.private != dev.private);
+    ndp.links.retain(|link| link.dev.private != dev.private);
+    ndp.dads.retain(|dad| dad.dev.private != dev.private);
+}
+
+fn ndp_timer() {
+    let now = Instant::now();
+    // (device, target, unicast)
+    let mut solicits = Vec::new();
+    let mut ndp = NDP.lock().unwrap();
+    let links: Vec<_> = ndp
+        .links
+        .iter()
+        .map(|link| (link.dev, link.reachable_time, link.retrans_timer))
+        .collect();
+    let params = |dev: &NetDeviceHandler| {
+        links
+            .iter()
+            .find(|(d, _, _)| d.private == dev.private)
+            .map_or((NDP_REACHABLE_TIME, NDP_RETRANS_TIMER), |(_, r, t)| {
+                (*r, *t)
+            })
+    };
+    ndp.cache.retain_mut(|entry| {
+        let (reachable_time, retrans_timer) = params(&entry.dev);
+        let elapsed = now.duration_since(entry.updated);
+        match entry.state {
+            NdpState::Static | NdpState::Stale => true,
+            NdpState::Reachable => {
+                if elapsed >= reachable_time {
+                    entry.set_state(NdpState::Stale, now);
+                }
+                true
+            }
+            NdpState::Delay => {
+                if elapsed >= NDP_DELAY_FIRST_PROBE_TIME {
+                    entry.set_state(NdpState::Probe, now);
+                    entry.probes = 1;
+                    solicits.push((entry.dev, entry.addr, true));
+                }
+                true
+            }
+            NdpState::Incomplete | NdpState::Probe => {
+                if elapsed < retrans_timer {
+                    return true;
+                }
+                let (max, unicast) = match entry.state {
+                    NdpState::Incomplete => (NDP_MAX_MULTICAST_SOLICIT, false),
+                    _ => (NDP_MAX_UNICAST_SOLICIT, true),
+                };
+                if entry.probes >= max {
+                    log::warn!(
+                        "no advertisement, dropped {} datagrams: addr={}",
+                        entry.pending.len(),
+                        entry.addr
+                    );
+                    return false;
+                }
+                entry.probes += 1;
+                entry.updated = now;
+                solicits.push((entry.dev, entry.addr, unicast));
+                true
+            }
+        }
+    });
+    for link in ndp.links.iter_mut() {
+        link.routers.retain(|router| router.expires > now);
+    }
+    drop(ndp);
+    for (dev, target, unicast) in solicits {
+        let Some(iface) = ipv6::ipv6_iface_of_dev(&dev) else {
+            continue;
+        };
+        if let Err(e) = ndp_solicit(&iface, target, Ipv6Address::UNSPECIFIED, unicast) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+pub fn ndp_init() -> UtcpResult<()> {
+    net::net_timer_register(NDP_TIMER_INTERVAL, ndp_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
@@ -1,12 +1,32 @@
-use std::{collections::VecDeque, sync::atomic::AtomicU32};
+use std::{
+    sync::{Arc, Mutex, atomic::AtomicU32},
+    time::{Duration, Instant},
+};
 
 use bitflags::bitflags;
 
 use crate::{
-    driver::{INTR_IRQ_SOFTIRQ, dummy::DummyNetDevice, loopback::LoopbackNetDevice},
+    arp, conntrack, dhcp,
+    driver::{
+        INTR_IRQ_SOFTIRQ,
+        bridge::{self, BridgeNetDevice},
+        dummy::{DUMMY_IRQ, DummyNetDevice},
+        ether_packet::{ETHER_PACKET_IRQ, EtherPacketNetDevice},
+        ether_tap::{ETHER_TAP_IRQ, EtherTapNetDevice},
+        loopback::{LOOPBACK_IRQ, LoopbackNetDevice},
+        tun::{TUN_IRQ, TunNetDevice},
+        udp_tunnel::{UDP_TUNNEL_IRQ, UdpTunnelNetDevice},
+        vlan::VlanNetDevice,
+    },
     error::{UtcpErr, UtcpResult},
+    igmp,
     ip::{self, IpInterface},
+    ipv6::{self, Ipv6Interface},
+    ndp,
     platform::linux::intr,
+    qdisc, slaac, tcp, udp,
+    utils::{BoundedQueue, DropPolicy, PushResult, QueueStats},
+    wire::ethernet::EthernetAddress,
 };
 
 pub const NET_PROTOCOL_TYPE_IP: u16 = 0x0800;
@@ -19,7 +39,7 @@ pub fn new_device_index() -> u32 {
 }
 
 bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct NetDeviceFlags: u16 {
         const UP = 0x1;
         const LOOPBACK = 0x10;
@@ -33,7 +53,12 @@ bitflags! {
 pub enum NetDevice {
     Dummy(DummyNetDevice),
     Loopback(LoopbackNetDevice),
-    Ethernet,
+    EtherTap(EtherTapNetDevice),
+    EtherPacket(EtherPacketNetDevice),
+    Tun(TunNetDevice),
+    UdpTunnel(UdpTunnelNetDevice),
+    Vlan(VlanNetDevice),
+    Bridge(BridgeNetDevice),
 }
 
 #[derive(Debug)]
@@ -41,6 +66,22 @@ pub enum NetDeviceType {
     Dummy,
     Loopback,
     Ethernet,
+    Tun,
+    UdpTunnel,
+    Vlan,
+    Bridge,
+}
+
+/// Counters of a device. Payload bytes, without the link-layer header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetDeviceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Received while the device was down.
+    pub rx_dropped: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
 }
 
 impl NetDevice {
@@ -48,7 +89,28 @@ impl NetDevice {
         match self {
             NetDevice::Dummy(_) => NetDeviceType::Dummy,
             NetDevice::Loopback(_) => NetDeviceType::Loopback,
-            NetDevice::Ethernet => NetDeviceType::Ethernet,
+            NetDevice::EtherTap(_) | NetDevice::EtherPacket(_) => NetDeviceType::Ethernet,
+            NetDevice::Tun(_) => NetDeviceType::Tun,
+            NetDevice::UdpTunnel(_) => NetDeviceType::UdpTunnel,
+            NetDevice::Vlan(_) => NetDeviceType::Vlan,
+            NetDevice::Bridge(_) => NetDeviceType::Bridge,
+        }
+    }
+
+    fn irq(&self) -> i32 {
+        match self {
+            NetDevice::Dummy(_) => DUMMY_IRQ,
+            NetDevice::Loopback(_) => LOOPBACK_IRQ,
+            NetDevice::EtherTap(_) => ETHER_TAP_IRQ,
+            NetDevice::EtherPacket(_) => ETHER_PACKET_IRQ,
+            NetDevice::Tun(_) => TUN_IRQ,
+            NetDevice::UdpTunnel(_) => UDP_TUNNEL_IRQ,
+            // frames come through the parent
+            NetDevice::Vlan(dev) => unsafe { &DEVICES[dev.parent().private] }.irq(),
+            NetDevice::Bridge(dev) => match dev.rx_port() {
+                Some(port) => unsafe { &DEVICES[port.private] }.irq(),
+                None => INTR_IRQ_SOFTIRQ,
+            },
         }
     }
 
@@ -56,7 +118,12 @@ impl NetDevice {
         match self {
             NetDevice::Dummy(_) => DummyNetDevice::MTU,
             NetDevice::Loopback(_) => LoopbackNetDevice::MTU,
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(_) => EtherTapNetDevice::MTU,
+            NetDevice::EtherPacket(_) => EtherPacketNetDevice::MTU,
+            NetDevice::Tun(_) => TunNetDevice::MTU,
+            NetDevice::UdpTunnel(_) => UdpTunnelNetDevice::MTU,
+            NetDevice::Vlan(dev) => dev.mtu(),
+            NetDevice::Bridge(_) => BridgeNetDevice::MTU,
         }
     }
 
@@ -64,15 +131,52 @@ impl NetDevice {
         match self {
             NetDevice::Dummy(dev) => dev.name(),
             NetDevice::Loopback(dev) => dev.name(),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.name(),
+            NetDevice::EtherPacket(dev) => dev.name(),
+            NetDevice::Tun(dev) => dev.name(),
+            NetDevice::UdpTunnel(dev) => dev.name(),
+            NetDevice::Vlan(dev) => dev.name(),
+            NetDevice::Bridge(dev) => dev.name(),
         }
     }
 
-    fn is_up(&self) -> bool {
+    pub fn flags(&self) -> NetDeviceFlags {
+        match self {
+            NetDevice::Dummy(dev) => dev.flags(),
+            NetDevice::Loopback(dev) => dev.flags(),
+            NetDevice::EtherTap(dev) => dev.flags(),
+            NetDevice::EtherPacket(dev) => dev.flags(),
+            NetDevice::Tun(dev) => dev.flags(),
+            NetDevice::UdpTunnel(dev) => dev.flags(),
+            NetDevice::Vlan(dev) => dev.flags(),
+            NetDevice::Bridge(dev) => dev.flags(),
+        }
+    }
+
+    /// Returns the hardware address of Ethernet-class devices.
+    pub fn hw_addr(&self) -> Option<EthernetAddress> {
+        match self {
+            NetDevice::Dummy(dev) => dev.hw_addr(),
+            NetDevice::Loopback(dev) => dev.hw_addr(),
+            NetDevice::EtherTap(dev) => dev.hw_addr(),
+            NetDevice::EtherPacket(dev) => dev.hw_addr(),
+            NetDevice::Tun(dev) => dev.hw_addr(),
+            NetDevice::UdpTunnel(dev) => dev.hw_addr(),
+            NetDevice::Vlan(dev) => dev.hw_addr(),
+            NetDevice::Bridge(dev) => dev.hw_addr(),
+        }
+    }
+
+    pub(crate) fn is_up(&self) -> bool {
         match self {
             NetDevice::Dummy(dev) => dev.is_up(),
             NetDevice::Loopback(dev) => dev.is_up(),
-            NetDevice::Ethernet => false,
+            NetDevice::EtherTap(dev) => dev.is_up(),
+            NetDevice::EtherPacket(dev) => dev.is_up(),
+            NetDevice::Tun(dev) => dev.is_up(),
+            NetDevice::UdpTunnel(dev) => dev.is_up(),
+            NetDevice::Vlan(dev) => dev.is_up(),
+            NetDevice::Bridge(dev) => dev.is_up(),
         }
     }
 
@@ -80,7 +184,12 @@ impl NetDevice {
         match self {
             NetDevice::Dummy(dev) => dev.open(),
             NetDevice::Loopback(dev) => dev.open(),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.open(),
+            NetDevice::EtherPacket(dev) => dev.open(),
+            NetDevice::Tun(dev) => dev.open(),
+            NetDevice::UdpTunnel(dev) => dev.open(),
+            NetDevice::Vlan(dev) => dev.open(),
+            NetDevice::Bridge(dev) => dev.open(),
         }
     }
 
@@ -88,23 +197,94 @@ impl NetDevice {
         match self {
             NetDevice::Dummy(dev) => dev.close(),
             NetDevice::Loopback(dev) => dev.close(),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.close(),
+            NetDevice::EtherPacket(dev) => dev.close(),
+            NetDevice::Tun(dev) => dev.close(),
+            NetDevice::UdpTunnel(dev) => dev.close(),
+            NetDevice::Vlan(dev) => dev.close(),
+            NetDevice::Bridge(dev) => dev.close(),
         }
     }
 
-    fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
+    /// Sends without checking the MTU, which `net_device_output` does.
+    pub(crate) fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()> {
         match self {
             NetDevice::Dummy(dev) => dev.transmit(ty, data, dst),
             NetDevice::Loopback(dev) => dev.transmit(ty, data, dst),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.transmit(ty, data, dst),
+            NetDevice::EtherPacket(dev) => dev.transmit(ty, data, dst),
+            NetDevice::Tun(dev) => dev.transmit(ty, data, dst),
+            NetDevice::UdpTunnel(dev) => dev.transmit(ty, data, dst),
+            NetDevice::Vlan(dev) => dev.transmit(ty, data, dst),
+            NetDevice::Bridge(dev) => dev.transmit(ty, data, dst),
+        }
+    }
+
+    /// Sends a whole Ethernet frame as it is, for devices that can.
+    pub(crate) fn transmit_frame(&mut self, frame: &[u8]) -> UtcpResult<()> {
+        match self {
+            NetDevice::Dummy(dev) => dev.transmit_frame(frame),
+            NetDevice::Loopback(dev) => dev.transmit_frame(frame),
+            NetDevice::EtherTap(dev) => dev.transmit_frame(frame),
+            NetDevice::EtherPacket(dev) => dev.transmit_frame(frame),
+            NetDevice::Tun(dev) => dev.transmit_frame(frame),
+            NetDevice::UdpTunnel(dev) => dev.transmit_frame(frame),
+            NetDevice::Vlan(dev) => dev.transmit_frame(frame),
+            NetDevice::Bridge(dev) => dev.transmit_frame(frame),
         }
     }
 
-    pub(crate) fn get_interfaces(&self) -> &[NetInterface] {
+    /// Returns the counters of devices that keep them.
+    pub fn stats(&self) -> Option<NetDeviceStats> {
         match self {
-            NetDevice::Dummy(_) => todo!(),
+            NetDevice::Dummy(dev) => dev.stats(),
+            NetDevice::Loopback(dev) => dev.stats(),
+            NetDevice::EtherTap(dev) => dev.stats(),
+            NetDevice::EtherPacket(dev) => dev.stats(),
+            NetDevice::Tun(dev) => dev.stats(),
+            NetDevice::UdpTunnel(dev) => dev.stats(),
+            NetDevice::Vlan(dev) => dev.stats(),
+            NetDevice::Bridge(dev) => dev.stats(),
+        }
+    }
+
+    fn set_queue_limit(&mut self, limit: usize, policy: DropPolicy) -> UtcpResult<()> {
+        match self {
+            NetDevice::Dummy(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::Loopback(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::EtherTap(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::EtherPacket(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::Tun(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::UdpTunnel(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::Vlan(dev) => dev.set_queue_limit(limit, policy),
+            NetDevice::Bridge(dev) => dev.set_queue_limit(limit, policy),
+        }
+    }
+
+    /// Returns the receive queue counters of devices that queue received packets.
+    pub fn queue_stats(&self) -> Option<QueueStats> {
+        match self {
+            NetDevice::Dummy(dev) => dev.queue_stats(),
+            NetDevice::Loopback(dev) => dev.queue_stats(),
+            NetDevice::EtherTap(dev) => dev.queue_stats(),
+            NetDevice::EtherPacket(dev) => dev.queue_stats(),
+            NetDevice::Tun(dev) => dev.queue_stats(),
+            NetDevice::UdpTunnel(dev) => dev.queue_stats(),
+            NetDevice::Vlan(dev) => dev.queue_stats(),
+            NetDevice::Bridge(dev) => dev.queue_stats(),
+        }
+    }
+
+    pub(crate) fn get_interfaces(&self) -> &[Arc<NetInterface>] {
+        match self {
+            NetDevice::Dummy(dev) => dev.get_interfaces(),
             NetDevice::Loopback(dev) => dev.get_interfaces(),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.get_interfaces(),
+            NetDevice::EtherPacket(dev) => dev.get_interfaces(),
+            NetDevice::Tun(dev) => dev.get_interfaces(),
+            NetDevice::UdpTunnel(dev) => dev.get_interfaces(),
+            NetDevice::Vlan(dev) => dev.get_interfaces(),
+            NetDevice::Bridge(dev) => dev.get_interfaces(),
         }
     }
 
@@ -115,9 +295,27 @@ impl NetDevice {
         iface: NetInterface,
     ) -> NetInterfaceHandler {
         match self {
-            NetDevice::Dummy(_) => todo!(),
+            NetDevice::Dummy(dev) => dev.add_interface(handler, iface),
             NetDevice::Loopback(dev) => dev.add_interface(handler, iface),
-            NetDevice::Ethernet => todo!(),
+            NetDevice::EtherTap(dev) => dev.add_interface(handler, iface),
+            NetDevice::EtherPacket(dev) => dev.add_interface(handler, iface),
+            NetDevice::Tun(dev) => dev.add_interface(handler, iface),
+            NetDevice::UdpTunnel(dev) => dev.add_interface(handler, iface),
+            NetDevice::Vlan(dev) => dev.add_interface(handler, iface),
+            NetDevice::Bridge(dev) => dev.add_interface(handler, iface),
+        }
+    }
+
+    pub(crate) fn remove_interface(&mut self, index: usize) -> Arc<NetInterface> {
+        match self {
+            NetDevice::Dummy(dev) => dev.remove_interface(index),
+            NetDevice::Loopback(dev) => dev.remove_interface(index),
+            NetDevice::EtherTap(dev) => dev.remove_interface(index),
+            NetDevice::EtherPacket(dev) => dev.remove_interface(index),
+            NetDevice::Tun(dev) => dev.remove_interface(index),
+            NetDevice::UdpTunnel(dev) => dev.remove_interface(index),
+            NetDevice::Vlan(dev) => dev.remove_interface(index),
+            NetDevice::Bridge(dev) => dev.remove_interface(index),
         }
     }
 }
@@ -128,7 +326,9 @@ impl<'a> TryFrom<&'a mut NetDevice> for &'a mut LoopbackNetDevice {
     fn try_from(value: &'a mut NetDevice) -> Result<Self, Self::Error> {
         match value {
             NetDevice::Loopback(dev) => Ok(dev),
-            _ => Err(UtcpErr::Net("not a loopback device".into())),
+            _ => Err(UtcpErr::DeviceTypeMismatch {
+                expected: "loopback",
+            }),
         }
     }
 }
@@ -140,13 +340,43 @@ pub trait NetDeviceOps {
 
     fn is_up(&self) -> bool;
     fn name(&self) -> &str;
+    fn flags(&self) -> NetDeviceFlags;
+
+    fn hw_addr(&self) -> Option<EthernetAddress> {
+        None
+    }
+
+    fn stats(&self) -> Option<NetDeviceStats> {
+        None
+    }
 
     fn open(&mut self) -> UtcpResult<()>;
     fn close(&mut self) -> UtcpResult<()>;
     fn transmit(&mut self, ty: u16, data: &[u8], dst: &mut [u8]) -> UtcpResult<()>;
+
+    /// Sends a frame built by someone else, such as one a bridge forwards.
+    fn transmit_frame(&mut self, _frame: &[u8]) -> UtcpResult<()> {
+        Err(UtcpErr::NotSupported(format!(
+            "raw frames on {}",
+            self.name()
+        )))
+    }
+
+    /// Changes the limit and drop policy of the queue of received packets, for drivers that
+    /// keep one.
+    fn set_queue_limit(&mut self, _limit: usize, _policy: DropPolicy) -> UtcpResult<()> {
+        Err(UtcpErr::NotSupported(format!(
+            "receive queue on {}",
+            self.name()
+        )))
+    }
+
+    fn queue_stats(&self) -> Option<QueueStats> {
+        None
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NetDeviceHandler {
     pub(crate) private: usize,
 }
@@ -168,7 +398,18 @@ pub static mut DEVICES: Vec<NetDevice> = Vec::new();
 
 pub fn net_init() -> UtcpResult<()> {
     intr::intr_init()?;
+    arp::arp_init()?;
+    bridge::bridge_init()?;
     ip::ip_init()?;
+    ipv6::ipv6_init()?;
+    ndp::ndp_init()?;
+    slaac::slaac_init()?;
+    igmp::igmp_init()?;
+    conntrack::conntrack_init()?;
+    qdisc::qdisc_init()?;
+    udp::udp_init()?;
+    tcp::tcp_init()?;
+    dhcp::dhcp_init()?;
     log::info!("initialized");
     Ok(())
 }
@@ -202,21 +443,63 @@ pub fn net_device_register(dev: NetDevice) -> UtcpResult<NetDeviceHandler> {
 
 #[allow(static_mut_refs)]
 pub fn net_device_output(
-    dev: &NetDeviceHandler,
+    handler: &NetDeviceHandler,
     r#type: u16,
     data: &[u8],
     dst: &mut [u8],
 ) -> UtcpResult<()> {
-    let dev = unsafe { &mut DEVICES[dev.private] };
+    let dev = unsafe { &mut DEVICES[handler.private] };
     if !dev.is_up() {
-        return Err(UtcpErr::Net("device not opened".into()));
+        return Err(UtcpErr::DeviceNotOpened(dev.name().to_string()));
     }
     if data.len() > dev.mtu() as usize {
-        return Err(UtcpErr::Net("data too large".into()));
+        return Err(UtcpErr::MessageTooLong {
+            mtu: dev.mtu() as usize,
+        });
+    }
+    if let Some(result) = qdisc::qdisc_enqueue(handler, r#type, data, dst) {
+        return result;
     }
     dev.transmit(r#type, data, dst)
 }
 
+pub fn net_device_mtu(handler: &NetDeviceHandler) -> u16 {
+    let dev = unsafe { &DEVICES[handler.private] };
+    dev.mtu()
+}
+
+pub fn net_device_flags(handler: &NetDeviceHandler) -> NetDeviceFlags {
+    let dev = unsafe { &DEVICES[handler.private] };
+    dev.flags()
+}
+
+pub fn net_device_stats(handler: &NetDeviceHandler) -> Option<NetDeviceStats> {
+    let dev = unsafe { &DEVICES[handler.private] };
+    dev.stats()
+}
+
+/// Changes the MTU of a device whose MTU can be changed, that is a VLAN device.
+pub fn net_device_set_mtu(handler: &NetDeviceHandler, mtu: u16) -> UtcpResult<()> {
+    let dev = unsafe { &mut DEVICES[handler.private] };
+    let dev: &mut VlanNetDevice = dev.try_into()?;
+    dev.set_mtu(mtu)
+}
+
+/// Changes the receive queue limit and drop policy of a device whose driver keeps one.
+pub fn net_device_set_queue_limit(
+    handler: &NetDeviceHandler,
+    limit: usize,
+    policy: DropPolicy,
+) -> UtcpResult<()> {
+    let dev = unsafe { &mut DEVICES[handler.private] };
+    dev.set_queue_limit(limit, policy)
+}
+
+pub fn net_device_queue_stats(handler: &NetDeviceHandler) -> Option<QueueStats> {
+    let dev = unsafe { &DEVICES[handler.private] };
+    dev.queue_stats()
+}
+
 fn net_device_open(dev: &mut NetDevice) -> UtcpResult<()> {
     dev.open()?;
     log::info!(
@@ -242,14 +525,30 @@ pub fn net_input_handler(dev: &NetDeviceHandler, r#type: u16, data: &[u8]) -> Ut
     log::debug!("dev={}, type={}, len={}", dev.private, r#type, data.len());
     log::debug!("data={:?}", data);
 
-    // Safety: We know that the current thread is the only one accessing the static variable
-    for proto in unsafe { NET_PROTOCOLS.iter_mut() } {
+    // Safety: protocols are only registered before the stack runs
+    for proto in unsafe { NET_PROTOCOLS.iter() } {
         if proto.ty == r#type {
             // enqueue the packet to the protocol queue
-            proto.queue.push_back(NetProtocolQueueEntry {
-                dev: dev.clone(),
+            let entry = NetProtocolQueueEntry {
+                dev: *dev,
                 data: data.to_vec(),
-            });
+            };
+            let mut queue = proto.queue.lock().unwrap();
+            match queue.push(entry) {
+                PushResult::Queued => (),
+                PushResult::DroppedHead(_) | PushResult::Dropped(_) => {
+                    log::warn!(
+                        "protocol queue overflow, dropped a packet: type={}, dropped={}",
+                        proto.ty,
+                        queue.stats().dropped
+                    );
+                }
+            }
+            let full = queue.is_full();
+            drop(queue);
+            if full {
+                net_device_throttle(&net_device_lower(dev));
+            }
             intr::intr_raise_irq(INTR_IRQ_SOFTIRQ)?;
             return Ok(());
         }
@@ -258,40 +557,99 @@ pub fn net_input_handler(dev: &NetDeviceHandler, r#type: u16, data: &[u8]) -> Ut
     Ok(())
 }
 
+/// Returns the device whose driver receives the packets of `dev`: the parent of a VLAN device,
+/// the port a bridge received the packet on, or `dev` itself.
+fn net_device_lower(dev: &NetDeviceHandler) -> NetDeviceHandler {
+    match unsafe { &DEVICES[dev.private] } {
+        NetDevice::Vlan(vlan) => net_device_lower(&vlan.parent()),
+        NetDevice::Bridge(bridge) => bridge.rx_port().unwrap_or(*dev),
+        _ => *dev,
+    }
+}
+
+/// Devices that were asked to stop delivering packets because a protocol queue is full.
+static mut THROTTLED_DEVICES: Vec<NetDeviceHandler> = Vec::new();
+
+#[allow(static_mut_refs)]
+fn net_device_throttle(dev: &NetDeviceHandler) {
+    // Safety: only accessed from the interrupt thread
+    let throttled = unsafe { &mut THROTTLED_DEVICES };
+    if !throttled.iter().any(|d| d.private == dev.private) {
+        log::debug!("throttled dev={}", dev.private);
+        throttled.push(*dev);
+    }
+}
+
+/// Returns true if the driver should stop pulling packets from the device and leave them in
+/// its own queue (or in the host). Once the protocol queues have been drained the device IRQ is
+/// raised again so that the driver can resume.
+#[allow(static_mut_refs)]
+pub fn net_device_throttled(dev: &NetDeviceHandler) -> bool {
+    // Safety: only accessed from the interrupt thread
+    unsafe { THROTTLED_DEVICES.iter().any(|d| d.private == dev.private) }
+}
+
+#[allow(static_mut_refs)]
+fn net_device_unthrottle_all() -> UtcpResult<()> {
+    // Safety: only accessed from the interrupt thread
+    let throttled = unsafe { std::mem::take(&mut THROTTLED_DEVICES) };
+    for dev in throttled {
+        log::debug!("unthrottled dev={}", dev.private);
+        let irq = unsafe { &DEVICES[dev.private] }.irq();
+        intr::intr_raise_irq(irq)?;
+    }
+    Ok(())
+}
+
 #[macro_export]
 macro_rules! net_device_get {
-    ($handler:expr) => {
+    ($handler:expr) => {{
+        let private = $handler.private;
         unsafe {
             use $crate::net::DEVICES;
-            &DEVICES[$handler.private]
+            &DEVICES[private]
         }
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! net_device_get_mut {
-    ($handler:expr) => {
+    ($handler:expr) => {{
+        let private = $handler.private;
         unsafe {
             use $crate::net::DEVICES;
-            &mut DEVICES[$handler.private]
+            &mut DEVICES[private]
         }
-    };
+    }};
 }
 
 static mut NET_PROTOCOLS: Vec<NetProtocol> = Vec::new();
 
+/// Default number of packets a protocol input queue can hold.
+pub const NET_PROTOCOL_QUEUE_LIMIT: usize = 256;
+
 pub struct NetProtocol {
     pub ty: u16,
     pub handler: fn(data: &[u8], dev: &NetDeviceHandler),
-    queue: VecDeque<NetProtocolQueueEntry>,
+    /// Filled by the interrupt thread and resized or read by any thread.
+    queue: Mutex<BoundedQueue<NetProtocolQueueEntry>>,
 }
 
 impl NetProtocol {
     pub fn new(ty: u16, handler: fn(data: &[u8], dev: &NetDeviceHandler)) -> Self {
+        Self::with_queue_limit(ty, handler, NET_PROTOCOL_QUEUE_LIMIT, DropPolicy::DropTail)
+    }
+
+    pub fn with_queue_limit(
+        ty: u16,
+        handler: fn(data: &[u8], dev: &NetDeviceHandler),
+        limit: usize,
+        policy: DropPolicy,
+    ) -> Self {
         Self {
             ty,
             handler,
-            queue: VecDeque::new(),
+            queue: Mutex::new(BoundedQueue::new(limit, policy)),
         }
     }
 }
@@ -310,19 +668,80 @@ pub fn net_protocol_register(proto: NetProtocol) {
     }
 }
 
+/// Changes the input queue limit and drop policy of a registered protocol.
+#[allow(static_mut_refs)]
+pub fn net_protocol_set_queue_limit(ty: u16, limit: usize, policy: DropPolicy) -> UtcpResult<()> {
+    let Some(proto) = (unsafe { NET_PROTOCOLS.iter() }).find(|proto| proto.ty == ty) else {
+        return Err(UtcpErr::ProtocolNotRegistered(ty));
+    };
+    proto.queue.lock().unwrap().set_limit(limit, policy);
+    Ok(())
+}
+
+/// Returns the input queue counters of a registered protocol.
+#[allow(static_mut_refs)]
+pub fn net_protocol_queue_stats(ty: u16) -> Option<QueueStats> {
+    unsafe { NET_PROTOCOLS.iter() }
+        .find(|proto| proto.ty == ty)
+        .map(|proto| proto.queue.lock().unwrap().stats())
+}
+
 #[allow(static_mut_refs)]
 pub fn net_softirq_handler() -> UtcpResult<()> {
-    for proto in unsafe { NET_PROTOCOLS.iter_mut() } {
-        while let Some(entry) = proto.queue.pop_front() {
+    for proto in unsafe { NET_PROTOCOLS.iter() } {
+        // the lock is not held while the packet is handled
+        loop {
+            let Some(entry) = proto.queue.lock().unwrap().pop_front() else {
+                break;
+            };
             (proto.handler)(&entry.data, &entry.dev);
         }
     }
+    net_device_unthrottle_all()
+}
+
+pub fn net_tx_softirq_handler() -> UtcpResult<()> {
+    qdisc::qdisc_run();
+    Ok(())
+}
+
+struct NetTimer {
+    interval: Duration,
+    last: Instant,
+    handler: fn(),
+}
+
+static NET_TIMERS: Mutex<Vec<NetTimer>> = Mutex::new(Vec::new());
+
+/// Registers a handler that the interrupt thread calls every `interval`. The resolution is
+/// `intr::INTR_TIMER_TICK`.
+pub fn net_timer_register(interval: Duration, handler: fn()) -> UtcpResult<()> {
+    let mut timers = NET_TIMERS.lock().unwrap();
+    timers.push(NetTimer {
+        interval,
+        last: Instant::now(),
+        handler,
+    });
+    log::info!("registered timer: interval={:?}", interval);
+    Ok(())
+}
+
+pub fn net_timer_handler() -> UtcpResult<()> {
+    let now = Instant::now();
+    let timers = &mut *NET_TIMERS.lock().unwrap();
+    for timer in timers {
+        if now.duration_since(timer.last) >= timer.interval {
+            timer.last = now;
+            (timer.handler)();
+        }
+    }
     Ok(())
 }
 
 #[derive(Debug)]
 pub enum NetInterface {
     Ip(IpInterface),
+    Ipv6(Ipv6Interface),
 }
 
 impl<'a> TryFrom<&'a NetInterface> for &'a IpInterface {
@@ -331,69 +750,111 @@ impl<'a> TryFrom<&'a NetInterface> for &'a IpInterface {
     fn try_from(value: &'a NetInterface) -> Result<Self, Self::Error> {
         match value {
             NetInterface::Ip(iface) => Ok(iface),
+            _ => Err(UtcpErr::InvalidArgument("not an IPv4 interface".into())),
         }
     }
 }
 
-/// Do not use this directly, use `net_device_get_iface` instead.
-#[derive(Copy, Clone)]
+impl<'a> TryFrom<&'a NetInterface> for &'a Ipv6Interface {
+    type Error = UtcpErr;
+
+    fn try_from(value: &'a NetInterface) -> Result<Self, Self::Error> {
+        match value {
+            NetInterface::Ipv6(iface) => Ok(iface),
+            _ => Err(UtcpErr::InvalidArgument("not an IPv6 interface".into())),
+        }
+    }
+}
+
+/// Interface of a device, held by routes and passed to the protocol handlers. It keeps the
+/// interface alive, so that it stays usable while the interface is removed by another thread.
+#[derive(Clone)]
 pub struct NetInterfaceHandler {
     pub(crate) dev: NetDeviceHandler,
-    pub(crate) iface_index: usize,
     pub(crate) family: NetInterfaceFamily,
+    pub(crate) iface: Arc<NetInterface>,
 }
 
-#[macro_export]
-macro_rules! net_iface_get {
-    ($handler:expr) => {
-        unsafe {
-            use $crate::net::DEVICES;
-            &DEVICES[$handler.dev.private].get_interfaces()[$handler.iface_index]
-        }
-    };
+impl NetInterfaceHandler {
+    pub(crate) fn get(&self) -> &NetInterface {
+        &self.iface
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum NetInterfaceFamily {
     Ip,
+    Ipv6,
 }
 
 impl NetInterface {
     pub fn family(&self) -> NetInterfaceFamily {
         match self {
             NetInterface::Ip(_) => NetInterfaceFamily::Ip,
+            NetInterface::Ipv6(_) => NetInterfaceFamily::Ipv6,
         }
     }
 }
 
-pub fn net_device_add_iface(handler: NetDeviceHandler, iface: NetInterface) -> UtcpResult<()> {
+/// Serializes the changes to the interface lists of the devices with the lookups in them,
+/// which happen on the intr thread, the timers and the application threads alike.
+static IFACES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Returns the handler of the interface of `family` on `dev`.
+pub(crate) fn net_iface_handler(
+    dev: &NetDeviceHandler,
+    family: NetInterfaceFamily,
+) -> Option<NetInterfaceHandler> {
+    let _lock = IFACES_LOCK.lock().unwrap();
+    let ifaces = unsafe { &DEVICES[dev.private] }.get_interfaces();
+    ifaces
+        .iter()
+        .find(|iface| iface.family() == family)
+        .map(|iface| NetInterfaceHandler {
+            dev: *dev,
+            family,
+            iface: iface.clone(),
+        })
+}
+
+pub fn net_device_add_iface(
+    handler: NetDeviceHandler,
+    iface: NetInterface,
+) -> UtcpResult<NetInterfaceHandler> {
+    let _lock = IFACES_LOCK.lock().unwrap();
     let dev = unsafe { &mut DEVICES[handler.private] };
 
-    for iface in dev.get_interfaces() {
-        if std::mem::discriminant(iface) == std::mem::discriminant(iface) {
-            return Err(UtcpErr::Net(format!(
-                "interface already exists, dev={}, family={:?}",
-                dev.name(),
-                iface.family()
-            )));
+    for existing in dev.get_interfaces() {
+        if existing.family() == iface.family() {
+            return Err(UtcpErr::InterfaceExists {
+                dev: dev.name().to_string(),
+                family: format!("{:?}", existing.family()),
+            });
         }
     }
 
-    dev.add_interface(handler, iface);
+    Ok(dev.add_interface(handler, iface))
+}
 
-    Ok(())
+/// Removes the interface of `family` from `dev`. Handlers of it keep working until they are
+/// dropped.
+pub(crate) fn net_device_remove_iface(
+    handler: NetDeviceHandler,
+    family: NetInterfaceFamily,
+) -> UtcpResult<Arc<NetInterface>> {
+    let _lock = IFACES_LOCK.lock().unwrap();
+    let dev = unsafe { &mut DEVICES[handler.private] };
+    let index = dev
+        .get_interfaces()
+        .iter()
+        .position(|iface| iface.family() == family)
+        .ok_or(UtcpErr::AddrNotAvailable)?;
+    Ok(dev.remove_interface(index))
 }
 
 pub fn net_device_get_iface(
     dev: &NetDeviceHandler,
     family: NetInterfaceFamily,
-) -> Option<&NetInterface> {
-    let dev = unsafe { &DEVICES[dev.private] };
-
-    for iface in dev.get_interfaces() {
-        if iface.family() == family {
-            return Some(iface);
-        }
-    }
-    None
+) -> Option<Arc<NetInterface>> {
+    net_iface_handler(dev, family).map(|handler| handler.iface)
 }
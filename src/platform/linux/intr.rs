@@ -1,13 +1,18 @@
 use std::{
     ffi::{c_int, c_void},
     ptr::{self, null, null_mut},
-    sync::Mutex,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+    },
+    thread::JoinHandle,
+    time::Duration,
 };
 
 use libc::SIG_BLOCK;
 
 use crate::{
-    driver::INTR_IRQ_SOFTIRQ,
+    driver::{INTR_IRQ_SOFTIRQ, INTR_IRQ_TIMER, INTR_IRQ_TX_SOFTIRQ},
     error::{UtcpErr, UtcpResult},
     net::{self, NetDeviceHandler},
     platform::{IRQEntry, IRQFlags},
@@ -18,6 +23,14 @@ static IRQS: Mutex<Vec<IRQEntry>> = Mutex::new(vec![]);
 static mut TID: libc::pthread_t = libc::pthread_t::MAX;
 static SIGMASK: Mutex<libc::sigset_t> = Mutex::new(unsafe { std::mem::zeroed() });
 static mut BARRIER: libc::pthread_barrier_t = unsafe { std::mem::zeroed() };
+/// Kernel thread id of the intr thread, for drivers that route `SIGIO`-style signals to it.
+static INTR_THREAD_TID: AtomicI32 = AtomicI32::new(0);
+
+/// Interval at which the timer thread raises `INTR_IRQ_TIMER`.
+pub const INTR_TIMER_TICK: Duration = Duration::from_millis(10);
+
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+static TIMER_THREAD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
 
 pub fn intr_request_irq(
     irq: i32,
@@ -27,13 +40,13 @@ pub fn intr_request_irq(
     dev: NetDeviceHandler,
 ) -> UtcpResult<()> {
     let mut irqs = IRQS.lock().unwrap();
-    // check conflicts
+    // check conflicts. an IRQ can be registered twice only if both are shared
     for ent in &*irqs {
         if ent.irq == irq
-            && ent.flags.contains(IRQFlags::SHARED) == flags.contains(IRQFlags::SHARED)
+            && !(ent.flags.contains(IRQFlags::SHARED) && flags.contains(IRQFlags::SHARED))
         {
             return Err(UtcpErr::Intr(format!(
-                "IRQ {} already registered with the same flags",
+                "IRQ {} already registered and not shared",
                 irq
             )));
         }
@@ -75,6 +88,10 @@ pub fn intr_init() -> UtcpResult<()> {
             libc::sigaddset(&mut *sigmask, libc::SIGHUP);
             // notify the intr thread to handle received packets
             libc::sigaddset(&mut *sigmask, INTR_IRQ_SOFTIRQ);
+            // notify the intr thread to send queued packets
+            libc::sigaddset(&mut *sigmask, INTR_IRQ_TX_SOFTIRQ);
+            // notify the intr thread to run timers
+            libc::sigaddset(&mut *sigmask, INTR_IRQ_TIMER);
         }
     }
     log::debug!("intr init");
@@ -85,18 +102,42 @@ pub fn intr_run() -> UtcpResult<()> {
     let sigmask = SIGMASK.lock().unwrap();
     let err = unsafe { libc::pthread_sigmask(SIG_BLOCK, &*sigmask, ptr::null_mut()) };
     if err != 0 {
-        return Err(UtcpErr::Intr(format!("pthread_sigmask failed: {}", err)));
+        return Err(UtcpErr::Sys {
+            call: "pthread_sigmask",
+            errno: err,
+        });
     }
     let err = unsafe { libc::pthread_create(&raw mut TID, null(), intr_thread, ptr::null_mut()) };
     if err != 0 {
-        return Err(UtcpErr::Intr(format!("pthread_create failed: {}", err)));
+        return Err(UtcpErr::Sys {
+            call: "pthread_create",
+            errno: err,
+        });
     }
     unsafe { libc::pthread_barrier_wait(&raw mut BARRIER) };
 
+    // The timer is a plain thread that signals the intr thread directly, so that no other
+    // thread of the process receives SIGALRM.
+    TIMER_RUNNING.store(true, Ordering::Relaxed);
+    let timer = std::thread::spawn(|| {
+        while TIMER_RUNNING.load(Ordering::Relaxed) {
+            std::thread::sleep(INTR_TIMER_TICK);
+            if let Err(e) = intr_raise_irq(INTR_IRQ_TIMER) {
+                log::error!("{}", e);
+                break;
+            }
+        }
+    });
+    *TIMER_THREAD.lock().unwrap() = Some(timer);
+
     Ok(())
 }
 
 pub fn intr_shutdown() -> UtcpResult<()> {
+    TIMER_RUNNING.store(false, Ordering::Relaxed);
+    if let Some(timer) = TIMER_THREAD.lock().unwrap().take() {
+        let _ = timer.join();
+    }
     unsafe {
         let current_tid = libc::pthread_self();
         if libc::pthread_equal(current_tid, TID) != 0 {
@@ -111,16 +152,59 @@ pub fn intr_shutdown() -> UtcpResult<()> {
     Ok(())
 }
 
+/// Returns the kernel thread id of the intr thread, or 0 before `intr_run`.
+pub fn intr_thread_tid() -> i32 {
+    INTR_THREAD_TID.load(Ordering::Relaxed)
+}
+
+// not exported by libc for glibc targets
+const F_SETSIG: c_int = 10;
+const F_SETOWN_EX: c_int = 15;
+const F_OWNER_TID: c_int = 0;
+
+#[repr(C)]
+struct FOwnerEx {
+    ty: c_int,
+    pid: libc::pid_t,
+}
+
+/// Makes `fd` non-blocking and has the kernel raise `irq` on the intr thread, which is the
+/// only one waiting for it, when data arrives on `fd`. The signal only tells that something
+/// arrived, so the handler reads until `fd` would block.
+pub fn intr_fd_set_async(fd: c_int, irq: i32) -> UtcpResult<()> {
+    let owner = FOwnerEx {
+        ty: F_OWNER_TID,
+        pid: intr_thread_tid(),
+    };
+    if unsafe { libc::fcntl(fd, F_SETOWN_EX, &owner) } < 0 {
+        return Err(UtcpErr::last_os_error("fcntl(F_SETOWN_EX)"));
+    }
+    if unsafe { libc::fcntl(fd, F_SETSIG, irq) } < 0 {
+        return Err(UtcpErr::last_os_error("fcntl(F_SETSIG)"));
+    }
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_ASYNC | libc::O_NONBLOCK) } < 0
+    {
+        return Err(UtcpErr::last_os_error("fcntl(F_SETFL)"));
+    }
+    Ok(())
+}
+
 pub fn intr_raise_irq(irq: i32) -> UtcpResult<()> {
     let err = unsafe { libc::pthread_kill(TID, irq) };
     if err != 0 {
-        return Err(UtcpErr::Intr(format!("pthread_kill failed: {}", err)));
+        return Err(UtcpErr::Sys {
+            call: "pthread_kill",
+            errno: err,
+        });
     }
     Ok(())
 }
 
 extern "C" fn intr_thread(_: *mut c_void) -> *mut c_void {
     log::debug!("intr thread start");
+    INTR_THREAD_TID.store(unsafe { libc::gettid() }, Ordering::Relaxed);
 
     let _ = unsafe { libc::pthread_barrier_wait(&raw mut BARRIER) };
 
@@ -142,10 +226,17 @@ extern "C" fn intr_thread(_: *mut c_void) -> *mut c_void {
                 INTR_IRQ_SOFTIRQ => {
                     net::net_softirq_handler().unwrap();
                 }
+                INTR_IRQ_TX_SOFTIRQ => {
+                    net::net_tx_softirq_handler().unwrap();
+                }
+                INTR_IRQ_TIMER => {
+                    net::net_timer_handler().unwrap();
+                }
                 _ => {
                     for ent in &*irqs {
                         if ent.irq == sig_sent {
-                            (ent.handler)(sig_sent, ent.dev.clone());
+                            log::debug!("irq={}, name={}", ent.irq, ent.debug_name);
+                            (ent.handler)(sig_sent, ent.dev);
                         }
                     }
                 }
@@ -0,0 +1,112 @@
+//! `poll(2)`-style readiness waiting over several sockets.
+//!
+//! The caller sleeps on the condition variable of `event`, which is signalled by the same
+//! input, timer and close paths that wake blocked socket calls.
+
+use std::time::{Duration, Instant};
+
+use bitflags::bitflags;
+
+use crate::{error::UtcpResult, event, socket};
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct PollEvents: u16 {
+        /// Data, a connection to accept or the end of the stream can be read without blocking.
+        const READABLE = libc::POLLIN as u16;
+        /// Data can be sent without blocking.
+        const WRITABLE = libc::POLLOUT as u16;
+        /// A connection error is pending. Always reported.
+        const ERROR = libc::POLLERR as u16;
+        /// The connection is closed or both directions are shut down. Always reported.
+        const HANGUP = libc::POLLHUP as u16;
+        /// The descriptor is not open. Always reported.
+        const INVALID = libc::POLLNVAL as u16;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PollMode {
+    /// An event is reported as long as the condition holds.
+    #[default]
+    Level,
+    /// An event is reported when the condition starts to hold, or when it still holds and
+    /// new input or a timer touched the socket since the previous call. Read or write until
+    /// `WouldBlock` before polling again.
+    Edge,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: PollEvents,
+    pub revents: PollEvents,
+    pub mode: PollMode,
+    /// Readiness and wakeup counter seen by the previous check.
+    last: Option<(PollEvents, u64)>,
+}
+
+impl PollFd {
+    pub fn new(fd: i32, events: PollEvents) -> Self {
+        Self {
+            fd,
+            events,
+            revents: PollEvents::empty(),
+            mode: PollMode::Level,
+            last: None,
+        }
+    }
+
+    pub fn edge(fd: i32, events: PollEvents) -> Self {
+        Self {
+            mode: PollMode::Edge,
+            ..Self::new(fd, events)
+        }
+    }
+
+    fn check(&mut self) {
+        let (ready, wakeups) = match socket::poll_events(self.fd) {
+            Ok(state) => state,
+            Err(_) => {
+                self.revents = PollEvents::INVALID;
+                return;
+            }
+        };
+        let new = match (self.mode, self.last) {
+            (PollMode::Edge, Some((last, last_wakeups))) if last_wakeups == wakeups => {
+                ready & !last
+            }
+            _ => ready,
+        };
+        self.last = Some((ready, wakeups));
+        let always = PollEvents::ERROR | PollEvents::HANGUP;
+        self.revents = new & (self.events | always);
+    }
+}
+
+/// Waits until one of `fds` is ready or `timeout` passes (forever if `None`). Fills `revents`
+/// and returns the number of descriptors with events, 0 on timeout.
+pub fn utcp_poll(fds: &mut [PollFd], timeout: Option<Duration>) -> UtcpResult<usize> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // read before checking, so that an event during the checks ends the wait
+        let seq = event::event_seq();
+        for fd in fds.iter_mut() {
+            fd.check();
+        }
+        let ready = fds.iter().filter(|fd| !fd.revents.is_empty()).count();
+        if ready > 0 {
+            return Ok(ready);
+        }
+        if !event::event_wait(seq, deadline) {
+            return Ok(0);
+        }
+    }
+}
+
+#[test]
+fn test_poll_invalid_descriptor() {
+    let mut fds = [PollFd::new(-1, PollEvents::READABLE)];
+    assert_eq!(utcp_poll(&mut fds, Some(Duration::ZERO)).unwrap(), 1);
+    assert_eq!(fds[0].revents, PollEvents::INVALID);
+}
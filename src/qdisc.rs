@@ -0,0 +1,573 @@
+//! Queueing disciplines (qdiscs) of the packets devices send.
+//!
+//! Without a qdisc, `net_device_output` hands packets to the driver right away. A qdisc
+//! attached to a device with `qdisc_attach` queues them instead, and the tx softirq hands
+//! them to the driver in the order the qdisc dequeues them, when the qdisc lets it. Packets a
+//! VLAN device sends to its parent, and frames a bridge forwards, do not go through the qdisc
+//! of the lower device.
+
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    driver::INTR_IRQ_TX_SOFTIRQ,
+    error::{UtcpErr, UtcpResult},
+    ip::{IP_PROTOCOL_ICMP, IP_PROTOCOL_IGMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP},
+    ipv6::IPV6_NEXT_HEADER_ICMPV6,
+    net::{self, NET_PROTOCOL_TYPE_IP, NET_PROTOCOL_TYPE_IPV6, NetDeviceHandler},
+    net_device_get, net_device_get_mut,
+    platform::linux::intr::{self, INTR_TIMER_TICK},
+    utils::{BoundedQueue, DropPolicy, PushResult},
+};
+
+/// Packets a queue holds by default, as the transmit queue length of Linux devices.
+pub const QDISC_LIMIT_DEFAULT: usize = 1000;
+/// Bands of `PrioQdisc`. Band 0 is sent first.
+pub const QDISC_PRIO_BANDS: usize = 3;
+/// How often shaped qdiscs are retried, once they have tokens again.
+const QDISC_TIMER_INTERVAL: Duration = INTR_TIMER_TICK;
+
+/// A packet waiting to be sent: the arguments of `net_device_output`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QdiscPacket {
+    pub ty: u16,
+    pub data: Vec<u8>,
+    pub dst: Vec<u8>,
+}
+
+pub trait QdiscOps {
+    /// Queues a packet, possibly dropping it or another one.
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket>;
+    /// Returns the next packet to send at `now`, if any may be sent.
+    fn dequeue(&mut self, now: Instant) -> Option<QdiscPacket>;
+    /// Number of packets queued.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug)]
+pub enum Qdisc {
+    Fifo(FifoQdisc),
+    Prio(PrioQdisc),
+    Tbf(TbfQdisc),
+    Fq(FqQdisc),
+}
+
+impl QdiscOps for Qdisc {
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket> {
+        match self {
+            Qdisc::Fifo(qdisc) => qdisc.enqueue(packet),
+            Qdisc::Prio(qdisc) => qdisc.enqueue(packet),
+            Qdisc::Tbf(qdisc) => qdisc.enqueue(packet),
+            Qdisc::Fq(qdisc) => qdisc.enqueue(packet),
+        }
+    }
+
+    fn dequeue(&mut self, now: Instant) -> Option<QdiscPacket> {
+        match self {
+            Qdisc::Fifo(qdisc) => qdisc.dequeue(now),
+            Qdisc::Prio(qdisc) => qdisc.dequeue(now),
+            Qdisc::Tbf(qdisc) => qdisc.dequeue(now),
+            Qdisc::Fq(qdisc) => qdisc.dequeue(now),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Qdisc::Fifo(qdisc) => qdisc.len(),
+            Qdisc::Prio(qdisc) => qdisc.len(),
+            Qdisc::Tbf(qdisc) => qdisc.len(),
+            Qdisc::Fq(qdisc) => qdisc.len(),
+        }
+    }
+}
+
+/// Sends packets in the order they were queued.
+#[derive(Debug)]
+pub struct FifoQdisc {
+    queue: BoundedQueue<QdiscPacket>,
+}
+
+impl FifoQdisc {
+    pub fn new(limit: usize, policy: DropPolicy) -> Self {
+        Self {
+            queue: BoundedQueue::new(limit, policy),
+        }
+    }
+}
+
+impl QdiscOps for FifoQdisc {
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket> {
+        self.queue.push(packet)
+    }
+
+    fn dequeue(&mut self, _now: Instant) -> Option<QdiscPacket> {
+        self.queue.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Sends the packets of a band only when the bands before it are empty. Each band holds up to
+/// `limit` packets.
+#[derive(Debug)]
+pub struct PrioQdisc {
+    bands: Vec<BoundedQueue<QdiscPacket>>,
+}
+
+impl PrioQdisc {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            bands: (0..QDISC_PRIO_BANDS)
+                .map(|_| BoundedQueue::new(limit, DropPolicy::DropTail))
+                .collect(),
+        }
+    }
+}
+
+impl QdiscOps for PrioQdisc {
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket> {
+        self.bands[qdisc_prio_band(&packet)].push(packet)
+    }
+
+    fn dequeue(&mut self, _now: Instant) -> Option<QdiscPacket> {
+        self.bands.iter_mut().find_map(|band| band.pop_front())
+    }
+
+    fn len(&self) -> usize {
+        self.bands.iter().map(|band| band.len()).sum()
+    }
+}
+
+/// Differentiated services code points (RFC 4594).
+const DSCP_CS1: u8 = 8;
+const DSCP_EF: u8 = 46;
+const DSCP_CS6: u8 = 48;
+
+/// Returns the band of a packet by the DSCP of its IPv4 TOS or IPv6 traffic class: network
+/// control (CS6 and CS7) and expedited forwarding (EF) go to band 0, lower effort (CS1) to
+/// band 2, and the rest to band 1. Packets that are not IP, and ICMP, ICMPv6 and IGMP, are
+/// control traffic, in band 0.
+fn qdisc_prio_band(packet: &QdiscPacket) -> usize {
+    let data = &packet.data;
+    let (dscp, protocol) = match packet.ty {
+        NET_PROTOCOL_TYPE_IP if data.len() >= 20 => (data[1] >> 2, data[9]),
+        NET_PROTOCOL_TYPE_IPV6 if data.len() >= 40 => {
+            let class = (data[0] & 0x0f) << 4 | data[1] >> 4;
+            (class >> 2, data[6])
+        }
+        _ => return 0,
+    };
+    match (dscp, protocol) {
+        (_, IP_PROTOCOL_ICMP | IP_PROTOCOL_IGMP | IPV6_NEXT_HEADER_ICMPV6) => 0,
+        (DSCP_CS6.., _) | (DSCP_EF, _) => 0,
+        (DSCP_CS1, _) => 2,
+        _ => 1,
+    }
+}
+
+/// Token bucket filter: shapes the packets dequeued from `child` to `rate` bytes per second,
+/// with bursts of up to `burst` bytes. Packets larger than `burst` are dropped.
+#[derive(Debug)]
+pub struct TbfQdisc {
+    rate: u64,
+    burst: usize,
+    tokens: f64,
+    updated: Option<Instant>,
+    /// Dequeued from the child, waiting for tokens.
+    held: Option<QdiscPacket>,
+    child: Box<Qdisc>,
+}
+
+impl TbfQdisc {
+    pub fn new(rate: u64, burst: usize, child: Qdisc) -> UtcpResult<Self> {
+        if rate == 0 || burst == 0 {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "invalid rate or burst: rate={}, burst={}",
+                rate, burst
+            )));
+        }
+        Ok(Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            updated: None,
+            held: None,
+            child: Box::new(child),
+        })
+    }
+}
+
+impl QdiscOps for TbfQdisc {
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket> {
+        if packet.data.len() > self.burst {
+            return PushResult::Dropped(packet);
+        }
+        self.child.enqueue(packet)
+    }
+
+    fn dequeue(&mut self, now: Instant) -> Option<QdiscPacket> {
+        if let Some(updated) = self.updated {
+            let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        }
+        self.updated = Some(now);
+        let packet = match self.held.take() {
+            Some(packet) => packet,
+            None => self.child.dequeue(now)?,
+        };
+        if (packet.data.len() as f64) > self.tokens {
+            self.held = Some(packet);
+            return None;
+        }
+        self.tokens -= packet.data.len() as f64;
+        Some(packet)
+    }
+
+    fn len(&self) -> usize {
+        self.child.len() + self.held.is_some() as usize
+    }
+}
+
+#[derive(Debug)]
+struct FqFlow {
+    key: u64,
+    queue: VecDeque<QdiscPacket>,
+    /// Bytes the flow may still send in its round.
+    deficit: isize,
+}
+
+/// Fair queueing: packets are queued by flow, and the flows take turns sending `quantum`
+/// bytes (deficit round robin). When `limit` packets are queued, the longest flow loses its
+/// oldest packet.
+#[derive(Debug)]
+pub struct FqQdisc {
+    /// Flows with packets, in the order of their turns.
+    flows: VecDeque<FqFlow>,
+    limit: usize,
+    quantum: usize,
+    len: usize,
+}
+
+impl FqQdisc {
+    pub fn new(limit: usize, quantum: usize) -> UtcpResult<Self> {
+        if limit == 0 || quantum == 0 {
+            return Err(UtcpErr::InvalidArgument(format!(
+                "invalid limit or quantum: limit={}, quantum={}",
+                limit, quantum
+            )));
+        }
+        Ok(Self {
+            flows: VecDeque::new(),
+            limit,
+            quantum,
+            len: 0,
+        })
+    }
+}
+
+impl QdiscOps for FqQdisc {
+    fn enqueue(&mut self, packet: QdiscPacket) -> PushResult<QdiscPacket> {
+        let dropped = match self.len >= self.limit {
+            true => {
+                let longest = self
+                    .flows
+                    .iter_mut()
+                    .max_by_key(|flow| flow.queue.len())
+                    .unwrap();
+                self.len -= 1;
+                longest.queue.pop_front()
+            }
+            false => None,
+        };
+        self.flows.retain(|flow| !flow.queue.is_empty());
+        let key = qdisc_flow(&packet);
+        match self.flows.iter_mut().find(|flow| flow.key == key) {
+            Some(flow) => flow.queue.push_back(packet),
+            None => self.flows.push_back(FqFlow {
+                key,
+                queue: VecDeque::from([packet]),
+                deficit: self.quantum as isize,
+            }),
+        }
+        self.len += 1;
+        match dropped {
+            Some(dropped) => PushResult::DroppedHead(dropped),
+            None => PushResult::Queued,
+        }
+    }
+
+    fn dequeue(&mut self, _now: Instant) -> Option<QdiscPacket> {
+        loop {
+            let flow = self.flows.front_mut()?;
+            if flow.deficit <= 0 {
+                flow.deficit += self.quantum as isize;
+                self.flows.rotate_left(1);
+                continue;
+            }
+            let packet = flow.queue.pop_front().unwrap();
+            flow.deficit -= packet.data.len() as isize;
+            if flow.queue.is_empty() {
+                self.flows.pop_front();
+            }
+            self.len -= 1;
+            return Some(packet);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Returns the flow of a packet: the protocol, the addresses and, for TCP and UDP, the ports
+/// of IP packets, and the type of the others.
+fn qdisc_flow(packet: &QdiscPacket) -> u64 {
+    let data = &packet.data;
+    let mut hasher = DefaultHasher::new();
+    packet.ty.hash(&mut hasher);
+    let (protocol, addrs, payload) = match packet.ty {
+        NET_PROTOCOL_TYPE_IP if data.len() >= 20 => {
+            let header_len = ((data[0] & 0x0f) as usize * 4).clamp(20, data.len());
+            (data[9], &data[12..20], &data[header_len..])
+        }
+        NET_PROTOCOL_TYPE_IPV6 if data.len() >= 40 => (data[6], &data[8..40], &data[40..]),
+        _ => return hasher.finish(),
+    };
+    protocol.hash(&mut hasher);
+    addrs.hash(&mut hasher);
+    if matches!(protocol, IP_PROTOCOL_TCP | IP_PROTOCOL_UDP) && payload.len() >= 4 {
+        payload[..4].hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Counters of the qdisc of a device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QdiscStats {
+    /// Sent by the driver.
+    pub packets: u64,
+    pub bytes: u64,
+    /// Dropped by the qdisc, or dequeued and then not sent by the driver.
+    pub drops: u64,
+    /// Packets queued.
+    pub backlog: usize,
+}
+
+#[derive(Debug)]
+struct QdiscEntry {
+    dev: NetDeviceHandler,
+    qdisc: Qdisc,
+    stats: QdiscStats,
+}
+
+static QDISCS: Mutex<Vec<QdiscEntry>> = Mutex::new(Vec::new());
+
+/// Makes `dev` queue the packets it sends in `qdisc`, replacing its qdisc and dropping the
+/// packets queued in it.
+pub fn qdisc_attach(dev: NetDeviceHandler, qdisc: Qdisc) -> UtcpResult<()> {
+    let mut qdiscs = QDISCS.lock().unwrap();
+    log::info!("dev={}, qdisc={:?}", net_device_get!(dev).name(), qdisc);
+    qdiscs.retain(|entry| entry.dev != dev);
+    qdiscs.push(QdiscEntry {
+        dev,
+        qdisc,
+        stats: QdiscStats::default(),
+    });
+    Ok(())
+}
+
+/// Makes `dev` send packets right away again, dropping the ones queued.
+pub fn qdisc_detach(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let mut qdiscs = QDISCS.lock().unwrap();
+    let len = qdiscs.len();
+    qdiscs.retain(|entry| entry.dev != dev);
+    match qdiscs.len() == len {
+        true => Err(UtcpErr::InvalidArgument("no qdisc attached".into())),
+        false => Ok(()),
+    }
+}
+
+pub fn qdisc_stats(dev: NetDeviceHandler) -> Option<QdiscStats> {
+    let qdiscs = QDISCS.lock().unwrap();
+    let entry = qdiscs.iter().find(|entry| entry.dev == dev)?;
+    Some(QdiscStats {
+        backlog: entry.qdisc.len(),
+        ..entry.stats
+    })
+}
+
+/// Queues a packet in the qdisc of `dev`, if it has one, and raises the tx softirq. Fails if
+/// the packet was dropped.
+pub(crate) fn qdisc_enqueue(
+    dev: &NetDeviceHandler,
+    ty: u16,
+    data: &[u8],
+    dst: &[u8],
+) -> Option<UtcpResult<()>> {
+    let mut qdiscs = QDISCS.lock().unwrap();
+    let entry = qdiscs.iter_mut().find(|entry| entry.dev == *dev)?;
+    let packet = QdiscPacket {
+        ty,
+        data: data.to_vec(),
+        dst: dst.to_vec(),
+    };
+    let result = match entry.qdisc.enqueue(packet) {
+        PushResult::Queued => Ok(()),
+        PushResult::DroppedHead(_) => {
+            entry.stats.drops += 1;
+            Ok(())
+        }
+        PushResult::Dropped(_) => {
+            entry.stats.drops += 1;
+            log::debug!("dropped: dev={}, drops={}", dev.private, entry.stats.drops);
+            Err(UtcpErr::QueueFull)
+        }
+    };
+    drop(qdiscs);
+    if result.is_ok() {
+        // the packet is sent even if the signal is not, on the next tick of the timer
+        if let Err(e) = intr::intr_raise_irq(INTR_IRQ_TX_SOFTIRQ) {
+            log::warn!("{}", e);
+        }
+    }
+    Some(result)
+}
+
+/// Hands the packets the qdiscs let go to the drivers. The lock is not held while a driver
+/// sends, since sending may queue other packets.
+pub(crate) fn qdisc_run() {
+    let now = Instant::now();
+    let devs: Vec<NetDeviceHandler> = QDISCS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| !entry.qdisc.is_empty())
+        .map(|entry| entry.dev)
+        .collect();
+    for dev in devs {
+        loop {
+            let packet = {
+                let mut qdiscs = QDISCS.lock().unwrap();
+                let Some(entry) = qdiscs.iter_mut().find(|entry| entry.dev == dev) else {
+                    break;
+                };
+                let Some(packet) = entry.qdisc.dequeue(now) else {
+                    break;
+                };
+                packet
+            };
+            let QdiscPacket { ty, data, mut dst } = packet;
+            let device = net_device_get_mut!(dev);
+            let result = match device.is_up() {
+                true => device.transmit(ty, &data, &mut dst),
+                false => Err(UtcpErr::DeviceNotOpened(device.name().to_string())),
+            };
+            if let Err(e) = &result {
+                log::debug!("not sent: dev={}, {}", device.name(), e);
+            }
+            // the qdisc may have been detached meanwhile, which leaves nothing to count
+            let mut qdiscs = QDISCS.lock().unwrap();
+            if let Some(entry) = qdiscs.iter_mut().find(|entry| entry.dev == dev) {
+                match result {
+                    Ok(()) => {
+                        entry.stats.packets += 1;
+                        entry.stats.bytes += data.len() as u64;
+                    }
+                    Err(_) => entry.stats.drops += 1,
+                }
+            }
+        }
+    }
+}
+
+pub fn qdisc_init() -> UtcpResult<()> {
+    net::net_timer_register(QDISC_TIMER_INTERVAL, qdisc_run)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+#[cfg(test)]
+fn qdisc_test_packet(tos: u8, protocol: u8, src_port: u16, len: usize) -> QdiscPacket {
+    let mut data = vec![0u8; len.max(24)];
+    data[0] = 0x45;
+    data[1] = tos;
+    data[9] = protocol;
+    data[20..22].copy_from_slice(&src_port.to_be_bytes());
+    QdiscPacket {
+        ty: NET_PROTOCOL_TYPE_IP,
+        data,
+        dst: Vec::new(),
+    }
+}
+
+#[test]
+fn test_qdisc_prio() {
+    let now = Instant::now();
+    let mut qdisc = PrioQdisc::new(2);
+    let bulk = qdisc_test_packet(0, IP_PROTOCOL_UDP, 1, 100);
+    let low = qdisc_test_packet(DSCP_CS1 << 2, IP_PROTOCOL_UDP, 2, 100);
+    let ef = qdisc_test_packet(DSCP_EF << 2, IP_PROTOCOL_UDP, 3, 100);
+    let icmp = qdisc_test_packet(0, IP_PROTOCOL_ICMP, 4, 100);
+    for packet in [&low, &bulk, &ef, &icmp] {
+        assert_eq!(qdisc.enqueue(packet.clone()), PushResult::Queued);
+    }
+    assert!(matches!(qdisc.enqueue(ef.clone()), PushResult::Dropped(_)));
+    assert_eq!(qdisc.len(), 4);
+    let order: Vec<_> = std::iter::from_fn(|| qdisc.dequeue(now)).collect();
+    assert_eq!(order, [ef, icmp, bulk, low]);
+}
+
+#[test]
+fn test_qdisc_tbf() {
+    let start = Instant::now();
+    let fifo = Qdisc::Fifo(FifoQdisc::new(10, DropPolicy::DropTail));
+    // 1000 bytes per second, in bursts of 1500
+    let mut qdisc = TbfQdisc::new(1000, 1500, fifo).unwrap();
+    assert!(matches!(
+        qdisc.enqueue(qdisc_test_packet(0, IP_PROTOCOL_UDP, 1, 1501)),
+        PushResult::Dropped(_)
+    ));
+    for port in 0..3 {
+        qdisc.enqueue(qdisc_test_packet(0, IP_PROTOCOL_UDP, port, 1000));
+    }
+    assert!(qdisc.dequeue(start).is_some());
+    assert!(qdisc.dequeue(start).is_none());
+    assert_eq!(qdisc.len(), 2);
+    assert!(qdisc.dequeue(start + Duration::from_millis(400)).is_none());
+    assert!(qdisc.dequeue(start + Duration::from_millis(500)).is_some());
+    // the bucket never holds more than a burst
+    assert!(qdisc.dequeue(start + Duration::from_secs(10)).is_some());
+    assert!(qdisc.is_empty());
+    assert!(TbfQdisc::new(0, 1500, Qdisc::Prio(PrioQdisc::new(1))).is_err());
+}
+
+#[test]
+fn test_qdisc_fq() {
+    let now = Instant::now();
+    let mut qdisc = FqQdisc::new(8, 100).unwrap();
+    for _ in 0..6 {
+        qdisc.enqueue(qdisc_test_packet(0, IP_PROTOCOL_TCP, 1, 100));
+    }
+    for _ in 0..2 {
+        qdisc.enqueue(qdisc_test_packet(0, IP_PROTOCOL_TCP, 2, 100));
+    }
+    // the longest flow loses a packet
+    let dropped = qdisc.enqueue(qdisc_test_packet(0, IP_PROTOCOL_TCP, 2, 100));
+    assert!(matches!(dropped, PushResult::DroppedHead(packet) if packet.data[21] == 1));
+    assert_eq!(qdisc.len(), 8);
+    let ports: Vec<u8> = std::iter::from_fn(|| qdisc.dequeue(now))
+        .map(|packet| packet.data[21])
+        .collect();
+    assert_eq!(ports, [1, 2, 1, 2, 1, 2, 1, 1]);
+    assert!(FqQdisc::new(8, 0).is_err());
+}
@@ -0,0 +1,206 @@
+use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    task::Waker,
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    event::{self, SocketWakers},
+    ip::{self, IP_ADDR_ANY, IpAddress},
+    poll::PollEvents,
+    utils::{BoundedQueue, DropPolicy, PushResult},
+    wire::ipv4::Ipv4Packet,
+};
+
+/// Number of datagrams a raw socket can hold before new ones are dropped.
+pub const RAW_RECV_QUEUE_LIMIT: usize = 64;
+
+#[derive(Debug)]
+struct RawPcb {
+    protocol: u8,
+    /// Only datagrams sent to this address are received (any if `IP_ADDR_ANY`).
+    local: IpAddress,
+    /// Only datagrams sent from this address are received (any if `IP_ADDR_ANY`).
+    remote: IpAddress,
+    /// The caller supplies the IP header when sending (IP_HDRINCL).
+    hdrincl: bool,
+    nonblocking: bool,
+    /// Received datagrams including their IP header.
+    queue: BoundedQueue<Vec<u8>>,
+    /// Counts queued datagrams, for edge-triggered polling.
+    wakeups: u64,
+    wakers: SocketWakers,
+}
+
+impl RawPcb {
+    fn matches(&self, ip_hdr: &Ipv4Packet<&[u8]>) -> bool {
+        ip_hdr.protocol() == self.protocol
+            && (self.local == IP_ADDR_ANY || self.local == ip_hdr.dst())
+            && (self.remote == IP_ADDR_ANY || self.remote == ip_hdr.src())
+    }
+}
+
+static RAW_PCBS: Mutex<Vec<Option<RawPcb>>> = Mutex::new(Vec::new());
+/// Notified when a datagram is queued or a socket is closed.
+static RAW_COND: Condvar = Condvar::new();
+
+fn raw_pcb_get<'a>(
+    pcbs: &'a mut MutexGuard<'_, Vec<Option<RawPcb>>>,
+    id: usize,
+) -> UtcpResult<&'a mut RawPcb> {
+    pcbs.get_mut(id)
+        .and_then(|pcb| pcb.as_mut())
+        .ok_or(UtcpErr::BadDescriptor(id as i32))
+}
+
+/// Called by `ip_input` with every validated datagram addressed to this host.
+pub(crate) fn raw_input(ip_hdr: &Ipv4Packet<&[u8]>) {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    let mut delivered = false;
+    let mut woken = Vec::new();
+    for (id, pcb) in pcbs.iter_mut().enumerate() {
+        let Some(pcb) = pcb else { continue };
+        if !pcb.matches(ip_hdr) {
+            continue;
+        }
+        let datagram = ip_hdr.header().iter().chain(ip_hdr.payload());
+        match pcb.queue.push(datagram.copied().collect()) {
+            PushResult::Queued => {
+                pcb.wakeups += 1;
+                woken.extend(pcb.wakers.take(PollEvents::READABLE));
+                delivered = true;
+            }
+            _ => log::warn!("receive queue full, dropped a datagram: id={}", id),
+        }
+    }
+    drop(pcbs);
+    if delivered {
+        RAW_COND.notify_all();
+        event::event_notify(woken);
+    }
+}
+
+/// Opens a raw socket that receives and sends datagrams of the given protocol number.
+pub fn raw_open(protocol: u8) -> UtcpResult<usize> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    let pcb = RawPcb {
+        protocol,
+        local: IP_ADDR_ANY,
+        remote: IP_ADDR_ANY,
+        hdrincl: false,
+        nonblocking: false,
+        queue: BoundedQueue::new(RAW_RECV_QUEUE_LIMIT, DropPolicy::DropTail),
+        wakeups: 0,
+        wakers: SocketWakers::default(),
+    };
+    let id = match pcbs.iter().position(|pcb| pcb.is_none()) {
+        Some(id) => {
+            pcbs[id] = Some(pcb);
+            id
+        }
+        None => {
+            pcbs.push(Some(pcb));
+            pcbs.len() - 1
+        }
+    };
+    log::debug!("opened: id={}, protocol={}", id, protocol);
+    Ok(id)
+}
+
+pub fn raw_close(id: usize) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    let woken = raw_pcb_get(&mut pcbs, id)?.wakers.take(PollEvents::INVALID);
+    pcbs[id] = None;
+    drop(pcbs);
+    RAW_COND.notify_all();
+    event::event_notify(woken);
+    log::debug!("closed: id={}", id);
+    Ok(())
+}
+
+/// Receives only datagrams sent to `addr`. Sent datagrams use it as the source address.
+pub fn raw_bind(id: usize, addr: IpAddress) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    raw_pcb_get(&mut pcbs, id)?.local = addr;
+    Ok(())
+}
+
+/// Receives only datagrams sent from `addr`. It is also the destination of `raw_send`.
+pub fn raw_connect(id: usize, addr: IpAddress) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    raw_pcb_get(&mut pcbs, id)?.remote = addr;
+    Ok(())
+}
+
+/// When enabled, the data passed to `raw_sendto` starts with an IP header built by the caller.
+pub fn raw_set_hdrincl(id: usize, hdrincl: bool) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    raw_pcb_get(&mut pcbs, id)?.hdrincl = hdrincl;
+    Ok(())
+}
+
+pub fn raw_set_nonblocking(id: usize, nonblocking: bool) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    raw_pcb_get(&mut pcbs, id)?.nonblocking = nonblocking;
+    Ok(())
+}
+
+pub fn raw_sendto(id: usize, data: &[u8], dst: IpAddress) -> UtcpResult<usize> {
+    let (protocol, local, hdrincl) = {
+        let mut pcbs = RAW_PCBS.lock().unwrap();
+        let pcb = raw_pcb_get(&mut pcbs, id)?;
+        (pcb.protocol, pcb.local, pcb.hdrincl)
+    };
+    if hdrincl {
+        ip::ip_output_raw(data)
+    } else {
+        ip::ip_output(protocol, data, local, dst)
+    }
+}
+
+pub fn raw_send(id: usize, data: &[u8]) -> UtcpResult<usize> {
+    let remote = {
+        let mut pcbs = RAW_PCBS.lock().unwrap();
+        raw_pcb_get(&mut pcbs, id)?.remote
+    };
+    if remote == IP_ADDR_ANY {
+        return Err(UtcpErr::NotConnected);
+    }
+    raw_sendto(id, data, remote)
+}
+
+/// Receives a datagram including its IP header. The datagram is truncated if `buf` is too
+/// small. Returns the copied length and the source address.
+pub fn raw_recvfrom(id: usize, buf: &mut [u8]) -> UtcpResult<(usize, IpAddress)> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    let datagram = loop {
+        let pcb = raw_pcb_get(&mut pcbs, id)?;
+        if let Some(datagram) = pcb.queue.pop_front() {
+            break datagram;
+        }
+        if pcb.nonblocking {
+            return Err(UtcpErr::WouldBlock);
+        }
+        pcbs = RAW_COND.wait(pcbs).unwrap();
+    };
+    let src = Ipv4Packet::new_unchecked(&datagram[..]).src();
+    let len = datagram.len().min(buf.len());
+    buf[..len].copy_from_slice(&datagram[..len]);
+    Ok((len, src))
+}
+
+/// Returns the readiness of the socket and its wakeup counter, for `utcp_poll`.
+pub fn raw_poll(id: usize) -> UtcpResult<(PollEvents, u64)> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    let pcb = raw_pcb_get(&mut pcbs, id)?;
+    let mut events = PollEvents::WRITABLE;
+    events.set(PollEvents::READABLE, !pcb.queue.is_empty());
+    Ok((events, pcb.wakeups))
+}
+
+/// Wakes `waker` once the socket is ready for `interest` or closed.
+pub fn raw_register_waker(id: usize, interest: PollEvents, waker: &Waker) -> UtcpResult<()> {
+    let mut pcbs = RAW_PCBS.lock().unwrap();
+    raw_pcb_get(&mut pcbs, id)?.wakers.register(interest, waker);
+    Ok(())
+}
@@ -0,0 +1,564 @@
+//! IPv6 Stateless Address Autoconfiguration (RFC 4862) for Ethernet-class devices.
+//!
+//! `slaac_start` registers an IPv6 interface for a device and forms a link-local address from
+//! its hardware address, either as a modified EUI-64 or as a stable, semantically opaque
+//! identifier (RFC 7217). Every address is tentative until Duplicate Address Detection
+//! passes. Routers are then solicited, and the autonomous prefixes they advertise form
+//! global addresses that are deprecated and removed as their lifetimes run out. The first
+//! default router learned by `ndp` becomes the default route of the device.
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ipv6::{self, Ipv6AddrState, Ipv6Address, Ipv6Interface},
+    ndp::{self, NdpRouterAdvert},
+    net::{self, NetDeviceHandler},
+    net_device_get,
+    utils::{self, XorShift32},
+    wire::{ethernet::EthernetAddress, icmpv6::NdpPrefixInfo},
+};
+
+/// Length of the interface identifiers, and so of the prefixes addresses are formed from.
+const SLAAC_IID_LEN: u8 = 64;
+/// Upper bound of the random delay before Duplicate Address Detection starts (RFC 4862
+/// section 5.4.2, MAX_RTR_SOLICITATION_DELAY of RFC 4861).
+const SLAAC_DAD_DELAY_MAX: Duration = Duration::from_secs(1);
+/// New identifiers tried after duplicates with stable identifiers (RFC 7217 section 6).
+const SLAAC_IDGEN_RETRIES: u8 = 3;
+const SLAAC_ROUTER_SOLICITATIONS: u32 = 3;
+const SLAAC_ROUTER_SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+/// Advertised valid lifetimes shorter than this do not shorten the remaining lifetime of an
+/// address below it (RFC 4862 section 5.5.3 e).
+const SLAAC_VALID_LIFETIME_MIN: Duration = Duration::from_secs(2 * 60 * 60);
+const SLAAC_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the interface identifier of the addresses of a device is generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlaacIdentifier {
+    /// Modified EUI-64 of the hardware address (RFC 4291 appendix A). The same on every
+    /// network, so it lets the host be tracked.
+    Eui64,
+    /// Hashes the prefix, the hardware address and the number of duplicates found with
+    /// `secret` (RFC 7217). Stable on a network, different on others.
+    StablePrivacy { secret: [u8; 16] },
+}
+
+impl SlaacIdentifier {
+    /// Returns the address for `prefix`, `dad_counter` being the number of duplicates found
+    /// for it.
+    fn address(
+        &self,
+        prefix: Ipv6Address,
+        hwaddr: EthernetAddress,
+        dad_counter: u8,
+    ) -> Ipv6Address {
+        let mut bytes = *prefix.mask(SLAAC_IID_LEN).as_bytes();
+        match self {
+            SlaacIdentifier::Eui64 => {
+                let h = hwaddr.0;
+                bytes[8..].copy_from_slice(&[
+                    h[0] ^ 0x02,
+                    h[1],
+                    h[2],
+                    0xff,
+                    0xfe,
+                    h[3],
+                    h[4],
+                    h[5],
+                ]);
+            }
+            SlaacIdentifier::StablePrivacy { secret } => {
+                let mut counter = dad_counter;
+                loop {
+                    let mut data = Vec::with_capacity(15);
+                    data.extend_from_slice(&bytes[..8]);
+                    data.extend_from_slice(&hwaddr.0);
+                    data.push(counter);
+                    let iid = utils::siphash24(secret, &data).to_be_bytes();
+                    if !slaac_iid_reserved(&iid) {
+                        bytes[8..].copy_from_slice(&iid);
+                        break;
+                    }
+                    counter = counter.wrapping_add(1);
+                }
+            }
+        }
+        Ipv6Address::from_bytes(&bytes)
+    }
+}
+
+/// Returns true if `iid` is reserved (RFC 5453): the Subnet-Router anycast identifier, the
+/// reserved subnet anycast identifiers and the ISATAP ones.
+fn slaac_iid_reserved(iid: &[u8; 8]) -> bool {
+    iid == &[0; 8]
+        || (iid[..7] == [0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff] && iid[7] >= 0x80)
+        || iid[..4] == [0x02, 0x00, 0x5e, 0xfe]
+}
+
+/// Returns when an address advertised with a valid lifetime of `advertised` seconds expires,
+/// given when it expires now (RFC 4862 section 5.5.3 e). `None` is never.
+fn slaac_valid_until(now: Instant, current: Option<Instant>, advertised: u32) -> Option<Instant> {
+    let advertised = slaac_lifetime(now, advertised);
+    let remaining = current.map(|until| until.saturating_duration_since(now));
+    let min = now + SLAAC_VALID_LIFETIME_MIN;
+    match (advertised, remaining) {
+        (None, _) => None,
+        (Some(until), _) if until > min => Some(until),
+        (Some(until), Some(remaining)) if until > now + remaining => Some(until),
+        (Some(_), Some(remaining)) if remaining <= SLAAC_VALID_LIFETIME_MIN => current,
+        (Some(_), _) => Some(min),
+    }
+}
+
+/// Converts a lifetime in seconds to the instant it ends, `u32::MAX` being infinity.
+fn slaac_lifetime(now: Instant, secs: u32) -> Option<Instant> {
+    (secs != u32::MAX).then(|| now + Duration::from_secs(secs as u64))
+}
+
+/// Address formed by autoconfiguration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlaacAddress {
+    pub addr: Ipv6Address,
+    pub prefix_len: u8,
+    pub state: Ipv6AddrState,
+    /// When the address is deprecated, `None` if never.
+    pub preferred_until: Option<Instant>,
+    /// When the address is removed, `None` if never.
+    pub valid_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+enum SlaacDad {
+    /// Waiting for the random delay before the solicitation.
+    Delay,
+    /// The solicitation was sent, waiting for a node that uses the address.
+    Probing,
+    Done,
+}
+
+#[derive(Debug)]
+struct SlaacAddr {
+    info: SlaacAddress,
+    prefix: Ipv6Address,
+    dad: SlaacDad,
+    /// Next step of Duplicate Address Detection.
+    deadline: Instant,
+    dad_counter: u8,
+}
+
+#[derive(Debug)]
+struct SlaacClient {
+    dev: NetDeviceHandler,
+    hwaddr: EthernetAddress,
+    identifier: SlaacIdentifier,
+    rng: XorShift32,
+    addrs: Vec<SlaacAddr>,
+    solicitations: u32,
+    /// Next Router Solicitation. Set when the link-local address becomes usable.
+    solicit_at: Option<Instant>,
+    /// Gateway of the default route installed on the device.
+    router: Option<Ipv6Address>,
+    /// Whether the IPv6 interface was registered by `slaac_start`.
+    registered: bool,
+}
+
+static SLAAC_CLIENTS: Mutex<Vec<SlaacClient>> = Mutex::new(Vec::new());
+/// Notified when an address passes or fails Duplicate Address Detection.
+static SLAAC_COND: Condvar = Condvar::new();
+
+impl SlaacClient {
+    fn name(&self) -> &str {
+        net_device_get!(&self.dev).name()
+    }
+
+    fn dad_delay(&mut self) -> Duration {
+        SLAAC_DAD_DELAY_MAX.mul_f64(self.rng.next_f64())
+    }
+
+    /// Adds a tentative address for `prefix` and schedules its Duplicate Address Detection.
+    fn add(
+        &mut self,
+        prefix: Ipv6Address,
+        dad_counter: u8,
+        preferred_until: Option<Instant>,
+        valid_until: Option<Instant>,
+        now: Instant,
+    ) -> UtcpResult<()> {
+        let addr = self.identifier.address(prefix, self.hwaddr, dad_counter);
+        ipv6::ipv6_addr_add_state(self.dev, addr, SLAAC_IID_LEN, Ipv6AddrState::Tentative)?;
+        let deadline = now + self.dad_delay();
+        self.addrs.push(SlaacAddr {
+            info: SlaacAddress {
+                addr,
+                prefix_len: SLAAC_IID_LEN,
+                state: Ipv6AddrState::Tentative,
+                preferred_until,
+                valid_until,
+            },
+            prefix,
+            dad: SlaacDad::Delay,
+            deadline,
+            dad_counter,
+        });
+        Ok(())
+    }
+
+    fn remove_addr(&self, addr: Ipv6Address) {
+        if let Err(e) = ipv6::ipv6_addr_del(self.dev, addr) {
+            log::warn!("dev={}, addr={}, {}", self.name(), addr, e);
+        }
+    }
+
+    fn set_state(&self, entry: &mut SlaacAddr, state: Ipv6AddrState) {
+        entry.info.state = state;
+        if let Err(e) = ipv6::ipv6_addr_set_state(self.dev, entry.info.addr, state) {
+            log::warn!("dev={}, addr={}, {}", self.name(), entry.info.addr, e);
+        }
+    }
+
+    /// Steps Duplicate Address Detection and the lifetimes of the addresses.
+    fn addrs_timeout(&mut self, now: Instant) {
+        let mut retries = Vec::new();
+        let mut addrs = std::mem::take(&mut self.addrs);
+        addrs.retain_mut(|entry| match entry.dad {
+            SlaacDad::Delay if entry.deadline <= now => {
+                if let Err(e) = ndp::ndp_dad_start(self.dev, entry.info.addr) {
+                    log::error!("dev={}, {}", self.name(), e);
+                }
+                entry.dad = SlaacDad::Probing;
+                entry.deadline = now + ndp::ndp_retrans_timer(&self.dev);
+                true
+            }
+            SlaacDad::Probing if entry.deadline <= now => {
+                SLAAC_COND.notify_all();
+                if ndp::ndp_dad_finish(self.dev, entry.info.addr) {
+                    log::warn!("dev={}, duplicate: {}", self.name(), entry.info.addr);
+                    self.remove_addr(entry.info.addr);
+                    if matches!(self.identifier, SlaacIdentifier::StablePrivacy { .. })
+                        && entry.dad_counter < SLAAC_IDGEN_RETRIES
+                    {
+                        retries.push((
+                            entry.prefix,
+                            entry.dad_counter + 1,
+                            entry.info.preferred_until,
+                            entry.info.valid_until,
+                        ));
+                    }
+                    return false;
+                }
+                entry.dad = SlaacDad::Done;
+                let state = match entry.info.preferred_until {
+                    Some(until) if until <= now => Ipv6AddrState::Deprecated,
+                    _ => Ipv6AddrState::Preferred,
+                };
+                self.set_state(entry, state);
+                if entry.info.addr.is_link_local() && self.solicitations == 0 {
+                    self.solicit_at = Some(now);
+                }
+                true
+            }
+            SlaacDad::Delay | SlaacDad::Probing => true,
+            SlaacDad::Done => {
+                if entry.info.valid_until.is_some_and(|until| until <= now) {
+                    log::info!("dev={}, expired: {}", self.name(), entry.info.addr);
+                    self.remove_addr(entry.info.addr);
+                    return false;
+                }
+                if entry.info.state == Ipv6AddrState::Preferred
+                    && entry.info.preferred_until.is_some_and(|until| until <= now)
+                {
+                    self.set_state(entry, Ipv6AddrState::Deprecated);
+                }
+                true
+            }
+        });
+        self.addrs = addrs;
+        for (prefix, dad_counter, preferred_until, valid_until) in retries {
+            if let Err(e) = self.add(prefix, dad_counter, preferred_until, valid_until, now) {
+                log::error!("dev={}, {}", self.name(), e);
+            }
+        }
+    }
+
+    /// Solicits routers until one advertises, and follows the default router list.
+    fn routers_timeout(&mut self, now: Instant) {
+        let routers = ndp::ndp_default_routers(self.dev);
+        if let Some(at) = self.solicit_at
+            && at <= now
+        {
+            self.solicit_at = None;
+            if routers.is_empty() && self.solicitations < SLAAC_ROUTER_SOLICITATIONS {
+                if let Err(e) = ndp::ndp_router_solicit(self.dev) {
+                    log::error!("dev={}, {}", self.name(), e);
+                }
+                self.solicitations += 1;
+                self.solicit_at = Some(now + SLAAC_ROUTER_SOLICITATION_INTERVAL);
+            }
+        }
+        let router = routers.first().copied();
+        if router == self.router {
+            return;
+        }
+        let result = match router {
+            Some(router) => ipv6::ipv6_route_set_default_gateway(self.dev, router),
+            None => ipv6::ipv6_route_del(self.dev, Ipv6Address::UNSPECIFIED, 0),
+        };
+        if let Err(e) = result {
+            log::error!("dev={}, {}", self.name(), e);
+        }
+        log::info!("dev={}, default router: {:?}", self.name(), router);
+        self.router = router;
+    }
+
+    /// Forms or updates the address of an advertised prefix (RFC 4862 section 5.5.3).
+    fn prefix_input(&mut self, info: &NdpPrefixInfo, now: Instant) {
+        if !info.autonomous || info.prefix.is_link_local() {
+            return;
+        }
+        if info.preferred_lifetime > info.valid_lifetime {
+            log::debug!(
+                "dev={}, preferred lifetime > valid: {:?}",
+                self.name(),
+                info
+            );
+            return;
+        }
+        if info.prefix_len != SLAAC_IID_LEN {
+            log::warn!(
+                "dev={}, prefix length is not {}: {}/{}",
+                self.name(),
+                SLAAC_IID_LEN,
+                info.prefix,
+                info.prefix_len
+            );
+            return;
+        }
+        let prefix = info.prefix.mask(SLAAC_IID_LEN);
+        let preferred_until = slaac_lifetime(now, info.preferred_lifetime);
+        if let Some(entry) = self.addrs.iter_mut().find(|entry| entry.prefix == prefix) {
+            entry.info.preferred_until = preferred_until;
+            entry.info.valid_until =
+                slaac_valid_until(now, entry.info.valid_until, info.valid_lifetime);
+            if entry.info.state == Ipv6AddrState::Deprecated && info.preferred_lifetime != 0 {
+                let addr = entry.info.addr;
+                entry.info.state = Ipv6AddrState::Preferred;
+                if let Err(e) = ipv6::ipv6_addr_set_state(self.dev, addr, Ipv6AddrState::Preferred)
+                {
+                    log::warn!("dev={}, addr={}, {}", self.name(), addr, e);
+                }
+            }
+            return;
+        }
+        if info.valid_lifetime == 0 {
+            return;
+        }
+        let valid_until = slaac_lifetime(now, info.valid_lifetime);
+        if let Err(e) = self.add(prefix, 0, preferred_until, valid_until, now) {
+            log::error!("dev={}, prefix={}, {}", self.name(), prefix, e);
+        }
+    }
+}
+
+/// Forms addresses from the prefixes of a Router Advertisement received on `dev`.
+pub(crate) fn slaac_router_advert(dev: NetDeviceHandler, advert: &NdpRouterAdvert) {
+    let mut clients = SLAAC_CLIENTS.lock().unwrap();
+    let Some(client) = clients
+        .iter_mut()
+        .find(|client| client.dev.private == dev.private)
+    else {
+        return;
+    };
+    let now = Instant::now();
+    for info in &advert.prefixes {
+        client.prefix_input(info, now);
+    }
+}
+
+fn slaac_timer() {
+    let now = Instant::now();
+    let mut clients = SLAAC_CLIENTS.lock().unwrap();
+    for client in clients.iter_mut() {
+        client.addrs_timeout(now);
+        client.routers_timeout(now);
+    }
+}
+
+/// Starts autoconfiguring `dev`, which must have a hardware address. An IPv6 interface is
+/// registered unless the device has one. Use `slaac_wait` to wait for a global address.
+pub fn slaac_start(dev: NetDeviceHandler, identifier: SlaacIdentifier) -> UtcpResult<()> {
+    let hwaddr = net_device_get!(&dev)
+        .hw_addr()
+        .ok_or(UtcpErr::NotSupported(
+            "autoconfiguration on a device without address".into(),
+        ))?;
+    // the DAD delays of hosts that start together must not follow from their addresses
+    let mut seed = [0u8; 4];
+    utils::getrandom(&mut seed)?;
+    let mut clients = SLAAC_CLIENTS.lock().unwrap();
+    if clients
+        .iter()
+        .any(|client| client.dev.private == dev.private)
+    {
+        return Err(UtcpErr::InvalidArgument(
+            "autoconfiguration already running".into(),
+        ));
+    }
+    let registered = ipv6::ipv6_iface_of_dev(&dev).is_none();
+    if registered {
+        ipv6::ipv6_iface_register(dev, Ipv6Interface::new())?;
+    }
+    let now = Instant::now();
+    let mut client = SlaacClient {
+        dev,
+        hwaddr,
+        identifier,
+        rng: XorShift32::new(u32::from_ne_bytes(seed) | 1),
+        addrs: Vec::new(),
+        solicitations: 0,
+        solicit_at: None,
+        router: None,
+        registered,
+    };
+    let link_local = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+    if let Err(e) = client.add(link_local, 0, None, None, now) {
+        if registered {
+            ipv6::ipv6_iface_unregister(dev)?;
+        }
+        return Err(e);
+    }
+    log::info!("dev={}, started", client.name());
+    clients.push(client);
+    Ok(())
+}
+
+/// Stops autoconfiguring `dev`, removing the addresses and the default route it configured,
+/// and the interface if `slaac_start` registered it.
+pub fn slaac_stop(dev: NetDeviceHandler) -> UtcpResult<()> {
+    let mut clients = SLAAC_CLIENTS.lock().unwrap();
+    let index = clients
+        .iter()
+        .position(|client| client.dev.private == dev.private)
+        .ok_or(UtcpErr::InvalidArgument(
+            "autoconfiguration not running".into(),
+        ))?;
+    let client = clients.remove(index);
+    if client.registered {
+        ipv6::ipv6_iface_unregister(dev)?;
+    } else {
+        for entry in &client.addrs {
+            ndp::ndp_dad_finish(dev, entry.info.addr);
+            client.remove_addr(entry.info.addr);
+        }
+        if client.router.is_some() {
+            ipv6::ipv6_route_del(dev, Ipv6Address::UNSPECIFIED, 0)?;
+        }
+    }
+    SLAAC_COND.notify_all();
+    log::info!("dev={}, stopped", client.name());
+    Ok(())
+}
+
+/// Returns the addresses formed for `dev`, the link-local one first.
+pub fn slaac_addresses(dev: NetDeviceHandler) -> Vec<SlaacAddress> {
+    let clients = SLAAC_CLIENTS.lock().unwrap();
+    clients
+        .iter()
+        .find(|client| client.dev.private == dev.private)
+        .map(|client| client.addrs.iter().map(|entry| entry.info).collect())
+        .unwrap_or_default()
+}
+
+/// Waits until `dev` has a usable global address and no tentative one, for at most `timeout`.
+/// Returns the global addresses.
+pub fn slaac_wait(dev: NetDeviceHandler, timeout: Duration) -> UtcpResult<Vec<SlaacAddress>> {
+    let deadline = Instant::now() + timeout;
+    let mut clients = SLAAC_CLIENTS.lock().unwrap();
+    loop {
+        let Some(client) = clients
+            .iter()
+            .find(|client| client.dev.private == dev.private)
+        else {
+            return Err(UtcpErr::InvalidArgument(
+                "autoconfiguration not running".into(),
+            ));
+        };
+        if client
+            .addrs
+            .iter()
+            .all(|entry| entry.info.state != Ipv6AddrState::Tentative)
+        {
+            let global: Vec<_> = client
+                .addrs
+                .iter()
+                .filter(|entry| !entry.info.addr.is_link_local())
+                .map(|entry| entry.info)
+                .collect();
+            if !global.is_empty() {
+                return Ok(global);
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UtcpErr::TimedOut);
+        }
+        clients = SLAAC_COND.wait_timeout(clients, deadline - now).unwrap().0;
+    }
+}
+
+pub fn slaac_init() -> UtcpResult<()> {
+    net::net_timer_register(SLAAC_TIMER_INTERVAL, slaac_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+#[test]
+fn test_slaac_identifier() {
+    let hwaddr = EthernetAddress([0x02, 0x00, 0x00, 0x99, 0x00, 0x12]);
+    let link_local = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+    assert_eq!(
+        SlaacIdentifier::Eui64.address(link_local, hwaddr, 0),
+        "fe80::ff:fe99:12".parse().unwrap()
+    );
+
+    let stable = SlaacIdentifier::StablePrivacy { secret: [7; 16] };
+    let prefix: Ipv6Address = "2001:db8:0:1::".parse().unwrap();
+    let addr = stable.address(prefix, hwaddr, 0);
+    assert_eq!(addr.mask(64), prefix);
+    assert_eq!(stable.address(prefix, hwaddr, 0), addr);
+    assert_ne!(stable.address(prefix, hwaddr, 1), addr);
+    let other: Ipv6Address = "2001:db8:0:2::".parse().unwrap();
+    assert_ne!(
+        stable.address(other, hwaddr, 0).as_bytes()[8..],
+        addr.as_bytes()[8..]
+    );
+    assert!(slaac_iid_reserved(&[
+        0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe
+    ]));
+    assert!(!slaac_iid_reserved(&[
+        0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f
+    ]));
+}
+
+#[test]
+fn test_slaac_valid_until() {
+    let now = Instant::now();
+    let hours = |h: u64| now + Duration::from_secs(h * 60 * 60);
+    // longer than two hours, or than the remaining lifetime, is taken
+    assert_eq!(
+        slaac_valid_until(now, Some(hours(1)), 3 * 3600),
+        Some(hours(3))
+    );
+    assert_eq!(
+        slaac_valid_until(now, Some(hours(1)), 90 * 60),
+        Some(now + Duration::from_secs(90 * 60))
+    );
+    assert_eq!(slaac_valid_until(now, Some(hours(1)), u32::MAX), None);
+    // a shorter one does not cut the remaining lifetime below two hours
+    assert_eq!(slaac_valid_until(now, Some(hours(1)), 60), Some(hours(1)));
+    assert_eq!(slaac_valid_until(now, Some(hours(5)), 60), Some(hours(2)));
+    assert_eq!(slaac_valid_until(now, None, 0), Some(hours(2)));
+}
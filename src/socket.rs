@@ -0,0 +1,417 @@
+//! BSD-style socket API with integer descriptors over the UDP, TCP and raw IP PCBs.
+
+use std::{sync::Mutex, task::Waker, time::Duration};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::{IP_ADDR_ANY, IpAddress, IpEndpoint},
+    poll::PollEvents,
+    raw, tcp, udp,
+    wire::ipv4::Ipv4Packet,
+};
+
+pub const AF_INET: i32 = libc::AF_INET;
+pub const AF_INET6: i32 = libc::AF_INET6;
+
+pub const SOCK_STREAM: i32 = libc::SOCK_STREAM;
+pub const SOCK_DGRAM: i32 = libc::SOCK_DGRAM;
+pub const SOCK_RAW: i32 = libc::SOCK_RAW;
+/// May be or-ed into the type passed to `socket`.
+pub const SOCK_NONBLOCK: i32 = libc::SOCK_NONBLOCK;
+
+pub const SHUT_RD: i32 = libc::SHUT_RD;
+pub const SHUT_WR: i32 = libc::SHUT_WR;
+pub const SHUT_RDWR: i32 = libc::SHUT_RDWR;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Udp(usize),
+    Tcp(usize),
+    Raw(usize),
+}
+
+#[derive(Debug)]
+struct Socket {
+    kind: SocketKind,
+    /// `AF_INET` or `AF_INET6`, the family of the addresses the socket takes and returns.
+    domain: i32,
+    nonblocking: bool,
+}
+
+static SOCKETS: Mutex<Vec<Option<Socket>>> = Mutex::new(Vec::new());
+
+fn socket_alloc(sock: Socket) -> i32 {
+    let mut sockets = SOCKETS.lock().unwrap();
+    let fd = match sockets.iter().position(|sock| sock.is_none()) {
+        Some(fd) => {
+            sockets[fd] = Some(sock);
+            fd
+        }
+        None => {
+            sockets.push(Some(sock));
+            sockets.len() - 1
+        }
+    };
+    fd as i32
+}
+
+/// Returns the PCB behind a descriptor.
+pub fn socket_kind(fd: i32) -> UtcpResult<SocketKind> {
+    socket_get(fd, |sock| sock.kind)
+}
+
+/// Returns the address family of a descriptor.
+pub fn socket_domain(fd: i32) -> UtcpResult<i32> {
+    socket_get(fd, |sock| sock.domain)
+}
+
+fn socket_get<T>(fd: i32, f: impl FnOnce(&Socket) -> T) -> UtcpResult<T> {
+    let sockets = SOCKETS.lock().unwrap();
+    usize::try_from(fd)
+        .ok()
+        .and_then(|fd| sockets.get(fd))
+        .and_then(|sock| sock.as_ref())
+        .map(f)
+        .ok_or(UtcpErr::BadDescriptor(fd))
+}
+
+/// Converts `addr` to the family of the socket. `AF_INET6` sockets take IPv4 addresses as
+/// IPv4-mapped ones, and `AF_INET` sockets do not take IPv6 addresses.
+fn socket_endpoint(fd: i32, addr: IpEndpoint) -> UtcpResult<IpEndpoint> {
+    match socket_domain(fd)? {
+        AF_INET6 => Ok(IpEndpoint::new(addr.addr.to_ipv6_mapped(), addr.port)),
+        _ if addr.is_ipv6() => Err(UtcpErr::AddrFamilyNotSupported),
+        _ => Ok(addr),
+    }
+}
+
+/// Returns the IPv4 address raw sockets take.
+fn raw_addr(addr: IpEndpoint) -> UtcpResult<IpAddress> {
+    addr.addr.to_ipv4().ok_or(UtcpErr::AddrFamilyNotSupported)
+}
+
+pub fn socket(domain: i32, ty: i32, protocol: i32) -> UtcpResult<i32> {
+    if domain != AF_INET && domain != AF_INET6 {
+        return Err(UtcpErr::NotSupported(format!("domain {}", domain)));
+    }
+    let nonblocking = ty & SOCK_NONBLOCK != 0;
+    let kind = match ty & !SOCK_NONBLOCK {
+        SOCK_STREAM if protocol == 0 || protocol == libc::IPPROTO_TCP => match domain {
+            AF_INET6 => SocketKind::Tcp(tcp::tcp_open_ipv6()?),
+            _ => SocketKind::Tcp(tcp::tcp_open()?),
+        },
+        SOCK_DGRAM if protocol == 0 || protocol == libc::IPPROTO_UDP => match domain {
+            AF_INET6 => SocketKind::Udp(udp::udp_open_ipv6()?),
+            _ => SocketKind::Udp(udp::udp_open()?),
+        },
+        SOCK_RAW if domain == AF_INET6 => {
+            return Err(UtcpErr::NotSupported("raw IPv6 sockets".into()));
+        }
+        SOCK_RAW => {
+            let protocol = u8::try_from(protocol)
+                .map_err(|_| UtcpErr::InvalidArgument(format!("protocol {}", protocol)))?;
+            SocketKind::Raw(raw::raw_open(protocol)?)
+        }
+        ty => {
+            return Err(UtcpErr::NotSupported(format!(
+                "type {} protocol {}",
+                ty, protocol
+            )));
+        }
+    };
+    let fd = socket_alloc(Socket {
+        kind,
+        domain,
+        nonblocking: false,
+    });
+    if nonblocking {
+        set_nonblocking(fd, true)?;
+    }
+    log::debug!("fd={}, kind={:?}", fd, kind);
+    Ok(fd)
+}
+
+pub fn close(fd: i32) -> UtcpResult<()> {
+    // taken out under one lock, so that the PCB is closed once and a socket that gets the
+    // descriptor next is not freed instead
+    let sock = usize::try_from(fd)
+        .ok()
+        .and_then(|fd| SOCKETS.lock().unwrap().get_mut(fd).and_then(Option::take))
+        .ok_or(UtcpErr::BadDescriptor(fd))?;
+    match sock.kind {
+        SocketKind::Udp(id) => udp::udp_close(id),
+        SocketKind::Tcp(id) => tcp::tcp_close(id),
+        SocketKind::Raw(id) => raw::raw_close(id),
+    }
+}
+
+pub fn bind(fd: i32, addr: IpEndpoint) -> UtcpResult<()> {
+    let addr = socket_endpoint(fd, addr)?;
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_bind(id, addr).map(|_| ()),
+        SocketKind::Tcp(id) => tcp::tcp_bind(id, addr).map(|_| ()),
+        SocketKind::Raw(id) => raw::raw_bind(id, raw_addr(addr)?),
+    }
+}
+
+pub fn connect(fd: i32, addr: IpEndpoint) -> UtcpResult<()> {
+    let addr = socket_endpoint(fd, addr)?;
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_connect(id, addr),
+        SocketKind::Tcp(id) => tcp::tcp_connect(id, addr),
+        SocketKind::Raw(id) => raw::raw_connect(id, raw_addr(addr)?),
+    }
+}
+
+pub fn listen(fd: i32, backlog: i32) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_listen(id, backlog.max(0) as usize),
+        _ => Err(UtcpErr::NotSupported("listen".into())),
+    }
+}
+
+pub fn accept(fd: i32) -> UtcpResult<(i32, IpEndpoint)> {
+    let SocketKind::Tcp(id) = socket_kind(fd)? else {
+        return Err(UtcpErr::NotSupported("accept".into()));
+    };
+    let domain = socket_domain(fd)?;
+    let (child, foreign) = tcp::tcp_accept(id)?;
+    let fd = socket_alloc(Socket {
+        kind: SocketKind::Tcp(child),
+        domain,
+        nonblocking: false,
+    });
+    Ok((fd, foreign))
+}
+
+pub fn send(fd: i32, data: &[u8]) -> UtcpResult<usize> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_send(id, data),
+        SocketKind::Tcp(id) => tcp::tcp_send(id, data),
+        SocketKind::Raw(id) => raw::raw_send(id, data),
+    }
+}
+
+pub fn recv(fd: i32, buf: &mut [u8]) -> UtcpResult<usize> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_receive(id, buf),
+        _ => recvfrom(fd, buf).map(|(len, _)| len),
+    }
+}
+
+pub fn sendto(fd: i32, data: &[u8], addr: IpEndpoint) -> UtcpResult<usize> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_sendto(id, data, socket_endpoint(fd, addr)?),
+        // the destination of a connected stream can not be changed
+        SocketKind::Tcp(id) => tcp::tcp_send(id, data),
+        SocketKind::Raw(id) => raw::raw_sendto(id, data, raw_addr(addr)?),
+    }
+}
+
+/// Receives a message. Raw sockets return the IP header as well, and port 0 as the source.
+pub fn recvfrom(fd: i32, buf: &mut [u8]) -> UtcpResult<(usize, IpEndpoint)> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_recvfrom(id, buf),
+        SocketKind::Tcp(id) => {
+            let foreign = tcp::tcp_foreign(id)?.ok_or(UtcpErr::NotConnected)?;
+            Ok((tcp::tcp_receive(id, buf)?, foreign))
+        }
+        SocketKind::Raw(id) => {
+            let (len, src) = raw::raw_recvfrom(id, buf)?;
+            Ok((len, IpEndpoint::new(src, 0)))
+        }
+    }
+}
+
+pub fn shutdown(fd: i32, how: i32) -> UtcpResult<()> {
+    let (read, write) = match how {
+        SHUT_RD => (true, false),
+        SHUT_WR => (false, true),
+        SHUT_RDWR => (true, true),
+        _ => return Err(UtcpErr::InvalidArgument(format!("how {}", how))),
+    };
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_shutdown(id, read, write),
+        _ => Err(UtcpErr::NotConnected),
+    }
+}
+
+pub fn getsockname(fd: i32) -> UtcpResult<IpEndpoint> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_local(id),
+        SocketKind::Tcp(id) => tcp::tcp_local(id),
+        SocketKind::Raw(_) => Ok(IpEndpoint::new(IP_ADDR_ANY, 0)),
+    }
+}
+
+pub fn getpeername(fd: i32) -> UtcpResult<IpEndpoint> {
+    let foreign = match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_foreign(id)?,
+        SocketKind::Tcp(id) => tcp::tcp_foreign(id)?,
+        SocketKind::Raw(_) => None,
+    };
+    foreign.ok_or(UtcpErr::NotConnected)
+}
+
+/// Returns the readiness of the socket and a counter that changes whenever input or timers
+/// touched it.
+pub fn poll_events(fd: i32) -> UtcpResult<(PollEvents, u64)> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_poll(id),
+        SocketKind::Tcp(id) => tcp::tcp_poll(id),
+        SocketKind::Raw(id) => raw::raw_poll(id),
+    }
+}
+
+/// Wakes `waker` once, when the socket becomes ready for `interest` (`READABLE` and/or
+/// `WRITABLE`), fails or is closed.
+pub fn register_waker(fd: i32, interest: PollEvents, waker: &Waker) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_register_waker(id, interest, waker),
+        SocketKind::Tcp(id) => tcp::tcp_register_waker(id, interest, waker),
+        SocketKind::Raw(id) => raw::raw_register_waker(id, interest, waker),
+    }
+}
+
+/// Equivalent of `SO_ERROR`.
+pub fn take_error(fd: i32) -> UtcpResult<Option<UtcpErr>> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_take_error(id),
+        _ => Ok(None),
+    }
+}
+
+/// Equivalent of `fcntl(fd, F_SETFL, O_NONBLOCK)`.
+pub fn set_nonblocking(fd: i32, nonblocking: bool) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_set_nonblocking(id, nonblocking)?,
+        SocketKind::Tcp(id) => tcp::tcp_set_nonblocking(id, nonblocking)?,
+        SocketKind::Raw(id) => raw::raw_set_nonblocking(id, nonblocking)?,
+    }
+    if let Some(sock) = SOCKETS.lock().unwrap()[fd as usize].as_mut() {
+        sock.nonblocking = nonblocking;
+    }
+    Ok(())
+}
+
+pub fn is_nonblocking(fd: i32) -> UtcpResult<bool> {
+    socket_kind(fd)?;
+    let sockets = SOCKETS.lock().unwrap();
+    Ok(sockets[fd as usize]
+        .as_ref()
+        .is_some_and(|sock| sock.nonblocking))
+}
+
+/// Equivalent of `SO_RCVTIMEO`. Timed out calls fail with `WouldBlock`.
+pub fn set_recv_timeout(fd: i32, timeout: Option<Duration>) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_set_recv_timeout(id, timeout),
+        SocketKind::Tcp(id) => tcp::tcp_set_recv_timeout(id, timeout),
+        SocketKind::Raw(_) => Err(UtcpErr::NotSupported("SO_RCVTIMEO".into())),
+    }
+}
+
+pub fn recv_timeout(fd: i32) -> UtcpResult<Option<Duration>> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_recv_timeout(id),
+        SocketKind::Tcp(id) => tcp::tcp_recv_timeout(id),
+        SocketKind::Raw(_) => Ok(None),
+    }
+}
+
+/// Equivalent of `SO_SNDTIMEO`.
+pub fn set_send_timeout(fd: i32, timeout: Option<Duration>) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_set_send_timeout(id, timeout),
+        _ => Err(UtcpErr::NotSupported("SO_SNDTIMEO".into())),
+    }
+}
+
+pub fn send_timeout(fd: i32) -> UtcpResult<Option<Duration>> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_send_timeout(id),
+        _ => Ok(None),
+    }
+}
+
+/// Equivalent of `IP_HDRINCL`.
+pub fn set_hdrincl(fd: i32, hdrincl: bool) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Raw(id) => raw::raw_set_hdrincl(id, hdrincl),
+        _ => Err(UtcpErr::NotSupported("IP_HDRINCL".into())),
+    }
+}
+
+/// Equivalent of `TCP_NODELAY`.
+pub fn set_nodelay(fd: i32, nodelay: bool) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_set_nodelay(id, nodelay),
+        _ => Err(UtcpErr::NotSupported("TCP_NODELAY".into())),
+    }
+}
+
+pub fn nodelay(fd: i32) -> UtcpResult<bool> {
+    match socket_kind(fd)? {
+        SocketKind::Tcp(id) => tcp::tcp_nodelay(id),
+        _ => Err(UtcpErr::NotSupported("TCP_NODELAY".into())),
+    }
+}
+
+/// Equivalent of `SO_REUSEADDR`. TCP ports are reused after close anyway, so it only changes
+/// UDP sockets, which can then share a port.
+pub fn set_reuse_addr(fd: i32, reuse_addr: bool) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_set_reuse_addr(id, reuse_addr),
+        SocketKind::Tcp(_) => Ok(()),
+        SocketKind::Raw(_) => Err(UtcpErr::NotSupported("SO_REUSEADDR".into())),
+    }
+}
+
+pub fn reuse_addr(fd: i32) -> UtcpResult<bool> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_reuse_addr(id),
+        _ => Ok(false),
+    }
+}
+
+/// Equivalent of `IPV6_V6ONLY`. An `AF_INET6` socket bound to :: also sends and receives IPv4,
+/// seen as IPv4-mapped addresses, unless set. It must be set before binding.
+pub fn set_v6only(fd: i32, v6only: bool) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_set_v6only(id, v6only),
+        SocketKind::Tcp(id) => tcp::tcp_set_v6only(id, v6only),
+        SocketKind::Raw(_) => Err(UtcpErr::NotSupported("IPV6_V6ONLY".into())),
+    }
+}
+
+pub fn v6only(fd: i32) -> UtcpResult<bool> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_v6only(id),
+        SocketKind::Tcp(id) => tcp::tcp_v6only(id),
+        SocketKind::Raw(_) => Err(UtcpErr::NotSupported("IPV6_V6ONLY".into())),
+    }
+}
+
+/// Equivalent of `IP_ADD_MEMBERSHIP`. `iface` is the address of the interface to join on, or
+/// `IP_ADDR_ANY`.
+pub fn join_multicast(fd: i32, group: IpAddress, iface: IpAddress) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_join_multicast(id, group, iface),
+        _ => Err(UtcpErr::NotSupported("IP_ADD_MEMBERSHIP".into())),
+    }
+}
+
+/// Equivalent of `IP_DROP_MEMBERSHIP`.
+pub fn leave_multicast(fd: i32, group: IpAddress, iface: IpAddress) -> UtcpResult<()> {
+    match socket_kind(fd)? {
+        SocketKind::Udp(id) => udp::udp_leave_multicast(id, group, iface),
+        _ => Err(UtcpErr::NotSupported("IP_DROP_MEMBERSHIP".into())),
+    }
+}
+
+/// Returns the payload of a datagram received on a raw socket.
+pub fn raw_payload(datagram: &[u8]) -> UtcpResult<&[u8]> {
+    let packet = Ipv4Packet::new_checked(datagram)?;
+    let header_len = packet.header_len();
+    Ok(&datagram[header_len..packet.total() as usize])
+}
@@ -0,0 +1,312 @@
+//! Socket types mirroring `std::net`, so that code can switch between the kernel stack and
+//! utcp with a type alias.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    ip::IpEndpoint,
+    socket::{self, AF_INET, AF_INET6, SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_DGRAM, SOCK_STREAM},
+};
+
+/// Default backlog of `TcpListener::bind`, the same as the one of `std::net`.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Tries every address until `f` succeeds, like `std::net` does.
+fn each_addr<A: ToSocketAddrs, T>(
+    addr: A,
+    mut f: impl FnMut(IpEndpoint) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match f(IpEndpoint::from(addr)) {
+            Ok(value) => return Ok(value),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+pub(crate) fn to_socket_addr(ep: IpEndpoint) -> SocketAddr {
+    SocketAddr::from(ep)
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(())
+}
+
+/// Owns a socket descriptor and closes it when dropped.
+#[derive(Debug)]
+pub(crate) struct Fd(pub(crate) i32);
+
+impl Fd {
+    /// Opens a socket of the family of `ep`.
+    pub(crate) fn open(ep: &IpEndpoint, ty: i32) -> io::Result<Self> {
+        let domain = if ep.is_ipv6() { AF_INET6 } else { AF_INET };
+        Ok(Fd(socket::socket(domain, ty, 0)?))
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        if let Err(e) = socket::close(self.0) {
+            log::warn!("failed to close fd={}: {}", self.0, e);
+        }
+    }
+}
+
+/// TCP connection over utcp, like `std::net::TcpStream`.
+#[derive(Debug)]
+pub struct TcpStream {
+    fd: Fd,
+}
+
+impl TcpStream {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        each_addr(addr, |ep| {
+            let fd = Fd::open(&ep, SOCK_STREAM)?;
+            socket::connect(fd.0, ep)?;
+            Ok(TcpStream { fd })
+        })
+    }
+
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        let ep = IpEndpoint::from(*addr);
+        let fd = Fd::open(&ep, SOCK_STREAM)?;
+        // the handshake waits as long as a blocked send would
+        socket::set_send_timeout(fd.0, Some(timeout))?;
+        socket::connect(fd.0, ep)?;
+        socket::set_send_timeout(fd.0, None)?;
+        Ok(TcpStream { fd })
+    }
+
+    /// Returns the descriptor of the socket layer.
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getpeername(self.fd.0)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let how = match how {
+            Shutdown::Read => SHUT_RD,
+            Shutdown::Write => SHUT_WR,
+            Shutdown::Both => SHUT_RDWR,
+        };
+        Ok(socket::shutdown(self.fd.0, how)?)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        Ok(socket::set_recv_timeout(self.fd.0, timeout)?)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        Ok(socket::set_send_timeout(self.fd.0, timeout)?)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(socket::recv_timeout(self.fd.0)?)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(socket::send_timeout(self.fd.0)?)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        Ok(socket::set_nodelay(self.fd.0, nodelay)?)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        Ok(socket::nodelay(self.fd.0)?)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Ok(socket::set_nonblocking(self.fd.0, nonblocking)?)
+    }
+}
+
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(socket::recv(self.fd.0, buf)?)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(socket::send(self.fd.0, buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // segments are handed to the stack by `write`
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// TCP listener over utcp, like `std::net::TcpListener`.
+#[derive(Debug)]
+pub struct TcpListener {
+    fd: Fd,
+}
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        each_addr(addr, |ep| {
+            let fd = Fd::open(&ep, SOCK_STREAM)?;
+            socket::bind(fd.0, ep)?;
+            socket::listen(fd.0, LISTEN_BACKLOG)?;
+            Ok(TcpListener { fd })
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (fd, peer) = socket::accept(self.fd.0)?;
+        Ok((TcpStream { fd: Fd(fd) }, to_socket_addr(peer)))
+    }
+
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Ok(socket::set_nonblocking(self.fd.0, nonblocking)?)
+    }
+}
+
+/// Iterator over the connections accepted by a `TcpListener`.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+/// UDP socket over utcp, like `std::net::UdpSocket`.
+#[derive(Debug)]
+pub struct UdpSocket {
+    fd: Fd,
+}
+
+impl UdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        each_addr(addr, |ep| {
+            let fd = Fd::open(&ep, SOCK_DGRAM)?;
+            socket::bind(fd.0, ep)?;
+            Ok(UdpSocket { fd })
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.fd.0
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, from) = socket::recvfrom(self.fd.0, buf)?;
+        Ok((len, to_socket_addr(from)))
+    }
+
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        each_addr(addr, |ep| Ok(socket::sendto(self.fd.0, buf, ep)?))
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        each_addr(addr, |ep| Ok(socket::connect(self.fd.0, ep)?))
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(socket::send(self.fd.0, buf)?)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(socket::recv(self.fd.0, buf)?)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getsockname(self.fd.0)?))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(to_socket_addr(socket::getpeername(self.fd.0)?))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        Ok(socket::set_recv_timeout(self.fd.0, timeout)?)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(socket::recv_timeout(self.fd.0)?)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Ok(socket::set_nonblocking(self.fd.0, nonblocking)?)
+    }
+
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        Ok(socket::join_multicast(
+            self.fd.0,
+            (*multiaddr).into(),
+            (*interface).into(),
+        )?)
+    }
+
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        Ok(socket::leave_multicast(
+            self.fd.0,
+            (*multiaddr).into(),
+            (*interface).into(),
+        )?)
+    }
+}
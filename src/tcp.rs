@@ -0,0 +1,1360 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard},
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    event::{self, SocketWakers},
+    ip::{self, IP_ADDR_BROADCAST, IP_PROTOCOL_TCP, IpAddr, IpAddress, IpEndpoint},
+    ipv6::{self, IPV6_MIN_MTU, Ipv6Address},
+    net::{self, NetInterfaceHandler},
+    poll::PollEvents,
+    wire::{
+        ipv4::IPV4_HEADER_MIN_LEN,
+        ipv6::IPV6_HEADER_LEN,
+        tcp::{TCP_HEADER_MIN_LEN, TcpFlags, TcpPacket},
+    },
+};
+
+const TCP_SOURCE_PORT_MIN: u16 = 49152;
+const TCP_SOURCE_PORT_MAX: u16 = 65535;
+
+/// Size of the send and receive buffers.
+pub const TCP_BUF_SIZE: usize = 65535;
+/// MSS assumed when the peer does not send the option.
+const TCP_DEFAULT_MSS: u16 = 536;
+
+const TCP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+const TCP_RTO_INITIAL: Duration = Duration::from_millis(200);
+const TCP_RTO_MAX: Duration = Duration::from_secs(60);
+const TCP_RETRANSMIT_MAX: u32 = 12;
+const TCP_TIMEWAIT: Duration = Duration::from_secs(30);
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+}
+
+/// Reason why a connection was torn down by the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpError {
+    Refused,
+    Reset,
+    TimedOut,
+}
+
+impl From<TcpError> for UtcpErr {
+    fn from(err: TcpError) -> Self {
+        match err {
+            TcpError::Refused => UtcpErr::ConnectionRefused,
+            TcpError::Reset => UtcpErr::ConnectionReset,
+            TcpError::TimedOut => UtcpErr::TimedOut,
+        }
+    }
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+#[derive(Debug)]
+struct TcpPcb {
+    state: TcpState,
+    local: IpEndpoint,
+    foreign: IpEndpoint,
+
+    // send sequence variables
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u16,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    iss: u32,
+    // receive sequence variables
+    rcv_nxt: u32,
+    irs: u32,
+    mss: u16,
+
+    /// Data from `snd_una` onward, both in flight and not sent yet.
+    snd_buf: VecDeque<u8>,
+    rcv_buf: VecDeque<u8>,
+    /// The user closed the sending side. FIN is sent once `snd_buf` has been sent.
+    fin_pending: bool,
+    fin_sent: bool,
+    /// The peer closed its sending side.
+    fin_received: bool,
+    shut_rd: bool,
+    /// Window advertised in the last segment.
+    rcv_wnd_advertised: u16,
+
+    rto: Duration,
+    rtx_deadline: Option<Instant>,
+    rtx_count: u32,
+    timewait_deadline: Option<Instant>,
+    error: Option<TcpError>,
+
+    /// Listener that created this PCB, until the connection is accepted.
+    parent: Option<usize>,
+    /// Established connections waiting for `tcp_accept`.
+    backlog: VecDeque<usize>,
+    backlog_limit: usize,
+
+    /// The user released the PCB. It is freed once the connection is closed.
+    user_closed: bool,
+    nonblocking: bool,
+    nodelay: bool,
+    /// Set by `tcp_set_v6only`. An IPv6 PCB bound to :: accepts IPv4 connections unless set.
+    v6only: bool,
+    recv_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    /// Counts changes made by input and timers, for edge-triggered polling.
+    wakeups: u64,
+    wakers: SocketWakers,
+}
+
+impl TcpPcb {
+    fn new() -> Self {
+        Self {
+            state: TcpState::Closed,
+            local: IpEndpoint::default(),
+            foreign: IpEndpoint::default(),
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            iss: 0,
+            rcv_nxt: 0,
+            irs: 0,
+            mss: TCP_DEFAULT_MSS,
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            fin_pending: false,
+            fin_sent: false,
+            fin_received: false,
+            shut_rd: false,
+            rcv_wnd_advertised: 0,
+            rto: TCP_RTO_INITIAL,
+            rtx_deadline: None,
+            rtx_count: 0,
+            timewait_deadline: None,
+            error: None,
+            parent: None,
+            backlog: VecDeque::new(),
+            backlog_limit: 0,
+            user_closed: false,
+            nonblocking: false,
+            nodelay: false,
+            v6only: false,
+            recv_timeout: None,
+            send_timeout: None,
+            wakeups: 0,
+            wakers: SocketWakers::default(),
+        }
+    }
+
+    fn rcv_wnd(&self) -> u16 {
+        (TCP_BUF_SIZE - self.rcv_buf.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// Sequence space in flight (data, SYN and FIN).
+    fn in_flight(&self) -> u32 {
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+
+    /// Data bytes in flight, excluding SYN and FIN.
+    fn data_in_flight(&self) -> usize {
+        let mut len = self.in_flight() as usize;
+        if self.fin_sent && len > 0 {
+            len -= 1;
+        }
+        len.min(self.snd_buf.len())
+    }
+
+    fn set_state(&mut self, state: TcpState) {
+        log::debug!(
+            "local={}, foreign={}, state: {:?} => {:?}",
+            self.local,
+            self.foreign,
+            self.state,
+            state
+        );
+        self.state = state;
+        if state == TcpState::TimeWait {
+            self.rtx_deadline = None;
+            self.timewait_deadline = Some(Instant::now() + TCP_TIMEWAIT);
+        }
+        if state == TcpState::Closed {
+            self.rtx_deadline = None;
+            self.timewait_deadline = None;
+        }
+    }
+
+    fn start_rtx_timer(&mut self) {
+        if self.rtx_deadline.is_none() {
+            self.rtx_deadline = Some(Instant::now() + self.rto);
+        }
+    }
+
+    fn poll_events(&self) -> PollEvents {
+        let mut events = PollEvents::empty();
+        match self.state {
+            TcpState::Listen => events.set(PollEvents::READABLE, !self.backlog.is_empty()),
+            TcpState::SynSent | TcpState::SynReceived => {}
+            state => {
+                // a read does not block at the end of the stream either
+                events.set(
+                    PollEvents::READABLE,
+                    !self.rcv_buf.is_empty() || self.fin_received || self.shut_rd,
+                );
+                events.set(
+                    PollEvents::WRITABLE,
+                    matches!(state, TcpState::Established | TcpState::CloseWait)
+                        && !self.fin_pending
+                        && self.snd_buf.len() < TCP_BUF_SIZE,
+                );
+                events.set(
+                    PollEvents::HANGUP,
+                    state == TcpState::Closed || (self.fin_received && self.fin_pending),
+                );
+            }
+        }
+        events.set(PollEvents::ERROR, self.error.is_some());
+        events
+    }
+
+    /// Counts a change made by input or a timer, and collects the tasks it makes ready.
+    fn touch(&mut self, woken: &mut Vec<Waker>) {
+        self.wakeups += 1;
+        woken.extend(self.wakers.take(self.poll_events()));
+    }
+}
+
+static TCP_PCBS: Mutex<Vec<Option<TcpPcb>>> = Mutex::new(Vec::new());
+/// Notified on every state change, data arrival and acknowledgment.
+static TCP_COND: Condvar = Condvar::new();
+
+type TcpPcbs<'a> = MutexGuard<'a, Vec<Option<TcpPcb>>>;
+
+fn tcp_pcb_alloc(pcbs: &mut TcpPcbs, pcb: TcpPcb) -> usize {
+    match pcbs.iter().position(|pcb| pcb.is_none()) {
+        Some(id) => {
+            pcbs[id] = Some(pcb);
+            id
+        }
+        None => {
+            pcbs.push(Some(pcb));
+            pcbs.len() - 1
+        }
+    }
+}
+
+/// Returns a PCB owned by the user.
+fn tcp_pcb_get<'a>(pcbs: &'a mut TcpPcbs, id: usize) -> UtcpResult<&'a mut TcpPcb> {
+    pcbs.get_mut(id)
+        .and_then(|pcb| pcb.as_mut())
+        .filter(|pcb| !pcb.user_closed && pcb.parent.is_none())
+        .ok_or(UtcpErr::BadDescriptor(id as i32))
+}
+
+/// Frees the PCB if the user released it and the connection is over.
+fn tcp_pcb_release_if_done(pcbs: &mut TcpPcbs, id: usize) {
+    if let Some(pcb) = &pcbs[id]
+        && pcb.user_closed
+        && pcb.state == TcpState::Closed
+    {
+        log::debug!("released: id={}", id);
+        pcbs[id] = None;
+    }
+}
+
+/// Frees the PCB if it is a connection that closed before it was accepted, and takes it off
+/// the backlog of its listener.
+fn tcp_pcb_release_if_unaccepted(pcbs: &mut TcpPcbs, id: usize) {
+    let Some(pcb) = &pcbs[id] else { return };
+    if let (TcpState::Closed, Some(parent)) = (pcb.state, pcb.parent) {
+        if let Some(listener) = pcbs[parent].as_mut() {
+            listener.backlog.retain(|&child| child != id);
+        }
+        log::debug!("released before accepted: id={}", id);
+        pcbs[id] = None;
+    }
+}
+
+/// `local` and `foreign` are canonical, as received.
+fn tcp_pcb_select(pcbs: &TcpPcbs, local: IpEndpoint, foreign: IpEndpoint) -> Option<usize> {
+    let mut listener = None;
+    for (id, pcb) in pcbs.iter().enumerate() {
+        let Some(pcb) = pcb else { continue };
+        if pcb.state == TcpState::Closed || pcb.local.port != local.port {
+            continue;
+        }
+        if !ip::ip_addr_covers(pcb.local.addr, pcb.v6only, local.addr) {
+            continue;
+        }
+        if pcb.state == TcpState::Listen {
+            listener = Some(id);
+        } else if pcb.foreign.to_canonical() == foreign {
+            return Some(id);
+        }
+    }
+    listener
+}
+
+fn tcp_port_in_use(pcbs: &TcpPcbs, local: IpEndpoint, v6only: bool) -> bool {
+    pcbs.iter().flatten().any(|pcb| {
+        pcb.local.port == local.port
+            && ip::ip_addr_overlaps(pcb.local.addr, pcb.v6only, local.addr, v6only)
+    })
+}
+
+fn tcp_generate_iss() -> u32 {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    nanos.wrapping_add(COUNTER.fetch_add(64000, Ordering::Relaxed))
+}
+
+fn tcp_mss_for(local: IpAddr, foreign: IpAddr) -> u16 {
+    let (mtu, header_len) = match (local.to_canonical(), foreign.to_canonical()) {
+        (IpAddr::V4(local), IpAddr::V4(foreign)) => (
+            ip::ip_route_mtu(local, foreign).unwrap_or(576) as usize,
+            IPV4_HEADER_MIN_LEN,
+        ),
+        (IpAddr::V6(local), IpAddr::V6(foreign)) => (
+            ipv6::ipv6_route_mtu(local, foreign).map_or(IPV6_MIN_MTU, |mtu| mtu as usize),
+            IPV6_HEADER_LEN,
+        ),
+        _ => return TCP_DEFAULT_MSS,
+    };
+    mtu.saturating_sub(header_len + TCP_HEADER_MIN_LEN)
+        .clamp(1, u16::MAX as usize) as u16
+}
+
+fn tcp_parse_mss(options: &[u8]) -> Option<u16> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => i += 1,
+            kind => {
+                let len = *options.get(i + 1)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return None;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    return Some(u16::from_be_bytes([options[i + 2], options[i + 3]]));
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tcp_output_segment(
+    local: IpEndpoint,
+    foreign: IpEndpoint,
+    seq: u32,
+    ack: u32,
+    flags: TcpFlags,
+    wnd: u16,
+    mss: Option<u16>,
+    data: &[u8],
+) -> UtcpResult<()> {
+    let header_len = TCP_HEADER_MIN_LEN + if mss.is_some() { 4 } else { 0 };
+    let mut buf = vec![0u8; header_len + data.len()];
+    let mut tcp = TcpPacket::new_unchecked(&mut buf[..]);
+    tcp.set_src_port(local.port);
+    tcp.set_dst_port(foreign.port);
+    tcp.set_seq(seq);
+    tcp.set_ack(ack);
+    tcp.set_header_len(header_len);
+    tcp.set_flags(flags);
+    tcp.set_window(wnd);
+    if let Some(mss) = mss {
+        let mss = mss.to_be_bytes();
+        tcp.options_mut()
+            .copy_from_slice(&[TCP_OPT_MSS, 4, mss[0], mss[1]]);
+    }
+    tcp.payload_mut().copy_from_slice(data);
+    // IPv6 PCBs of IPv4 connections hold IPv4-mapped addresses
+    let (src, dst) = (local.addr.to_canonical(), foreign.addr.to_canonical());
+    tcp.fill_checksum(src, dst);
+    log::debug!("{} => {}, {:?}", local, foreign, tcp);
+    ip::ip_transport_output(IP_PROTOCOL_TCP, &buf, src, dst)?;
+    Ok(())
+}
+
+/// Sends a segment carrying the current receive state of the PCB.
+fn tcp_output_pcb(pcb: &mut TcpPcb, seq: u32, flags: TcpFlags, data: &[u8]) {
+    let wnd = pcb.rcv_wnd();
+    pcb.rcv_wnd_advertised = wnd;
+    let mss = flags
+        .contains(TcpFlags::SYN)
+        .then(|| tcp_mss_for(pcb.local.addr, pcb.foreign.addr));
+    let ack = if flags.contains(TcpFlags::ACK) {
+        pcb.rcv_nxt
+    } else {
+        0
+    };
+    if let Err(e) = tcp_output_segment(pcb.local, pcb.foreign, seq, ack, flags, wnd, mss, data) {
+        log::warn!("failed to send a segment: {}", e);
+    }
+}
+
+fn tcp_send_ack(pcb: &mut TcpPcb) {
+    tcp_output_pcb(pcb, pcb.snd_nxt, TcpFlags::ACK, &[]);
+}
+
+/// Sends as much queued data as the peer window allows, then FIN if it is pending.
+fn tcp_output(pcb: &mut TcpPcb) {
+    if !matches!(
+        pcb.state,
+        TcpState::Established | TcpState::CloseWait | TcpState::FinWait1 | TcpState::LastAck
+    ) {
+        return;
+    }
+    loop {
+        let off = pcb.data_in_flight();
+        if pcb.fin_sent || off >= pcb.snd_buf.len() {
+            break;
+        }
+        let window = (pcb.snd_wnd as usize).saturating_sub(pcb.in_flight() as usize);
+        let len = (pcb.snd_buf.len() - off).min(window).min(pcb.mss as usize);
+        if len == 0 {
+            // zero window. the retransmission timer probes it.
+            pcb.start_rtx_timer();
+            return;
+        }
+        // Nagle: do not send a small segment while data is in flight
+        if !pcb.nodelay && len < pcb.mss as usize && pcb.in_flight() > 0 {
+            return;
+        }
+        let data: Vec<u8> = pcb.snd_buf.range(off..off + len).copied().collect();
+        tcp_output_pcb(pcb, pcb.snd_nxt, TcpFlags::ACK | TcpFlags::PSH, &data);
+        pcb.snd_nxt = pcb.snd_nxt.wrapping_add(len as u32);
+        pcb.start_rtx_timer();
+    }
+    if pcb.fin_pending && !pcb.fin_sent && pcb.data_in_flight() == pcb.snd_buf.len() {
+        tcp_output_pcb(pcb, pcb.snd_nxt, TcpFlags::ACK | TcpFlags::FIN, &[]);
+        pcb.snd_nxt = pcb.snd_nxt.wrapping_add(1);
+        pcb.fin_sent = true;
+        pcb.start_rtx_timer();
+    }
+}
+
+fn tcp_retransmit(pcb: &mut TcpPcb) {
+    match pcb.state {
+        TcpState::SynSent => tcp_output_pcb(pcb, pcb.iss, TcpFlags::SYN, &[]),
+        TcpState::SynReceived => {
+            tcp_output_pcb(pcb, pcb.iss, TcpFlags::SYN | TcpFlags::ACK, &[]);
+        }
+        _ => {
+            let len = pcb.data_in_flight().min(pcb.mss as usize);
+            if len > 0 {
+                let data: Vec<u8> = pcb.snd_buf.range(..len).copied().collect();
+                tcp_output_pcb(pcb, pcb.snd_una, TcpFlags::ACK | TcpFlags::PSH, &data);
+            } else if pcb.fin_sent && pcb.in_flight() > 0 {
+                tcp_output_pcb(pcb, pcb.snd_una, TcpFlags::ACK | TcpFlags::FIN, &[]);
+            } else if pcb.snd_wnd == 0 && !pcb.snd_buf.is_empty() {
+                // window probe
+                let data = [pcb.snd_buf[0]];
+                tcp_output_pcb(pcb, pcb.snd_una, TcpFlags::ACK, &data);
+                pcb.snd_nxt = pcb.snd_una.wrapping_add(1);
+            }
+        }
+    }
+}
+
+/// Sends RST in reply to a segment that does not belong to any connection.
+fn tcp_output_reset(local: IpEndpoint, foreign: IpEndpoint, seg: &TcpPacket<&[u8]>) {
+    if seg.flags().contains(TcpFlags::RST) {
+        return;
+    }
+    let result = if seg.flags().contains(TcpFlags::ACK) {
+        tcp_output_segment(local, foreign, seg.ack(), 0, TcpFlags::RST, 0, None, &[])
+    } else {
+        let ack = seg.seq().wrapping_add(seg.segment_len() as u32);
+        let flags = TcpFlags::RST | TcpFlags::ACK;
+        tcp_output_segment(local, foreign, 0, ack, flags, 0, None, &[])
+    };
+    if let Err(e) = result {
+        log::warn!("failed to send RST: {}", e);
+    }
+}
+
+/// Aborts a connection: sends RST if it is synchronized and closes the PCB.
+fn tcp_abort(pcb: &mut TcpPcb) {
+    if matches!(
+        pcb.state,
+        TcpState::SynReceived
+            | TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::CloseWait
+    ) {
+        tcp_output_pcb(pcb, pcb.snd_nxt, TcpFlags::RST | TcpFlags::ACK, &[]);
+    }
+    pcb.set_state(TcpState::Closed);
+}
+
+/// Handles a segment for the listener `id`. Returns the new PCB in SYN-RECEIVED.
+fn tcp_listen_input(
+    pcbs: &mut TcpPcbs,
+    id: usize,
+    local: IpEndpoint,
+    foreign: IpEndpoint,
+    seg: &TcpPacket<&[u8]>,
+) {
+    let flags = seg.flags();
+    if flags.contains(TcpFlags::RST) {
+        return;
+    }
+    if flags.contains(TcpFlags::ACK) {
+        tcp_output_reset(local, foreign, seg);
+        return;
+    }
+    if !flags.contains(TcpFlags::SYN) {
+        return;
+    }
+    let listener = pcbs[id].as_ref().unwrap();
+    let pending = pcbs
+        .iter()
+        .flatten()
+        .filter(|pcb| pcb.parent == Some(id) && pcb.state != TcpState::Closed)
+        .count();
+    if pending >= listener.backlog_limit {
+        log::warn!("backlog full, dropped SYN: local={}", local);
+        return;
+    }
+    let mut pcb = TcpPcb::new();
+    pcb.local = local.in_family_of(&listener.local);
+    pcb.foreign = foreign.in_family_of(&listener.local);
+    pcb.parent = Some(id);
+    pcb.nodelay = listener.nodelay;
+    pcb.v6only = listener.v6only;
+    pcb.rcv_nxt = seg.seq().wrapping_add(1);
+    pcb.irs = seg.seq();
+    pcb.iss = tcp_generate_iss();
+    pcb.snd_una = pcb.iss;
+    pcb.snd_nxt = pcb.iss.wrapping_add(1);
+    pcb.snd_wnd = seg.window();
+    pcb.snd_wl1 = seg.seq();
+    pcb.mss = seg_mss(seg).min(tcp_mss_for(local.addr, foreign.addr));
+    pcb.set_state(TcpState::SynReceived);
+    let iss = pcb.iss;
+    tcp_output_pcb(&mut pcb, iss, TcpFlags::SYN | TcpFlags::ACK, &[]);
+    pcb.start_rtx_timer();
+    tcp_pcb_alloc(pcbs, pcb);
+}
+
+fn seg_mss(seg: &TcpPacket<&[u8]>) -> u16 {
+    tcp_parse_mss(seg.options()).unwrap_or(TCP_DEFAULT_MSS)
+}
+
+fn tcp_syn_sent_input(pcb: &mut TcpPcb, seg: &TcpPacket<&[u8]>) {
+    let flags = seg.flags();
+    let mut acceptable = false;
+    if flags.contains(TcpFlags::ACK) {
+        if seq_le(seg.ack(), pcb.iss) || seq_lt(pcb.snd_nxt, seg.ack()) {
+            tcp_output_reset(pcb.local, pcb.foreign, seg);
+            return;
+        }
+        acceptable = seq_le(pcb.snd_una, seg.ack()) && seq_le(seg.ack(), pcb.snd_nxt);
+    }
+    if flags.contains(TcpFlags::RST) {
+        if acceptable {
+            pcb.error = Some(TcpError::Refused);
+            pcb.set_state(TcpState::Closed);
+        }
+        return;
+    }
+    if !flags.contains(TcpFlags::SYN) {
+        return;
+    }
+    pcb.rcv_nxt = seg.seq().wrapping_add(1);
+    pcb.irs = seg.seq();
+    pcb.mss = pcb.mss.min(seg_mss(seg));
+    if acceptable {
+        pcb.snd_una = seg.ack();
+        pcb.rtx_deadline = None;
+        pcb.rtx_count = 0;
+    }
+    if seq_lt(pcb.iss, pcb.snd_una) {
+        pcb.snd_wnd = seg.window();
+        pcb.snd_wl1 = seg.seq();
+        pcb.snd_wl2 = seg.ack();
+        pcb.set_state(TcpState::Established);
+        tcp_send_ack(pcb);
+    } else {
+        // simultaneous open
+        pcb.set_state(TcpState::SynReceived);
+        tcp_output_pcb(pcb, pcb.iss, TcpFlags::SYN | TcpFlags::ACK, &[]);
+    }
+}
+
+/// Segment processing for the synchronized states and SYN-RECEIVED (RFC 793, 3.9).
+/// Returns true if the PCB became established and should be queued on its listener.
+fn tcp_segment_input(pcb: &mut TcpPcb, seg: &TcpPacket<&[u8]>) -> bool {
+    let flags = seg.flags();
+    let seq = seg.seq();
+    let seg_len = seg.segment_len() as u32;
+    let rcv_wnd = pcb.rcv_wnd() as u32;
+
+    // first check sequence number
+    let rcv_end = pcb.rcv_nxt.wrapping_add(rcv_wnd);
+    let in_window = |s: u32| seq_le(pcb.rcv_nxt, s) && seq_lt(s, rcv_end);
+    let acceptable = match (seg_len, rcv_wnd) {
+        (0, 0) => seq == pcb.rcv_nxt,
+        (0, _) => in_window(seq),
+        (_, 0) => false,
+        (_, _) => in_window(seq) || in_window(seq.wrapping_add(seg_len - 1)),
+    };
+    if !acceptable {
+        if !flags.contains(TcpFlags::RST) {
+            tcp_send_ack(pcb);
+        }
+        return false;
+    }
+
+    // second check the RST bit
+    if flags.contains(TcpFlags::RST) {
+        match pcb.state {
+            TcpState::SynReceived if pcb.parent.is_some() => {}
+            TcpState::SynReceived => pcb.error = Some(TcpError::Refused),
+            TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::CloseWait => pcb.error = Some(TcpError::Reset),
+            _ => {}
+        }
+        pcb.set_state(TcpState::Closed);
+        return false;
+    }
+
+    // fourth check the SYN bit
+    if flags.contains(TcpFlags::SYN) {
+        tcp_abort(pcb);
+        pcb.error = Some(TcpError::Reset);
+        return false;
+    }
+
+    // fifth check the ACK field
+    if !flags.contains(TcpFlags::ACK) {
+        return false;
+    }
+    let ack = seg.ack();
+    let mut established = false;
+    if pcb.state == TcpState::SynReceived {
+        if seq_le(pcb.snd_una, ack) && seq_le(ack, pcb.snd_nxt) {
+            pcb.snd_wnd = seg.window();
+            pcb.snd_wl1 = seq;
+            pcb.snd_wl2 = ack;
+            pcb.set_state(TcpState::Established);
+            established = true;
+        } else {
+            tcp_output_reset(pcb.local, pcb.foreign, seg);
+            return false;
+        }
+    }
+    if seq_lt(pcb.snd_una, ack) && seq_le(ack, pcb.snd_nxt) {
+        let mut acked = ack.wrapping_sub(pcb.snd_una) as usize;
+        if pcb.snd_una == pcb.iss {
+            // SYN
+            acked -= 1;
+        }
+        let data_acked = acked.min(pcb.snd_buf.len());
+        pcb.snd_buf.drain(..data_acked);
+        pcb.snd_una = ack;
+        pcb.rto = TCP_RTO_INITIAL;
+        pcb.rtx_count = 0;
+        pcb.rtx_deadline = None;
+        if pcb.in_flight() > 0 {
+            pcb.start_rtx_timer();
+        }
+    } else if seq_lt(pcb.snd_nxt, ack) {
+        tcp_send_ack(pcb);
+        return false;
+    }
+    if seq_le(pcb.snd_una, ack)
+        && (seq_lt(pcb.snd_wl1, seq) || (pcb.snd_wl1 == seq && seq_le(pcb.snd_wl2, ack)))
+    {
+        pcb.snd_wnd = seg.window();
+        pcb.snd_wl1 = seq;
+        pcb.snd_wl2 = ack;
+    }
+    let fin_acked = pcb.fin_sent && pcb.snd_una == pcb.snd_nxt;
+    match pcb.state {
+        TcpState::FinWait1 if fin_acked => pcb.set_state(TcpState::FinWait2),
+        TcpState::Closing if fin_acked => pcb.set_state(TcpState::TimeWait),
+        TcpState::LastAck if fin_acked => {
+            pcb.set_state(TcpState::Closed);
+            return established;
+        }
+        _ => {}
+    }
+
+    // seventh, process the segment text
+    let payload = seg.payload();
+    let mut fin_in_order = flags.contains(TcpFlags::FIN);
+    if matches!(
+        pcb.state,
+        TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+    ) && !payload.is_empty()
+    {
+        if seq_le(seq, pcb.rcv_nxt) {
+            let off = pcb.rcv_nxt.wrapping_sub(seq) as usize;
+            let data = payload.get(off..).unwrap_or_default();
+            let len = data.len().min(rcv_wnd as usize);
+            if !pcb.shut_rd {
+                pcb.rcv_buf.extend(&data[..len]);
+            }
+            pcb.rcv_nxt = pcb.rcv_nxt.wrapping_add(len as u32);
+            fin_in_order &= len == data.len();
+        } else {
+            // out of order. wait for the retransmission.
+            fin_in_order = false;
+        }
+        tcp_send_ack(pcb);
+    } else if fin_in_order {
+        fin_in_order = seq.wrapping_add(payload.len() as u32) == pcb.rcv_nxt;
+    }
+
+    // eighth, check the FIN bit
+    if fin_in_order {
+        pcb.rcv_nxt = pcb.rcv_nxt.wrapping_add(1);
+        pcb.fin_received = true;
+        tcp_send_ack(pcb);
+        match pcb.state {
+            TcpState::SynReceived | TcpState::Established => {
+                pcb.set_state(TcpState::CloseWait);
+            }
+            TcpState::FinWait1 => {
+                if fin_acked {
+                    pcb.set_state(TcpState::TimeWait);
+                } else {
+                    pcb.set_state(TcpState::Closing);
+                }
+            }
+            TcpState::FinWait2 => pcb.set_state(TcpState::TimeWait),
+            TcpState::TimeWait => pcb.set_state(TcpState::TimeWait),
+            _ => {}
+        }
+    }
+
+    // the window may have opened
+    tcp_output(pcb);
+    established
+}
+
+fn tcp_input(data: &[u8], src: IpAddress, dst: IpAddress, _iface: &NetInterfaceHandler) {
+    if src == IP_ADDR_BROADCAST || dst == IP_ADDR_BROADCAST || dst.is_multicast() {
+        log::debug!("broadcast and multicast segments are not supported");
+        return;
+    }
+    tcp_segment_arrives(data, src.into(), dst.into());
+}
+
+fn tcp_input_ipv6(data: &[u8], src: Ipv6Address, dst: Ipv6Address, _iface: &NetInterfaceHandler) {
+    if dst.is_multicast() {
+        log::debug!("multicast segments are not supported");
+        return;
+    }
+    tcp_segment_arrives(data, src.into(), dst.into());
+}
+
+/// Handles a segment received by either family.
+fn tcp_segment_arrives(data: &[u8], src: IpAddr, dst: IpAddr) {
+    let seg = match TcpPacket::new_checked(data) {
+        Ok(seg) => seg,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if !seg.verify_checksum(src, dst) {
+        log::error!("checksum mismatch: sum=0x{:04x}", seg.sum());
+        return;
+    }
+    let local = IpEndpoint::new(dst, seg.dst_port());
+    let foreign = IpEndpoint::new(src, seg.src_port());
+    log::debug!("{} => {}, {:?}", foreign, local, seg);
+
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let mut woken = Vec::new();
+    let Some(id) = tcp_pcb_select(&pcbs, local, foreign) else {
+        tcp_output_reset(local, foreign, &seg);
+        return;
+    };
+    match pcbs[id].as_ref().unwrap().state {
+        TcpState::Listen => tcp_listen_input(&mut pcbs, id, local, foreign, &seg),
+        TcpState::SynSent => tcp_syn_sent_input(pcbs[id].as_mut().unwrap(), &seg),
+        _ => {
+            let pcb = pcbs[id].as_mut().unwrap();
+            if tcp_segment_input(pcb, &seg)
+                && let Some(parent) = pcb.parent
+                && let Some(listener) = pcbs[parent].as_mut()
+            {
+                listener.backlog.push_back(id);
+                listener.touch(&mut woken);
+            }
+            tcp_pcb_release_if_unaccepted(&mut pcbs, id);
+        }
+    }
+    if let Some(pcb) = pcbs[id].as_mut() {
+        pcb.touch(&mut woken);
+    }
+    tcp_pcb_release_if_done(&mut pcbs, id);
+    drop(pcbs);
+    TCP_COND.notify_all();
+    event::event_notify(woken);
+}
+
+/// Runs the TIME-WAIT and retransmission timers that are due at `now`. Returns whether a
+/// connection was closed.
+fn tcp_timer_expire(pcbs: &mut TcpPcbs, now: Instant, woken: &mut Vec<Waker>) -> bool {
+    let mut changed = false;
+    for id in 0..pcbs.len() {
+        let Some(pcb) = pcbs[id].as_mut() else {
+            continue;
+        };
+        if pcb
+            .timewait_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            pcb.set_state(TcpState::Closed);
+            pcb.touch(woken);
+            changed = true;
+        }
+        if pcb.rtx_deadline.is_some_and(|deadline| deadline <= now) {
+            pcb.rtx_deadline = None;
+            pcb.rtx_count += 1;
+            if pcb.rtx_count > TCP_RETRANSMIT_MAX {
+                log::warn!(
+                    "retransmission timeout: local={}, foreign={}",
+                    pcb.local,
+                    pcb.foreign
+                );
+                pcb.error = Some(TcpError::TimedOut);
+                tcp_abort(pcb);
+                pcb.touch(woken);
+                changed = true;
+            } else {
+                tcp_retransmit(pcb);
+                pcb.rto = (pcb.rto * 2).min(TCP_RTO_MAX);
+                pcb.start_rtx_timer();
+            }
+        }
+        tcp_pcb_release_if_unaccepted(pcbs, id);
+        tcp_pcb_release_if_done(pcbs, id);
+    }
+    changed
+}
+
+fn tcp_timer() {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let mut woken = Vec::new();
+    let changed = tcp_timer_expire(&mut pcbs, Instant::now(), &mut woken);
+    drop(pcbs);
+    if changed {
+        TCP_COND.notify_all();
+        event::event_notify(woken);
+    }
+}
+
+pub fn tcp_init() -> UtcpResult<()> {
+    ip::ip_protocol_register(IP_PROTOCOL_TCP, tcp_input)?;
+    ipv6::ipv6_protocol_register(IP_PROTOCOL_TCP, tcp_input_ipv6)?;
+    net::net_timer_register(TCP_TIMER_INTERVAL, tcp_timer)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+/// Waits on the condition variable until `deadline`. Returns `WouldBlock` if it has passed.
+fn tcp_wait<'a>(pcbs: TcpPcbs<'a>, deadline: Option<Instant>) -> UtcpResult<TcpPcbs<'a>> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UtcpErr::WouldBlock);
+            }
+            Ok(TCP_COND.wait_timeout(pcbs, deadline - now).unwrap().0)
+        }
+        None => Ok(TCP_COND.wait(pcbs).unwrap()),
+    }
+}
+
+/// Opens an IPv4 PCB.
+pub fn tcp_open() -> UtcpResult<usize> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let id = tcp_pcb_alloc(&mut pcbs, TcpPcb::new());
+    log::debug!("opened: id={}", id);
+    Ok(id)
+}
+
+/// Opens an IPv6 PCB. Bound to ::, it also accepts and makes IPv4 connections, with
+/// IPv4-mapped addresses, unless `tcp_set_v6only` is set.
+pub fn tcp_open_ipv6() -> UtcpResult<usize> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let mut pcb = TcpPcb::new();
+    pcb.local = IpEndpoint::new(Ipv6Address::UNSPECIFIED, 0);
+    let id = tcp_pcb_alloc(&mut pcbs, pcb);
+    log::debug!("opened: id={}", id);
+    Ok(id)
+}
+
+/// Binds the PCB to a local endpoint of its family. Port 0 picks an ephemeral port.
+pub fn tcp_bind(id: usize, mut local: IpEndpoint) -> UtcpResult<IpEndpoint> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    if pcb.local.port != 0 || pcb.state != TcpState::Closed {
+        return Err(UtcpErr::InvalidArgument("already bound".into()));
+    }
+    if local.is_ipv6() != pcb.local.is_ipv6() {
+        return Err(UtcpErr::AddrFamilyNotSupported);
+    }
+    let v6only = pcb.v6only;
+    if local.port == 0 {
+        local.port = (TCP_SOURCE_PORT_MIN..=TCP_SOURCE_PORT_MAX)
+            .find(|&port| !tcp_port_in_use(&pcbs, IpEndpoint::new(local.addr, port), v6only))
+            .ok_or(UtcpErr::AddrInUse)?;
+    } else if tcp_port_in_use(&pcbs, local, v6only) {
+        return Err(UtcpErr::AddrInUse);
+    }
+    tcp_pcb_get(&mut pcbs, id)?.local = local;
+    log::debug!("bound: id={}, local={}", id, local);
+    Ok(local)
+}
+
+pub fn tcp_listen(id: usize, backlog: usize) -> UtcpResult<()> {
+    let local = tcp_local(id)?;
+    if local.port == 0 {
+        tcp_bind(id, IpEndpoint::new(local.addr, 0))?;
+    }
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    if !matches!(pcb.state, TcpState::Closed | TcpState::Listen) {
+        return Err(UtcpErr::AlreadyConnected);
+    }
+    pcb.backlog_limit = backlog.max(1);
+    pcb.set_state(TcpState::Listen);
+    Ok(())
+}
+
+/// Waits for a connection on a listening PCB. Returns the PCB of the connection and the
+/// foreign endpoint.
+pub fn tcp_accept(id: usize) -> UtcpResult<(usize, IpEndpoint)> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    let deadline = pcb.recv_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let pcb = tcp_pcb_get(&mut pcbs, id)?;
+        if pcb.state != TcpState::Listen {
+            return Err(UtcpErr::InvalidArgument("not listening".into()));
+        }
+        if let Some(child_id) = pcb.backlog.pop_front() {
+            let child = pcbs[child_id].as_mut().unwrap();
+            child.parent = None;
+            log::debug!("accepted: id={}, foreign={}", child_id, child.foreign);
+            return Ok((child_id, child.foreign));
+        }
+        if pcb.nonblocking {
+            return Err(UtcpErr::WouldBlock);
+        }
+        pcbs = tcp_wait(pcbs, deadline)?;
+    }
+}
+
+/// Starts an active open. Blocks until the connection is established unless the PCB is
+/// non-blocking, in which case it returns `InProgress`.
+pub fn tcp_connect(id: usize, foreign: IpEndpoint) -> UtcpResult<()> {
+    let (local, v6only) = {
+        let mut pcbs = TCP_PCBS.lock().unwrap();
+        let pcb = tcp_pcb_get(&mut pcbs, id)?;
+        (pcb.local, pcb.v6only)
+    };
+    if v6only && foreign.addr.to_ipv4().is_some() {
+        return Err(UtcpErr::NetUnreachable);
+    }
+    // the PCB keeps its family: IPv6 ones hold IPv4 connections with IPv4-mapped addresses
+    let (addr, _) = ip::ip_transport_addrs(local.addr, foreign.addr)?;
+    let addr = IpEndpoint::new(addr, local.port).in_family_of(&local).addr;
+    let foreign = foreign.in_family_of(&local);
+    if local.port == 0 {
+        tcp_bind(id, IpEndpoint::new(addr, 0))?;
+    }
+
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    match pcb.state {
+        TcpState::Closed => {}
+        TcpState::SynSent => return Err(UtcpErr::InProgress),
+        TcpState::Listen => return Err(UtcpErr::InvalidArgument("listening".into())),
+        _ => return Err(UtcpErr::AlreadyConnected),
+    }
+    pcb.local.addr = addr;
+    pcb.foreign = foreign;
+    pcb.error = None;
+    pcb.mss = tcp_mss_for(pcb.local.addr, foreign.addr);
+    pcb.iss = tcp_generate_iss();
+    pcb.snd_una = pcb.iss;
+    pcb.snd_nxt = pcb.iss.wrapping_add(1);
+    pcb.set_state(TcpState::SynSent);
+    tcp_output_pcb(pcb, pcb.iss, TcpFlags::SYN, &[]);
+    pcb.start_rtx_timer();
+    if pcb.nonblocking {
+        return Err(UtcpErr::InProgress);
+    }
+    let deadline = pcb.send_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let pcb = tcp_pcb_get(&mut pcbs, id)?;
+        match pcb.state {
+            TcpState::SynSent | TcpState::SynReceived => {}
+            TcpState::Closed => return Err(pcb.error.take().unwrap_or(TcpError::Refused).into()),
+            _ => return Ok(()),
+        }
+        pcbs = match tcp_wait(pcbs, deadline) {
+            Ok(pcbs) => pcbs,
+            Err(_) => {
+                let mut pcbs = TCP_PCBS.lock().unwrap();
+                let pcb = tcp_pcb_get(&mut pcbs, id)?;
+                pcb.set_state(TcpState::Closed);
+                return Err(UtcpErr::TimedOut);
+            }
+        };
+    }
+}
+
+/// Queues data for sending. Blocks until at least one byte fits in the send buffer unless the
+/// PCB is non-blocking. Returns the queued length.
+pub fn tcp_send(id: usize, data: &[u8]) -> UtcpResult<usize> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    let deadline = pcb.send_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let pcb = tcp_pcb_get(&mut pcbs, id)?;
+        if let Some(err) = pcb.error.take() {
+            return Err(err.into());
+        }
+        if pcb.fin_pending {
+            return Err(UtcpErr::BrokenPipe);
+        }
+        match pcb.state {
+            TcpState::Established | TcpState::CloseWait => {
+                let len = (TCP_BUF_SIZE - pcb.snd_buf.len()).min(data.len());
+                if len > 0 || data.is_empty() {
+                    pcb.snd_buf.extend(&data[..len]);
+                    tcp_output(pcb);
+                    return Ok(len);
+                }
+            }
+            TcpState::SynSent | TcpState::SynReceived => {}
+            TcpState::Closed | TcpState::Listen => return Err(UtcpErr::NotConnected),
+            _ => return Err(UtcpErr::BrokenPipe),
+        }
+        if pcb.nonblocking {
+            return Err(UtcpErr::WouldBlock);
+        }
+        pcbs = tcp_wait(pcbs, deadline)?;
+    }
+}
+
+/// Receives data. Blocks until data arrives unless the PCB is non-blocking. Returns 0 when the
+/// peer closed the connection.
+pub fn tcp_receive(id: usize, buf: &mut [u8]) -> UtcpResult<usize> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    let deadline = pcb.recv_timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let pcb = tcp_pcb_get(&mut pcbs, id)?;
+        if !pcb.rcv_buf.is_empty() && !buf.is_empty() {
+            let len = pcb.rcv_buf.len().min(buf.len());
+            for (dst, src) in buf.iter_mut().zip(pcb.rcv_buf.drain(..len)) {
+                *dst = src;
+            }
+            // tell the peer that the window opened
+            if (pcb.rcv_wnd_advertised as usize) < pcb.mss as usize
+                && pcb.rcv_wnd() as usize >= pcb.mss as usize
+            {
+                tcp_send_ack(pcb);
+            }
+            return Ok(len);
+        }
+        if let Some(err) = pcb.error.take() {
+            return Err(err.into());
+        }
+        if pcb.fin_received || pcb.shut_rd || buf.is_empty() {
+            return Ok(0);
+        }
+        match pcb.state {
+            TcpState::Closed | TcpState::Listen => return Err(UtcpErr::NotConnected),
+            _ => {}
+        }
+        if pcb.nonblocking {
+            return Err(UtcpErr::WouldBlock);
+        }
+        pcbs = tcp_wait(pcbs, deadline)?;
+    }
+}
+
+/// Closes the sending side of the connection (`how_write`) and/or stops receiving
+/// (`how_read`).
+pub fn tcp_shutdown(id: usize, how_read: bool, how_write: bool) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    if matches!(
+        pcb.state,
+        TcpState::Closed | TcpState::Listen | TcpState::SynSent
+    ) {
+        return Err(UtcpErr::NotConnected);
+    }
+    if how_read {
+        pcb.shut_rd = true;
+        pcb.rcv_buf.clear();
+    }
+    if how_write {
+        tcp_close_send(pcb);
+    }
+    let woken = pcb.wakers.take(pcb.poll_events());
+    drop(pcbs);
+    TCP_COND.notify_all();
+    event::event_notify(woken);
+    Ok(())
+}
+
+fn tcp_close_send(pcb: &mut TcpPcb) {
+    if pcb.fin_pending {
+        return;
+    }
+    match pcb.state {
+        TcpState::SynReceived | TcpState::Established => pcb.set_state(TcpState::FinWait1),
+        TcpState::CloseWait => pcb.set_state(TcpState::LastAck),
+        _ => return,
+    }
+    pcb.fin_pending = true;
+    tcp_output(pcb);
+}
+
+/// Releases the PCB. The connection is closed gracefully in the background, or reset if
+/// received data was not read.
+pub fn tcp_close(id: usize) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    pcb.user_closed = true;
+    let woken = pcb.wakers.take(PollEvents::INVALID);
+    match pcb.state {
+        TcpState::Listen => {
+            pcb.set_state(TcpState::Closed);
+            // reset connections that were never accepted
+            for child in pcbs.iter_mut().flatten() {
+                if child.parent == Some(id) {
+                    child.parent = None;
+                    child.user_closed = true;
+                    tcp_abort(child);
+                }
+            }
+        }
+        TcpState::SynSent => pcb.set_state(TcpState::Closed),
+        _ if !pcb.rcv_buf.is_empty() => tcp_abort(pcb),
+        _ => tcp_close_send(pcb),
+    }
+    for child in 0..pcbs.len() {
+        tcp_pcb_release_if_done(&mut pcbs, child);
+    }
+    drop(pcbs);
+    TCP_COND.notify_all();
+    event::event_notify(woken);
+    log::debug!("closed: id={}", id);
+    Ok(())
+}
+
+/// Equivalent of `IPV6_V6ONLY`. Only IPv6 PCBs have it, and it cannot change once bound.
+pub fn tcp_set_v6only(id: usize, v6only: bool) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    if !pcb.local.is_ipv6() {
+        return Err(UtcpErr::NotSupported("IPV6_V6ONLY".into()));
+    }
+    if pcb.local.port != 0 {
+        return Err(UtcpErr::InvalidArgument("already bound".into()));
+    }
+    pcb.v6only = v6only;
+    Ok(())
+}
+
+pub fn tcp_v6only(id: usize) -> UtcpResult<bool> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    if !pcb.local.is_ipv6() {
+        return Err(UtcpErr::NotSupported("IPV6_V6ONLY".into()));
+    }
+    Ok(pcb.v6only)
+}
+
+pub fn tcp_local(id: usize) -> UtcpResult<IpEndpoint> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.local)
+}
+
+pub fn tcp_foreign(id: usize) -> UtcpResult<Option<IpEndpoint>> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    Ok(match pcb.state {
+        TcpState::Closed | TcpState::Listen => None,
+        _ => Some(pcb.foreign),
+    })
+}
+
+pub fn tcp_state(id: usize) -> UtcpResult<TcpState> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.state)
+}
+
+/// Returns the readiness of the PCB and its wakeup counter, for `utcp_poll`.
+pub fn tcp_poll(id: usize) -> UtcpResult<(PollEvents, u64)> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    Ok((pcb.poll_events(), pcb.wakeups))
+}
+
+/// Wakes `waker` once the PCB is ready for `interest`, fails or is closed. A connection being
+/// established becomes writable.
+pub fn tcp_register_waker(id: usize, interest: PollEvents, waker: &Waker) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    tcp_pcb_get(&mut pcbs, id)?.wakers.register(interest, waker);
+    Ok(())
+}
+
+/// Returns and clears the pending error of the connection, like `SO_ERROR`.
+pub fn tcp_take_error(id: usize) -> UtcpResult<Option<UtcpErr>> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.error.take().map(UtcpErr::from))
+}
+
+pub fn tcp_set_nonblocking(id: usize, nonblocking: bool) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    tcp_pcb_get(&mut pcbs, id)?.nonblocking = nonblocking;
+    Ok(())
+}
+
+/// Disables Nagle's algorithm when `nodelay` is true.
+pub fn tcp_set_nodelay(id: usize, nodelay: bool) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    let pcb = tcp_pcb_get(&mut pcbs, id)?;
+    pcb.nodelay = nodelay;
+    tcp_output(pcb);
+    Ok(())
+}
+
+pub fn tcp_nodelay(id: usize) -> UtcpResult<bool> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.nodelay)
+}
+
+pub fn tcp_set_recv_timeout(id: usize, timeout: Option<Duration>) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    tcp_pcb_get(&mut pcbs, id)?.recv_timeout = timeout;
+    Ok(())
+}
+
+pub fn tcp_set_send_timeout(id: usize, timeout: Option<Duration>) -> UtcpResult<()> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    tcp_pcb_get(&mut pcbs, id)?.send_timeout = timeout;
+    Ok(())
+}
+
+pub fn tcp_recv_timeout(id: usize) -> UtcpResult<Option<Duration>> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.recv_timeout)
+}
+
+pub fn tcp_send_timeout(id: usize) -> UtcpResult<Option<Duration>> {
+    let mut pcbs = TCP_PCBS.lock().unwrap();
+    Ok(tcp_pcb_get(&mut pcbs, id)?.send_timeout)
+}
+
+#[test]
+fn test_tcp_syn_received_timeout() {
+    let (local, remote) = (
+        IpAddress::parse_from("192.0.2.1"),
+        IpAddress::parse_from("192.0.2.2"),
+    );
+    let arrives = |src_port: u16, seq: u32, ack: u32, flags: TcpFlags| {
+        let mut buf = [0u8; TCP_HEADER_MIN_LEN];
+        let mut seg = TcpPacket::new_unchecked(&mut buf[..]);
+        seg.set_src_port(src_port);
+        seg.set_dst_port(80);
+        seg.set_seq(seq);
+        seg.set_ack(ack);
+        seg.set_header_len(TCP_HEADER_MIN_LEN);
+        seg.set_flags(flags);
+        seg.set_window(TCP_BUF_SIZE as u16);
+        seg.fill_checksum(remote, local);
+        tcp_segment_arrives(&buf, remote.into(), local.into());
+    };
+    let id = tcp_open().unwrap();
+    tcp_bind(id, IpEndpoint::new(local, 80)).unwrap();
+    tcp_listen(id, 2).unwrap();
+    tcp_set_nonblocking(id, true).unwrap();
+    let children = || {
+        let pcbs = TCP_PCBS.lock().unwrap();
+        pcbs.iter()
+            .enumerate()
+            .filter_map(|(child, pcb)| pcb.as_ref().map(|pcb| (child, pcb)))
+            .filter(|(_, pcb)| pcb.parent == Some(id))
+            .map(|(child, pcb)| (child, pcb.iss))
+            .collect::<Vec<_>>()
+    };
+
+    // the SYN-ACKs are never answered, and the SYN past the backlog is dropped
+    for port in [40000, 40001, 40002] {
+        arrives(port, 1000, 0, TcpFlags::SYN);
+    }
+    assert_eq!(children().len(), 2);
+
+    // the half-open connections give up and make room for new ones
+    for _ in 0..=TCP_RETRANSMIT_MAX {
+        let mut pcbs = TCP_PCBS.lock().unwrap();
+        tcp_timer_expire(&mut pcbs, Instant::now() + TCP_RTO_MAX, &mut Vec::new());
+    }
+    assert!(children().is_empty());
+    assert!(matches!(tcp_accept(id), Err(UtcpErr::WouldBlock)));
+
+    arrives(40003, 1000, 0, TcpFlags::SYN);
+    let [(child, iss)] = children()[..] else {
+        panic!("the SYN was dropped");
+    };
+    arrives(40003, 1001, iss.wrapping_add(1), TcpFlags::ACK);
+    assert_eq!(
+        tcp_accept(id).unwrap(),
+        (child, IpEndpoint::new(remote, 40003))
+    );
+    tcp_close(child).unwrap();
+    tcp_close(id).unwrap();
+}
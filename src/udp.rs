@@ -0,0 +1,459 @@
+use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    event::{self, SocketWakers},
+    igmp,
+    ip::{self, IP_ADDR_BROADCAST, IP_PROTOCOL_UDP, IpAddr, IpAddress, IpEndpoint, IpInterface},
+    ipv6::{self, Ipv6Address},
+    net::{NetDeviceHandler, NetInterfaceHandler},
+    poll::PollEvents,
+    utils::{self, BoundedQueue, DropPolicy, PushResult},
+    wire::udp::{UDP_HEADER_LEN, UdpPacket},
+};
+
+/// Number of datagrams a PCB can hold before new ones are dropped.
+pub const UDP_RECV_QUEUE_LIMIT: usize = 64;
+
+const UDP_SOURCE_PORT_MIN: u16 = 49152;
+const UDP_SOURCE_PORT_MAX: u16 = 65535;
+/// Random ports tried by `udp_bind_random` before it settles for the first free one.
+const UDP_RANDOM_PORT_ATTEMPTS: usize = 16;
+
+#[derive(Debug)]
+struct UdpPcb {
+    local: IpEndpoint,
+    /// Set by `udp_connect`. Only datagrams from this endpoint are received.
+    foreign: Option<IpEndpoint>,
+    nonblocking: bool,
+    recv_timeout: Option<Duration>,
+    queue: BoundedQueue<(IpEndpoint, Vec<u8>)>,
+    /// Counts queued datagrams, for edge-triggered polling.
+    wakeups: u64,
+    wakers: SocketWakers,
+    /// Set by `udp_set_reuse_addr`. PCBs that all set it can share a port.
+    reuse_addr: bool,
+    /// Set by `udp_set_v6only`. An IPv6 PCB bound to :: receives IPv4 datagrams unless set.
+    v6only: bool,
+    /// Groups joined with `udp_join_multicast`, left when the PCB is closed.
+    memberships: Vec<(NetDeviceHandler, IpAddress)>,
+}
+
+impl UdpPcb {
+    /// `local` and `foreign` are canonical, as received.
+    fn accepts(&self, local: IpEndpoint, foreign: IpEndpoint) -> bool {
+        self.local.port == local.port
+            && ip::ip_addr_covers(self.local.addr, self.v6only, local.addr)
+            && self.foreign.is_none_or(|f| f.to_canonical() == foreign)
+    }
+
+    fn membership(&self, dev: &NetDeviceHandler, group: IpAddress) -> Option<usize> {
+        self.memberships
+            .iter()
+            .position(|(d, g)| d.private == dev.private && *g == group)
+    }
+}
+
+static UDP_PCBS: Mutex<Vec<Option<UdpPcb>>> = Mutex::new(Vec::new());
+/// Notified when a datagram is queued or a PCB is closed.
+static UDP_COND: Condvar = Condvar::new();
+
+fn udp_pcb_get<'a>(
+    pcbs: &'a mut MutexGuard<'_, Vec<Option<UdpPcb>>>,
+    id: usize,
+) -> UtcpResult<&'a mut UdpPcb> {
+    pcbs.get_mut(id)
+        .and_then(|pcb| pcb.as_mut())
+        .ok_or(UtcpErr::BadDescriptor(id as i32))
+}
+
+fn udp_pcb_select(
+    pcbs: &mut [Option<UdpPcb>],
+    local: IpEndpoint,
+    foreign: IpEndpoint,
+) -> Option<&mut UdpPcb> {
+    pcbs.iter_mut()
+        .flatten()
+        .find(|pcb| pcb.accepts(local, foreign))
+}
+
+/// Returns true if `local` cannot be bound. A PCB with `reuse_addr` shares its port with
+/// others that set it too.
+fn udp_port_in_use(
+    pcbs: &[Option<UdpPcb>],
+    local: IpEndpoint,
+    reuse_addr: bool,
+    v6only: bool,
+) -> bool {
+    pcbs.iter().flatten().any(|pcb| {
+        pcb.local.port == local.port
+            && !(reuse_addr && pcb.reuse_addr)
+            && ip::ip_addr_overlaps(pcb.local.addr, pcb.v6only, local.addr, v6only)
+    })
+}
+
+fn udp_input(data: &[u8], src: IpAddress, dst: IpAddress, iface: &NetInterfaceHandler) {
+    let ip_iface: &IpInterface = iface.get().try_into().unwrap();
+    let broadcast = dst == IP_ADDR_BROADCAST || dst == ip_iface.broadcast();
+    udp_deliver(data, src.into(), dst.into(), broadcast);
+}
+
+fn udp_input_ipv6(data: &[u8], src: Ipv6Address, dst: Ipv6Address, _iface: &NetInterfaceHandler) {
+    udp_deliver(data, src.into(), dst.into(), false);
+}
+
+/// Queues a datagram received by either family on the PCBs it is addressed to.
+fn udp_deliver(data: &[u8], src: IpAddr, dst: IpAddr, broadcast: bool) {
+    let udp = match UdpPacket::new_checked(data) {
+        Ok(udp) => udp,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if !udp.verify_checksum(src, dst) {
+        log::error!("checksum mismatch: sum=0x{:04x}", udp.sum());
+        return;
+    }
+    log::debug!("{} => {}, {:?}", src, dst, udp);
+
+    let local = IpEndpoint::new(dst, udp.dst_port());
+    let foreign = IpEndpoint::new(src, udp.src_port());
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    // multicast and broadcast datagrams go to every PCB bound to the port
+    let targets: Vec<&mut UdpPcb> = match dst.is_multicast() || broadcast {
+        true => pcbs
+            .iter_mut()
+            .flatten()
+            .filter(|pcb| pcb.accepts(local, foreign))
+            .collect(),
+        false => udp_pcb_select(&mut pcbs, local, foreign)
+            .into_iter()
+            .collect(),
+    };
+    if targets.is_empty() {
+        // port is not in use
+        log::debug!("no PCB bound to {}", local);
+        return;
+    }
+    let mut woken = Vec::new();
+    for pcb in targets {
+        // IPv6 PCBs see IPv4 senders as IPv4-mapped addresses
+        let sender = foreign.in_family_of(&pcb.local);
+        match pcb.queue.push((sender, udp.payload().to_vec())) {
+            PushResult::Queued => {
+                pcb.wakeups += 1;
+                woken.extend(pcb.wakers.take(PollEvents::READABLE));
+            }
+            _ => log::warn!("receive queue full, dropped a datagram: local={}", local),
+        }
+    }
+    drop(pcbs);
+    UDP_COND.notify_all();
+    event::event_notify(woken);
+}
+
+/// Builds a UDP datagram and passes it to `ip_output` or `ipv6_output`, by the family of `dst`.
+/// IPv4-mapped addresses are sent as IPv4.
+pub fn udp_output(src: IpEndpoint, dst: IpEndpoint, data: &[u8]) -> UtcpResult<usize> {
+    let total = UDP_HEADER_LEN + data.len();
+    if total > u16::MAX as usize {
+        return Err(UtcpErr::MessageTooLong {
+            mtu: u16::MAX as usize,
+        });
+    }
+    let mut buf = vec![0u8; total];
+    let mut udp = UdpPacket::new_unchecked(&mut buf[..]);
+    udp.set_src_port(src.port);
+    udp.set_dst_port(dst.port);
+    udp.set_len(total as u16);
+    udp.payload_mut().copy_from_slice(data);
+    // the checksum needs the source address, which `ip_output` would otherwise choose
+    let (src_addr, dst_addr) = ip::ip_transport_addrs(src.addr, dst.addr)?;
+    udp.fill_checksum(src_addr, dst_addr);
+    log::debug!("{} => {}, {:?}", src, dst, udp);
+    ip::ip_transport_output(IP_PROTOCOL_UDP, &buf, src_addr, dst_addr)?;
+    Ok(data.len())
+}
+
+pub fn udp_init() -> UtcpResult<()> {
+    ip::ip_protocol_register(IP_PROTOCOL_UDP, udp_input)?;
+    ipv6::ipv6_protocol_register(IP_PROTOCOL_UDP, udp_input_ipv6)?;
+    log::info!("initialized");
+    Ok(())
+}
+
+/// Opens an IPv4 PCB.
+pub fn udp_open() -> UtcpResult<usize> {
+    udp_pcb_alloc(IpEndpoint::default())
+}
+
+/// Opens an IPv6 PCB. Bound to ::, it also sends and receives IPv4 datagrams, with IPv4-mapped
+/// addresses, unless `udp_set_v6only` is set.
+pub fn udp_open_ipv6() -> UtcpResult<usize> {
+    udp_pcb_alloc(IpEndpoint::new(Ipv6Address::UNSPECIFIED, 0))
+}
+
+/// Allocates a PCB whose unbound local endpoint is `local`, which sets its family.
+fn udp_pcb_alloc(local: IpEndpoint) -> UtcpResult<usize> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = UdpPcb {
+        local,
+        foreign: None,
+        nonblocking: false,
+        recv_timeout: None,
+        queue: BoundedQueue::new(UDP_RECV_QUEUE_LIMIT, DropPolicy::DropTail),
+        wakeups: 0,
+        wakers: SocketWakers::default(),
+        reuse_addr: false,
+        v6only: false,
+        memberships: Vec::new(),
+    };
+    let id = match pcbs.iter().position(|pcb| pcb.is_none()) {
+        Some(id) => {
+            pcbs[id] = Some(pcb);
+            id
+        }
+        None => {
+            pcbs.push(Some(pcb));
+            pcbs.len() - 1
+        }
+    };
+    log::debug!("opened: id={}", id);
+    Ok(id)
+}
+
+pub fn udp_close(id: usize) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    for (dev, group) in pcb.memberships.drain(..) {
+        if let Err(e) = igmp::igmp_leave(dev, group) {
+            log::error!("failed to leave {}: {}", group, e);
+        }
+    }
+    let woken = pcb.wakers.take(PollEvents::INVALID);
+    pcbs[id] = None;
+    drop(pcbs);
+    UDP_COND.notify_all();
+    event::event_notify(woken);
+    log::debug!("closed: id={}", id);
+    Ok(())
+}
+
+/// Binds the PCB to a local endpoint of its family. Port 0 picks an ephemeral port.
+pub fn udp_bind(id: usize, mut local: IpEndpoint) -> UtcpResult<IpEndpoint> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    if pcb.local.port != 0 {
+        return Err(UtcpErr::InvalidArgument("already bound".into()));
+    }
+    if local.is_ipv6() != pcb.local.is_ipv6() {
+        return Err(UtcpErr::AddrFamilyNotSupported);
+    }
+    let (reuse_addr, v6only) = (pcb.reuse_addr, pcb.v6only);
+    if local.port == 0 {
+        // ephemeral ports are never shared
+        local.port = (UDP_SOURCE_PORT_MIN..=UDP_SOURCE_PORT_MAX)
+            .find(|&port| !udp_port_in_use(&pcbs, IpEndpoint::new(local.addr, port), false, v6only))
+            .ok_or(UtcpErr::AddrInUse)?;
+    } else if udp_port_in_use(&pcbs, local, reuse_addr, v6only) {
+        return Err(UtcpErr::AddrInUse);
+    }
+    udp_pcb_get(&mut pcbs, id)?.local = local;
+    log::debug!("bound: id={}, local={}", id, local);
+    Ok(local)
+}
+
+/// Binds the PCB to a random ephemeral port on `addr`, so that the port of a query cannot be
+/// predicted from the previous one (RFC 5452).
+pub(crate) fn udp_bind_random(id: usize, addr: IpAddr) -> UtcpResult<IpEndpoint> {
+    let mut random = [0u8; 2 * UDP_RANDOM_PORT_ATTEMPTS];
+    utils::getrandom(&mut random)?;
+    let range = (UDP_SOURCE_PORT_MAX - UDP_SOURCE_PORT_MIN) as u32 + 1;
+    for bytes in random.chunks_exact(2) {
+        let offset = u16::from_ne_bytes([bytes[0], bytes[1]]) as u32 % range;
+        let port = UDP_SOURCE_PORT_MIN + offset as u16;
+        match udp_bind(id, IpEndpoint::new(addr, port)) {
+            Err(UtcpErr::AddrInUse) => continue,
+            result => return result,
+        }
+    }
+    // the range is crowded, take whatever is free
+    udp_bind(id, IpEndpoint::new(addr, 0))
+}
+
+/// Sets the default destination and only receives datagrams from it.
+pub fn udp_connect(id: usize, foreign: IpEndpoint) -> UtcpResult<()> {
+    let local = udp_local_bound(id, foreign)?;
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    udp_pcb_get(&mut pcbs, id)?.foreign = Some(foreign.in_family_of(&local));
+    Ok(())
+}
+
+/// Returns the local endpoint to send to `foreign` from, binding an ephemeral port of the
+/// family of the PCB if it is not bound yet.
+fn udp_local_bound(id: usize, foreign: IpEndpoint) -> UtcpResult<IpEndpoint> {
+    let local = udp_local(id)?;
+    if udp_v6only(id).unwrap_or(false) && foreign.addr.to_ipv4().is_some() {
+        return Err(UtcpErr::NetUnreachable);
+    }
+    match local.port {
+        0 => udp_bind(id, IpEndpoint::new(local.addr, 0)),
+        _ => Ok(local),
+    }
+}
+
+pub fn udp_local(id: usize) -> UtcpResult<IpEndpoint> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    Ok(udp_pcb_get(&mut pcbs, id)?.local)
+}
+
+pub fn udp_foreign(id: usize) -> UtcpResult<Option<IpEndpoint>> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    Ok(udp_pcb_get(&mut pcbs, id)?.foreign)
+}
+
+pub fn udp_set_nonblocking(id: usize, nonblocking: bool) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    udp_pcb_get(&mut pcbs, id)?.nonblocking = nonblocking;
+    Ok(())
+}
+
+pub fn udp_set_recv_timeout(id: usize, timeout: Option<Duration>) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    udp_pcb_get(&mut pcbs, id)?.recv_timeout = timeout;
+    Ok(())
+}
+
+pub fn udp_recv_timeout(id: usize) -> UtcpResult<Option<Duration>> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    Ok(udp_pcb_get(&mut pcbs, id)?.recv_timeout)
+}
+
+/// Equivalent of `SO_REUSEADDR`. Takes effect on the next bind.
+pub fn udp_set_reuse_addr(id: usize, reuse_addr: bool) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    udp_pcb_get(&mut pcbs, id)?.reuse_addr = reuse_addr;
+    Ok(())
+}
+
+pub fn udp_reuse_addr(id: usize) -> UtcpResult<bool> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    Ok(udp_pcb_get(&mut pcbs, id)?.reuse_addr)
+}
+
+/// Equivalent of `IPV6_V6ONLY`. Only IPv6 PCBs have it, and it cannot change once bound.
+pub fn udp_set_v6only(id: usize, v6only: bool) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    if !pcb.local.is_ipv6() {
+        return Err(UtcpErr::NotSupported("IPV6_V6ONLY".into()));
+    }
+    if pcb.local.port != 0 {
+        return Err(UtcpErr::InvalidArgument("already bound".into()));
+    }
+    pcb.v6only = v6only;
+    Ok(())
+}
+
+pub fn udp_v6only(id: usize) -> UtcpResult<bool> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    if !pcb.local.is_ipv6() {
+        return Err(UtcpErr::NotSupported("IPV6_V6ONLY".into()));
+    }
+    Ok(pcb.v6only)
+}
+
+/// Joins `group` on the interface with the address `iface_addr`, or on the interface that
+/// multicast is sent from if it is `IP_ADDR_ANY`. The equivalent of `IP_ADD_MEMBERSHIP`.
+pub fn udp_join_multicast(id: usize, group: IpAddress, iface_addr: IpAddress) -> UtcpResult<()> {
+    let dev = ip::ip_multicast_dev(iface_addr)?;
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    if pcb.membership(&dev, group).is_some() {
+        return Err(UtcpErr::AddrInUse);
+    }
+    igmp::igmp_join(dev, group)?;
+    pcb.memberships.push((dev, group));
+    Ok(())
+}
+
+/// Leaves a group joined by `udp_join_multicast`. The equivalent of `IP_DROP_MEMBERSHIP`.
+pub fn udp_leave_multicast(id: usize, group: IpAddress, iface_addr: IpAddress) -> UtcpResult<()> {
+    let dev = ip::ip_multicast_dev(iface_addr)?;
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    let pos = pcb
+        .membership(&dev, group)
+        .ok_or(UtcpErr::AddrNotAvailable)?;
+    pcb.memberships.remove(pos);
+    igmp::igmp_leave(dev, group)
+}
+
+pub fn udp_sendto(id: usize, data: &[u8], foreign: IpEndpoint) -> UtcpResult<usize> {
+    let local = udp_local_bound(id, foreign)?;
+    udp_output(local, foreign, data)
+}
+
+pub fn udp_send(id: usize, data: &[u8]) -> UtcpResult<usize> {
+    let foreign = udp_foreign(id)?.ok_or(UtcpErr::NotConnected)?;
+    udp_sendto(id, data, foreign)
+}
+
+/// Receives a datagram. The datagram is truncated if `buf` is too small. Returns the copied
+/// length and the sender.
+pub fn udp_recvfrom(id: usize, buf: &mut [u8]) -> UtcpResult<(usize, IpEndpoint)> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let timeout = udp_pcb_get(&mut pcbs, id)?.recv_timeout;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (foreign, data) = loop {
+        let pcb = udp_pcb_get(&mut pcbs, id)?;
+        if let Some(entry) = pcb.queue.pop_front() {
+            break entry;
+        }
+        if pcb.nonblocking {
+            return Err(UtcpErr::WouldBlock);
+        }
+        pcbs = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(UtcpErr::WouldBlock);
+                }
+                UDP_COND.wait_timeout(pcbs, deadline - now).unwrap().0
+            }
+            None => UDP_COND.wait(pcbs).unwrap(),
+        };
+    };
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    Ok((len, foreign))
+}
+
+/// Returns true if a datagram can be received without blocking.
+pub fn udp_readable(id: usize) -> UtcpResult<bool> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    Ok(!udp_pcb_get(&mut pcbs, id)?.queue.is_empty())
+}
+
+/// Returns the readiness of the PCB and its wakeup counter, for `utcp_poll`. Sending never
+/// blocks.
+pub fn udp_poll(id: usize) -> UtcpResult<(PollEvents, u64)> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    let pcb = udp_pcb_get(&mut pcbs, id)?;
+    let mut events = PollEvents::WRITABLE;
+    events.set(PollEvents::READABLE, !pcb.queue.is_empty());
+    Ok((events, pcb.wakeups))
+}
+
+/// Wakes `waker` once the PCB is ready for `interest` or closed.
+pub fn udp_register_waker(id: usize, interest: PollEvents, waker: &Waker) -> UtcpResult<()> {
+    let mut pcbs = UDP_PCBS.lock().unwrap();
+    udp_pcb_get(&mut pcbs, id)?.wakers.register(interest, waker);
+    Ok(())
+}
@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+
+use crate::error::UtcpResult;
+
 /// Fixed-length queue that drops the oldest element when it is full.
 #[derive(Debug)]
 pub struct SmallQueue<T, const N: usize> {
@@ -106,3 +110,371 @@ pub fn checksum16(mut data: &[u8], init: u32) -> u16 {
     }
     !(sum as u16)
 }
+
+/// Updates a checksum computed by `checksum16` after the bytes `old` of the data it covers were
+/// replaced with `new`, without summing the rest again (RFC 1624). Both have to be of the same
+/// even length and start at an even offset.
+pub fn checksum16_adjust(sum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let words = |data: &[u8]| {
+        data.chunks(2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]]) as u32)
+            .collect::<Vec<_>>()
+    };
+    // HC' = ~(~HC + ~m + m')
+    let mut acc = !sum as u32;
+    for word in words(old) {
+        acc += !word as u16 as u32;
+    }
+    for word in words(new) {
+        acc += word;
+    }
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+#[test]
+fn test_checksum16_adjust() {
+    let mut data = [0x45, 0x00, 0x00, 0x1c, 0x12, 0x34, 0x0a, 0x63, 0x2e, 0x02];
+    let sum = checksum16(&data, 0);
+    data[6..10].copy_from_slice(&[0xc0, 0xa8, 0x01, 0xfe]);
+    let adjusted = checksum16_adjust(sum, &[0x0a, 0x63, 0x2e, 0x02], &data[6..10]);
+    assert_eq!(adjusted, checksum16(&data, 0));
+}
+
+/// Policy applied by [`BoundedQueue`] when an element arrives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    /// Drop the arriving element when the queue is full.
+    DropTail,
+    /// Drop the oldest element to make room for the arriving one.
+    DropHead,
+    /// Random Early Detection: drop arriving elements with a probability that grows linearly
+    /// from 0 to `max_p` while the average queue length is between `min_th` and `max_th`,
+    /// and drop all of them above `max_th` (or when the queue is full).
+    Red {
+        min_th: usize,
+        max_th: usize,
+        max_p: f64,
+    },
+}
+
+/// Counters kept by [`BoundedQueue`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub enqueued: u64,
+    pub dequeued: u64,
+    pub dropped: u64,
+}
+
+/// Result of [`BoundedQueue::push`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushResult<T> {
+    /// The element was queued.
+    Queued,
+    /// The element was queued and the oldest element was dropped to make room for it.
+    DroppedHead(T),
+    /// The element was dropped and not queued.
+    Dropped(T),
+}
+
+/// Queue with a configurable length limit and drop policy.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    buf: VecDeque<T>,
+    limit: usize,
+    policy: DropPolicy,
+    stats: QueueStats,
+    /// Average queue length used by RED (exponentially weighted moving average).
+    avg: f64,
+    rng: XorShift32,
+}
+
+impl<T> BoundedQueue<T> {
+    /// Weight of the current queue length in the RED average.
+    const RED_WEIGHT: f64 = 0.002;
+
+    pub fn new(limit: usize, policy: DropPolicy) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            limit,
+            policy,
+            stats: QueueStats::default(),
+            avg: 0.0,
+            rng: XorShift32::new(0x2545_f491),
+        }
+    }
+
+    pub fn push(&mut self, elem: T) -> PushResult<T> {
+        let result = match self.policy {
+            DropPolicy::DropTail => {
+                if self.is_full() {
+                    PushResult::Dropped(elem)
+                } else {
+                    self.buf.push_back(elem);
+                    PushResult::Queued
+                }
+            }
+            DropPolicy::DropHead => {
+                let head = if self.is_full() {
+                    self.buf.pop_front()
+                } else {
+                    None
+                };
+                // a zero-length queue keeps nothing
+                if self.limit == 0 {
+                    PushResult::Dropped(elem)
+                } else {
+                    self.buf.push_back(elem);
+                    match head {
+                        Some(head) => PushResult::DroppedHead(head),
+                        None => PushResult::Queued,
+                    }
+                }
+            }
+            DropPolicy::Red {
+                min_th,
+                max_th,
+                max_p,
+            } => {
+                self.avg =
+                    (1.0 - Self::RED_WEIGHT) * self.avg + Self::RED_WEIGHT * self.buf.len() as f64;
+                let drop = if self.is_full() || self.avg >= max_th as f64 {
+                    true
+                } else if self.avg < min_th as f64 {
+                    false
+                } else {
+                    let p = max_p * (self.avg - min_th as f64) / (max_th - min_th) as f64;
+                    self.rng.next_f64() < p
+                };
+                if drop {
+                    PushResult::Dropped(elem)
+                } else {
+                    self.buf.push_back(elem);
+                    PushResult::Queued
+                }
+            }
+        };
+        match result {
+            PushResult::Queued => self.stats.enqueued += 1,
+            PushResult::DroppedHead(_) => {
+                self.stats.enqueued += 1;
+                self.stats.dropped += 1;
+            }
+            PushResult::Dropped(_) => self.stats.dropped += 1,
+        }
+        result
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let elem = self.buf.pop_front()?;
+        self.stats.dequeued += 1;
+        Some(elem)
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.buf.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.buf.len() >= self.limit
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    /// Changes the limit and the policy. Elements beyond the new limit are kept until they are
+    /// popped.
+    pub fn set_limit(&mut self, limit: usize, policy: DropPolicy) {
+        self.limit = limit;
+        self.policy = policy;
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+}
+
+#[test]
+fn test_bounded_queue_drop_tail() {
+    let mut q = BoundedQueue::new(2, DropPolicy::DropTail);
+    assert_eq!(q.push(1), PushResult::Queued);
+    assert_eq!(q.push(2), PushResult::Queued);
+    assert!(q.is_full());
+    assert_eq!(q.push(3), PushResult::Dropped(3));
+    assert_eq!(q.pop_front(), Some(1));
+    assert_eq!(q.push(4), PushResult::Queued);
+    assert_eq!(q.pop_front(), Some(2));
+    assert_eq!(q.pop_front(), Some(4));
+    assert_eq!(q.pop_front(), None);
+    assert_eq!(
+        q.stats(),
+        QueueStats {
+            enqueued: 3,
+            dequeued: 3,
+            dropped: 1
+        }
+    );
+}
+
+#[test]
+fn test_bounded_queue_drop_head() {
+    let mut q = BoundedQueue::new(2, DropPolicy::DropHead);
+    q.push(1);
+    q.push(2);
+    assert_eq!(q.push(3), PushResult::DroppedHead(1));
+    assert_eq!(q.pop_front(), Some(2));
+    assert_eq!(q.pop_front(), Some(3));
+    assert_eq!(q.stats().dropped, 1);
+
+    let mut q = BoundedQueue::new(0, DropPolicy::DropHead);
+    assert_eq!(q.push(1), PushResult::Dropped(1));
+}
+
+#[test]
+fn test_bounded_queue_red() {
+    let policy = DropPolicy::Red {
+        min_th: 4,
+        max_th: 8,
+        max_p: 0.5,
+    };
+    let mut q = BoundedQueue::new(16, policy);
+    // the average stays below min_th for a short burst, so nothing is dropped
+    for i in 0..16 {
+        assert_eq!(q.push(i), PushResult::Queued);
+    }
+    // the queue is full
+    assert_eq!(q.push(16), PushResult::Dropped(16));
+
+    // a standing queue drives the average above max_th
+    for _ in 0..10000 {
+        q.push(0);
+    }
+    for _ in 0..4 {
+        q.pop_front();
+    }
+    assert!(!q.is_full());
+    assert_eq!(q.push(0), PushResult::Dropped(0));
+}
+
+/// Small xorshift PRNG. Not suitable for anything security related.
+#[derive(Debug, Clone)]
+pub struct XorShift32(u32);
+
+impl XorShift32 {
+    pub fn new(seed: u32) -> Self {
+        Self(if seed == 0 { 1 } else { seed })
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Returns a value in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u32() as f64 / (u32::MAX as f64 + 1.0)
+    }
+}
+
+/// Fills `buf` from the random source of the kernel, for values that an off-path attacker
+/// must not guess, such as DNS query IDs.
+pub fn getrandom(buf: &mut [u8]) -> UtcpResult<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let len = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        filled += len as usize;
+    }
+    Ok(())
+}
+
+/// SipHash-2-4 of `data` with a 128-bit key, a keyed hash that is stable across builds,
+/// unlike `std::hash::DefaultHasher`.
+pub fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+    let compress = |v: &mut [u64; 4], m: u64| {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    // the last block holds the remaining bytes and the length
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[test]
+fn test_siphash24() {
+    // vectors of the reference implementation
+    let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+    assert_eq!(siphash24(&key, &[]), 0x726f_db47_dd0e_0e31);
+    let data: Vec<u8> = (0..15).collect();
+    assert_eq!(siphash24(&key, &data), 0xa129_ca61_49be_45e5);
+    let data: Vec<u8> = (0..16).collect();
+    assert_eq!(siphash24(&key, &data), 0x3f2a_cc7f_57c2_9bdb);
+}
+
+#[test]
+fn test_getrandom() {
+    let (mut a, mut b) = ([0u8; 16], [0u8; 16]);
+    getrandom(&mut a).unwrap();
+    getrandom(&mut b).unwrap();
+    assert_ne!(a, b);
+    getrandom(&mut []).unwrap();
+}
@@ -0,0 +1,130 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+};
+
+use super::{
+    ethernet::{ETHERNET_ADDR_LEN, EthernetAddress},
+    read_u16, write_u16,
+};
+
+mod field {
+    pub const HTYPE: usize = 0;
+    pub const PTYPE: usize = 2;
+    pub const HLEN: usize = 4;
+    pub const PLEN: usize = 5;
+    pub const OPER: usize = 6;
+    pub const SHA: usize = 8;
+    pub const SPA: usize = 14;
+    pub const THA: usize = 18;
+    pub const TPA: usize = 24;
+}
+
+pub const ARP_HRD_ETHER: u16 = 0x0001;
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+/// Length of an Ethernet/IPv4 ARP message.
+pub const ARP_ETHER_IP_LEN: usize = 28;
+
+/// View of an ARP message. Only Ethernet/IPv4 messages are accepted by `new_checked`.
+pub struct ArpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> ArpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let buf = packet.buffer.as_ref();
+        if buf.len() < ARP_ETHER_IP_LEN {
+            return Err(UtcpErr::Malformed("ARP message is too short".into()));
+        }
+        if buf[field::HLEN] as usize != ETHERNET_ADDR_LEN || buf[field::PLEN] != 4 {
+            return Err(UtcpErr::Malformed(format!(
+                "unsupported ARP address length: hlen={}, plen={}",
+                buf[field::HLEN],
+                buf[field::PLEN]
+            )));
+        }
+        Ok(packet)
+    }
+
+    pub fn hardware_type(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::HTYPE)
+    }
+
+    pub fn protocol_type(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::PTYPE)
+    }
+
+    pub fn operation(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::OPER)
+    }
+
+    pub fn sender_hw_addr(&self) -> EthernetAddress {
+        EthernetAddress::from_bytes(&self.buffer.as_ref()[field::SHA..field::SPA]).unwrap()
+    }
+
+    pub fn sender_proto_addr(&self) -> IpAddress {
+        let b = &self.buffer.as_ref()[field::SPA..field::THA];
+        IpAddress::from([b[0], b[1], b[2], b[3]])
+    }
+
+    pub fn target_hw_addr(&self) -> EthernetAddress {
+        EthernetAddress::from_bytes(&self.buffer.as_ref()[field::THA..field::TPA]).unwrap()
+    }
+
+    pub fn target_proto_addr(&self) -> IpAddress {
+        let b = &self.buffer.as_ref()[field::TPA..ARP_ETHER_IP_LEN];
+        IpAddress::from([b[0], b[1], b[2], b[3]])
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> ArpPacket<T> {
+    /// Fills the fixed part of an Ethernet/IPv4 message.
+    pub fn set_ether_ip(&mut self, protocol_type: u16) {
+        let buf = self.buffer.as_mut();
+        write_u16(buf, field::HTYPE, ARP_HRD_ETHER);
+        write_u16(buf, field::PTYPE, protocol_type);
+        buf[field::HLEN] = ETHERNET_ADDR_LEN as u8;
+        buf[field::PLEN] = 4;
+    }
+
+    pub fn set_operation(&mut self, op: u16) {
+        write_u16(self.buffer.as_mut(), field::OPER, op);
+    }
+
+    pub fn set_sender_hw_addr(&mut self, addr: EthernetAddress) {
+        self.buffer.as_mut()[field::SHA..field::SPA].copy_from_slice(&addr.0);
+    }
+
+    pub fn set_sender_proto_addr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::SPA..field::THA].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_target_hw_addr(&mut self, addr: EthernetAddress) {
+        self.buffer.as_mut()[field::THA..field::TPA].copy_from_slice(&addr.0);
+    }
+
+    pub fn set_target_proto_addr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::TPA..ARP_ETHER_IP_LEN].copy_from_slice(&addr.octets());
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for ArpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "op={}, sha={}, spa={}, tha={}, tpa={}",
+            self.operation(),
+            self.sender_hw_addr(),
+            self.sender_proto_addr(),
+            self.target_hw_addr(),
+            self.target_proto_addr()
+        )
+    }
+}
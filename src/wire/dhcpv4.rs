@@ -0,0 +1,346 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+};
+
+use super::{
+    ethernet::{ETHERNET_ADDR_LEN, EthernetAddress},
+    read_u16, read_u32, write_u16, write_u32,
+};
+
+mod field {
+    pub const OP: usize = 0;
+    pub const HTYPE: usize = 1;
+    pub const HLEN: usize = 2;
+    pub const HOPS: usize = 3;
+    pub const XID: usize = 4;
+    pub const SECS: usize = 8;
+    pub const FLAGS: usize = 10;
+    pub const CIADDR: usize = 12;
+    pub const YIADDR: usize = 16;
+    pub const SIADDR: usize = 20;
+    pub const GIADDR: usize = 24;
+    pub const CHADDR: usize = 28;
+    pub const MAGIC: usize = 236;
+}
+
+/// Length of the fixed part, up to and including the magic cookie.
+pub const DHCP_HEADER_LEN: usize = 240;
+/// Messages are padded to the minimum length of a BOOTP message, which some servers and
+/// relays still require.
+pub const DHCP_MESSAGE_MIN_LEN: usize = 300;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+pub const DHCP_OP_REQUEST: u8 = 1;
+pub const DHCP_OP_REPLY: u8 = 2;
+pub const DHCP_HTYPE_ETHER: u8 = 1;
+/// Asks the server to broadcast its replies, since the client can not receive unicast
+/// datagrams before it has an address.
+pub const DHCP_FLAG_BROADCAST: u16 = 0x8000;
+const DHCP_MAGIC: u32 = 0x63825363;
+
+pub const DHCP_DISCOVER: u8 = 1;
+pub const DHCP_OFFER: u8 = 2;
+pub const DHCP_REQUEST: u8 = 3;
+pub const DHCP_DECLINE: u8 = 4;
+pub const DHCP_ACK: u8 = 5;
+pub const DHCP_NAK: u8 = 6;
+pub const DHCP_RELEASE: u8 = 7;
+pub const DHCP_INFORM: u8 = 8;
+
+pub const DHCP_OPT_PAD: u8 = 0;
+pub const DHCP_OPT_SUBNET_MASK: u8 = 1;
+pub const DHCP_OPT_ROUTER: u8 = 3;
+pub const DHCP_OPT_DNS_SERVER: u8 = 6;
+pub const DHCP_OPT_HOST_NAME: u8 = 12;
+pub const DHCP_OPT_DOMAIN_NAME: u8 = 15;
+pub const DHCP_OPT_INTERFACE_MTU: u8 = 26;
+pub const DHCP_OPT_BROADCAST: u8 = 28;
+pub const DHCP_OPT_REQUESTED_IP: u8 = 50;
+pub const DHCP_OPT_LEASE_TIME: u8 = 51;
+pub const DHCP_OPT_MESSAGE_TYPE: u8 = 53;
+pub const DHCP_OPT_SERVER_ID: u8 = 54;
+pub const DHCP_OPT_PARAMETER_LIST: u8 = 55;
+pub const DHCP_OPT_RENEWAL_TIME: u8 = 58;
+pub const DHCP_OPT_REBINDING_TIME: u8 = 59;
+pub const DHCP_OPT_CLIENT_ID: u8 = 61;
+pub const DHCP_OPT_END: u8 = 255;
+
+/// View of a DHCP message (RFC 2131) carried over Ethernet.
+pub struct DhcpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> DhcpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let buf = packet.buffer.as_ref();
+        if buf.len() < DHCP_HEADER_LEN {
+            return Err(UtcpErr::Malformed("DHCP message is too short".into()));
+        }
+        if read_u32(buf, field::MAGIC) != DHCP_MAGIC {
+            return Err(UtcpErr::Malformed("DHCP magic cookie mismatch".into()));
+        }
+        if buf[field::HTYPE] != DHCP_HTYPE_ETHER || buf[field::HLEN] as usize != ETHERNET_ADDR_LEN {
+            return Err(UtcpErr::Malformed(format!(
+                "unsupported DHCP hardware type: htype={}, hlen={}",
+                buf[field::HTYPE],
+                buf[field::HLEN]
+            )));
+        }
+        Ok(packet)
+    }
+
+    pub fn op(&self) -> u8 {
+        self.buffer.as_ref()[field::OP]
+    }
+
+    pub fn xid(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::XID)
+    }
+
+    pub fn secs(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SECS)
+    }
+
+    pub fn flags(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::FLAGS)
+    }
+
+    fn addr(&self, off: usize) -> IpAddress {
+        IpAddress::from(read_u32(self.buffer.as_ref(), off))
+    }
+
+    /// Address of a client that already has one (renewing, rebinding or releasing).
+    pub fn ciaddr(&self) -> IpAddress {
+        self.addr(field::CIADDR)
+    }
+
+    /// Address offered to or assigned to the client.
+    pub fn yiaddr(&self) -> IpAddress {
+        self.addr(field::YIADDR)
+    }
+
+    pub fn siaddr(&self) -> IpAddress {
+        self.addr(field::SIADDR)
+    }
+
+    pub fn giaddr(&self) -> IpAddress {
+        self.addr(field::GIADDR)
+    }
+
+    pub fn chaddr(&self) -> EthernetAddress {
+        let buf = self.buffer.as_ref();
+        EthernetAddress::from_bytes(&buf[field::CHADDR..field::CHADDR + ETHERNET_ADDR_LEN]).unwrap()
+    }
+
+    /// Iterates over the options as `(code, data)`. Stops at the end option or at the first
+    /// option that does not fit in the message.
+    pub fn options(&self) -> DhcpOptions<'_> {
+        DhcpOptions {
+            buf: &self.buffer.as_ref()[DHCP_HEADER_LEN..],
+        }
+    }
+
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| data)
+    }
+
+    pub fn option_addr(&self, code: u8) -> Option<IpAddress> {
+        self.option_addrs(code).next()
+    }
+
+    /// Addresses of an option holding a list of them, such as `DHCP_OPT_DNS_SERVER`.
+    pub fn option_addrs(&self, code: u8) -> impl Iterator<Item = IpAddress> + '_ {
+        self.option(code)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|b| IpAddress::from([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn option_u32(&self, code: u8) -> Option<u32> {
+        let data = self.option(code)?;
+        Some(read_u32(data.get(..4)?, 0))
+    }
+
+    pub fn message_type(&self) -> Option<u8> {
+        self.option(DHCP_OPT_MESSAGE_TYPE)?.first().copied()
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> DhcpPacket<T> {
+    /// Fills the fixed part of a message over Ethernet with zero addresses. The options follow
+    /// the magic cookie and can be written with `DhcpOptionWriter`.
+    pub fn set_header(&mut self, op: u8, xid: u32, chaddr: EthernetAddress) {
+        let buf = self.buffer.as_mut();
+        buf[..DHCP_HEADER_LEN].fill(0);
+        buf[field::OP] = op;
+        buf[field::HTYPE] = DHCP_HTYPE_ETHER;
+        buf[field::HLEN] = ETHERNET_ADDR_LEN as u8;
+        buf[field::HOPS] = 0;
+        write_u32(buf, field::XID, xid);
+        buf[field::CHADDR..field::CHADDR + ETHERNET_ADDR_LEN].copy_from_slice(&chaddr.0);
+        write_u32(buf, field::MAGIC, DHCP_MAGIC);
+    }
+
+    pub fn set_secs(&mut self, secs: u16) {
+        write_u16(self.buffer.as_mut(), field::SECS, secs);
+    }
+
+    pub fn set_flags(&mut self, flags: u16) {
+        write_u16(self.buffer.as_mut(), field::FLAGS, flags);
+    }
+
+    pub fn set_ciaddr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::CIADDR..field::CIADDR + 4].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_yiaddr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::YIADDR..field::YIADDR + 4].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_siaddr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::SIADDR..field::SIADDR + 4].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_giaddr(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::GIADDR..field::GIADDR + 4].copy_from_slice(&addr.octets());
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for DhcpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "op={}, type={:?}, xid=0x{:08x}, ciaddr={}, yiaddr={}, chaddr={}",
+            self.op(),
+            self.message_type(),
+            self.xid(),
+            self.ciaddr(),
+            self.yiaddr(),
+            self.chaddr()
+        )
+    }
+}
+
+pub struct DhcpOptions<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for DhcpOptions<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&code, rest) = self.buf.split_first()?;
+            match code {
+                DHCP_OPT_PAD => self.buf = rest,
+                DHCP_OPT_END => {
+                    self.buf = &[];
+                    return None;
+                }
+                _ => {
+                    let (&len, rest) = rest.split_first()?;
+                    let Some((data, rest)) = rest.split_at_checked(len as usize) else {
+                        self.buf = &[];
+                        return None;
+                    };
+                    self.buf = rest;
+                    return Some((code, data));
+                }
+            }
+        }
+    }
+}
+
+/// Appends options to a message whose fixed part is already in `buf`.
+pub struct DhcpOptionWriter<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+impl<'a> DhcpOptionWriter<'a> {
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        buf.truncate(DHCP_HEADER_LEN);
+        Self { buf }
+    }
+
+    /// Options longer than 255 bytes are truncated.
+    pub fn option(&mut self, code: u8, data: &[u8]) -> &mut Self {
+        let data = &data[..data.len().min(u8::MAX as usize)];
+        self.buf.push(code);
+        self.buf.push(data.len() as u8);
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    pub fn option_u8(&mut self, code: u8, value: u8) -> &mut Self {
+        self.option(code, &[value])
+    }
+
+    pub fn option_u32(&mut self, code: u8, value: u32) -> &mut Self {
+        self.option(code, &value.to_be_bytes())
+    }
+
+    pub fn option_addrs(&mut self, code: u8, addrs: &[IpAddress]) -> &mut Self {
+        let data: Vec<u8> = addrs.iter().flat_map(|addr| addr.octets()).collect();
+        self.option(code, &data)
+    }
+
+    /// Writes the end option and pads the message to `DHCP_MESSAGE_MIN_LEN`.
+    pub fn finish(&mut self) {
+        self.buf.push(DHCP_OPT_END);
+        if self.buf.len() < DHCP_MESSAGE_MIN_LEN {
+            self.buf.resize(DHCP_MESSAGE_MIN_LEN, DHCP_OPT_PAD);
+        }
+    }
+}
+
+#[test]
+fn test_dhcp_packet() {
+    let chaddr = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+    let server = IpAddress::parse_from("192.0.2.1");
+    let mut buf = vec![0u8; DHCP_HEADER_LEN];
+    let mut packet = DhcpPacket::new_unchecked(&mut buf[..]);
+    packet.set_header(DHCP_OP_REPLY, 0x12345678, chaddr);
+    packet.set_flags(DHCP_FLAG_BROADCAST);
+    packet.set_yiaddr(IpAddress::parse_from("192.0.2.10"));
+    DhcpOptionWriter::new(&mut buf)
+        .option_u8(DHCP_OPT_MESSAGE_TYPE, DHCP_OFFER)
+        .option_addrs(DHCP_OPT_SERVER_ID, &[server])
+        .option_addrs(
+            DHCP_OPT_DNS_SERVER,
+            &[server, IpAddress::parse_from("192.0.2.2")],
+        )
+        .option_u32(DHCP_OPT_LEASE_TIME, 3600)
+        .finish();
+    assert_eq!(buf.len(), DHCP_MESSAGE_MIN_LEN);
+
+    let packet = DhcpPacket::new_checked(&buf[..]).unwrap();
+    assert_eq!(packet.op(), DHCP_OP_REPLY);
+    assert_eq!(packet.xid(), 0x12345678);
+    assert_eq!(packet.flags(), DHCP_FLAG_BROADCAST);
+    assert_eq!(packet.chaddr(), chaddr);
+    assert_eq!(packet.yiaddr(), IpAddress::parse_from("192.0.2.10"));
+    assert_eq!(packet.message_type(), Some(DHCP_OFFER));
+    assert_eq!(packet.option_addr(DHCP_OPT_SERVER_ID), Some(server));
+    assert_eq!(packet.option_addrs(DHCP_OPT_DNS_SERVER).count(), 2);
+    assert_eq!(packet.option_u32(DHCP_OPT_LEASE_TIME), Some(3600));
+    assert_eq!(packet.option(DHCP_OPT_ROUTER), None);
+
+    // an option running past the end of the message ends the iteration
+    let mut truncated = buf[..DHCP_HEADER_LEN].to_vec();
+    truncated.extend_from_slice(&[DHCP_OPT_MESSAGE_TYPE, 1, DHCP_ACK, DHCP_OPT_ROUTER, 4, 192]);
+    let packet = DhcpPacket::new_checked(&truncated[..]).unwrap();
+    assert_eq!(packet.options().count(), 1);
+
+    buf[236] = 0;
+    assert!(DhcpPacket::new_checked(&buf[..]).is_err());
+}
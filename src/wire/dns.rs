@@ -0,0 +1,439 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+};
+
+use super::{read_u16, read_u32, write_u16};
+
+mod field {
+    pub const ID: usize = 0;
+    pub const FLAGS: usize = 2;
+    pub const QDCOUNT: usize = 4;
+    pub const ANCOUNT: usize = 6;
+    pub const NSCOUNT: usize = 8;
+    pub const ARCOUNT: usize = 10;
+}
+
+pub const DNS_HEADER_LEN: usize = 12;
+pub const DNS_PORT: u16 = 53;
+/// Largest message carried over UDP without EDNS (RFC 1035 4.2.1). Longer responses are
+/// truncated and have to be fetched over TCP.
+pub const DNS_UDP_MESSAGE_MAX: usize = 512;
+
+pub const DNS_FLAG_QR: u16 = 0x8000;
+pub const DNS_FLAG_AA: u16 = 0x0400;
+pub const DNS_FLAG_TC: u16 = 0x0200;
+pub const DNS_FLAG_RD: u16 = 0x0100;
+pub const DNS_FLAG_RA: u16 = 0x0080;
+const DNS_RCODE_MASK: u16 = 0x000f;
+
+pub const DNS_RCODE_NOERROR: u8 = 0;
+pub const DNS_RCODE_FORMERR: u8 = 1;
+pub const DNS_RCODE_SERVFAIL: u8 = 2;
+pub const DNS_RCODE_NXDOMAIN: u8 = 3;
+pub const DNS_RCODE_NOTIMP: u8 = 4;
+pub const DNS_RCODE_REFUSED: u8 = 5;
+
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_NS: u16 = 2;
+pub const DNS_TYPE_CNAME: u16 = 5;
+pub const DNS_TYPE_SOA: u16 = 6;
+pub const DNS_TYPE_PTR: u16 = 12;
+pub const DNS_TYPE_AAAA: u16 = 28;
+pub const DNS_CLASS_IN: u16 = 1;
+
+const DNS_LABEL_MAX: usize = 63;
+const DNS_NAME_MAX: usize = 255;
+/// Compression pointers followed while reading one name, which stops pointer loops.
+const DNS_POINTER_MAX: usize = 32;
+
+/// View of a DNS message (RFC 1035).
+pub struct DnsPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> DnsPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        if packet.buffer.as_ref().len() < DNS_HEADER_LEN {
+            return Err(UtcpErr::Malformed("DNS message is too short".into()));
+        }
+        Ok(packet)
+    }
+
+    pub fn id(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ID)
+    }
+
+    pub fn flags(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::FLAGS)
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags() & DNS_FLAG_QR != 0
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.flags() & DNS_FLAG_TC != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags() & DNS_RCODE_MASK) as u8
+    }
+
+    pub fn qdcount(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::QDCOUNT)
+    }
+
+    pub fn ancount(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ANCOUNT)
+    }
+
+    pub fn nscount(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::NSCOUNT)
+    }
+
+    pub fn arcount(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ARCOUNT)
+    }
+
+    /// Parses the question, answer and authority sections. The additional section is not
+    /// used by a stub resolver and is skipped.
+    pub fn sections(&self) -> UtcpResult<DnsSections> {
+        let buf = self.buffer.as_ref();
+        let mut off = DNS_HEADER_LEN;
+        let mut sections = DnsSections::default();
+        for _ in 0..self.qdcount() {
+            let (name, next) = dns_name_read(buf, off)?;
+            let fixed = buf
+                .get(next..next + 4)
+                .ok_or_else(|| UtcpErr::Malformed("DNS question is too short".into()))?;
+            sections.questions.push(DnsQuestion {
+                name,
+                qtype: read_u16(fixed, 0),
+                qclass: read_u16(fixed, 2),
+            });
+            off = next + 4;
+        }
+        for _ in 0..self.ancount() {
+            let (record, next) = dns_record_read(buf, off)?;
+            sections.answers.push(record);
+            off = next;
+        }
+        for _ in 0..self.nscount() {
+            let (record, next) = dns_record_read(buf, off)?;
+            sections.authorities.push(record);
+            off = next;
+        }
+        Ok(sections)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> DnsPacket<T> {
+    /// Writes the ID and flags and clears the section counts. The sections can be written with
+    /// `DnsWriter`.
+    pub fn set_header(&mut self, id: u16, flags: u16) {
+        let buf = self.buffer.as_mut();
+        buf[..DNS_HEADER_LEN].fill(0);
+        write_u16(buf, field::ID, id);
+        write_u16(buf, field::FLAGS, flags);
+    }
+
+    pub fn set_flags(&mut self, flags: u16) {
+        write_u16(self.buffer.as_mut(), field::FLAGS, flags);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for DnsPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "id=0x{:04x}, flags=0x{:04x}, rcode={}, qd={}, an={}, ns={}, ar={}",
+            self.id(),
+            self.flags(),
+            self.rcode(),
+            self.qdcount(),
+            self.ancount(),
+            self.nscount(),
+            self.arcount()
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsRecordData {
+    A(IpAddress),
+    /// Target of a `DNS_TYPE_CNAME` record.
+    Cname(String),
+    /// The minimum field of a `DNS_TYPE_SOA` record, which bounds negative caching
+    /// (RFC 2308).
+    Soa {
+        minimum: u32,
+    },
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsRecord {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: DnsRecordData,
+}
+
+#[derive(Debug, Default)]
+pub struct DnsSections {
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+}
+
+/// Reads a possibly compressed name at `off`. Returns the name in dotted form without the
+/// trailing dot (the root is the empty string) and the offset following it.
+pub fn dns_name_read(buf: &[u8], mut off: usize) -> UtcpResult<(String, usize)> {
+    let malformed = || UtcpErr::Malformed("bad DNS name".into());
+    let mut name = String::new();
+    let mut next = None;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(off).ok_or_else(malformed)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = buf.get(off + 1..off + 1 + len).ok_or_else(malformed)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                if name.len() > DNS_NAME_MAX {
+                    return Err(malformed());
+                }
+                off += 1 + len;
+            }
+            0xc0 => {
+                let low = *buf.get(off + 1).ok_or_else(malformed)? as usize;
+                pointers += 1;
+                if pointers > DNS_POINTER_MAX {
+                    return Err(malformed());
+                }
+                next.get_or_insert(off + 2);
+                off = (len & 0x3f) << 8 | low;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok((name, next.unwrap_or(off + 1)))
+}
+
+/// Appends `name` without compression. A trailing dot is accepted.
+pub fn dns_name_write(buf: &mut Vec<u8>, name: &str) -> UtcpResult<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() + 2 > DNS_NAME_MAX {
+        return Err(UtcpErr::InvalidArgument(format!(
+            "name is too long: {}",
+            name
+        )));
+    }
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > DNS_LABEL_MAX {
+                return Err(UtcpErr::InvalidArgument(format!(
+                    "bad label in name: {}",
+                    name
+                )));
+            }
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn dns_record_read(buf: &[u8], off: usize) -> UtcpResult<(DnsRecord, usize)> {
+    let (name, off) = dns_name_read(buf, off)?;
+    let fixed = buf
+        .get(off..off + 10)
+        .ok_or_else(|| UtcpErr::Malformed("DNS record is too short".into()))?;
+    let (rtype, class, ttl) = (read_u16(fixed, 0), read_u16(fixed, 2), read_u32(fixed, 4));
+    let rdlen = read_u16(fixed, 8) as usize;
+    let start = off + 10;
+    let rdata = buf
+        .get(start..start + rdlen)
+        .ok_or_else(|| UtcpErr::Malformed("DNS record data is too short".into()))?;
+    let data = match (rtype, class) {
+        (DNS_TYPE_A, DNS_CLASS_IN) if rdlen == 4 => {
+            DnsRecordData::A(IpAddress::from([rdata[0], rdata[1], rdata[2], rdata[3]]))
+        }
+        (DNS_TYPE_CNAME, _) => DnsRecordData::Cname(dns_name_read(buf, start)?.0),
+        (DNS_TYPE_SOA, _) => {
+            // MNAME and RNAME, then five 32-bit fields of which MINIMUM is the last
+            let (_, next) = dns_name_read(buf, start)?;
+            let (_, next) = dns_name_read(buf, next)?;
+            let minimum = buf
+                .get(next + 16..next + 20)
+                .ok_or_else(|| UtcpErr::Malformed("DNS SOA record is too short".into()))?;
+            DnsRecordData::Soa {
+                minimum: read_u32(minimum, 0),
+            }
+        }
+        _ => DnsRecordData::Other(rdata.to_vec()),
+    };
+    let record = DnsRecord {
+        name,
+        rtype,
+        class,
+        ttl,
+        data,
+    };
+    Ok((record, start + rdlen))
+}
+
+/// Appends sections to a message whose header is already in `buf`, counting what is written.
+/// Sections must be written in order: questions, then answers, then authorities.
+pub struct DnsWriter<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+impl<'a> DnsWriter<'a> {
+    /// Drops any sections already written, along with their counts.
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        buf.truncate(DNS_HEADER_LEN);
+        buf[field::QDCOUNT..DNS_HEADER_LEN].fill(0);
+        Self { buf }
+    }
+
+    fn count(&mut self, off: usize) {
+        let count = read_u16(self.buf, off);
+        write_u16(self.buf, off, count + 1);
+    }
+
+    pub fn question(&mut self, name: &str, qtype: u16) -> UtcpResult<&mut Self> {
+        dns_name_write(self.buf, name)?;
+        self.buf.extend_from_slice(&qtype.to_be_bytes());
+        self.buf.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        self.count(field::QDCOUNT);
+        Ok(self)
+    }
+
+    fn record(&mut self, name: &str, rtype: u16, ttl: u32, rdata: &[u8]) -> UtcpResult<()> {
+        dns_name_write(self.buf, name)?;
+        self.buf.extend_from_slice(&rtype.to_be_bytes());
+        self.buf.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        self.buf.extend_from_slice(&ttl.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(rdata);
+        Ok(())
+    }
+
+    /// Appends an answer record with raw record data.
+    pub fn answer(
+        &mut self,
+        name: &str,
+        rtype: u16,
+        ttl: u32,
+        rdata: &[u8],
+    ) -> UtcpResult<&mut Self> {
+        self.record(name, rtype, ttl, rdata)?;
+        self.count(field::ANCOUNT);
+        Ok(self)
+    }
+
+    pub fn answer_a(&mut self, name: &str, ttl: u32, addr: IpAddress) -> UtcpResult<&mut Self> {
+        self.answer(name, DNS_TYPE_A, ttl, &addr.octets())
+    }
+
+    pub fn answer_cname(&mut self, name: &str, ttl: u32, target: &str) -> UtcpResult<&mut Self> {
+        let mut rdata = Vec::new();
+        dns_name_write(&mut rdata, target)?;
+        self.answer(name, DNS_TYPE_CNAME, ttl, &rdata)
+    }
+
+    /// Appends an SOA record to the authority section, as servers do in negative answers.
+    pub fn authority_soa(&mut self, zone: &str, ttl: u32, minimum: u32) -> UtcpResult<&mut Self> {
+        let mut rdata = Vec::new();
+        dns_name_write(&mut rdata, zone)?;
+        dns_name_write(&mut rdata, zone)?;
+        for value in [1, 3600, 600, 86400, minimum] {
+            rdata.extend_from_slice(&u32::to_be_bytes(value));
+        }
+        self.record(zone, DNS_TYPE_SOA, ttl, &rdata)?;
+        self.count(field::NSCOUNT);
+        Ok(self)
+    }
+}
+
+#[test]
+fn test_dns_packet() {
+    let addr = IpAddress::parse_from("192.0.2.10");
+    let mut buf = vec![0u8; DNS_HEADER_LEN];
+    DnsPacket::new_unchecked(&mut buf[..]).set_header(0x1234, DNS_FLAG_RD);
+    DnsWriter::new(&mut buf)
+        .question("www.example.com.", DNS_TYPE_A)
+        .unwrap();
+    let query = buf.clone();
+    let packet = DnsPacket::new_checked(&query[..]).unwrap();
+    assert_eq!(packet.id(), 0x1234);
+    assert!(!packet.is_response());
+    let sections = packet.sections().unwrap();
+    assert_eq!(sections.questions[0].name, "www.example.com");
+    assert_eq!(sections.questions[0].qtype, DNS_TYPE_A);
+
+    DnsPacket::new_unchecked(&mut buf[..]).set_flags(DNS_FLAG_QR | DNS_FLAG_RD | DNS_FLAG_RA);
+    DnsWriter::new(&mut buf)
+        .question("www.example.com", DNS_TYPE_A)
+        .unwrap()
+        .answer_cname("www.example.com", 300, "example.com")
+        .unwrap()
+        .answer_a("example.com", 60, addr)
+        .unwrap();
+    let packet = DnsPacket::new_checked(&buf[..]).unwrap();
+    assert!(packet.is_response());
+    assert_eq!(packet.rcode(), DNS_RCODE_NOERROR);
+    let sections = packet.sections().unwrap();
+    assert_eq!(sections.answers.len(), 2);
+    assert_eq!(
+        sections.answers[0].data,
+        DnsRecordData::Cname("example.com".into())
+    );
+    assert_eq!(sections.answers[1].ttl, 60);
+    assert_eq!(sections.answers[1].data, DnsRecordData::A(addr));
+
+    // a compressed name pointing back at the question
+    let mut compressed = buf[..DNS_HEADER_LEN].to_vec();
+    compressed[field::ANCOUNT + 1] = 1;
+    compressed.extend_from_slice(&query[DNS_HEADER_LEN..]);
+    compressed.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 192, 0, 2, 10]);
+    let packet = DnsPacket::new_checked(&compressed[..]).unwrap();
+    let answer = &packet.sections().unwrap().answers[0];
+    assert_eq!(answer.name, "www.example.com");
+    assert_eq!(answer.data, DnsRecordData::A(addr));
+
+    // a pointer to itself is rejected rather than followed forever
+    let mut looped = compressed[..DNS_HEADER_LEN].to_vec();
+    looped[field::QDCOUNT + 1] = 1;
+    looped[field::ANCOUNT + 1] = 0;
+    looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+    assert!(
+        DnsPacket::new_checked(&looped[..])
+            .unwrap()
+            .sections()
+            .is_err()
+    );
+
+    assert!(dns_name_write(&mut Vec::new(), "a..b").is_err());
+    assert!(dns_name_write(&mut Vec::new(), &"a".repeat(64)).is_err());
+}
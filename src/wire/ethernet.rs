@@ -0,0 +1,191 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+    ipv6::Ipv6Address,
+};
+
+use super::{read_u16, write_u16};
+
+mod field {
+    pub const DST: usize = 0;
+    pub const SRC: usize = 6;
+    pub const TYPE: usize = 12;
+}
+
+pub const ETHERNET_ADDR_LEN: usize = 6;
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const ETHERNET_PAYLOAD_MIN_LEN: usize = 46;
+pub const ETHERNET_PAYLOAD_MAX_LEN: usize = 1500;
+
+/// MAC address
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct EthernetAddress(pub [u8; ETHERNET_ADDR_LEN]);
+
+impl EthernetAddress {
+    pub const ANY: EthernetAddress = EthernetAddress([0; ETHERNET_ADDR_LEN]);
+    pub const BROADCAST: EthernetAddress = EthernetAddress([0xff; ETHERNET_ADDR_LEN]);
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        Some(EthernetAddress(data.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        !self.is_multicast()
+    }
+
+    /// Maps an IPv4 multicast group to 01:00:5e followed by its low 23 bits (RFC 1112 6.4).
+    pub fn from_ip_multicast(group: IpAddress) -> Self {
+        let [_, b1, b2, b3] = group.octets();
+        EthernetAddress([0x01, 0x00, 0x5e, b1 & 0x7f, b2, b3])
+    }
+
+    /// Maps an IPv6 multicast address to 33:33 followed by its low 32 bits (RFC 2464 7).
+    pub fn from_ipv6_multicast(group: Ipv6Address) -> Self {
+        let b = group.as_bytes();
+        EthernetAddress([0x33, 0x33, b[12], b[13], b[14], b[15]])
+    }
+}
+
+impl std::fmt::Display for EthernetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let b = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+impl std::str::FromStr for EthernetAddress {
+    type Err = UtcpErr;
+
+    /// Parses the colon-separated form, e.g. `02:00:00:00:00:01`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut addr = [0u8; ETHERNET_ADDR_LEN];
+        let mut parts = s.split(':');
+        for byte in addr.iter_mut() {
+            let part = parts
+                .next()
+                .filter(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(|| UtcpErr::InvalidAddress(s.to_string()))?;
+            *byte =
+                u8::from_str_radix(part, 16).map_err(|_| UtcpErr::InvalidAddress(s.to_string()))?;
+        }
+        if parts.next().is_some() {
+            return Err(UtcpErr::InvalidAddress(s.to_string()));
+        }
+        Ok(EthernetAddress(addr))
+    }
+}
+
+impl std::fmt::Debug for EthernetAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+/// View of an Ethernet II frame.
+pub struct EthernetFrame<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> EthernetFrame<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let frame = Self::new_unchecked(buffer);
+        if frame.buffer.as_ref().len() < ETHERNET_HEADER_LEN {
+            return Err(UtcpErr::Malformed("Ethernet header is too short".into()));
+        }
+        Ok(frame)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn dst(&self) -> EthernetAddress {
+        let buf = self.buffer.as_ref();
+        EthernetAddress::from_bytes(&buf[field::DST..field::DST + ETHERNET_ADDR_LEN]).unwrap()
+    }
+
+    pub fn src(&self) -> EthernetAddress {
+        let buf = self.buffer.as_ref();
+        EthernetAddress::from_bytes(&buf[field::SRC..field::SRC + ETHERNET_ADDR_LEN]).unwrap()
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::TYPE)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[ETHERNET_HEADER_LEN..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> EthernetFrame<T> {
+    pub fn set_dst(&mut self, addr: EthernetAddress) {
+        self.buffer.as_mut()[field::DST..field::DST + ETHERNET_ADDR_LEN].copy_from_slice(&addr.0);
+    }
+
+    pub fn set_src(&mut self, addr: EthernetAddress) {
+        self.buffer.as_mut()[field::SRC..field::SRC + ETHERNET_ADDR_LEN].copy_from_slice(&addr.0);
+    }
+
+    pub fn set_ethertype(&mut self, ty: u16) {
+        write_u16(self.buffer.as_mut(), field::TYPE, ty);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[ETHERNET_HEADER_LEN..]
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for EthernetFrame<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "src={}, dst={}, type=0x{:04x}",
+            self.src(),
+            self.dst(),
+            self.ethertype()
+        )
+    }
+}
+
+#[test]
+fn test_ethernet_address_parse() {
+    let addr: EthernetAddress = "02:00:5e:0a:FF:01".parse().unwrap();
+    assert_eq!(addr, EthernetAddress([0x02, 0x00, 0x5e, 0x0a, 0xff, 0x01]));
+    assert_eq!(addr.to_string(), "02:00:5e:0a:ff:01");
+    assert!("02:00:5e:0a:ff".parse::<EthernetAddress>().is_err());
+    assert!("02:00:5e:0a:ff:01:02".parse::<EthernetAddress>().is_err());
+    assert!("02:00:5e:0a:ff:1".parse::<EthernetAddress>().is_err());
+    assert!("02:00:5e:0a:ff:zz".parse::<EthernetAddress>().is_err());
+}
+
+#[test]
+fn test_ethernet_address_from_ip_multicast() {
+    let addr = EthernetAddress::from_ip_multicast(IpAddress::parse_from("239.129.2.3"));
+    assert_eq!(addr, EthernetAddress([0x01, 0x00, 0x5e, 0x01, 0x02, 0x03]));
+    assert!(addr.is_multicast());
+
+    let group: Ipv6Address = "ff02::1:ff0a:b0c".parse().unwrap();
+    let addr = EthernetAddress::from_ipv6_multicast(group);
+    assert_eq!(addr, EthernetAddress([0x33, 0x33, 0xff, 0x0a, 0x0b, 0x0c]));
+}
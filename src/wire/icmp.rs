@@ -0,0 +1,129 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    utils,
+};
+
+use super::{read_u16, read_u32, write_checksum, write_u16, write_u32};
+
+mod field {
+    pub const TYPE: usize = 0;
+    pub const CODE: usize = 1;
+    pub const SUM: usize = 2;
+    pub const ID: usize = 4;
+    pub const SEQ: usize = 6;
+    pub const REST: usize = 4;
+}
+
+pub const ICMP_HEADER_LEN: usize = 8;
+
+pub const ICMP_TYPE_ECHOREPLY: u8 = 0;
+pub const ICMP_TYPE_DEST_UNREACH: u8 = 3;
+pub const ICMP_TYPE_ECHO: u8 = 8;
+pub const ICMP_TYPE_TIME_EXCEEDED: u8 = 11;
+
+pub const ICMP_CODE_NET_UNREACH: u8 = 0;
+pub const ICMP_CODE_HOST_UNREACH: u8 = 1;
+pub const ICMP_CODE_PROTO_UNREACH: u8 = 2;
+pub const ICMP_CODE_PORT_UNREACH: u8 = 3;
+pub const ICMP_CODE_FRAGMENT_NEEDED: u8 = 4;
+pub const ICMP_CODE_ADMIN_PROHIBITED: u8 = 13;
+
+/// View of an ICMP message. The checksum covers the whole buffer.
+pub struct IcmpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> IcmpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        if packet.buffer.as_ref().len() < ICMP_HEADER_LEN {
+            return Err(UtcpErr::Malformed("ICMP header is too short".into()));
+        }
+        Ok(packet)
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buffer.as_ref()[field::TYPE]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.buffer.as_ref()[field::CODE]
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    /// Identifier of echo messages.
+    pub fn id(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ID)
+    }
+
+    /// Sequence number of echo messages.
+    pub fn seq(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SEQ)
+    }
+
+    /// The 4 bytes after the checksum, whose meaning depends on the type.
+    pub fn rest_of_header(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::REST)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[ICMP_HEADER_LEN..]
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        utils::checksum16(self.buffer.as_ref(), 0) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IcmpPacket<T> {
+    pub fn set_msg_type(&mut self, ty: u8) {
+        self.buffer.as_mut()[field::TYPE] = ty;
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.buffer.as_mut()[field::CODE] = code;
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        write_u16(self.buffer.as_mut(), field::ID, id);
+    }
+
+    pub fn set_seq(&mut self, seq: u16) {
+        write_u16(self.buffer.as_mut(), field::SEQ, seq);
+    }
+
+    pub fn set_rest_of_header(&mut self, value: u32) {
+        write_u32(self.buffer.as_mut(), field::REST, value);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[ICMP_HEADER_LEN..]
+    }
+
+    pub fn fill_checksum(&mut self) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let sum = utils::checksum16(self.buffer.as_ref(), 0);
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for IcmpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "type={}, code={}, sum=0x{:04x}, rest=0x{:08x}, len={}",
+            self.msg_type(),
+            self.code(),
+            self.sum(),
+            self.rest_of_header(),
+            self.buffer.as_ref().len()
+        )
+    }
+}
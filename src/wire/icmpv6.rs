@@ -0,0 +1,401 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ipv6::{IPV6_NEXT_HEADER_ICMPV6, Ipv6Address},
+    utils,
+};
+
+use super::{
+    ethernet::{ETHERNET_ADDR_LEN, EthernetAddress},
+    ipv6::ipv6_pseudo_header_sum,
+    read_u16, read_u32, write_checksum, write_u16, write_u32,
+};
+
+mod field {
+    pub const TYPE: usize = 0;
+    pub const CODE: usize = 1;
+    pub const SUM: usize = 2;
+    pub const ID: usize = 4;
+    pub const SEQ: usize = 6;
+    pub const REST: usize = 4;
+    /// Target address of Neighbor Solicitation and Advertisement messages.
+    pub const TARGET: usize = 8;
+    // Router Advertisement
+    pub const CUR_HOP_LIMIT: usize = 4;
+    pub const RA_FLAGS: usize = 5;
+    pub const ROUTER_LIFETIME: usize = 6;
+    pub const REACHABLE_TIME: usize = 8;
+    pub const RETRANS_TIMER: usize = 12;
+}
+
+pub const ICMPV6_HEADER_LEN: usize = 8;
+
+// error messages (RFC 4443)
+pub const ICMPV6_TYPE_DEST_UNREACH: u8 = 1;
+pub const ICMPV6_TYPE_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TYPE_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_TYPE_PARAM_PROBLEM: u8 = 4;
+// informational messages
+pub const ICMPV6_TYPE_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_TYPE_ECHO_REPLY: u8 = 129;
+// Neighbor Discovery (RFC 4861)
+pub const ICMPV6_TYPE_ROUTER_SOLICIT: u8 = 133;
+pub const ICMPV6_TYPE_ROUTER_ADVERT: u8 = 134;
+pub const ICMPV6_TYPE_NEIGHBOR_SOLICIT: u8 = 135;
+pub const ICMPV6_TYPE_NEIGHBOR_ADVERT: u8 = 136;
+pub const ICMPV6_TYPE_REDIRECT: u8 = 137;
+
+pub const ICMPV6_CODE_NO_ROUTE: u8 = 0;
+pub const ICMPV6_CODE_ADMIN_PROHIBITED: u8 = 1;
+pub const ICMPV6_CODE_BEYOND_SCOPE: u8 = 2;
+pub const ICMPV6_CODE_ADDR_UNREACH: u8 = 3;
+pub const ICMPV6_CODE_PORT_UNREACH: u8 = 4;
+pub const ICMPV6_CODE_HOP_LIMIT_EXCEEDED: u8 = 0;
+pub const ICMPV6_CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+pub const ICMPV6_CODE_ERRONEOUS_FIELD: u8 = 0;
+pub const ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub const ICMPV6_CODE_UNRECOGNIZED_OPTION: u8 = 2;
+
+/// Length of Neighbor Solicitation and Advertisement messages without options.
+pub const NDP_NEIGHBOR_LEN: usize = 24;
+/// Length of Router Solicitation messages without options.
+pub const NDP_ROUTER_SOLICIT_LEN: usize = 8;
+/// Length of Router Advertisement messages without options.
+pub const NDP_ROUTER_ADVERT_LEN: usize = 16;
+
+/// Flags of Neighbor Advertisement messages, in `rest_of_header`.
+pub const NDP_NA_FLAG_ROUTER: u32 = 0x8000_0000;
+pub const NDP_NA_FLAG_SOLICITED: u32 = 0x4000_0000;
+pub const NDP_NA_FLAG_OVERRIDE: u32 = 0x2000_0000;
+
+/// Flags of Router Advertisement messages.
+pub const NDP_RA_FLAG_MANAGED: u8 = 0x80;
+pub const NDP_RA_FLAG_OTHER: u8 = 0x40;
+
+pub const NDP_OPTION_SOURCE_LL_ADDR: u8 = 1;
+pub const NDP_OPTION_TARGET_LL_ADDR: u8 = 2;
+pub const NDP_OPTION_PREFIX_INFO: u8 = 3;
+pub const NDP_OPTION_MTU: u8 = 5;
+/// Length of a link-layer address option for Ethernet.
+pub const NDP_OPTION_LL_ADDR_LEN: usize = 8;
+
+const NDP_PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const NDP_PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// View of an ICMPv6 message. The checksum covers the whole buffer and the IPv6 pseudo header.
+pub struct Icmpv6Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Icmpv6Packet<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Wraps a buffer after checking the length of the common header, and the fixed part of
+    /// Neighbor Discovery messages, whose accessors need it.
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < ICMPV6_HEADER_LEN {
+            return Err(UtcpErr::Malformed("ICMPv6 header is too short".into()));
+        }
+        let min = match packet.msg_type() {
+            ICMPV6_TYPE_ROUTER_ADVERT => NDP_ROUTER_ADVERT_LEN,
+            ICMPV6_TYPE_NEIGHBOR_SOLICIT | ICMPV6_TYPE_NEIGHBOR_ADVERT => NDP_NEIGHBOR_LEN,
+            ICMPV6_TYPE_REDIRECT => NDP_NEIGHBOR_LEN + 16,
+            _ => ICMPV6_HEADER_LEN,
+        };
+        if len < min {
+            return Err(UtcpErr::Malformed(format!(
+                "ICMPv6 message is too short: type={}, len={}",
+                packet.msg_type(),
+                len
+            )));
+        }
+        Ok(packet)
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buffer.as_ref()[field::TYPE]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.buffer.as_ref()[field::CODE]
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    /// Identifier of echo messages.
+    pub fn id(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ID)
+    }
+
+    /// Sequence number of echo messages.
+    pub fn seq(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SEQ)
+    }
+
+    /// The 4 bytes after the checksum: the MTU of Packet Too Big, the pointer of Parameter
+    /// Problem and the flags of Neighbor Advertisement messages.
+    pub fn rest_of_header(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::REST)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[ICMPV6_HEADER_LEN..]
+    }
+
+    /// Target address of Neighbor Solicitation, Neighbor Advertisement and Redirect messages.
+    pub fn target(&self) -> Ipv6Address {
+        Ipv6Address::from_bytes(&self.buffer.as_ref()[field::TARGET..field::TARGET + 16])
+    }
+
+    /// Hop limit advertised by a router, 0 if unspecified.
+    pub fn cur_hop_limit(&self) -> u8 {
+        self.buffer.as_ref()[field::CUR_HOP_LIMIT]
+    }
+
+    pub fn ra_flags(&self) -> u8 {
+        self.buffer.as_ref()[field::RA_FLAGS]
+    }
+
+    /// Seconds the router may be used as a default router, 0 if it is not one.
+    pub fn router_lifetime(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ROUTER_LIFETIME)
+    }
+
+    /// Milliseconds, 0 if unspecified.
+    pub fn reachable_time(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::REACHABLE_TIME)
+    }
+
+    /// Milliseconds, 0 if unspecified.
+    pub fn retrans_timer(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::RETRANS_TIMER)
+    }
+
+    /// Parses the options of a Neighbor Discovery message.
+    pub fn ndp_options(&self) -> UtcpResult<Vec<NdpOption>> {
+        let start = match self.msg_type() {
+            ICMPV6_TYPE_ROUTER_SOLICIT => NDP_ROUTER_SOLICIT_LEN,
+            ICMPV6_TYPE_ROUTER_ADVERT => NDP_ROUTER_ADVERT_LEN,
+            ICMPV6_TYPE_NEIGHBOR_SOLICIT | ICMPV6_TYPE_NEIGHBOR_ADVERT => NDP_NEIGHBOR_LEN,
+            ICMPV6_TYPE_REDIRECT => NDP_NEIGHBOR_LEN + 16,
+            ty => {
+                return Err(UtcpErr::InvalidArgument(format!(
+                    "not a Neighbor Discovery message: type={}",
+                    ty
+                )));
+            }
+        };
+        ndp_options_parse(&self.buffer.as_ref()[start..])
+    }
+
+    pub fn verify_checksum(&self, src: Ipv6Address, dst: Ipv6Address) -> bool {
+        let data = self.buffer.as_ref();
+        let init = ipv6_pseudo_header_sum(src, dst, IPV6_NEXT_HEADER_ICMPV6, data.len() as u32);
+        utils::checksum16(data, init) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Icmpv6Packet<T> {
+    pub fn set_msg_type(&mut self, ty: u8) {
+        self.buffer.as_mut()[field::TYPE] = ty;
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.buffer.as_mut()[field::CODE] = code;
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        write_u16(self.buffer.as_mut(), field::ID, id);
+    }
+
+    pub fn set_seq(&mut self, seq: u16) {
+        write_u16(self.buffer.as_mut(), field::SEQ, seq);
+    }
+
+    pub fn set_rest_of_header(&mut self, value: u32) {
+        write_u32(self.buffer.as_mut(), field::REST, value);
+    }
+
+    pub fn set_target(&mut self, addr: Ipv6Address) {
+        self.buffer.as_mut()[field::TARGET..field::TARGET + 16].copy_from_slice(addr.as_bytes());
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[ICMPV6_HEADER_LEN..]
+    }
+
+    /// Computes the checksum. Call it after the source and destination addresses are known.
+    pub fn fill_checksum(&mut self, src: Ipv6Address, dst: Ipv6Address) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let data = self.buffer.as_ref();
+        let init = ipv6_pseudo_header_sum(src, dst, IPV6_NEXT_HEADER_ICMPV6, data.len() as u32);
+        let sum = utils::checksum16(data, init);
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for Icmpv6Packet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "type={}, code={}, sum=0x{:04x}, rest=0x{:08x}, len={}",
+            self.msg_type(),
+            self.code(),
+            self.sum(),
+            self.rest_of_header(),
+            self.buffer.as_ref().len()
+        )
+    }
+}
+
+/// Prefix Information option of Router Advertisements (RFC 4861 section 4.6.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NdpPrefixInfo {
+    pub prefix: Ipv6Address,
+    pub prefix_len: u8,
+    /// The prefix is on the link.
+    pub on_link: bool,
+    /// The prefix can be used for stateless address autoconfiguration.
+    pub autonomous: bool,
+    /// Seconds, `u32::MAX` for infinity.
+    pub valid_lifetime: u32,
+    /// Seconds, `u32::MAX` for infinity.
+    pub preferred_lifetime: u32,
+}
+
+/// Option of a Neighbor Discovery message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NdpOption {
+    SourceLinkLayerAddr(EthernetAddress),
+    TargetLinkLayerAddr(EthernetAddress),
+    PrefixInfo(NdpPrefixInfo),
+    Mtu(u32),
+    /// Options that are not used, which are skipped (RFC 4861 section 4.6).
+    Unknown(u8),
+}
+
+/// Parses Neighbor Discovery options. An option of length zero makes the whole message invalid.
+pub fn ndp_options_parse(mut data: &[u8]) -> UtcpResult<Vec<NdpOption>> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err(UtcpErr::Malformed("truncated NDP option".into()));
+        }
+        let (ty, len) = (data[0], data[1] as usize * 8);
+        if len == 0 || len > data.len() {
+            return Err(UtcpErr::Malformed(format!(
+                "invalid NDP option length: type={}, len={}",
+                ty, len
+            )));
+        }
+        let option = &data[..len];
+        options.push(match ty {
+            NDP_OPTION_SOURCE_LL_ADDR | NDP_OPTION_TARGET_LL_ADDR
+                if len == NDP_OPTION_LL_ADDR_LEN =>
+            {
+                let addr = EthernetAddress::from_bytes(&option[2..2 + ETHERNET_ADDR_LEN]).unwrap();
+                match ty {
+                    NDP_OPTION_SOURCE_LL_ADDR => NdpOption::SourceLinkLayerAddr(addr),
+                    _ => NdpOption::TargetLinkLayerAddr(addr),
+                }
+            }
+            NDP_OPTION_PREFIX_INFO if len == 32 => NdpOption::PrefixInfo(NdpPrefixInfo {
+                prefix_len: option[2],
+                on_link: option[3] & NDP_PREFIX_FLAG_ON_LINK != 0,
+                autonomous: option[3] & NDP_PREFIX_FLAG_AUTONOMOUS != 0,
+                valid_lifetime: read_u32(option, 4),
+                preferred_lifetime: read_u32(option, 8),
+                prefix: Ipv6Address::from_bytes(&option[16..32]),
+            }),
+            NDP_OPTION_MTU if len == 8 => NdpOption::Mtu(read_u32(option, 4)),
+            ty => NdpOption::Unknown(ty),
+        });
+        data = &data[len..];
+    }
+    Ok(options)
+}
+
+/// Writes a Source or Target Link-Layer Address option for an Ethernet address into the
+/// first `NDP_OPTION_LL_ADDR_LEN` bytes of `buf`.
+pub fn ndp_option_ll_addr_write(buf: &mut [u8], ty: u8, addr: EthernetAddress) {
+    buf[0] = ty;
+    buf[1] = (NDP_OPTION_LL_ADDR_LEN / 8) as u8;
+    buf[2..NDP_OPTION_LL_ADDR_LEN].copy_from_slice(&addr.0);
+}
+
+/// Writes a Prefix Information option into the first 32 bytes of `buf`.
+pub fn ndp_option_prefix_info_write(buf: &mut [u8], info: &NdpPrefixInfo) {
+    buf[0] = NDP_OPTION_PREFIX_INFO;
+    buf[1] = 4;
+    buf[2] = info.prefix_len;
+    buf[3] = match info.on_link {
+        true => NDP_PREFIX_FLAG_ON_LINK,
+        false => 0,
+    } | match info.autonomous {
+        true => NDP_PREFIX_FLAG_AUTONOMOUS,
+        false => 0,
+    };
+    write_u32(buf, 4, info.valid_lifetime);
+    write_u32(buf, 8, info.preferred_lifetime);
+    buf[12..16].fill(0);
+    buf[16..32].copy_from_slice(info.prefix.as_bytes());
+}
+
+#[test]
+fn test_icmpv6_packet() {
+    let src: Ipv6Address = "fe80::1".parse().unwrap();
+    let target: Ipv6Address = "2001:db8::2".parse().unwrap();
+    let dst = target.solicited_node();
+    let sha: EthernetAddress = "02:00:00:00:00:01".parse().unwrap();
+
+    let mut buf = [0u8; NDP_NEIGHBOR_LEN + NDP_OPTION_LL_ADDR_LEN];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut buf[..]);
+    packet.set_msg_type(ICMPV6_TYPE_NEIGHBOR_SOLICIT);
+    packet.set_code(0);
+    packet.set_target(target);
+    ndp_option_ll_addr_write(
+        &mut packet.payload_mut()[NDP_NEIGHBOR_LEN - ICMPV6_HEADER_LEN..],
+        NDP_OPTION_SOURCE_LL_ADDR,
+        sha,
+    );
+    packet.fill_checksum(src, dst);
+
+    let packet = Icmpv6Packet::new_checked(&buf[..]).unwrap();
+    assert!(packet.verify_checksum(src, dst));
+    assert!(!packet.verify_checksum(src, target));
+    assert_eq!(packet.target(), target);
+    assert_eq!(
+        packet.ndp_options().unwrap(),
+        vec![NdpOption::SourceLinkLayerAddr(sha)]
+    );
+    assert!(Icmpv6Packet::new_checked(&buf[..NDP_NEIGHBOR_LEN - 1]).is_err());
+
+    // a router advertisement with a prefix, an MTU and an unknown option
+    let info = NdpPrefixInfo {
+        prefix: "2001:db8::".parse().unwrap(),
+        prefix_len: 64,
+        on_link: true,
+        autonomous: true,
+        valid_lifetime: 86400,
+        preferred_lifetime: 14400,
+    };
+    let mut options = [0u8; 32 + 8 + 8];
+    ndp_option_prefix_info_write(&mut options, &info);
+    options[32..40].copy_from_slice(&[NDP_OPTION_MTU, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+    options[40..48].copy_from_slice(&[31, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(
+        ndp_options_parse(&options).unwrap(),
+        vec![
+            NdpOption::PrefixInfo(info),
+            NdpOption::Mtu(1500),
+            NdpOption::Unknown(31)
+        ]
+    );
+    options[41] = 0;
+    assert!(ndp_options_parse(&options).is_err());
+}
@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+    utils,
+};
+
+use super::{read_u16, read_u32, write_checksum, write_u16};
+
+mod field {
+    pub const TYPE: usize = 0;
+    pub const MAX_RESP: usize = 1;
+    pub const SUM: usize = 2;
+    pub const GROUP: usize = 4;
+    /// IGMPv3 queries only.
+    pub const S_QRV: usize = 8;
+    pub const QQIC: usize = 9;
+    pub const NUM_SOURCES: usize = 10;
+    /// IGMPv3 reports only.
+    pub const NUM_RECORDS: usize = 6;
+}
+
+/// Length of IGMPv1 and IGMPv2 messages, and of the fixed part of IGMPv3 reports.
+pub const IGMP_HEADER_LEN: usize = 8;
+/// IGMPv3 queries are told apart from older ones by their length (RFC 3376 7.1).
+pub const IGMP_V3_QUERY_MIN_LEN: usize = 12;
+const IGMP_V3_RECORD_LEN: usize = 8;
+
+pub const IGMP_TYPE_MEMBERSHIP_QUERY: u8 = 0x11;
+pub const IGMP_TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+pub const IGMP_TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+pub const IGMP_TYPE_V2_LEAVE_GROUP: u8 = 0x17;
+pub const IGMP_TYPE_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// Group record types of IGMPv3 reports.
+pub const IGMP_V3_MODE_IS_INCLUDE: u8 = 1;
+pub const IGMP_V3_MODE_IS_EXCLUDE: u8 = 2;
+pub const IGMP_V3_CHANGE_TO_INCLUDE: u8 = 3;
+pub const IGMP_V3_CHANGE_TO_EXCLUDE: u8 = 4;
+pub const IGMP_V3_ALLOW_NEW_SOURCES: u8 = 5;
+pub const IGMP_V3_BLOCK_OLD_SOURCES: u8 = 6;
+
+/// Maximum response time of IGMPv1 queries, which have no field for it.
+const IGMP_V1_MAX_RESP_TIME: Duration = Duration::from_secs(10);
+
+/// View of an IGMP message (RFC 1112, RFC 2236, RFC 3376). The checksum covers the whole
+/// buffer.
+pub struct IgmpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> IgmpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        if packet.buffer.as_ref().len() < IGMP_HEADER_LEN {
+            return Err(UtcpErr::Malformed("IGMP message is too short".into()));
+        }
+        Ok(packet)
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buffer.as_ref()[field::TYPE]
+    }
+
+    pub fn max_resp_code(&self) -> u8 {
+        self.buffer.as_ref()[field::MAX_RESP]
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    /// Group of a query, report or leave. Zero in general queries.
+    pub fn group(&self) -> IpAddress {
+        IpAddress::from(read_u32(self.buffer.as_ref(), field::GROUP))
+    }
+
+    pub fn is_v3_query(&self) -> bool {
+        self.msg_type() == IGMP_TYPE_MEMBERSHIP_QUERY
+            && self.buffer.as_ref().len() >= IGMP_V3_QUERY_MIN_LEN
+    }
+
+    /// An IGMPv1 query has no maximum response time.
+    pub fn is_v1_query(&self) -> bool {
+        self.msg_type() == IGMP_TYPE_MEMBERSHIP_QUERY
+            && !self.is_v3_query()
+            && self.max_resp_code() == 0
+    }
+
+    /// Robustness variable of an IGMPv3 query.
+    pub fn qrv(&self) -> u8 {
+        self.buffer.as_ref()[field::S_QRV] & 0x07
+    }
+
+    /// Querier's query interval code of an IGMPv3 query.
+    pub fn qqic(&self) -> u8 {
+        self.buffer.as_ref()[field::QQIC]
+    }
+
+    /// Number of sources of an IGMPv3 query.
+    pub fn num_sources(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::NUM_SOURCES)
+    }
+
+    /// Time within which a query is answered. IGMPv3 codes from 128 on are floating point
+    /// values (RFC 3376 4.1.1).
+    pub fn max_resp_time(&self) -> Duration {
+        let code = self.max_resp_code();
+        if self.is_v1_query() {
+            return IGMP_V1_MAX_RESP_TIME;
+        }
+        let tenths = if self.is_v3_query() && code >= 0x80 {
+            let (exp, mant) = ((code >> 4) & 0x07, code & 0x0f);
+            ((mant as u64) | 0x10) << (exp + 3)
+        } else {
+            code as u64
+        };
+        Duration::from_millis(tenths * 100)
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        utils::checksum16(self.buffer.as_ref(), 0) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IgmpPacket<T> {
+    pub fn set_msg_type(&mut self, ty: u8) {
+        self.buffer.as_mut()[field::TYPE] = ty;
+    }
+
+    pub fn set_max_resp_code(&mut self, code: u8) {
+        self.buffer.as_mut()[field::MAX_RESP] = code;
+    }
+
+    pub fn set_group(&mut self, group: IpAddress) {
+        self.buffer.as_mut()[field::GROUP..field::GROUP + 4].copy_from_slice(&group.octets());
+    }
+
+    pub fn fill_checksum(&mut self) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let sum = utils::checksum16(self.buffer.as_ref(), 0);
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for IgmpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "type=0x{:02x}, max_resp={}, sum=0x{:04x}, group={}, len={}",
+            self.msg_type(),
+            self.max_resp_code(),
+            self.sum(),
+            self.group(),
+            self.buffer.as_ref().len()
+        )
+    }
+}
+
+/// Builds an IGMPv3 report of group records without sources, which is all a host that only
+/// joins whole groups sends.
+pub fn igmp_v3_report(records: &[(u8, IpAddress)]) -> Vec<u8> {
+    let mut buf = vec![0u8; IGMP_HEADER_LEN];
+    buf[field::TYPE] = IGMP_TYPE_V3_MEMBERSHIP_REPORT;
+    write_u16(&mut buf, field::NUM_RECORDS, records.len() as u16);
+    for &(ty, group) in records {
+        let mut record = [0u8; IGMP_V3_RECORD_LEN];
+        record[0] = ty;
+        record[4..8].copy_from_slice(&group.octets());
+        buf.extend_from_slice(&record);
+    }
+    IgmpPacket::new_unchecked(&mut buf[..]).fill_checksum();
+    buf
+}
+
+#[test]
+fn test_igmp_packet() {
+    let group = IpAddress::parse_from("239.1.2.3");
+    let mut buf = [0u8; IGMP_HEADER_LEN];
+    let mut packet = IgmpPacket::new_unchecked(&mut buf[..]);
+    packet.set_msg_type(IGMP_TYPE_MEMBERSHIP_QUERY);
+    packet.set_max_resp_code(100);
+    packet.set_group(group);
+    packet.fill_checksum();
+    let packet = IgmpPacket::new_checked(&buf[..]).unwrap();
+    assert!(packet.verify_checksum());
+    assert!(!packet.is_v1_query() && !packet.is_v3_query());
+    assert_eq!(packet.group(), group);
+    assert_eq!(packet.max_resp_time(), Duration::from_secs(10));
+
+    // IGMPv3 query with a floating point maximum response time: mant=0, exp=1
+    let mut v3 = [0u8; IGMP_V3_QUERY_MIN_LEN];
+    v3[field::TYPE] = IGMP_TYPE_MEMBERSHIP_QUERY;
+    v3[field::MAX_RESP] = 0x90;
+    v3[field::S_QRV] = 2;
+    let packet = IgmpPacket::new_checked(&v3[..]).unwrap();
+    assert!(packet.is_v3_query());
+    assert_eq!(packet.qrv(), 2);
+    assert_eq!(packet.max_resp_time(), Duration::from_millis(25600));
+
+    let v1 = [IGMP_TYPE_MEMBERSHIP_QUERY, 0, 0, 0, 0, 0, 0, 0];
+    assert!(IgmpPacket::new_checked(&v1[..]).unwrap().is_v1_query());
+
+    let report = igmp_v3_report(&[(IGMP_V3_CHANGE_TO_EXCLUDE, group)]);
+    assert_eq!(report.len(), IGMP_HEADER_LEN + IGMP_V3_RECORD_LEN);
+    assert!(
+        IgmpPacket::new_checked(&report[..])
+            .unwrap()
+            .verify_checksum()
+    );
+    assert_eq!(read_u16(&report, field::NUM_RECORDS), 1);
+    assert_eq!(&report[12..16], &group.octets());
+}
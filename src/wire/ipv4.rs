@@ -0,0 +1,265 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddress,
+    utils,
+};
+
+use super::{read_u16, write_checksum, write_u16};
+
+mod field {
+    pub const VHL: usize = 0;
+    pub const TOS: usize = 1;
+    pub const TOTAL: usize = 2;
+    pub const ID: usize = 4;
+    pub const OFFSET: usize = 6;
+    pub const TTL: usize = 8;
+    pub const PROTOCOL: usize = 9;
+    pub const SUM: usize = 10;
+    pub const SRC: usize = 12;
+    pub const DST: usize = 16;
+}
+
+pub const IPV4_HEADER_MIN_LEN: usize = 20;
+pub const IPV4_HEADER_MAX_LEN: usize = 60;
+
+/// View of an IPv4 datagram.
+pub struct Ipv4Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    /// Wraps a buffer without validating it. Only the setters may be used until the header has
+    /// been filled in.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Wraps a buffer after checking that the header and the total length fit in it.
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        packet.check_len()?;
+        Ok(packet)
+    }
+
+    pub fn check_len(&self) -> UtcpResult<()> {
+        let len = self.buffer.as_ref().len();
+        if len < IPV4_HEADER_MIN_LEN {
+            return Err(UtcpErr::Malformed("IP header is too short".into()));
+        }
+        let header_len = self.header_len();
+        if header_len < IPV4_HEADER_MIN_LEN || header_len > len {
+            return Err(UtcpErr::Malformed(format!(
+                "invalid IP header length: {}",
+                header_len
+            )));
+        }
+        let total = self.total() as usize;
+        if total < header_len || total > len {
+            return Err(UtcpErr::Malformed(format!(
+                "invalid IP total length: {}",
+                total
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[field::VHL] >> 4
+    }
+
+    /// Header length in bytes.
+    pub fn header_len(&self) -> usize {
+        ((self.buffer.as_ref()[field::VHL] & 0x0f) as usize) * 4
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.buffer.as_ref()[field::TOS]
+    }
+
+    pub fn total(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::TOTAL)
+    }
+
+    pub fn id(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::ID)
+    }
+
+    pub fn offset(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::OFFSET) & 0x1fff
+    }
+
+    pub fn flags(&self) -> u8 {
+        ((read_u16(self.buffer.as_ref(), field::OFFSET) & 0xe000) >> 13) as u8
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags() & 0x02 != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags() & 0x01 != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.buffer.as_ref()[field::TTL]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[field::PROTOCOL]
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    pub fn src(&self) -> IpAddress {
+        let buf = self.buffer.as_ref();
+        IpAddress::from([
+            buf[field::SRC],
+            buf[field::SRC + 1],
+            buf[field::SRC + 2],
+            buf[field::SRC + 3],
+        ])
+    }
+
+    pub fn dst(&self) -> IpAddress {
+        let buf = self.buffer.as_ref();
+        IpAddress::from([
+            buf[field::DST],
+            buf[field::DST + 1],
+            buf[field::DST + 2],
+            buf[field::DST + 3],
+        ])
+    }
+
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[IPV4_HEADER_MIN_LEN..self.header_len()]
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.header_len()]
+    }
+
+    /// Data after the header, up to the total length.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..self.total() as usize]
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        utils::checksum16(self.header(), 0) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    /// Sets the version to 4 and the header length in bytes.
+    pub fn set_header_len(&mut self, len: usize) {
+        self.buffer.as_mut()[field::VHL] = 0x40 | ((len / 4) as u8 & 0x0f);
+    }
+
+    pub fn set_tos(&mut self, tos: u8) {
+        self.buffer.as_mut()[field::TOS] = tos;
+    }
+
+    pub fn set_total(&mut self, total: u16) {
+        write_u16(self.buffer.as_mut(), field::TOTAL, total);
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        write_u16(self.buffer.as_mut(), field::ID, id);
+    }
+
+    /// Sets the flags (3 bits) and the fragment offset (13 bits, in 8 byte units).
+    pub fn set_flags_offset(&mut self, flags: u8, offset: u16) {
+        let value = ((flags as u16 & 0x07) << 13) | (offset & 0x1fff);
+        write_u16(self.buffer.as_mut(), field::OFFSET, value);
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.buffer.as_mut()[field::TTL] = ttl;
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.buffer.as_mut()[field::PROTOCOL] = protocol;
+    }
+
+    pub fn set_src(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::SRC..field::SRC + 4].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_dst(&mut self, addr: IpAddress) {
+        self.buffer.as_mut()[field::DST..field::DST + 4].copy_from_slice(&addr.octets());
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.header_len(), self.total() as usize);
+        &mut self.buffer.as_mut()[start..end]
+    }
+
+    /// Computes the header checksum. Call it after all the other header fields are set.
+    pub fn fill_checksum(&mut self) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let sum = utils::checksum16(self.header(), 0);
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for Ipv4Packet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "version={}, header_len={}, tos={}, total={}, id={}, offset={} df={}, mf={}, ttl={}, protocol={}, sum=0x{:04x}, src={}, dst={}",
+            self.version(),
+            self.header_len(),
+            self.tos(),
+            self.total(),
+            self.id(),
+            self.offset(),
+            self.dont_fragment(),
+            self.more_fragments(),
+            self.ttl(),
+            self.protocol(),
+            self.sum(),
+            self.src(),
+            self.dst()
+        )
+    }
+}
+
+#[test]
+fn test_ipv4_packet() {
+    let mut buf = [0u8; 28];
+    let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+    packet.set_header_len(IPV4_HEADER_MIN_LEN);
+    packet.set_total(28);
+    packet.set_id(0x80);
+    packet.set_flags_offset(0x02, 0);
+    packet.set_ttl(64);
+    packet.set_protocol(17);
+    packet.set_src(IpAddress::parse_from("10.0.0.1"));
+    packet.set_dst(IpAddress::parse_from("10.0.0.2"));
+    packet.payload_mut().copy_from_slice(b"abcdefgh");
+    packet.fill_checksum();
+
+    // unaligned buffer
+    let mut unaligned = [0u8; 29];
+    unaligned[1..].copy_from_slice(&buf);
+    let packet = Ipv4Packet::new_checked(&unaligned[1..]).unwrap();
+    assert_eq!(packet.version(), 4);
+    assert_eq!(packet.header_len(), 20);
+    assert_eq!(packet.total(), 28);
+    assert_eq!(packet.id(), 0x80);
+    assert!(packet.dont_fragment());
+    assert!(!packet.more_fragments());
+    assert_eq!(packet.protocol(), 17);
+    assert_eq!(packet.src(), IpAddress::parse_from("10.0.0.1"));
+    assert_eq!(packet.dst(), IpAddress::parse_from("10.0.0.2"));
+    assert_eq!(packet.payload(), b"abcdefgh");
+    assert!(packet.verify_checksum());
+
+    assert!(Ipv4Packet::new_checked(&buf[..19]).is_err());
+    assert!(Ipv4Packet::new_checked(&buf[..27]).is_err());
+}
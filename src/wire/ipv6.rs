@@ -0,0 +1,409 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ipv6::Ipv6Address,
+};
+
+use super::{read_u16, read_u32, write_u16, write_u32};
+
+mod field {
+    /// Version (4 bits), traffic class (8 bits) and flow label (20 bits).
+    pub const VER_TC_FLOW: usize = 0;
+    pub const PAYLOAD_LEN: usize = 4;
+    pub const NEXT_HEADER: usize = 6;
+    pub const HOP_LIMIT: usize = 7;
+    pub const SRC: usize = 8;
+    pub const DST: usize = 24;
+}
+
+pub const IPV6_HEADER_LEN: usize = 40;
+
+/// Next header values of the extension headers (RFC 8200 section 4).
+pub const IPV6_NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+pub const IPV6_NEXT_HEADER_ROUTING: u8 = 43;
+pub const IPV6_NEXT_HEADER_FRAGMENT: u8 = 44;
+pub const IPV6_NEXT_HEADER_NONE: u8 = 59;
+pub const IPV6_NEXT_HEADER_DEST_OPTS: u8 = 60;
+
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+const IPV6_OPTION_PAD1: u8 = 0;
+const IPV6_OPTION_PADN: u8 = 1;
+
+/// View of an IPv6 datagram.
+pub struct Ipv6Packet<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv6Packet<T> {
+    /// Wraps a buffer without validating it. Only the setters may be used until the header has
+    /// been filled in.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Wraps a buffer after checking that the header and the payload length fit in it.
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < IPV6_HEADER_LEN {
+            return Err(UtcpErr::Malformed("IPv6 header is too short".into()));
+        }
+        if IPV6_HEADER_LEN + packet.payload_len() as usize > len {
+            return Err(UtcpErr::Malformed(format!(
+                "invalid IPv6 payload length: {}",
+                packet.payload_len()
+            )));
+        }
+        Ok(packet)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[field::VER_TC_FLOW] >> 4
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (read_u16(self.buffer.as_ref(), field::VER_TC_FLOW) >> 4) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::VER_TC_FLOW) & 0x000f_ffff
+    }
+
+    pub fn payload_len(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::PAYLOAD_LEN)
+    }
+
+    pub fn next_header(&self) -> u8 {
+        self.buffer.as_ref()[field::NEXT_HEADER]
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.buffer.as_ref()[field::HOP_LIMIT]
+    }
+
+    pub fn src(&self) -> Ipv6Address {
+        Ipv6Address::from_bytes(&self.buffer.as_ref()[field::SRC..field::SRC + 16])
+    }
+
+    pub fn dst(&self) -> Ipv6Address {
+        Ipv6Address::from_bytes(&self.buffer.as_ref()[field::DST..field::DST + 16])
+    }
+
+    /// Extension headers and the upper-layer payload, without link-layer padding.
+    pub fn payload(&self) -> &[u8] {
+        let end = IPV6_HEADER_LEN + self.payload_len() as usize;
+        &self.buffer.as_ref()[IPV6_HEADER_LEN..end]
+    }
+
+    /// Walks the extension headers up to the upper-layer header.
+    pub fn upper_layer(&self) -> Result<Ipv6UpperLayer, Ipv6ExtError> {
+        ipv6_ext_headers_walk(self.next_header(), self.payload())
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv6Packet<T> {
+    /// Sets the version to 6, the traffic class and the flow label.
+    pub fn set_version_tc_flow(&mut self, traffic_class: u8, flow_label: u32) {
+        let value = (6 << 28) | ((traffic_class as u32) << 20) | (flow_label & 0x000f_ffff);
+        write_u32(self.buffer.as_mut(), field::VER_TC_FLOW, value);
+    }
+
+    pub fn set_payload_len(&mut self, len: u16) {
+        write_u16(self.buffer.as_mut(), field::PAYLOAD_LEN, len);
+    }
+
+    pub fn set_next_header(&mut self, next_header: u8) {
+        self.buffer.as_mut()[field::NEXT_HEADER] = next_header;
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.buffer.as_mut()[field::HOP_LIMIT] = hop_limit;
+    }
+
+    pub fn set_src(&mut self, addr: Ipv6Address) {
+        self.buffer.as_mut()[field::SRC..field::SRC + 16].copy_from_slice(addr.as_bytes());
+    }
+
+    pub fn set_dst(&mut self, addr: Ipv6Address) {
+        self.buffer.as_mut()[field::DST..field::DST + 16].copy_from_slice(addr.as_bytes());
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let end = IPV6_HEADER_LEN + self.payload_len() as usize;
+        &mut self.buffer.as_mut()[IPV6_HEADER_LEN..end]
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for Ipv6Packet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "src={}, dst={}, next_header={}, hop_limit={}, payload_len={}",
+            self.src(),
+            self.dst(),
+            self.next_header(),
+            self.hop_limit(),
+            self.payload_len()
+        )
+    }
+}
+
+/// Partial sum of the IPv6 pseudo header (RFC 8200 section 8.1) used by upper-layer
+/// checksums. Pass it as `init` to `utils::checksum16`.
+pub fn ipv6_pseudo_header_sum(
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    next_header: u8,
+    len: u32,
+) -> u32 {
+    let mut pseudo = [0u8; 40];
+    pseudo[0..16].copy_from_slice(src.as_bytes());
+    pseudo[16..32].copy_from_slice(dst.as_bytes());
+    pseudo[32..36].copy_from_slice(&len.to_be_bytes());
+    pseudo[39] = next_header;
+    pseudo
+        .chunks(2)
+        .map(|w| u16::from_le_bytes([w[0], w[1]]) as u32)
+        .sum()
+}
+
+/// Fragment header of a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Fragment {
+    pub id: u32,
+    /// Offset in bytes.
+    pub offset: usize,
+    pub more: bool,
+}
+
+impl Ipv6Fragment {
+    /// A fragment header with no offset and no more fragments stands for the whole datagram
+    /// (RFC 6946).
+    pub fn is_atomic(&self) -> bool {
+        self.offset == 0 && !self.more
+    }
+}
+
+/// Where the upper-layer header starts, found by `Ipv6Packet::upper_layer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6UpperLayer {
+    /// Next header value of the upper-layer protocol, `IPV6_NEXT_HEADER_NONE` if there is none.
+    pub protocol: u8,
+    /// Offset of the upper-layer header in the payload.
+    pub offset: usize,
+    /// Offset of the field holding `protocol` from the start of the IPv6 header, for
+    /// Parameter Problem messages about an unrecognized protocol.
+    pub pointer: usize,
+    pub fragment: Option<Ipv6Fragment>,
+}
+
+/// Why the extension headers of a datagram were rejected. Pointers are offsets from the start
+/// of the IPv6 header, for ICMPv6 Parameter Problem messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6ExtError {
+    /// Truncated or inconsistent headers, discarded silently.
+    Malformed,
+    /// A header field that can not be processed (code 0).
+    ErroneousField { pointer: usize },
+    /// A Hop-by-Hop Options header that is not the first one (code 1).
+    UnrecognizedNextHeader { pointer: usize },
+    /// An option whose type asks for the datagram to be discarded (code 2). `report` is set
+    /// if the type asks for a Parameter Problem message too, and `report_multicast` if the
+    /// message is sent for multicast destinations as well.
+    UnrecognizedOption {
+        pointer: usize,
+        report: bool,
+        report_multicast: bool,
+    },
+}
+
+/// Checks the options of a Hop-by-Hop or Destination Options header. `base` is the offset of
+/// the header from the start of the IPv6 header.
+fn ipv6_options_check(header: &[u8], base: usize) -> Result<(), Ipv6ExtError> {
+    let mut off = 2;
+    while off < header.len() {
+        let ty = header[off];
+        if ty == IPV6_OPTION_PAD1 {
+            off += 1;
+            continue;
+        }
+        let len = *header.get(off + 1).ok_or(Ipv6ExtError::Malformed)? as usize;
+        if off + 2 + len > header.len() {
+            return Err(Ipv6ExtError::Malformed);
+        }
+        // no option besides padding is implemented, the two high-order bits of the type say
+        // what to do with the others
+        if ty != IPV6_OPTION_PADN {
+            let pointer = base + off;
+            match ty >> 6 {
+                0 => {}
+                1 => {
+                    return Err(Ipv6ExtError::UnrecognizedOption {
+                        pointer,
+                        report: false,
+                        report_multicast: false,
+                    });
+                }
+                action => {
+                    return Err(Ipv6ExtError::UnrecognizedOption {
+                        pointer,
+                        report: true,
+                        report_multicast: action == 2,
+                    });
+                }
+            }
+        }
+        off += 2 + len;
+    }
+    Ok(())
+}
+
+/// Walks the extension headers of `payload`, the first of which is `next_header`.
+pub fn ipv6_ext_headers_walk(
+    mut next_header: u8,
+    payload: &[u8],
+) -> Result<Ipv6UpperLayer, Ipv6ExtError> {
+    let mut off = 0;
+    let mut fragment = None;
+    // offset of the field holding `next_header`, from the start of the IPv6 header
+    let mut next_header_field = field::NEXT_HEADER;
+    loop {
+        let base = IPV6_HEADER_LEN + off;
+        let header_len = match next_header {
+            IPV6_NEXT_HEADER_HOP_BY_HOP | IPV6_NEXT_HEADER_DEST_OPTS | IPV6_NEXT_HEADER_ROUTING => {
+                if next_header == IPV6_NEXT_HEADER_HOP_BY_HOP && off != 0 {
+                    return Err(Ipv6ExtError::UnrecognizedNextHeader {
+                        pointer: next_header_field,
+                    });
+                }
+                let len = payload.get(off + 1).ok_or(Ipv6ExtError::Malformed)?;
+                (*len as usize + 1) * 8
+            }
+            IPV6_NEXT_HEADER_FRAGMENT => IPV6_FRAGMENT_HEADER_LEN,
+            protocol => {
+                return Ok(Ipv6UpperLayer {
+                    protocol,
+                    offset: off,
+                    pointer: next_header_field,
+                    fragment,
+                });
+            }
+        };
+        let header = payload
+            .get(off..off + header_len)
+            .ok_or(Ipv6ExtError::Malformed)?;
+        match next_header {
+            IPV6_NEXT_HEADER_HOP_BY_HOP | IPV6_NEXT_HEADER_DEST_OPTS => {
+                ipv6_options_check(header, base)?;
+            }
+            // a host is never an intermediate destination
+            IPV6_NEXT_HEADER_ROUTING if header[3] != 0 => {
+                return Err(Ipv6ExtError::ErroneousField { pointer: base + 2 });
+            }
+            IPV6_NEXT_HEADER_FRAGMENT => {
+                let offset_flags = read_u16(header, 2);
+                fragment = Some(Ipv6Fragment {
+                    id: read_u32(header, 4),
+                    offset: (offset_flags & 0xfff8) as usize,
+                    more: offset_flags & 0x0001 != 0,
+                });
+            }
+            _ => {}
+        }
+        next_header_field = base;
+        next_header = header[0];
+        off += header_len;
+    }
+}
+
+#[test]
+fn test_ipv6_packet() {
+    let src: Ipv6Address = "fe80::1".parse().unwrap();
+    let dst: Ipv6Address = "2001:db8::2".parse().unwrap();
+    // destination options with PadN, then an atomic fragment header, then 4 bytes of UDP
+    let ext = [
+        IPV6_NEXT_HEADER_FRAGMENT,
+        0,
+        IPV6_OPTION_PADN,
+        4,
+        0,
+        0,
+        0,
+        0,
+        17,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0x12,
+        0x34,
+        1,
+        2,
+        3,
+        4,
+    ];
+    let mut buf = vec![0u8; IPV6_HEADER_LEN + ext.len()];
+    let mut packet = Ipv6Packet::new_unchecked(&mut buf[..]);
+    packet.set_version_tc_flow(0x12, 0xabcde);
+    packet.set_payload_len(ext.len() as u16);
+    packet.set_next_header(IPV6_NEXT_HEADER_DEST_OPTS);
+    packet.set_hop_limit(64);
+    packet.set_src(src);
+    packet.set_dst(dst);
+    packet.payload_mut().copy_from_slice(&ext);
+
+    let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+    assert_eq!(packet.version(), 6);
+    assert_eq!(packet.traffic_class(), 0x12);
+    assert_eq!(packet.flow_label(), 0xabcde);
+    assert_eq!((packet.src(), packet.dst()), (src, dst));
+    let upper = packet.upper_layer().unwrap();
+    assert_eq!(upper.protocol, 17);
+    assert_eq!(upper.offset, 16);
+    assert_eq!(upper.pointer, IPV6_HEADER_LEN + 8);
+    assert_eq!(
+        upper.fragment,
+        Some(Ipv6Fragment {
+            id: 0x1234,
+            offset: 0,
+            more: false
+        })
+    );
+    assert!(upper.fragment.unwrap().is_atomic());
+    assert!(Ipv6Packet::new_checked(&buf[..IPV6_HEADER_LEN + 4]).is_err());
+
+    // an option of type 0x80 is discarded with a Parameter Problem at its offset
+    let opts = [6, 0, 0x80, 0, 0, 0, 0, 0];
+    assert_eq!(
+        ipv6_ext_headers_walk(IPV6_NEXT_HEADER_HOP_BY_HOP, &opts),
+        Err(Ipv6ExtError::UnrecognizedOption {
+            pointer: IPV6_HEADER_LEN + 2,
+            report: true,
+            report_multicast: true
+        })
+    );
+    // a Hop-by-Hop header after another header
+    let late = [0, 0, IPV6_OPTION_PADN, 4, 0, 0, 0, 0];
+    assert_eq!(
+        ipv6_ext_headers_walk(IPV6_NEXT_HEADER_DEST_OPTS, &late),
+        Err(Ipv6ExtError::UnrecognizedNextHeader {
+            pointer: IPV6_HEADER_LEN
+        })
+    );
+    // a routing header with segments left
+    let routing = [59, 0, 0, 1, 0, 0, 0, 0];
+    assert_eq!(
+        ipv6_ext_headers_walk(IPV6_NEXT_HEADER_ROUTING, &routing),
+        Err(Ipv6ExtError::ErroneousField {
+            pointer: IPV6_HEADER_LEN + 2
+        })
+    );
+    assert_eq!(
+        ipv6_ext_headers_walk(IPV6_NEXT_HEADER_DEST_OPTS, &opts[..4]),
+        Err(Ipv6ExtError::Malformed)
+    );
+}
@@ -0,0 +1,84 @@
+//! Bounds-checked views over packet headers.
+//!
+//! Every view wraps a byte buffer and reads fields through byte offsets, so the buffer may have
+//! any alignment. `new_checked` validates the lengths once, after which the accessors can not go
+//! out of bounds. When the buffer is mutable the same types work as builders: the setters write
+//! the fields in network byte order and `fill_checksum` computes the checksum.
+
+pub mod arp;
+pub mod dhcpv4;
+pub mod dns;
+pub mod ethernet;
+pub mod icmp;
+pub mod icmpv6;
+pub mod igmp;
+pub mod ipv4;
+pub mod ipv6;
+pub mod tcp;
+pub mod udp;
+pub mod vlan;
+
+use crate::{
+    ip::{IpAddr, IpAddress},
+    utils,
+};
+
+pub(crate) fn read_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([buf[off], buf[off + 1]])
+}
+
+pub(crate) fn read_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+pub(crate) fn write_u16(buf: &mut [u8], off: usize, value: u16) {
+    buf[off..off + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn write_u32(buf: &mut [u8], off: usize, value: u32) {
+    buf[off..off + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Writes a value computed by `utils::checksum16`, which sums native (little-endian) words.
+pub(crate) fn write_checksum(buf: &mut [u8], off: usize, sum: u16) {
+    buf[off..off + 2].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// Partial sum of the IPv4 pseudo header used by the UDP and TCP checksums. Pass it as `init`
+/// to `utils::checksum16`.
+pub fn pseudo_header_sum(src: IpAddress, dst: IpAddress, protocol: u8, len: u16) -> u32 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.octets());
+    pseudo[4..8].copy_from_slice(&dst.octets());
+    pseudo[9] = protocol;
+    pseudo[10..12].copy_from_slice(&len.to_be_bytes());
+    pseudo
+        .chunks(2)
+        .map(|w| u16::from_le_bytes([w[0], w[1]]) as u32)
+        .sum()
+}
+
+/// Checksum of a transport segment including the pseudo header of the family of the
+/// addresses. An IPv4 address paired with an IPv4-mapped one is summed as IPv4. Any other mix
+/// of families is a bug of the caller, and is summed with the IPv6 forms of the addresses, so
+/// that the checksum is defined but verifies with neither family.
+pub(crate) fn transport_checksum(src: IpAddr, dst: IpAddr, protocol: u8, data: &[u8]) -> u16 {
+    let init = match (src, dst) {
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            ipv6::ipv6_pseudo_header_sum(src, dst, protocol, data.len() as u32)
+        }
+        _ => match (src.to_ipv4(), dst.to_ipv4()) {
+            (Some(src), Some(dst)) => pseudo_header_sum(src, dst, protocol, data.len() as u16),
+            _ => {
+                debug_assert!(
+                    false,
+                    "pseudo header of mixed families: src={}, dst={}",
+                    src, dst
+                );
+                let (src, dst) = (src.to_ipv6_mapped(), dst.to_ipv6_mapped());
+                ipv6::ipv6_pseudo_header_sum(src, dst, protocol, data.len() as u32)
+            }
+        },
+    };
+    utils::checksum16(data, init)
+}
@@ -0,0 +1,231 @@
+use bitflags::bitflags;
+
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddr,
+};
+
+use super::{read_u16, read_u32, transport_checksum, write_checksum, write_u16, write_u32};
+
+mod field {
+    pub const SRC_PORT: usize = 0;
+    pub const DST_PORT: usize = 2;
+    pub const SEQ: usize = 4;
+    pub const ACK: usize = 8;
+    pub const OFFSET: usize = 12;
+    pub const FLAGS: usize = 13;
+    pub const WINDOW: usize = 14;
+    pub const SUM: usize = 16;
+    pub const URGENT: usize = 18;
+}
+
+pub const TCP_HEADER_MIN_LEN: usize = 20;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TcpFlags: u8 {
+        const FIN = 0x01;
+        const SYN = 0x02;
+        const RST = 0x04;
+        const PSH = 0x08;
+        const ACK = 0x10;
+        const URG = 0x20;
+        const ECE = 0x40;
+        const CWR = 0x80;
+    }
+}
+
+/// View of a TCP segment. The segment extends to the end of the buffer.
+pub struct TcpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> TcpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let len = packet.buffer.as_ref().len();
+        if len < TCP_HEADER_MIN_LEN {
+            return Err(UtcpErr::Malformed("TCP header is too short".into()));
+        }
+        let header_len = packet.header_len();
+        if header_len < TCP_HEADER_MIN_LEN || header_len > len {
+            return Err(UtcpErr::Malformed(format!(
+                "invalid TCP header length: {}",
+                header_len
+            )));
+        }
+        Ok(packet)
+    }
+
+    pub fn src_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SRC_PORT)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::DST_PORT)
+    }
+
+    pub fn seq(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::SEQ)
+    }
+
+    pub fn ack(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), field::ACK)
+    }
+
+    /// Header length in bytes.
+    pub fn header_len(&self) -> usize {
+        ((self.buffer.as_ref()[field::OFFSET] >> 4) as usize) * 4
+    }
+
+    pub fn flags(&self) -> TcpFlags {
+        TcpFlags::from_bits_truncate(self.buffer.as_ref()[field::FLAGS])
+    }
+
+    pub fn window(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::WINDOW)
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    pub fn urgent(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::URGENT)
+    }
+
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[TCP_HEADER_MIN_LEN..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..]
+    }
+
+    /// Sequence space consumed by the segment (SYN and FIN count as one).
+    pub fn segment_len(&self) -> usize {
+        let flags = self.flags();
+        self.payload().len()
+            + flags.contains(TcpFlags::SYN) as usize
+            + flags.contains(TcpFlags::FIN) as usize
+    }
+
+    pub fn verify_checksum(&self, src: impl Into<IpAddr>, dst: impl Into<IpAddr>) -> bool {
+        transport_checksum(
+            src.into(),
+            dst.into(),
+            crate::ip::IP_PROTOCOL_TCP,
+            self.buffer.as_ref(),
+        ) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> TcpPacket<T> {
+    pub fn set_src_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), field::SRC_PORT, port);
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), field::DST_PORT, port);
+    }
+
+    pub fn set_seq(&mut self, seq: u32) {
+        write_u32(self.buffer.as_mut(), field::SEQ, seq);
+    }
+
+    pub fn set_ack(&mut self, ack: u32) {
+        write_u32(self.buffer.as_mut(), field::ACK, ack);
+    }
+
+    pub fn set_header_len(&mut self, len: usize) {
+        self.buffer.as_mut()[field::OFFSET] = ((len / 4) as u8) << 4;
+    }
+
+    pub fn set_flags(&mut self, flags: TcpFlags) {
+        self.buffer.as_mut()[field::FLAGS] = flags.bits();
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        write_u16(self.buffer.as_mut(), field::WINDOW, window);
+    }
+
+    pub fn set_urgent(&mut self, urgent: u16) {
+        write_u16(self.buffer.as_mut(), field::URGENT, urgent);
+    }
+
+    pub fn options_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buffer.as_mut()[TCP_HEADER_MIN_LEN..header_len]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buffer.as_mut()[header_len..]
+    }
+
+    pub fn fill_checksum(&mut self, src: impl Into<IpAddr>, dst: impl Into<IpAddr>) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let sum = transport_checksum(
+            src.into(),
+            dst.into(),
+            crate::ip::IP_PROTOCOL_TCP,
+            self.buffer.as_ref(),
+        );
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for TcpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "src_port={}, dst_port={}, seq={}, ack={}, flags={:?}, window={}, sum=0x{:04x}, len={}",
+            self.src_port(),
+            self.dst_port(),
+            self.seq(),
+            self.ack(),
+            self.flags(),
+            self.window(),
+            self.sum(),
+            self.payload().len()
+        )
+    }
+}
+
+#[test]
+fn test_tcp_packet() {
+    use crate::ip::IpAddress;
+
+    let src = IpAddress::parse_from("192.0.2.1");
+    let dst = IpAddress::parse_from("192.0.2.2");
+    let mut buf = [0u8; 27];
+    let mut packet = TcpPacket::new_unchecked(&mut buf[..]);
+    packet.set_src_port(49152);
+    packet.set_dst_port(80);
+    packet.set_seq(1000);
+    packet.set_ack(2000);
+    packet.set_header_len(24);
+    packet.options_mut().copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    packet.set_flags(TcpFlags::SYN | TcpFlags::ACK);
+    packet.set_window(65535);
+    packet.payload_mut().copy_from_slice(b"abc");
+    packet.fill_checksum(src, dst);
+
+    let packet = TcpPacket::new_checked(&buf[..]).unwrap();
+    assert_eq!(packet.src_port(), 49152);
+    assert_eq!(packet.dst_port(), 80);
+    assert_eq!(packet.seq(), 1000);
+    assert_eq!(packet.ack(), 2000);
+    assert_eq!(packet.header_len(), 24);
+    assert_eq!(packet.options(), &[2, 4, 0x05, 0xb4]);
+    assert_eq!(packet.flags(), TcpFlags::SYN | TcpFlags::ACK);
+    assert_eq!(packet.payload(), b"abc");
+    assert_eq!(packet.segment_len(), 4);
+    assert!(packet.verify_checksum(src, dst));
+
+    assert!(TcpPacket::new_checked(&buf[..23]).is_err());
+}
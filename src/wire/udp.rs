@@ -0,0 +1,167 @@
+use crate::{
+    error::{UtcpErr, UtcpResult},
+    ip::IpAddr,
+};
+
+use super::{read_u16, transport_checksum, write_checksum, write_u16};
+
+mod field {
+    pub const SRC_PORT: usize = 0;
+    pub const DST_PORT: usize = 2;
+    pub const LEN: usize = 4;
+    pub const SUM: usize = 6;
+}
+
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// View of a UDP datagram.
+pub struct UdpPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> UdpPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        let buf_len = packet.buffer.as_ref().len();
+        if buf_len < UDP_HEADER_LEN {
+            return Err(UtcpErr::Malformed("UDP header is too short".into()));
+        }
+        let len = packet.len() as usize;
+        if len < UDP_HEADER_LEN || len > buf_len {
+            return Err(UtcpErr::Malformed(format!("invalid UDP length: {}", len)));
+        }
+        Ok(packet)
+    }
+
+    pub fn src_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SRC_PORT)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::DST_PORT)
+    }
+
+    /// Length of the header and the data.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::LEN)
+    }
+
+    pub fn sum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::SUM)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[UDP_HEADER_LEN..self.len() as usize]
+    }
+
+    /// Verifies the checksum with the pseudo header of the family of the addresses. A zero
+    /// checksum means that the sender did not compute one, which IPv6 does not allow (RFC 8200
+    /// section 8.1).
+    pub fn verify_checksum(&self, src: impl Into<IpAddr>, dst: impl Into<IpAddr>) -> bool {
+        let (src, dst) = (src.into(), dst.into());
+        if self.sum() == 0 {
+            return matches!(src, IpAddr::V4(_));
+        }
+        let data = &self.buffer.as_ref()[..self.len() as usize];
+        transport_checksum(src, dst, crate::ip::IP_PROTOCOL_UDP, data) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpPacket<T> {
+    pub fn set_src_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), field::SRC_PORT, port);
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        write_u16(self.buffer.as_mut(), field::DST_PORT, port);
+    }
+
+    pub fn set_len(&mut self, len: u16) {
+        write_u16(self.buffer.as_mut(), field::LEN, len);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let len = self.len() as usize;
+        &mut self.buffer.as_mut()[UDP_HEADER_LEN..len]
+    }
+
+    /// Computes the checksum with the pseudo header of the family of the addresses. Call it
+    /// after the length is set.
+    pub fn fill_checksum(&mut self, src: impl Into<IpAddr>, dst: impl Into<IpAddr>) {
+        write_checksum(self.buffer.as_mut(), field::SUM, 0);
+        let len = self.len() as usize;
+        let mut sum = transport_checksum(
+            src.into(),
+            dst.into(),
+            crate::ip::IP_PROTOCOL_UDP,
+            &self.buffer.as_ref()[..len],
+        );
+        // zero means "no checksum" in UDP
+        if sum == 0 {
+            sum = 0xffff;
+        }
+        write_checksum(self.buffer.as_mut(), field::SUM, sum);
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for UdpPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "src_port={}, dst_port={}, len={}, sum=0x{:04x}",
+            self.src_port(),
+            self.dst_port(),
+            self.len(),
+            self.sum()
+        )
+    }
+}
+
+#[test]
+fn test_udp_packet() {
+    use crate::{ip::IpAddress, ipv6::Ipv6Address};
+
+    let src = IpAddress::parse_from("192.0.2.1");
+    let dst = IpAddress::parse_from("192.0.2.2");
+    let mut buf = [0u8; 13];
+    let mut packet = UdpPacket::new_unchecked(&mut buf[..]);
+    packet.set_src_port(7);
+    packet.set_dst_port(10007);
+    packet.set_len(13);
+    packet.payload_mut().copy_from_slice(b"hello");
+    packet.fill_checksum(src, dst);
+
+    let packet = UdpPacket::new_checked(&buf[..]).unwrap();
+    assert_eq!(packet.src_port(), 7);
+    assert_eq!(packet.dst_port(), 10007);
+    assert_eq!(packet.payload(), b"hello");
+    assert!(packet.verify_checksum(src, dst));
+    assert!(!packet.verify_checksum(src, IpAddress::parse_from("192.0.2.3")));
+    // an IPv4-mapped address stands for the IPv4 one
+    assert!(packet.verify_checksum(src, Ipv6Address::from_ipv4_mapped(dst)));
+
+    assert!(UdpPacket::new_checked(&buf[..12]).is_err());
+
+    // the same datagram over IPv6 has another checksum, which must not be zero
+    let src: Ipv6Address = "2001:db8::1".parse().unwrap();
+    let dst: Ipv6Address = "2001:db8::2".parse().unwrap();
+    let mut packet = UdpPacket::new_unchecked(&mut buf[..]);
+    packet.fill_checksum(src, dst);
+    let packet = UdpPacket::new_checked(&buf[..]).unwrap();
+    assert!(packet.verify_checksum(src, dst));
+    assert!(!packet.verify_checksum(
+        IpAddress::parse_from("192.0.2.1"),
+        IpAddress::parse_from("192.0.2.2")
+    ));
+    buf[6..8].fill(0);
+    assert!(
+        !UdpPacket::new_checked(&buf[..])
+            .unwrap()
+            .verify_checksum(src, dst)
+    );
+}
@@ -0,0 +1,115 @@
+use crate::error::{UtcpErr, UtcpResult};
+
+use super::{read_u16, write_u16};
+
+mod field {
+    pub const TCI: usize = 0;
+    pub const TYPE: usize = 2;
+}
+
+/// Length of the tag after the TPID, that is the part of an 802.1Q header that follows the
+/// Ethernet header whose type is `ETHERNET_TYPE_VLAN`.
+pub const VLAN_HEADER_LEN: usize = 4;
+/// TPID of 802.1Q tags, carried in the type field of the Ethernet header.
+pub const ETHERNET_TYPE_VLAN: u16 = 0x8100;
+
+/// VID 0 tags priority only, and 4095 is reserved.
+pub const VLAN_VID_MAX: u16 = 4094;
+pub const VLAN_PCP_MAX: u8 = 7;
+
+const VLAN_VID_MASK: u16 = 0x0fff;
+const VLAN_DEI: u16 = 0x1000;
+const VLAN_PCP_SHIFT: u16 = 13;
+
+/// View of an 802.1Q tag: the TCI and the type of the encapsulated payload.
+pub struct VlanPacket<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> VlanPacket<T> {
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    pub fn new_checked(buffer: T) -> UtcpResult<Self> {
+        let packet = Self::new_unchecked(buffer);
+        if packet.buffer.as_ref().len() < VLAN_HEADER_LEN {
+            return Err(UtcpErr::Malformed("802.1Q tag is too short".into()));
+        }
+        Ok(packet)
+    }
+
+    fn tci(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::TCI)
+    }
+
+    /// Priority code point.
+    pub fn pcp(&self) -> u8 {
+        (self.tci() >> VLAN_PCP_SHIFT) as u8
+    }
+
+    /// Drop eligible indicator.
+    pub fn dei(&self) -> bool {
+        self.tci() & VLAN_DEI != 0
+    }
+
+    pub fn vid(&self) -> u16 {
+        self.tci() & VLAN_VID_MASK
+    }
+
+    pub fn ethertype(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), field::TYPE)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[VLAN_HEADER_LEN..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> VlanPacket<T> {
+    /// Sets the whole TCI, with DEI cleared.
+    pub fn set_tci(&mut self, pcp: u8, vid: u16) {
+        let tci = ((pcp as u16) << VLAN_PCP_SHIFT) | (vid & VLAN_VID_MASK);
+        write_u16(self.buffer.as_mut(), field::TCI, tci);
+    }
+
+    pub fn set_ethertype(&mut self, ty: u16) {
+        write_u16(self.buffer.as_mut(), field::TYPE, ty);
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[VLAN_HEADER_LEN..]
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for VlanPacket<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "vid={}, pcp={}, dei={}, type=0x{:04x}",
+            self.vid(),
+            self.pcp(),
+            self.dei(),
+            self.ethertype()
+        )
+    }
+}
+
+#[test]
+fn test_vlan_packet() {
+    let mut buf = [0u8; VLAN_HEADER_LEN + 2];
+    let mut packet = VlanPacket::new_unchecked(&mut buf[..]);
+    packet.set_tci(5, 100);
+    packet.set_ethertype(0x0800);
+    packet.payload_mut().copy_from_slice(&[0xab, 0xcd]);
+    assert_eq!(buf, [0xa0, 0x64, 0x08, 0x00, 0xab, 0xcd]);
+
+    let packet = VlanPacket::new_checked(&buf[..]).unwrap();
+    assert_eq!((packet.pcp(), packet.dei(), packet.vid()), (5, false, 100));
+    assert_eq!(packet.ethertype(), 0x0800);
+    assert_eq!(packet.payload(), &[0xab, 0xcd]);
+
+    let packet = VlanPacket::new_checked(&[0x1f, 0xff, 0x86, 0xdd][..]).unwrap();
+    assert_eq!((packet.pcp(), packet.dei(), packet.vid()), (0, true, 4095));
+    assert!(VlanPacket::new_checked(&[0u8; 3][..]).is_err());
+}
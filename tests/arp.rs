@@ -0,0 +1,161 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::Link;
+use utcp::{
+    UdpSocket,
+    arp::{self, ArpState},
+    ip::{self, IpAddress},
+    net::{self, NET_PROTOCOL_TYPE_ARP, NET_PROTOCOL_TYPE_IP},
+    wire::{
+        arp::{ARP_ETHER_IP_LEN, ARP_OP_REPLY, ARP_OP_REQUEST, ArpPacket},
+        ethernet::{EthernetAddress, EthernetFrame},
+        ipv4::Ipv4Packet,
+        udp::UdpPacket,
+    },
+};
+
+const ADDR: IpAddress = IpAddress::parse_from("10.99.61.1");
+const PEER: IpAddress = IpAddress::parse_from("10.99.61.2");
+/// Never answers.
+const SILENT: IpAddress = IpAddress::parse_from("10.99.61.3");
+const ASKER: IpAddress = IpAddress::parse_from("10.99.61.4");
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x61, 0x01]);
+const PEER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x61, 0x02]);
+
+/// Same as in `arp.rs`.
+const ARP_PENDING_LIMIT: u8 = 8;
+const ARP_REQUEST_MAX: usize = 3;
+
+/// The peer, which has the other addresses of the link.
+struct Peer(Link);
+
+impl Peer {
+    fn send_arp(&self, op: u16, spa: IpAddress, tha: EthernetAddress, tpa: IpAddress) {
+        let mut buf = [0u8; ARP_ETHER_IP_LEN];
+        let mut packet = ArpPacket::new_unchecked(&mut buf[..]);
+        packet.set_ether_ip(NET_PROTOCOL_TYPE_IP);
+        packet.set_operation(op);
+        packet.set_sender_hw_addr(PEER_HWADDR);
+        packet.set_sender_proto_addr(spa);
+        packet.set_target_hw_addr(tha);
+        packet.set_target_proto_addr(tpa);
+        let dst = match op {
+            ARP_OP_REQUEST => EthernetAddress::BROADCAST,
+            _ => HWADDR,
+        };
+        self.0
+            .send_frame(dst, PEER_HWADDR, NET_PROTOCOL_TYPE_ARP, &buf);
+    }
+
+    /// Returns the destination, type and payload of the next frame from the stack.
+    fn recv(&self) -> Option<(EthernetAddress, u16, Vec<u8>)> {
+        self.0.recv(Duration::from_millis(300), |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            assert_eq!(frame.src(), HWADDR);
+            Some((frame.dst(), frame.ethertype(), frame.payload().to_vec()))
+        })
+    }
+
+    /// Returns the ARP requests for `tpa` and the UDP payloads received until nothing comes
+    /// for a while.
+    fn drain(&self, tpa: IpAddress) -> (usize, Vec<Vec<u8>>) {
+        let (mut requests, mut payloads) = (0, Vec::new());
+        while let Some((dst, ty, payload)) = self.recv() {
+            match ty {
+                NET_PROTOCOL_TYPE_ARP => {
+                    let packet = ArpPacket::new_checked(&payload[..]).unwrap();
+                    if packet.operation() == ARP_OP_REQUEST && packet.target_proto_addr() == tpa {
+                        assert_eq!(dst, EthernetAddress::BROADCAST);
+                        assert_eq!(packet.sender_hw_addr(), HWADDR);
+                        assert_eq!(packet.sender_proto_addr(), ADDR);
+                        requests += 1;
+                    }
+                }
+                NET_PROTOCOL_TYPE_IP => {
+                    assert_eq!(dst, PEER_HWADDR);
+                    let ip_hdr = Ipv4Packet::new_checked(&payload[..]).unwrap();
+                    let udp = UdpPacket::new_checked(ip_hdr.payload()).unwrap();
+                    payloads.push(udp.payload().to_vec());
+                }
+                _ => {}
+            }
+        }
+        (requests, payloads)
+    }
+}
+
+fn wait_until(cond: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(6);
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// Resolution, the datagrams queued meanwhile, answering requests and giving up on an
+/// address nobody answers for, over an Ethernet tunnel to a host socket.
+#[test]
+fn arp_resolve() {
+    net::net_init().unwrap();
+    let (link, dev) = Link::open(Some(HWADDR));
+    let netmask = IpAddress::parse_from("255.255.255.0");
+    ip::ip_iface_register(dev, ip::IpInterface::new(ADDR, netmask)).unwrap();
+    net::net_run().unwrap();
+    let link = Peer(link);
+
+    // the first datagram sends a request and waits with the next ones, of which the newest
+    // are kept
+    let udp = UdpSocket::bind(format!("{}:7000", ADDR)).unwrap();
+    for i in 0..ARP_PENDING_LIMIT + 2 {
+        udp.send_to(&[i], format!("{}:9", PEER)).unwrap();
+    }
+    let (requests, payloads) = link.drain(PEER);
+    assert!(requests >= 1);
+    assert!(payloads.is_empty());
+    assert!(matches!(
+        arp::arp_lookup(PEER),
+        Some((_, ArpState::Incomplete))
+    ));
+
+    // the reply sends them in order
+    link.send_arp(ARP_OP_REPLY, PEER, HWADDR, ADDR);
+    let (_, payloads) = link.drain(PEER);
+    let expected: Vec<Vec<u8>> = (2..ARP_PENDING_LIMIT + 2).map(|i| vec![i]).collect();
+    assert_eq!(payloads, expected);
+    assert_eq!(
+        arp::arp_lookup(PEER),
+        Some((PEER_HWADDR, ArpState::Resolved))
+    );
+    // and the next datagram goes out right away
+    udp.send_to(b"now", format!("{}:9", PEER)).unwrap();
+    assert_eq!(link.drain(PEER), (0, vec![b"now".to_vec()]));
+
+    // a request for the address of the stack is answered, and the asker is cached
+    link.send_arp(ARP_OP_REQUEST, ASKER, EthernetAddress::ANY, ADDR);
+    let (dst, ty, payload) = link.recv().unwrap();
+    assert_eq!((dst, ty), (PEER_HWADDR, NET_PROTOCOL_TYPE_ARP));
+    let reply = ArpPacket::new_checked(&payload[..]).unwrap();
+    assert_eq!(reply.operation(), ARP_OP_REPLY);
+    assert_eq!(reply.sender_hw_addr(), HWADDR);
+    assert_eq!(reply.sender_proto_addr(), ADDR);
+    assert_eq!(reply.target_proto_addr(), ASKER);
+    assert_eq!(
+        arp::arp_lookup(ASKER),
+        Some((PEER_HWADDR, ArpState::Resolved))
+    );
+
+    // an address nobody answers for is requested a few times, then forgotten with the
+    // datagram waiting for it
+    udp.send_to(b"lost", format!("{}:9", SILENT)).unwrap();
+    assert!(wait_until(|| arp::arp_lookup(SILENT).is_some()));
+    assert!(wait_until(|| arp::arp_lookup(SILENT).is_none()));
+    assert_eq!(link.drain(SILENT), (ARP_REQUEST_MAX, vec![]));
+
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,170 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    AsyncTcpListener, AsyncTcpStream, AsyncUdpSocket,
+    driver::loopback::LoopbackNetDevice,
+    event,
+    ip::{self, IpAddress},
+    net,
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Counts how many times the task was woken.
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Minimal executor: parks the thread until the future is woken.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn eventfd_readable(fd: i32) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 1000) == 1 }
+}
+
+fn tcp_echo() {
+    let addr: SocketAddr = "127.0.0.1:7100".parse().unwrap();
+    let listener = AsyncTcpListener::bind(addr).unwrap();
+
+    let server = thread::spawn(move || {
+        block_on(async {
+            let (stream, peer) = listener.accept().await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), peer);
+            let mut buf = [0u8; 1024];
+            loop {
+                let len = stream.read(&mut buf).await.unwrap();
+                if len == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..len]).await.unwrap();
+            }
+        })
+    });
+
+    block_on(async {
+        let stream = AsyncTcpStream::connect(addr).await.unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+        // fits in the send and receive buffers of both ends, so the echo never stalls
+        stream.write_all(&data).await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while received.len() < data.len() {
+            let len = stream.read(&mut buf).await.unwrap();
+            assert_ne!(len, 0);
+            received.extend_from_slice(&buf[..len]);
+        }
+        stream.shutdown().await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(received == data);
+    });
+    server.join().unwrap();
+
+    let err = block_on(AsyncTcpStream::connect("127.0.0.1:7101".parse().unwrap())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+fn udp_eventfd() {
+    let efd = event::event_fd().unwrap();
+    let server = AsyncUdpSocket::bind("127.0.0.1:7102".parse().unwrap()).unwrap();
+    let client = AsyncUdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.connect(server.local_addr().unwrap()).unwrap();
+
+    event::event_clear().unwrap();
+    block_on(client.send(b"ping")).unwrap();
+    // the delivery in the softirq signals the eventfd
+    assert!(eventfd_readable(efd));
+    event::event_clear().unwrap();
+
+    let mut buf = [0u8; 16];
+    let (len, from) = block_on(server.recv_from(&mut buf)).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, client.local_addr().unwrap());
+    block_on(server.send_to(b"pong", from)).unwrap();
+    let len = block_on(client.recv(&mut buf)).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+}
+
+/// A datagram for one socket wakes the task waiting on it and leaves the others asleep.
+fn wake_only_ready_socket() {
+    let first = AsyncUdpSocket::bind("127.0.0.1:7103".parse().unwrap()).unwrap();
+    let second = AsyncUdpSocket::bind("127.0.0.1:7104".parse().unwrap()).unwrap();
+    let client = AsyncUdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let wakers = [
+        Arc::new(CountingWaker::default()),
+        Arc::new(CountingWaker::default()),
+    ];
+    let mut buf = [0u8; 16];
+    for (socket, waker) in [&first, &second].into_iter().zip(&wakers) {
+        let waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(socket.poll_recv_from(&mut cx, &mut buf).is_pending());
+    }
+
+    block_on(client.send_to(b"first", first.local_addr().unwrap())).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while wakers[0].0.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(wakers[0].0.load(Ordering::SeqCst), 1);
+    assert_eq!(wakers[1].0.load(Ordering::SeqCst), 0);
+
+    let (len, _) = block_on(first.recv_from(&mut buf)).unwrap();
+    assert_eq!(&buf[..len], b"first");
+}
+
+#[test]
+fn async_api() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    tcp_echo();
+    udp_eventfd();
+    wake_only_ready_socket();
+
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use std::{sync::MutexGuard, time::Duration};
+
+use common::{Link, Shared};
+use utcp::{
+    UdpSocket,
+    driver::bridge::{self, BRIDGE_AGEING_TIME, BridgeNetDevice},
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    net::{self, NET_PROTOCOL_TYPE_ARP, NET_PROTOCOL_TYPE_IP, NetDeviceHandler},
+    wire::{
+        arp::{ARP_ETHER_IP_LEN, ARP_OP_REPLY, ARP_OP_REQUEST, ArpPacket},
+        ethernet::{ETHERNET_HEADER_LEN, ETHERNET_PAYLOAD_MIN_LEN, EthernetAddress, EthernetFrame},
+    },
+};
+
+/// Local experimental type, which nothing handles.
+const TEST_TYPE: u16 = 0x88b5;
+const BRIDGE_ADDR: IpAddress = IpAddress::parse_from("10.99.45.2");
+const HOST_ADDR: IpAddress = IpAddress::parse_from("10.99.45.1");
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x44]);
+const HOST_A: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0xa0, 0x01]);
+const HOST_B: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0xa0, 0x02]);
+const UNKNOWN: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0xa0, 0x03]);
+
+const SHORT: Duration = Duration::from_millis(200);
+const LONG: Duration = Duration::from_secs(2);
+
+/// A segment attached to a bridge port, whose hosts the test plays.
+struct Segment(Link);
+
+impl Segment {
+    fn send(&self, frame: &[u8]) {
+        self.0.send(frame);
+    }
+
+    /// Waits for a while for a frame of type `ty` coming out of the bridge.
+    fn recv(&self, ty: u16, timeout: Duration) -> Option<Vec<u8>> {
+        self.0.recv(timeout, |frame| {
+            (EthernetFrame::new_checked(frame).unwrap().ethertype() == ty).then(|| frame.to_vec())
+        })
+    }
+}
+
+fn frame(src: EthernetAddress, dst: EthernetAddress, ty: u16, payload: &[u8]) -> Vec<u8> {
+    let len = ETHERNET_HEADER_LEN + payload.len().max(ETHERNET_PAYLOAD_MIN_LEN);
+    let mut buf = vec![0u8; len];
+    let mut eth = EthernetFrame::new_unchecked(&mut buf[..]);
+    eth.set_dst(dst);
+    eth.set_src(src);
+    eth.set_ethertype(ty);
+    eth.payload_mut()[..payload.len()].copy_from_slice(payload);
+    buf
+}
+
+fn arp_request(src: EthernetAddress, sender: IpAddress, target: IpAddress) -> Vec<u8> {
+    let mut arp = vec![0u8; ARP_ETHER_IP_LEN];
+    let mut packet = ArpPacket::new_unchecked(&mut arp[..]);
+    packet.set_ether_ip(NET_PROTOCOL_TYPE_IP);
+    packet.set_operation(ARP_OP_REQUEST);
+    packet.set_sender_hw_addr(src);
+    packet.set_sender_proto_addr(sender);
+    packet.set_target_hw_addr(EthernetAddress([0; 6]));
+    packet.set_target_proto_addr(target);
+    frame(src, EthernetAddress([0xff; 6]), NET_PROTOCOL_TYPE_ARP, &arp)
+}
+
+/// A bridge with an interface over three ports.
+struct Stack {
+    br: NetDeviceHandler,
+    ports: Vec<NetDeviceHandler>,
+    segments: Vec<Segment>,
+}
+
+static STACK: Shared<Stack> = Shared::new();
+
+/// Sets the stack up for the first test, and waits for the turn of the caller. The segments
+/// are quiet, all the ports in the bridge and nothing learned by then.
+fn stack() -> (MutexGuard<'static, ()>, &'static Stack) {
+    let (turn, stack) = STACK.get(|| {
+        net::net_init().unwrap();
+        let br = BridgeNetDevice::init(Some(HWADDR)).unwrap();
+        let (segments, ports) = (0..3)
+            .map(|_| {
+                let (link, port) = Link::open(None);
+                (Segment(link), port)
+            })
+            .unzip();
+        let iface = ip::IpInterface::new(BRIDGE_ADDR, IpAddress::parse_from("255.255.255.0"));
+        ip::ip_iface_register(br, iface).unwrap();
+        net::net_run().unwrap();
+        Stack {
+            br,
+            ports,
+            segments,
+        }
+    });
+    let enslaved = bridge::bridge_ports(stack.br);
+    for &port in &stack.ports {
+        if !enslaved.contains(&port) {
+            bridge::bridge_port_add(stack.br, port).unwrap();
+        }
+    }
+    bridge::bridge_set_ageing_time(stack.br, BRIDGE_AGEING_TIME).unwrap();
+    bridge::bridge_fdb_flush(stack.br).unwrap();
+    for segment in &stack.segments {
+        segment.0.drain();
+    }
+    (turn, stack)
+}
+
+#[test]
+fn bridge_ports_checked() {
+    let (_turn, Stack { br, ports, .. }) = stack();
+    assert!(matches!(
+        bridge::bridge_port_add(*br, ports[0]),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        bridge::bridge_port_add(*br, *br),
+        Err(UtcpErr::DeviceTypeMismatch { .. })
+    ));
+    let enslaved = bridge::bridge_ports(*br);
+    assert_eq!(enslaved.len(), ports.len());
+    assert!(ports.iter().all(|port| enslaved.contains(port)));
+}
+
+/// Broadcast is flooded, and the source learned.
+#[test]
+fn bridge_flood() {
+    let (_turn, Stack { br, ports, segments }) = stack();
+    let broadcast = EthernetAddress([0xff; 6]);
+    segments[0].send(&frame(HOST_A, broadcast, TEST_TYPE, b"flood"));
+    for segment in &segments[1..] {
+        let received = segment.recv(TEST_TYPE, LONG).expect("not flooded");
+        assert_eq!(EthernetFrame::new_unchecked(&received[..]).src(), HOST_A);
+    }
+    let fdb = bridge::bridge_fdb(*br).unwrap();
+    let entry = fdb.iter().find(|entry| entry.addr == HOST_A).unwrap();
+    assert_eq!(entry.port, ports[0]);
+}
+
+/// A learned destination only gets the frame on its own port, and an unknown one on all the
+/// others.
+#[test]
+fn bridge_unicast() {
+    let (_turn, Stack { br, ports, segments }) = stack();
+    segments[0].send(&frame(HOST_A, UNKNOWN, TEST_TYPE, b"unknown"));
+    assert!(segments[1].recv(TEST_TYPE, LONG).is_some());
+    assert!(segments[2].recv(TEST_TYPE, LONG).is_some());
+
+    segments[1].send(&frame(HOST_B, HOST_A, TEST_TYPE, b"unicast"));
+    assert!(segments[0].recv(TEST_TYPE, LONG).is_some());
+    assert!(segments[2].recv(TEST_TYPE, SHORT).is_none());
+    let fdb = bridge::bridge_fdb(*br).unwrap();
+    let entry = fdb.iter().find(|entry| entry.addr == HOST_B).unwrap();
+    assert_eq!(entry.port, ports[1]);
+
+    // nothing goes back to the segment the destination is on
+    segments[0].send(&frame(HOST_A, HOST_A, TEST_TYPE, b"local"));
+    for segment in &segments[1..] {
+        assert!(segment.recv(TEST_TYPE, SHORT).is_none());
+    }
+}
+
+/// The interface of the bridge answers on every segment, and sends to a learned address on
+/// its port only.
+#[test]
+fn bridge_interface() {
+    let (_turn, Stack { segments, .. }) = stack();
+    segments[0].send(&arp_request(HOST_A, HOST_ADDR, BRIDGE_ADDR));
+    let reply = segments[0]
+        .recv(NET_PROTOCOL_TYPE_ARP, LONG)
+        .expect("no ARP reply");
+    let arp = ArpPacket::new_checked(&reply[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(arp.operation(), ARP_OP_REPLY);
+    assert_eq!(arp.sender_hw_addr(), HWADDR);
+    assert_eq!(EthernetFrame::new_unchecked(&reply[..]).src(), HWADDR);
+    let request = segments[1].recv(NET_PROTOCOL_TYPE_ARP, LONG).unwrap();
+    assert_eq!(
+        ArpPacket::new_checked(&request[ETHERNET_HEADER_LEN..])
+            .unwrap()
+            .operation(),
+        ARP_OP_REQUEST
+    );
+
+    let socket = UdpSocket::bind("10.99.45.2:4000").unwrap();
+    socket
+        .send_to(b"from the bridge", "10.99.45.1:4000")
+        .unwrap();
+    let datagram = segments[0].recv(NET_PROTOCOL_TYPE_IP, LONG).unwrap();
+    assert_eq!(EthernetFrame::new_unchecked(&datagram[..]).dst(), HOST_A);
+    assert!(segments[2].recv(NET_PROTOCOL_TYPE_IP, SHORT).is_none());
+}
+
+/// Addresses are forgotten with their port, or once they age.
+#[test]
+fn bridge_fdb_forget() {
+    let (_turn, Stack { br, ports, segments }) = stack();
+    let broadcast = EthernetAddress([0xff; 6]);
+    segments[0].send(&frame(HOST_A, broadcast, TEST_TYPE, b"learn"));
+    segments[1].send(&frame(HOST_B, broadcast, TEST_TYPE, b"learn"));
+    assert!(segments[2].recv(TEST_TYPE, LONG).is_some());
+    assert!(segments[2].recv(TEST_TYPE, LONG).is_some());
+
+    bridge::bridge_port_del(*br, ports[1]).unwrap();
+    let fdb = bridge::bridge_fdb(*br).unwrap();
+    assert!(fdb.iter().all(|entry| entry.port != ports[1]));
+    assert!(fdb.iter().any(|entry| entry.addr == HOST_A));
+    assert!(matches!(
+        bridge::bridge_port_del(*br, ports[1]),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+
+    bridge::bridge_set_ageing_time(*br, Duration::from_millis(100)).unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(
+        bridge::bridge_fdb(*br)
+            .unwrap()
+            .iter()
+            .all(|entry| entry.addr != HOST_A)
+    );
+}
@@ -0,0 +1,154 @@
+//! Fixtures of the tests that play the hosts around a stack over UDP tunnels, and of the
+//! tests that hold the interrupt thread to fill the queues in front of it.
+
+// every test crate uses its own part of this
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        Mutex, MutexGuard, OnceLock, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    driver::udp_tunnel::{UdpTunnelFraming, UdpTunnelNetDevice},
+    net::NetDeviceHandler,
+    wire::ethernet::{ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+};
+
+/// How long `Link::drain` waits for the link to be quiet.
+pub const QUIET: Duration = Duration::from_millis(200);
+
+/// A stack shared by the tests of a file. The tests take turns, since they would see each
+/// other's traffic.
+pub struct Shared<T> {
+    stack: OnceLock<T>,
+    turn: Mutex<()>,
+}
+
+impl<T> Shared<T> {
+    pub const fn new() -> Self {
+        Self {
+            stack: OnceLock::new(),
+            turn: Mutex::new(()),
+        }
+    }
+
+    /// Sets the stack up with `init` for the first test, and waits for the turn of the
+    /// caller. A test that failed does not stop the others.
+    pub fn get(&'static self, init: impl FnOnce() -> T) -> (MutexGuard<'static, ()>, &'static T) {
+        let turn = self.turn.lock().unwrap_or_else(PoisonError::into_inner);
+        (turn, self.stack.get_or_init(init))
+    }
+}
+
+/// A link of the stack, whose hosts a test plays with a host UDP socket at the other end of
+/// an Ethernet tunnel.
+pub struct Link {
+    pub socket: std::net::UdpSocket,
+    pub tunnel: SocketAddr,
+}
+
+impl Link {
+    /// Registers a tunnel device with `hwaddr`, or a random address, and returns the link it
+    /// is on.
+    pub fn open(hwaddr: Option<EthernetAddress>) -> (Self, NetDeviceHandler) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tunnel = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let framing = UdpTunnelFraming::Ethernet(hwaddr);
+        let dev = UdpTunnelNetDevice::init(tunnel, socket.local_addr().unwrap(), framing).unwrap();
+        (Self { socket, tunnel }, dev)
+    }
+
+    pub fn send(&self, frame: &[u8]) {
+        self.socket.send_to(frame, self.tunnel).unwrap();
+    }
+
+    /// Sends `payload` in a frame of type `ty`.
+    pub fn send_frame(&self, dst: EthernetAddress, src: EthernetAddress, ty: u16, payload: &[u8]) {
+        let mut frame = vec![0u8; ETHERNET_HEADER_LEN + payload.len()];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+        eth.set_dst(dst);
+        eth.set_src(src);
+        eth.set_ethertype(ty);
+        eth.payload_mut().copy_from_slice(payload);
+        self.send(&frame);
+    }
+
+    /// Waits for a while for a frame from the stack that `pick` makes something of, and
+    /// returns that. The frames it passes over are lost.
+    pub fn recv<R>(
+        &self,
+        timeout: Duration,
+        mut pick: impl FnMut(&[u8]) -> Option<R>,
+    ) -> Option<R> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0u8; 2048];
+        while let Some(left) = deadline.checked_duration_since(Instant::now())
+            && !left.is_zero()
+        {
+            self.socket.set_read_timeout(Some(left)).unwrap();
+            let Ok(len) = self.socket.recv(&mut buf) else {
+                continue;
+            };
+            if let Some(picked) = pick(&buf[..len]) {
+                return Some(picked);
+            }
+        }
+        None
+    }
+
+    /// Forgets what the stack sent on the link until now.
+    pub fn drain(&self) {
+        self.socket.set_read_timeout(Some(QUIET)).unwrap();
+        while self.socket.recv(&mut [0u8; 2048]).is_ok() {}
+    }
+}
+
+/// Set while `held_handler` holds the interrupt thread in the first packet.
+static BLOCKED: AtomicBool = AtomicBool::new(true);
+static ENTERED: AtomicBool = AtomicBool::new(false);
+static DELIVERED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// A protocol handler that holds the interrupt thread in the first packet until `release`,
+/// and records the first byte of every packet.
+pub fn held_handler(data: &[u8], _: &NetDeviceHandler) {
+    ENTERED.store(true, Ordering::Relaxed);
+    while BLOCKED.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    DELIVERED.lock().unwrap().push(data[0]);
+}
+
+/// Whether `held_handler` holds the interrupt thread, or did.
+pub fn held() -> bool {
+    ENTERED.load(Ordering::Relaxed)
+}
+
+pub fn release() {
+    BLOCKED.store(false, Ordering::Relaxed);
+}
+
+/// The first bytes of the packets `held_handler` got, in order.
+pub fn delivered() -> Vec<u8> {
+    DELIVERED.lock().unwrap().clone()
+}
+
+/// Waits for at most 5 seconds for `cond` to hold, and returns whether it did.
+pub fn wait_until(cond: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
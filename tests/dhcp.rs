@@ -0,0 +1,322 @@
+mod common;
+
+use std::{sync::MutexGuard, time::Duration};
+
+use common::{Link, Shared};
+use utcp::{
+    dhcp::{self, DhcpState},
+    error::UtcpErr,
+    ip::{IP_ADDR_ANY, IP_ADDR_BROADCAST, IP_PROTOCOL_UDP, IpAddress},
+    net::{
+        self, NET_PROTOCOL_TYPE_ARP, NET_PROTOCOL_TYPE_IP, NetDeviceHandler, NetInterfaceFamily,
+    },
+    wire::{
+        arp::{ARP_ETHER_IP_LEN, ARP_OP_REPLY, ARP_OP_REQUEST, ArpPacket},
+        dhcpv4::*,
+        ethernet::{EthernetAddress, EthernetFrame},
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    },
+};
+
+const ADDR: IpAddress = IpAddress::parse_from("10.99.35.10");
+const SERVER: IpAddress = IpAddress::parse_from("10.99.35.1");
+const NETMASK: IpAddress = IpAddress::parse_from("255.255.255.0");
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x35, 0x10]);
+const SERVER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x35, 0x01]);
+
+/// Lease, renewal and rebinding times in seconds.
+const LEASE_LONG: [u32; 3] = [600, 300, 525];
+
+/// The DHCP server, which is also the only other host of the link.
+struct Server(Link);
+
+impl Server {
+    /// Returns the destination of the next DHCP message from the client and the message,
+    /// answering ARP requests for the server on the way.
+    fn recv(&self, timeout: Duration) -> Option<(IpAddress, Vec<u8>)> {
+        self.0.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            assert_eq!(frame.src(), HWADDR);
+            match frame.ethertype() {
+                NET_PROTOCOL_TYPE_ARP => {
+                    let arp = ArpPacket::new_checked(frame.payload()).unwrap();
+                    if arp.operation() == ARP_OP_REQUEST && arp.target_proto_addr() == SERVER {
+                        self.send_arp(ARP_OP_REPLY, arp.sender_hw_addr(), arp.sender_proto_addr());
+                    }
+                    None
+                }
+                NET_PROTOCOL_TYPE_IP => {
+                    let ip_hdr = Ipv4Packet::new_checked(frame.payload()).unwrap();
+                    if ip_hdr.protocol() != IP_PROTOCOL_UDP {
+                        return None;
+                    }
+                    let udp = UdpPacket::new_checked(ip_hdr.payload()).unwrap();
+                    (udp.dst_port() == DHCP_SERVER_PORT)
+                        .then(|| (ip_hdr.dst(), udp.payload().to_vec()))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Like `recv`, but the message must be of type `ty`.
+    fn expect(&self, ty: u8, timeout: Duration) -> (IpAddress, Vec<u8>) {
+        let (dst, message) = self
+            .recv(timeout)
+            .unwrap_or_else(|| panic!("no message of type {}", ty));
+        let packet = DhcpPacket::new_checked(&message[..]).unwrap();
+        assert_eq!(packet.op(), DHCP_OP_REQUEST);
+        assert_eq!(packet.chaddr(), HWADDR);
+        assert_eq!(packet.message_type(), Some(ty));
+        (dst, message)
+    }
+
+    /// Returns the address the stack probes with ARP next, or `None` if there is no probe
+    /// within `timeout`.
+    fn recv_probe(&self, timeout: Duration) -> Option<IpAddress> {
+        self.0.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            if frame.ethertype() != NET_PROTOCOL_TYPE_ARP {
+                return None;
+            }
+            let arp = ArpPacket::new_checked(frame.payload()).unwrap();
+            (arp.operation() == ARP_OP_REQUEST && arp.sender_proto_addr() == IP_ADDR_ANY)
+                .then(|| arp.target_proto_addr())
+        })
+    }
+
+    fn send_arp(&self, op: u16, tha: EthernetAddress, tpa: IpAddress) {
+        let mut buf = [0u8; ARP_ETHER_IP_LEN];
+        let mut packet = ArpPacket::new_unchecked(&mut buf[..]);
+        packet.set_ether_ip(NET_PROTOCOL_TYPE_IP);
+        packet.set_operation(op);
+        packet.set_sender_hw_addr(SERVER_HWADDR);
+        packet.set_sender_proto_addr(SERVER);
+        packet.set_target_hw_addr(tha);
+        packet.set_target_proto_addr(tpa);
+        let dst = match op {
+            ARP_OP_REQUEST => EthernetAddress::BROADCAST,
+            _ => tha,
+        };
+        self.0
+            .send_frame(dst, SERVER_HWADDR, NET_PROTOCOL_TYPE_ARP, &buf);
+    }
+
+    /// Answers `request` with a message of type `ty`, which hands `ADDR` out for the lease,
+    /// renewal and rebinding times of `lease` if there are any. Replies are broadcast, as the
+    /// client has no address yet or is about to lose it.
+    fn reply(&self, request: &[u8], ty: u8, lease: Option<[u32; 3]>) {
+        let request = DhcpPacket::new_checked(request).unwrap();
+        let mut message = vec![0u8; DHCP_HEADER_LEN];
+        let mut packet = DhcpPacket::new_unchecked(&mut message[..]);
+        packet.set_header(DHCP_OP_REPLY, request.xid(), request.chaddr());
+        packet.set_flags(request.flags());
+        if lease.is_some() {
+            packet.set_yiaddr(ADDR);
+        }
+        let mut options = DhcpOptionWriter::new(&mut message);
+        options
+            .option_u8(DHCP_OPT_MESSAGE_TYPE, ty)
+            .option_addrs(DHCP_OPT_SERVER_ID, &[SERVER]);
+        if let Some([lease_time, renewal_time, rebinding_time]) = lease {
+            options
+                .option_addrs(DHCP_OPT_SUBNET_MASK, &[NETMASK])
+                .option_u32(DHCP_OPT_LEASE_TIME, lease_time)
+                .option_u32(DHCP_OPT_RENEWAL_TIME, renewal_time)
+                .option_u32(DHCP_OPT_REBINDING_TIME, rebinding_time);
+        }
+        options.finish();
+
+        let udp_len = UDP_HEADER_LEN + message.len();
+        let total = IPV4_HEADER_MIN_LEN + udp_len;
+        let mut datagram = vec![0u8; total];
+        let mut udp = UdpPacket::new_unchecked(&mut datagram[IPV4_HEADER_MIN_LEN..]);
+        udp.set_src_port(DHCP_SERVER_PORT);
+        udp.set_dst_port(DHCP_CLIENT_PORT);
+        udp.set_len(udp_len as u16);
+        udp.payload_mut().copy_from_slice(&message);
+        udp.fill_checksum(SERVER, IP_ADDR_BROADCAST);
+        let mut ip_hdr = Ipv4Packet::new_unchecked(&mut datagram[..]);
+        ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+        ip_hdr.set_total(total as u16);
+        ip_hdr.set_ttl(64);
+        ip_hdr.set_protocol(IP_PROTOCOL_UDP);
+        ip_hdr.set_src(SERVER);
+        ip_hdr.set_dst(IP_ADDR_BROADCAST);
+        ip_hdr.fill_checksum();
+        self.0.send_frame(
+            EthernetAddress::BROADCAST,
+            SERVER_HWADDR,
+            NET_PROTOCOL_TYPE_IP,
+            &datagram,
+        );
+    }
+
+    /// Leads a client that just started through DISCOVER, OFFER, REQUEST and ACK.
+    fn bind(&self, lease: [u32; 3]) {
+        let (_, discover) = self.expect(DHCP_DISCOVER, Duration::from_secs(1));
+        self.reply(&discover, DHCP_OFFER, Some(lease));
+        let (dst, request) = self.expect(DHCP_REQUEST, Duration::from_secs(1));
+        assert_eq!(dst, IP_ADDR_BROADCAST);
+        self.reply(&request, DHCP_ACK, Some(lease));
+    }
+}
+
+struct Stack {
+    dev: NetDeviceHandler,
+    server: Server,
+}
+
+static STACK: Shared<Stack> = Shared::new();
+
+/// Sets the stack up for the first test, and waits for the turn of the caller. No client runs
+/// on the device and the link is quiet by then.
+fn stack() -> (MutexGuard<'static, ()>, &'static Stack) {
+    let (turn, stack) = STACK.get(|| {
+        net::net_init().unwrap();
+        let (link, dev) = Link::open(Some(HWADDR));
+        net::net_run().unwrap();
+        Stack {
+            dev,
+            server: Server(link),
+        }
+    });
+    if dhcp::dhcp_client_state(stack.dev).is_some() {
+        dhcp::dhcp_client_stop(stack.dev).unwrap();
+    }
+    stack.server.0.drain();
+    (turn, stack)
+}
+
+fn has_iface(dev: NetDeviceHandler) -> bool {
+    net::net_device_get_iface(&dev, NetInterfaceFamily::Ip).is_some()
+}
+
+#[test]
+fn dhcp_bind() {
+    let (_turn, stack) = stack();
+    let server = &stack.server;
+    dhcp::dhcp_client_start(stack.dev).unwrap();
+
+    let (dst, discover) = server.expect(DHCP_DISCOVER, Duration::from_secs(1));
+    assert_eq!(dst, IP_ADDR_BROADCAST);
+    let packet = DhcpPacket::new_checked(&discover[..]).unwrap();
+    assert_eq!(packet.ciaddr(), IP_ADDR_ANY);
+    assert_eq!(packet.flags(), DHCP_FLAG_BROADCAST);
+    assert!(packet.option(DHCP_OPT_PARAMETER_LIST).is_some());
+    server.reply(&discover, DHCP_OFFER, Some(LEASE_LONG));
+
+    // the REQUEST names the offer it takes
+    let (dst, request) = server.expect(DHCP_REQUEST, Duration::from_secs(1));
+    assert_eq!(dst, IP_ADDR_BROADCAST);
+    let packet = DhcpPacket::new_checked(&request[..]).unwrap();
+    assert_eq!(
+        packet.xid(),
+        DhcpPacket::new_checked(&discover[..]).unwrap().xid()
+    );
+    assert_eq!(packet.option_addr(DHCP_OPT_REQUESTED_IP), Some(ADDR));
+    assert_eq!(packet.option_addr(DHCP_OPT_SERVER_ID), Some(SERVER));
+    server.reply(&request, DHCP_ACK, Some(LEASE_LONG));
+
+    // nobody answers the probe, so the address is used
+    assert_eq!(server.recv_probe(Duration::from_secs(1)), Some(ADDR));
+    assert_eq!(dhcp::dhcp_client_state(stack.dev), Some(DhcpState::Probing));
+    assert!(!has_iface(stack.dev));
+    let lease = dhcp::dhcp_client_wait(stack.dev, Duration::from_secs(3)).unwrap();
+    assert_eq!(lease.addr, ADDR);
+    assert_eq!(lease.netmask, NETMASK);
+    assert_eq!(lease.server, SERVER);
+    assert_eq!(lease.router, None);
+    assert_eq!(lease.lease_time, Duration::from_secs(600));
+    assert_eq!(lease.renewal_time, Duration::from_secs(300));
+    assert_eq!(lease.rebinding_time, Duration::from_secs(525));
+    assert_eq!(dhcp::dhcp_client_state(stack.dev), Some(DhcpState::Bound));
+    assert!(has_iface(stack.dev));
+
+    // the server checks the address in use, so the stack knows where to send the RELEASE
+    // when the lease is given back
+    server.send_arp(ARP_OP_REQUEST, EthernetAddress::ANY, ADDR);
+    std::thread::sleep(common::QUIET);
+    dhcp::dhcp_client_stop(stack.dev).unwrap();
+    let (dst, release) = server.expect(DHCP_RELEASE, Duration::from_secs(1));
+    assert_eq!(dst, SERVER);
+    let packet = DhcpPacket::new_checked(&release[..]).unwrap();
+    assert_eq!(packet.ciaddr(), ADDR);
+    assert!(!has_iface(stack.dev));
+}
+
+#[test]
+fn dhcp_nak() {
+    let (_turn, stack) = stack();
+    let server = &stack.server;
+    dhcp::dhcp_client_start(stack.dev).unwrap();
+
+    let (_, discover) = server.expect(DHCP_DISCOVER, Duration::from_secs(1));
+    server.reply(&discover, DHCP_OFFER, Some(LEASE_LONG));
+    let (_, request) = server.expect(DHCP_REQUEST, Duration::from_secs(1));
+    server.reply(&request, DHCP_NAK, None);
+
+    // the client starts over at once, in a new exchange
+    let (_, rediscover) = server.expect(DHCP_DISCOVER, Duration::from_secs(1));
+    let xid = |message: &[u8]| DhcpPacket::new_checked(message).unwrap().xid();
+    assert_ne!(xid(&rediscover), xid(&discover));
+    assert_eq!(
+        dhcp::dhcp_client_state(stack.dev),
+        Some(DhcpState::Selecting)
+    );
+    assert_eq!(dhcp::dhcp_client_lease(stack.dev), None);
+    assert!(!has_iface(stack.dev));
+
+    // and takes an address that is acknowledged
+    server.reply(&rediscover, DHCP_OFFER, Some(LEASE_LONG));
+    let (_, request) = server.expect(DHCP_REQUEST, Duration::from_secs(1));
+    server.reply(&request, DHCP_ACK, Some(LEASE_LONG));
+    let lease = dhcp::dhcp_client_wait(stack.dev, Duration::from_secs(3)).unwrap();
+    assert_eq!(lease.addr, ADDR);
+
+    dhcp::dhcp_client_stop(stack.dev).unwrap();
+}
+
+/// The server stops answering once the address is bound.
+#[test]
+fn dhcp_lease_expiry() {
+    let (_turn, stack) = stack();
+    let server = &stack.server;
+    dhcp::dhcp_client_start(stack.dev).unwrap();
+    server.bind([4, 2, 3]);
+    dhcp::dhcp_client_wait(stack.dev, Duration::from_secs(3)).unwrap();
+
+    // at T1 the lease is renewed with the server
+    let (dst, renew) = server.expect(DHCP_REQUEST, Duration::from_secs(2));
+    assert_eq!(dst, SERVER);
+    let packet = DhcpPacket::new_checked(&renew[..]).unwrap();
+    assert_eq!(packet.ciaddr(), ADDR);
+    assert_eq!(packet.option(DHCP_OPT_REQUESTED_IP), None);
+    assert_eq!(
+        dhcp::dhcp_client_state(stack.dev),
+        Some(DhcpState::Renewing)
+    );
+    assert!(has_iface(stack.dev));
+
+    // at T2 with any server
+    let (dst, rebind) = server.expect(DHCP_REQUEST, Duration::from_secs(2));
+    assert_eq!(dst, IP_ADDR_BROADCAST);
+    assert_eq!(DhcpPacket::new_checked(&rebind[..]).unwrap().ciaddr(), ADDR);
+    assert_eq!(
+        dhcp::dhcp_client_state(stack.dev),
+        Some(DhcpState::Rebinding)
+    );
+
+    // and when it expires the address is given up
+    server.expect(DHCP_DISCOVER, Duration::from_secs(2));
+    assert_eq!(dhcp::dhcp_client_lease(stack.dev), None);
+    assert!(!has_iface(stack.dev));
+    assert!(matches!(
+        dhcp::dhcp_client_wait(stack.dev, Duration::from_millis(100)),
+        Err(UtcpErr::TimedOut)
+    ));
+
+    dhcp::dhcp_client_stop(stack.dev).unwrap();
+}
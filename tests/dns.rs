@@ -0,0 +1,213 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use utcp::{
+    TcpListener, UdpSocket, dns,
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    net,
+    wire::dns::{
+        DNS_FLAG_QR, DNS_FLAG_RA, DNS_FLAG_RD, DNS_FLAG_TC, DNS_HEADER_LEN, DNS_RCODE_NXDOMAIN,
+        DnsPacket, DnsWriter,
+    },
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const WEB_ADDR: IpAddress = IpAddress::parse_from("192.0.2.10");
+
+type QueryCounts = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Source port and ID of every query received over UDP.
+static UDP_QUERIES: Mutex<Vec<(u16, u16)>> = Mutex::new(Vec::new());
+
+/// Answers a query for the zone example.com the way the test expects. `tcp` is set for
+/// queries that came over TCP.
+fn answer(query: &[u8], counts: &QueryCounts, tcp: bool) -> Option<Vec<u8>> {
+    let packet = DnsPacket::new_checked(query).ok()?;
+    let question = packet.sections().ok()?.questions.pop()?;
+    let name = question.name.to_ascii_lowercase();
+    let count = {
+        let mut counts = counts.lock().unwrap();
+        let count = counts.entry(name.clone()).or_default();
+        *count += 1;
+        *count
+    };
+
+    let mut buf = vec![0u8; DNS_HEADER_LEN];
+    DnsPacket::new_unchecked(&mut buf[..])
+        .set_header(packet.id(), DNS_FLAG_QR | DNS_FLAG_RD | DNS_FLAG_RA);
+    let mut writer = DnsWriter::new(&mut buf);
+    writer.question(&question.name, question.qtype).unwrap();
+    match name.as_str() {
+        "www.example.com" => {
+            writer
+                .answer_cname(&question.name, 300, "web.example.com")
+                .unwrap()
+                .answer_a("web.example.com", 60, WEB_ADDR)
+                .unwrap();
+        }
+        // the target is left for a second query
+        "alias.example.com" => {
+            writer
+                .answer_cname(&question.name, 300, "web.example.com")
+                .unwrap();
+        }
+        "web.example.com" => {
+            writer.answer_a(&question.name, 60, WEB_ADDR).unwrap();
+        }
+        "slow.example.com" if count == 1 => return None,
+        "slow.example.com" => {
+            writer.answer_a(&question.name, 60, WEB_ADDR).unwrap();
+        }
+        "big.example.com" if !tcp => {
+            DnsPacket::new_unchecked(&mut buf[..])
+                .set_flags(DNS_FLAG_QR | DNS_FLAG_RD | DNS_FLAG_RA | DNS_FLAG_TC);
+        }
+        "big.example.com" => {
+            for host in 1..=3 {
+                writer
+                    .answer_a(&question.name, 60, IpAddress::from([192, 0, 2, host]))
+                    .unwrap();
+            }
+        }
+        "loop.example.com" => {
+            writer
+                .answer_cname(&question.name, 60, "loop.example.com")
+                .unwrap();
+        }
+        _ => {
+            writer.authority_soa("example.com", 3600, 30).unwrap();
+            DnsPacket::new_unchecked(&mut buf[..])
+                .set_flags(DNS_FLAG_QR | DNS_FLAG_RD | DNS_FLAG_RA | DNS_RCODE_NXDOMAIN as u16);
+        }
+    }
+    Some(buf)
+}
+
+fn name_server(counts: QueryCounts) {
+    let udp = UdpSocket::bind("127.0.0.1:53").unwrap();
+    let udp_counts = counts.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((len, from)) = udp.recv_from(&mut buf) {
+            if let Ok(packet) = DnsPacket::new_checked(&buf[..len]) {
+                UDP_QUERIES.lock().unwrap().push((from.port(), packet.id()));
+            }
+            if let Some(response) = answer(&buf[..len], &udp_counts, false) {
+                udp.send_to(&response, from).unwrap();
+            }
+        }
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:53").unwrap();
+    std::thread::spawn(move || {
+        while let Ok((mut stream, _)) = listener.accept() {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let response = answer(&query, &counts, true).unwrap();
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+}
+
+#[test]
+fn dns_resolver() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    let counts = QueryCounts::default();
+    name_server(counts.clone());
+    let queries = |name: &str| counts.lock().unwrap().get(name).copied().unwrap_or(0);
+    assert!(matches!(
+        dns::dns_resolve("www.example.com"),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    dns::dns_set_nameservers(&[LOOPBACK_IP_ADDR]);
+    dns::dns_set_timeout(Duration::from_millis(200), 2).unwrap();
+
+    assert_eq!(
+        dns::dns_resolve("192.0.2.99").unwrap(),
+        vec![IpAddress::parse_from("192.0.2.99")]
+    );
+
+    // CNAME within one response, then answered from the cache whatever the case
+    assert_eq!(dns::dns_resolve("www.example.com").unwrap(), vec![WEB_ADDR]);
+    assert_eq!(
+        dns::dns_resolve("WWW.Example.com.").unwrap(),
+        vec![WEB_ADDR]
+    );
+    assert_eq!(queries("www.example.com"), 1);
+
+    // CNAME whose target needs a query of its own
+    assert_eq!(
+        dns::dns_resolve("alias.example.com").unwrap(),
+        vec![WEB_ADDR]
+    );
+    assert_eq!(queries("web.example.com"), 1);
+
+    // the first query is lost and retransmitted
+    assert_eq!(
+        dns::dns_resolve("slow.example.com").unwrap(),
+        vec![WEB_ADDR]
+    );
+    assert_eq!(queries("slow.example.com"), 2);
+
+    // truncated over UDP, complete over TCP
+    assert_eq!(dns::dns_resolve("big.example.com").unwrap().len(), 3);
+
+    // negative answers are cached too
+    for _ in 0..2 {
+        assert!(matches!(
+            dns::dns_resolve("missing.example.com"),
+            Err(UtcpErr::NameNotFound(_))
+        ));
+    }
+    assert_eq!(queries("missing.example.com"), 1);
+
+    assert!(matches!(
+        dns::dns_resolve("loop.example.com"),
+        Err(UtcpErr::NameNotFound(_))
+    ));
+
+    // static names win over the name servers
+    dns::dns_hosts_parse("192.0.2.20 www.example.com\n");
+    assert_eq!(
+        dns::dns_resolve("www.example.com").unwrap(),
+        vec![IpAddress::parse_from("192.0.2.20")]
+    );
+    dns::dns_hosts_clear();
+    dns::dns_cache_flush();
+    assert_eq!(dns::dns_resolve("www.example.com").unwrap(), vec![WEB_ADDR]);
+    assert_eq!(queries("www.example.com"), 2);
+
+    // neither the source port nor the ID of a query tells the next one
+    let queries = UDP_QUERIES.lock().unwrap().clone();
+    assert!(queries.len() >= 8);
+    let ports: HashSet<_> = queries.iter().map(|&(port, _)| port).collect();
+    let ids: HashSet<_> = queries.iter().map(|&(_, id)| id).collect();
+    assert!(ports.len() > queries.len() / 2, "{:?}", queries);
+    assert!(ids.len() > queries.len() / 2, "{:?}", queries);
+
+    // a name server that does not answer
+    dns::dns_set_nameservers(&[IpAddress::parse_from("127.0.0.2")]);
+    assert!(matches!(
+        dns::dns_resolve("other.example.com"),
+        Err(UtcpErr::TimedOut)
+    ));
+
+    net::net_shutdown().unwrap();
+}
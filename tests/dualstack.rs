@@ -0,0 +1,198 @@
+use std::net::SocketAddr;
+
+use utcp::{
+    TcpListener, TcpStream,
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    ip::{self, IP_ADDR_ANY, IpAddress, IpEndpoint},
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    net,
+    socket::{self, AF_INET, AF_INET6, SOCK_DGRAM, SOCK_RAW, SOCK_STREAM},
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+
+fn mapped(addr: IpAddress) -> Ipv6Address {
+    Ipv6Address::from_ipv4_mapped(addr)
+}
+
+/// One listener bound to [::] accepts connections of both families.
+fn tcp_dual_stack() {
+    let listener = socket::socket(AF_INET6, SOCK_STREAM, 0).unwrap();
+    assert!(!socket::v6only(listener).unwrap());
+    socket::bind(listener, IpEndpoint::new(Ipv6Address::UNSPECIFIED, 8080)).unwrap();
+    socket::listen(listener, 4).unwrap();
+
+    for (domain, addr) in [
+        (AF_INET6, IpEndpoint::new(Ipv6Address::LOOPBACK, 8080)),
+        (AF_INET, IpEndpoint::new(LOOPBACK_IP_ADDR, 8080)),
+    ] {
+        let client = std::thread::spawn(move || {
+            let fd = socket::socket(domain, SOCK_STREAM, 0).unwrap();
+            socket::connect(fd, addr).unwrap();
+            assert_eq!(socket::getpeername(fd).unwrap(), addr);
+            socket::send(fd, b"hello").unwrap();
+            let mut buf = [0u8; 16];
+            let len = socket::recv(fd, &mut buf).unwrap();
+            assert_eq!(&buf[..len], b"world");
+            let local = socket::getsockname(fd).unwrap();
+            socket::close(fd).unwrap();
+            local
+        });
+        let (conn, peer) = socket::accept(listener).unwrap();
+        let mut buf = [0u8; 16];
+        let len = socket::recv(conn, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        socket::send(conn, b"world").unwrap();
+        let client_local = client.join().unwrap();
+        // IPv4 peers show up as IPv4-mapped addresses on the IPv6 socket
+        let expected = match domain {
+            AF_INET => IpEndpoint::new(mapped(LOOPBACK_IP_ADDR), client_local.port),
+            _ => client_local,
+        };
+        assert_eq!(peer, expected);
+        assert_eq!(socket::getsockname(conn).unwrap().port, 8080);
+        socket::close(conn).unwrap();
+    }
+    socket::close(listener).unwrap();
+}
+
+fn udp_dual_stack() {
+    let server = socket::socket(AF_INET6, SOCK_DGRAM, 0).unwrap();
+    socket::bind(server, IpEndpoint::new(Ipv6Address::UNSPECIFIED, 7)).unwrap();
+
+    let mut buf = [0u8; 64];
+    let client4 = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+    socket::sendto(client4, b"v4", IpEndpoint::new(LOOPBACK_IP_ADDR, 7)).unwrap();
+    let (len, from) = socket::recvfrom(server, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"v4");
+    assert_eq!(from.addr, mapped(LOOPBACK_IP_ADDR));
+    assert_eq!(from.port, socket::getsockname(client4).unwrap().port);
+    socket::sendto(server, b"pong4", from).unwrap();
+    let (len, from4) = socket::recvfrom(client4, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong4");
+    assert_eq!(from4, IpEndpoint::new(LOOPBACK_IP_ADDR, 7));
+
+    let client6 = socket::socket(AF_INET6, SOCK_DGRAM, 0).unwrap();
+    socket::sendto(client6, b"v6", IpEndpoint::new(Ipv6Address::LOOPBACK, 7)).unwrap();
+    let (len, from) = socket::recvfrom(server, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"v6");
+    assert_eq!(from.addr, Ipv6Address::LOOPBACK);
+    socket::sendto(server, b"pong6", from).unwrap();
+    let (len, from6) = socket::recvfrom(client6, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong6");
+    assert_eq!(from6, IpEndpoint::new(Ipv6Address::LOOPBACK, 7));
+
+    // an IPv4 destination given to an IPv6 socket is sent as IPv4
+    socket::sendto(client6, b"mapped", IpEndpoint::new(LOOPBACK_IP_ADDR, 7)).unwrap();
+    let (len, from) = socket::recvfrom(server, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"mapped");
+    assert_eq!(from.addr, mapped(LOOPBACK_IP_ADDR));
+
+    // an IPv4 socket does not take IPv6 addresses
+    assert!(matches!(
+        socket::sendto(client4, b"x", IpEndpoint::new(Ipv6Address::LOOPBACK, 7)),
+        Err(UtcpErr::AddrFamilyNotSupported)
+    ));
+    for fd in [client4, client6, server] {
+        socket::close(fd).unwrap();
+    }
+}
+
+fn v6only() {
+    let listener = socket::socket(AF_INET6, SOCK_STREAM, 0).unwrap();
+    socket::set_v6only(listener, true).unwrap();
+    socket::bind(listener, IpEndpoint::new(Ipv6Address::UNSPECIFIED, 9090)).unwrap();
+    assert!(matches!(
+        socket::set_v6only(listener, false),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    socket::listen(listener, 4).unwrap();
+
+    // IPv4 is refused, so an IPv4 socket can take the port
+    let fd = socket::socket(AF_INET, SOCK_STREAM, 0).unwrap();
+    assert!(matches!(
+        socket::connect(fd, IpEndpoint::new(LOOPBACK_IP_ADDR, 9090)),
+        Err(UtcpErr::ConnectionRefused)
+    ));
+    socket::close(fd).unwrap();
+    let v4 = socket::socket(AF_INET, SOCK_STREAM, 0).unwrap();
+    socket::bind(v4, IpEndpoint::new(IP_ADDR_ANY, 9090)).unwrap();
+
+    let udp = socket::socket(AF_INET6, SOCK_DGRAM, 0).unwrap();
+    socket::set_v6only(udp, true).unwrap();
+    assert!(matches!(
+        socket::sendto(udp, b"x", IpEndpoint::new(LOOPBACK_IP_ADDR, 7)),
+        Err(UtcpErr::NetUnreachable)
+    ));
+
+    let udp4 = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+    assert!(matches!(
+        socket::set_v6only(udp4, true),
+        Err(UtcpErr::NotSupported(_))
+    ));
+    assert!(matches!(
+        socket::socket(AF_INET6, SOCK_RAW, 1),
+        Err(UtcpErr::NotSupported(_))
+    ));
+    for fd in [listener, v4, udp, udp4] {
+        socket::close(fd).unwrap();
+    }
+}
+
+/// A dual-stack socket on :: and an IPv4 one on 0.0.0.0 cannot share a port.
+fn bind_conflicts() {
+    let v4 = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+    socket::bind(v4, IpEndpoint::new(IP_ADDR_ANY, 5353)).unwrap();
+    let v6 = socket::socket(AF_INET6, SOCK_DGRAM, 0).unwrap();
+    assert!(matches!(
+        socket::bind(v6, IpEndpoint::new(Ipv6Address::UNSPECIFIED, 5353)),
+        Err(UtcpErr::AddrInUse)
+    ));
+    // a specific IPv6 address does not overlap with IPv4
+    socket::bind(v6, IpEndpoint::new(Ipv6Address::LOOPBACK, 5353)).unwrap();
+    let mapped_v6 = socket::socket(AF_INET6, SOCK_DGRAM, 0).unwrap();
+    assert!(matches!(
+        socket::bind(mapped_v6, IpEndpoint::new(LOOPBACK_IP_ADDR, 5353)),
+        Err(UtcpErr::AddrInUse)
+    ));
+    for fd in [v4, v6, mapped_v6] {
+        socket::close(fd).unwrap();
+    }
+}
+
+fn std_dual_stack() {
+    let listener = TcpListener::bind("[::]:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    assert!(listener.local_addr().unwrap().is_ipv6());
+    let client = std::thread::spawn(move || {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.local_addr().unwrap()
+    });
+    let (_stream, peer) = listener.accept().unwrap();
+    let local = client.join().unwrap();
+    let expected: SocketAddr = format!("[::ffff:127.0.0.1]:{}", local.port())
+        .parse()
+        .unwrap();
+    assert_eq!(peer, expected);
+}
+
+#[test]
+fn dualstack() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+    ipv6::ipv6_addr_add(dev, Ipv6Address::LOOPBACK, 128).unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    tcp_dual_stack();
+    udp_dual_stack();
+    v6only();
+    bind_conflicts();
+    std_dual_stack();
+
+    net::net_shutdown().unwrap();
+}
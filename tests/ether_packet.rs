@@ -0,0 +1,179 @@
+use std::{
+    ffi::CString,
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use utcp::{
+    UdpSocket,
+    driver::ether_packet::EtherPacketNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    net,
+};
+
+/// Network namespace of the other ends of the veth pairs, where the test plays the peers.
+const NETNS: &str = "utcp-pk";
+/// Host sides of the veth pairs, without and with rings, and their peers.
+const LINKS: [(&str, &str); 2] = [("utcp-pk0", "utcp-pk1"), ("utcp-pk2", "utcp-pk3")];
+
+const PORT: u16 = 7000;
+/// Address of the host itself on the first link.
+const HOST_ADDR: &str = "10.99.51.3";
+
+fn ip(args: &[&str]) -> String {
+    let output = Command::new("ip").args(args).output().unwrap();
+    assert!(output.status.success(), "ip {:?}: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The namespace, deleted with the veth pairs in it when dropped.
+struct Netns;
+
+impl Netns {
+    fn create() -> Self {
+        let _ = Command::new("ip").args(["netns", "del", NETNS]).output();
+        ip(&["netns", "add", NETNS]);
+        let netns = Netns;
+        for (i, (host, peer)) in LINKS.into_iter().enumerate() {
+            ip(&[
+                "link", "add", host, "type", "veth", "peer", peer, "netns", NETNS,
+            ]);
+            ip(&["link", "set", host, "up"]);
+            let addr = format!("10.99.{}.2/24", 51 + i);
+            let peer_ip = |args: &[&str]| ip(&[&["-n", NETNS], args].concat());
+            peer_ip(&["addr", "add", &addr, "dev", peer]);
+            peer_ip(&["link", "set", peer, "up"]);
+        }
+        ip(&[
+            "addr",
+            "add",
+            &format!("{}/24", HOST_ADDR),
+            "dev",
+            LINKS[0].0,
+        ]);
+        netns
+    }
+
+    /// Runs `f` on a thread in the namespace.
+    fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> T {
+        thread::spawn(move || {
+            let path = CString::new(format!("/var/run/netns/{}", NETNS)).unwrap();
+            unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+                assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+                assert_eq!(libc::setns(fd, libc::CLONE_NEWNET), 0);
+                libc::close(fd);
+            }
+            f()
+        })
+        .join()
+        .unwrap()
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(["netns", "del", NETNS]).output();
+    }
+}
+
+/// Sends `data` to `dst` until it comes back, from a peer.
+fn echo_from_peer(dst: &str) -> bool {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut buf = [0u8; 64];
+    (0..10).any(|_| {
+        socket.send_to(dst.as_bytes(), dst).unwrap();
+        matches!(socket.recv_from(&mut buf), Ok((n, _)) if &buf[..n] == dst.as_bytes())
+    })
+}
+
+#[test]
+fn ether_packet_checked() {
+    assert!(matches!(
+        EtherPacketNetDevice::init("", None, None),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        EtherPacketNetDevice::init(LINKS[0].0, None, Some(1)),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+}
+
+/// The peers talk to the stack over veth pairs whose host sides it is attached to, without
+/// and with rings. This needs CAP_NET_ADMIN and
+/// CAP_SYS_ADMIN for the namespace.
+#[test]
+#[ignore = "needs CAP_NET_ADMIN and CAP_SYS_ADMIN for veth pairs in a network namespace"]
+fn ether_packet() {
+    net::net_init().unwrap();
+    let netns = Netns::create();
+    let netmask = IpAddress::parse_from("255.255.255.0");
+    let mut addrs = Vec::new();
+    for (i, ring) in [None, Some(64)].into_iter().enumerate() {
+        let dev = EtherPacketNetDevice::init(LINKS[i].0, None, ring).unwrap();
+        let addr = format!("10.99.{}.1", 51 + i);
+        let iface = ip::IpInterface::new(IpAddress::parse_from(&addr), netmask);
+        ip::ip_iface_register(dev, iface).unwrap();
+        addrs.push(addr);
+    }
+    net::net_run().unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let timeout = Some(Duration::from_millis(100));
+    let mut echoes: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let socket = UdpSocket::bind(format!("{}:{}", addr, PORT)).unwrap();
+            socket.set_read_timeout(timeout).unwrap();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                while !stop.load(Ordering::Relaxed) {
+                    if let Ok((n, peer)) = socket.recv_from(&mut buf) {
+                        socket.send_to(&buf[..n], peer).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    // the host keeps the traffic to its own address on the interface
+    let host = std::net::UdpSocket::bind(format!("{}:{}", HOST_ADDR, PORT)).unwrap();
+    host.set_read_timeout(timeout).unwrap();
+    let host_stop = stop.clone();
+    echoes.push(thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while !host_stop.load(Ordering::Relaxed) {
+            if let Ok((n, peer)) = host.recv_from(&mut buf) {
+                host.send_to(&buf[..n], peer).unwrap();
+            }
+        }
+    }));
+
+    let dsts: Vec<String> = addrs
+        .iter()
+        .map(String::as_str)
+        .chain([HOST_ADDR])
+        .map(|addr| format!("{}:{}", addr, PORT))
+        .collect();
+    let failed = netns.run(move || {
+        dsts.into_iter()
+            .filter(|dst| !echo_from_peer(dst))
+            .collect::<Vec<_>>()
+    });
+    assert!(failed.is_empty(), "no echo from {:?}", failed);
+
+    stop.store(true, Ordering::Relaxed);
+    for echo in echoes {
+        echo.join().unwrap();
+    }
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,418 @@
+mod common;
+
+use std::{io::ErrorKind, sync::MutexGuard, time::Duration};
+
+use common::Shared;
+use utcp::{
+    TcpListener, UdpSocket, arp,
+    dhcp_server::{self, DhcpServerConfig},
+    error::UtcpErr,
+    filter::{self, FilterHook, FilterMatch, FilterPacket, FilterStates, FilterVerdict},
+    ip::{
+        self, IP_ADDR_ANY, IP_ADDR_BROADCAST, IP_PROTOCOL_ICMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP,
+        IpAddress,
+    },
+    net::{self, NET_PROTOCOL_TYPE_IP, NetDeviceHandler},
+    wire::{
+        dhcpv4::*,
+        ethernet::{EthernetAddress, EthernetFrame},
+        icmp::{ICMP_CODE_PORT_UNREACH, ICMP_HEADER_LEN, ICMP_TYPE_DEST_UNREACH, IcmpPacket},
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        tcp::{TCP_HEADER_MIN_LEN, TcpFlags, TcpPacket},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    },
+};
+
+const LAN_ADDR: IpAddress = IpAddress::parse_from("10.99.48.1");
+const WAN_ADDR: IpAddress = IpAddress::parse_from("10.99.49.1");
+const HOST: IpAddress = IpAddress::parse_from("10.99.48.2");
+const REMOTE: IpAddress = IpAddress::parse_from("10.99.49.2");
+
+const LAN_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x48, 0x01]);
+const WAN_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x49, 0x01]);
+const HOST_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x48, 0x02]);
+const REMOTE_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x49, 0x02]);
+
+const SHORT: Duration = Duration::from_millis(200);
+const LONG: Duration = Duration::from_secs(2);
+
+/// A link of the stack, whose hosts the test plays.
+struct Link {
+    tunnel: common::Link,
+    /// Address of the stack on the link.
+    router: EthernetAddress,
+    /// Address the test sends from.
+    hwaddr: EthernetAddress,
+}
+
+impl Link {
+    /// Registers the device of the stack on the link.
+    fn open(router: EthernetAddress, hwaddr: EthernetAddress) -> (Self, NetDeviceHandler) {
+        let (tunnel, dev) = common::Link::open(Some(router));
+        let link = Self {
+            tunnel,
+            router,
+            hwaddr,
+        };
+        (link, dev)
+    }
+
+    fn send(&self, datagram: &[u8]) {
+        self.tunnel
+            .send_frame(self.router, self.hwaddr, NET_PROTOCOL_TYPE_IP, datagram);
+    }
+
+    /// Waits for a while for a datagram of `protocol` the stack sends on the link.
+    fn recv(&self, protocol: u8, timeout: Duration) -> Option<Vec<u8>> {
+        self.tunnel.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            if frame.ethertype() != NET_PROTOCOL_TYPE_IP {
+                return None;
+            }
+            let ip_hdr = Ipv4Packet::new_checked(frame.payload()).unwrap();
+            (ip_hdr.protocol() == protocol).then(|| frame.payload().to_vec())
+        })
+    }
+}
+
+fn datagram(protocol: u8, src: IpAddress, dst: IpAddress, payload: &[u8]) -> Vec<u8> {
+    let total = IPV4_HEADER_MIN_LEN + payload.len();
+    let mut buf = vec![0u8; total];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_total(total as u16);
+    ip_hdr.set_id(1);
+    ip_hdr.set_ttl(64);
+    ip_hdr.set_protocol(protocol);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    ip_hdr.payload_mut().copy_from_slice(payload);
+    ip_hdr.fill_checksum();
+    buf
+}
+
+fn udp(src: (IpAddress, u16), dst: (IpAddress, u16), data: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0u8; UDP_HEADER_LEN + data.len()];
+    let mut packet = UdpPacket::new_unchecked(&mut segment[..]);
+    packet.set_src_port(src.1);
+    packet.set_dst_port(dst.1);
+    packet.set_len((UDP_HEADER_LEN + data.len()) as u16);
+    packet.payload_mut().copy_from_slice(data);
+    packet.fill_checksum(src.0, dst.0);
+    datagram(IP_PROTOCOL_UDP, src.0, dst.0, &segment)
+}
+
+fn syn(src: (IpAddress, u16), dst: (IpAddress, u16)) -> Vec<u8> {
+    let mut segment = vec![0u8; TCP_HEADER_MIN_LEN];
+    let mut packet = TcpPacket::new_unchecked(&mut segment[..]);
+    packet.set_src_port(src.1);
+    packet.set_dst_port(dst.1);
+    packet.set_seq(1000);
+    packet.set_header_len(TCP_HEADER_MIN_LEN);
+    packet.set_flags(TcpFlags::SYN);
+    packet.set_window(8192);
+    packet.fill_checksum(src.0, dst.0);
+    datagram(IP_PROTOCOL_TCP, src.0, dst.0, &segment)
+}
+
+/// Checks that `datagram` is a RST answering `syn`, from the destination of `syn`.
+fn assert_reset(datagram: &[u8], src: (IpAddress, u16), dst: (IpAddress, u16)) {
+    let ip_hdr = Ipv4Packet::new_checked(datagram).unwrap();
+    assert_eq!((ip_hdr.src(), ip_hdr.dst()), (dst.0, src.0));
+    let seg = TcpPacket::new_checked(ip_hdr.payload()).unwrap();
+    assert!(seg.verify_checksum(ip_hdr.src(), ip_hdr.dst()));
+    assert_eq!((seg.src_port(), seg.dst_port()), (dst.1, src.1));
+    assert_eq!(seg.flags(), TcpFlags::RST | TcpFlags::ACK);
+    assert_eq!(seg.ack(), 1001);
+}
+
+fn rule_packets(id: usize) -> u64 {
+    filter::filter_rules()
+        .iter()
+        .find(|rule| rule.id == id)
+        .unwrap()
+        .packets
+}
+
+/// Sends the datagrams for port 7100 to port 7004 instead.
+fn redirect(packet: &mut FilterPacket) -> FilterVerdict {
+    let ip_hdr = Ipv4Packet::new_unchecked(&packet.datagram[..]);
+    if ip_hdr.protocol() != IP_PROTOCOL_UDP {
+        return FilterVerdict::Accept;
+    }
+    let (src, dst) = (ip_hdr.src(), ip_hdr.dst());
+    let (header_len, total) = (ip_hdr.header_len(), ip_hdr.total() as usize);
+    let mut udp = UdpPacket::new_unchecked(&mut packet.datagram[header_len..total]);
+    if udp.dst_port() == 7100 {
+        udp.set_dst_port(7004);
+        udp.fill_checksum(src, dst);
+    }
+    FilterVerdict::Accept
+}
+
+/// The stack between a host on one link and a remote host on another, forwarding.
+struct Stack {
+    lan: NetDeviceHandler,
+    wan: NetDeviceHandler,
+    host: Link,
+    remote: Link,
+}
+
+static STACK: Shared<Stack> = Shared::new();
+
+/// Sets the stack up for the first test, and waits for the turn of the caller. The links are
+/// quiet and the rules and policies back to none and accepting by then.
+fn stack() -> (MutexGuard<'static, ()>, &'static Stack) {
+    let (turn, stack) = STACK.get(|| {
+        net::net_init().unwrap();
+        let (host, lan) = Link::open(LAN_HWADDR, HOST_HWADDR);
+        let (remote, wan) = Link::open(WAN_HWADDR, REMOTE_HWADDR);
+        let netmask = IpAddress::parse_from("255.255.255.0");
+        ip::ip_iface_register(lan, ip::IpInterface::new(LAN_ADDR, netmask)).unwrap();
+        ip::ip_iface_register(wan, ip::IpInterface::new(WAN_ADDR, netmask)).unwrap();
+        arp::arp_add_static(lan, HOST, HOST_HWADDR);
+        arp::arp_add_static(wan, REMOTE, REMOTE_HWADDR);
+        ip::ip_forwarding_set(true);
+        net::net_run().unwrap();
+        Stack {
+            lan,
+            wan,
+            host,
+            remote,
+        }
+    });
+    for rule in filter::filter_rules() {
+        filter::filter_rule_del(rule.id).unwrap();
+    }
+    filter::filter_set_policy(FilterHook::Input, FilterVerdict::Accept);
+    stack.host.tunnel.drain();
+    stack.remote.tunnel.drain();
+    (turn, stack)
+}
+
+#[test]
+fn filter_rules_checked() {
+    let (_turn, stack) = stack();
+    assert!(matches!(
+        filter::filter_rule_add(
+            FilterHook::Input,
+            FilterMatch {
+                out_dev: Some(stack.lan),
+                ..Default::default()
+            },
+            FilterVerdict::Drop
+        ),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        filter::filter_rule_add(
+            FilterHook::Input,
+            FilterMatch {
+                dst_ports: Some(1..=1023),
+                ..Default::default()
+            },
+            FilterVerdict::Drop
+        ),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+}
+
+/// Dropped at the input hook, and counted.
+#[test]
+fn filter_input_drop() {
+    let (_turn, Stack { host, .. }) = stack();
+    let mut buf = [0u8; 64];
+    let socket = UdpSocket::bind("10.99.48.1:7000").unwrap();
+    socket.set_read_timeout(Some(SHORT)).unwrap();
+    let udp_7000 = FilterMatch {
+        protocol: Some(IP_PROTOCOL_UDP),
+        dst_ports: Some(7000..=7000),
+        ..Default::default()
+    };
+    let id = filter::filter_rule_add(FilterHook::Input, udp_7000, FilterVerdict::Drop).unwrap();
+    host.send(&udp((HOST, 5000), (LAN_ADDR, 7000), b"dropped"));
+    assert!(socket.recv_from(&mut buf).is_err());
+    assert_eq!(rule_packets(id), 1);
+    filter::filter_rule_del(id).unwrap();
+    host.send(&udp((HOST, 5000), (LAN_ADDR, 7000), b"accepted"));
+    let (n, _) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"accepted");
+}
+
+/// Rejected TCP gets RST even with a listener, and UDP port unreachable.
+#[test]
+fn filter_input_reject() {
+    let (_turn, Stack { host, .. }) = stack();
+    let _listener = TcpListener::bind("10.99.48.1:2323").unwrap();
+    let tcp_2323 = FilterMatch {
+        protocol: Some(IP_PROTOCOL_TCP),
+        dst_ports: Some(2323..=2323),
+        tcp_flags: Some((TcpFlags::SYN | TcpFlags::ACK, TcpFlags::SYN)),
+        ..Default::default()
+    };
+    let id = filter::filter_rule_add(FilterHook::Input, tcp_2323, FilterVerdict::Reject).unwrap();
+    host.send(&syn((HOST, 40000), (LAN_ADDR, 2323)));
+    let rst = host.recv(IP_PROTOCOL_TCP, LONG).expect("no RST");
+    assert_reset(&rst, (HOST, 40000), (LAN_ADDR, 2323));
+    assert_eq!(rule_packets(id), 1);
+
+    let udp_7001 = FilterMatch {
+        protocol: Some(IP_PROTOCOL_UDP),
+        dst_ports: Some(7001..=7001),
+        ..Default::default()
+    };
+    filter::filter_rule_add(FilterHook::Input, udp_7001, FilterVerdict::Reject).unwrap();
+    let rejected = udp((HOST, 5001), (LAN_ADDR, 7001), b"rejected");
+    host.send(&rejected);
+    let error = host
+        .recv(IP_PROTOCOL_ICMP, LONG)
+        .expect("no port unreachable");
+    let ip_hdr = Ipv4Packet::new_checked(&error[..]).unwrap();
+    assert_eq!((ip_hdr.src(), ip_hdr.dst()), (LAN_ADDR, HOST));
+    let icmp = IcmpPacket::new_checked(ip_hdr.payload()).unwrap();
+    assert!(icmp.verify_checksum());
+    assert_eq!(
+        (icmp.msg_type(), icmp.code()),
+        (ICMP_TYPE_DEST_UNREACH, ICMP_CODE_PORT_UNREACH)
+    );
+    assert_eq!(
+        &ip_hdr.payload()[ICMP_HEADER_LEN..],
+        &rejected[..IPV4_HEADER_MIN_LEN + 8]
+    );
+}
+
+/// With a dropping policy, only replies get in.
+#[test]
+fn filter_input_policy() {
+    let (_turn, Stack { host, .. }) = stack();
+    let mut buf = [0u8; 64];
+    filter::filter_set_policy(FilterHook::Input, FilterVerdict::Drop);
+    let replies = FilterMatch {
+        states: Some(FilterStates::ESTABLISHED | FilterStates::RELATED),
+        ..Default::default()
+    };
+    let id = filter::filter_rule_add(FilterHook::Input, replies, FilterVerdict::Accept).unwrap();
+    let socket = UdpSocket::bind("10.99.48.1:7002").unwrap();
+    socket.set_read_timeout(Some(SHORT)).unwrap();
+    socket.send_to(b"request", "10.99.48.2:9000").unwrap();
+    assert!(host.recv(IP_PROTOCOL_UDP, LONG).is_some());
+    host.send(&udp((HOST, 9001), (LAN_ADDR, 7002), b"unsolicited"));
+    assert!(socket.recv_from(&mut buf).is_err());
+    host.send(&udp((HOST, 9000), (LAN_ADDR, 7002), b"reply"));
+    let (n, _) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"reply");
+    assert_eq!(rule_packets(id), 1);
+}
+
+/// The DHCP server gets its requests behind the input hook, like the sockets.
+#[test]
+fn filter_input_dhcp() {
+    let (_turn, Stack { lan, host, .. }) = stack();
+    let pool = (
+        IpAddress::parse_from("10.99.48.100"),
+        IpAddress::parse_from("10.99.48.110"),
+    );
+    dhcp_server::dhcp_server_start(*lan, DhcpServerConfig::new(pool.0, pool.1)).unwrap();
+    let mut message = vec![0u8; DHCP_HEADER_LEN];
+    let mut packet = DhcpPacket::new_unchecked(&mut message[..]);
+    packet.set_header(DHCP_OP_REQUEST, 1, HOST_HWADDR);
+    packet.set_flags(DHCP_FLAG_BROADCAST);
+    DhcpOptionWriter::new(&mut message)
+        .option_u8(DHCP_OPT_MESSAGE_TYPE, DHCP_DISCOVER)
+        .finish();
+    let discover = udp(
+        (IP_ADDR_ANY, DHCP_CLIENT_PORT),
+        (IP_ADDR_BROADCAST, DHCP_SERVER_PORT),
+        &message,
+    );
+    let send_discover = || {
+        host.tunnel.send_frame(
+            EthernetAddress::BROADCAST,
+            HOST_HWADDR,
+            NET_PROTOCOL_TYPE_IP,
+            &discover,
+        )
+    };
+
+    let udp_67 = FilterMatch {
+        protocol: Some(IP_PROTOCOL_UDP),
+        dst_ports: Some(67..=67),
+        ..Default::default()
+    };
+    let id = filter::filter_rule_add(FilterHook::Input, udp_67, FilterVerdict::Drop).unwrap();
+    send_discover();
+    assert!(host.recv(IP_PROTOCOL_UDP, SHORT).is_none());
+    assert_eq!(rule_packets(id), 1);
+    filter::filter_rule_del(id).unwrap();
+    send_discover();
+    let offer = host.recv(IP_PROTOCOL_UDP, LONG).unwrap();
+    let ip_hdr = Ipv4Packet::new_checked(&offer[..]).unwrap();
+    let reply = UdpPacket::new_checked(ip_hdr.payload()).unwrap();
+    let packet = DhcpPacket::new_checked(reply.payload()).unwrap();
+    assert_eq!(packet.message_type(), Some(DHCP_OFFER));
+    dhcp_server::dhcp_server_stop(*lan).unwrap();
+}
+
+/// Senders learn that their datagrams were dropped at the output hook.
+#[test]
+fn filter_output_drop() {
+    let (_turn, Stack { host, .. }) = stack();
+    let udp_9999 = FilterMatch {
+        protocol: Some(IP_PROTOCOL_UDP),
+        dst_ports: Some(9999..=9999),
+        ..Default::default()
+    };
+    filter::filter_rule_add(FilterHook::Output, udp_9999, FilterVerdict::Drop).unwrap();
+    let socket = UdpSocket::bind("10.99.48.1:7003").unwrap();
+    let err = socket.send_to(b"denied", "10.99.48.2:9999").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(host.recv(IP_PROTOCOL_UDP, SHORT).is_none());
+}
+
+/// Handlers modify datagrams.
+#[test]
+fn filter_handler() {
+    let (_turn, Stack { host, .. }) = stack();
+    let mut buf = [0u8; 64];
+    let socket = UdpSocket::bind("10.99.48.1:7004").unwrap();
+    socket.set_read_timeout(Some(SHORT)).unwrap();
+    let handler = filter::filter_handler_register(FilterHook::Prerouting, redirect);
+    host.send(&udp((HOST, 5002), (LAN_ADDR, 7100), b"redirected"));
+    let received = socket.recv_from(&mut buf);
+    filter::filter_handler_unregister(handler).unwrap();
+    let (n, _) = received.unwrap();
+    assert_eq!(&buf[..n], b"redirected");
+    assert!(filter::filter_handler_unregister(handler).is_err());
+}
+
+/// Forwarded datagrams are rejected on behalf of their destination.
+#[test]
+fn filter_forward() {
+    let (_turn, stack) = stack();
+    let Stack { host, remote, .. } = stack;
+    let forward_tcp = FilterMatch {
+        in_dev: Some(stack.lan),
+        out_dev: Some(stack.wan),
+        protocol: Some(IP_PROTOCOL_TCP),
+        ..Default::default()
+    };
+    filter::filter_rule_add(FilterHook::Forward, forward_tcp, FilterVerdict::Reject).unwrap();
+    host.send(&syn((HOST, 40001), (REMOTE, 80)));
+    let rst = host.recv(IP_PROTOCOL_TCP, LONG).expect("no RST");
+    assert_reset(&rst, (HOST, 40001), (REMOTE, 80));
+    assert!(remote.recv(IP_PROTOCOL_TCP, SHORT).is_none());
+
+    let forward_udp = FilterMatch {
+        dst: Some((REMOTE, IpAddress::parse_from("255.255.255.255"))),
+        protocol: Some(IP_PROTOCOL_UDP),
+        dst_ports: Some(53..=53),
+        ..Default::default()
+    };
+    let id =
+        filter::filter_rule_add(FilterHook::Forward, forward_udp, FilterVerdict::Drop).unwrap();
+    host.send(&udp((HOST, 5003), (REMOTE, 53), b"query"));
+    assert!(remote.recv(IP_PROTOCOL_UDP, SHORT).is_none());
+    filter::filter_rule_del(id).unwrap();
+    host.send(&udp((HOST, 5003), (REMOTE, 53), b"query"));
+    assert!(remote.recv(IP_PROTOCOL_UDP, LONG).is_some());
+}
@@ -0,0 +1,66 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    icmpv6,
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    net,
+    wire::{
+        icmpv6::{ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER, ICMPV6_TYPE_PARAM_PROBLEM},
+        ipv6::Ipv6Packet,
+    },
+};
+
+/// No protocol handler is registered for it.
+const UNKNOWN_PROTOCOL: u8 = 252;
+
+static ERRORS: Mutex<Vec<(u8, u8, u32, Ipv6Address)>> = Mutex::new(Vec::new());
+
+fn error_input(ty: u8, code: u8, param: u32, invoking: &[u8]) {
+    let dst = Ipv6Packet::new_unchecked(invoking).dst();
+    ERRORS.lock().unwrap().push((ty, code, param, dst));
+}
+
+#[test]
+fn icmpv6() {
+    net::net_init().unwrap();
+    icmpv6::icmpv6_error_handler_register(UNKNOWN_PROTOCOL, error_input).unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+    net::net_run().unwrap();
+    ipv6::ipv6_addr_add(dev, Ipv6Address::LOOPBACK, 128).unwrap();
+    ipv6::ipv6_addr_add(dev, "2001:db8::1".parse().unwrap(), 64).unwrap();
+
+    let timeout = Duration::from_secs(1);
+    icmpv6::icmpv6_ping(Ipv6Address::LOOPBACK, b"ping", timeout).unwrap();
+    icmpv6::icmpv6_ping(Ipv6Address::ALL_NODES, b"ping", timeout).unwrap();
+    // the loopback device hands the request back, and nobody answers for another address
+    assert!(matches!(
+        icmpv6::icmpv6_ping("2001:db8::2".parse().unwrap(), b"ping", timeout),
+        Err(UtcpErr::TimedOut)
+    ));
+
+    // a datagram of an unknown protocol comes back as a Parameter Problem pointing at the
+    // next header field
+    let dst: Ipv6Address = "2001:db8::1".parse().unwrap();
+    ipv6::ipv6_output(UNKNOWN_PROTOCOL, b"unknown", Ipv6Address::UNSPECIFIED, dst).unwrap();
+    let deadline = Instant::now() + timeout;
+    while ERRORS.lock().unwrap().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        ERRORS.lock().unwrap().as_slice(),
+        [(
+            ICMPV6_TYPE_PARAM_PROBLEM,
+            ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+            6,
+            dst
+        )]
+    );
+
+    net::net_shutdown().unwrap();
+}
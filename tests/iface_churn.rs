@@ -0,0 +1,72 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    UdpSocket,
+    driver::{dummy::DummyNetDevice, loopback::LoopbackNetDevice},
+    ip::{self, IpAddress},
+    ipv6::{self, Ipv6Interface},
+    net,
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const DUMMY_ADDR: IpAddress = IpAddress::parse_from("10.99.62.1");
+
+/// Interfaces come and go on other threads, as the DHCP client and SLAAC do from their timers,
+/// while datagrams are routed and delivered through the ones that stay.
+#[test]
+fn iface_churn() {
+    net::net_init().unwrap();
+    let lo = LoopbackNetDevice::init().unwrap();
+    // the IPv6 interface comes first, so that removing it used to move the IP one
+    ipv6::ipv6_iface_register(lo, Ipv6Interface::new()).unwrap();
+    ip::ip_iface_register(lo, ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK)).unwrap();
+    let dummy = DummyNetDevice::init().unwrap();
+    net::net_run().unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let netmask = IpAddress::parse_from("255.255.255.0");
+            while !stop.load(Ordering::Relaxed) {
+                ipv6::ipv6_iface_unregister(lo).unwrap();
+                ip::ip_iface_register(dummy, ip::IpInterface::new(DUMMY_ADDR, netmask)).unwrap();
+                ipv6::ipv6_iface_register(lo, Ipv6Interface::new()).unwrap();
+                ip::ip_iface_unregister(dummy).unwrap();
+            }
+        })
+    };
+
+    let server = UdpSocket::bind("127.0.0.1:7300").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut buf = [0u8; 8];
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut count = 0u32;
+    while Instant::now() < deadline {
+        client
+            .send_to(&count.to_be_bytes(), "127.0.0.1:7300")
+            .unwrap();
+        let (len, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], count.to_be_bytes());
+        assert_eq!(
+            ip::ip_route_source(IpAddress::parse_from("127.0.0.2")).unwrap(),
+            LOOPBACK_IP_ADDR
+        );
+        count += 1;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+    net::net_shutdown().unwrap();
+}
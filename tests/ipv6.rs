@@ -0,0 +1,142 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    UdpSocket,
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    net::{self, NetInterfaceHandler},
+    wire::ipv6::IPV6_NEXT_HEADER_DEST_OPTS,
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const TEST_PROTOCOL: u8 = 253;
+
+static RECEIVED: Mutex<Vec<(Ipv6Address, Ipv6Address, Vec<u8>)>> = Mutex::new(Vec::new());
+
+fn test_input(data: &[u8], src: Ipv6Address, dst: Ipv6Address, _: &NetInterfaceHandler) {
+    RECEIVED.lock().unwrap().push((src, dst, data.to_vec()));
+}
+
+/// Waits for the next datagram of the test protocol, or returns `None` after a while.
+fn received() -> Option<(Ipv6Address, Ipv6Address, Vec<u8>)> {
+    let deadline = Instant::now() + Duration::from_millis(300);
+    while Instant::now() < deadline {
+        let mut received = RECEIVED.lock().unwrap();
+        if !received.is_empty() {
+            return Some(received.remove(0));
+        }
+        drop(received);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+#[test]
+fn ipv6() {
+    net::net_init().unwrap();
+    ipv6::ipv6_protocol_register(TEST_PROTOCOL, test_input).unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    // IPv6 first, so that removing it moves the IPv4 interface
+    ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    let global: Ipv6Address = "2001:db8::1".parse().unwrap();
+    ipv6::ipv6_addr_add(dev, Ipv6Address::LOOPBACK, 128).unwrap();
+    ipv6::ipv6_addr_add(dev, global, 64).unwrap();
+    assert!(matches!(
+        ipv6::ipv6_addr_add(dev, global, 64),
+        Err(UtcpErr::AddrInUse)
+    ));
+    assert_eq!(ipv6::ipv6_addrs(dev).len(), 2);
+
+    // the source address follows the destination
+    ipv6::ipv6_output(
+        TEST_PROTOCOL,
+        b"loopback",
+        Ipv6Address::UNSPECIFIED,
+        Ipv6Address::LOOPBACK,
+    )
+    .unwrap();
+    let (src, dst, data) = received().unwrap();
+    assert_eq!((src, dst), (Ipv6Address::LOOPBACK, Ipv6Address::LOOPBACK));
+    assert_eq!(data, b"loopback");
+
+    ipv6::ipv6_output(TEST_PROTOCOL, b"global", Ipv6Address::UNSPECIFIED, global).unwrap();
+    let (src, _, data) = received().unwrap();
+    assert_eq!(src, global);
+    assert_eq!(data, b"global");
+    assert_eq!(ipv6::ipv6_route_source(global).unwrap(), global);
+
+    // multicast to all nodes is received, other hosts on the prefix are not
+    ipv6::ipv6_output(
+        TEST_PROTOCOL,
+        b"all nodes",
+        Ipv6Address::UNSPECIFIED,
+        Ipv6Address::ALL_NODES,
+    )
+    .unwrap();
+    assert_eq!(received().unwrap().1, Ipv6Address::ALL_NODES);
+    let neighbor: Ipv6Address = "2001:db8::2".parse().unwrap();
+    ipv6::ipv6_output(TEST_PROTOCOL, b"other", Ipv6Address::UNSPECIFIED, neighbor).unwrap();
+    assert!(received().is_none());
+
+    // the payload starts after the extension headers
+    let mut dest_opts = vec![TEST_PROTOCOL, 0, 1, 4, 0, 0, 0, 0];
+    dest_opts.extend_from_slice(b"options");
+    ipv6::ipv6_output(IPV6_NEXT_HEADER_DEST_OPTS, &dest_opts, global, global).unwrap();
+    assert_eq!(received().unwrap().2, b"options");
+    // an unknown option of type 01xxxxxx discards the datagram
+    dest_opts[2] = 0x40;
+    ipv6::ipv6_output(IPV6_NEXT_HEADER_DEST_OPTS, &dest_opts, global, global).unwrap();
+    assert!(received().is_none());
+
+    // routes
+    let remote: Ipv6Address = "2001:db8:1::1".parse().unwrap();
+    assert!(matches!(
+        ipv6::ipv6_output(TEST_PROTOCOL, b"", Ipv6Address::UNSPECIFIED, remote),
+        Err(UtcpErr::NoRoute)
+    ));
+    ipv6::ipv6_route_set_default_gateway(dev, "fe80::1".parse().unwrap()).unwrap();
+    ipv6::ipv6_output(TEST_PROTOCOL, b"", Ipv6Address::UNSPECIFIED, remote).unwrap();
+    assert!(received().is_none());
+    ipv6::ipv6_addr_del(dev, global).unwrap();
+    assert!(matches!(
+        ipv6::ipv6_addr_del(dev, global),
+        Err(UtcpErr::AddrNotAvailable)
+    ));
+    assert_eq!(
+        ipv6::ipv6_addrs(dev),
+        vec![ipv6::Ipv6InterfaceAddress {
+            addr: Ipv6Address::LOOPBACK,
+            prefix_len: 128
+        }]
+    );
+
+    // IPv4 keeps working without the IPv6 interface
+    ipv6::ipv6_iface_unregister(dev).unwrap();
+    assert!(ipv6::ipv6_addrs(dev).is_empty());
+    assert!(matches!(
+        ipv6::ipv6_output(
+            TEST_PROTOCOL,
+            b"",
+            Ipv6Address::UNSPECIFIED,
+            Ipv6Address::LOOPBACK
+        ),
+        Err(UtcpErr::NoRoute)
+    ));
+    let socket = UdpSocket::bind("127.0.0.1:7000").unwrap();
+    socket.send_to(b"ipv4", "127.0.0.1:7000").unwrap();
+    let mut buf = [0u8; 16];
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ipv4");
+
+    net::net_shutdown().unwrap();
+}
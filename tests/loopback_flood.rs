@@ -0,0 +1,66 @@
+mod common;
+
+use common::{delivered, held, held_handler, release, wait_until};
+use utcp::{
+    driver::{dummy::DummyNetDevice, loopback::LoopbackNetDevice},
+    error::UtcpErr,
+    net::{self, NetProtocol},
+    utils::DropPolicy,
+};
+
+/// Local experimental Ethertype, so that only the test handler sees the packets.
+const TYPE: u16 = 0x88b5;
+
+const DEVICE_QUEUE_LIMIT: usize = 8;
+const PROTOCOL_QUEUE_LIMIT: usize = 4;
+
+/// While the interrupt thread is stuck in the handler, the loopback queue fills and drops the
+/// rest. Once it is released, the protocol queue throttles the device instead of dropping, and
+/// everything that was queued is delivered in order.
+#[test]
+fn loopback_flood() {
+    net::net_init().unwrap();
+    let dev =
+        LoopbackNetDevice::init_with_queue_limit(DEVICE_QUEUE_LIMIT, DropPolicy::DropTail).unwrap();
+    net::net_protocol_register(NetProtocol::new(TYPE, held_handler));
+    net::net_protocol_set_queue_limit(TYPE, PROTOCOL_QUEUE_LIMIT, DropPolicy::DropTail).unwrap();
+    // devices without a receive queue of their own have no limit to set
+    let dummy = DummyNetDevice::init().unwrap();
+    assert!(matches!(
+        net::net_device_set_queue_limit(&dummy, 1, DropPolicy::DropTail),
+        Err(UtcpErr::NotSupported(_))
+    ));
+    assert_eq!(net::net_device_queue_stats(&dummy), None);
+    net::net_run().unwrap();
+
+    let output = |id: u8| net::net_device_output(&dev, TYPE, &[id], &mut []);
+    output(0).unwrap();
+    assert!(wait_until(held));
+
+    let results: Vec<_> = (1..=20).map(output).collect();
+    assert!(results[..DEVICE_QUEUE_LIMIT].iter().all(Result::is_ok));
+    assert!(
+        results[DEVICE_QUEUE_LIMIT..]
+            .iter()
+            .all(|result| matches!(result, Err(UtcpErr::QueueFull)))
+    );
+    let stats = net::net_device_queue_stats(&dev).unwrap();
+    assert_eq!(stats.dropped, (20 - DEVICE_QUEUE_LIMIT) as u64);
+
+    release();
+    let expected: Vec<u8> = (0..=DEVICE_QUEUE_LIMIT as u8).collect();
+    assert!(
+        wait_until(|| delivered() == expected),
+        "delivered {:?}",
+        delivered()
+    );
+    let stats = net::net_protocol_queue_stats(TYPE).unwrap();
+    assert_eq!(stats.dropped, 0);
+    assert_eq!(stats.dequeued, expected.len() as u64);
+
+    // the device was unthrottled, so what comes next gets through right away
+    output(100).unwrap();
+    assert!(wait_until(|| delivered().last() == Some(&100)));
+
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    igmp::{self, IGMP_ADDR_ALL_ROUTERS, IGMP_ADDR_V3_ROUTERS, IgmpVersion},
+    ip::{self, IP_ADDR_ALL_HOSTS, IP_ADDR_ANY, IP_PROTOCOL_IGMP, IpAddress, IpEndpoint},
+    net, raw, socket,
+    wire::{
+        igmp::{
+            IGMP_TYPE_MEMBERSHIP_QUERY, IGMP_TYPE_V2_LEAVE_GROUP, IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            IGMP_TYPE_V3_MEMBERSHIP_REPORT, IgmpPacket,
+        },
+        ipv4::Ipv4Packet,
+    },
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const GROUP: IpAddress = IpAddress::parse_from("239.1.2.3");
+const OTHER_GROUP: IpAddress = IpAddress::parse_from("239.1.2.4");
+
+/// Waits for an IGMP message of type `ty` about `group` sent to `dst`.
+fn igmp_wait(rx: usize, ty: u8, group: IpAddress, dst: IpAddress) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    let mut buf = [0u8; 256];
+    while Instant::now() < deadline {
+        let len = match raw::raw_recvfrom(rx, &mut buf) {
+            Ok((len, _)) => len,
+            Err(UtcpErr::WouldBlock) => {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => panic!("{}", e),
+        };
+        let ip_hdr = Ipv4Packet::new_checked(&buf[..len]).unwrap();
+        let message = IgmpPacket::new_checked(ip_hdr.payload()).unwrap();
+        assert!(message.verify_checksum());
+        let about = match ty {
+            // the group is in the first group record
+            IGMP_TYPE_V3_MEMBERSHIP_REPORT => IpAddress::from([
+                ip_hdr.payload()[12],
+                ip_hdr.payload()[13],
+                ip_hdr.payload()[14],
+                ip_hdr.payload()[15],
+            ]),
+            _ => message.group(),
+        };
+        if message.msg_type() == ty && about == group && ip_hdr.dst() == dst {
+            assert_eq!(ip_hdr.ttl(), 1);
+            return ip_hdr.payload().to_vec();
+        }
+    }
+    panic!("no IGMP message: type=0x{:02x}, group={}", ty, group);
+}
+
+fn udp_member(port: u16) -> i32 {
+    let fd = socket::socket(socket::AF_INET, socket::SOCK_DGRAM, 0).unwrap();
+    socket::set_reuse_addr(fd, true).unwrap();
+    socket::bind(fd, IpEndpoint::new(IP_ADDR_ANY, port)).unwrap();
+    socket::set_recv_timeout(fd, Some(Duration::from_millis(200))).unwrap();
+    fd
+}
+
+#[test]
+fn multicast() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    // listen where routers would, to see what the host sends
+    let rx = raw::raw_open(IP_PROTOCOL_IGMP).unwrap();
+    raw::raw_set_nonblocking(rx, true).unwrap();
+    igmp::igmp_join(dev, IGMP_ADDR_V3_ROUTERS).unwrap();
+    igmp::igmp_join(dev, IGMP_ADDR_ALL_ROUTERS).unwrap();
+    assert_eq!(igmp::igmp_version(dev), IgmpVersion::V3);
+
+    // two sockets share the port and the group, a third one is on another port
+    let a = udp_member(5000);
+    let b = udp_member(5000);
+    let other = udp_member(5001);
+    socket::join_multicast(a, GROUP, IP_ADDR_ANY).unwrap();
+    igmp_wait(
+        rx,
+        IGMP_TYPE_V3_MEMBERSHIP_REPORT,
+        GROUP,
+        IGMP_ADDR_V3_ROUTERS,
+    );
+    socket::join_multicast(b, GROUP, LOOPBACK_IP_ADDR).unwrap();
+    assert!(matches!(
+        socket::join_multicast(b, GROUP, IP_ADDR_ANY),
+        Err(UtcpErr::AddrInUse)
+    ));
+    assert!(igmp::igmp_groups(dev).contains(&GROUP));
+    // the port is only shared by sockets that ask for it
+    let exclusive = socket::socket(socket::AF_INET, socket::SOCK_DGRAM, 0).unwrap();
+    assert!(matches!(
+        socket::bind(exclusive, IpEndpoint::new(IP_ADDR_ANY, 5000)),
+        Err(UtcpErr::AddrInUse)
+    ));
+
+    let tx = socket::socket(socket::AF_INET, socket::SOCK_DGRAM, 0).unwrap();
+    socket::sendto(tx, b"to the group", IpEndpoint::new(GROUP, 5000)).unwrap();
+    let mut buf = [0u8; 64];
+    for fd in [a, b] {
+        let (len, _) = socket::recvfrom(fd, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"to the group");
+    }
+    assert!(matches!(
+        socket::recvfrom(other, &mut buf),
+        Err(UtcpErr::WouldBlock)
+    ));
+
+    // groups that were not joined are not received
+    socket::sendto(tx, b"elsewhere", IpEndpoint::new(OTHER_GROUP, 5000)).unwrap();
+    assert!(matches!(
+        socket::recvfrom(a, &mut buf),
+        Err(UtcpErr::WouldBlock)
+    ));
+
+    // an IGMPv2 querier switches the host to IGMPv2 reports within the response time
+    let mut query = [0u8; 8];
+    let mut packet = IgmpPacket::new_unchecked(&mut query[..]);
+    packet.set_msg_type(IGMP_TYPE_MEMBERSHIP_QUERY);
+    packet.set_max_resp_code(10);
+    packet.fill_checksum();
+    let tx_raw = raw::raw_open(IP_PROTOCOL_IGMP).unwrap();
+    raw::raw_sendto(tx_raw, &query, IP_ADDR_ALL_HOSTS).unwrap();
+    igmp_wait(rx, IGMP_TYPE_V2_MEMBERSHIP_REPORT, GROUP, GROUP);
+    assert_eq!(igmp::igmp_version(dev), IgmpVersion::V2);
+
+    // the group is left with the last socket
+    socket::leave_multicast(a, GROUP, IP_ADDR_ANY).unwrap();
+    assert!(matches!(
+        socket::leave_multicast(a, GROUP, IP_ADDR_ANY),
+        Err(UtcpErr::AddrNotAvailable)
+    ));
+    assert!(igmp::igmp_groups(dev).contains(&GROUP));
+    socket::close(b).unwrap();
+    igmp_wait(rx, IGMP_TYPE_V2_LEAVE_GROUP, GROUP, IGMP_ADDR_ALL_ROUTERS);
+    assert!(!igmp::igmp_groups(dev).contains(&GROUP));
+
+    socket::sendto(tx, b"to the group", IpEndpoint::new(GROUP, 5000)).unwrap();
+    assert!(matches!(
+        socket::recvfrom(a, &mut buf),
+        Err(UtcpErr::WouldBlock)
+    ));
+
+    net::net_shutdown().unwrap();
+}
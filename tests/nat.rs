@@ -0,0 +1,432 @@
+mod common;
+
+use std::{sync::MutexGuard, time::Duration};
+
+use common::Shared;
+use utcp::{
+    arp,
+    conntrack::{self, ConntrackState, ConntrackTimeouts},
+    error::UtcpErr,
+    ip::{self, IP_PROTOCOL_ICMP, IP_PROTOCOL_TCP, IP_PROTOCOL_UDP, IpAddress, IpEndpoint},
+    nat::{self, NAT_PORT_MAX, NAT_PORT_MIN},
+    net::{self, NET_PROTOCOL_TYPE_IP, NetDeviceHandler},
+    wire::{
+        ethernet::{EthernetAddress, EthernetFrame},
+        icmp::{
+            ICMP_CODE_FRAGMENT_NEEDED, ICMP_CODE_PORT_UNREACH, ICMP_HEADER_LEN,
+            ICMP_TYPE_DEST_UNREACH, ICMP_TYPE_ECHO, ICMP_TYPE_ECHOREPLY, ICMP_TYPE_TIME_EXCEEDED,
+            IcmpPacket,
+        },
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        tcp::{TCP_HEADER_MIN_LEN, TcpFlags, TcpPacket},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    },
+};
+
+const INSIDE_ADDR: IpAddress = IpAddress::parse_from("10.99.46.1");
+const OUTSIDE_ADDR: IpAddress = IpAddress::parse_from("10.99.47.1");
+const HOST: IpAddress = IpAddress::parse_from("10.99.46.2");
+const REMOTE: IpAddress = IpAddress::parse_from("10.99.47.2");
+const REMOTE2: IpAddress = IpAddress::parse_from("10.99.47.3");
+
+const INSIDE_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x46, 0x01]);
+const OUTSIDE_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x47, 0x01]);
+const HOST_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x46, 0x02]);
+const REMOTE_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x47, 0x02]);
+
+const SERVER_PORT: u16 = 8080;
+
+const SHORT: Duration = Duration::from_millis(200);
+const LONG: Duration = Duration::from_secs(2);
+
+/// A link of the router, whose hosts the test plays.
+struct Link {
+    tunnel: common::Link,
+    /// Address of the router on the link.
+    router: EthernetAddress,
+    /// Address the test sends from.
+    hwaddr: EthernetAddress,
+}
+
+impl Link {
+    /// Registers the device of the router on the link.
+    fn open(router: EthernetAddress, hwaddr: EthernetAddress) -> (Self, NetDeviceHandler) {
+        let (tunnel, dev) = common::Link::open(Some(router));
+        let link = Self {
+            tunnel,
+            router,
+            hwaddr,
+        };
+        (link, dev)
+    }
+
+    /// Sends `datagram` to the router.
+    fn send(&self, datagram: &[u8]) {
+        self.tunnel
+            .send_frame(self.router, self.hwaddr, NET_PROTOCOL_TYPE_IP, datagram);
+    }
+
+    /// Waits for a while for a datagram the router sends on the link.
+    fn recv(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.tunnel.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            assert_eq!(frame.src(), self.router);
+            if frame.ethertype() != NET_PROTOCOL_TYPE_IP {
+                return None;
+            }
+            let ip_hdr = Ipv4Packet::new_checked(frame.payload()).unwrap();
+            assert!(ip_hdr.verify_checksum());
+            Some(frame.payload().to_vec())
+        })
+    }
+}
+
+/// The router between an inside link and an outside one, masquerading the inside hosts and
+/// forwarding `SERVER_PORT` to the server of the inside host.
+struct Router {
+    inside: NetDeviceHandler,
+    outside: NetDeviceHandler,
+    lan: Link,
+    wan: Link,
+}
+
+static ROUTER: Shared<Router> = Shared::new();
+
+/// Sets the router up for the first test, and waits for the turn of the caller. The links
+/// are quiet and the settings some tests change are back to their defaults by then.
+fn router() -> (MutexGuard<'static, ()>, &'static Router) {
+    let (turn, router) = ROUTER.get(|| {
+        net::net_init().unwrap();
+        let (lan, inside) = Link::open(INSIDE_HWADDR, HOST_HWADDR);
+        let (wan, outside) = Link::open(OUTSIDE_HWADDR, REMOTE_HWADDR);
+        let netmask = IpAddress::parse_from("255.255.255.0");
+        ip::ip_iface_register(inside, ip::IpInterface::new(INSIDE_ADDR, netmask)).unwrap();
+        ip::ip_iface_register(outside, ip::IpInterface::new(OUTSIDE_ADDR, netmask)).unwrap();
+        arp::arp_add_static(inside, HOST, HOST_HWADDR);
+        arp::arp_add_static(outside, REMOTE, REMOTE_HWADDR);
+        arp::arp_add_static(outside, REMOTE2, REMOTE_HWADDR);
+        nat::nat_masquerade_add(inside, outside).unwrap();
+        let server = IpEndpoint::new(HOST, 80);
+        nat::nat_port_forward_add(outside, IP_PROTOCOL_TCP, SERVER_PORT, server).unwrap();
+        net::net_run().unwrap();
+        Router {
+            inside,
+            outside,
+            lan,
+            wan,
+        }
+    });
+    ip::ip_forwarding_set(true);
+    conntrack::conntrack_set_timeouts(ConntrackTimeouts::default());
+    router.lan.tunnel.drain();
+    router.wan.tunnel.drain();
+    (turn, router)
+}
+
+fn datagram(protocol: u8, src: IpAddress, dst: IpAddress, ttl: u8, payload: &[u8]) -> Vec<u8> {
+    let total = IPV4_HEADER_MIN_LEN + payload.len();
+    let mut buf = vec![0u8; total];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_total(total as u16);
+    ip_hdr.set_id(1);
+    ip_hdr.set_ttl(ttl);
+    ip_hdr.set_protocol(protocol);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    ip_hdr.payload_mut().copy_from_slice(payload);
+    ip_hdr.fill_checksum();
+    buf
+}
+
+fn udp(src: (IpAddress, u16), dst: (IpAddress, u16), ttl: u8, data: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0u8; UDP_HEADER_LEN + data.len()];
+    let mut packet = UdpPacket::new_unchecked(&mut segment[..]);
+    packet.set_src_port(src.1);
+    packet.set_dst_port(dst.1);
+    packet.set_len((UDP_HEADER_LEN + data.len()) as u16);
+    packet.payload_mut().copy_from_slice(data);
+    packet.fill_checksum(src.0, dst.0);
+    datagram(IP_PROTOCOL_UDP, src.0, dst.0, ttl, &segment)
+}
+
+fn tcp(src: (IpAddress, u16), dst: (IpAddress, u16), flags: TcpFlags) -> Vec<u8> {
+    let mut segment = vec![0u8; TCP_HEADER_MIN_LEN];
+    let mut packet = TcpPacket::new_unchecked(&mut segment[..]);
+    packet.set_src_port(src.1);
+    packet.set_dst_port(dst.1);
+    packet.set_seq(1000);
+    packet.set_ack(if flags.contains(TcpFlags::ACK) {
+        2001
+    } else {
+        0
+    });
+    packet.set_header_len(TCP_HEADER_MIN_LEN);
+    packet.set_flags(flags);
+    packet.set_window(8192);
+    packet.fill_checksum(src.0, dst.0);
+    datagram(IP_PROTOCOL_TCP, src.0, dst.0, 64, &segment)
+}
+
+fn icmp_echo(ty: u8, src: IpAddress, dst: IpAddress, id: u16) -> Vec<u8> {
+    let mut msg = vec![0u8; ICMP_HEADER_LEN + 4];
+    let mut packet = IcmpPacket::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ty);
+    packet.set_code(0);
+    packet.set_id(id);
+    packet.set_seq(1);
+    packet.payload_mut().copy_from_slice(b"ping");
+    packet.fill_checksum();
+    datagram(IP_PROTOCOL_ICMP, src, dst, 64, &msg)
+}
+
+/// An ICMP error about `about`, quoting its header and the first 8 bytes of its payload.
+fn icmp_error(ty: u8, code: u8, src: IpAddress, dst: IpAddress, about: &[u8]) -> Vec<u8> {
+    let quoted = &about[..IPV4_HEADER_MIN_LEN + 8];
+    let mut msg = vec![0u8; ICMP_HEADER_LEN + quoted.len()];
+    let mut packet = IcmpPacket::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ty);
+    packet.set_code(code);
+    packet.payload_mut().copy_from_slice(quoted);
+    packet.fill_checksum();
+    datagram(IP_PROTOCOL_ICMP, src, dst, 64, &msg)
+}
+
+/// Checks the headers of a received ICMP error and returns its source and destination, and
+/// the source and destination endpoints of the datagram it quotes.
+fn icmp_error_endpoints(datagram: &[u8]) -> ((IpAddress, IpAddress), [(IpAddress, u16); 2]) {
+    let ip_hdr = Ipv4Packet::new_checked(datagram).unwrap();
+    assert!(ip_hdr.verify_checksum());
+    let icmp = IcmpPacket::new_checked(ip_hdr.payload()).unwrap();
+    assert!(icmp.verify_checksum());
+    let quoted = Ipv4Packet::new_unchecked(icmp.payload());
+    assert!(quoted.verify_checksum());
+    let ports = &icmp.payload()[quoted.header_len()..];
+    let port = |off: usize| u16::from_be_bytes([ports[off], ports[off + 1]]);
+    (
+        (ip_hdr.src(), ip_hdr.dst()),
+        [(quoted.src(), port(0)), (quoted.dst(), port(2))],
+    )
+}
+
+/// Checks the headers of a received datagram and returns its source and destination
+/// endpoints.
+fn endpoints(datagram: &[u8]) -> ((IpAddress, u16), (IpAddress, u16)) {
+    let ip_hdr = Ipv4Packet::new_checked(datagram).unwrap();
+    let (src, dst) = (ip_hdr.src(), ip_hdr.dst());
+    match ip_hdr.protocol() {
+        IP_PROTOCOL_UDP => {
+            let udp = UdpPacket::new_checked(ip_hdr.payload()).unwrap();
+            assert!(udp.verify_checksum(src, dst));
+            ((src, udp.src_port()), (dst, udp.dst_port()))
+        }
+        IP_PROTOCOL_TCP => {
+            let tcp = TcpPacket::new_checked(ip_hdr.payload()).unwrap();
+            assert!(tcp.verify_checksum(src, dst));
+            ((src, tcp.src_port()), (dst, tcp.dst_port()))
+        }
+        _ => {
+            let icmp = IcmpPacket::new_checked(ip_hdr.payload()).unwrap();
+            assert!(icmp.verify_checksum());
+            ((src, icmp.id()), (dst, icmp.id()))
+        }
+    }
+}
+
+fn connection(protocol: u8, inside: (IpAddress, u16)) -> Option<nat::NatConnection> {
+    nat::nat_connections().into_iter().find(|conn| {
+        conn.protocol == protocol && conn.inside == IpEndpoint::new(inside.0, inside.1)
+    })
+}
+
+#[test]
+fn nat_rules_checked() {
+    let (_turn, router) = router();
+    assert!(matches!(
+        nat::nat_masquerade_add(router.inside, router.outside),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    let server = IpEndpoint::new(HOST, 80);
+    assert!(matches!(
+        nat::nat_port_forward_add(router.outside, IP_PROTOCOL_TCP, SERVER_PORT, server),
+        Err(UtcpErr::AddrInUse)
+    ));
+    assert!(matches!(
+        nat::nat_port_forward_add(router.outside, IP_PROTOCOL_ICMP, 1, server),
+        Err(UtcpErr::ProtocolNotSupported(_))
+    ));
+}
+
+/// The mapping does not depend on the remote host, but the filtering does (RFC 4787).
+#[test]
+fn nat_udp() {
+    let (_turn, Router { lan, wan, .. }) = router();
+
+    // UDP leaves with the outside address and an allocated port
+    lan.send(&udp((HOST, 5000), (REMOTE, 53), 64, b"query"));
+    let out = wan.recv(LONG).expect("not forwarded");
+    let (src, dst) = endpoints(&out);
+    assert_eq!(src.0, OUTSIDE_ADDR);
+    assert!((NAT_PORT_MIN..=NAT_PORT_MAX).contains(&src.1));
+    assert_eq!(dst, (REMOTE, 53));
+    assert_eq!(Ipv4Packet::new_unchecked(&out[..]).ttl(), 63);
+    let mapped = src;
+    assert_eq!(
+        connection(IP_PROTOCOL_UDP, (HOST, 5000)).unwrap().state,
+        ConntrackState::New
+    );
+
+    // and the reply gets the inside endpoint back
+    wan.send(&udp((REMOTE, 53), mapped, 64, b"answer"));
+    let back = lan.recv(LONG).expect("reply not forwarded");
+    assert_eq!(endpoints(&back), ((REMOTE, 53), (HOST, 5000)));
+    let conn = connection(IP_PROTOCOL_UDP, (HOST, 5000)).unwrap();
+    assert_eq!(conn.state, ConntrackState::Established);
+    assert_eq!(conn.outside, IpEndpoint::new(mapped.0, mapped.1));
+
+    lan.send(&udp((HOST, 5000), (REMOTE2, 53), 64, b"query"));
+    let (src, _) = endpoints(&wan.recv(LONG).unwrap());
+    assert_eq!(src, mapped);
+    wan.send(&udp((REMOTE, 54), mapped, 64, b"unsolicited"));
+    wan.send(&udp(
+        (REMOTE, 53),
+        (OUTSIDE_ADDR, mapped.1 + 1),
+        64,
+        b"unsolicited",
+    ));
+    assert!(lan.recv(SHORT).is_none());
+}
+
+/// ICMP errors about a connection reach the inside host as if it had been talking to the
+/// remote one directly (RFC 5508).
+#[test]
+fn nat_icmp_error_inbound() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    lan.send(&udp((HOST, 5001), (REMOTE, 53), 64, b"query"));
+    let out = wan.recv(LONG).expect("not forwarded");
+    let (mapped, _) = endpoints(&out);
+
+    let errors = [
+        (ICMP_TYPE_TIME_EXCEEDED, 0, REMOTE2),
+        (ICMP_TYPE_DEST_UNREACH, ICMP_CODE_FRAGMENT_NEEDED, REMOTE),
+    ];
+    for (ty, code, from) in errors {
+        wan.send(&icmp_error(ty, code, from, OUTSIDE_ADDR, &out));
+        let back = lan.recv(LONG).expect("ICMP error not forwarded");
+        assert_eq!(
+            icmp_error_endpoints(&back),
+            ((from, HOST), [(HOST, 5001), (REMOTE, 53)])
+        );
+    }
+    // but not the ones about a connection that is not tracked, the ports being allocated in
+    // turn
+    let untracked = udp((OUTSIDE_ADDR, mapped.1 + 1), (REMOTE, 53), 63, b"query");
+    wan.send(&icmp_error(
+        ICMP_TYPE_DEST_UNREACH,
+        ICMP_CODE_PORT_UNREACH,
+        REMOTE,
+        OUTSIDE_ADDR,
+        &untracked,
+    ));
+    assert!(lan.recv(SHORT).is_none());
+}
+
+/// ICMP echo ids are translated like ports.
+#[test]
+fn nat_icmp_echo() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    lan.send(&icmp_echo(ICMP_TYPE_ECHO, HOST, REMOTE, 0x1234));
+    let (src, dst) = endpoints(&wan.recv(LONG).expect("not forwarded"));
+    assert_eq!(src.0, OUTSIDE_ADDR);
+    assert_eq!(dst.0, REMOTE);
+    wan.send(&icmp_echo(ICMP_TYPE_ECHOREPLY, REMOTE, OUTSIDE_ADDR, src.1));
+    let (_, dst) = endpoints(&lan.recv(LONG).expect("reply not forwarded"));
+    assert_eq!(dst, (HOST, 0x1234));
+}
+
+/// A forwarded port reaches the inside server, whose replies and ICMP errors leave from the
+/// port.
+#[test]
+fn nat_port_forward() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    wan.send(&tcp(
+        (REMOTE, 40000),
+        (OUTSIDE_ADDR, SERVER_PORT),
+        TcpFlags::SYN,
+    ));
+    let syn = lan.recv(LONG).expect("port not forwarded");
+    assert_eq!(endpoints(&syn), ((REMOTE, 40000), (HOST, 80)));
+    lan.send(&tcp(
+        (HOST, 80),
+        (REMOTE, 40000),
+        TcpFlags::SYN | TcpFlags::ACK,
+    ));
+    let syn_ack = wan.recv(LONG).expect("reply not forwarded");
+    assert_eq!(
+        endpoints(&syn_ack),
+        ((OUTSIDE_ADDR, SERVER_PORT), (REMOTE, 40000))
+    );
+
+    lan.send(&icmp_error(
+        ICMP_TYPE_DEST_UNREACH,
+        ICMP_CODE_PORT_UNREACH,
+        HOST,
+        REMOTE,
+        &syn,
+    ));
+    let error = wan.recv(LONG).expect("ICMP error not forwarded");
+    assert_eq!(
+        icmp_error_endpoints(&error),
+        (
+            (OUTSIDE_ADDR, REMOTE),
+            [(REMOTE, 40000), (OUTSIDE_ADDR, SERVER_PORT)]
+        )
+    );
+    assert_eq!(
+        connection(IP_PROTOCOL_TCP, (HOST, 80)).unwrap().state,
+        ConntrackState::Established
+    );
+
+    // a reset closes the connection
+    wan.send(&tcp(
+        (REMOTE, 40000),
+        (OUTSIDE_ADDR, SERVER_PORT),
+        TcpFlags::RST,
+    ));
+    assert!(lan.recv(LONG).is_some());
+    assert_eq!(
+        connection(IP_PROTOCOL_TCP, (HOST, 80)).unwrap().state,
+        ConntrackState::Closing
+    );
+}
+
+/// Datagrams whose TTL runs out are not forwarded.
+#[test]
+fn nat_ttl_expired() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    lan.send(&udp((HOST, 5002), (REMOTE, 53), 1, b"expired"));
+    assert!(wan.recv(SHORT).is_none());
+}
+
+#[test]
+fn nat_idle_expiry() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    conntrack::conntrack_set_timeouts(ConntrackTimeouts {
+        udp: Duration::from_millis(100),
+        ..ConntrackTimeouts::default()
+    });
+    lan.send(&udp((HOST, 6000), (REMOTE, 53), 64, b"short-lived"));
+    assert!(wan.recv(LONG).is_some());
+    assert!(connection(IP_PROTOCOL_UDP, (HOST, 6000)).is_some());
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(connection(IP_PROTOCOL_UDP, (HOST, 6000)).is_none());
+}
+
+/// Without forwarding, nothing goes through.
+#[test]
+fn nat_forwarding_disabled() {
+    let (_turn, Router { lan, wan, .. }) = router();
+    ip::ip_forwarding_set(false);
+    lan.send(&udp((HOST, 5003), (REMOTE, 53), 64, b"query"));
+    assert!(wan.recv(SHORT).is_none());
+}
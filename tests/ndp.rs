@@ -0,0 +1,63 @@
+use std::{process::Command, time::Duration};
+
+use utcp::{
+    driver::ether_tap::EtherTapNetDevice,
+    icmpv6,
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    ndp::{self, NdpState},
+    net,
+    wire::ethernet::EthernetAddress,
+};
+
+const TAP_NAME: &str = "utcp-nd0";
+
+fn ip(args: &[&str]) -> String {
+    let output = Command::new("ip").args(args).output().unwrap();
+    assert!(output.status.success(), "ip {:?}: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The host side of the TAP interface is the neighbor, so this needs CAP_NET_ADMIN. The
+/// same exchanges with a neighbor the test plays run in `ndp_tunnel.rs`.
+#[test]
+#[ignore = "needs CAP_NET_ADMIN for a TAP interface"]
+fn ndp() {
+    net::net_init().unwrap();
+    let hwaddr: EthernetAddress = "02:00:00:99:00:02".parse().unwrap();
+    let dev = EtherTapNetDevice::init(TAP_NAME, Some(hwaddr)).unwrap();
+    ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+    net::net_run().expect("the TAP interface can not be opened");
+    ip(&["link", "set", TAP_NAME, "up"]);
+    ip(&["-6", "addr", "add", "fd99::1/64", "dev", TAP_NAME, "nodad"]);
+    let host_hwaddr: EthernetAddress =
+        std::fs::read_to_string(format!("/sys/class/net/{}/address", TAP_NAME))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+    let local: Ipv6Address = "fd99::2".parse().unwrap();
+    let host: Ipv6Address = "fd99::1".parse().unwrap();
+    ipv6::ipv6_addr_add(dev, "fe80::99:2".parse().unwrap(), 64).unwrap();
+    ipv6::ipv6_addr_add(dev, local, 64).unwrap();
+
+    // the request waits for the host to advertise its address, and the host solicits ours
+    // before replying
+    icmpv6::icmpv6_ping(host, b"ping", Duration::from_secs(3)).unwrap();
+    assert_eq!(
+        ndp::ndp_lookup(host),
+        Some((host_hwaddr, NdpState::Reachable))
+    );
+    let neigh = ip(&["-6", "neigh", "show", "fd99::2", "dev", TAP_NAME]);
+    assert!(neigh.contains(&hwaddr.to_string()), "{}", neigh);
+    icmpv6::icmpv6_ping(host, b"again", Duration::from_secs(1)).unwrap();
+
+    // nobody answers for this one
+    let absent: Ipv6Address = "fd99::3".parse().unwrap();
+    assert!(icmpv6::icmpv6_ping(absent, b"ping", Duration::from_millis(500)).is_err());
+    assert_eq!(
+        ndp::ndp_lookup(absent).map(|(_, s)| s),
+        Some(NdpState::Incomplete)
+    );
+
+    net::net_shutdown().unwrap();
+}
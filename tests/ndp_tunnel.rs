@@ -0,0 +1,206 @@
+mod common;
+
+use std::{sync::MutexGuard, thread, time::Duration};
+
+use common::{Link, Shared};
+use utcp::{
+    icmpv6,
+    ipv6::{self, IPV6_NEXT_HEADER_ICMPV6, Ipv6Address, Ipv6Interface},
+    ndp::{self, NdpState},
+    net::{self, NET_PROTOCOL_TYPE_IPV6},
+    wire::{
+        ethernet::{EthernetAddress, EthernetFrame},
+        icmpv6::*,
+        ipv6::{IPV6_HEADER_LEN, Ipv6Packet},
+    },
+};
+
+const ADDR: Ipv6Address = Ipv6Address::new(0xfd99, 1, 0, 0, 0, 0, 0, 2);
+const LINK_LOCAL: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0x99, 2);
+const PEER: Ipv6Address = Ipv6Address::new(0xfd99, 1, 0, 0, 0, 0, 0, 1);
+/// Also the peer's, which the stack does not know yet.
+const ASKER: Ipv6Address = Ipv6Address::new(0xfd99, 1, 0, 0, 0, 0, 0, 3);
+/// Nobody answers for it.
+const ABSENT: Ipv6Address = Ipv6Address::new(0xfd99, 1, 0, 0, 0, 0, 0, 4);
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x02]);
+const PEER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x01]);
+
+/// The neighbor.
+struct Neighbor(Link);
+
+impl Neighbor {
+    /// Sends `icmp` from `src` to `dst`, at `dst_hwaddr`.
+    fn send(&self, dst_hwaddr: EthernetAddress, src: Ipv6Address, dst: Ipv6Address, icmp: &[u8]) {
+        let mut packet = vec![0u8; IPV6_HEADER_LEN + icmp.len()];
+        let mut ip6 = Ipv6Packet::new_unchecked(&mut packet[..]);
+        ip6.set_version_tc_flow(0, 0);
+        ip6.set_payload_len(icmp.len() as u16);
+        ip6.set_next_header(IPV6_NEXT_HEADER_ICMPV6);
+        ip6.set_hop_limit(255);
+        ip6.set_src(src);
+        ip6.set_dst(dst);
+        let msg = ip6.payload_mut();
+        msg.copy_from_slice(icmp);
+        Icmpv6Packet::new_unchecked(msg).fill_checksum(src, dst);
+        self.0
+            .send_frame(dst_hwaddr, PEER_HWADDR, NET_PROTOCOL_TYPE_IPV6, &packet);
+    }
+
+    /// Waits for a while for an ICMPv6 message of type `ty`, and returns the destination
+    /// address of its frame, its source and destination addresses and the message.
+    fn recv(
+        &self,
+        ty: u8,
+        timeout: Duration,
+    ) -> Option<(EthernetAddress, Ipv6Address, Ipv6Address, Vec<u8>)> {
+        self.0.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            assert_eq!(frame.src(), HWADDR);
+            if frame.ethertype() != NET_PROTOCOL_TYPE_IPV6 {
+                return None;
+            }
+            let ip6 = Ipv6Packet::new_checked(frame.payload()).unwrap();
+            if ip6.next_header() != IPV6_NEXT_HEADER_ICMPV6 {
+                return None;
+            }
+            let icmp = Icmpv6Packet::new_checked(ip6.payload()).unwrap();
+            assert!(icmp.verify_checksum(ip6.src(), ip6.dst()));
+            (icmp.msg_type() == ty)
+                .then(|| (frame.dst(), ip6.src(), ip6.dst(), ip6.payload().to_vec()))
+        })
+    }
+}
+
+/// A Neighbor Solicitation or Advertisement for `target`, with the link-layer address of the
+/// peer in the option `option`.
+fn neighbor(ty: u8, flags: u32, target: Ipv6Address, option: u8) -> Vec<u8> {
+    let mut msg = vec![0u8; NDP_NEIGHBOR_LEN + NDP_OPTION_LL_ADDR_LEN];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ty);
+    packet.set_code(0);
+    packet.set_rest_of_header(flags);
+    packet.set_target(target);
+    ndp_option_ll_addr_write(&mut msg[NDP_NEIGHBOR_LEN..], option, PEER_HWADDR);
+    msg
+}
+
+/// Returns the link-layer address of the option `option` of the Neighbor Discovery message
+/// `msg`.
+fn ll_addr_option(msg: &[u8], option: u8) -> Option<EthernetAddress> {
+    let options = Icmpv6Packet::new_checked(msg)
+        .unwrap()
+        .ndp_options()
+        .unwrap();
+    options.into_iter().find_map(|opt| match opt {
+        NdpOption::SourceLinkLayerAddr(addr) if option == NDP_OPTION_SOURCE_LL_ADDR => Some(addr),
+        NdpOption::TargetLinkLayerAddr(addr) if option == NDP_OPTION_TARGET_LL_ADDR => Some(addr),
+        _ => None,
+    })
+}
+
+fn multicast_hwaddr(group: Ipv6Address) -> EthernetAddress {
+    let addr = group.as_bytes();
+    EthernetAddress([0x33, 0x33, addr[12], addr[13], addr[14], addr[15]])
+}
+
+static LINK: Shared<Neighbor> = Shared::new();
+
+/// Sets the stack up for the first test, and waits for the turn of the caller, with the link
+/// quiet.
+fn link() -> (MutexGuard<'static, ()>, &'static Neighbor) {
+    let (turn, link) = LINK.get(|| {
+        net::net_init().unwrap();
+        let (link, dev) = Link::open(Some(HWADDR));
+        ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+        net::net_run().unwrap();
+        ipv6::ipv6_addr_add(dev, LINK_LOCAL, 64).unwrap();
+        ipv6::ipv6_addr_add(dev, ADDR, 64).unwrap();
+        Neighbor(link)
+    });
+    link.0.drain();
+    (turn, link)
+}
+
+/// The datagram waits for the neighbor to advertise its address.
+#[test]
+fn ndp_resolve() {
+    let (_turn, link) = link();
+    let ping = thread::spawn(|| icmpv6::icmpv6_ping(PEER, b"ping", Duration::from_secs(3)));
+
+    let (dst_hwaddr, src, dst, solicit) = link
+        .recv(ICMPV6_TYPE_NEIGHBOR_SOLICIT, Duration::from_secs(2))
+        .expect("no solicitation");
+    assert_eq!(dst, PEER.solicited_node());
+    assert_eq!(dst_hwaddr, multicast_hwaddr(dst));
+    assert_eq!(Icmpv6Packet::new_unchecked(&solicit[..]).target(), PEER);
+    assert_eq!(
+        ll_addr_option(&solicit, NDP_OPTION_SOURCE_LL_ADDR),
+        Some(HWADDR)
+    );
+    let advert = neighbor(
+        ICMPV6_TYPE_NEIGHBOR_ADVERT,
+        NDP_NA_FLAG_SOLICITED | NDP_NA_FLAG_OVERRIDE,
+        PEER,
+        NDP_OPTION_TARGET_LL_ADDR,
+    );
+    link.send(HWADDR, PEER, src, &advert);
+
+    let (dst_hwaddr, src, dst, request) = link
+        .recv(ICMPV6_TYPE_ECHO_REQUEST, Duration::from_secs(2))
+        .expect("no echo request");
+    assert_eq!((dst_hwaddr, src, dst), (PEER_HWADDR, ADDR, PEER));
+    let mut reply = request.clone();
+    Icmpv6Packet::new_unchecked(&mut reply[..]).set_msg_type(ICMPV6_TYPE_ECHO_REPLY);
+    link.send(HWADDR, PEER, ADDR, &reply);
+
+    ping.join().unwrap().unwrap();
+    assert_eq!(
+        ndp::ndp_lookup(PEER),
+        Some((PEER_HWADDR, NdpState::Reachable))
+    );
+}
+
+/// The stack advertises its address to a neighbor that solicits it, and learns the
+/// neighbor's.
+#[test]
+fn ndp_solicited() {
+    let (_turn, link) = link();
+    let solicit = neighbor(
+        ICMPV6_TYPE_NEIGHBOR_SOLICIT,
+        0,
+        ADDR,
+        NDP_OPTION_SOURCE_LL_ADDR,
+    );
+    let group = ADDR.solicited_node();
+    link.send(multicast_hwaddr(group), ASKER, group, &solicit);
+
+    let (dst_hwaddr, src, dst, advert) = link
+        .recv(ICMPV6_TYPE_NEIGHBOR_ADVERT, Duration::from_secs(2))
+        .expect("no advertisement");
+    assert_eq!((dst_hwaddr, src, dst), (PEER_HWADDR, ADDR, ASKER));
+    let packet = Icmpv6Packet::new_unchecked(&advert[..]);
+    assert_eq!(packet.target(), ADDR);
+    assert_ne!(packet.rest_of_header() & NDP_NA_FLAG_SOLICITED, 0);
+    assert_eq!(
+        ll_addr_option(&advert, NDP_OPTION_TARGET_LL_ADDR),
+        Some(HWADDR)
+    );
+    assert_eq!(ndp::ndp_lookup(ASKER).map(|(ha, _)| ha), Some(PEER_HWADDR));
+}
+
+/// An address nobody answers for stays incomplete.
+#[test]
+fn ndp_unanswered() {
+    let (_turn, link) = link();
+    assert!(icmpv6::icmpv6_ping(ABSENT, b"ping", Duration::from_millis(500)).is_err());
+    assert_eq!(
+        ndp::ndp_lookup(ABSENT).map(|(_, state)| state),
+        Some(NdpState::Incomplete)
+    );
+    let (_, _, dst, solicit) = link
+        .recv(ICMPV6_TYPE_NEIGHBOR_SOLICIT, Duration::from_millis(100))
+        .expect("no solicitation");
+    assert_eq!(dst, ABSENT.solicited_node());
+    assert_eq!(Icmpv6Packet::new_unchecked(&solicit[..]).target(), ABSENT);
+}
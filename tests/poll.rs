@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress, IpEndpoint},
+    net,
+    poll::{PollEvents, PollFd, utcp_poll},
+    socket::{self, AF_INET, SOCK_DGRAM, SOCK_NONBLOCK, SOCK_STREAM},
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+fn level_triggered() {
+    let a = socket::socket(AF_INET, SOCK_DGRAM | SOCK_NONBLOCK, 0).unwrap();
+    socket::bind(a, IpEndpoint::new(LOOPBACK_IP_ADDR, 7200)).unwrap();
+    let b = socket::socket(AF_INET, SOCK_DGRAM | SOCK_NONBLOCK, 0).unwrap();
+    socket::bind(b, IpEndpoint::new(LOOPBACK_IP_ADDR, 7201)).unwrap();
+
+    let mut fds = [
+        PollFd::new(a, PollEvents::READABLE),
+        PollFd::new(b, PollEvents::READABLE),
+    ];
+    assert_eq!(
+        utcp_poll(&mut fds, Some(Duration::from_millis(20))).unwrap(),
+        0
+    );
+
+    // a datagram sent from another thread ends the wait
+    let sender = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        let fd = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+        socket::sendto(fd, b"x", IpEndpoint::new(LOOPBACK_IP_ADDR, 7201)).unwrap();
+        socket::close(fd).unwrap();
+    });
+    assert_eq!(utcp_poll(&mut fds, TIMEOUT).unwrap(), 1);
+    assert!(fds[0].revents.is_empty());
+    assert_eq!(fds[1].revents, PollEvents::READABLE);
+    sender.join().unwrap();
+
+    // still reported until the datagram is received
+    assert_eq!(utcp_poll(&mut fds, Some(Duration::ZERO)).unwrap(), 1);
+    socket::recv(b, &mut [0u8; 8]).unwrap();
+    assert_eq!(utcp_poll(&mut fds, Some(Duration::ZERO)).unwrap(), 0);
+
+    // UDP is always writable
+    let mut fds = [PollFd::new(a, PollEvents::WRITABLE)];
+    assert_eq!(utcp_poll(&mut fds, None).unwrap(), 1);
+
+    socket::close(a).unwrap();
+    socket::close(b).unwrap();
+}
+
+fn edge_triggered() {
+    let listener = socket::socket(AF_INET, SOCK_STREAM | SOCK_NONBLOCK, 0).unwrap();
+    socket::bind(listener, IpEndpoint::new(LOOPBACK_IP_ADDR, 7202)).unwrap();
+    socket::listen(listener, 4).unwrap();
+    let client = socket::socket(AF_INET, SOCK_STREAM | SOCK_NONBLOCK, 0).unwrap();
+    assert!(matches!(
+        socket::connect(client, IpEndpoint::new(LOOPBACK_IP_ADDR, 7202)),
+        Err(UtcpErr::InProgress)
+    ));
+
+    let mut fds = [PollFd::new(listener, PollEvents::READABLE)];
+    assert_eq!(utcp_poll(&mut fds, TIMEOUT).unwrap(), 1);
+    let (server, _) = socket::accept(listener).unwrap();
+    socket::set_nonblocking(server, true).unwrap();
+
+    let mut fds = [PollFd::new(client, PollEvents::WRITABLE)];
+    assert_eq!(utcp_poll(&mut fds, TIMEOUT).unwrap(), 1);
+    socket::send(client, b"hello").unwrap();
+
+    let mut fds = [PollFd::edge(server, PollEvents::READABLE)];
+    assert_eq!(utcp_poll(&mut fds, TIMEOUT).unwrap(), 1);
+    // not reported again while nothing new arrives
+    assert_eq!(
+        utcp_poll(&mut fds, Some(Duration::from_millis(20))).unwrap(),
+        0
+    );
+    let mut buf = [0u8; 16];
+    assert_eq!(socket::recv(server, &mut buf).unwrap(), 5);
+    assert!(matches!(
+        socket::recv(server, &mut buf),
+        Err(UtcpErr::WouldBlock)
+    ));
+
+    // the close of the peer is a new edge
+    socket::close(client).unwrap();
+    assert_eq!(utcp_poll(&mut fds, TIMEOUT).unwrap(), 1);
+    assert!(fds[0].revents.contains(PollEvents::READABLE));
+    assert_eq!(socket::recv(server, &mut buf).unwrap(), 0);
+
+    socket::close(server).unwrap();
+    socket::close(listener).unwrap();
+}
+
+#[test]
+fn poll_api() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    level_triggered();
+    edge_triggered();
+
+    net::net_shutdown().unwrap();
+}
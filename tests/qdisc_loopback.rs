@@ -0,0 +1,58 @@
+mod common;
+
+use common::{delivered, held, held_handler, release, wait_until};
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    net::{self, NetProtocol},
+    qdisc::{self, FifoQdisc, Qdisc, QdiscStats},
+    utils::DropPolicy,
+};
+
+/// Local experimental Ethertype, so that only the test handler sees the packets.
+const TYPE: u16 = 0x88b6;
+const DEVICE_QUEUE_LIMIT: usize = 2;
+const LEN: usize = 100;
+
+/// The qdisc counts what the driver sent, and what it dequeued but the driver refused as
+/// drops. The loopback device refuses packets once its own queue is full, which it is while
+/// the interrupt thread is held in the handler.
+#[test]
+fn qdisc_loopback() {
+    net::net_init().unwrap();
+    let dev =
+        LoopbackNetDevice::init_with_queue_limit(DEVICE_QUEUE_LIMIT, DropPolicy::DropTail).unwrap();
+    net::net_protocol_register(NetProtocol::new(TYPE, held_handler));
+    let fifo = Qdisc::Fifo(FifoQdisc::new(100, DropPolicy::DropTail));
+    qdisc::qdisc_attach(dev, fifo).unwrap();
+    net::net_run().unwrap();
+
+    let output = |id: u8| net::net_device_output(&dev, TYPE, &[id; LEN], &mut []);
+    output(0).unwrap();
+    assert!(wait_until(held));
+
+    // the tx softirq waits for the interrupt thread as well, so these stay in the qdisc
+    for id in 1..=10 {
+        output(id).unwrap();
+    }
+    assert_eq!(qdisc::qdisc_stats(dev).unwrap().backlog, 10);
+
+    release();
+    let expected: Vec<u8> = (0..=DEVICE_QUEUE_LIMIT as u8).collect();
+    assert!(
+        wait_until(|| delivered() == expected),
+        "delivered {:?}",
+        delivered()
+    );
+    let sent = DEVICE_QUEUE_LIMIT as u64 + 1;
+    assert_eq!(
+        qdisc::qdisc_stats(dev).unwrap(),
+        QdiscStats {
+            packets: sent,
+            bytes: sent * LEN as u64,
+            drops: 11 - sent,
+            backlog: 0,
+        }
+    );
+
+    net::net_shutdown().unwrap();
+}
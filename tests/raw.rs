@@ -0,0 +1,63 @@
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    ip::{self, IpAddress},
+    net, raw,
+    wire::ipv4::Ipv4Packet,
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+const TEST_PROTOCOL: u8 = 253;
+
+#[test]
+fn raw_socket() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    let rx = raw::raw_open(TEST_PROTOCOL).unwrap();
+    let other = raw::raw_open(TEST_PROTOCOL + 1).unwrap();
+    raw::raw_set_nonblocking(other, true).unwrap();
+
+    // header built by the stack
+    let tx = raw::raw_open(TEST_PROTOCOL).unwrap();
+    raw::raw_sendto(tx, b"hello", LOOPBACK_IP_ADDR).unwrap();
+
+    let mut buf = [0u8; 128];
+    let (len, src) = raw::raw_recvfrom(rx, &mut buf).unwrap();
+    assert_eq!(src, LOOPBACK_IP_ADDR);
+    let ip_hdr = Ipv4Packet::new_checked(&buf[..len]).unwrap();
+    assert_eq!(ip_hdr.protocol(), TEST_PROTOCOL);
+    assert_eq!(ip_hdr.payload(), b"hello");
+
+    // header supplied by the caller
+    raw::raw_set_hdrincl(tx, true).unwrap();
+    let mut datagram = [0u8; 25];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut datagram[..]);
+    ip_hdr.set_header_len(20);
+    ip_hdr.set_ttl(64);
+    ip_hdr.set_protocol(TEST_PROTOCOL);
+    ip_hdr.set_src(LOOPBACK_IP_ADDR);
+    ip_hdr.set_dst(LOOPBACK_IP_ADDR);
+    datagram[20..].copy_from_slice(b"world");
+    raw::raw_sendto(tx, &datagram, LOOPBACK_IP_ADDR).unwrap();
+
+    let (len, _) = raw::raw_recvfrom(rx, &mut buf).unwrap();
+    let ip_hdr = Ipv4Packet::new_checked(&buf[..len]).unwrap();
+    assert!(ip_hdr.verify_checksum());
+    assert_eq!(ip_hdr.ttl(), 64);
+    assert_eq!(ip_hdr.payload(), b"world");
+
+    // the other socket filters by protocol number
+    assert!(matches!(
+        raw::raw_recvfrom(other, &mut buf),
+        Err(utcp::error::UtcpErr::WouldBlock)
+    ));
+
+    raw::raw_close(rx).unwrap();
+    raw::raw_close(tx).unwrap();
+    raw::raw_close(other).unwrap();
+    net::net_shutdown().unwrap();
+}
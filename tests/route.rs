@@ -0,0 +1,68 @@
+use utcp::{
+    driver::dummy::DummyNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    net,
+};
+
+const ADDR_A: IpAddress = IpAddress::parse_from("10.99.58.1");
+const ADDR_B: IpAddress = IpAddress::parse_from("10.99.59.1");
+const GATEWAY_A: IpAddress = IpAddress::parse_from("10.99.58.254");
+const GATEWAY_B: IpAddress = IpAddress::parse_from("10.99.59.254");
+
+fn addr(s: &str) -> IpAddress {
+    IpAddress::parse_from(s)
+}
+
+/// The interface a datagram leaves through is told by the source address the stack picks.
+fn source(dst: &str) -> IpAddress {
+    ip::ip_route_source(addr(dst)).unwrap()
+}
+
+#[test]
+fn route_longest_prefix_match() {
+    net::net_init().unwrap();
+    let netmask = addr("255.255.255.0");
+    let dev_a = DummyNetDevice::init().unwrap();
+    ip::ip_iface_register(dev_a, ip::IpInterface::new(ADDR_A, netmask)).unwrap();
+    let dev_b = DummyNetDevice::init().unwrap();
+    ip::ip_iface_register(dev_b, ip::IpInterface::new(ADDR_B, netmask)).unwrap();
+
+    assert_eq!(source("10.99.58.7"), ADDR_A);
+    assert_eq!(source("10.99.59.7"), ADDR_B);
+    assert!(matches!(
+        ip::ip_route_source(addr("192.0.2.1")),
+        Err(UtcpErr::NoRoute)
+    ));
+    // the gateway must be on a connected network
+    assert!(matches!(
+        ip::ip_route_add(addr("10.99.60.0"), netmask, addr("192.0.2.1")),
+        Err(UtcpErr::NetUnreachable)
+    ));
+
+    ip::ip_route_set_default_gateway(GATEWAY_A).unwrap();
+    ip::ip_route_add(addr("10.99.60.128"), addr("255.255.255.128"), GATEWAY_B).unwrap();
+    ip::ip_route_add(addr("10.99.60.0"), netmask, GATEWAY_A).unwrap();
+    ip::ip_route_add(addr("10.99.60.200"), addr("255.255.255.255"), GATEWAY_A).unwrap();
+
+    assert_eq!(source("192.0.2.1"), ADDR_A);
+    assert_eq!(source("10.99.60.5"), ADDR_A);
+    // the /25 wins over the /24 added after it
+    assert_eq!(source("10.99.60.130"), ADDR_B);
+    // and the host route over both
+    assert_eq!(source("10.99.60.200"), ADDR_A);
+    // connected networks are more specific than the default route
+    assert_eq!(source("10.99.59.7"), ADDR_B);
+
+    // adding the same network again replaces the route
+    ip::ip_route_add(addr("10.99.60.0"), netmask, GATEWAY_B).unwrap();
+    assert_eq!(source("10.99.60.5"), ADDR_B);
+
+    // the routes through an interface go away with it, and the shorter prefixes take over
+    ip::ip_iface_unregister(dev_b).unwrap();
+    assert_eq!(source("10.99.60.130"), ADDR_A);
+    assert_eq!(source("10.99.60.5"), ADDR_A);
+    assert_eq!(source("10.99.59.7"), ADDR_A);
+
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,172 @@
+use std::{
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use utcp::{
+    driver::ether_tap::EtherTapNetDevice,
+    icmpv6,
+    ipv6::{self, IPV6_NEXT_HEADER_ICMPV6, Ipv6AddrState, Ipv6Address},
+    net::{self, NET_PROTOCOL_TYPE_IPV6},
+    slaac::{self, SlaacIdentifier},
+    wire::{
+        ethernet::{ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+        icmpv6::*,
+        ipv6::{IPV6_HEADER_LEN, Ipv6Packet},
+    },
+};
+
+const TAP_NAME: &str = "utcp-ra0";
+
+fn ip(args: &[&str]) -> String {
+    let output = Command::new("ip").args(args).output().unwrap();
+    assert!(output.status.success(), "ip {:?}: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Assigns `addr` to the host side of the TAP interface without Duplicate Address Detection.
+fn host_addr_add(addr: &str) {
+    ip(&["-6", "addr", "add", addr, "dev", TAP_NAME, "nodad"]);
+}
+
+/// Builds a Router Advertisement from `router` with a Prefix Information option for each of
+/// `prefixes`.
+fn router_advert(
+    router: Ipv6Address,
+    hwaddr: EthernetAddress,
+    prefixes: &[Ipv6Address],
+) -> Vec<u8> {
+    let icmp_len = NDP_ROUTER_ADVERT_LEN + NDP_OPTION_LL_ADDR_LEN + 32 * prefixes.len();
+    let mut msg = vec![0u8; icmp_len];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ICMPV6_TYPE_ROUTER_ADVERT);
+    packet.set_code(0);
+    // hop limit 64, router lifetime 1800 seconds
+    packet.set_rest_of_header((64 << 24) | 1800);
+    let mut offset = NDP_ROUTER_ADVERT_LEN;
+    ndp_option_ll_addr_write(&mut msg[offset..], NDP_OPTION_SOURCE_LL_ADDR, hwaddr);
+    offset += NDP_OPTION_LL_ADDR_LEN;
+    for &prefix in prefixes {
+        let info = NdpPrefixInfo {
+            prefix,
+            prefix_len: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: 86400,
+            preferred_lifetime: 14400,
+        };
+        ndp_option_prefix_info_write(&mut msg[offset..], &info);
+        offset += 32;
+    }
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.fill_checksum(router, Ipv6Address::ALL_NODES);
+
+    let mut frame = vec![0u8; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + icmp_len];
+    let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+    eth.set_dst(EthernetAddress([0x33, 0x33, 0, 0, 0, 1]));
+    eth.set_src(hwaddr);
+    eth.set_ethertype(NET_PROTOCOL_TYPE_IPV6);
+    let mut ip6 = Ipv6Packet::new_unchecked(&mut frame[ETHERNET_HEADER_LEN..]);
+    ip6.set_version_tc_flow(0, 0);
+    ip6.set_payload_len(icmp_len as u16);
+    ip6.set_next_header(IPV6_NEXT_HEADER_ICMPV6);
+    ip6.set_hop_limit(255);
+    ip6.set_src(router);
+    ip6.set_dst(Ipv6Address::ALL_NODES);
+    frame[ETHERNET_HEADER_LEN + IPV6_HEADER_LEN..].copy_from_slice(&msg);
+    frame
+}
+
+/// Sends `frame` out of the host side of the TAP interface.
+fn host_send(frame: &[u8]) {
+    let name = std::ffi::CString::new(TAP_NAME).unwrap();
+    unsafe {
+        let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0);
+        assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+        let mut addr: libc::sockaddr_ll = std::mem::zeroed();
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_ifindex = libc::if_nametoindex(name.as_ptr()) as i32;
+        addr.sll_halen = 6;
+        let sent = libc::sendto(
+            fd,
+            frame.as_ptr() as *const libc::c_void,
+            frame.len(),
+            0,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as u32,
+        );
+        assert_eq!(
+            sent,
+            frame.len() as isize,
+            "{}",
+            std::io::Error::last_os_error()
+        );
+        libc::close(fd);
+    }
+}
+
+/// The host side of the TAP interface plays the router, so this needs CAP_NET_ADMIN. The same
+/// with a router the test plays runs in `slaac_tunnel.rs`.
+#[test]
+#[ignore = "needs CAP_NET_ADMIN for a TAP interface"]
+fn slaac() {
+    net::net_init().unwrap();
+    let hwaddr: EthernetAddress = "02:00:00:99:00:12".parse().unwrap();
+    let dev = EtherTapNetDevice::init(TAP_NAME, Some(hwaddr)).unwrap();
+    net::net_run().expect("the TAP interface can not be opened");
+    ip(&["link", "set", TAP_NAME, "up"]);
+    let host_hwaddr: EthernetAddress =
+        std::fs::read_to_string(format!("/sys/class/net/{}/address", TAP_NAME))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+    let router: Ipv6Address = "fe80::99:1".parse().unwrap();
+    host_addr_add("fe80::99:1/64");
+    host_addr_add("fd99:0:0:2::1/64");
+    // the host already uses the address the first prefix forms
+    let duplicate: Ipv6Address = "fd99:0:0:1:0:ff:fe99:12".parse().unwrap();
+    host_addr_add("fd99:0:0:1:0:ff:fe99:12/64");
+
+    slaac::slaac_start(dev, SlaacIdentifier::Eui64).unwrap();
+    let link_local: Ipv6Address = "fe80::ff:fe99:12".parse().unwrap();
+    assert_eq!(
+        ipv6::ipv6_addr_state(dev, link_local),
+        Some(Ipv6AddrState::Tentative)
+    );
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while ipv6::ipv6_addr_state(dev, link_local) != Some(Ipv6AddrState::Preferred) {
+        assert!(
+            Instant::now() < deadline,
+            "link-local address not configured"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let prefixes = [
+        "fd99:0:0:1::".parse().unwrap(),
+        "fd99:0:0:2::".parse().unwrap(),
+    ];
+    host_send(&router_advert(router, host_hwaddr, &prefixes));
+    let global = slaac::slaac_wait(dev, Duration::from_secs(5)).unwrap();
+    let addr: Ipv6Address = "fd99:0:0:2:0:ff:fe99:12".parse().unwrap();
+    assert_eq!(global.len(), 1, "{:?}", global);
+    assert_eq!(global[0].addr, addr);
+    assert_eq!(global[0].state, Ipv6AddrState::Preferred);
+    assert!(global[0].valid_until.is_some());
+    assert_eq!(ipv6::ipv6_addr_state(dev, duplicate), None);
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while ipv6::ipv6_route_default_gateway(dev) != Some(router) {
+        assert!(Instant::now() < deadline, "default route not installed");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let host: Ipv6Address = "fd99:0:0:2::1".parse().unwrap();
+    icmpv6::icmpv6_ping(host, b"ping", Duration::from_secs(3)).unwrap();
+
+    slaac::slaac_stop(dev).unwrap();
+    assert!(ipv6::ipv6_addrs(dev).is_empty());
+    assert_eq!(ipv6::ipv6_route_default_gateway(dev), None);
+
+    net::net_shutdown().unwrap();
+}
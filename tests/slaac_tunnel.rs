@@ -0,0 +1,170 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::Link;
+use utcp::{
+    ipv6::{self, IPV6_NEXT_HEADER_ICMPV6, Ipv6AddrState, Ipv6Address},
+    net::{self, NET_PROTOCOL_TYPE_IPV6},
+    slaac::{self, SlaacIdentifier},
+    wire::{
+        ethernet::{EthernetAddress, EthernetFrame},
+        icmpv6::*,
+        ipv6::{IPV6_HEADER_LEN, Ipv6Packet},
+    },
+};
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x12]);
+const ROUTER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x11]);
+const ROUTER: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0x99, 1);
+
+/// The router.
+struct Router(Link);
+
+impl Router {
+    /// Sends `icmp` from `src` to `dst`, which is a multicast group.
+    fn send(&self, src: Ipv6Address, dst: Ipv6Address, icmp: &[u8]) {
+        let mut packet = vec![0u8; IPV6_HEADER_LEN + icmp.len()];
+        let mut ip6 = Ipv6Packet::new_unchecked(&mut packet[..]);
+        ip6.set_version_tc_flow(0, 0);
+        ip6.set_payload_len(icmp.len() as u16);
+        ip6.set_next_header(IPV6_NEXT_HEADER_ICMPV6);
+        ip6.set_hop_limit(255);
+        ip6.set_src(src);
+        ip6.set_dst(dst);
+        let msg = ip6.payload_mut();
+        msg.copy_from_slice(icmp);
+        Icmpv6Packet::new_unchecked(msg).fill_checksum(src, dst);
+        let group = dst.as_bytes();
+        let dst_hwaddr = EthernetAddress([0x33, 0x33, group[12], group[13], group[14], group[15]]);
+        self.0
+            .send_frame(dst_hwaddr, ROUTER_HWADDR, NET_PROTOCOL_TYPE_IPV6, &packet);
+    }
+
+    /// Returns the target of the next Neighbor Solicitation the stack sends for Duplicate
+    /// Address Detection, or `None` if there is none within `timeout`.
+    fn recv_dad(&self, timeout: Duration) -> Option<Ipv6Address> {
+        self.0.recv(timeout, |frame| {
+            let frame = EthernetFrame::new_checked(frame).unwrap();
+            if frame.ethertype() != NET_PROTOCOL_TYPE_IPV6 {
+                return None;
+            }
+            let ip6 = Ipv6Packet::new_checked(frame.payload()).unwrap();
+            if ip6.next_header() != IPV6_NEXT_HEADER_ICMPV6 || !ip6.src().is_unspecified() {
+                return None;
+            }
+            let icmp = Icmpv6Packet::new_checked(ip6.payload()).unwrap();
+            (icmp.msg_type() == ICMPV6_TYPE_NEIGHBOR_SOLICIT).then(|| icmp.target())
+        })
+    }
+}
+
+/// A Router Advertisement with a Prefix Information option for each of `prefixes`.
+fn router_advert(prefixes: &[Ipv6Address]) -> Vec<u8> {
+    let mut msg = vec![0u8; NDP_ROUTER_ADVERT_LEN + NDP_OPTION_LL_ADDR_LEN + 32 * prefixes.len()];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ICMPV6_TYPE_ROUTER_ADVERT);
+    packet.set_code(0);
+    // hop limit 64, router lifetime 1800 seconds
+    packet.set_rest_of_header((64 << 24) | 1800);
+    let mut offset = NDP_ROUTER_ADVERT_LEN;
+    ndp_option_ll_addr_write(&mut msg[offset..], NDP_OPTION_SOURCE_LL_ADDR, ROUTER_HWADDR);
+    offset += NDP_OPTION_LL_ADDR_LEN;
+    for &prefix in prefixes {
+        let info = NdpPrefixInfo {
+            prefix,
+            prefix_len: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: 86400,
+            preferred_lifetime: 14400,
+        };
+        ndp_option_prefix_info_write(&mut msg[offset..], &info);
+        offset += 32;
+    }
+    msg
+}
+
+/// A Neighbor Advertisement of `target`, as its owner answers Duplicate Address Detection.
+fn duplicate_advert(target: Ipv6Address) -> Vec<u8> {
+    let mut msg = vec![0u8; NDP_NEIGHBOR_LEN + NDP_OPTION_LL_ADDR_LEN];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut msg[..]);
+    packet.set_msg_type(ICMPV6_TYPE_NEIGHBOR_ADVERT);
+    packet.set_code(0);
+    packet.set_rest_of_header(NDP_NA_FLAG_OVERRIDE);
+    packet.set_target(target);
+    ndp_option_ll_addr_write(
+        &mut msg[NDP_NEIGHBOR_LEN..],
+        NDP_OPTION_TARGET_LL_ADDR,
+        ROUTER_HWADDR,
+    );
+    msg
+}
+
+/// The test plays the router, and a host that already uses the address the first prefix
+/// forms.
+#[test]
+fn slaac_tunnel() {
+    net::net_init().unwrap();
+    let (link, dev) = Link::open(Some(HWADDR));
+    net::net_run().unwrap();
+    let router = Router(link);
+
+    slaac::slaac_start(dev, SlaacIdentifier::Eui64).unwrap();
+    let link_local: Ipv6Address = "fe80::ff:fe99:12".parse().unwrap();
+    assert_eq!(
+        ipv6::ipv6_addr_state(dev, link_local),
+        Some(Ipv6AddrState::Tentative)
+    );
+    // after a random delay of up to a second
+    assert_eq!(router.recv_dad(Duration::from_secs(2)), Some(link_local));
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while ipv6::ipv6_addr_state(dev, link_local) != Some(Ipv6AddrState::Preferred) {
+        assert!(
+            Instant::now() < deadline,
+            "link-local address not configured"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let prefixes = [
+        "fd99:0:0:1::".parse().unwrap(),
+        "fd99:0:0:2::".parse().unwrap(),
+    ];
+    router.send(ROUTER, Ipv6Address::ALL_NODES, &router_advert(&prefixes));
+    let wait = thread::spawn(move || slaac::slaac_wait(dev, Duration::from_secs(5)));
+    let duplicate: Ipv6Address = "fd99:0:0:1:0:ff:fe99:12".parse().unwrap();
+    let mut checked = Vec::new();
+    while !wait.is_finished() {
+        let Some(target) = router.recv_dad(Duration::from_millis(100)) else {
+            continue;
+        };
+        if target == duplicate {
+            router.send(duplicate, Ipv6Address::ALL_NODES, &duplicate_advert(target));
+        }
+        checked.push(target);
+    }
+    assert!(checked.contains(&duplicate), "checked {:?}", checked);
+    let global = wait.join().unwrap().unwrap();
+    let addr: Ipv6Address = "fd99:0:0:2:0:ff:fe99:12".parse().unwrap();
+    assert_eq!(global.len(), 1, "{:?}", global);
+    assert_eq!(global[0].addr, addr);
+    assert_eq!(global[0].state, Ipv6AddrState::Preferred);
+    assert!(global[0].valid_until.is_some());
+    assert_eq!(ipv6::ipv6_addr_state(dev, duplicate), None);
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while ipv6::ipv6_route_default_gateway(dev) != Some(ROUTER) {
+        assert!(Instant::now() < deadline, "default route not installed");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    slaac::slaac_stop(dev).unwrap();
+    assert!(ipv6::ipv6_addrs(dev).is_empty());
+    assert_eq!(ipv6::ipv6_route_default_gateway(dev), None);
+
+    net::net_shutdown().unwrap();
+}
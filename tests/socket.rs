@@ -0,0 +1,123 @@
+use utcp::{
+    driver::loopback::LoopbackNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress, IpEndpoint},
+    net,
+    socket::{self, AF_INET, SHUT_WR, SOCK_DGRAM, SOCK_NONBLOCK, SOCK_STREAM},
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+
+fn udp_echo() {
+    let server = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+    socket::bind(server, IpEndpoint::new(LOOPBACK_IP_ADDR, 7)).unwrap();
+
+    let client = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+    socket::connect(client, IpEndpoint::new(LOOPBACK_IP_ADDR, 7)).unwrap();
+    let client_addr = socket::getsockname(client).unwrap();
+    assert_ne!(client_addr.port, 0);
+    // UDP sends do not block
+    assert!(matches!(
+        socket::set_send_timeout(client, Some(std::time::Duration::from_secs(1))),
+        Err(UtcpErr::NotSupported(_))
+    ));
+    socket::send(client, b"ping").unwrap();
+
+    let mut buf = [0u8; 64];
+    let (len, from) = socket::recvfrom(server, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from.port, client_addr.port);
+    socket::sendto(server, b"pong", from).unwrap();
+
+    let len = socket::recv(client, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+
+    socket::close(client).unwrap();
+    socket::close(server).unwrap();
+}
+
+fn tcp_transfer() {
+    let listener = socket::socket(AF_INET, SOCK_STREAM | SOCK_NONBLOCK, 0).unwrap();
+    socket::bind(listener, IpEndpoint::new(LOOPBACK_IP_ADDR, 8080)).unwrap();
+    socket::listen(listener, 4).unwrap();
+    assert!(matches!(socket::accept(listener), Err(UtcpErr::WouldBlock)));
+    socket::set_nonblocking(listener, false).unwrap();
+
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let client = std::thread::spawn(move || {
+        let fd = socket::socket(AF_INET, SOCK_STREAM, 0).unwrap();
+        socket::connect(fd, IpEndpoint::new(LOOPBACK_IP_ADDR, 8080)).unwrap();
+        assert_eq!(socket::getpeername(fd).unwrap().port, 8080);
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += socket::send(fd, &data[sent..]).unwrap();
+        }
+        socket::shutdown(fd, SHUT_WR).unwrap();
+        // the server echoes a short reply and closes
+        let mut buf = [0u8; 16];
+        let len = socket::recv(fd, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"done");
+        assert_eq!(socket::recv(fd, &mut buf).unwrap(), 0);
+        socket::close(fd).unwrap();
+    });
+
+    let (fd, peer) = socket::accept(listener).unwrap();
+    assert_eq!(peer.addr, LOOPBACK_IP_ADDR);
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = socket::recv(fd, &mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(received.len(), expected.len());
+    assert!(received == expected);
+    socket::send(fd, b"done").unwrap();
+    socket::close(fd).unwrap();
+    client.join().unwrap();
+
+    // nobody listens on this port
+    let fd = socket::socket(AF_INET, SOCK_STREAM, 0).unwrap();
+    assert!(matches!(
+        socket::connect(fd, IpEndpoint::new(LOOPBACK_IP_ADDR, 8081)),
+        Err(UtcpErr::ConnectionRefused)
+    ));
+    socket::close(fd).unwrap();
+    socket::close(listener).unwrap();
+}
+
+/// Threads racing to close a descriptor close its PCB once.
+fn close_once() {
+    for _ in 0..20 {
+        let fd = socket::socket(AF_INET, SOCK_DGRAM, 0).unwrap();
+        let closers: Vec<_> = (0..4)
+            .map(|_| std::thread::spawn(move || socket::close(fd)))
+            .collect();
+        let results: Vec<_> = closers.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .all(|result| matches!(result, Ok(()) | Err(UtcpErr::BadDescriptor(_))))
+        );
+    }
+}
+
+#[test]
+fn socket_api() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    udp_echo();
+    tcp_transfer();
+    close_once();
+
+    net::net_shutdown().unwrap();
+}
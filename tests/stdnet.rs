@@ -0,0 +1,93 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::Duration,
+};
+
+use utcp::{
+    TcpListener, TcpStream, UdpSocket,
+    driver::loopback::LoopbackNetDevice,
+    ip::{self, IpAddress},
+    net,
+};
+
+const LOOPBACK_IP_ADDR: IpAddress = IpAddress::parse_from("127.0.0.1");
+const LOOPBACK_NETMASK: IpAddress = IpAddress::parse_from("255.0.0.0");
+
+fn tcp_line_echo() {
+    let listener = TcpListener::bind("127.0.0.1:7000").unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_eq!(addr, "127.0.0.1:7000".parse::<SocketAddr>().unwrap());
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        writeln!(stream, "hello").unwrap();
+        writeln!(stream, "world").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "HELLO\nWORLD\n");
+    });
+
+    let stream = listener.incoming().next().unwrap().unwrap();
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        writeln!(writer, "{}", line.unwrap().to_uppercase()).unwrap();
+    }
+    drop(stream);
+    client.join().unwrap();
+
+    // reads time out when the peer stays silent
+    let client = TcpStream::connect_timeout(&addr, Duration::from_secs(1)).unwrap();
+    let (_server, peer) = listener.accept().unwrap();
+    assert_eq!(client.local_addr().unwrap(), peer);
+    client
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    assert_eq!(
+        client.read_timeout().unwrap(),
+        Some(Duration::from_millis(50))
+    );
+    let err = (&client).read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(client.set_read_timeout(Some(Duration::ZERO)).is_err());
+
+    let err = TcpStream::connect("127.0.0.1:7001").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+fn udp_echo() {
+    let server = UdpSocket::bind("127.0.0.1:7002").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(server.local_addr().unwrap()).unwrap();
+    client.send(b"ping").unwrap();
+
+    let mut buf = [0u8; 16];
+    let (len, from) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, client.local_addr().unwrap());
+    server.send_to(b"pong", from).unwrap();
+    let len = client.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+
+    server.set_nonblocking(true).unwrap();
+    let err = server.recv_from(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn std_net_api() {
+    net::net_init().unwrap();
+    let dev = LoopbackNetDevice::init().unwrap();
+    let iface = ip::IpInterface::new(LOOPBACK_IP_ADDR, LOOPBACK_NETMASK);
+    ip::ip_iface_register(dev, iface).unwrap();
+    net::net_run().unwrap();
+
+    tcp_line_echo();
+    udp_echo();
+
+    net::net_shutdown().unwrap();
+}
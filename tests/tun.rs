@@ -0,0 +1,142 @@
+use std::{
+    io::{Read, Write},
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use utcp::{
+    TcpListener, UdpSocket,
+    driver::tun::TunNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    ipv6::{self, Ipv6Address, Ipv6Interface},
+    net::{self, NetDeviceFlags},
+};
+
+const TUN_NAME: &str = "utcp-tun0";
+
+const ADDR: &str = "10.99.55.2";
+const HOST_ADDR: &str = "10.99.55.1";
+const ADDR6: &str = "fd99:55::2";
+const HOST_ADDR6: &str = "fd99:55::1";
+
+const PORT: u16 = 7000;
+
+fn ip(args: &[&str]) -> String {
+    let output = Command::new("ip").args(args).output().unwrap();
+    assert!(output.status.success(), "ip {:?}: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Sends `dst` to itself from the host until it comes back.
+fn echo_from_host(src: &str, dst: &str) -> bool {
+    let socket = std::net::UdpSocket::bind(src).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut buf = [0u8; 64];
+    (0..10).any(|_| {
+        socket.send_to(dst.as_bytes(), dst).unwrap();
+        matches!(socket.recv_from(&mut buf), Ok((n, _)) if &buf[..n] == dst.as_bytes())
+    })
+}
+
+#[test]
+fn tun_name_checked() {
+    assert!(matches!(
+        TunNetDevice::init(""),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+}
+
+/// The host routes IPv4 and IPv6 traffic into the stack through the TUN interface, so this
+/// needs CAP_NET_ADMIN.
+#[test]
+#[ignore = "needs CAP_NET_ADMIN for a TUN interface"]
+fn tun() {
+    net::net_init().unwrap();
+    let dev = TunNetDevice::init(TUN_NAME).unwrap();
+    let flags = net::net_device_flags(&dev);
+    assert!(flags.contains(NetDeviceFlags::P2P));
+    assert!(!flags.intersects(NetDeviceFlags::NEED_ARP | NetDeviceFlags::BROADCAST));
+    let netmask = IpAddress::parse_from("255.255.255.0");
+    ip::ip_iface_register(
+        dev,
+        ip::IpInterface::new(IpAddress::parse_from(ADDR), netmask),
+    )
+    .unwrap();
+    ipv6::ipv6_iface_register(dev, Ipv6Interface::new()).unwrap();
+    ipv6::ipv6_addr_add(dev, ADDR6.parse::<Ipv6Address>().unwrap(), 64).unwrap();
+
+    net::net_run().expect("the TUN interface can not be opened");
+    ip(&["addr", "add", &format!("{}/24", HOST_ADDR), "dev", TUN_NAME]);
+    ip(&[
+        "-6",
+        "addr",
+        "add",
+        &format!("{}/64", HOST_ADDR6),
+        "dev",
+        TUN_NAME,
+        "nodad",
+    ]);
+    ip(&["link", "set", TUN_NAME, "up"]);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let echoes: Vec<_> = [
+        format!("{}:{}", ADDR, PORT),
+        format!("[{}]:{}", ADDR6, PORT),
+    ]
+    .into_iter()
+    .map(|addr| {
+        let socket = UdpSocket::bind(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while !stop.load(Ordering::Relaxed) {
+                if let Ok((n, peer)) = socket.recv_from(&mut buf) {
+                    socket.send_to(&buf[..n], peer).unwrap();
+                }
+            }
+        })
+    })
+    .collect();
+    assert!(echo_from_host(
+        &format!("{}:0", HOST_ADDR),
+        &format!("{}:{}", ADDR, PORT)
+    ));
+    assert!(echo_from_host(
+        &format!("[{}]:0", HOST_ADDR6),
+        &format!("[{}]:{}", ADDR6, PORT)
+    ));
+    stop.store(true, Ordering::Relaxed);
+    for echo in echoes {
+        echo.join().unwrap();
+    }
+
+    // a TCP connection from the host
+    let listener = TcpListener::bind(format!("{}:{}", ADDR, PORT)).unwrap();
+    let client = thread::spawn(|| {
+        let mut stream = std::net::TcpStream::connect((ADDR, PORT)).unwrap();
+        stream.write_all(b"hello over tun").unwrap();
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).unwrap();
+        buf[..n].to_vec()
+    });
+    let (mut stream, peer) = listener.accept().unwrap();
+    assert_eq!(peer.ip().to_string(), HOST_ADDR);
+    let mut buf = [0u8; 64];
+    let n = stream.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello over tun");
+    stream.write_all(b"hello from utcp").unwrap();
+    assert_eq!(client.join().unwrap(), b"hello from utcp");
+
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,202 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use utcp::{
+    UdpSocket, arp,
+    driver::udp_tunnel::{UdpTunnelFraming, UdpTunnelNetDevice},
+    error::UtcpErr,
+    ip::{self, IP_PROTOCOL_UDP, IpAddress},
+    net::{self, NET_PROTOCOL_TYPE_IP, NetDeviceFlags},
+    wire::{
+        ethernet::{ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+        ipv4::{IPV4_HEADER_MIN_LEN, Ipv4Packet},
+        udp::{UDP_HEADER_LEN, UdpPacket},
+    },
+};
+
+const ETHER_ADDR: IpAddress = IpAddress::parse_from("10.99.56.1");
+const ETHER_PEER: IpAddress = IpAddress::parse_from("10.99.56.2");
+const IP_ADDR: IpAddress = IpAddress::parse_from("10.99.57.1");
+const IP_PEER: IpAddress = IpAddress::parse_from("10.99.57.2");
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x56, 0x01]);
+const PEER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x56, 0x02]);
+
+const PORT: u16 = 7000;
+const PEER_PORT: u16 = 40000;
+
+/// Returns a port on 127.0.0.1 nobody uses right now.
+fn free_endpoint() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+/// A UDP datagram from the peer at `src` to the echo socket of the stack at `dst`.
+fn datagram(src: IpAddress, dst: IpAddress, payload: &[u8]) -> Vec<u8> {
+    let len = IPV4_HEADER_MIN_LEN + UDP_HEADER_LEN + payload.len();
+    let mut buf = vec![0u8; len];
+    let mut ip_hdr = Ipv4Packet::new_unchecked(&mut buf[..]);
+    ip_hdr.set_header_len(IPV4_HEADER_MIN_LEN);
+    ip_hdr.set_total(len as u16);
+    ip_hdr.set_ttl(64);
+    ip_hdr.set_protocol(IP_PROTOCOL_UDP);
+    ip_hdr.set_src(src);
+    ip_hdr.set_dst(dst);
+    ip_hdr.fill_checksum();
+    let mut udp = UdpPacket::new_unchecked(ip_hdr.payload_mut());
+    udp.set_src_port(PEER_PORT);
+    udp.set_dst_port(PORT);
+    udp.set_len((UDP_HEADER_LEN + payload.len()) as u16);
+    udp.payload_mut().copy_from_slice(payload);
+    udp.fill_checksum(src, dst);
+    buf
+}
+
+/// Returns the payload of a UDP datagram the stack sent back to the peer at `dst`.
+fn echoed(datagram: &[u8], dst: IpAddress) -> Option<Vec<u8>> {
+    let ip_hdr = Ipv4Packet::new_checked(datagram).ok()?;
+    if ip_hdr.protocol() != IP_PROTOCOL_UDP || ip_hdr.dst() != dst {
+        return None;
+    }
+    let udp = UdpPacket::new_checked(ip_hdr.payload()).ok()?;
+    (udp.src_port() == PORT && udp.dst_port() == PEER_PORT).then(|| udp.payload().to_vec())
+}
+
+/// Other end of a tunnel, played with a host UDP socket the way another process would.
+struct Peer {
+    socket: std::net::UdpSocket,
+    /// Endpoint of the stack.
+    tunnel: SocketAddr,
+    /// Ethernet framing if set.
+    hwaddr: Option<EthernetAddress>,
+}
+
+impl Peer {
+    fn send(&self, datagram: &[u8]) {
+        match self.hwaddr {
+            Some(hwaddr) => {
+                let mut buf = vec![0u8; ETHERNET_HEADER_LEN + datagram.len()];
+                let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+                frame.set_dst(HWADDR);
+                frame.set_src(hwaddr);
+                frame.set_ethertype(NET_PROTOCOL_TYPE_IP);
+                frame.payload_mut().copy_from_slice(datagram);
+                self.socket.send_to(&buf, self.tunnel).unwrap();
+            }
+            None => {
+                self.socket.send_to(datagram, self.tunnel).unwrap();
+            }
+        }
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        let (len, src) = self.socket.recv_from(&mut buf).ok()?;
+        assert_eq!(src, self.tunnel);
+        match self.hwaddr {
+            Some(hwaddr) => {
+                let frame = EthernetFrame::new_checked(&buf[..len]).ok()?;
+                (frame.dst() == hwaddr && frame.src() == HWADDR).then(|| frame.payload().to_vec())
+            }
+            None => Some(buf[..len].to_vec()),
+        }
+    }
+
+    /// Sends `payload` from `src` to `dst` until it comes back.
+    fn echo(&self, src: IpAddress, dst: IpAddress, payload: &[u8]) -> bool {
+        (0..10).any(|_| {
+            self.send(&datagram(src, dst, payload));
+            self.recv()
+                .and_then(|datagram| echoed(&datagram, src))
+                .is_some_and(|echo| echo == payload)
+        })
+    }
+}
+
+/// The test plays the peer processes of an Ethernet tunnel and a raw IP tunnel with host
+/// sockets on 127.0.0.1, so this needs no privilege.
+#[test]
+fn udp_tunnel() {
+    net::net_init().unwrap();
+    let local = free_endpoint();
+    assert!(matches!(
+        UdpTunnelNetDevice::init(local, "[::1]:7000".parse().unwrap(), UdpTunnelFraming::Ip),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        UdpTunnelNetDevice::init(local, "127.0.0.1:0".parse().unwrap(), UdpTunnelFraming::Ip),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+
+    let timeout = Some(Duration::from_millis(300));
+    let netmask = IpAddress::parse_from("255.255.255.0");
+    let mut peers = Vec::new();
+    for (framing, addr) in [
+        (UdpTunnelFraming::Ethernet(Some(HWADDR)), ETHER_ADDR),
+        (UdpTunnelFraming::Ip, IP_ADDR),
+    ] {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(timeout).unwrap();
+        let tunnel = free_endpoint();
+        let dev = UdpTunnelNetDevice::init(tunnel, socket.local_addr().unwrap(), framing).unwrap();
+        ip::ip_iface_register(dev, ip::IpInterface::new(addr, netmask)).unwrap();
+        let hwaddr = match framing {
+            UdpTunnelFraming::Ethernet(_) => {
+                arp::arp_add_static(dev, ETHER_PEER, PEER_HWADDR);
+                Some(PEER_HWADDR)
+            }
+            UdpTunnelFraming::Ip => {
+                assert_eq!(net::net_device_flags(&dev), NetDeviceFlags::P2P);
+                None
+            }
+        };
+        peers.push(Peer {
+            socket,
+            tunnel,
+            hwaddr,
+        });
+    }
+    net::net_run().unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let echoes: Vec<_> = [ETHER_ADDR, IP_ADDR]
+        .into_iter()
+        .map(|addr| {
+            let socket = UdpSocket::bind(format!("{}:{}", addr, PORT)).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; 64];
+                while !stop.load(Ordering::Relaxed) {
+                    if let Ok((n, peer)) = socket.recv_from(&mut buf) {
+                        socket.send_to(&buf[..n], peer).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    assert!(peers[0].echo(ETHER_PEER, ETHER_ADDR, b"over ethernet"));
+    assert!(peers[1].echo(IP_PEER, IP_ADDR, b"over ip"));
+
+    // a datagram from anywhere but the remote endpoint never reaches the stack
+    let stranger = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = datagram(IP_PEER, IP_ADDR, b"stranger");
+    stranger.send_to(&datagram, peers[1].tunnel).unwrap();
+    assert_eq!(peers[1].recv(), None);
+
+    stop.store(true, Ordering::Relaxed);
+    for echo in echoes {
+        echo.join().unwrap();
+    }
+    net::net_shutdown().unwrap();
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use std::{
+    sync::MutexGuard,
+    time::{Duration, Instant},
+};
+
+use common::{Link, Shared};
+use utcp::{
+    UdpSocket, arp,
+    driver::vlan::VlanNetDevice,
+    error::UtcpErr,
+    ip::{self, IpAddress},
+    net::{self, NET_PROTOCOL_TYPE_ARP, NET_PROTOCOL_TYPE_IP, NetDeviceHandler, NetDeviceStats},
+    wire::{
+        arp::{ARP_ETHER_IP_LEN, ARP_OP_REPLY, ARP_OP_REQUEST, ArpPacket},
+        ethernet::{ETHERNET_HEADER_LEN, EthernetAddress, EthernetFrame},
+        vlan::{ETHERNET_TYPE_VLAN, VLAN_HEADER_LEN},
+    },
+};
+
+const ADDR: IpAddress = IpAddress::parse_from("10.99.43.2");
+const VLAN_ADDR: IpAddress = IpAddress::parse_from("10.99.44.2");
+const PEER_VLAN_ADDR: IpAddress = IpAddress::parse_from("10.99.44.1");
+
+const HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x00, 0x43]);
+const PEER_HWADDR: EthernetAddress = EthernetAddress([0x02, 0, 0, 0x99, 0x43, 0x02]);
+
+const VID: u16 = 10;
+const PCP: u8 = 5;
+
+const SHORT: Duration = Duration::from_millis(300);
+const LONG: Duration = Duration::from_secs(2);
+
+/// The other end of the trunk. The tags stay in the frames.
+struct Trunk(Link);
+
+impl Trunk {
+    fn send(&self, frame: &[u8]) {
+        self.0.send(frame);
+    }
+
+    /// Waits for a while for a frame of type `ty`, and returns it without its tag and the TCI
+    /// of the tag, if it was tagged.
+    fn recv(&self, ty: u16, timeout: Duration) -> Option<(Vec<u8>, Option<u16>)> {
+        self.0.recv(timeout, |frame| {
+            let mut frame = frame.to_vec();
+            assert_eq!(
+                EthernetFrame::new_checked(&frame[..]).unwrap().src(),
+                HWADDR
+            );
+            let mut tci = None;
+            if EthernetFrame::new_unchecked(&frame[..]).ethertype() == ETHERNET_TYPE_VLAN {
+                assert!(frame.len() >= ETHERNET_HEADER_LEN + VLAN_HEADER_LEN);
+                tci = Some(u16::from_be_bytes([frame[14], frame[15]]));
+                frame.drain(12..16);
+            }
+            (EthernetFrame::new_unchecked(&frame[..]).ethertype() == ty).then_some((frame, tci))
+        })
+    }
+}
+
+/// Builds an ARP request for `target`, tagged with `vid` unless it is `None`.
+fn arp_request(sender: IpAddress, target: IpAddress, vid: Option<u16>) -> Vec<u8> {
+    let mut arp = vec![0u8; ARP_ETHER_IP_LEN];
+    let mut packet = ArpPacket::new_unchecked(&mut arp[..]);
+    packet.set_ether_ip(NET_PROTOCOL_TYPE_IP);
+    packet.set_operation(ARP_OP_REQUEST);
+    packet.set_sender_hw_addr(PEER_HWADDR);
+    packet.set_sender_proto_addr(sender);
+    packet.set_target_hw_addr(EthernetAddress([0; 6]));
+    packet.set_target_proto_addr(target);
+
+    let mut frame = vec![0u8; ETHERNET_HEADER_LEN];
+    let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+    eth.set_dst(EthernetAddress([0xff; 6]));
+    eth.set_src(PEER_HWADDR);
+    match vid {
+        Some(vid) => {
+            eth.set_ethertype(ETHERNET_TYPE_VLAN);
+            frame.extend_from_slice(&vid.to_be_bytes());
+            frame.extend_from_slice(&NET_PROTOCOL_TYPE_ARP.to_be_bytes());
+        }
+        None => eth.set_ethertype(NET_PROTOCOL_TYPE_ARP),
+    }
+    frame.extend_from_slice(&arp);
+    frame
+}
+
+/// Waits for the transmission counter of `dev` to reach `tx_packets`, since the peer may see
+/// a frame before the device counts it.
+fn tx_settled(dev: &NetDeviceHandler, tx_packets: u64) -> NetDeviceStats {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        let stats = net::net_device_stats(dev).unwrap();
+        if stats.tx_packets >= tx_packets || Instant::now() > deadline {
+            assert_eq!(stats.tx_packets, tx_packets);
+            return stats;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A parent device with an interface of its own, and the VLAN device `VID` on it.
+struct Stack {
+    parent: NetDeviceHandler,
+    dev: NetDeviceHandler,
+    trunk: Trunk,
+}
+
+static STACK: Shared<Stack> = Shared::new();
+
+/// Sets the stack up for the first test, and waits for the turn of the caller, with the trunk
+/// quiet.
+fn stack() -> (MutexGuard<'static, ()>, &'static Stack) {
+    let (turn, stack) = STACK.get(|| {
+        net::net_init().unwrap();
+        let (link, parent) = Link::open(Some(HWADDR));
+        let netmask = IpAddress::parse_from("255.255.255.0");
+        ip::ip_iface_register(parent, ip::IpInterface::new(ADDR, netmask)).unwrap();
+        let dev = VlanNetDevice::init(parent, VID, PCP).unwrap();
+        ip::ip_iface_register(dev, ip::IpInterface::new(VLAN_ADDR, netmask)).unwrap();
+        net::net_run().unwrap();
+        Stack {
+            parent,
+            dev,
+            trunk: Trunk(link),
+        }
+    });
+    stack.trunk.0.drain();
+    (turn, stack)
+}
+
+#[test]
+fn vlan_devices_checked() {
+    let (_turn, &Stack { parent, dev, .. }) = stack();
+    assert!(matches!(
+        VlanNetDevice::init(parent, 0, 0),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        VlanNetDevice::init(parent, 4095, 0),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        VlanNetDevice::init(parent, VID, 0),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        VlanNetDevice::init(dev, 20, 0),
+        Err(UtcpErr::DeviceTypeMismatch { .. })
+    ));
+}
+
+/// The MTU is the one of the parent, and can only be lowered.
+#[test]
+fn vlan_mtu() {
+    let (_turn, &Stack { parent, dev, .. }) = stack();
+    let mtu = net::net_device_mtu(&parent);
+    assert_eq!(net::net_device_mtu(&dev), mtu);
+    assert!(matches!(
+        net::net_device_set_mtu(&dev, mtu + 1),
+        Err(UtcpErr::InvalidArgument(_))
+    ));
+    net::net_device_set_mtu(&dev, 1400).unwrap();
+    assert_eq!(net::net_device_mtu(&dev), 1400);
+    net::net_device_set_mtu(&dev, mtu).unwrap();
+    assert!(matches!(
+        net::net_device_set_mtu(&parent, 1400),
+        Err(UtcpErr::DeviceTypeMismatch { .. })
+    ));
+}
+
+/// Only the request tagged with the VID reaches the interface of the VLAN, and the reply is
+/// tagged with the PCP.
+#[test]
+fn vlan_arp() {
+    let (_turn, Stack { dev, trunk, .. }) = stack();
+    let before = net::net_device_stats(dev).unwrap();
+    trunk.send(&arp_request(PEER_VLAN_ADDR, VLAN_ADDR, None));
+    trunk.send(&arp_request(PEER_VLAN_ADDR, VLAN_ADDR, Some(20)));
+    assert!(trunk.recv(NET_PROTOCOL_TYPE_ARP, SHORT).is_none());
+
+    trunk.send(&arp_request(PEER_VLAN_ADDR, VLAN_ADDR, Some(VID)));
+    let (frame, tci) = trunk
+        .recv(NET_PROTOCOL_TYPE_ARP, LONG)
+        .expect("no ARP reply");
+    assert_eq!(tci, Some(((PCP as u16) << 13) | VID));
+    let reply = ArpPacket::new_checked(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(reply.operation(), ARP_OP_REPLY);
+    assert_eq!(reply.sender_hw_addr(), HWADDR);
+    assert_eq!(reply.sender_proto_addr(), VLAN_ADDR);
+    let stats = tx_settled(dev, before.tx_packets + 1);
+    assert_eq!(stats.rx_packets, before.rx_packets + 1);
+    assert_eq!(stats.rx_bytes, before.rx_bytes + ARP_ETHER_IP_LEN as u64);
+}
+
+/// Untagged frames are the parent's.
+#[test]
+fn vlan_untagged() {
+    let (_turn, Stack { dev, trunk, .. }) = stack();
+    let before = net::net_device_stats(dev).unwrap();
+    trunk.send(&arp_request(PEER_VLAN_ADDR, ADDR, None));
+    let (frame, tci) = trunk
+        .recv(NET_PROTOCOL_TYPE_ARP, LONG)
+        .expect("no ARP reply");
+    assert_eq!(tci, None);
+    let reply = ArpPacket::new_checked(&frame[ETHERNET_HEADER_LEN..]).unwrap();
+    assert_eq!(reply.sender_proto_addr(), ADDR);
+    assert_eq!(net::net_device_stats(dev).unwrap(), before);
+}
+
+#[test]
+fn vlan_ip() {
+    let (_turn, Stack { dev, trunk, .. }) = stack();
+    arp::arp_add_static(*dev, PEER_VLAN_ADDR, PEER_HWADDR);
+    let before = net::net_device_stats(dev).unwrap();
+    let socket = UdpSocket::bind("10.99.44.2:4000").unwrap();
+    socket
+        .send_to(
+            b"tagged datagram over the VLAN sub-device",
+            "10.99.44.1:4000",
+        )
+        .unwrap();
+    let (frame, tci) = trunk
+        .recv(NET_PROTOCOL_TYPE_IP, LONG)
+        .expect("no IP datagram");
+    assert_eq!(tci, Some(((PCP as u16) << 13) | VID));
+    assert_eq!(EthernetFrame::new_unchecked(&frame[..]).dst(), PEER_HWADDR);
+    assert!(frame.ends_with(b"tagged datagram over the VLAN sub-device"));
+    let stats = tx_settled(dev, before.tx_packets + 1);
+    assert_eq!(stats.tx_errors, before.tx_errors);
+}